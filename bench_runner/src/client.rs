@@ -0,0 +1,263 @@
+//! Minimal blocking HTTP/1.1 client for load generation.
+//!
+//! Connections are reused while the server keeps them open; the gateways
+//! answer with `Connection: close`, so against them every request pays for a
+//! connect, as it does under `wrk`.
+//!
+//! A response with `X-Body-Sha256` (the gateways' `BODY_SHA256=1`) has its
+//! body checked against it.
+
+use anyhow::{anyhow, Context, Result};
+use sha2::{Digest, Sha256};
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+const IO_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Debug)]
+pub(crate) struct Target {
+    addr: SocketAddr,
+    /// `Host` header value.
+    host: String,
+}
+
+impl Target {
+    pub(crate) fn parse(url: &str) -> Result<Self> {
+        let parsed = url::Url::parse(url).with_context(|| format!("invalid target {url}"))?;
+        if parsed.scheme() != "http" {
+            return Err(anyhow!("only http:// targets are supported: {url}"));
+        }
+        let host = parsed
+            .host_str()
+            .ok_or_else(|| anyhow!("target has no host: {url}"))?;
+        let port = parsed.port_or_known_default().unwrap_or(80);
+        let addr = (host, port)
+            .to_socket_addrs()
+            .with_context(|| format!("failed to resolve {host}:{port}"))?
+            .next()
+            .ok_or_else(|| anyhow!("{host}:{port} resolved to no addresses"))?;
+        let host = match parsed.port() {
+            Some(port) => format!("{host}:{port}"),
+            None => host.to_string(),
+        };
+        Ok(Target { addr, host })
+    }
+}
+
+/// What came back for one request.
+#[derive(Debug)]
+pub(crate) struct Response {
+    pub(crate) status: u16,
+    pub(crate) bytes: usize,
+    /// Whether the body matched its `X-Body-Sha256`; `None` without one.
+    pub(crate) checksum_ok: Option<bool>,
+}
+
+/// A worker's connection, reopened whenever the server closed it.
+#[derive(Debug, Default)]
+pub(crate) struct Connection {
+    stream: Option<TcpStream>,
+    buf: Vec<u8>,
+}
+
+impl Connection {
+    pub(crate) fn send(
+        &mut self,
+        target: &Target,
+        method: &str,
+        path: &str,
+        body: &[u8],
+    ) -> Result<Response> {
+        let result = self.exchange(target, method, path, body);
+        if !matches!(result, Ok((_, true))) {
+            self.stream = None;
+        }
+        result.map(|(response, _)| response)
+    }
+
+    /// The response and whether the connection can be reused.
+    fn exchange(
+        &mut self,
+        target: &Target,
+        method: &str,
+        path: &str,
+        body: &[u8],
+    ) -> Result<(Response, bool)> {
+        let stream = match self.stream.as_mut() {
+            Some(stream) => stream,
+            None => {
+                let stream = TcpStream::connect_timeout(&target.addr, IO_TIMEOUT)
+                    .with_context(|| format!("connect {}", target.addr))?;
+                stream.set_read_timeout(Some(IO_TIMEOUT))?;
+                stream.set_write_timeout(Some(IO_TIMEOUT))?;
+                stream.set_nodelay(true).ok();
+                self.stream.insert(stream)
+            }
+        };
+        let mut request = format!("{method} {path} HTTP/1.1\r\nHost: {}\r\n", target.host);
+        if !body.is_empty() || !matches!(method, "GET" | "HEAD") {
+            request.push_str(&format!("Content-Length: {}\r\n", body.len()));
+        }
+        request.push_str("\r\n");
+        let mut out = request.into_bytes();
+        out.extend_from_slice(body);
+        stream.write_all(&out).context("send request")?;
+
+        read_response(stream, &mut self.buf, method == "HEAD")
+    }
+}
+
+/// `GET path` on its own connection: the status and the raw body.
+pub(crate) fn fetch(target: &Target, path: &str) -> Result<(u16, Vec<u8>)> {
+    let mut conn = Connection::default();
+    let response = conn.send(target, "GET", path, &[])?;
+    let head_end = conn
+        .buf
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .map_or(0, |pos| pos + 4);
+    let body = conn.buf[head_end..response.bytes.min(conn.buf.len())].to_vec();
+    Ok((response.status, body))
+}
+
+fn read_response(
+    stream: &mut TcpStream,
+    buf: &mut Vec<u8>,
+    head_only: bool,
+) -> Result<(Response, bool)> {
+    buf.clear();
+    let mut chunk = [0u8; 16 * 1024];
+    // Interim responses (`100 Continue`, `103 Early Hints`) are skipped.
+    let (head_end, status) = loop {
+        let head_end = loop {
+            if let Some(pos) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+                break pos + 4;
+            }
+            let n = stream.read(&mut chunk).context("read response")?;
+            if n == 0 {
+                return Err(anyhow!("connection closed before response head"));
+            }
+            buf.extend_from_slice(&chunk[..n]);
+        };
+        let status_line = std::str::from_utf8(&buf[..head_end])
+            .context("response head not UTF-8")?
+            .split("\r\n")
+            .next()
+            .unwrap_or("");
+        let status = status_line
+            .split_whitespace()
+            .nth(1)
+            .and_then(|s| s.parse::<u16>().ok())
+            .ok_or_else(|| anyhow!("malformed status line {status_line:?}"))?;
+        if (100..200).contains(&status) && status != 101 {
+            buf.drain(..head_end);
+            continue;
+        }
+        break (head_end, status);
+    };
+    let head = std::str::from_utf8(&buf[..head_end]).context("response head not UTF-8")?;
+    let mut lines = head.split("\r\n");
+    let status_line = lines.next().unwrap_or("");
+    let mut keep_alive = status_line.starts_with("HTTP/1.1");
+    let mut content_length = None;
+    let mut chunked = false;
+    let mut sha256 = None;
+    for line in lines {
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        let value = value.trim();
+        if name.eq_ignore_ascii_case("content-length") {
+            content_length = value.parse::<usize>().ok();
+        } else if name.eq_ignore_ascii_case("transfer-encoding") {
+            chunked = value.to_ascii_lowercase().ends_with("chunked");
+        } else if name.eq_ignore_ascii_case("connection") {
+            keep_alive = !value.eq_ignore_ascii_case("close");
+        } else if name.eq_ignore_ascii_case("x-body-sha256") {
+            sha256 = Some(value.to_ascii_lowercase());
+        }
+    }
+
+    let no_body = head_only || status == 204 || status == 304 || (100..200).contains(&status);
+    let body_len = if no_body {
+        0
+    } else if chunked {
+        // Read to the terminating chunk; the body is only counted.
+        while !buf[head_end..].ends_with(b"0\r\n\r\n") {
+            let n = stream.read(&mut chunk).context("read chunked body")?;
+            if n == 0 {
+                return Err(anyhow!("connection closed inside chunked body"));
+            }
+            buf.extend_from_slice(&chunk[..n]);
+        }
+        buf.len() - head_end
+    } else if let Some(len) = content_length {
+        while buf.len() - head_end < len {
+            let n = stream.read(&mut chunk).context("read body")?;
+            if n == 0 {
+                return Err(anyhow!("connection closed before end of body"));
+            }
+            buf.extend_from_slice(&chunk[..n]);
+        }
+        len
+    } else {
+        // Delimited by close.
+        keep_alive = false;
+        loop {
+            let n = stream.read(&mut chunk).context("read body")?;
+            if n == 0 {
+                break;
+            }
+            buf.extend_from_slice(&chunk[..n]);
+        }
+        buf.len() - head_end
+    };
+    let checksum_ok = sha256.map(|expected| {
+        let body = &buf[head_end..head_end + body_len];
+        let digest = if chunked {
+            dechunk(body).map(Sha256::digest)
+        } else {
+            Some(Sha256::digest(body))
+        };
+        digest.is_some_and(|digest| hex::encode(digest) == expected)
+    });
+    Ok((
+        Response {
+            status,
+            bytes: head_end + body_len,
+            checksum_ok,
+        },
+        keep_alive,
+    ))
+}
+
+/// The payload of a complete chunked body; `None` when it is malformed.
+fn dechunk(mut body: &[u8]) -> Option<Vec<u8>> {
+    let mut out = Vec::new();
+    loop {
+        let line_end = body.windows(2).position(|w| w == b"\r\n")?;
+        let size = std::str::from_utf8(&body[..line_end]).ok()?;
+        let size = usize::from_str_radix(size.split(';').next()?.trim(), 16).ok()?;
+        body = &body[line_end + 2..];
+        if size == 0 {
+            return Some(out);
+        }
+        out.extend_from_slice(body.get(..size)?);
+        body = body.get(size + 2..)?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_chunked_bodies() {
+        assert_eq!(
+            dechunk(b"5\r\nhello\r\n6;ext=1\r\n world\r\n0\r\ngrpc-status: 0\r\n\r\n").as_deref(),
+            Some(&b"hello world"[..])
+        );
+        assert_eq!(dechunk(b"5\r\nhel"), None);
+    }
+}
@@ -0,0 +1,269 @@
+//! `bench_runner compare old.json new.json`: percentage deltas between two
+//! reports, phase by phase (matched by name), and a regression gate.
+//!
+//! A throughput drop above `--max-throughput-drop` percent, or a rise above
+//! `--max-latency-increase` percent in any latency percentile listed in
+//! `--latency`, counts as a regression, and the command exits non-zero.
+//! Differences between the two reports' environments (see `environment`) are
+//! printed first, as they may explain a delta.
+
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+use std::path::Path;
+
+use crate::environment;
+use crate::stats::PhaseReport;
+
+pub(crate) const USAGE: &str = "usage: bench_runner compare <old.json> <new.json> \
+[--max-throughput-drop PCT] [--max-latency-increase PCT] [--latency p50,p90,p99]";
+
+const LATENCY_METRICS: [&str; 6] = ["mean", "p50", "p90", "p99", "p999", "max"];
+
+#[derive(Debug, Deserialize)]
+struct Run {
+    /// Absent from reports written before it was recorded.
+    #[serde(default)]
+    environment: serde_json::Value,
+    phases: Vec<PhaseReport>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Thresholds {
+    /// Percent.
+    pub(crate) max_throughput_drop: f64,
+    /// Percent.
+    pub(crate) max_latency_increase: f64,
+    /// Latency metrics the gate applies to; all are reported.
+    pub(crate) latency: Vec<String>,
+}
+
+impl Default for Thresholds {
+    fn default() -> Self {
+        Thresholds {
+            max_throughput_drop: 5.0,
+            max_latency_increase: 10.0,
+            latency: ["p50", "p90", "p99"].map(String::from).to_vec(),
+        }
+    }
+}
+
+impl Thresholds {
+    /// Parses the flags after the two file names.
+    pub(crate) fn parse(args: &[String]) -> Result<Self> {
+        let mut thresholds = Thresholds::default();
+        let mut rest = args.iter();
+        while let Some(flag) = rest.next() {
+            let value = rest
+                .next()
+                .ok_or_else(|| anyhow!("{flag} needs a value\n{USAGE}"))?;
+            let percent = || -> Result<f64> {
+                value
+                    .parse::<f64>()
+                    .ok()
+                    .filter(|v| v.is_finite() && *v >= 0.0)
+                    .ok_or_else(|| anyhow!("invalid {flag} {value:?}"))
+            };
+            match flag.as_str() {
+                "--max-throughput-drop" => thresholds.max_throughput_drop = percent()?,
+                "--max-latency-increase" => thresholds.max_latency_increase = percent()?,
+                "--latency" => {
+                    thresholds.latency = value.split(',').map(|m| m.trim().to_string()).collect();
+                    if let Some(bad) = thresholds
+                        .latency
+                        .iter()
+                        .find(|m| !LATENCY_METRICS.contains(&m.as_str()))
+                    {
+                        return Err(anyhow!(
+                            "unknown latency metric {bad:?} (expected: {})",
+                            LATENCY_METRICS.join(",")
+                        ));
+                    }
+                }
+                other => return Err(anyhow!("unexpected argument {other:?}\n{USAGE}")),
+            }
+        }
+        Ok(thresholds)
+    }
+}
+
+/// One compared value.
+#[derive(Debug)]
+pub(crate) struct Delta {
+    pub(crate) phase: String,
+    pub(crate) metric: &'static str,
+    pub(crate) old: f64,
+    pub(crate) new: f64,
+    /// Percent change from `old`; `None` when `old` is 0.
+    pub(crate) change: Option<f64>,
+    pub(crate) regression: bool,
+}
+
+fn load(path: &Path) -> Result<Run> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read {}", path.display()))?;
+    serde_json::from_str(&text).with_context(|| format!("invalid report {}", path.display()))
+}
+
+/// Prints the comparison and fails when anything regressed.
+pub(crate) fn run(old: &Path, new: &Path, thresholds: &Thresholds) -> Result<()> {
+    let (old_run, new_run) = (load(old)?, load(new)?);
+    if !old_run.environment.is_null() && !new_run.environment.is_null() {
+        for line in environment::differences(&old_run.environment, &new_run.environment) {
+            eprintln!("[bench] environment differs: {line}");
+        }
+    }
+    for phase in &old_run.phases {
+        if !new_run.phases.iter().any(|p| p.name == phase.name) {
+            eprintln!("[bench] phase {:?} only in {}", phase.name, old.display());
+        }
+    }
+    for phase in &new_run.phases {
+        if !old_run.phases.iter().any(|p| p.name == phase.name) {
+            eprintln!("[bench] phase {:?} only in {}", phase.name, new.display());
+        }
+    }
+    let deltas = compare(&old_run.phases, &new_run.phases, thresholds);
+    println!(
+        "{:<16} {:<8} {:>12} {:>12} {:>9}",
+        "phase", "metric", "old", "new", "change"
+    );
+    for d in &deltas {
+        println!(
+            "{:<16} {:<8} {:>12.3} {:>12.3} {:>9}{}",
+            d.phase,
+            d.metric,
+            d.old,
+            d.new,
+            d.change.map_or("n/a".to_string(), |c| format!("{c:+.1}%")),
+            if d.regression { "  REGRESSION" } else { "" },
+        );
+    }
+    let regressions = deltas.iter().filter(|d| d.regression).count();
+    if regressions > 0 {
+        return Err(anyhow!(
+            "{regressions} regression(s) over threshold (throughput drop > {}%, latency increase > {}%)",
+            thresholds.max_throughput_drop,
+            thresholds.max_latency_increase
+        ));
+    }
+    Ok(())
+}
+
+/// Deltas for every phase present in both runs, in `new`'s order.
+pub(crate) fn compare(
+    old: &[PhaseReport],
+    new: &[PhaseReport],
+    thresholds: &Thresholds,
+) -> Vec<Delta> {
+    let mut deltas = Vec::new();
+    for new_phase in new {
+        let Some(old_phase) = old.iter().find(|p| p.name == new_phase.name) else {
+            continue;
+        };
+        let mut push = |metric: &'static str, old: f64, new: f64, limit: Option<f64>| {
+            let change = (old != 0.0).then(|| (new - old) / old * 100.0);
+            // Throughput regresses downwards, latency upwards.
+            let worse = match metric {
+                "rps" => change.map(|c| -c),
+                _ => change,
+            };
+            deltas.push(Delta {
+                phase: new_phase.name.clone(),
+                metric,
+                old,
+                new,
+                change,
+                regression: matches!((worse, limit), (Some(w), Some(l)) if w > l),
+            });
+        };
+        push(
+            "rps",
+            old_phase.rps,
+            new_phase.rps,
+            Some(thresholds.max_throughput_drop),
+        );
+        let (o, n) = (&old_phase.latency_ms, &new_phase.latency_ms);
+        for (metric, old, new) in [
+            ("mean", o.mean, n.mean),
+            ("p50", o.p50, n.p50),
+            ("p90", o.p90, n.p90),
+            ("p99", o.p99, n.p99),
+            ("p999", o.p999, n.p999),
+            ("max", o.max, n.max),
+        ] {
+            let gated = thresholds.latency.iter().any(|m| m == metric);
+            push(
+                metric,
+                old,
+                new,
+                gated.then_some(thresholds.max_latency_increase),
+            );
+        }
+    }
+    deltas
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stats::LatencySummary;
+
+    fn phase(name: &str, rps: f64, p99: f64) -> PhaseReport {
+        PhaseReport {
+            name: name.to_string(),
+            duration_s: 1.0,
+            concurrency: 1,
+            ramp_from: None,
+            requests: 0,
+            errors: 0,
+            rps,
+            bytes: 0,
+            status: Default::default(),
+            latency_ms: LatencySummary {
+                p50: 1.0,
+                p99,
+                max: 100.0,
+                ..LatencySummary::default()
+            },
+            open_loop: None,
+            checksum: None,
+        }
+    }
+
+    #[test]
+    fn flags_drops_and_rises_over_threshold() {
+        let old = [phase("steady", 1000.0, 10.0), phase("spike", 500.0, 20.0)];
+        let new = [phase("steady", 940.0, 10.5), phase("spike", 480.0, 30.0)];
+        let deltas = compare(&old, &new, &Thresholds::default());
+        let flagged: Vec<(&str, &str)> = deltas
+            .iter()
+            .filter(|d| d.regression)
+            .map(|d| (d.phase.as_str(), d.metric))
+            .collect();
+        assert_eq!(flagged, [("steady", "rps"), ("spike", "p99")]);
+        let rps = &deltas[0];
+        assert!((rps.change.unwrap() + 6.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn ungated_metrics_and_missing_phases_never_fail() {
+        let old = [phase("steady", 1000.0, 10.0), phase("gone", 1.0, 1.0)];
+        let mut new = [phase("steady", 2000.0, 10.0)];
+        new[0].latency_ms.max = 1000.0;
+        let deltas = compare(&old, &new, &Thresholds::default());
+        assert!(deltas.iter().all(|d| d.phase == "steady" && !d.regression));
+    }
+
+    #[test]
+    fn parses_threshold_flags() {
+        let args: Vec<String> = ["--max-throughput-drop", "2.5", "--latency", "p99,max"]
+            .map(String::from)
+            .to_vec();
+        let thresholds = Thresholds::parse(&args).unwrap();
+        assert_eq!(thresholds.max_throughput_drop, 2.5);
+        assert_eq!(thresholds.max_latency_increase, 10.0);
+        assert_eq!(thresholds.latency, ["p99", "max"]);
+        assert!(Thresholds::parse(&["--latency".to_string(), "p42".to_string()]).is_err());
+        assert!(Thresholds::parse(&["--max-latency-increase".to_string()]).is_err());
+    }
+}
@@ -0,0 +1,166 @@
+//! Where a report was measured: the load generator's machine and the target
+//! gateway's build and machine fingerprint (its `/stats` `fingerprint`), so
+//! reports from different machines or builds are not compared unawares.
+
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+use crate::client::{self, Target};
+
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+/// cgroup v1 reports "no memory limit" as a huge page-aligned number.
+const V1_UNLIMITED_MEMORY: u64 = 1 << 62;
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub(crate) struct Environment {
+    pub(crate) runner: Machine,
+    /// `null` when the target has no `/stats` fingerprint.
+    pub(crate) gateway: Option<serde_json::Value>,
+}
+
+#[derive(Debug, Default, Deserialize, PartialEq, Serialize)]
+pub(crate) struct Machine {
+    pub(crate) cpu_model: Option<String>,
+    pub(crate) cpus: usize,
+    pub(crate) cpu_limit: Option<f64>,
+    pub(crate) memory_limit_bytes: Option<u64>,
+}
+
+impl Environment {
+    pub(crate) fn collect(target: &Target) -> Self {
+        let gateway = match client::fetch(target, "/stats") {
+            Ok((200, body)) => serde_json::from_slice::<serde_json::Value>(&body)
+                .ok()
+                .map(|mut stats| stats["fingerprint"].take())
+                .filter(|fingerprint| !fingerprint.is_null()),
+            _ => None,
+        };
+        if gateway.is_none() {
+            eprintln!("[bench] target has no /stats fingerprint; report records the runner only");
+        }
+        Environment {
+            runner: Machine::current(),
+            gateway,
+        }
+    }
+}
+
+impl Machine {
+    fn current() -> Self {
+        let (cpu_limit, memory_limit_bytes) = container_limits(Path::new(CGROUP_ROOT));
+        Machine {
+            cpu_model: std::fs::read_to_string("/proc/cpuinfo")
+                .ok()
+                .and_then(|info| cpu_model(&info)),
+            cpus: std::thread::available_parallelism().map_or(1, |n| n.get()),
+            cpu_limit,
+            memory_limit_bytes,
+        }
+    }
+}
+
+/// Differences between two environments as `path: old -> new` lines.
+pub(crate) fn differences(old: &serde_json::Value, new: &serde_json::Value) -> Vec<String> {
+    let mut lines = Vec::new();
+    diff("", old, new, &mut lines);
+    lines
+}
+
+fn diff(path: &str, old: &serde_json::Value, new: &serde_json::Value, out: &mut Vec<String>) {
+    match (old, new) {
+        (serde_json::Value::Object(a), serde_json::Value::Object(b)) => {
+            let mut keys: Vec<&String> = a.keys().chain(b.keys()).collect();
+            keys.sort();
+            keys.dedup();
+            let null = serde_json::Value::Null;
+            for key in keys {
+                let path = if path.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{path}.{key}")
+                };
+                diff(
+                    &path,
+                    a.get(key).unwrap_or(&null),
+                    b.get(key).unwrap_or(&null),
+                    out,
+                );
+            }
+        }
+        _ if old != new => out.push(format!("{path}: {old} -> {new}")),
+        _ => {}
+    }
+}
+
+/// The CPU model: `model name` on x86; ARM kernels report `Hardware` or
+/// `Model` instead.
+fn cpu_model(cpuinfo: &str) -> Option<String> {
+    ["model name", "Hardware", "Model", "cpu model"]
+        .iter()
+        .find_map(|key| {
+            cpuinfo.lines().find_map(|line| {
+                let (name, value) = line.split_once(':')?;
+                (name.trim() == *key).then(|| value.trim().to_string())
+            })
+        })
+        .filter(|model| !model.is_empty())
+}
+
+/// CPU cores and memory bytes the container may use, from cgroup v2 or v1.
+fn container_limits(root: &Path) -> (Option<f64>, Option<u64>) {
+    let read = |file: &str| {
+        std::fs::read_to_string(root.join(file))
+            .ok()
+            .map(|v| v.trim().to_string())
+    };
+    if let Some(cpu_max) = read("cpu.max") {
+        let cpu = cpu_max.split_once(' ').and_then(|(quota, period)| {
+            let (quota, period) = (quota.parse::<f64>().ok()?, period.parse::<f64>().ok()?);
+            (period > 0.0).then_some(quota / period)
+        });
+        let memory = read("memory.max").and_then(|v| v.parse::<u64>().ok());
+        return (cpu, memory);
+    }
+    let cpu = match (
+        read("cpu/cpu.cfs_quota_us").and_then(|v| v.parse::<i64>().ok()),
+        read("cpu/cpu.cfs_period_us").and_then(|v| v.parse::<i64>().ok()),
+    ) {
+        (Some(quota), Some(period)) if quota > 0 && period > 0 => {
+            Some(quota as f64 / period as f64)
+        }
+        _ => None,
+    };
+    let memory = read("memory/memory.limit_in_bytes")
+        .and_then(|v| v.parse::<u64>().ok())
+        .filter(|&bytes| bytes < V1_UNLIMITED_MEMORY);
+    (cpu, memory)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lists_changed_fields() {
+        let old = serde_json::json!({
+            "runner": {"cpu_model": "A", "cpus": 8},
+            "gateway": {"rustc": "rustc 1.90.0", "opt_level": "3"},
+        });
+        let new = serde_json::json!({
+            "runner": {"cpu_model": "B", "cpus": 8},
+            "gateway": {"rustc": "rustc 1.90.0", "opt_level": "3", "cpu_limit": 2.0},
+        });
+        assert_eq!(
+            differences(&old, &new),
+            [
+                "gateway.cpu_limit: null -> 2.0",
+                "runner.cpu_model: \"A\" -> \"B\"",
+            ]
+        );
+        assert!(differences(&old, &old).is_empty());
+        assert_eq!(
+            cpu_model("processor : 0\nmodel name : AMD EPYC 7B13\n").as_deref(),
+            Some("AMD EPYC 7B13")
+        );
+    }
+}
@@ -0,0 +1,255 @@
+//! Scenario-driven load generator for the gateways.
+//!
+//! `bench_runner run scenario.toml [--out results.json] [--seed N]` runs the
+//! scenario's phases in order against its target and writes one JSON report,
+//! labelled per phase, to `--out` or stdout, with the runner's and the
+//! gateway's build and machine fingerprint (see `environment`). The same seed
+//! replays the same requests (see `plan`). Progress goes to stderr. Response
+//! bodies are checked against `X-Body-Sha256` when the gateway sends it, and
+//! the run fails, after writing the report, if any did not match.
+//!
+//! `bench_runner compare old.json new.json` diffs two reports and exits
+//! non-zero on regressions (see `compare`).
+
+mod client;
+mod compare;
+mod environment;
+mod plan;
+mod scenario;
+mod stats;
+
+use anyhow::{anyhow, Context, Result};
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use client::{Connection, Target};
+use environment::Environment;
+use plan::Plan;
+use scenario::{Phase, Scenario};
+use stats::{PhaseReport, Recorder};
+
+/// How long an idle worker (above the ramp's current concurrency) waits
+/// before checking again.
+const IDLE_POLL: Duration = Duration::from_millis(5);
+
+const USAGE: &str =
+    "usage: bench_runner run <scenario.toml|.yaml> [--out results.json] [--seed N]\n       \
+bench_runner compare <old.json> <new.json> [thresholds]";
+
+#[derive(Debug, Serialize)]
+struct Report {
+    scenario: String,
+    target: String,
+    started_unix_s: u64,
+    /// Replays the same request sequence with `--seed`.
+    seed: u64,
+    environment: Environment,
+    phases: Vec<PhaseReport>,
+}
+
+fn main() -> Result<()> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        Some("run") => {
+            let mut scenario = None;
+            let mut out = None;
+            let mut seed = None;
+            let mut rest = args[1..].iter();
+            while let Some(arg) = rest.next() {
+                match arg.as_str() {
+                    "--out" => {
+                        out = Some(PathBuf::from(
+                            rest.next().ok_or_else(|| anyhow!("--out needs a path"))?,
+                        ))
+                    }
+                    "--seed" => {
+                        let value = rest.next().ok_or_else(|| anyhow!("--seed needs a value"))?;
+                        seed = Some(
+                            value
+                                .parse::<u64>()
+                                .with_context(|| format!("invalid --seed {value:?}"))?,
+                        );
+                    }
+                    other if scenario.is_none() && !other.starts_with("--") => {
+                        scenario = Some(PathBuf::from(other))
+                    }
+                    other => return Err(anyhow!("unexpected argument {other:?}\n{USAGE}")),
+                }
+            }
+            let scenario = scenario.ok_or_else(|| anyhow!(USAGE))?;
+            run(&scenario, out.as_deref(), seed)
+        }
+        Some("compare") => {
+            let [old, new] = [args.get(1), args.get(2)]
+                .map(|p| p.filter(|p| !p.starts_with("--")).map(PathBuf::from));
+            let (Some(old), Some(new)) = (old, new) else {
+                return Err(anyhow!(compare::USAGE));
+            };
+            let thresholds = compare::Thresholds::parse(&args[3..])?;
+            compare::run(&old, &new, &thresholds)
+        }
+        _ => Err(anyhow!(USAGE)),
+    }
+}
+
+fn run(path: &Path, out: Option<&Path>, seed: Option<u64>) -> Result<()> {
+    let scenario = Scenario::load(path)?;
+    let target = Target::parse(&scenario.target)?;
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let started_unix_s = now.as_secs();
+    let seed = seed.or(scenario.seed).unwrap_or(now.as_nanos() as u64);
+    eprintln!("[bench] seed {seed}");
+    let environment = Environment::collect(&target);
+
+    let mut phases = Vec::new();
+    for (index, phase) in scenario.phases()?.iter().enumerate() {
+        eprintln!(
+            "[bench] phase {:?}: {:.1}s, concurrency {}{}{}",
+            phase.name,
+            phase.duration.as_secs_f64(),
+            phase
+                .ramp_from
+                .map_or(String::new(), |from| format!("{from} -> ")),
+            phase.concurrency,
+            phase
+                .rate
+                .map_or(String::new(), |rate| format!(", open loop at {rate} req/s")),
+        );
+        let report = run_phase(&target, phase, &Plan::new(phase, seed, index));
+        eprintln!(
+            "[bench] phase {:?}: {} requests, {} errors, {:.1} req/s, p50 {:.2} ms, p99 {:.2} ms",
+            report.name,
+            report.requests,
+            report.errors,
+            report.rps,
+            report.latency_ms.p50,
+            report.latency_ms.p99,
+        );
+        if let Some(open_loop) = &report.open_loop {
+            eprintln!(
+                "[bench] phase {:?}: service time p99 {:.2} ms, {} request(s) never sent",
+                report.name, open_loop.service_ms.p99, open_loop.unsent,
+            );
+        }
+        if let Some(checksum) = &report.checksum {
+            eprintln!(
+                "[bench] phase {:?}: {} body checksum(s) verified, {} mismatch(es)",
+                report.name, checksum.verified, checksum.mismatches,
+            );
+        }
+        phases.push(report);
+    }
+
+    let report = Report {
+        scenario: path.display().to_string(),
+        target: scenario.target.clone(),
+        started_unix_s,
+        seed,
+        environment,
+        phases,
+    };
+    let json = serde_json::to_string_pretty(&report)?;
+    match out {
+        Some(out) => std::fs::write(out, json + "\n")
+            .with_context(|| format!("failed to write {}", out.display()))?,
+        None => println!("{json}"),
+    }
+    let mismatches: u64 = report
+        .phases
+        .iter()
+        .filter_map(|phase| phase.checksum.as_ref())
+        .map(|checksum| checksum.mismatches)
+        .sum();
+    if mismatches > 0 {
+        return Err(anyhow!(
+            "{mismatches} response body(ies) did not match X-Body-Sha256"
+        ));
+    }
+    Ok(())
+}
+
+/// Runs `phase.concurrency` workers until the phase ends; while ramping, the
+/// ones above the current concurrency wait. Open-loop workers each take the
+/// next request and wait until it is due.
+fn run_phase(target: &Target, phase: &Phase, plan: &Plan) -> PhaseReport {
+    // One counter for all workers: request n is the same in every run.
+    let next = AtomicU64::new(0);
+    let start = Instant::now();
+
+    let mut total = Recorder::default();
+    std::thread::scope(|scope| {
+        let workers: Vec<_> = (0..phase.concurrency)
+            .map(|index| {
+                let next = &next;
+                scope.spawn(move || {
+                    let mut recorder = Recorder::default();
+                    let mut conn = Connection::default();
+                    loop {
+                        let elapsed = start.elapsed();
+                        if elapsed >= phase.duration {
+                            break;
+                        }
+                        let due = if phase.rate.is_some() {
+                            let n = next.fetch_add(1, Ordering::Relaxed);
+                            let Some(due) = phase.due(n) else {
+                                break;
+                            };
+                            if due > elapsed {
+                                std::thread::sleep(due - elapsed);
+                            }
+                            Some((n, due))
+                        } else if index >= phase.active_workers(elapsed) {
+                            conn = Connection::default();
+                            std::thread::sleep(IDLE_POLL);
+                            continue;
+                        } else {
+                            None
+                        };
+                        let request = plan.request(match due {
+                            Some((n, _)) => n,
+                            None => next.fetch_add(1, Ordering::Relaxed),
+                        });
+                        let sent = Instant::now();
+                        match conn.send(
+                            target,
+                            &request.mix.method,
+                            &request.mix.path,
+                            request.body,
+                        ) {
+                            // Open loop: latency counts from when the request
+                            // was due, including any wait for a free worker.
+                            Ok(resp) => {
+                                match due {
+                                    Some((_, due)) => {
+                                        let latency = start.elapsed().saturating_sub(due);
+                                        recorder.record(resp.status, latency, resp.bytes);
+                                        recorder.service(sent.elapsed());
+                                    }
+                                    None => {
+                                        recorder.record(resp.status, sent.elapsed(), resp.bytes)
+                                    }
+                                }
+                                recorder.checksum(resp.checksum_ok);
+                            }
+                            Err(_) => recorder.error(),
+                        }
+                        if !request.think.is_zero() {
+                            std::thread::sleep(request.think);
+                        }
+                    }
+                    recorder
+                })
+            })
+            .collect();
+        for worker in workers {
+            if let Ok(recorder) = worker.join() {
+                total.merge(recorder);
+            }
+        }
+    });
+    total.report(phase, start.elapsed())
+}
@@ -0,0 +1,162 @@
+//! What the n-th request of a phase is: its path and method, body and the
+//! think time after it.
+//!
+//! Every choice is a pure function of the run's seed, the phase's position
+//! and n, so two runs with the same `--seed` send byte-identical requests in
+//! the same order, whichever worker ends up sending each one. Paths follow the
+//! weights exactly: each cycle through the weighted table is a seeded shuffle
+//! of it. Bodies are slices of a seeded filler, and think time is
+//! `think_ms` plus a seeded jitter of up to `think_jitter_ms` either way.
+
+use std::time::Duration;
+
+use crate::scenario::{PathMix, Phase};
+
+/// One request, borrowed from the plan.
+#[derive(Debug)]
+pub(crate) struct Request<'a> {
+    pub(crate) mix: &'a PathMix,
+    pub(crate) body: &'a [u8],
+    /// Pause after the response, before the worker's next request.
+    pub(crate) think: Duration,
+}
+
+#[derive(Debug)]
+pub(crate) struct Plan<'a> {
+    phase: &'a Phase,
+    table: Vec<&'a PathMix>,
+    filler: Vec<u8>,
+    seed: u64,
+}
+
+impl<'a> Plan<'a> {
+    /// `index` is the phase's position in the scenario, so phases with the
+    /// same mix still get different sequences.
+    pub(crate) fn new(phase: &'a Phase, seed: u64, index: usize) -> Self {
+        let seed = mix(seed, index as u64);
+        let max = phase.payload_bytes.iter().copied().max().unwrap_or(0);
+        // Twice the largest body, so its start can vary too.
+        let mut rng = SplitMix(mix(seed, u64::MAX));
+        let filler = (0..max * 2).map(|_| b'a' + rng.below(26) as u8).collect();
+        Plan {
+            phase,
+            table: phase.path_table(),
+            filler,
+            seed,
+        }
+    }
+
+    pub(crate) fn request(&self, n: u64) -> Request<'_> {
+        let len = self.table.len() as u64;
+        let (cycle, slot) = (n / len, (n % len) as usize);
+        // Fisher-Yates over this cycle's table, stopped once `slot` is fixed.
+        let mut order: Vec<usize> = (0..self.table.len()).collect();
+        let mut shuffle = SplitMix(mix(self.seed, cycle << 1));
+        for i in 0..=slot {
+            let j = i + shuffle.below((order.len() - i) as u64) as usize;
+            order.swap(i, j);
+        }
+        let mix_entry = self.table[order[slot]];
+
+        let mut rng = SplitMix(mix(self.seed, (n << 1) | 1));
+        let body = match mix_entry.method.as_str() {
+            "POST" | "PUT" | "PATCH" if !self.phase.payload_bytes.is_empty() => {
+                let sizes = &self.phase.payload_bytes;
+                let size = sizes[rng.below(sizes.len() as u64) as usize];
+                let start = rng.below((self.filler.len() - size + 1) as u64) as usize;
+                &self.filler[start..start + size]
+            }
+            _ => &[][..],
+        };
+        let jitter = self.phase.think_jitter.as_secs_f64();
+        let think = if jitter > 0.0 {
+            let offset = (rng.unit() * 2.0 - 1.0) * jitter;
+            Duration::from_secs_f64((self.phase.think.as_secs_f64() + offset).max(0.0))
+        } else {
+            self.phase.think
+        };
+        Request {
+            mix: mix_entry,
+            body,
+            think,
+        }
+    }
+}
+
+/// A seed for stream `stream` of `seed`.
+fn mix(seed: u64, stream: u64) -> u64 {
+    SplitMix(seed ^ stream.wrapping_mul(0xd1b5_4a32_d192_ed03)).next()
+}
+
+/// SplitMix64: tiny, fast and good enough to pick requests.
+struct SplitMix(u64);
+
+impl SplitMix {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform in `0..n`, for `n > 0`.
+    fn below(&mut self, n: u64) -> u64 {
+        ((self.next() as u128 * n as u128) >> 64) as u64
+    }
+
+    /// Uniform in `[0, 1)`.
+    fn unit(&mut self) -> f64 {
+        (self.next() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scenario::Scenario;
+
+    const SCENARIO: &str = r#"
+target = "http://127.0.0.1:18081"
+paths = [{ path = "/proxy", weight = 3 }, { path = "/transform", method = "POST" }]
+payload_bytes = [16, 64]
+
+[[phase]]
+name = "steady"
+duration_secs = 1
+concurrency = 1
+think_ms = 10
+think_jitter_ms = 5
+"#;
+
+    fn requests(seed: u64) -> Vec<(String, Vec<u8>, Duration)> {
+        let phases = Scenario::parse(SCENARIO, "toml").unwrap().phases().unwrap();
+        let plan = Plan::new(&phases[0], seed, 0);
+        (0..40)
+            .map(|n| {
+                let r = plan.request(n);
+                (r.mix.path.clone(), r.body.to_vec(), r.think)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn same_seed_same_traffic() {
+        let (a, b, other) = (requests(7), requests(7), requests(8));
+        assert_eq!(a, b);
+        assert_ne!(a, other);
+
+        // Exact proportions within every cycle of the weighted table.
+        for cycle in a.chunks(4) {
+            let posts = cycle.iter().filter(|(p, _, _)| p == "/transform").count();
+            assert_eq!(posts, 1);
+        }
+        for (path, body, think) in &a {
+            match path.as_str() {
+                "/transform" => assert!(body.len() == 16 || body.len() == 64),
+                _ => assert!(body.is_empty()),
+            }
+            assert!(*think >= Duration::from_millis(5) && *think <= Duration::from_millis(15));
+        }
+    }
+}
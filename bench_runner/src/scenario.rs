@@ -0,0 +1,297 @@
+//! Scenario files: a target and phases run in order.
+//!
+//! TOML (`.toml`) or YAML (`.yaml` / `.yml`):
+//!
+//! ```toml
+//! target = "http://127.0.0.1:18081"
+//! paths = [{ path = "/proxy", weight = 9 }, { path = "/transform", method = "POST", weight = 1 }]
+//! payload_bytes = [256, 4096]
+//!
+//! [[phase]]
+//! name = "ramp-up"
+//! duration_secs = 10
+//! concurrency = 50
+//! ramp_from = 1
+//!
+//! [[phase]]
+//! name = "spike"
+//! duration_secs = 5
+//! concurrency = 200
+//! paths = [{ path = "/compute?iters=50000" }]
+//! ```
+//!
+//! `paths` and `payload_bytes` at the top level are defaults for phases that
+//! do not set their own. A phase's `think_ms` (with up to `think_jitter_ms`
+//! either way) is the pause each worker takes after a response. `seed` fixes
+//! the request sequence (see `plan`); `--seed` overrides it.
+//!
+//! A phase with `rate` (requests per second) runs open loop, like `wrk2`: the
+//! n-th request is due at n / rate seconds and `concurrency` workers send
+//! requests as they fall due, however slowly earlier ones are answered.
+//! Latency is measured from when a request was due, so a stalled server
+//! shows up in the tail instead of just slowing the load down. Open-loop
+//! phases take no `ramp_from` or think time.
+
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+use std::path::Path;
+use std::time::Duration;
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Scenario {
+    pub(crate) target: String,
+    pub(crate) seed: Option<u64>,
+    #[serde(default)]
+    paths: Vec<PathMix>,
+    #[serde(default)]
+    payload_bytes: Vec<usize>,
+    #[serde(rename = "phase")]
+    phases: Vec<PhaseSpec>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct PathMix {
+    pub(crate) path: String,
+    #[serde(default = "default_method")]
+    pub(crate) method: String,
+    /// Share of requests relative to the other paths of the phase.
+    #[serde(default = "default_weight")]
+    pub(crate) weight: u32,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PhaseSpec {
+    name: String,
+    duration_secs: f64,
+    concurrency: usize,
+    /// Concurrency at the start of the phase, rising linearly to
+    /// `concurrency` by its end.
+    ramp_from: Option<usize>,
+    paths: Option<Vec<PathMix>>,
+    payload_bytes: Option<Vec<usize>>,
+    /// Requests per second, for an open-loop phase.
+    rate: Option<f64>,
+    #[serde(default)]
+    think_ms: f64,
+    #[serde(default)]
+    think_jitter_ms: f64,
+}
+
+/// One phase with the scenario defaults filled in.
+#[derive(Clone, Debug)]
+pub(crate) struct Phase {
+    pub(crate) name: String,
+    pub(crate) duration: Duration,
+    pub(crate) concurrency: usize,
+    pub(crate) ramp_from: Option<usize>,
+    pub(crate) paths: Vec<PathMix>,
+    /// Body sizes drawn from for requests that carry a body.
+    pub(crate) payload_bytes: Vec<usize>,
+    pub(crate) think: Duration,
+    pub(crate) think_jitter: Duration,
+    /// Requests per second when open loop.
+    pub(crate) rate: Option<f64>,
+}
+
+fn default_method() -> String {
+    "GET".to_string()
+}
+
+fn default_weight() -> u32 {
+    1
+}
+
+impl Scenario {
+    pub(crate) fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        let extension = path.extension().and_then(|e| e.to_str()).unwrap_or("");
+        Self::parse(&text, extension)
+            .with_context(|| format!("invalid scenario {}", path.display()))
+    }
+
+    /// Parses `text` as TOML or YAML, by file extension.
+    pub(crate) fn parse(text: &str, extension: &str) -> Result<Self> {
+        let scenario: Scenario = match extension {
+            "toml" => toml::from_str(text)?,
+            "yaml" | "yml" => serde_yaml::from_str(text)?,
+            _ => return Err(anyhow!("scenario must be .toml, .yaml or .yml")),
+        };
+        scenario.phases()?;
+        Ok(scenario)
+    }
+
+    /// Phases in order, validated.
+    pub(crate) fn phases(&self) -> Result<Vec<Phase>> {
+        if self.phases.is_empty() {
+            return Err(anyhow!("scenario has no [[phase]]"));
+        }
+        self.phases
+            .iter()
+            .map(|spec| {
+                let paths = spec.paths.clone().unwrap_or_else(|| self.paths.clone());
+                if paths.is_empty() || paths.iter().all(|p| p.weight == 0) {
+                    return Err(anyhow!("phase {:?} has no paths", spec.name));
+                }
+                if spec.concurrency == 0 {
+                    return Err(anyhow!("phase {:?} needs concurrency > 0", spec.name));
+                }
+                if !(spec.duration_secs.is_finite() && spec.duration_secs > 0.0) {
+                    return Err(anyhow!("phase {:?} needs duration_secs > 0", spec.name));
+                }
+                if let Some(rate) = spec.rate {
+                    if !(rate.is_finite() && rate > 0.0) {
+                        return Err(anyhow!("phase {:?} needs rate > 0", spec.name));
+                    }
+                    if spec.ramp_from.is_some() || spec.think_ms > 0.0 || spec.think_jitter_ms > 0.0
+                    {
+                        return Err(anyhow!(
+                            "phase {:?}: rate cannot be combined with ramp_from or think time",
+                            spec.name
+                        ));
+                    }
+                }
+                let millis = |v: f64, field: &str| {
+                    if v.is_finite() && v >= 0.0 {
+                        Ok(Duration::from_secs_f64(v / 1000.0))
+                    } else {
+                        Err(anyhow!("phase {:?} needs {field} >= 0", spec.name))
+                    }
+                };
+                Ok(Phase {
+                    name: spec.name.clone(),
+                    duration: Duration::from_secs_f64(spec.duration_secs),
+                    concurrency: spec.concurrency,
+                    ramp_from: spec.ramp_from,
+                    paths,
+                    payload_bytes: spec
+                        .payload_bytes
+                        .clone()
+                        .unwrap_or_else(|| self.payload_bytes.clone()),
+                    think: millis(spec.think_ms, "think_ms")?,
+                    think_jitter: millis(spec.think_jitter_ms, "think_jitter_ms")?,
+                    rate: spec.rate,
+                })
+            })
+            .collect()
+    }
+}
+
+impl Phase {
+    /// Workers that should be sending `elapsed` into the phase.
+    pub(crate) fn active_workers(&self, elapsed: Duration) -> usize {
+        let Some(from) = self.ramp_from else {
+            return self.concurrency;
+        };
+        let progress = (elapsed.as_secs_f64() / self.duration.as_secs_f64()).min(1.0);
+        let from = from as f64;
+        let to = self.concurrency as f64;
+        ((from + (to - from) * progress).round() as usize).max(1)
+    }
+
+    /// When the n-th request of an open-loop phase is due, if within the
+    /// phase.
+    pub(crate) fn due(&self, n: u64) -> Option<Duration> {
+        let due = Duration::from_secs_f64(n as f64 / self.rate?);
+        (due < self.duration).then_some(due)
+    }
+
+    /// Requests an open-loop phase schedules.
+    pub(crate) fn scheduled(&self) -> Option<u64> {
+        self.rate
+            .map(|rate| (self.duration.as_secs_f64() * rate).ceil() as u64)
+    }
+
+    /// Expands the weights into one entry per unit of weight; `plan` shuffles
+    /// it per cycle.
+    pub(crate) fn path_table(&self) -> Vec<&PathMix> {
+        self.paths
+            .iter()
+            .flat_map(|p| std::iter::repeat_n(p, p.weight as usize))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCENARIO: &str = r#"
+target = "http://127.0.0.1:18081"
+paths = [{ path = "/proxy", weight = 3 }, { path = "/transform", method = "POST" }]
+payload_bytes = [16]
+
+[[phase]]
+name = "ramp-up"
+duration_secs = 10
+concurrency = 11
+ramp_from = 1
+
+[[phase]]
+name = "spike"
+duration_secs = 2
+concurrency = 100
+paths = [{ path = "/health" }]
+payload_bytes = []
+"#;
+
+    #[test]
+    fn phases_inherit_defaults() {
+        let phases = Scenario::parse(SCENARIO, "toml").unwrap().phases().unwrap();
+        assert_eq!(phases.len(), 2);
+        assert_eq!(phases[0].paths.len(), 2);
+        assert_eq!(phases[0].paths[1].method, "POST");
+        assert_eq!(phases[0].payload_bytes, [16]);
+        assert_eq!(phases[1].paths[0].path, "/health");
+        assert!(phases[1].payload_bytes.is_empty());
+        let table: Vec<&str> = phases[0]
+            .path_table()
+            .iter()
+            .map(|p| p.path.as_str())
+            .collect();
+        assert_eq!(table, ["/proxy", "/proxy", "/proxy", "/transform"]);
+    }
+
+    #[test]
+    fn yaml_matches_toml() {
+        let yaml = "target: http://127.0.0.1:18081\nphase:\n  - name: steady\n    duration_secs: 1.5\n    concurrency: 4\n    paths: [{ path: /proxy }]\n";
+        let phases = Scenario::parse(yaml, "yaml").unwrap().phases().unwrap();
+        assert_eq!(phases[0].duration, Duration::from_millis(1500));
+        assert_eq!(phases[0].paths[0].method, "GET");
+    }
+
+    #[test]
+    fn ramp_rises_linearly() {
+        let phases = Scenario::parse(SCENARIO, "toml").unwrap().phases().unwrap();
+        let ramp = &phases[0];
+        assert_eq!(ramp.active_workers(Duration::ZERO), 1);
+        assert_eq!(ramp.active_workers(Duration::from_secs(5)), 6);
+        assert_eq!(ramp.active_workers(Duration::from_secs(20)), 11);
+        assert_eq!(phases[1].active_workers(Duration::ZERO), 100);
+    }
+
+    #[test]
+    fn open_loop_schedule() {
+        let text = "target = \"http://x\"\npaths = [{ path = \"/\" }]\n[[phase]]\nname = \"a\"\nduration_secs = 2\nconcurrency = 4\nrate = 100";
+        let phase = &Scenario::parse(text, "toml").unwrap().phases().unwrap()[0];
+        assert_eq!(phase.due(0), Some(Duration::ZERO));
+        assert_eq!(phase.due(150), Some(Duration::from_millis(1500)));
+        assert_eq!(phase.due(200), None);
+        assert_eq!(phase.scheduled(), Some(200));
+        assert!(Scenario::parse(&format!("{text}\nramp_from = 1"), "toml").is_err());
+        assert!(Scenario::parse(&text.replace("rate = 100", "rate = 0"), "toml").is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_phases() {
+        assert!(Scenario::parse("target = \"http://x\"\nphase = []", "toml").is_err());
+        assert!(Scenario::parse(
+            "target = \"http://x\"\n[[phase]]\nname = \"a\"\nduration_secs = 1\nconcurrency = 1",
+            "toml"
+        )
+        .is_err());
+    }
+}
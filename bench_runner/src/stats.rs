@@ -0,0 +1,189 @@
+//! Per-phase measurements and the exported report.
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use crate::scenario::Phase;
+
+/// Status classes: index 0 counts statuses outside 100..=599.
+const CLASSES: [&str; 6] = ["other", "1xx", "2xx", "3xx", "4xx", "5xx"];
+
+/// What one worker saw during a phase.
+#[derive(Debug, Default)]
+pub(crate) struct Recorder {
+    latencies_us: Vec<u64>,
+    /// Open loop: from send to response, without the wait for a worker.
+    service_us: Vec<u64>,
+    by_class: [u64; 6],
+    errors: u64,
+    bytes: u64,
+    checksums: u64,
+    checksum_mismatches: u64,
+}
+
+impl Recorder {
+    pub(crate) fn record(&mut self, status: u16, latency: Duration, bytes: usize) {
+        let class = match status {
+            100..=599 => (status / 100) as usize,
+            _ => 0,
+        };
+        self.by_class[class] += 1;
+        self.latencies_us.push(latency.as_micros() as u64);
+        self.bytes += bytes as u64;
+    }
+
+    /// Open loop: the time the server took, next to the latency from when
+    /// the request was due passed to `record`.
+    pub(crate) fn service(&mut self, service: Duration) {
+        self.service_us.push(service.as_micros() as u64);
+    }
+
+    /// The outcome of checking a response's `X-Body-Sha256`, if it had one.
+    pub(crate) fn checksum(&mut self, ok: Option<bool>) {
+        if let Some(ok) = ok {
+            self.checksums += 1;
+            self.checksum_mismatches += u64::from(!ok);
+        }
+    }
+
+    /// A request that got no response (connect, I/O or parse failure).
+    pub(crate) fn error(&mut self) {
+        self.errors += 1;
+    }
+
+    pub(crate) fn merge(&mut self, other: Recorder) {
+        self.latencies_us.extend(other.latencies_us);
+        self.service_us.extend(other.service_us);
+        for (total, n) in self.by_class.iter_mut().zip(other.by_class) {
+            *total += n;
+        }
+        self.errors += other.errors;
+        self.bytes += other.bytes;
+        self.checksums += other.checksums;
+        self.checksum_mismatches += other.checksum_mismatches;
+    }
+
+    pub(crate) fn report(mut self, phase: &Phase, elapsed: Duration) -> PhaseReport {
+        self.latencies_us.sort_unstable();
+        self.service_us.sort_unstable();
+        let requests = self.latencies_us.len() as u64;
+        let status = CLASSES
+            .iter()
+            .zip(self.by_class)
+            .filter(|(_, n)| *n > 0)
+            .map(|(class, n)| (class.to_string(), n))
+            .collect();
+        PhaseReport {
+            name: phase.name.clone(),
+            duration_s: elapsed.as_secs_f64(),
+            concurrency: phase.concurrency,
+            ramp_from: phase.ramp_from,
+            requests,
+            errors: self.errors,
+            rps: requests as f64 / elapsed.as_secs_f64().max(f64::EPSILON),
+            bytes: self.bytes,
+            status,
+            latency_ms: LatencySummary::from_sorted(&self.latencies_us),
+            open_loop: phase
+                .rate
+                .zip(phase.scheduled())
+                .map(|(rate, scheduled)| OpenLoopReport {
+                    target_rps: rate,
+                    unsent: scheduled.saturating_sub(requests + self.errors),
+                    service_ms: LatencySummary::from_sorted(&self.service_us),
+                }),
+            checksum: (self.checksums > 0).then_some(ChecksumReport {
+                verified: self.checksums,
+                mismatches: self.checksum_mismatches,
+            }),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct PhaseReport {
+    pub(crate) name: String,
+    pub(crate) duration_s: f64,
+    pub(crate) concurrency: usize,
+    pub(crate) ramp_from: Option<usize>,
+    /// Requests that got a response, whatever its status.
+    pub(crate) requests: u64,
+    pub(crate) errors: u64,
+    pub(crate) rps: f64,
+    pub(crate) bytes: u64,
+    /// Responses by status class.
+    pub(crate) status: BTreeMap<String, u64>,
+    /// From when each request was due, for open-loop phases.
+    pub(crate) latency_ms: LatencySummary,
+    pub(crate) open_loop: Option<OpenLoopReport>,
+    /// Present when responses carried `X-Body-Sha256`.
+    pub(crate) checksum: Option<ChecksumReport>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct ChecksumReport {
+    /// Responses whose body was checked against `X-Body-Sha256`.
+    pub(crate) verified: u64,
+    /// Those whose body did not match.
+    pub(crate) mismatches: u64,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct OpenLoopReport {
+    pub(crate) target_rps: f64,
+    /// Requests that fell due but were never sent because every worker was
+    /// still waiting on a response when the phase ended.
+    pub(crate) unsent: u64,
+    /// Send to response, which is what a closed loop would have reported.
+    pub(crate) service_ms: LatencySummary,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub(crate) struct LatencySummary {
+    pub(crate) mean: f64,
+    pub(crate) p50: f64,
+    pub(crate) p90: f64,
+    pub(crate) p99: f64,
+    pub(crate) p999: f64,
+    pub(crate) max: f64,
+}
+
+impl LatencySummary {
+    fn from_sorted(us: &[u64]) -> Self {
+        if us.is_empty() {
+            return LatencySummary::default();
+        }
+        let ms = |v: u64| v as f64 / 1000.0;
+        LatencySummary {
+            mean: us.iter().sum::<u64>() as f64 / us.len() as f64 / 1000.0,
+            p50: ms(percentile(us, 50.0)),
+            p90: ms(percentile(us, 90.0)),
+            p99: ms(percentile(us, 99.0)),
+            p999: ms(percentile(us, 99.9)),
+            max: ms(us[us.len() - 1]),
+        }
+    }
+}
+
+/// Nearest-rank percentile of a sorted, non-empty slice.
+fn percentile(sorted: &[u64], q: f64) -> u64 {
+    // The epsilon keeps e.g. 99.9% of 1000 at rank 999 despite rounding.
+    let rank = ((q / 100.0) * sorted.len() as f64 - 1e-9).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nearest_rank_percentiles() {
+        let values: Vec<u64> = (1..=1000).collect();
+        assert_eq!(percentile(&values, 50.0), 500);
+        assert_eq!(percentile(&values, 99.0), 990);
+        assert_eq!(percentile(&values, 99.9), 999);
+        assert_eq!(percentile(&values, 100.0), 1000);
+        assert_eq!(percentile(&[7], 0.0), 7);
+    }
+}
@@ -0,0 +1,20 @@
+//! Build facts for the `/stats` fingerprint (see `src/fingerprint.rs`).
+
+use std::process::Command;
+
+fn main() {
+    let rustc = std::env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+    let rustc_version = Command::new(rustc)
+        .arg("--version")
+        .output()
+        .ok()
+        .and_then(|out| String::from_utf8(out.stdout).ok())
+        .map(|v| v.trim().to_string())
+        .unwrap_or_default();
+    println!("cargo:rustc-env=GATEWAY_RUSTC_VERSION={rustc_version}");
+    for var in ["PROFILE", "OPT_LEVEL", "TARGET"] {
+        let value = std::env::var(var).unwrap_or_default();
+        println!("cargo:rustc-env=GATEWAY_BUILD_{var}={value}");
+    }
+    println!("cargo:rerun-if-changed=build.rs");
+}
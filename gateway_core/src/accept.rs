@@ -0,0 +1,204 @@
+//! Error handling for the accept loop.
+//!
+//! `accept()` fails for two different reasons. A connection can die while it
+//! waits in the backlog (reset, aborted); that connection is simply skipped.
+//! The process can also run out of descriptors or buffers (`EMFILE`, `ENFILE`,
+//! `ENOBUFS`, `ENOMEM`); retrying at once fails the same way and spins the
+//! loop, so those back off from 10 ms, doubling up to 1 s, until an accept
+//! succeeds. A run of failures is logged when it starts and summarised when it
+//! ends instead of once per error.
+
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use crate::variant;
+
+const BACKOFF_START: Duration = Duration::from_millis(10);
+const BACKOFF_MAX: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AcceptError {
+    /// The pending connection failed; the next one is unaffected.
+    Connection,
+    /// Out of descriptors or memory; retrying immediately will fail again.
+    Exhausted,
+    /// Anything else, retried after a short pause.
+    Other,
+}
+
+impl AcceptError {
+    const ALL: [AcceptError; 3] = [
+        AcceptError::Connection,
+        AcceptError::Exhausted,
+        AcceptError::Other,
+    ];
+
+    pub fn classify(e: &io::Error) -> AcceptError {
+        match e.kind() {
+            io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::Interrupted
+            | io::ErrorKind::WouldBlock
+            | io::ErrorKind::TimedOut => return AcceptError::Connection,
+            io::ErrorKind::OutOfMemory => return AcceptError::Exhausted,
+            _ => {}
+        }
+        #[cfg(unix)]
+        if let Some(libc::EMFILE | libc::ENFILE | libc::ENOBUFS | libc::ENOMEM) = e.raw_os_error() {
+            return AcceptError::Exhausted;
+        }
+        #[cfg(unix)]
+        if let Some(libc::EPROTO | libc::ENETDOWN | libc::EHOSTUNREACH | libc::ENETUNREACH) =
+            e.raw_os_error()
+        {
+            return AcceptError::Connection;
+        }
+        AcceptError::Other
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            AcceptError::Connection => "connection",
+            AcceptError::Exhausted => "exhausted",
+            AcceptError::Other => "other",
+        }
+    }
+}
+
+static ERRORS: [AtomicU64; 3] = [AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0)];
+
+/// Accept failures since startup, by class.
+pub fn error_counts() -> [(AcceptError, u64); 3] {
+    AcceptError::ALL.map(|kind| (kind, ERRORS[kind as usize].load(Ordering::Relaxed)))
+}
+
+/// Backoff and log state for one accept loop.
+#[derive(Debug, Default)]
+pub struct AcceptBackoff {
+    delay: Option<Duration>,
+    /// Failures since the last successful accept.
+    failures: u64,
+}
+
+impl AcceptBackoff {
+    pub fn accepted(&mut self) {
+        if self.failures > 1 {
+            eprintln!(
+                "[{}] accept recovered after {} failed attempts",
+                variant(),
+                self.failures
+            );
+        }
+        self.delay = None;
+        self.failures = 0;
+    }
+
+    /// Counts and logs `e`, sleeping first when the loop should slow down.
+    pub fn failed(&mut self, e: &io::Error) {
+        let kind = AcceptError::classify(e);
+        ERRORS[kind as usize].fetch_add(1, Ordering::Relaxed);
+        if kind == AcceptError::Connection {
+            eprintln!("[{}] accept error ({}): {e}", variant(), kind.label());
+            return;
+        }
+        self.failures += 1;
+        let delay = match (kind, self.delay) {
+            (AcceptError::Exhausted, Some(prev)) => (prev * 2).min(BACKOFF_MAX),
+            _ => BACKOFF_START,
+        };
+        self.delay = Some(delay);
+        if self.failures == 1 {
+            eprintln!(
+                "[{}] accept error ({}): {e}; retrying with backoff",
+                variant(),
+                kind.label()
+            );
+        }
+        std::thread::sleep(delay);
+    }
+}
+
+/// Raises the soft `RLIMIT_NOFILE` to the hard limit, so the descriptor
+/// ceiling that triggers `EMFILE` is as high as the process is allowed.
+/// `RAISE_NOFILE=0` leaves the inherited limit alone.
+pub fn raise_nofile_limit() {
+    if std::env::var("RAISE_NOFILE").is_ok_and(|v| v == "0") {
+        return;
+    }
+    #[cfg(unix)]
+    {
+        let mut limit = libc::rlimit {
+            rlim_cur: 0,
+            rlim_max: 0,
+        };
+        if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } != 0 {
+            return;
+        }
+        // macOS rejects values above OPEN_MAX even when the hard limit is
+        // unlimited.
+        #[cfg(target_os = "macos")]
+        let target = limit.rlim_max.min(libc::OPEN_MAX as libc::rlim_t);
+        #[cfg(not(target_os = "macos"))]
+        let target = limit.rlim_max;
+        if limit.rlim_cur >= target {
+            return;
+        }
+        let previous = limit.rlim_cur;
+        limit.rlim_cur = target;
+        if unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &limit) } == 0 {
+            eprintln!(
+                "[{}] raised open file limit from {previous} to {target}",
+                variant()
+            );
+        } else {
+            eprintln!(
+                "[{}] could not raise open file limit: {}",
+                variant(),
+                io::Error::last_os_error()
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classifies_accept_errors() {
+        let kind = |e: io::Error| AcceptError::classify(&e);
+        assert_eq!(
+            kind(io::ErrorKind::ConnectionAborted.into()),
+            AcceptError::Connection
+        );
+        assert_eq!(kind(io::ErrorKind::Other.into()), AcceptError::Other);
+        #[cfg(unix)]
+        {
+            assert_eq!(
+                kind(io::Error::from_raw_os_error(libc::EMFILE)),
+                AcceptError::Exhausted
+            );
+            assert_eq!(
+                kind(io::Error::from_raw_os_error(libc::ENFILE)),
+                AcceptError::Exhausted
+            );
+            assert_eq!(
+                kind(io::Error::from_raw_os_error(libc::EPROTO)),
+                AcceptError::Connection
+            );
+        }
+    }
+
+    #[test]
+    fn exhaustion_backoff_doubles_and_resets() {
+        let mut backoff = AcceptBackoff::default();
+        let emfile = io::Error::from(io::ErrorKind::OutOfMemory);
+        backoff.failed(&emfile);
+        assert_eq!(backoff.delay, Some(BACKOFF_START));
+        backoff.failed(&emfile);
+        assert_eq!(backoff.delay, Some(BACKOFF_START * 2));
+        backoff.accepted();
+        assert_eq!((backoff.delay, backoff.failures), (None, 0));
+    }
+}
@@ -0,0 +1,97 @@
+//! CPU pinning for benchmark runs (Linux).
+//!
+//! `CPU_AFFINITY=0-3,6` restricts the gateway to those cores. It is applied in
+//! `main` before any thread starts, so the accept loops, worker threads and
+//! every child process inherit the same mask; keeping both gateways on the
+//! same cores reduces run-to-run variance between them.
+
+use anyhow::{anyhow, Context, Result};
+
+use crate::variant;
+
+/// Parses a `taskset`-style list: comma-separated core numbers and inclusive
+/// `a-b` ranges.
+pub fn parse_cpu_list(spec: &str) -> Result<Vec<usize>> {
+    let mut cpus = Vec::new();
+    for part in spec.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+        let (first, last) = match part.split_once('-') {
+            Some((a, b)) => (a.trim(), b.trim()),
+            None => (part, part),
+        };
+        let first: usize = first
+            .parse()
+            .with_context(|| format!("invalid CPU {first:?}"))?;
+        let last: usize = last
+            .parse()
+            .with_context(|| format!("invalid CPU {last:?}"))?;
+        if first > last {
+            return Err(anyhow!("invalid CPU range {part:?}"));
+        }
+        cpus.extend(first..=last);
+    }
+    cpus.sort_unstable();
+    cpus.dedup();
+    if cpus.is_empty() {
+        return Err(anyhow!("empty CPU list"));
+    }
+    Ok(cpus)
+}
+
+/// The CPU list in `var`, or `None` when unset.
+pub fn cpus_from_env(var: &str) -> Result<Option<Vec<usize>>> {
+    match std::env::var(var) {
+        Ok(v) if !v.trim().is_empty() => {
+            let cpus = parse_cpu_list(&v).with_context(|| format!("invalid {var}={v}"))?;
+            if !cfg!(target_os = "linux") {
+                return Err(anyhow!("{var} is only supported on Linux"));
+            }
+            Ok(Some(cpus))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Pins the whole process to `CPU_AFFINITY`, if set.
+pub fn pin_process_from_env() -> Result<()> {
+    let Some(cpus) = cpus_from_env("CPU_AFFINITY")? else {
+        return Ok(());
+    };
+    #[cfg(target_os = "linux")]
+    {
+        let set = cpu_set(&cpus)?;
+        let size = std::mem::size_of::<libc::cpu_set_t>();
+        if unsafe { libc::sched_setaffinity(0, size, &set) } != 0 {
+            return Err(anyhow::Error::new(std::io::Error::last_os_error()))
+                .with_context(|| format!("failed to pin to CPUs {cpus:?}"));
+        }
+    }
+    eprintln!("[{}] pinned to CPUs {cpus:?}", variant());
+    Ok(())
+}
+
+/// Builds the kernel mask for `cpus`.
+#[cfg(target_os = "linux")]
+pub fn cpu_set(cpus: &[usize]) -> Result<libc::cpu_set_t> {
+    let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+    for &cpu in cpus {
+        if cpu >= libc::CPU_SETSIZE as usize {
+            return Err(anyhow!("CPU {cpu} is out of range"));
+        }
+        unsafe { libc::CPU_SET(cpu, &mut set) };
+    }
+    Ok(set)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_cpu_lists() {
+        assert_eq!(parse_cpu_list("0-3").unwrap(), [0, 1, 2, 3]);
+        assert_eq!(parse_cpu_list("6, 2-3,2").unwrap(), [2, 3, 6]);
+        assert!(parse_cpu_list("").is_err());
+        assert!(parse_cpu_list("3-1").is_err());
+        assert!(parse_cpu_list("a").is_err());
+    }
+}
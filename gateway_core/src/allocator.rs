@@ -0,0 +1,294 @@
+//! Global allocator and allocation statistics (`GET /debug/allocator`).
+//!
+//! The `jemalloc` and `mimalloc` cargo features replace the system allocator.
+//! Whichever is in use, debug builds also count the allocations each request
+//! makes on its handling thread, by route, which shows how much of the proxy
+//! path is spent copying buffers; release builds skip the counting.
+
+use std::alloc::{GlobalAlloc, Layout};
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::sync::Mutex;
+
+pub const ROUTE: &str = "/debug/allocator";
+
+#[cfg(all(feature = "jemalloc", feature = "mimalloc"))]
+compile_error!("enable at most one of the `jemalloc` and `mimalloc` features");
+
+#[cfg(feature = "jemalloc")]
+pub const NAME: &str = "jemalloc";
+#[cfg(feature = "jemalloc")]
+#[global_allocator]
+static GLOBAL: Counting<tikv_jemallocator::Jemalloc> = Counting(tikv_jemallocator::Jemalloc);
+
+#[cfg(all(feature = "mimalloc", not(feature = "jemalloc")))]
+pub const NAME: &str = "mimalloc";
+#[cfg(all(feature = "mimalloc", not(feature = "jemalloc")))]
+#[global_allocator]
+static GLOBAL: Counting<mimalloc::MiMalloc> = Counting(mimalloc::MiMalloc);
+
+#[cfg(not(any(feature = "jemalloc", feature = "mimalloc")))]
+pub const NAME: &str = "system";
+#[cfg(not(any(feature = "jemalloc", feature = "mimalloc")))]
+#[global_allocator]
+static GLOBAL: Counting<std::alloc::System> = Counting(std::alloc::System);
+
+/// Route prefixes counted separately; everything else is forwarded upstream.
+const LABELS: [&str; 11] = [
+    "/upload",
+    "/echo",
+    "/health",
+    "/metrics",
+    "/stats",
+    "/compute",
+    "/render",
+    "/transform",
+    "/admin",
+    "/state",
+    "/debug",
+];
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+struct Counts {
+    allocations: u64,
+    bytes: u64,
+}
+
+thread_local! {
+    static THREAD: Cell<Counts> = const {
+        Cell::new(Counts {
+            allocations: 0,
+            bytes: 0,
+        })
+    };
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+struct RouteAllocs {
+    requests: u64,
+    allocations: u64,
+    bytes: u64,
+}
+
+static ROUTES: Mutex<BTreeMap<&str, RouteAllocs>> = Mutex::new(BTreeMap::new());
+
+/// Passes everything to `A`; in debug builds also counts allocations on the
+/// calling thread. A `realloc` counts as an allocation of the new size.
+struct Counting<A>(A);
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for Counting<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        count(layout.size());
+        self.0.alloc(layout)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        count(layout.size());
+        self.0.alloc_zeroed(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.0.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        count(new_size);
+        self.0.realloc(ptr, layout, new_size)
+    }
+}
+
+#[inline]
+fn count(bytes: usize) {
+    if cfg!(debug_assertions) {
+        // `try_with`: the slot is gone while the thread shuts down.
+        let _ = THREAD.try_with(|slot| {
+            let mut counts = slot.get();
+            counts.allocations += 1;
+            counts.bytes += bytes as u64;
+            slot.set(counts);
+        });
+    }
+}
+
+fn thread_counts() -> Counts {
+    THREAD.try_with(Cell::get).unwrap_or_default()
+}
+
+/// One request's allocations on the current thread, added to its route's
+/// totals when dropped (debug builds only).
+pub struct RequestAllocs {
+    start: Counts,
+    route: &'static str,
+}
+
+impl RequestAllocs {
+    pub fn start() -> Self {
+        RequestAllocs {
+            start: thread_counts(),
+            route: "unparsed",
+        }
+    }
+
+    pub fn route(&mut self, path: &str) {
+        self.route = route_label(path);
+    }
+}
+
+impl Drop for RequestAllocs {
+    fn drop(&mut self) {
+        if !cfg!(debug_assertions) {
+            return;
+        }
+        let now = thread_counts();
+        let mut routes = ROUTES.lock().unwrap_or_else(|e| e.into_inner());
+        let totals = routes.entry(self.route).or_default();
+        totals.requests += 1;
+        totals.allocations += now.allocations - self.start.allocations;
+        totals.bytes += now.bytes - self.start.bytes;
+    }
+}
+
+fn route_label(path: &str) -> &'static str {
+    let path = crate::http::route_path(path);
+    if path == "/" {
+        return "/";
+    }
+    LABELS
+        .iter()
+        .find(|label| {
+            path.strip_prefix(**label)
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+        })
+        .copied()
+        .unwrap_or("proxy")
+}
+
+/// Allocator-reported figures, in bytes unless named otherwise.
+#[cfg(feature = "jemalloc")]
+fn allocator_stats() -> Vec<(&'static str, u64)> {
+    use tikv_jemalloc_ctl::{epoch, stats};
+    // jemalloc caches its statistics until the epoch advances.
+    if epoch::advance().is_err() {
+        return Vec::new();
+    }
+    [
+        ("allocated", stats::allocated::read()),
+        ("active", stats::active::read()),
+        ("resident", stats::resident::read()),
+        ("mapped", stats::mapped::read()),
+        ("retained", stats::retained::read()),
+        ("metadata", stats::metadata::read()),
+    ]
+    .into_iter()
+    .filter_map(|(name, value)| value.ok().map(|v| (name, v as u64)))
+    .collect()
+}
+
+#[cfg(all(feature = "mimalloc", not(feature = "jemalloc")))]
+fn allocator_stats() -> Vec<(&'static str, u64)> {
+    let mut info = [0usize; 8];
+    {
+        let [elapsed_ms, user_ms, system_ms, rss, peak_rss, commit, peak_commit, faults] =
+            &mut info;
+        unsafe {
+            libmimalloc_sys::mi_process_info(
+                elapsed_ms,
+                user_ms,
+                system_ms,
+                rss,
+                peak_rss,
+                commit,
+                peak_commit,
+                faults,
+            )
+        };
+    }
+    let [_, _, _, rss, peak_rss, commit, peak_commit, faults] = info;
+    vec![
+        ("resident", rss as u64),
+        ("peak_resident", peak_rss as u64),
+        ("committed", commit as u64),
+        ("peak_committed", peak_commit as u64),
+        ("page_faults", faults as u64),
+    ]
+}
+
+#[cfg(not(any(feature = "jemalloc", feature = "mimalloc")))]
+fn allocator_stats() -> Vec<(&'static str, u64)> {
+    #[cfg(all(target_os = "linux", target_env = "gnu"))]
+    {
+        let info = unsafe { libc::mallinfo2() };
+        vec![
+            ("arena", info.arena as u64),
+            ("in_use", info.uordblks as u64),
+            ("free", info.fordblks as u64),
+            ("mmap", info.hblkhd as u64),
+        ]
+    }
+    #[cfg(not(all(target_os = "linux", target_env = "gnu")))]
+    Vec::new()
+}
+
+/// The `/debug/allocator` body: allocator name and figures, and per-route
+/// request allocation totals (`null` in release builds).
+pub fn report_json() -> String {
+    let mut out = format!("{{\"allocator\":\"{NAME}\",\"stats\":{{");
+    for (i, (name, value)) in allocator_stats().into_iter().enumerate() {
+        let sep = if i == 0 { "" } else { "," };
+        let _ = write!(out, "{sep}\"{name}\":{value}");
+    }
+    out.push_str("},\"per_request\":");
+    if cfg!(debug_assertions) {
+        out.push('{');
+        let routes = ROUTES.lock().unwrap_or_else(|e| e.into_inner()).clone();
+        for (i, (route, totals)) in routes.iter().enumerate() {
+            let sep = if i == 0 { "" } else { "," };
+            let per = |n: u64| n as f64 / totals.requests.max(1) as f64;
+            let _ = write!(
+                out,
+                "{sep}\"{route}\":{{\"requests\":{},\"allocations\":{},\"bytes\":{},\
+                 \"allocations_per_request\":{:.1},\"bytes_per_request\":{:.1}}}",
+                totals.requests,
+                totals.allocations,
+                totals.bytes,
+                per(totals.allocations),
+                per(totals.bytes),
+            );
+        }
+        out.push('}');
+    } else {
+        out.push_str("null");
+    }
+    out.push('}');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn labels_routes() {
+        assert_eq!(route_label("/"), "/");
+        assert_eq!(route_label("/health/full"), "/health");
+        assert_eq!(route_label("/compute?iters=5"), "/compute");
+        assert_eq!(route_label("/transform/batch"), "/transform");
+        assert_eq!(route_label("/computer"), "proxy");
+        assert_eq!(route_label("/api/users"), "proxy");
+    }
+
+    #[test]
+    fn counts_allocations_on_this_thread() {
+        let before = thread_counts();
+        let buf = std::hint::black_box(vec![0u8; 4096]);
+        let after = thread_counts();
+        drop(buf);
+        if cfg!(debug_assertions) {
+            assert!(after.allocations > before.allocations);
+            assert!(after.bytes - before.bytes >= 4096);
+        } else {
+            assert_eq!(after, before);
+        }
+    }
+}
@@ -0,0 +1,515 @@
+//! Weighted round robin over `UPSTREAM_POOL`, with outlier ejection.
+//!
+//! `UPSTREAM_POOL=http://a:8080*3,http://b:8080` sends three requests to `a`
+//! for every one to `b`, interleaved (smooth WRR, as in nginx). Each member
+//! keeps a sliding window (`POOL_WINDOW_SECS`) of its latencies and failures;
+//! a connection error, unreadable response or 5xx counts as a failure. Once a
+//! window holds `POOL_MIN_REQUESTS`, a member whose failure rate exceeds
+//! `POOL_MAX_ERROR_RATE` or whose p99 exceeds `POOL_MAX_P99_MS` is ejected for
+//! `POOL_EJECT_SECS`. It then comes back with a weight that ramps from 1 to
+//! its configured weight over `POOL_RAMP_SECS`. The last member still in
+//! rotation is never ejected.
+//!
+//! Members can be replaced while the gateway runs (service discovery); those
+//! kept by name keep their window, ejection and ramp. An empty pool picks
+//! nothing and requests fall back to `UPSTREAM_URL`.
+
+use anyhow::{anyhow, Context, Result};
+use std::cell::Cell;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::variant;
+
+/// Samples kept per member, whatever the window length.
+const MAX_SAMPLES: usize = 1000;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct OutlierSettings {
+    pub window: Duration,
+    pub min_requests: usize,
+    pub max_error_rate: f64,
+    pub max_p99: Option<Duration>,
+    pub eject_for: Duration,
+    pub ramp: Duration,
+}
+
+impl Default for OutlierSettings {
+    fn default() -> Self {
+        OutlierSettings {
+            window: Duration::from_secs(30),
+            min_requests: 20,
+            max_error_rate: 0.5,
+            max_p99: None,
+            eject_for: Duration::from_secs(30),
+            ramp: Duration::from_secs(30),
+        }
+    }
+}
+
+impl OutlierSettings {
+    pub fn from_env() -> Result<Self> {
+        let var = |name: &str| std::env::var(name).ok().filter(|v| !v.trim().is_empty());
+        let secs = |name: &str, default: Duration| -> Result<Duration> {
+            var(name).map_or(Ok(default), |v| {
+                v.trim()
+                    .parse::<u64>()
+                    .map(Duration::from_secs)
+                    .with_context(|| format!("invalid {name}={v}"))
+            })
+        };
+        let defaults = OutlierSettings::default();
+        let max_error_rate = match var("POOL_MAX_ERROR_RATE") {
+            Some(v) => match v.trim().parse::<f64>() {
+                Ok(rate) if (0.0..=1.0).contains(&rate) => rate,
+                _ => return Err(anyhow!("invalid POOL_MAX_ERROR_RATE={v} (expected 0..1)")),
+            },
+            None => defaults.max_error_rate,
+        };
+        Ok(OutlierSettings {
+            window: secs("POOL_WINDOW_SECS", defaults.window)?,
+            min_requests: match var("POOL_MIN_REQUESTS") {
+                Some(v) => v
+                    .trim()
+                    .parse()
+                    .with_context(|| format!("invalid POOL_MIN_REQUESTS={v}"))?,
+                None => defaults.min_requests,
+            },
+            max_error_rate,
+            max_p99: var("POOL_MAX_P99_MS")
+                .map(|v| {
+                    v.trim()
+                        .parse::<u64>()
+                        .map(Duration::from_millis)
+                        .with_context(|| format!("invalid POOL_MAX_P99_MS={v}"))
+                })
+                .transpose()?,
+            eject_for: secs("POOL_EJECT_SECS", defaults.eject_for)?,
+            ramp: secs("POOL_RAMP_SECS", defaults.ramp)?,
+        })
+    }
+}
+
+/// `url[*weight],...`; weights default to 1.
+pub fn parse_pool(spec: &str) -> Result<Vec<(&str, u32)>> {
+    let members = spec
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| match entry.rsplit_once('*') {
+            Some((url, weight)) => match weight.trim().parse::<u32>() {
+                Ok(weight) if weight > 0 => Ok((url.trim(), weight)),
+                _ => Err(anyhow!("invalid UPSTREAM_POOL weight in {entry:?}")),
+            },
+            None => Ok((entry, 1)),
+        })
+        .collect::<Result<Vec<_>>>()?;
+    if members.is_empty() {
+        return Err(anyhow!("UPSTREAM_POOL has no members"));
+    }
+    Ok(members)
+}
+
+#[derive(Debug)]
+pub struct Balancer<T> {
+    settings: OutlierSettings,
+    slots: Mutex<Vec<Slot<T>>>,
+}
+
+#[derive(Debug)]
+pub struct Member<T> {
+    /// For logs.
+    pub name: String,
+    pub weight: u32,
+    pub target: T,
+}
+
+#[derive(Debug)]
+struct Slot<T> {
+    member: Arc<Member<T>>,
+    state: MemberState,
+}
+
+#[derive(Debug, Default)]
+struct MemberState {
+    /// Smooth WRR running weight.
+    current: i64,
+    samples: VecDeque<Sample>,
+    ejected_until: Option<Instant>,
+    /// Reintroduced at this time; the weight ramps up from here.
+    ramp_from: Option<Instant>,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Sample {
+    at: Instant,
+    latency: Duration,
+    failed: bool,
+}
+
+/// The member a request goes to. Call `finish` with the upstream status, or
+/// `release` when the request was answered without it (from a cache); a pick
+/// dropped without either (the exchange failed) is recorded as a failure.
+pub struct Pick<'a, T> {
+    balancer: &'a Balancer<T>,
+    member: Arc<Member<T>>,
+    started: Instant,
+    finished: Cell<bool>,
+}
+
+impl<T> std::ops::Deref for Pick<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.member.target
+    }
+}
+
+impl<T> Pick<'_, T> {
+    /// Takes `&self` so the target can stay borrowed; later calls do nothing.
+    pub fn finish(&self, status: u16) {
+        if !self.finished.replace(true) {
+            self.balancer.record(
+                &self.member,
+                self.started.elapsed(),
+                status >= 500,
+                Instant::now(),
+            );
+        }
+    }
+}
+
+impl<T> Pick<'_, T> {
+    /// The member was not contacted after all; nothing is recorded for it.
+    pub fn release(&self) {
+        self.finished.set(true);
+    }
+}
+
+impl<T> Drop for Pick<'_, T> {
+    fn drop(&mut self) {
+        if !self.finished.get() {
+            self.balancer
+                .record(&self.member, self.started.elapsed(), true, Instant::now());
+        }
+    }
+}
+
+impl<T> Balancer<T> {
+    /// `members` are `(name for logs, weight, target)`.
+    pub fn new(members: Vec<(String, u32, T)>, settings: OutlierSettings) -> Self {
+        let balancer = Balancer {
+            settings,
+            slots: Mutex::default(),
+        };
+        balancer.replace_members(members);
+        balancer
+    }
+
+    /// Swaps in a new member list. Members whose name was already in the pool
+    /// keep their state; picks of removed members still finish harmlessly.
+    pub fn replace_members(&self, members: Vec<(String, u32, T)>) {
+        let mut slots = self.lock();
+        let mut old = std::mem::take(&mut *slots);
+        for (name, weight, target) in members {
+            let state = old
+                .iter()
+                .position(|slot| slot.member.name == name)
+                .map(|i| old.swap_remove(i).state)
+                .unwrap_or_default();
+            slots.push(Slot {
+                member: Arc::new(Member {
+                    name,
+                    weight,
+                    target,
+                }),
+                state,
+            });
+        }
+    }
+
+    /// Every member, at this point in time.
+    pub fn members(&self) -> Vec<Arc<Member<T>>> {
+        self.lock()
+            .iter()
+            .map(|slot| Arc::clone(&slot.member))
+            .collect()
+    }
+
+    /// `None` while the pool is empty.
+    pub fn pick(&self) -> Option<Pick<'_, T>> {
+        let now = Instant::now();
+        let member = {
+            let mut slots = self.lock();
+            let index = self.pick_index(&mut slots, now)?;
+            Arc::clone(&slots[index].member)
+        };
+        Some(Pick {
+            balancer: self,
+            member,
+            started: now,
+            finished: Cell::new(false),
+        })
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Vec<Slot<T>>> {
+        self.slots.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn pick_index(&self, slots: &mut [Slot<T>], now: Instant) -> Option<usize> {
+        if slots.is_empty() {
+            return None;
+        }
+        for Slot { member, state: s } in slots.iter_mut() {
+            if s.ejected_until.is_some_and(|until| until <= now) {
+                s.ejected_until = None;
+                s.ramp_from = Some(now);
+                s.samples.clear();
+                eprintln!(
+                    "[{}] upstream {} back in rotation, ramping to weight {} over {:?}",
+                    variant(),
+                    member.name,
+                    member.weight,
+                    self.settings.ramp
+                );
+            }
+        }
+        let weights: Vec<i64> = slots
+            .iter()
+            .map(|slot| self.effective_weight(&slot.member, &slot.state, now))
+            .collect();
+        let total: i64 = weights.iter().sum();
+        for (slot, weight) in slots.iter_mut().zip(&weights) {
+            slot.state.current += weight;
+        }
+        // Highest running weight wins; ties go to the earlier member.
+        let mut best = 0;
+        for i in 1..slots.len() {
+            if weights[i] > 0
+                && (weights[best] == 0 || slots[i].state.current > slots[best].state.current)
+            {
+                best = i;
+            }
+        }
+        slots[best].state.current -= total;
+        Some(best)
+    }
+
+    fn effective_weight(&self, member: &Member<T>, s: &MemberState, now: Instant) -> i64 {
+        if s.ejected_until.is_some() {
+            return 0;
+        }
+        let full = i64::from(member.weight);
+        match s.ramp_from.map(|from| now.duration_since(from)) {
+            Some(elapsed) if elapsed < self.settings.ramp => {
+                let share = elapsed.as_secs_f64() / self.settings.ramp.as_secs_f64();
+                ((full as f64 * share).ceil() as i64).clamp(1, full)
+            }
+            _ => full,
+        }
+    }
+
+    fn record(&self, member: &Arc<Member<T>>, latency: Duration, failed: bool, now: Instant) {
+        let mut slots = self.lock();
+        let in_rotation = slots
+            .iter()
+            .filter(|slot| slot.state.ejected_until.is_none())
+            .count();
+        // Gone if the pool was replaced meanwhile.
+        let Some(slot) = slots
+            .iter_mut()
+            .find(|slot| Arc::ptr_eq(&slot.member, member))
+        else {
+            return;
+        };
+        let s = &mut slot.state;
+        if s.ejected_until.is_some() {
+            return;
+        }
+        let window = self.settings.window;
+        while s
+            .samples
+            .front()
+            .is_some_and(|sample| now.duration_since(sample.at) > window)
+        {
+            s.samples.pop_front();
+        }
+        if s.samples.len() == MAX_SAMPLES {
+            s.samples.pop_front();
+        }
+        s.samples.push_back(Sample {
+            at: now,
+            latency,
+            failed,
+        });
+        if s.samples.len() < self.settings.min_requests.max(1) || in_rotation <= 1 {
+            return;
+        }
+        let failures = s.samples.iter().filter(|sample| sample.failed).count();
+        let error_rate = failures as f64 / s.samples.len() as f64;
+        let mut latencies: Vec<Duration> = s.samples.iter().map(|sample| sample.latency).collect();
+        latencies.sort_unstable();
+        let p99 = latencies[(latencies.len() * 99).div_ceil(100) - 1];
+        let slow = self.settings.max_p99.is_some_and(|max| p99 > max);
+        if error_rate > self.settings.max_error_rate || slow {
+            eprintln!(
+                "[{}] upstream {} ejected for {:?}: error rate {:.2}, p99 {:?} over {} requests",
+                variant(),
+                member.name,
+                self.settings.eject_for,
+                error_rate,
+                p99,
+                s.samples.len()
+            );
+            s.ejected_until = Some(now + self.settings.eject_for);
+            s.ramp_from = None;
+            s.current = 0;
+            s.samples.clear();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn balancer(weights: &[u32], settings: OutlierSettings) -> Balancer<usize> {
+        Balancer::new(
+            weights
+                .iter()
+                .enumerate()
+                .map(|(i, w)| (format!("m{i}"), *w, i))
+                .collect(),
+            settings,
+        )
+    }
+
+    /// Picks at `now`; members' targets are their indexes.
+    fn pick_at(b: &Balancer<usize>, now: Instant) -> usize {
+        let mut slots = b.lock();
+        let index = b.pick_index(&mut slots, now).unwrap();
+        slots[index].member.target
+    }
+
+    fn record(b: &Balancer<usize>, index: usize, latency: Duration, failed: bool, now: Instant) {
+        b.record(&b.members()[index], latency, failed, now);
+    }
+
+    #[test]
+    fn parses_pool_spec() {
+        assert_eq!(
+            parse_pool("http://a:1*3, http://b:2").unwrap(),
+            [("http://a:1", 3), ("http://b:2", 1)]
+        );
+        assert!(parse_pool("http://a:1*0").is_err());
+        assert!(parse_pool(" , ").is_err());
+    }
+
+    #[test]
+    fn smooth_weighted_round_robin() {
+        let b = balancer(&[5, 1, 1], OutlierSettings::default());
+        let now = Instant::now();
+        let picks: Vec<usize> = (0..7).map(|_| pick_at(&b, now)).collect();
+        assert_eq!(picks, [0, 0, 1, 0, 2, 0, 0]);
+    }
+
+    #[test]
+    fn ejects_outliers_and_ramps_them_back() {
+        let settings = OutlierSettings {
+            min_requests: 4,
+            max_p99: Some(Duration::from_millis(100)),
+            ramp: Duration::from_secs(10),
+            ..OutlierSettings::default()
+        };
+        let b = balancer(&[4, 4], settings);
+        let start = Instant::now();
+        let fast = Duration::from_millis(5);
+        for _ in 0..4 {
+            record(&b, 0, fast, true, start);
+            record(&b, 1, fast, false, start);
+        }
+        assert!((0..4).all(|_| pick_at(&b, start) == 1));
+
+        // Back after POOL_EJECT_SECS with weight 1 of 4, then full weight.
+        let back = start + settings.eject_for;
+        let picks: Vec<usize> = (0..5).map(|_| pick_at(&b, back)).collect();
+        assert_eq!(picks.iter().filter(|&&i| i == 0).count(), 1);
+        let ramped = back + settings.ramp;
+        let picks: Vec<usize> = (0..8).map(|_| pick_at(&b, ramped)).collect();
+        assert_eq!(picks.iter().filter(|&&i| i == 0).count(), 4);
+
+        // Slow responses eject too, but never the last member in rotation.
+        for _ in 0..4 {
+            record(&b, 1, Duration::from_millis(500), false, ramped);
+        }
+        for _ in 0..4 {
+            record(&b, 0, fast, true, ramped);
+        }
+        assert!((0..4).all(|_| pick_at(&b, ramped) == 0));
+    }
+
+    #[test]
+    fn unfinished_pick_counts_as_failure() {
+        let settings = OutlierSettings {
+            min_requests: 2,
+            ..OutlierSettings::default()
+        };
+        let b = balancer(&[1, 1], settings);
+        for _ in 0..4 {
+            let pick = b.pick().unwrap();
+            if *pick == 0 {
+                drop(pick);
+            } else {
+                pick.finish(200);
+            }
+        }
+        assert!((0..4).all(|_| *b.pick().unwrap() == 1));
+    }
+
+    #[test]
+    fn released_picks_record_nothing() {
+        let settings = OutlierSettings {
+            min_requests: 2,
+            ..OutlierSettings::default()
+        };
+        let b = balancer(&[1, 1], settings);
+        for _ in 0..4 {
+            let pick = b.pick().unwrap();
+            pick.release();
+            // Neither a success nor, once dropped, a failure.
+            pick.finish(500);
+        }
+        let picks: Vec<usize> = (0..4).map(|_| *b.pick().unwrap()).collect();
+        assert_eq!(picks, [0, 1, 0, 1]);
+    }
+
+    #[test]
+    fn replaced_members_keep_their_state() {
+        let settings = OutlierSettings {
+            min_requests: 2,
+            ..OutlierSettings::default()
+        };
+        let b = balancer(&[1, 1], settings);
+        let now = Instant::now();
+        for _ in 0..2 {
+            record(&b, 0, Duration::from_millis(5), true, now);
+        }
+        let stale = b.pick().unwrap();
+        b.replace_members(vec![
+            ("m0".to_string(), 1, 0),
+            ("m2".to_string(), 1, 2),
+            ("m1".to_string(), 1, 1),
+        ]);
+        // A pick of the old list finishes without touching the new one.
+        stale.finish(500);
+        drop(stale);
+        let names: Vec<String> = b.members().iter().map(|m| m.name.clone()).collect();
+        assert_eq!(names, ["m0", "m2", "m1"]);
+        // `m0` is still ejected.
+        assert!((0..4).all(|_| pick_at(&b, now) != 0));
+
+        b.replace_members(Vec::new());
+        assert!(b.pick().is_none());
+    }
+}
@@ -0,0 +1,317 @@
+//! `Transfer-Encoding: chunked` bodies on the proxy path.
+//!
+//! Upstream responses are read to EOF and then decoded here, keeping the
+//! trailer fields after the last chunk (`grpc-status`, checksums) so they can
+//! be sent on to the client instead of being dropped.
+//!
+//! `decode_response` also owns the framing decision for upstream responses:
+//! a body must be readable exactly one way (RFC 9112 §6.3). A message with
+//! both `Transfer-Encoding` and `Content-Length`, Content-Length values that
+//! disagree, or a body that does not match its declared length is refused
+//! rather than guessed at, since the guess is what smuggling and truncation
+//! rely on.
+//!
+//! `audit_response` applies the same rules to the gateway's own responses
+//! (`FRAMING_AUDIT` in `gateway_host`).
+
+use anyhow::{anyhow, Context, Result};
+
+use crate::headers::Headers;
+
+/// Error marker for an upstream response whose framing cannot be trusted;
+/// the proxy path answers it with a 502.
+#[derive(Debug)]
+pub struct AmbiguousFraming(pub String);
+
+impl std::fmt::Display for AmbiguousFraming {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "ambiguous upstream response framing: {}", self.0)
+    }
+}
+
+impl std::error::Error for AmbiguousFraming {}
+
+/// Payload and trailers of an upstream response whose body was read to EOF.
+/// `bodyless` is for responses that carry no body whatever their headers say
+/// (to `HEAD`, and 1xx/204/304); anything read after them is dropped.
+pub fn decode_response(
+    headers: &Headers,
+    body: Vec<u8>,
+    bodyless: bool,
+) -> Result<(Vec<u8>, Headers)> {
+    let ambiguous = |reason: String| anyhow::Error::new(AmbiguousFraming(reason));
+    let chunked = headers.get("transfer-encoding").is_some();
+    let mut lengths = headers
+        .get_all("content-length")
+        .flat_map(|v| v.split(','))
+        .map(str::trim);
+    let length = match lengths.next() {
+        Some(first) if chunked => {
+            return Err(ambiguous(format!(
+                "Transfer-Encoding with Content-Length {first}"
+            )))
+        }
+        Some(first) => {
+            if lengths.any(|other| other != first) {
+                return Err(ambiguous("conflicting Content-Length values".to_string()));
+            }
+            let valid = !first.is_empty() && first.bytes().all(|b| b.is_ascii_digit());
+            match first.parse::<usize>() {
+                Ok(n) if valid => Some(n),
+                _ => return Err(ambiguous(format!("invalid Content-Length {first:?}"))),
+            }
+        }
+        None => None,
+    };
+    if bodyless {
+        return Ok((Vec::new(), Headers::default()));
+    }
+    if chunked {
+        if !is_chunked(headers) {
+            return Err(ambiguous(
+                "Transfer-Encoding does not end in chunked".to_string(),
+            ));
+        }
+        return decode(&body).map_err(|e| ambiguous(format!("{e:#}")));
+    }
+    match length {
+        Some(n) if n != body.len() => Err(ambiguous(format!(
+            "Content-Length {n} but {} body bytes",
+            body.len()
+        ))),
+        _ => Ok((body, Headers::default())),
+    }
+}
+
+/// True when `Transfer-Encoding` ends in `chunked`, the only case where the
+/// body is chunk-framed.
+pub fn is_chunked(headers: &Headers) -> bool {
+    headers
+        .get_all("transfer-encoding")
+        .flat_map(|v| v.split(','))
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
+        .last()
+        .is_some_and(|coding| coding.eq_ignore_ascii_case("chunked"))
+}
+
+/// Decodes a complete chunked body into its payload and trailer fields.
+/// Bytes after the trailer section are an error, not a second message.
+pub fn decode(mut body: &[u8]) -> Result<(Vec<u8>, Headers)> {
+    let mut payload = Vec::new();
+    loop {
+        let line_end = find_crlf(body).ok_or_else(|| anyhow!("truncated chunk size line"))?;
+        let line = std::str::from_utf8(&body[..line_end]).context("chunk size not UTF-8")?;
+        let size_hex = line.split(';').next().unwrap_or("").trim();
+        let size = usize::from_str_radix(size_hex, 16)
+            .with_context(|| format!("invalid chunk size {size_hex:?}"))?;
+        body = &body[line_end + 2..];
+        if size == 0 {
+            break;
+        }
+        if body.len() < size + 2 || &body[size..size + 2] != b"\r\n" {
+            return Err(anyhow!("truncated chunk of {size} bytes"));
+        }
+        payload.extend_from_slice(&body[..size]);
+        body = &body[size + 2..];
+    }
+    // Trailer section: field lines up to the final empty line.
+    let end = if body.starts_with(b"\r\n") {
+        0
+    } else {
+        body.windows(4)
+            .position(|w| w == b"\r\n\r\n")
+            .map(|i| i + 2)
+            .ok_or_else(|| anyhow!("truncated trailer section"))?
+    };
+    if body.len() > end + 2 {
+        return Err(anyhow!(
+            "{} bytes after the chunked body",
+            body.len() - end - 2
+        ));
+    }
+    let trailers = std::str::from_utf8(&body[..end]).context("trailers not UTF-8")?;
+    Ok((payload, Headers::parse(trailers)))
+}
+
+/// Writes `payload` as a single chunk followed by the last chunk and
+/// `trailers`.
+pub fn encode(out: &mut Vec<u8>, payload: &[u8], trailers: &Headers) {
+    if !payload.is_empty() {
+        out.extend_from_slice(format!("{:x}\r\n", payload.len()).as_bytes());
+        out.extend_from_slice(payload);
+        out.extend_from_slice(b"\r\n");
+    }
+    out.extend_from_slice(b"0\r\n");
+    trailers.write_to(out);
+    out.extend_from_slice(b"\r\n");
+}
+
+/// Checks a complete outgoing response (head and body) the way
+/// `decode_response` checks an upstream one: the body must be exactly what
+/// its `Content-Length` or chunked framing says. `head_request` marks an
+/// answer to `HEAD`, which must carry no body bytes at all.
+pub fn audit_response(resp: &[u8], head_request: bool) -> Result<(), String> {
+    let head_end = resp
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .ok_or("no end of head")?;
+    let (status_line, headers) =
+        Headers::parse_head(&resp[..head_end]).map_err(|e| format!("{e:#}"))?;
+    let body = &resp[head_end + 4..];
+    let status = status_line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|code| code.parse::<u16>().ok())
+        .ok_or_else(|| format!("malformed status line {status_line:?}"))?;
+    let bodyless = head_request || matches!(status, 100..=199 | 204 | 304);
+    if bodyless && !body.is_empty() {
+        return Err(format!(
+            "{} body bytes after a bodyless {status}",
+            body.len()
+        ));
+    }
+    decode_response(&headers, body.to_vec(), bodyless)
+        .map(drop)
+        .map_err(|e| match e.downcast::<AmbiguousFraming>() {
+            Ok(framing) => framing.0,
+            Err(e) => format!("{e:#}"),
+        })
+}
+
+fn find_crlf(buf: &[u8]) -> Option<usize> {
+    buf.windows(2).position(|w| w == b"\r\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn audits_outgoing_framing() {
+        assert_eq!(
+            audit_response(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello", false),
+            Ok(())
+        );
+        assert_eq!(
+            audit_response(b"HTTP/1.1 200 OK\r\nContent-Length: 6\r\n\r\nhello", false),
+            Err("Content-Length 6 but 5 body bytes".to_string())
+        );
+        assert_eq!(
+            audit_response(
+                b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n0\r\n\r\n",
+                false
+            ),
+            Ok(())
+        );
+        assert!(audit_response(
+            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhel",
+            false
+        )
+        .is_err());
+        assert_eq!(
+            audit_response(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\n", true),
+            Ok(())
+        );
+        assert_eq!(
+            audit_response(b"HTTP/1.1 204 No Content\r\n\r\nx", false),
+            Err("1 body bytes after a bodyless 204".to_string())
+        );
+    }
+
+    #[test]
+    fn decodes_chunks_and_trailers() {
+        let (payload, trailers) = decode(
+            b"5;ext=1\r\nhello\r\n7\r\n, world\r\n0\r\ngrpc-status: 0\r\ngrpc-message: ok\r\n\r\n",
+        )
+        .unwrap();
+        assert_eq!(payload, b"hello, world");
+        assert_eq!(
+            trailers.iter().collect::<Vec<_>>(),
+            [("grpc-status", "0"), ("grpc-message", "ok")]
+        );
+    }
+
+    #[test]
+    fn decodes_without_trailers() {
+        let (payload, trailers) = decode(b"A\r\n0123456789\r\n0\r\n\r\n").unwrap();
+        assert_eq!(payload, b"0123456789");
+        assert_eq!(trailers.iter().count(), 0);
+    }
+
+    #[test]
+    fn rejects_truncated_bodies() {
+        assert!(decode(b"5\r\nhel").is_err());
+        assert!(decode(b"5\r\nhello\r\n").is_err());
+        assert!(decode(b"0\r\nx-checksum: 1\r\n").is_err());
+        assert!(decode(b"zz\r\n").is_err());
+    }
+
+    #[test]
+    fn rejects_ambiguous_response_framing() {
+        let framed = |head: &str, body: &[u8], bodyless: bool| {
+            decode_response(&Headers::parse(head), body.to_vec(), bodyless)
+        };
+        let is_ambiguous = |r: Result<(Vec<u8>, Headers)>| r.unwrap_err().is::<AmbiguousFraming>();
+
+        assert_eq!(framed("Content-Length: 2", b"ok", false).unwrap().0, b"ok");
+        assert_eq!(
+            framed("Content-Length: 2, 2\r\nContent-Length: 2", b"ok", false)
+                .unwrap()
+                .0,
+            b"ok"
+        );
+        assert_eq!(framed("", b"to eof", false).unwrap().0, b"to eof");
+        assert!(framed("Content-Length: 10", b"", true)
+            .unwrap()
+            .0
+            .is_empty());
+
+        for (head, body) in [
+            (
+                "Transfer-Encoding: chunked\r\nContent-Length: 2",
+                &b"0\r\n\r\n"[..],
+            ),
+            ("Content-Length: 2\r\nContent-Length: 3", b"ok"),
+            ("Content-Length: +2", b"ok"),
+            ("Content-Length: 5", b"ok"),
+            ("Content-Length: 1", b"ok"),
+            ("Transfer-Encoding: gzip", b"ok"),
+            ("Transfer-Encoding: chunked", b"2\r\nok\r\n"),
+            (
+                "Transfer-Encoding: chunked",
+                b"0\r\n\r\nHTTP/1.1 200 OK\r\n\r\n",
+            ),
+        ] {
+            assert!(is_ambiguous(framed(head, body, false)), "{head:?}");
+        }
+        assert!(is_ambiguous(framed(
+            "Transfer-Encoding: chunked\r\nContent-Length: 0",
+            b"",
+            true
+        )));
+    }
+
+    #[test]
+    fn encode_round_trips() {
+        let trailers = Headers::parse("x-checksum: abc");
+        let mut out = Vec::new();
+        encode(&mut out, b"payload", &trailers);
+        assert_eq!(out, b"7\r\npayload\r\n0\r\nx-checksum: abc\r\n\r\n");
+        assert_eq!(decode(&out).unwrap(), (b"payload".to_vec(), trailers));
+    }
+
+    #[test]
+    fn chunked_only_when_last_coding() {
+        assert!(is_chunked(&Headers::parse(
+            "Transfer-Encoding: gzip, chunked"
+        )));
+        assert!(is_chunked(&Headers::parse(
+            "Transfer-Encoding: gzip\r\nTransfer-Encoding: Chunked"
+        )));
+        assert!(!is_chunked(&Headers::parse(
+            "Transfer-Encoding: chunked, gzip"
+        )));
+        assert!(!is_chunked(&Headers::default()));
+    }
+}
@@ -0,0 +1,214 @@
+//! Gossip replication of the `/state` counter between gateway replicas.
+//!
+//! Each replica owns one slot of a grow-only counter (G-counter) and
+//! periodically sends its whole view to every `CLUSTER_PEERS` address over UDP.
+//! Views merge by taking the per-replica maximum, so all replicas converge on
+//! the same total without coordination; values handed out between gossip
+//! rounds may overlap across replicas.
+
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::net::{ToSocketAddrs, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::variant;
+
+const MAGIC: &str = "gcounter/1";
+const MAX_DATAGRAM: usize = 64 * 1024;
+
+#[derive(Debug)]
+pub struct Gossip {
+    replica_id: String,
+    counts: Mutex<HashMap<String, u64>>,
+}
+
+impl Gossip {
+    /// Binds `bind`, then spawns one thread receiving peer views and one
+    /// sending ours every `interval`. Peers are re-resolved on every round.
+    pub fn start(
+        replica_id: String,
+        bind: &str,
+        peers: Vec<String>,
+        interval: Duration,
+    ) -> Result<Arc<Self>> {
+        let socket = UdpSocket::bind(bind).with_context(|| format!("bind CLUSTER_BIND={bind}"))?;
+        let sender = socket.try_clone().context("clone gossip socket")?;
+        let gossip = Arc::new(Gossip {
+            replica_id,
+            counts: Mutex::new(HashMap::new()),
+        });
+
+        let receiver = Arc::clone(&gossip);
+        std::thread::spawn(move || {
+            let mut buf = vec![0u8; MAX_DATAGRAM];
+            loop {
+                match socket.recv_from(&mut buf) {
+                    Ok((n, _)) => {
+                        if let Ok(payload) = std::str::from_utf8(&buf[..n]) {
+                            receiver.merge(payload);
+                        }
+                    }
+                    Err(e) => eprintln!("[{}] gossip recv error: {e}", variant()),
+                }
+            }
+        });
+
+        let publisher = Arc::clone(&gossip);
+        std::thread::spawn(move || loop {
+            std::thread::sleep(interval);
+            let payload = publisher.encode();
+            for peer in &peers {
+                let Ok(addrs) = peer.to_socket_addrs() else {
+                    continue;
+                };
+                for addr in addrs {
+                    sender.send_to(payload.as_bytes(), addr).ok();
+                }
+            }
+        });
+
+        Ok(gossip)
+    }
+
+    /// Increments this replica's slot and returns the cluster-wide total
+    /// before the increment.
+    pub fn incr(&self) -> u64 {
+        let mut counts = self.counts.lock().unwrap_or_else(|e| e.into_inner());
+        let total: u64 = counts.values().sum();
+        *counts.entry(self.replica_id.clone()).or_insert(0) += 1;
+        total
+    }
+
+    fn encode(&self) -> String {
+        let counts = self.counts.lock().unwrap_or_else(|e| e.into_inner());
+        let mut out = format!("{MAGIC}\n");
+        for (id, count) in counts.iter() {
+            out.push_str(&format!("{id} {count}\n"));
+        }
+        out
+    }
+
+    fn merge(&self, payload: &str) {
+        let mut lines = payload.lines();
+        if lines.next() != Some(MAGIC) {
+            return;
+        }
+        let mut counts = self.counts.lock().unwrap_or_else(|e| e.into_inner());
+        for line in lines {
+            let Some((id, count)) = line.rsplit_once(' ') else {
+                continue;
+            };
+            let Ok(count) = count.parse::<u64>() else {
+                continue;
+            };
+            let slot = counts.entry(id.to_string()).or_insert(0);
+            *slot = (*slot).max(count);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    fn gossip(replica_id: &str) -> Gossip {
+        Gossip {
+            replica_id: replica_id.to_string(),
+            counts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn counts(gossip: &Gossip) -> Vec<(String, u64)> {
+        let mut counts: Vec<_> = gossip
+            .counts
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, n)| (id.clone(), *n))
+            .collect();
+        counts.sort();
+        counts
+    }
+
+    #[test]
+    fn views_merge_by_per_replica_maximum() {
+        let a = gossip("a");
+        let b = gossip("b");
+        assert_eq!(a.incr(), 0);
+        assert_eq!(a.incr(), 1);
+        assert_eq!(b.incr(), 0);
+
+        b.merge(&a.encode());
+        assert_eq!(counts(&b), [("a".to_string(), 2), ("b".to_string(), 1)]);
+        // Values handed out next continue from the merged total.
+        assert_eq!(b.incr(), 3);
+
+        // An older view never lowers a slot.
+        let stale = gossip("a");
+        stale.incr();
+        b.merge(&stale.encode());
+        assert_eq!(counts(&b), [("a".to_string(), 2), ("b".to_string(), 2)]);
+
+        a.merge(&b.encode());
+        assert_eq!(counts(&a), counts(&b));
+    }
+
+    #[test]
+    fn ignores_foreign_and_malformed_payloads() {
+        let g = gossip("a");
+        g.merge("gcounter/2\nb 5\n");
+        g.merge("b 5\n");
+        g.merge("");
+        assert!(counts(&g).is_empty());
+
+        g.merge("gcounter/1\nb 5\nno-count\nc -1\nd x\ne 18446744073709551616\nreplica two 3\n\n");
+        assert_eq!(
+            counts(&g),
+            [("b".to_string(), 5), ("replica two".to_string(), 3)]
+        );
+        assert_eq!(gossip("a").encode(), "gcounter/1\n");
+    }
+
+    #[test]
+    fn replicas_converge_over_udp() {
+        let free_port = || {
+            UdpSocket::bind("127.0.0.1:0")
+                .unwrap()
+                .local_addr()
+                .unwrap()
+                .port()
+        };
+        let (port_a, port_b) = (free_port(), free_port());
+        let interval = Duration::from_millis(10);
+        let a = Gossip::start(
+            "a".to_string(),
+            &format!("127.0.0.1:{port_a}"),
+            vec![format!("127.0.0.1:{port_b}")],
+            interval,
+        )
+        .unwrap();
+        let b = Gossip::start(
+            "b".to_string(),
+            &format!("127.0.0.1:{port_b}"),
+            vec![
+                format!("localhost:{port_a}"),
+                "unresolvable.invalid:1".to_string(),
+            ],
+            interval,
+        )
+        .unwrap();
+        for _ in 0..3 {
+            a.incr();
+        }
+        b.incr();
+        let start = Instant::now();
+        while counts(&a) != counts(&b) || counts(&a).len() < 2 {
+            assert!(start.elapsed() < Duration::from_secs(5), "no convergence");
+            std::thread::sleep(interval);
+        }
+        assert_eq!(counts(&a), [("a".to_string(), 3), ("b".to_string(), 1)]);
+        assert_eq!(b.incr(), 4);
+    }
+}
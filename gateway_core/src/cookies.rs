@@ -0,0 +1,311 @@
+//! `Cookie` / `Set-Cookie` parsing and the config-driven rewrites applied to
+//! proxied traffic.
+//!
+//! `COOKIE_DROP` names cookies (a trailing `*` matches a prefix, e.g. `_ga*`)
+//! that are removed from requests before forwarding and from upstream
+//! `Set-Cookie` headers. `COOKIE_STRIP_ATTRS` removes attributes such as
+//! `Domain` from `Set-Cookie`; `COOKIE_SAMESITE` and `COOKIE_SECURE=1` add the
+//! corresponding attributes when the upstream left them out.
+
+use anyhow::{anyhow, Result};
+
+use crate::headers::Headers;
+
+#[derive(Debug, Default)]
+pub struct CookieConfig {
+    drop: Vec<String>,
+    strip_attrs: Vec<String>,
+    same_site: Option<String>,
+    secure: bool,
+}
+
+/// One `Set-Cookie` header value.
+#[derive(Debug)]
+pub struct SetCookie {
+    pub name: String,
+    pub value: String,
+    /// Attributes in original order; flags such as `Secure` have no value.
+    pub attrs: Vec<(String, Option<String>)>,
+}
+
+impl SetCookie {
+    pub fn parse(header_value: &str) -> Option<Self> {
+        let mut parts = header_value.split(';');
+        let (name, value) = parts.next()?.split_once('=')?;
+        let name = name.trim();
+        if name.is_empty() {
+            return None;
+        }
+        let attrs = parts
+            .map(str::trim)
+            .filter(|a| !a.is_empty())
+            .map(|a| match a.split_once('=') {
+                Some((k, v)) => (k.trim().to_string(), Some(v.trim().to_string())),
+                None => (a.to_string(), None),
+            })
+            .collect();
+        Some(SetCookie {
+            name: name.to_string(),
+            value: value.trim().to_string(),
+            attrs,
+        })
+    }
+
+    pub fn has_attr(&self, name: &str) -> bool {
+        self.attrs.iter().any(|(k, _)| k.eq_ignore_ascii_case(name))
+    }
+
+    pub fn to_header_value(&self) -> String {
+        let mut out = format!("{}={}", self.name, self.value);
+        for (k, v) in &self.attrs {
+            out.push_str("; ");
+            out.push_str(k);
+            if let Some(v) = v {
+                out.push('=');
+                out.push_str(v);
+            }
+        }
+        out
+    }
+}
+
+/// Splits a request `Cookie` header into `(name, value)` pairs.
+pub fn parse_cookie_header(header_value: &str) -> Vec<(&str, &str)> {
+    header_value
+        .split(';')
+        .filter_map(|pair| {
+            let (name, value) = pair.split_once('=')?;
+            let name = name.trim();
+            (!name.is_empty()).then_some((name, value.trim()))
+        })
+        .collect()
+}
+
+impl CookieConfig {
+    pub fn from_env() -> Result<Option<Self>> {
+        let list = |var: &str| -> Vec<String> {
+            std::env::var(var)
+                .map(|v| {
+                    v.split(',')
+                        .map(str::trim)
+                        .filter(|p| !p.is_empty())
+                        .map(str::to_string)
+                        .collect()
+                })
+                .unwrap_or_default()
+        };
+        let same_site = match std::env::var("COOKIE_SAMESITE") {
+            Ok(v) if v.is_empty() => None,
+            Ok(v) => match v.to_ascii_lowercase().as_str() {
+                "strict" => Some("Strict".to_string()),
+                "lax" => Some("Lax".to_string()),
+                "none" => Some("None".to_string()),
+                _ => {
+                    return Err(anyhow!(
+                        "invalid COOKIE_SAMESITE={v} (expected: Strict|Lax|None)"
+                    ))
+                }
+            },
+            Err(_) => None,
+        };
+        let config = CookieConfig {
+            drop: list("COOKIE_DROP"),
+            strip_attrs: list("COOKIE_STRIP_ATTRS"),
+            same_site,
+            secure: std::env::var("COOKIE_SECURE").is_ok_and(|v| v == "1"),
+        };
+        if config.drop.is_empty()
+            && config.strip_attrs.is_empty()
+            && config.same_site.is_none()
+            && !config.secure
+        {
+            return Ok(None);
+        }
+        Ok(Some(config))
+    }
+
+    fn drops(&self, name: &str) -> bool {
+        self.drop.iter().any(|d| match d.strip_suffix('*') {
+            Some(prefix) => name.starts_with(prefix),
+            None => name == d,
+        })
+    }
+
+    /// Request cookies that survive `COOKIE_DROP`, in original order.
+    pub fn filter_request<'a>(&self, cookies: Vec<(&'a str, &'a str)>) -> Vec<(&'a str, &'a str)> {
+        cookies
+            .into_iter()
+            .filter(|(name, _)| !self.drops(name))
+            .collect()
+    }
+
+    /// Rewrites one upstream `Set-Cookie` value; `None` drops the header.
+    pub fn rewrite_set_cookie(&self, header_value: &str) -> Option<String> {
+        let Some(mut cookie) = SetCookie::parse(header_value) else {
+            return Some(header_value.to_string());
+        };
+        if self.drops(&cookie.name) {
+            return None;
+        }
+        cookie
+            .attrs
+            .retain(|(k, _)| !self.strip_attrs.iter().any(|s| s.eq_ignore_ascii_case(k)));
+        if let Some(same_site) = self.same_site.as_deref() {
+            if !cookie.has_attr("SameSite") {
+                cookie
+                    .attrs
+                    .push(("SameSite".to_string(), Some(same_site.to_string())));
+            }
+        }
+        // Browsers reject SameSite=None without Secure.
+        let needs_secure = self.secure
+            || cookie
+                .attrs
+                .iter()
+                .any(|(k, v)| k.eq_ignore_ascii_case("SameSite") && v.as_deref() == Some("None"));
+        if needs_secure && !cookie.has_attr("Secure") {
+            cookie.attrs.push(("Secure".to_string(), None));
+        }
+        Some(cookie.to_header_value())
+    }
+
+    /// Applies `rewrite_set_cookie` to every `Set-Cookie` field.
+    pub fn rewrite_response_headers(&self, headers: &mut Headers) {
+        headers.rewrite_all("set-cookie", |value| self.rewrite_set_cookie(value));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(drop: &[&str], strip_attrs: &[&str]) -> CookieConfig {
+        CookieConfig {
+            drop: drop.iter().map(|d| d.to_string()).collect(),
+            strip_attrs: strip_attrs.iter().map(|a| a.to_string()).collect(),
+            ..CookieConfig::default()
+        }
+    }
+
+    #[test]
+    fn parses_cookie_and_set_cookie_headers() {
+        assert_eq!(
+            parse_cookie_header(" a=1; b = two ;=x; junk; c=; d=e=f"),
+            [("a", "1"), ("b", "two"), ("c", ""), ("d", "e=f")]
+        );
+        let cookie =
+            SetCookie::parse("sid=abc; Path=/; Domain=.example.com; HttpOnly; secure").unwrap();
+        assert_eq!(
+            (cookie.name.as_str(), cookie.value.as_str()),
+            ("sid", "abc")
+        );
+        assert!(cookie.has_attr("Secure") && cookie.has_attr("httponly"));
+        assert!(!cookie.has_attr("SameSite"));
+        assert_eq!(
+            cookie.to_header_value(),
+            "sid=abc; Path=/; Domain=.example.com; HttpOnly; secure"
+        );
+        assert!(SetCookie::parse("no-equals").is_none());
+        assert!(SetCookie::parse("=value; Path=/").is_none());
+    }
+
+    #[test]
+    fn drops_named_and_prefixed_cookies() {
+        let config = config(&["_ga*", "tracking"], &[]);
+        let kept = config.filter_request(parse_cookie_header(
+            "_ga=1; _gat_UA=2; session=s; tracking=t; tracking_id=3; ga=4",
+        ));
+        assert_eq!(kept, [("session", "s"), ("tracking_id", "3"), ("ga", "4")]);
+
+        assert_eq!(config.rewrite_set_cookie("_ga=GA1.2; Path=/"), None);
+        assert_eq!(config.rewrite_set_cookie("tracking=t"), None);
+        assert_eq!(
+            config.rewrite_set_cookie("session=s; Path=/").as_deref(),
+            Some("session=s; Path=/")
+        );
+    }
+
+    #[test]
+    fn rewrites_set_cookie_attributes() {
+        let config = config(&[], &["domain", "Expires"]);
+        assert_eq!(
+            config
+                .rewrite_set_cookie(
+                    "sid=abc; Domain=up.internal; Path=/; Expires=Wed, 21 Oct 2026 07:28:00 GMT"
+                )
+                .as_deref(),
+            Some("sid=abc; Path=/")
+        );
+
+        let lax = CookieConfig {
+            same_site: Some("Lax".to_string()),
+            ..CookieConfig::default()
+        };
+        assert_eq!(
+            lax.rewrite_set_cookie("sid=abc").as_deref(),
+            Some("sid=abc; SameSite=Lax")
+        );
+        // The upstream's own SameSite wins.
+        assert_eq!(
+            lax.rewrite_set_cookie("sid=abc; samesite=Strict")
+                .as_deref(),
+            Some("sid=abc; samesite=Strict")
+        );
+
+        let secure = CookieConfig {
+            secure: true,
+            ..CookieConfig::default()
+        };
+        assert_eq!(
+            secure.rewrite_set_cookie("sid=abc; HttpOnly").as_deref(),
+            Some("sid=abc; HttpOnly; Secure")
+        );
+        assert_eq!(
+            secure.rewrite_set_cookie("sid=abc; Secure").as_deref(),
+            Some("sid=abc; Secure")
+        );
+
+        // SameSite=None always gets Secure.
+        let none = CookieConfig {
+            same_site: Some("None".to_string()),
+            ..CookieConfig::default()
+        };
+        assert_eq!(
+            none.rewrite_set_cookie("sid=abc").as_deref(),
+            Some("sid=abc; SameSite=None; Secure")
+        );
+        assert_eq!(
+            CookieConfig::default()
+                .rewrite_set_cookie("sid=abc; SameSite=None")
+                .as_deref(),
+            Some("sid=abc; SameSite=None; Secure")
+        );
+
+        // Values it cannot parse pass through untouched.
+        assert_eq!(
+            secure.rewrite_set_cookie("garbage").as_deref(),
+            Some("garbage")
+        );
+    }
+
+    #[test]
+    fn rewrites_every_set_cookie_field() {
+        let config = CookieConfig {
+            drop: vec!["_ga*".to_string()],
+            secure: true,
+            ..CookieConfig::default()
+        };
+        let mut headers = Headers::parse(
+            "Content-Type: text/html\r\nSet-Cookie: _ga=1\r\nset-cookie: sid=abc\r\nSet-Cookie: theme=dark; Secure",
+        );
+        config.rewrite_response_headers(&mut headers);
+        assert_eq!(
+            headers.iter().collect::<Vec<_>>(),
+            [
+                ("Content-Type", "text/html"),
+                ("set-cookie", "sid=abc; Secure"),
+                ("Set-Cookie", "theme=dark; Secure"),
+            ]
+        );
+    }
+}
@@ -0,0 +1,574 @@
+//! Upstream discovery through DNS for `UPSTREAM_POOL`.
+//!
+//! A pool entry `dns+http://tasks.api:8080` becomes one member per A/AAAA
+//! record of `tasks.api` (Swarm publishes one per task), each with the entry's
+//! weight. `srv+http://_http._tcp.api` becomes one member per address of each
+//! lowest-priority SRV target, on the record's port, weighted by the record
+//! (times the entry's weight). Names are resolved once, at startup; members
+//! are addressed by IP, which is also what `Host` and TLS verification see.
+//!
+//! - `DNS_SERVER`: `ip[:port]` to ask, e.g. Docker's `127.0.0.11`. Without it,
+//!   addresses come from the system resolver and SRV records from the first
+//!   `nameserver` in `/etc/resolv.conf`.
+//! - `DNS_TIMEOUT_MS`: per query attempt (default 2000). A truncated UDP
+//!   answer is asked again over TCP.
+
+use anyhow::{anyhow, Context, Result};
+use std::hash::{BuildHasher, Hasher};
+use std::io::{Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, ToSocketAddrs, UdpSocket};
+use std::time::Duration;
+use url::Url;
+
+use crate::variant;
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_millis(2000);
+const UDP_ATTEMPTS: usize = 2;
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+const TYPE_SRV: u16 = 33;
+const CLASS_IN: u16 = 1;
+/// Compression pointers followed while reading one name.
+const MAX_JUMPS: usize = 16;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Srv {
+    pub priority: u16,
+    pub weight: u16,
+    pub port: u16,
+    pub target: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Data {
+    Addr(IpAddr),
+    Srv(Srv),
+    Other,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Record {
+    name: String,
+    data: Data,
+}
+
+/// The answer and additional sections of a response.
+#[derive(Debug, Default)]
+struct Message {
+    truncated: bool,
+    answers: Vec<Record>,
+    additional: Vec<Record>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Resolver {
+    server: Option<SocketAddr>,
+    timeout: Duration,
+}
+
+impl Resolver {
+    pub fn from_env() -> Result<Self> {
+        let server = match var("DNS_SERVER") {
+            Some(v) => Some(parse_server(&v).with_context(|| format!("invalid DNS_SERVER={v}"))?),
+            None => None,
+        };
+        let timeout = match var("DNS_TIMEOUT_MS") {
+            Some(v) => Duration::from_millis(
+                v.trim()
+                    .parse()
+                    .with_context(|| format!("invalid DNS_TIMEOUT_MS={v}"))?,
+            ),
+            None => DEFAULT_TIMEOUT,
+        };
+        Ok(Resolver { server, timeout })
+    }
+
+    fn server(&self) -> Result<SocketAddr> {
+        if let Some(server) = self.server {
+            return Ok(server);
+        }
+        let conf = std::fs::read_to_string("/etc/resolv.conf")
+            .context("read /etc/resolv.conf (set DNS_SERVER)")?;
+        conf.lines()
+            .filter_map(|line| line.trim().strip_prefix("nameserver"))
+            .find_map(|rest| parse_server(rest.trim()).ok())
+            .ok_or_else(|| anyhow!("no nameserver in /etc/resolv.conf (set DNS_SERVER)"))
+    }
+
+    /// A and AAAA records of `name`, without duplicates.
+    pub fn lookup_ip(&self, name: &str) -> Result<Vec<IpAddr>> {
+        let mut addrs = Vec::new();
+        if self.server.is_some() {
+            for qtype in [TYPE_A, TYPE_AAAA] {
+                for record in self.query(name, qtype)?.answers {
+                    if let Data::Addr(ip) = record.data {
+                        addrs.push(ip);
+                    }
+                }
+            }
+        } else {
+            addrs.extend(
+                (name, 0)
+                    .to_socket_addrs()
+                    .with_context(|| format!("resolve {name}"))?
+                    .map(|addr| addr.ip()),
+            );
+        }
+        let mut seen = Vec::new();
+        addrs.retain(|ip| {
+            let new = !seen.contains(ip);
+            seen.push(*ip);
+            new
+        });
+        if addrs.is_empty() {
+            return Err(anyhow!("{name} has no A or AAAA records"));
+        }
+        Ok(addrs)
+    }
+
+    /// `(record, addresses of its target)` for the lowest-priority SRV
+    /// records of `name`. Targets are looked up unless the server already
+    /// sent their addresses along.
+    pub fn lookup_srv(&self, name: &str) -> Result<Vec<(Srv, Vec<IpAddr>)>> {
+        let message = self.query(name, TYPE_SRV)?;
+        let records: Vec<Srv> = message
+            .answers
+            .iter()
+            .filter_map(|record| match &record.data {
+                Data::Srv(srv) => Some(srv.clone()),
+                _ => None,
+            })
+            .collect();
+        let Some(priority) = records.iter().map(|srv| srv.priority).min() else {
+            return Err(anyhow!("{name} has no SRV records"));
+        };
+        records
+            .into_iter()
+            .filter(|srv| srv.priority == priority)
+            .map(|srv| {
+                let glued: Vec<IpAddr> = message
+                    .additional
+                    .iter()
+                    .filter(|r| r.name.eq_ignore_ascii_case(&srv.target))
+                    .filter_map(|r| match r.data {
+                        Data::Addr(ip) => Some(ip),
+                        _ => None,
+                    })
+                    .collect();
+                let addrs = if glued.is_empty() {
+                    self.lookup_ip(&srv.target)?
+                } else {
+                    glued
+                };
+                Ok((srv, addrs))
+            })
+            .collect()
+    }
+
+    fn query(&self, name: &str, qtype: u16) -> Result<Message> {
+        let server = self.server()?;
+        let id = query_id();
+        let query = encode_query(id, name, qtype)?;
+        let message = self
+            .exchange_udp(server, id, &query)
+            .with_context(|| format!("query {name} at {server}"))?;
+        if !message.truncated {
+            return Ok(message);
+        }
+        self.exchange_tcp(server, id, &query)
+            .with_context(|| format!("query {name} at {server} over TCP"))
+    }
+
+    fn exchange_udp(&self, server: SocketAddr, id: u16, query: &[u8]) -> Result<Message> {
+        let local: SocketAddr = match server {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+        let socket = UdpSocket::bind(local).context("bind DNS socket")?;
+        socket.connect(server)?;
+        socket.set_read_timeout(Some(self.timeout))?;
+        let mut buf = [0u8; 4096];
+        let mut last_err = anyhow!("no answer");
+        for _ in 0..UDP_ATTEMPTS {
+            socket.send(query)?;
+            loop {
+                match socket.recv(&mut buf) {
+                    // Stray datagrams (a late answer to an earlier attempt
+                    // has the same id and is fine) are skipped.
+                    Ok(n) if n >= 2 && u16::from_be_bytes([buf[0], buf[1]]) != id => continue,
+                    Ok(n) => return parse_response(id, &buf[..n]),
+                    Err(e) => {
+                        last_err = anyhow::Error::new(e).context("no answer");
+                        break;
+                    }
+                }
+            }
+        }
+        Err(last_err)
+    }
+
+    fn exchange_tcp(&self, server: SocketAddr, id: u16, query: &[u8]) -> Result<Message> {
+        let mut tcp = TcpStream::connect_timeout(&server, self.timeout)?;
+        tcp.set_read_timeout(Some(self.timeout))?;
+        tcp.set_write_timeout(Some(self.timeout))?;
+        let mut framed = (query.len() as u16).to_be_bytes().to_vec();
+        framed.extend_from_slice(query);
+        tcp.write_all(&framed)?;
+        let mut len = [0u8; 2];
+        tcp.read_exact(&mut len)?;
+        let mut buf = vec![0u8; usize::from(u16::from_be_bytes(len))];
+        tcp.read_exact(&mut buf)?;
+        parse_response(id, &buf)
+    }
+}
+
+/// Expands the `dns+` and `srv+` entries of `UPSTREAM_POOL` into one
+/// `(url, weight)` per discovered address; other entries pass through.
+pub fn expand_pool(entries: &[(&str, u32)]) -> Result<Vec<(String, u32)>> {
+    if !entries.iter().any(|(url, _)| is_discovered(url)) {
+        if configured() {
+            return Err(anyhow!(
+                "DNS_SERVER is set but UPSTREAM_POOL has no dns+ or srv+ member"
+            ));
+        }
+        return Ok(entries
+            .iter()
+            .map(|(url, w)| (url.to_string(), *w))
+            .collect());
+    }
+    expand_with(&Resolver::from_env()?, entries)
+}
+
+fn expand_with(resolver: &Resolver, entries: &[(&str, u32)]) -> Result<Vec<(String, u32)>> {
+    let mut members = Vec::new();
+    for &(entry, weight) in entries {
+        let (srv, rest) = match (entry.strip_prefix("dns+"), entry.strip_prefix("srv+")) {
+            (Some(rest), _) => (false, rest),
+            (_, Some(rest)) => (true, rest),
+            _ => {
+                members.push((entry.to_string(), weight));
+                continue;
+            }
+        };
+        let url =
+            Url::parse(rest).with_context(|| format!("invalid UPSTREAM_POOL member {entry:?}"))?;
+        let name = url
+            .host_str()
+            .ok_or_else(|| anyhow!("UPSTREAM_POOL member {entry:?} has no name to resolve"))?
+            .to_string();
+        let found: Vec<(IpAddr, u16, u32)> = if srv {
+            if url.port().is_some() {
+                return Err(anyhow!(
+                    "UPSTREAM_POOL member {entry:?}: srv+ takes the port from the SRV record"
+                ));
+            }
+            resolver
+                .lookup_srv(&name)?
+                .into_iter()
+                .flat_map(|(record, addrs)| {
+                    let weight = weight.saturating_mul(u32::from(record.weight.max(1)));
+                    addrs.into_iter().map(move |ip| (ip, record.port, weight))
+                })
+                .collect()
+        } else {
+            let port = url
+                .port_or_known_default()
+                .ok_or_else(|| anyhow!("UPSTREAM_POOL member {entry:?} has no port"))?;
+            resolver
+                .lookup_ip(&name)?
+                .into_iter()
+                .map(|ip| (ip, port, weight))
+                .collect()
+        };
+        eprintln!(
+            "[{}] UPSTREAM_POOL: {entry} resolved to {} member(s)",
+            variant(),
+            found.len()
+        );
+        for (ip, port, weight) in found {
+            let mut member = url.clone();
+            member
+                .set_ip_host(ip)
+                .and_then(|()| member.set_port(Some(port)))
+                .map_err(|()| anyhow!("cannot address {entry:?} by IP"))?;
+            members.push((member.to_string(), weight));
+        }
+    }
+    Ok(members)
+}
+
+fn is_discovered(url: &str) -> bool {
+    url.starts_with("dns+") || url.starts_with("srv+")
+}
+
+/// Whether `DNS_SERVER` / `DNS_TIMEOUT_MS` is set, so a setting with nothing
+/// to discover can be rejected rather than silently ignored.
+pub fn configured() -> bool {
+    ["DNS_SERVER", "DNS_TIMEOUT_MS"]
+        .iter()
+        .any(|name| var(name).is_some())
+}
+
+fn var(name: &str) -> Option<String> {
+    std::env::var(name).ok().filter(|v| !v.trim().is_empty())
+}
+
+/// `ip`, `ip:port` or `[ipv6]:port`; the port defaults to 53.
+fn parse_server(s: &str) -> Result<SocketAddr> {
+    let s = s.trim();
+    if let Ok(addr) = s.parse::<SocketAddr>() {
+        return Ok(addr);
+    }
+    let ip: IpAddr = s
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse()
+        .context("expected ip or ip:port")?;
+    Ok((ip, 53).into())
+}
+
+/// Not cryptographic; only has to differ between queries.
+fn query_id() -> u16 {
+    let mut hasher = std::collections::hash_map::RandomState::new().build_hasher();
+    hasher.write_u128(
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos()),
+    );
+    hasher.finish() as u16
+}
+
+fn encode_query(id: u16, name: &str, qtype: u16) -> Result<Vec<u8>> {
+    let mut out = Vec::with_capacity(18 + name.len());
+    out.extend_from_slice(&id.to_be_bytes());
+    // Recursion desired; one question.
+    out.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+    let name = name.trim_end_matches('.');
+    if name.is_empty() || name.len() > 253 {
+        return Err(anyhow!("invalid DNS name {name:?}"));
+    }
+    for label in name.split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(anyhow!("invalid DNS name {name:?}"));
+        }
+        out.push(label.len() as u8);
+        out.extend_from_slice(label.as_bytes());
+    }
+    out.push(0);
+    out.extend_from_slice(&qtype.to_be_bytes());
+    out.extend_from_slice(&CLASS_IN.to_be_bytes());
+    Ok(out)
+}
+
+fn parse_response(id: u16, buf: &[u8]) -> Result<Message> {
+    let short = || anyhow!("truncated DNS response");
+    let u16_at = |pos: usize| -> Result<u16> {
+        buf.get(pos..pos + 2)
+            .map(|b| u16::from_be_bytes([b[0], b[1]]))
+            .ok_or_else(short)
+    };
+    if u16_at(0)? != id {
+        return Err(anyhow!("DNS response for another query"));
+    }
+    let flags = u16_at(2)?;
+    if flags & 0x8000 == 0 {
+        return Err(anyhow!("DNS message is not a response"));
+    }
+    match flags & 0x000f {
+        0 => {}
+        3 => return Err(anyhow!("no such name (NXDOMAIN)")),
+        rcode => return Err(anyhow!("DNS server answered with rcode {rcode}")),
+    }
+    let mut message = Message {
+        truncated: flags & 0x0200 != 0,
+        ..Message::default()
+    };
+    let counts = [u16_at(4)?, u16_at(6)?, u16_at(8)?, u16_at(10)?];
+    let mut pos = 12;
+    for _ in 0..counts[0] {
+        pos = read_name(buf, pos)?.1 + 4;
+    }
+    for (section, &count) in counts.iter().enumerate().skip(1) {
+        for _ in 0..count {
+            let (name, next) = read_name(buf, pos)?;
+            let (rtype, class) = (u16_at(next)?, u16_at(next + 2)?);
+            let len = usize::from(u16_at(next + 8)?);
+            let start = next + 10;
+            let rdata = buf.get(start..start + len).ok_or_else(short)?;
+            pos = start + len;
+            let data = match (rtype, class, len) {
+                (TYPE_A, CLASS_IN, 4) => {
+                    Data::Addr(IpAddr::from(<[u8; 4]>::try_from(rdata).unwrap()))
+                }
+                (TYPE_AAAA, CLASS_IN, 16) => {
+                    Data::Addr(IpAddr::from(<[u8; 16]>::try_from(rdata).unwrap()))
+                }
+                (TYPE_SRV, CLASS_IN, 7..) => Data::Srv(Srv {
+                    priority: u16_at(start)?,
+                    weight: u16_at(start + 2)?,
+                    port: u16_at(start + 4)?,
+                    target: read_name(buf, start + 6)?.0,
+                }),
+                _ => Data::Other,
+            };
+            match section {
+                1 => message.answers.push(Record { name, data }),
+                3 => message.additional.push(Record { name, data }),
+                _ => {}
+            }
+        }
+    }
+    Ok(message)
+}
+
+/// The name at `pos`, following compression pointers, and the offset just
+/// past it.
+fn read_name(buf: &[u8], mut pos: usize) -> Result<(String, usize)> {
+    let mut labels: Vec<String> = Vec::new();
+    let mut end = None;
+    let mut jumps = 0;
+    loop {
+        let len = *buf.get(pos).ok_or_else(|| anyhow!("truncated DNS name"))?;
+        match len {
+            0 => break,
+            len if len & 0xc0 == 0xc0 => {
+                let low = *buf
+                    .get(pos + 1)
+                    .ok_or_else(|| anyhow!("truncated DNS name"))?;
+                end.get_or_insert(pos + 2);
+                jumps += 1;
+                if jumps > MAX_JUMPS {
+                    return Err(anyhow!("DNS name compression loop"));
+                }
+                pos = usize::from(u16::from_be_bytes([len & 0x3f, low]));
+            }
+            len if len & 0xc0 == 0 => {
+                let label = buf
+                    .get(pos + 1..pos + 1 + usize::from(len))
+                    .ok_or_else(|| anyhow!("truncated DNS name"))?;
+                labels.push(String::from_utf8_lossy(label).into_owned());
+                pos += 1 + usize::from(len);
+            }
+            _ => return Err(anyhow!("unsupported DNS label type")),
+        }
+    }
+    Ok((labels.join("."), end.unwrap_or(pos + 1)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Answers every query on a local UDP socket with `reply(query)`.
+    fn fake_server(reply: fn(&[u8]) -> Vec<u8>) -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let mut buf = [0u8; 512];
+            while let Ok((n, from)) = socket.recv_from(&mut buf) {
+                socket.send_to(&reply(&buf[..n]), from).ok();
+            }
+        });
+        addr
+    }
+
+    /// The query turned into a response carrying `answers` and `additional`
+    /// records, given as `(type, rdata)` owned by the question's name.
+    fn respond(query: &[u8], answers: &[(u16, Vec<u8>)], additional: &[(u16, Vec<u8>)]) -> Vec<u8> {
+        let question_end = read_name(query, 12).unwrap().1 + 4;
+        let mut out = query[..question_end].to_vec();
+        out[2] |= 0x80;
+        out[6..8].copy_from_slice(&(answers.len() as u16).to_be_bytes());
+        out[10..12].copy_from_slice(&(additional.len() as u16).to_be_bytes());
+        for (rtype, rdata) in answers.iter().chain(additional) {
+            // Name: pointer to the question.
+            out.extend_from_slice(&[0xc0, 12]);
+            out.extend_from_slice(&rtype.to_be_bytes());
+            out.extend_from_slice(&CLASS_IN.to_be_bytes());
+            out.extend_from_slice(&30u32.to_be_bytes());
+            out.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+            out.extend_from_slice(rdata);
+        }
+        out
+    }
+
+    fn qtype(query: &[u8]) -> u16 {
+        let end = read_name(query, 12).unwrap().1;
+        u16::from_be_bytes([query[end], query[end + 1]])
+    }
+
+    fn srv_rdata(priority: u16, weight: u16, port: u16) -> Vec<u8> {
+        let mut rdata = Vec::new();
+        for v in [priority, weight, port] {
+            rdata.extend_from_slice(&v.to_be_bytes());
+        }
+        // Target: the queried name itself, by pointer.
+        rdata.extend_from_slice(&[0xc0, 12]);
+        rdata
+    }
+
+    #[test]
+    fn expands_address_and_srv_entries() {
+        let server = fake_server(|query| match qtype(query) {
+            TYPE_A => respond(
+                query,
+                &[(TYPE_A, vec![10, 0, 0, 1]), (TYPE_A, vec![10, 0, 0, 2])],
+                &[],
+            ),
+            TYPE_SRV => respond(
+                query,
+                &[
+                    (TYPE_SRV, srv_rdata(10, 3, 8081)),
+                    (TYPE_SRV, srv_rdata(20, 1, 9999)),
+                ],
+                &[(TYPE_A, vec![10, 0, 0, 9])],
+            ),
+            _ => respond(query, &[], &[]),
+        });
+        let resolver = Resolver {
+            server: Some(server),
+            timeout: DEFAULT_TIMEOUT,
+        };
+        let members = expand_with(
+            &resolver,
+            &[
+                ("dns+http://tasks.api:8080/v1", 2),
+                ("srv+https://_api._tcp.web", 1),
+                ("http://static:80", 1),
+            ],
+        )
+        .unwrap();
+        assert_eq!(
+            members,
+            [
+                ("http://10.0.0.1:8080/v1".to_string(), 2),
+                ("http://10.0.0.2:8080/v1".to_string(), 2),
+                ("https://10.0.0.9:8081/".to_string(), 3),
+                ("http://static:80".to_string(), 1),
+            ]
+        );
+        let err = expand_with(&resolver, &[("srv+http://_api._tcp.web:8080", 1)]).unwrap_err();
+        assert!(err.to_string().contains("SRV record"), "{err:#}");
+    }
+
+    #[test]
+    fn rejects_malformed_responses() {
+        let query = encode_query(7, "tasks.api", TYPE_A).unwrap();
+        let mut nxdomain = respond(&query, &[], &[]);
+        nxdomain[3] |= 3;
+        let err = parse_response(7, &nxdomain).unwrap_err();
+        assert!(err.to_string().contains("NXDOMAIN"));
+        assert!(parse_response(8, &respond(&query, &[], &[])).is_err());
+
+        // An answer whose name points at itself.
+        let mut looped = respond(&query, &[(TYPE_A, vec![10, 0, 0, 1])], &[]);
+        let at = query.len();
+        looped[at..at + 2].copy_from_slice(&[0xc0, at as u8]);
+        let err = parse_response(7, &looped).unwrap_err();
+        assert!(err.to_string().contains("loop"), "{err:#}");
+
+        let truncated = respond(&query, &[(TYPE_A, vec![10, 0, 0, 1])], &[]);
+        assert!(parse_response(7, &truncated[..truncated.len() - 2]).is_err());
+        assert!(encode_query(1, "a..b", TYPE_A).is_err());
+    }
+}
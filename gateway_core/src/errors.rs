@@ -0,0 +1,241 @@
+//! What went wrong with a request the gateway had to answer itself.
+//!
+//! Each gateway's `handle_client` classifies a failed request into one
+//! `GatewayError`, which fixes its status code, the `X-Gateway-Error-Code`
+//! header, the body (its `Display`; the error chain is only logged) and, in
+//! `gateway_host`, the `code` label of `gateway_errors_total`. Failures are tagged where they happen, with a
+//! `GatewayError` as anyhow context or with `chunked::AmbiguousFraming`;
+//! anything untagged is `Internal`, or `ClientBadRequest` when not even the
+//! request line was read. `gateway_host` recognises its own older markers on
+//! top of `classify`.
+
+use std::io;
+
+use crate::chunked;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GatewayError {
+    /// The request could not be read or parsed.
+    ClientBadRequest,
+    UpstreamConnect,
+    UpstreamTls,
+    /// The upstream sent nothing for `IO_TIMEOUT`.
+    UpstreamTimeout,
+    UpstreamFraming,
+    /// A failed request replayed from the cache (`CACHE_NEGATIVE_SECS`).
+    UpstreamCachedFailure,
+    /// Any other failure talking to the upstream after connecting.
+    Upstream,
+    /// The wasm runtime or worker process could not be started.
+    WasmSpawn,
+    /// The guest trapped or exited with a non-zero status.
+    WasmTrap,
+    WasmTimeout,
+    /// A non-wasm transform backend failed.
+    Transform,
+    /// No wasm process free within `WASM_QUEUE_TIMEOUT_MS`.
+    LimitsWasmQueue,
+    /// Shed by `MAX_INFLIGHT` admission.
+    LimitsShed,
+    /// `REQUEST_TIMEOUT_MS` ran out, in whichever step; see `deadline`.
+    Deadline,
+    Internal,
+}
+
+impl GatewayError {
+    pub const ALL: [GatewayError; 15] = [
+        GatewayError::ClientBadRequest,
+        GatewayError::UpstreamConnect,
+        GatewayError::UpstreamTls,
+        GatewayError::UpstreamTimeout,
+        GatewayError::UpstreamFraming,
+        GatewayError::UpstreamCachedFailure,
+        GatewayError::Upstream,
+        GatewayError::WasmSpawn,
+        GatewayError::WasmTrap,
+        GatewayError::WasmTimeout,
+        GatewayError::Transform,
+        GatewayError::LimitsWasmQueue,
+        GatewayError::LimitsShed,
+        GatewayError::Deadline,
+        GatewayError::Internal,
+    ];
+
+    /// The kind of `e`; `request_read` is false when the request line was
+    /// never parsed.
+    pub fn classify(e: &anyhow::Error, request_read: bool) -> GatewayError {
+        if e.downcast_ref::<chunked::AmbiguousFraming>().is_some() {
+            GatewayError::UpstreamFraming
+        } else if let Some(kind) = e.downcast_ref::<GatewayError>() {
+            *kind
+        } else if !request_read {
+            GatewayError::ClientBadRequest
+        } else {
+            GatewayError::Internal
+        }
+    }
+
+    /// Tags a failed exchange with a connected upstream: a timeout or not.
+    pub fn upstream(e: anyhow::Error) -> anyhow::Error {
+        let timed_out = e.chain().any(|cause| {
+            cause.downcast_ref::<io::Error>().is_some_and(|io| {
+                matches!(
+                    io.kind(),
+                    io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock
+                )
+            })
+        });
+        if timed_out {
+            e.context(GatewayError::UpstreamTimeout)
+        } else {
+            e.context(GatewayError::Upstream)
+        }
+    }
+
+    pub fn status_line(self) -> &'static str {
+        match self {
+            GatewayError::ClientBadRequest => "HTTP/1.1 400 Bad Request",
+            GatewayError::UpstreamTimeout | GatewayError::WasmTimeout | GatewayError::Deadline => {
+                "HTTP/1.1 504 Gateway Timeout"
+            }
+            GatewayError::LimitsWasmQueue | GatewayError::LimitsShed => {
+                "HTTP/1.1 503 Service Unavailable"
+            }
+            _ => "HTTP/1.1 502 Bad Gateway",
+        }
+    }
+
+    /// `X-Gateway-Error-Code` and the metrics label.
+    pub fn code(self) -> &'static str {
+        match self {
+            GatewayError::ClientBadRequest => "client-bad-request",
+            GatewayError::UpstreamConnect => "upstream-connect",
+            GatewayError::UpstreamTls => "upstream-tls",
+            GatewayError::UpstreamTimeout => "upstream-timeout",
+            GatewayError::UpstreamFraming => "upstream-framing",
+            GatewayError::UpstreamCachedFailure => "upstream-cached-failure",
+            GatewayError::Upstream => "upstream",
+            GatewayError::WasmSpawn => "wasm-spawn",
+            GatewayError::WasmTrap => "wasm-trap",
+            GatewayError::WasmTimeout => "wasm-timeout",
+            GatewayError::Transform => "transform",
+            GatewayError::LimitsWasmQueue => "limits-wasm-queue",
+            GatewayError::LimitsShed => "limits-shed",
+            GatewayError::Deadline => "deadline",
+            GatewayError::Internal => "internal",
+        }
+    }
+
+    /// `X-Gateway-Workload` of the error response, as before the taxonomy.
+    pub fn workload(self) -> &'static str {
+        match self {
+            GatewayError::UpstreamTls => "upstream-tls",
+            GatewayError::UpstreamFraming => "upstream-framing",
+            GatewayError::UpstreamCachedFailure => "upstream-cached-failure",
+            GatewayError::WasmTimeout => "wasm-timeout",
+            GatewayError::LimitsWasmQueue => "wasm-queue",
+            GatewayError::LimitsShed => "queued",
+            GatewayError::Deadline => "deadline",
+            _ => "error",
+        }
+    }
+
+    /// Headers the error response has always carried for this kind.
+    pub fn headers(self) -> &'static [(&'static str, &'static str)] {
+        match self {
+            GatewayError::UpstreamTls => &[("X-Upstream-Error", "tls-handshake")],
+            GatewayError::UpstreamFraming => &[("X-Upstream-Error", "framing")],
+            GatewayError::UpstreamCachedFailure => {
+                &[("X-Cache", "HIT"), ("X-Cached-Failure", "connect")]
+            }
+            GatewayError::WasmTimeout => &[("X-Wasm-Error", "timeout")],
+            GatewayError::LimitsWasmQueue => {
+                &[("X-Wasm-Error", "queue-timeout"), ("Retry-After", "1")]
+            }
+            GatewayError::LimitsShed => &[("Retry-After", "1")],
+            _ => &[],
+        }
+    }
+}
+
+impl std::fmt::Display for GatewayError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            GatewayError::ClientBadRequest => "bad request",
+            GatewayError::UpstreamConnect => "upstream connect failed",
+            GatewayError::UpstreamTls => "upstream TLS failed",
+            GatewayError::UpstreamTimeout => "upstream timed out",
+            GatewayError::UpstreamFraming => "upstream framing is ambiguous",
+            GatewayError::UpstreamCachedFailure => "cached upstream failure",
+            GatewayError::Upstream => "upstream request failed",
+            GatewayError::WasmSpawn => "wasm runtime failed to start",
+            GatewayError::WasmTrap => "wasm module failed",
+            GatewayError::WasmTimeout => "wasm module timed out",
+            GatewayError::Transform => "transform failed",
+            GatewayError::LimitsWasmQueue => "no wasm process free",
+            GatewayError::LimitsShed => "gateway busy",
+            GatewayError::Deadline => "request deadline exceeded",
+            GatewayError::Internal => "internal error",
+        })
+    }
+}
+
+impl std::error::Error for GatewayError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::{anyhow, Context};
+
+    #[test]
+    fn classifies_tagged_and_marked_errors() {
+        let refused: anyhow::Result<()> =
+            Err(io::Error::from(io::ErrorKind::ConnectionRefused).into());
+        let e = refused
+            .context(GatewayError::UpstreamConnect)
+            .context("connect upstream 127.0.0.1:1")
+            .unwrap_err();
+        assert_eq!(e.to_string(), "connect upstream 127.0.0.1:1");
+        let kind = GatewayError::classify(&e, true);
+        assert_eq!(kind, GatewayError::UpstreamConnect);
+        // What the client is told names the kind, not the upstream.
+        assert_eq!(kind.to_string(), "upstream connect failed");
+
+        let timed_out = anyhow::Error::new(io::Error::from(io::ErrorKind::WouldBlock))
+            .context("read upstream response");
+        let e = GatewayError::upstream(timed_out);
+        assert_eq!(
+            GatewayError::classify(&e, true),
+            GatewayError::UpstreamTimeout
+        );
+        assert_eq!(
+            GatewayError::UpstreamTimeout.status_line(),
+            "HTTP/1.1 504 Gateway Timeout"
+        );
+        let reset = anyhow::Error::new(io::Error::from(io::ErrorKind::ConnectionReset));
+        assert_eq!(
+            GatewayError::classify(&GatewayError::upstream(reset), true),
+            GatewayError::Upstream
+        );
+
+        let e = anyhow::Error::new(chunked::AmbiguousFraming("two lengths".into()))
+            .context("decode upstream response");
+        assert_eq!(
+            GatewayError::classify(&e, true),
+            GatewayError::UpstreamFraming
+        );
+        assert_eq!(GatewayError::UpstreamFraming.workload(), "upstream-framing");
+
+        let e = anyhow!("invalid request line");
+        assert_eq!(
+            GatewayError::classify(&e, false),
+            GatewayError::ClientBadRequest
+        );
+        assert_eq!(GatewayError::classify(&e, true), GatewayError::Internal);
+
+        let mut codes: Vec<_> = GatewayError::ALL.iter().map(|k| k.code()).collect();
+        codes.sort();
+        codes.dedup();
+        assert_eq!(codes.len(), GatewayError::ALL.len());
+    }
+}
@@ -0,0 +1,167 @@
+//! Build and machine fingerprint for `/stats`, so a benchmark result can be
+//! tied to the binary and environment that produced it.
+//!
+//! Build facts (rustc version, profile, opt-level, target triple) come from
+//! `build.rs`. The machine is read once: CPU model from `/proc/cpuinfo`,
+//! logical CPUs, and the container's CPU and memory limits from cgroup v2
+//! (`cpu.max`, `memory.max`) or v1 (`cpu.cfs_quota_us`,
+//! `memory.limit_in_bytes`). Limits are `null` when unlimited or unknown.
+
+use once_cell::sync::Lazy;
+use std::path::Path;
+
+use crate::{json_string, variant};
+
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+/// cgroup v1 reports "no memory limit" as a huge page-aligned number.
+const V1_UNLIMITED_MEMORY: u64 = 1 << 62;
+
+static FINGERPRINT: Lazy<Fingerprint> = Lazy::new(|| {
+    let (cpu_limit, memory_limit_bytes) = container_limits(Path::new(CGROUP_ROOT));
+    Fingerprint {
+        cpu_model: std::fs::read_to_string("/proc/cpuinfo")
+            .ok()
+            .and_then(|info| cpu_model(&info)),
+        cpus: std::thread::available_parallelism().map_or(1, |n| n.get()),
+        cpu_limit,
+        memory_limit_bytes,
+    }
+});
+
+#[derive(Debug, PartialEq)]
+pub struct Fingerprint {
+    pub cpu_model: Option<String>,
+    /// Logical CPUs the process may run on.
+    pub cpus: usize,
+    /// Cores, from the CPU quota.
+    pub cpu_limit: Option<f64>,
+    pub memory_limit_bytes: Option<u64>,
+}
+
+pub fn get() -> &'static Fingerprint {
+    &FINGERPRINT
+}
+
+impl Fingerprint {
+    pub fn to_json(&self) -> String {
+        let or_null = |v: Option<String>| v.unwrap_or_else(|| "null".to_string());
+        format!(
+            concat!(
+                "{{\"variant\":{},\"version\":{},\"rustc\":{},\"profile\":{},",
+                "\"opt_level\":{},\"target\":{},\"cpu_model\":{},\"cpus\":{},",
+                "\"cpu_limit\":{},\"memory_limit_bytes\":{}}}"
+            ),
+            json_string(variant()),
+            json_string(env!("CARGO_PKG_VERSION")),
+            json_string(env!("GATEWAY_RUSTC_VERSION")),
+            json_string(env!("GATEWAY_BUILD_PROFILE")),
+            json_string(env!("GATEWAY_BUILD_OPT_LEVEL")),
+            json_string(env!("GATEWAY_BUILD_TARGET")),
+            or_null(self.cpu_model.as_deref().map(json_string)),
+            self.cpus,
+            or_null(self.cpu_limit.map(|c| format!("{c:.3}"))),
+            or_null(self.memory_limit_bytes.map(|b| b.to_string())),
+        )
+    }
+}
+
+/// The CPU model: `model name` on x86; ARM kernels report `Hardware` or
+/// `Model` instead.
+fn cpu_model(cpuinfo: &str) -> Option<String> {
+    ["model name", "Hardware", "Model", "cpu model"]
+        .iter()
+        .find_map(|key| {
+            cpuinfo.lines().find_map(|line| {
+                let (name, value) = line.split_once(':')?;
+                (name.trim() == *key).then(|| value.trim().to_string())
+            })
+        })
+        .filter(|model| !model.is_empty())
+}
+
+/// CPU cores and memory bytes the container may use.
+fn container_limits(root: &Path) -> (Option<f64>, Option<u64>) {
+    let read = |file: &str| {
+        std::fs::read_to_string(root.join(file))
+            .ok()
+            .map(|v| v.trim().to_string())
+    };
+    if let Some(cpu_max) = read("cpu.max") {
+        // v2: "<quota> <period>" or "max <period>".
+        let cpu = cpu_max.split_once(' ').and_then(|(quota, period)| {
+            let (quota, period) = (quota.parse::<f64>().ok()?, period.parse::<f64>().ok()?);
+            (period > 0.0).then_some(quota / period)
+        });
+        let memory = read("memory.max").and_then(|v| v.parse::<u64>().ok());
+        return (cpu, memory);
+    }
+    let cpu = match (
+        read("cpu/cpu.cfs_quota_us").and_then(|v| v.parse::<i64>().ok()),
+        read("cpu/cpu.cfs_period_us").and_then(|v| v.parse::<i64>().ok()),
+    ) {
+        (Some(quota), Some(period)) if quota > 0 && period > 0 => {
+            Some(quota as f64 / period as f64)
+        }
+        _ => None,
+    };
+    let memory = read("memory/memory.limit_in_bytes")
+        .and_then(|v| v.parse::<u64>().ok())
+        .filter(|&bytes| bytes < V1_UNLIMITED_MEMORY);
+    (cpu, memory)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_cpu_model_and_container_limits() {
+        let x86 = "processor\t: 0\nvendor_id\t: GenuineIntel\nmodel name\t: Intel(R) Xeon(R) CPU @ 2.20GHz\n";
+        assert_eq!(
+            cpu_model(x86).as_deref(),
+            Some("Intel(R) Xeon(R) CPU @ 2.20GHz")
+        );
+        assert_eq!(
+            cpu_model("processor : 0\nModel : Raspberry Pi 4 Model B\n").as_deref(),
+            Some("Raspberry Pi 4 Model B")
+        );
+        assert_eq!(cpu_model("processor : 0\n"), None);
+
+        let root = std::env::temp_dir().join(format!("gateway-fingerprint-{}", std::process::id()));
+        std::fs::create_dir_all(root.join("cpu")).unwrap();
+        std::fs::create_dir_all(root.join("memory")).unwrap();
+        std::fs::write(root.join("cpu/cpu.cfs_quota_us"), "-1\n").unwrap();
+        std::fs::write(root.join("cpu/cpu.cfs_period_us"), "100000\n").unwrap();
+        std::fs::write(
+            root.join("memory/memory.limit_in_bytes"),
+            "9223372036854771712\n",
+        )
+        .unwrap();
+        assert_eq!(container_limits(&root), (None, None));
+
+        std::fs::write(root.join("cpu.max"), "150000 100000\n").unwrap();
+        std::fs::write(root.join("memory.max"), "536870912\n").unwrap();
+        assert_eq!(container_limits(&root), (Some(1.5), Some(536870912)));
+        std::fs::write(root.join("cpu.max"), "max 100000\n").unwrap();
+        std::fs::write(root.join("memory.max"), "max\n").unwrap();
+        assert_eq!(container_limits(&root), (None, None));
+        std::fs::remove_dir_all(&root).ok();
+
+        let fingerprint = Fingerprint {
+            cpu_model: Some("Test \"CPU\"".to_string()),
+            cpus: 4,
+            cpu_limit: Some(2.0),
+            memory_limit_bytes: None,
+        };
+        let json = fingerprint.to_json();
+        assert!(
+            json.contains("\"cpu_model\":\"Test \\\"CPU\\\"\""),
+            "{json}"
+        );
+        assert!(
+            json.contains("\"cpus\":4,\"cpu_limit\":2.000,\"memory_limit_bytes\":null"),
+            "{json}"
+        );
+        assert!(json.contains("\"rustc\":\"rustc "), "{json}");
+    }
+}
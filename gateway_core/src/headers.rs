@@ -0,0 +1,216 @@
+//! Ordered header multimap used on the request and response paths.
+//!
+//! Fields keep their order and the case of their names; lookups ignore case.
+//! Repeated fields stay separate entries, so every `Set-Cookie` survives the
+//! proxy, and obsolete line folding (continuation lines starting with a space
+//! or tab) is unfolded into the previous value while parsing.
+
+use anyhow::{anyhow, Context, Result};
+
+/// Fields that must never be combined into one comma-separated value.
+const NEVER_COMBINED: &[&str] = &["set-cookie"];
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Headers {
+    fields: Vec<(String, String)>,
+}
+
+impl Headers {
+    /// Parses the field lines of a head (everything after the start line).
+    /// Lines without a colon are ignored.
+    pub fn parse(lines: &str) -> Headers {
+        let mut headers = Headers::default();
+        for line in lines.split("\r\n") {
+            if line.starts_with([' ', '\t']) {
+                if let Some((_, value)) = headers.fields.last_mut() {
+                    let folded = line.trim();
+                    if !folded.is_empty() {
+                        if !value.is_empty() {
+                            value.push(' ');
+                        }
+                        value.push_str(folded);
+                    }
+                }
+                continue;
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                headers.append(name.trim(), value.trim());
+            }
+        }
+        headers
+    }
+
+    /// Splits a raw head (without the final blank line) into its start line
+    /// and fields.
+    pub fn parse_head(head: &[u8]) -> Result<(String, Headers)> {
+        let head = std::str::from_utf8(head).context("head not valid UTF-8")?;
+        let (start, rest) = head.split_once("\r\n").unwrap_or((head, ""));
+        if start.is_empty() {
+            return Err(anyhow!("missing start line"));
+        }
+        Ok((start.to_string(), Headers::parse(rest)))
+    }
+
+    /// First value of `name`.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.fields
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Every value of `name`, in order.
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.fields
+            .iter()
+            .filter(move |(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Every value of `name` joined with `, `, as a single field would carry
+    /// them; `None` when absent or for fields that cannot be combined.
+    pub fn get_joined(&self, name: &str) -> Option<String> {
+        if is_never_combined(name) {
+            return None;
+        }
+        let values: Vec<&str> = self.get_all(name).collect();
+        (!values.is_empty()).then(|| values.join(", "))
+    }
+
+    pub fn append(&mut self, name: impl Into<String>, value: impl Into<String>) {
+        self.fields.push((name.into(), value.into()));
+    }
+
+    pub fn remove(&mut self, name: &str) {
+        self.fields.retain(|(k, _)| !k.eq_ignore_ascii_case(name));
+    }
+
+    /// Rewrites every value of `name` in place; `None` drops that field.
+    pub fn rewrite_all(&mut self, name: &str, mut f: impl FnMut(&str) -> Option<String>) {
+        self.fields.retain_mut(|(k, v)| {
+            if !k.eq_ignore_ascii_case(name) {
+                return true;
+            }
+            match f(v) {
+                Some(new) => {
+                    *v = new;
+                    true
+                }
+                None => false,
+            }
+        });
+    }
+
+    /// Applies fields set by the gateway: each replaces the fields of the same
+    /// name, except `Set-Cookie`, which is added next to the existing ones. An
+    /// empty value only removes.
+    pub fn apply_overrides(&mut self, overrides: &[(&str, &str)]) {
+        for (name, _) in overrides {
+            if !is_never_combined(name) {
+                self.remove(name);
+            }
+        }
+        for (name, value) in overrides.iter().filter(|(_, v)| !v.is_empty()) {
+            self.append(*name, *value);
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.fields.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+
+    /// Writes `Name: value\r\n` for every field, in order, skipping any that
+    /// `syntax::write_field` rejects.
+    pub fn write_to(&self, out: &mut Vec<u8>) {
+        for (name, value) in &self.fields {
+            crate::syntax::write_field(out, name, value);
+        }
+    }
+}
+
+fn is_never_combined(name: &str) -> bool {
+    NEVER_COMBINED.iter().any(|n| n.eq_ignore_ascii_case(name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn written(headers: &Headers) -> String {
+        let mut out = Vec::new();
+        headers.write_to(&mut out);
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn parse_keeps_order_case_and_duplicates() {
+        let headers = Headers::parse("Set-Cookie: a=1\r\nX-One: 1\r\nset-cookie: b=2\r\n\r\n");
+        assert_eq!(
+            headers.iter().collect::<Vec<_>>(),
+            [("Set-Cookie", "a=1"), ("X-One", "1"), ("set-cookie", "b=2")]
+        );
+        assert_eq!(headers.get("SET-COOKIE"), Some("a=1"));
+        assert_eq!(
+            headers.get_all("set-cookie").collect::<Vec<_>>(),
+            ["a=1", "b=2"]
+        );
+    }
+
+    #[test]
+    fn parse_unfolds_continuation_lines() {
+        let headers = Headers::parse("X-Long: one\r\n two\r\n\tthree\r\nX-Next: 4");
+        assert_eq!(
+            headers.iter().collect::<Vec<_>>(),
+            [("X-Long", "one two three"), ("X-Next", "4")]
+        );
+        // A continuation with nothing to continue is dropped.
+        assert_eq!(Headers::parse(" stray\r\nA: b").iter().count(), 1);
+    }
+
+    #[test]
+    fn parse_head_splits_start_line() {
+        let (start, headers) =
+            Headers::parse_head(b"HTTP/1.1 200 OK\r\nContent-Type: text/plain").unwrap();
+        assert_eq!(start, "HTTP/1.1 200 OK");
+        assert_eq!(headers.get("content-type"), Some("text/plain"));
+        assert!(Headers::parse_head(b"").is_err());
+    }
+
+    #[test]
+    fn joined_values_skip_set_cookie() {
+        let headers =
+            Headers::parse("Accept: a\r\nSet-Cookie: x=1\r\naccept: b\r\nSet-Cookie: y=2");
+        assert_eq!(headers.get_joined("Accept").as_deref(), Some("a, b"));
+        assert_eq!(headers.get_joined("Set-Cookie"), None);
+        assert_eq!(headers.get_joined("Missing"), None);
+    }
+
+    #[test]
+    fn overrides_replace_but_set_cookie_accumulates() {
+        let mut headers = Headers::parse(
+            "Set-Cookie: a=1\r\nX-Trace: up\r\nSet-Cookie: b=2\r\nX-Drop: 1\r\nx-trace: up2",
+        );
+        headers.apply_overrides(&[("X-Trace", "gw"), ("Set-Cookie", "c=3"), ("X-Drop", "")]);
+        assert_eq!(
+            written(&headers),
+            "Set-Cookie: a=1\r\nSet-Cookie: b=2\r\nX-Trace: gw\r\nSet-Cookie: c=3\r\n"
+        );
+    }
+
+    #[test]
+    fn rewrite_all_maps_and_drops_each_value() {
+        let mut headers =
+            Headers::parse("Set-Cookie: a=1\r\nX: y\r\nSet-Cookie: drop=1\r\nSet-Cookie: b=2");
+        headers.rewrite_all("set-cookie", |v| {
+            (!v.starts_with("drop")).then(|| format!("{v}; Secure"))
+        });
+        assert_eq!(
+            written(&headers),
+            "Set-Cookie: a=1; Secure\r\nX: y\r\nSet-Cookie: b=2; Secure\r\n"
+        );
+    }
+}
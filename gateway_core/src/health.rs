@@ -0,0 +1,89 @@
+//! What `/health/full` and `/stats` report the same way in both gateways:
+//! process uptime and whether the upstream accepts connections.
+
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Context};
+use once_cell::sync::Lazy;
+
+use crate::http::Upstream;
+use crate::json_string;
+
+const PROBE_TIMEOUT: Duration = Duration::from_secs(1);
+
+static STARTED_AT: Lazy<Instant> = Lazy::new(Instant::now);
+
+/// Starts the uptime clock; call once from `main`.
+pub fn mark_started() {
+    Lazy::force(&STARTED_AT);
+}
+
+/// Time since `mark_started`.
+pub fn uptime() -> Duration {
+    STARTED_AT.elapsed()
+}
+
+/// Outcome of `probe_upstream`.
+#[derive(Debug)]
+pub struct UpstreamProbe {
+    pub reachable: bool,
+    /// The JSON object `/health/full` reports under `upstream`.
+    pub json: String,
+}
+
+/// Probes `upstream` with a bare TCP connect.
+pub fn probe_upstream(upstream: &Upstream) -> UpstreamProbe {
+    let probe_start = Instant::now();
+    let probe = (upstream.host.as_str(), upstream.port)
+        .to_socket_addrs()
+        .context("resolve upstream")
+        .and_then(|mut addrs| {
+            addrs
+                .next()
+                .ok_or_else(|| anyhow!("upstream resolved to no addresses"))
+        })
+        .and_then(|addr| {
+            TcpStream::connect_timeout(&addr, PROBE_TIMEOUT).context("connect upstream")
+        });
+    let probe_ms = probe_start.elapsed().as_secs_f64() * 1000.0;
+    let (reachable, error) = match probe {
+        Ok(_) => (true, "null".to_string()),
+        Err(e) => (false, json_string(&format!("{e:#}"))),
+    };
+    UpstreamProbe {
+        reachable,
+        json: format!(
+            "{{\"url\":{},\"reachable\":{},\"probe_ms\":{:.3},\"error\":{}}}",
+            json_string(&upstream.raw_url),
+            reachable,
+            probe_ms,
+            error
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::parse_upstream;
+    use std::net::TcpListener;
+
+    #[test]
+    fn probe_reports_reachable_and_refused_upstreams() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let up = parse_upstream(&format!("http://127.0.0.1:{port}")).unwrap();
+        let probe = probe_upstream(&up);
+        assert!(probe.reachable);
+        let json: serde_json::Value = serde_json::from_str(&probe.json).unwrap();
+        assert_eq!(json["url"], up.raw_url);
+        assert_eq!(json["error"], serde_json::Value::Null);
+
+        drop(listener);
+        let probe = probe_upstream(&up);
+        assert!(!probe.reachable);
+        let json: serde_json::Value = serde_json::from_str(&probe.json).unwrap();
+        assert!(json["error"].as_str().unwrap().contains("connect upstream"));
+    }
+}
@@ -0,0 +1,605 @@
+//! Reading, parsing and forwarding requests and building responses.
+//!
+//! Requests are read whole: the head up to `MAX_HEADER_BYTES`, then a
+//! `Content-Length` body up to `MAX_REQ_BODY_BYTES` (or streamed with
+//! `read_body_chunks`). Upstream responses are read to EOF, since the
+//! forwarded request always says `Connection: close`, and re-framed with
+//! `rebuild_response`; the gateway's own answers come from `build_response`.
+//! Every header field written here goes through `syntax::write_field`.
+
+use std::io::{Read, Write};
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+use once_cell::sync::Lazy;
+use sha2::{Digest, Sha256};
+use url::Url;
+
+use crate::headers::Headers;
+use crate::{chunked, secrets, syntax, variant};
+
+/// Socket read and write timeout for clients and upstreams.
+pub const IO_TIMEOUT: Duration = Duration::from_secs(5);
+pub const MAX_HEADER_BYTES: usize = 64 * 1024;
+pub const MAX_REQ_BODY_BYTES: usize = 2 * 1024 * 1024;
+pub const MAX_RESP_BYTES: usize = 10 * 1024 * 1024;
+
+/// `BODY_SHA256=1`: responses sent whole carry `X-Body-Sha256`.
+static BODY_SHA256: Lazy<bool> = Lazy::new(|| std::env::var("BODY_SHA256").is_ok_and(|v| v == "1"));
+
+/// The path of a request target, without its query.
+pub fn route_path(path: &str) -> &str {
+    path.split_once('?').map_or(path, |(p, _)| p)
+}
+
+/// The first `key` in the query of `path`, undecoded (`/compute?iters=123`).
+pub fn query_param(path: &str, key: &str) -> Option<String> {
+    let (_, q) = path.split_once('?')?;
+    for pair in q.split('&') {
+        let (k, v) = pair.split_once('=').unwrap_or((pair, ""));
+        if k == key {
+            return Some(v.to_string());
+        }
+    }
+    None
+}
+
+/// Whether `req` carries `Authorization: Bearer <expected>`; any request
+/// passes when no token is expected.
+pub fn bearer_token_matches(req: &RequestLine, expected: Option<&str>) -> bool {
+    let Some(expected) = expected else {
+        return true;
+    };
+    let presented = req
+        .header("Authorization")
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .unwrap_or("");
+    secrets::constant_time_eq(presented.as_bytes(), expected.as_bytes())
+}
+
+/// Where requests are forwarded: an `UPSTREAM_URL` (or pool member) taken
+/// apart once at startup.
+#[derive(Clone, Debug)]
+pub struct Upstream {
+    pub host: String,
+    pub port: u16,
+    /// Path of `UPSTREAM_URL` without trailing slashes; empty for the root.
+    pub base_path: String,
+    /// Query of `UPSTREAM_URL`, sent ahead of the request's own query.
+    pub base_query: Option<String>,
+    pub raw_url: String,
+    /// `https://`, which only `gateway_host` can speak.
+    pub tls: bool,
+}
+
+/// Parses an `http://` or `https://` upstream URL.
+pub fn parse_upstream(s: &str) -> Result<Upstream> {
+    let url = Url::parse(s).with_context(|| format!("invalid UPSTREAM_URL={s}"))?;
+    if url.scheme() != "http" && url.scheme() != "https" {
+        return Err(anyhow!(
+            "only http and https upstreams supported (got scheme {})",
+            url.scheme()
+        ));
+    }
+    let host = url
+        .host_str()
+        .ok_or_else(|| anyhow!("UPSTREAM_URL missing host"))?
+        .to_string();
+    let port = url
+        .port_or_known_default()
+        .ok_or_else(|| anyhow!("UPSTREAM_URL missing port"))?;
+    let base_path = url.path().trim_end_matches('/').to_string();
+    Ok(Upstream {
+        host,
+        port,
+        base_path,
+        base_query: url.query().filter(|q| !q.is_empty()).map(str::to_string),
+        raw_url: s.to_string(),
+        tls: url.scheme() == "https",
+    })
+}
+
+/// Request target sent upstream: `base_path` and the request path joined with
+/// a single `/` (a trailing slash on the request path is kept, so `/` maps to
+/// `base_path/`), then the upstream URL's query followed by the request's.
+/// Percent-encoding is passed through as received, fragments are dropped and
+/// targets not in origin form (`*`) are forwarded unchanged.
+pub fn forwarded_target(upstream: &Upstream, target: &str) -> String {
+    let target = target.split_once('#').map_or(target, |(t, _)| t);
+    if !target.starts_with('/') {
+        return target.to_string();
+    }
+    let (path, query) = match target.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (target, None),
+    };
+    let mut out = if upstream.base_path.is_empty() {
+        path.to_string()
+    } else {
+        format!("{}/{}", upstream.base_path, path.trim_start_matches('/'))
+    };
+    let queries: Vec<&str> = [upstream.base_query.as_deref(), query]
+        .into_iter()
+        .flatten()
+        .filter(|q| !q.is_empty())
+        .collect();
+    if !queries.is_empty() {
+        out.push('?');
+        out.push_str(&queries.join("&"));
+    }
+    out
+}
+
+/// A parsed request head.
+#[derive(Debug)]
+pub struct RequestLine {
+    pub method: String,
+    /// The target routing sees, after `NORMALIZE` and access-rule rewrites.
+    pub path: String,
+    /// The target as the client sent it (in origin form), which request
+    /// signatures cover.
+    pub target: String,
+    pub version: String,
+    pub content_length: usize,
+    pub headers: Headers,
+}
+
+impl RequestLine {
+    /// First value of a header, matched case-insensitively.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name)
+    }
+}
+
+/// Reads the request head up to CRLFCRLF. Returns the head and any bytes already
+/// read past it (the start of the body).
+pub fn read_http_head(stream: &mut impl Read) -> Result<(Vec<u8>, Vec<u8>)> {
+    let mut buf = Vec::<u8>::new();
+    let mut tmp = [0u8; 4096];
+
+    loop {
+        let n = stream.read(&mut tmp).context("read from client")?;
+        if n == 0 {
+            return Err(anyhow!("client closed before request complete"));
+        }
+        buf.extend_from_slice(&tmp[..n]);
+
+        if buf.len() > MAX_HEADER_BYTES {
+            return Err(anyhow!("request headers too large"));
+        }
+        if find_double_crlf(&buf).is_some() {
+            break;
+        }
+    }
+
+    let header_end = find_double_crlf(&buf).ok_or_else(|| anyhow!("malformed headers"))?;
+    let head = buf[..header_end].to_vec();
+    let remainder = buf[header_end + 4..].to_vec();
+    Ok((head, remainder))
+}
+
+/// Reads a Content-Length delimited body into memory.
+/// Does NOT support chunked transfer encoding.
+pub fn read_http_body(
+    stream: &mut impl Read,
+    remainder: Vec<u8>,
+    content_length: usize,
+) -> Result<Vec<u8>> {
+    if content_length == 0 {
+        return Ok(Vec::new());
+    }
+    if content_length > MAX_REQ_BODY_BYTES {
+        return Err(anyhow!(
+            "request body too large (Content-Length {content_length})"
+        ));
+    }
+
+    let mut body = Vec::with_capacity(content_length);
+    read_body_chunks(stream, remainder, content_length, |chunk| {
+        body.extend_from_slice(chunk);
+        Ok(())
+    })?;
+    Ok(body)
+}
+
+/// Feeds exactly `content_length` body bytes to `on_chunk` without buffering them.
+pub fn read_body_chunks(
+    stream: &mut impl Read,
+    remainder: Vec<u8>,
+    content_length: usize,
+    mut on_chunk: impl FnMut(&[u8]) -> Result<()>,
+) -> Result<()> {
+    let mut got = remainder.len().min(content_length);
+    on_chunk(&remainder[..got])?;
+
+    let mut tmp = [0u8; 16 * 1024];
+    while got < content_length {
+        let n = stream.read(&mut tmp).context("read request body")?;
+        if n == 0 {
+            return Err(anyhow!(
+                "client closed during body read (got {got}, expected {content_length})"
+            ));
+        }
+        let take = n.min(content_length - got);
+        on_chunk(&tmp[..take])?;
+        got += take;
+    }
+    Ok(())
+}
+
+/// Parses a request head. An absolute-form target (`http://host/path`) is
+/// reduced to origin form, and its authority replaces `Host`, so routing
+/// only ever sees `/path`.
+pub fn parse_request_head(head: &[u8]) -> Result<RequestLine> {
+    let s = std::str::from_utf8(head).context("headers not valid UTF-8")?;
+    let (request_line, field_lines) = s.split_once("\r\n").unwrap_or((s, ""));
+    let (method, target, version) = syntax::parse_request_line(request_line)?;
+
+    let mut headers = Headers::parse(field_lines);
+    let path = match absolute_form(target) {
+        Some((authority, path)) => {
+            if authority.is_empty() {
+                return Err(anyhow!("missing host in request target"));
+            }
+            headers.apply_overrides(&[("Host", authority)]);
+            path
+        }
+        None => target.to_string(),
+    };
+    let (method, version) = (method.to_string(), version.to_string());
+    // Repeated Content-Length fields are only acceptable when they agree.
+    let lengths: Vec<&str> = headers.get_all("content-length").collect();
+    if lengths.windows(2).any(|pair| pair[0] != pair[1]) {
+        return Err(anyhow!("conflicting Content-Length headers"));
+    }
+    let content_length = match lengths.first() {
+        Some(value) => value.parse::<usize>().context("invalid Content-Length")?,
+        None => 0,
+    };
+
+    Ok(RequestLine {
+        method,
+        target: path.clone(),
+        path,
+        version,
+        content_length,
+        headers,
+    })
+}
+
+/// `(authority, origin-form target)` of an `http://` or `https://` target.
+fn absolute_form(target: &str) -> Option<(&str, String)> {
+    let rest = ["http://", "https://"].iter().find_map(|scheme| {
+        target
+            .get(..scheme.len())
+            .filter(|prefix| prefix.eq_ignore_ascii_case(scheme))
+            .map(|_| &target[scheme.len()..])
+    })?;
+    let split = rest.find(['/', '?']).unwrap_or(rest.len());
+    let (authority, path) = rest.split_at(split);
+    let path = if path.starts_with('/') {
+        path.to_string()
+    } else {
+        format!("/{path}")
+    };
+    Some((authority, path))
+}
+
+/// Offset of the blank line ending a head.
+pub fn find_double_crlf(buf: &[u8]) -> Option<usize> {
+    buf.windows(4).position(|w| w == b"\r\n\r\n")
+}
+
+/// Rewrites request line to send `target` under upstream base_path.
+/// Rewrites Host.
+/// Forces Connection: close.
+/// Appends `extra_headers`, dropping any client-sent headers of the same name
+/// (an empty value only drops).
+pub fn build_forwarded_request(
+    req: &RequestLine,
+    target: &str,
+    body: &[u8],
+    upstream: &Upstream,
+    extra_headers: &[(&str, &str)],
+) -> Result<Vec<u8>> {
+    let forwarded_path = forwarded_target(upstream, target);
+
+    let mut out = Vec::<u8>::new();
+    out.extend_from_slice(
+        format!("{} {} {}\r\n", req.method, forwarded_path, req.version).as_bytes(),
+    );
+
+    let mut headers = req.headers.clone();
+    for name in ["Host", "Connection", "Expect"] {
+        headers.remove(name);
+    }
+    headers.apply_overrides(extra_headers);
+    headers.write_to(&mut out);
+    syntax::write_field(&mut out, "Host", &upstream.host);
+    out.extend_from_slice(b"Connection: close\r\n");
+    out.extend_from_slice(b"\r\n");
+    out.extend_from_slice(body);
+
+    Ok(out)
+}
+
+/// Minimal response read: read until EOF (Connection: close).
+pub fn read_all_response(stream: &mut impl Read) -> Result<Vec<u8>> {
+    let mut resp = Vec::<u8>::new();
+    let mut tmp = [0u8; 8192];
+
+    loop {
+        let n = stream.read(&mut tmp).context("read upstream response")?;
+        if n == 0 {
+            break;
+        }
+        resp.extend_from_slice(&tmp[..n]);
+        if resp.len() > MAX_RESP_BYTES {
+            return Err(anyhow!("upstream response too large"));
+        }
+    }
+
+    Ok(resp)
+}
+
+pub fn split_http_response(resp: &[u8]) -> Result<(Vec<u8>, Vec<u8>)> {
+    let header_end = find_double_crlf(resp).ok_or_else(|| anyhow!("invalid upstream response"))?;
+    let head = resp[..header_end].to_vec();
+    let body = resp[header_end + 4..].to_vec();
+    Ok((head, body))
+}
+
+pub fn parse_status_code_from_head(head: &[u8]) -> Result<u16> {
+    let head_str = std::str::from_utf8(head).context("resp head not utf8")?;
+    let status_line = head_str
+        .split("\r\n")
+        .next()
+        .ok_or_else(|| anyhow!("missing status line"))?;
+    let status = status_line
+        .split_whitespace()
+        .nth(1)
+        .ok_or_else(|| anyhow!("missing status code"))?
+        .parse::<u16>()
+        .context("invalid status code")?;
+    Ok(status)
+}
+
+/// A complete response of the gateway's own, sent with `Content-Length`.
+pub fn build_response(
+    status_line: &str,
+    body: &[u8],
+    workload: &str,
+    content_type: Option<&str>,
+    extra_headers: &[(&str, &str)],
+) -> Vec<u8> {
+    let mut out = Vec::<u8>::new();
+    out.extend_from_slice(status_line.as_bytes());
+    out.extend_from_slice(b"\r\n");
+
+    if let Some(content_type) = content_type {
+        syntax::write_field(&mut out, "Content-Type", content_type);
+    }
+
+    syntax::write_field(&mut out, "X-Gateway-Variant", variant());
+    syntax::write_field(&mut out, "X-Gateway-Workload", workload);
+
+    for (name, value) in extra_headers {
+        syntax::write_field(&mut out, name, value);
+    }
+    if *BODY_SHA256 {
+        syntax::write_field(
+            &mut out,
+            "X-Body-Sha256",
+            &hex::encode(Sha256::digest(body)),
+        );
+    }
+
+    out.extend_from_slice(format!("Content-Length: {}\r\n", body.len()).as_bytes());
+    out.extend_from_slice(b"Connection: close\r\n\r\n");
+    out.extend_from_slice(body);
+    out
+}
+
+/// The head of a streamed response; the body follows as `write_chunk`s and
+/// a final `0\r\n\r\n`.
+pub fn build_chunked_head(
+    status_line: &str,
+    workload: &str,
+    content_type: Option<&str>,
+    extra_headers: &[(&str, &str)],
+) -> Vec<u8> {
+    let mut out = Vec::<u8>::new();
+    out.extend_from_slice(status_line.as_bytes());
+    out.extend_from_slice(b"\r\n");
+
+    if let Some(content_type) = content_type {
+        syntax::write_field(&mut out, "Content-Type", content_type);
+    }
+
+    syntax::write_field(&mut out, "X-Gateway-Variant", variant());
+    syntax::write_field(&mut out, "X-Gateway-Workload", workload);
+
+    for (name, value) in extra_headers {
+        syntax::write_field(&mut out, name, value);
+    }
+
+    out.extend_from_slice(b"Transfer-Encoding: chunked\r\n");
+    out.extend_from_slice(b"Connection: close\r\n\r\n");
+    out
+}
+
+pub fn write_chunk(w: &mut impl Write, data: &[u8]) -> Result<()> {
+    if data.is_empty() {
+        return Ok(());
+    }
+    w.write_all(format!("{:x}\r\n", data.len()).as_bytes())?;
+    w.write_all(data)?;
+    w.write_all(b"\r\n")?;
+    Ok(())
+}
+
+/// Upstream response re-framed by the gateway: stale framing and gateway
+/// fields dropped, `X-Gateway-*` and `extra_headers` added (see
+/// `Headers::apply_overrides`). `body` is sent with `Content-Length`, or as one
+/// chunk followed by `trailers` (announced in `Trailer`) when there are any.
+pub fn rebuild_response(
+    status_line: &str,
+    mut headers: Headers,
+    body: &[u8],
+    trailers: &Headers,
+    workload: &str,
+    extra_headers: &[(&str, &str)],
+) -> Vec<u8> {
+    for name in [
+        "Content-Length",
+        "Transfer-Encoding",
+        "Trailer",
+        "Connection",
+        "X-Gateway-Variant",
+        "X-Gateway-Workload",
+        "X-Upstream-Url",
+        "X-Upstream-Status",
+    ] {
+        headers.remove(name);
+    }
+    headers.append("X-Gateway-Variant", variant());
+    headers.append("X-Gateway-Workload", workload);
+    headers.apply_overrides(extra_headers);
+    if *BODY_SHA256 {
+        // The upstream's own digest no longer describes a transformed body.
+        headers.remove("X-Body-Sha256");
+        headers.append("X-Body-Sha256", hex::encode(Sha256::digest(body)));
+    }
+
+    if trailers.is_empty() {
+        headers.append("Content-Length", body.len().to_string());
+    } else {
+        let mut names: Vec<&str> = Vec::new();
+        for (name, _) in trailers.iter() {
+            if !names.iter().any(|n| n.eq_ignore_ascii_case(name)) {
+                names.push(name);
+            }
+        }
+        headers.append("Trailer", names.join(", "));
+        headers.append("Transfer-Encoding", "chunked");
+    }
+    headers.append("Connection", "close");
+
+    let mut out = Vec::<u8>::new();
+    out.extend_from_slice(status_line.as_bytes());
+    out.extend_from_slice(b"\r\n");
+    headers.write_to(&mut out);
+    out.extend_from_slice(b"\r\n");
+    if trailers.is_empty() {
+        out.extend_from_slice(body);
+    } else {
+        chunked::encode(&mut out, body, trailers);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn query_params_and_bearer_tokens() {
+        assert_eq!(
+            query_param("/compute?iters=5&seed", "iters").as_deref(),
+            Some("5")
+        );
+        assert_eq!(
+            query_param("/compute?iters=5&seed", "seed").as_deref(),
+            Some("")
+        );
+        assert_eq!(query_param("/compute?iters=5", "seed"), None);
+        assert_eq!(query_param("/compute", "iters"), None);
+
+        let req =
+            parse_request_head(b"GET / HTTP/1.1\r\nAuthorization: Bearer s3cret\r\n").unwrap();
+        assert!(bearer_token_matches(&req, None));
+        assert!(bearer_token_matches(&req, Some("s3cret")));
+        assert!(!bearer_token_matches(&req, Some("s3cre")));
+        let anonymous = parse_request_head(b"GET / HTTP/1.1\r\n").unwrap();
+        assert!(!bearer_token_matches(&anonymous, Some("s3cret")));
+    }
+
+    #[test]
+    fn reads_and_forwards_a_request() {
+        let mut client: &[u8] =
+            b"POST /users?x=1 HTTP/1.1\r\nHost: gw\r\nContent-Length: 5\r\nExpect: 100-continue\r\nX-Id: 7\r\n\r\nhel";
+        let (head, remainder) = read_http_head(&mut client).unwrap();
+        let req = parse_request_head(&head).unwrap();
+        assert_eq!(
+            (req.method.as_str(), req.path.as_str()),
+            ("POST", "/users?x=1")
+        );
+        assert_eq!(req.header("x-id"), Some("7"));
+        let mut rest: &[u8] = b"lo";
+        let body = read_http_body(&mut rest, remainder, req.content_length).unwrap();
+        assert_eq!(body, b"hello");
+
+        let upstream = parse_upstream("http://up:8080/api?key=k").unwrap();
+        let out =
+            build_forwarded_request(&req, &req.path, &body, &upstream, &[("X-Id", "")]).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            concat!(
+                "POST /api/users?key=k&x=1 HTTP/1.1\r\n",
+                "Content-Length: 5\r\n",
+                "Host: up\r\n",
+                "Connection: close\r\n\r\n",
+                "hello"
+            )
+        );
+
+        assert!(
+            parse_request_head(b"GET / HTTP/1.1\r\nContent-Length: 1\r\nContent-Length: 2")
+                .is_err()
+        );
+        let mut short: &[u8] = b"GET / HTTP/1.1\r\nHost: gw\r\n";
+        assert!(read_http_head(&mut short).is_err());
+    }
+
+    #[test]
+    fn reduces_absolute_form_targets() {
+        let parse = |head: &str| parse_request_head(head.as_bytes());
+        let req =
+            parse("GET HTTP://Api.Example:8080/admin?x=1 HTTP/1.1\r\nHost: other\r\n").unwrap();
+        assert_eq!(req.path, "/admin?x=1");
+        assert_eq!(
+            req.headers.get_all("host").collect::<Vec<_>>(),
+            ["Api.Example:8080"]
+        );
+        assert_eq!(parse("GET http://h HTTP/1.1\r\n").unwrap().path, "/");
+        assert_eq!(parse("GET https://h?q HTTP/1.1\r\n").unwrap().path, "/?q");
+        assert_eq!(
+            parse("GET /plain HTTP/1.1\r\nHost: h\r\n")
+                .unwrap()
+                .header("Host"),
+            Some("h")
+        );
+        assert!(parse("GET http:///admin HTTP/1.1\r\n").is_err());
+    }
+
+    #[test]
+    fn builds_and_rebuilds_responses() {
+        let resp = build_response("HTTP/1.1 200 OK", b"hi", "echo", Some("text/plain"), &[]);
+        let (head, body) = split_http_response(&resp).unwrap();
+        assert_eq!(parse_status_code_from_head(&head).unwrap(), 200);
+        assert_eq!(body, b"hi");
+        assert_eq!(chunked::audit_response(&resp, false), Ok(()));
+
+        let (status, headers) = Headers::parse_head(
+            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\nX-Cache: HIT\r\nX-Upstream-Url: stale",
+        )
+        .unwrap();
+        let trailers = Headers::parse("grpc-status: 0");
+        let resp = rebuild_response(&status, headers, b"abcd", &trailers, "proxy", &[]);
+        let text = String::from_utf8(resp.clone()).unwrap();
+        assert!(text.contains("X-Cache: HIT\r\n"));
+        assert!(!text.contains("stale"));
+        assert!(text.ends_with("4\r\nabcd\r\n0\r\ngrpc-status: 0\r\n\r\n"));
+        assert_eq!(chunked::audit_response(&resp, false), Ok(()));
+    }
+}
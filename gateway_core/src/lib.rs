@@ -0,0 +1,65 @@
+//! Code shared by `gateway_host` and `gateway_native`: the HTTP/1.1 plumbing
+//! (reading and parsing requests, building the forwarded request, framing
+//! responses) and the serving infrastructure around it (accept backoff,
+//! CPU affinity, allocator stats, upstream balancing and DNS, clustering,
+//! logging, profiling, service registration, secrets and the shared store),
+//! the routes both serve the same way (the `/compute`, `/upload` and `/echo`
+//! workloads, the `/state` counter and the `/health/full` upstream probe)
+//! and the error kinds both answer failed requests with.
+//! Each binary names itself once with `set_variant` at startup; that name
+//! goes into `X-Gateway-Variant` and the log lines written from here.
+
+pub mod accept;
+pub mod affinity;
+pub mod allocator;
+pub mod balancer;
+pub mod chunked;
+pub mod cluster;
+pub mod cookies;
+pub mod dns;
+pub mod errors;
+pub mod fingerprint;
+pub mod headers;
+pub mod health;
+pub mod http;
+pub mod logging;
+pub mod profiling;
+pub mod registry;
+pub mod secrets;
+pub mod state;
+pub mod store;
+pub mod syntax;
+pub mod workloads;
+
+use once_cell::sync::OnceCell;
+
+static VARIANT: OnceCell<&'static str> = OnceCell::new();
+
+/// Names the gateway (`wasm-host`, `native`). The first call wins.
+pub fn set_variant(name: &'static str) {
+    VARIANT.get_or_init(|| name);
+}
+
+/// The name given to `set_variant`, or `gateway` before it is called.
+pub fn variant() -> &'static str {
+    VARIANT.get().copied().unwrap_or("gateway")
+}
+
+/// `s` as a JSON string literal.
+pub fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
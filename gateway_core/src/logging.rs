@@ -0,0 +1,239 @@
+//! Log destinations and rotation.
+//!
+//! Two streams: the access log (one line per proxied request) and the error
+//! log (everything else the gateway prints, plus the stderr of child
+//! processes). Each goes to stderr by default or to a file:
+//!
+//! - `ACCESS_LOG`: `stderr` (default), `off`, or a file path.
+//! - `ERROR_LOG`: `stderr` (default) or a file path. The file replaces the
+//!   process's stderr, so nothing written there is lost.
+//!
+//! Log files rotate once they reach `LOG_MAX_MB` and/or every
+//! `LOG_ROTATE_SECS`: `gateway.log` becomes `gateway.log.1`, older files
+//! shift up, and only `LOG_KEEP` (default 5) rotated files are kept.
+
+use anyhow::{anyhow, Context, Result};
+use once_cell::sync::OnceCell;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::variant;
+
+const DEFAULT_KEEP: usize = 5;
+/// How often the error log is checked for rotation; its writes bypass us.
+const ERROR_LOG_CHECK: Duration = Duration::from_secs(1);
+
+static ACCESS: OnceCell<AccessLog> = OnceCell::new();
+
+enum AccessLog {
+    Stderr,
+    Off,
+    File(Mutex<RotatingFile>),
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Rotation {
+    /// Rotate once the file reaches this size.
+    pub max_bytes: Option<u64>,
+    /// Rotate when the file has been open this long.
+    pub every: Option<Duration>,
+    /// Rotated files kept (`path.1` is the newest).
+    pub keep: usize,
+}
+
+impl Rotation {
+    fn from_env() -> Result<Self> {
+        let number = |var: &str| -> Result<Option<u64>> {
+            match std::env::var(var) {
+                Ok(v) if !v.trim().is_empty() => v
+                    .trim()
+                    .parse::<u64>()
+                    .map(|n| Some(n).filter(|n| *n > 0))
+                    .with_context(|| format!("invalid {var}={v}")),
+                _ => Ok(None),
+            }
+        };
+        Ok(Rotation {
+            max_bytes: number("LOG_MAX_MB")?.map(|mb| mb * 1024 * 1024),
+            every: number("LOG_ROTATE_SECS")?.map(Duration::from_secs),
+            keep: match std::env::var("LOG_KEEP") {
+                Ok(v) if !v.trim().is_empty() => v
+                    .trim()
+                    .parse()
+                    .with_context(|| format!("invalid LOG_KEEP={v}"))?,
+                _ => DEFAULT_KEEP,
+            },
+        })
+    }
+}
+
+/// Sets up both logs from the environment. Call first thing in `main`, so
+/// the startup lines already land in `ERROR_LOG`.
+pub fn init_from_env() -> Result<()> {
+    let rotation = Rotation::from_env()?;
+    let target = |var: &str| std::env::var(var).ok().filter(|v| !v.trim().is_empty());
+
+    if let Some(path) = target("ERROR_LOG").filter(|p| p != "stderr") {
+        let mut file = RotatingFile::open(PathBuf::from(&path), rotation)?;
+        file.redirect_stderr = true;
+        file.redirect()
+            .with_context(|| format!("redirect stderr to {path}"))?;
+        if rotation.max_bytes.is_some() || rotation.every.is_some() {
+            std::thread::Builder::new()
+                .name("error-log-rotate".to_string())
+                .spawn(move || loop {
+                    std::thread::sleep(ERROR_LOG_CHECK);
+                    if let Err(e) = file.rotate_if_due() {
+                        eprintln!("[{}] error log rotation failed: {e:#}", variant());
+                    }
+                })
+                .context("spawn error log rotation thread")?;
+        }
+    }
+
+    let access = match target("ACCESS_LOG").as_deref() {
+        None | Some("stderr") => AccessLog::Stderr,
+        Some("off") => AccessLog::Off,
+        Some(path) => AccessLog::File(Mutex::new(RotatingFile::open(
+            PathBuf::from(path),
+            rotation,
+        )?)),
+    };
+    ACCESS
+        .set(access)
+        .map_err(|_| anyhow!("logging initialised twice"))
+}
+
+/// Writes one access log line.
+pub fn access(line: fmt::Arguments<'_>) {
+    match ACCESS.get().unwrap_or(&AccessLog::Stderr) {
+        AccessLog::Stderr => eprintln!("{line}"),
+        AccessLog::Off => {}
+        AccessLog::File(file) => {
+            let mut file = file.lock().unwrap_or_else(|e| e.into_inner());
+            if let Err(e) = file.write_line(line) {
+                eprintln!("[{}] access log write failed: {e:#}", variant());
+            }
+        }
+    }
+}
+
+pub struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    opened: Instant,
+    rotation: Rotation,
+    /// Point fd 2 at the current file after every (re)open.
+    redirect_stderr: bool,
+}
+
+impl RotatingFile {
+    pub fn open(path: PathBuf, rotation: Rotation) -> Result<Self> {
+        let file = append(&path)?;
+        Ok(RotatingFile {
+            path,
+            file,
+            opened: Instant::now(),
+            rotation,
+            redirect_stderr: false,
+        })
+    }
+
+    pub fn write_line(&mut self, line: fmt::Arguments<'_>) -> Result<()> {
+        self.rotate_if_due()?;
+        writeln!(self.file, "{line}").with_context(|| format!("write {}", self.path.display()))
+    }
+
+    /// Rotates when the file is over size or over age.
+    pub fn rotate_if_due(&mut self) -> Result<()> {
+        let size = self.file.metadata().map(|m| m.len()).unwrap_or(0);
+        let full = self.rotation.max_bytes.is_some_and(|max| size >= max);
+        let old = self
+            .rotation
+            .every
+            .is_some_and(|every| self.opened.elapsed() >= every);
+        if (full || old) && size > 0 {
+            self.rotate()?;
+        }
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> Result<()> {
+        let numbered = |n: usize| {
+            let mut name = self.path.clone().into_os_string();
+            name.push(format!(".{n}"));
+            PathBuf::from(name)
+        };
+        // Drop the oldest kept file, shift the rest up, then move the
+        // current one to `.1`.
+        let _ = std::fs::remove_file(numbered(self.rotation.keep.max(1)));
+        for n in (1..self.rotation.keep).rev() {
+            let _ = std::fs::rename(numbered(n), numbered(n + 1));
+        }
+        if self.rotation.keep == 0 {
+            std::fs::remove_file(&self.path)
+        } else {
+            std::fs::rename(&self.path, numbered(1))
+        }
+        .with_context(|| format!("rotate {}", self.path.display()))?;
+        self.file = append(&self.path)?;
+        self.opened = Instant::now();
+        self.redirect()
+    }
+
+    fn redirect(&self) -> Result<()> {
+        if !self.redirect_stderr {
+            return Ok(());
+        }
+        #[cfg(unix)]
+        {
+            use std::os::unix::io::AsRawFd;
+            if unsafe { libc::dup2(self.file.as_raw_fd(), libc::STDERR_FILENO) } < 0 {
+                return Err(std::io::Error::last_os_error().into());
+            }
+            Ok(())
+        }
+        #[cfg(not(unix))]
+        Err(anyhow!("ERROR_LOG files are only supported on Unix"))
+    }
+}
+
+fn append(path: &Path) -> Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("open log file {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rotates_by_size_and_keeps_the_newest() {
+        let dir =
+            std::env::temp_dir().join(format!("{}-logging-{}", variant(), std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("access.log");
+        let rotation = Rotation {
+            max_bytes: Some(10),
+            every: None,
+            keep: 2,
+        };
+        let mut log = RotatingFile::open(path.clone(), rotation).unwrap();
+        for n in 0..4 {
+            log.write_line(format_args!("line {n} padding")).unwrap();
+        }
+        let read = |name: &str| std::fs::read_to_string(dir.join(name)).ok();
+        assert_eq!(read("access.log").as_deref(), Some("line 3 padding\n"));
+        assert_eq!(read("access.log.1").as_deref(), Some("line 2 padding\n"));
+        assert_eq!(read("access.log.2").as_deref(), Some("line 1 padding\n"));
+        assert_eq!(read("access.log.3"), None);
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -0,0 +1,202 @@
+//! On-demand CPU profiles: `GET /debug/pprof/profile?seconds=N`.
+//!
+//! Samples every thread with `SIGPROF` (pprof-rs) for `seconds` and answers
+//! with a pprof protobuf (`go tool pprof` reads it directly) or, with
+//! `format=flamegraph`, an SVG flame graph. Served on the `LISTEN_INTERNAL`
+//! listener only, so it can be pointed at a gateway under load without
+//! showing up in the benchmark's own traffic.
+
+use anyhow::{anyhow, Context, Result};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use crate::http::{build_response, query_param, RequestLine};
+use crate::variant;
+
+pub const ROUTE: &str = "/debug/pprof/profile";
+
+const DEFAULT_SECONDS: u64 = 30;
+const MAX_SECONDS: u64 = 300;
+/// Off the 100 Hz tick so samples do not line up with periodic work.
+const DEFAULT_FREQUENCY: i32 = 99;
+const MAX_FREQUENCY: i32 = 1000;
+
+/// pprof-rs supports one profiler per process.
+static RUNNING: AtomicBool = AtomicBool::new(false);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Pprof,
+    Flamegraph,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ProfileRequest {
+    pub duration: Duration,
+    /// Samples per second.
+    pub frequency: i32,
+    pub format: Format,
+}
+
+impl ProfileRequest {
+    /// From the `seconds`, `frequency` and `format` query parameters.
+    pub fn parse(
+        seconds: Option<&str>,
+        frequency: Option<&str>,
+        format: Option<&str>,
+    ) -> Result<Self> {
+        let seconds = match seconds {
+            Some(v) => v
+                .parse::<u64>()
+                .ok()
+                .filter(|s| (1..=MAX_SECONDS).contains(s))
+                .ok_or_else(|| anyhow!("seconds must be 1..={MAX_SECONDS}"))?,
+            None => DEFAULT_SECONDS,
+        };
+        let frequency = match frequency {
+            Some(v) => v
+                .parse::<i32>()
+                .ok()
+                .filter(|f| (1..=MAX_FREQUENCY).contains(f))
+                .ok_or_else(|| anyhow!("frequency must be 1..={MAX_FREQUENCY}"))?,
+            None => DEFAULT_FREQUENCY,
+        };
+        let format = match format {
+            None | Some("pprof") | Some("proto") => Format::Pprof,
+            Some("flamegraph") | Some("svg") => Format::Flamegraph,
+            Some(other) => return Err(anyhow!("unknown format {other:?} (pprof, flamegraph)")),
+        };
+        Ok(ProfileRequest {
+            duration: Duration::from_secs(seconds),
+            frequency,
+            format,
+        })
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self.format {
+            Format::Pprof => "application/octet-stream",
+            Format::Flamegraph => "image/svg+xml",
+        }
+    }
+}
+
+/// Why no profile was produced.
+#[derive(Debug)]
+pub enum ProfileError {
+    /// Another profile is being taken.
+    Busy,
+    Failed(anyhow::Error),
+}
+
+/// Profiles the whole process for `req.duration`, blocking the calling thread.
+pub fn capture(req: &ProfileRequest) -> std::result::Result<Vec<u8>, ProfileError> {
+    if RUNNING.swap(true, Ordering::AcqRel) {
+        return Err(ProfileError::Busy);
+    }
+    eprintln!(
+        "[{}] CPU profile: {}s at {} Hz",
+        variant(),
+        req.duration.as_secs(),
+        req.frequency
+    );
+    let result = sample(req);
+    RUNNING.store(false, Ordering::Release);
+    result.map_err(ProfileError::Failed)
+}
+
+/// Answers `GET /debug/pprof/profile[?seconds=N&frequency=HZ&format=pprof|flamegraph]`;
+/// blocks for the whole profile.
+pub fn response(req: &RequestLine) -> Vec<u8> {
+    let text = |status: &str, message: String| {
+        build_response(
+            status,
+            message.as_bytes(),
+            "profile",
+            Some("text/plain"),
+            &[],
+        )
+    };
+    let request = match ProfileRequest::parse(
+        query_param(&req.path, "seconds").as_deref(),
+        query_param(&req.path, "frequency").as_deref(),
+        query_param(&req.path, "format").as_deref(),
+    ) {
+        Ok(request) => request,
+        Err(e) => return text("HTTP/1.1 400 Bad Request", format!("{e}")),
+    };
+    match capture(&request) {
+        Ok(body) => build_response(
+            "HTTP/1.1 200 OK",
+            &body,
+            "profile",
+            Some(request.content_type()),
+            &[],
+        ),
+        Err(ProfileError::Busy) => text(
+            "HTTP/1.1 409 Conflict",
+            "a profile is already being taken".to_string(),
+        ),
+        Err(ProfileError::Failed(e)) => {
+            text("HTTP/1.1 500 Internal Server Error", format!("{e:#}"))
+        }
+    }
+}
+
+#[cfg(unix)]
+fn sample(req: &ProfileRequest) -> Result<Vec<u8>> {
+    use pprof::protos::Message;
+
+    let guard = pprof::ProfilerGuardBuilder::default()
+        .frequency(req.frequency)
+        // Unwinding from the signal handler through these can deadlock.
+        .blocklist(&["libc", "libgcc", "pthread", "vdso"])
+        .build()
+        .context("failed to start profiler")?;
+    std::thread::sleep(req.duration);
+    let report = guard.report().build().context("failed to build profile")?;
+    let mut out = Vec::new();
+    match req.format {
+        Format::Pprof => report
+            .pprof()
+            .context("failed to encode pprof profile")?
+            .encode(&mut out)
+            .context("failed to encode pprof profile")?,
+        // inferno renders nothing at all for an empty profile.
+        Format::Flamegraph if report.data.is_empty() => {
+            return Err(anyhow!("no samples collected; the process was idle"))
+        }
+        Format::Flamegraph => report
+            .flamegraph(&mut out)
+            .context("failed to render flame graph")?,
+    }
+    Ok(out)
+}
+
+#[cfg(not(unix))]
+fn sample(_req: &ProfileRequest) -> Result<Vec<u8>> {
+    Err(anyhow!("CPU profiling is only supported on Unix"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_query() {
+        let req = ProfileRequest::parse(None, None, None).unwrap();
+        assert_eq!(req.duration, Duration::from_secs(DEFAULT_SECONDS));
+        assert_eq!(req.frequency, DEFAULT_FREQUENCY);
+        assert_eq!(req.format, Format::Pprof);
+
+        let req = ProfileRequest::parse(Some("5"), Some("250"), Some("flamegraph")).unwrap();
+        assert_eq!(req.duration, Duration::from_secs(5));
+        assert_eq!(req.frequency, 250);
+        assert_eq!(req.content_type(), "image/svg+xml");
+
+        assert!(ProfileRequest::parse(Some("0"), None, None).is_err());
+        assert!(ProfileRequest::parse(Some("301"), None, None).is_err());
+        assert!(ProfileRequest::parse(None, Some("5000"), None).is_err());
+        assert!(ProfileRequest::parse(None, None, Some("gif")).is_err());
+    }
+}
@@ -0,0 +1,299 @@
+//! Self-registration with a Consul agent (`CONSUL_ADDR`), so load generators
+//! can find every gateway of a benchmark fleet in Consul's catalog.
+//!
+//! The gateway registers service `CONSUL_SERVICE` (default `wasm-gateway`)
+//! as `<service>-<replica id>` on the `LISTEN` port, tagged with its variant
+//! and carrying `variant`, `version` and `replica` in its service meta. The
+//! address is `CONSUL_SERVICE_ADDRESS`, else the `LISTEN` host unless that is
+//! a wildcard, in which case Consul uses the agent's. `CONSUL_TOKEN` is sent
+//! as `X-Consul-Token`.
+//!
+//! The service's TTL check (`CONSUL_TTL_SECS`, default 10) is refreshed every
+//! third of the TTL with the `/health/full` report: `passing` while the
+//! upstream is reachable, `warning` otherwise. A gateway that stops
+//! refreshing goes `critical`, and Consul removes it after
+//! `CONSUL_DEREGISTER_AFTER` (default `1m`). Registration is retried until the
+//! agent answers, and repeated if the agent forgets the service.
+
+use anyhow::{anyhow, Context, Result};
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::time::Duration;
+use url::Url;
+
+use crate::http::IO_TIMEOUT;
+use crate::{json_string, variant};
+
+const DEFAULT_SERVICE: &str = "wasm-gateway";
+const DEFAULT_TTL: Duration = Duration::from_secs(10);
+const DEFAULT_DEREGISTER_AFTER: &str = "1m";
+
+pub struct Registration {
+    /// `host:port` of the agent's HTTP API.
+    agent: String,
+    token: Option<crate::secrets::Secret>,
+    id: String,
+    ttl: Duration,
+    /// Body of `PUT /v1/agent/service/register`.
+    service: String,
+}
+
+impl Registration {
+    /// Reads the `CONSUL_*` variables; `None` without `CONSUL_ADDR`.
+    pub fn from_env(listen: &str, replica_id: &str) -> Result<Option<Self>> {
+        let var = |name: &str| {
+            std::env::var(name)
+                .ok()
+                .map(|v| v.trim().to_string())
+                .filter(|v| !v.is_empty())
+        };
+        let Some(addr) = var("CONSUL_ADDR") else {
+            return Ok(None);
+        };
+        let ttl = match var("CONSUL_TTL_SECS") {
+            Some(v) => Duration::from_secs(
+                v.parse::<u64>()
+                    .ok()
+                    .filter(|&secs| secs > 0)
+                    .ok_or_else(|| anyhow!("invalid CONSUL_TTL_SECS={v}"))?,
+            ),
+            None => DEFAULT_TTL,
+        };
+        let listen_addr: SocketAddr = listen
+            .to_socket_addrs()
+            .with_context(|| format!("resolve LISTEN={listen}"))?
+            .next()
+            .ok_or_else(|| anyhow!("LISTEN={listen} resolved to no addresses"))?;
+        let address = var("CONSUL_SERVICE_ADDRESS")
+            .or_else(|| (!listen_addr.ip().is_unspecified()).then(|| listen_addr.ip().to_string()));
+        let registration = Registration::new(
+            &addr,
+            crate::secrets::lookup("CONSUL_TOKEN"),
+            &var("CONSUL_SERVICE").unwrap_or_else(|| DEFAULT_SERVICE.to_string()),
+            replica_id,
+            address.as_deref(),
+            listen_addr.port(),
+            ttl,
+            &var("CONSUL_DEREGISTER_AFTER").unwrap_or_else(|| DEFAULT_DEREGISTER_AFTER.into()),
+        )
+        .context("CONSUL_ADDR")?;
+        eprintln!(
+            "[{}] registering {} with Consul at {} (TTL {:?})",
+            variant(),
+            registration.id,
+            registration.agent,
+            registration.ttl
+        );
+        Ok(Some(registration))
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        agent: &str,
+        token: Option<crate::secrets::Secret>,
+        service: &str,
+        replica_id: &str,
+        address: Option<&str>,
+        port: u16,
+        ttl: Duration,
+        deregister_after: &str,
+    ) -> Result<Self> {
+        let with_scheme = if agent.contains("://") {
+            agent.to_string()
+        } else {
+            format!("http://{agent}")
+        };
+        let url = Url::parse(&with_scheme).with_context(|| format!("invalid address {agent}"))?;
+        if url.scheme() != "http" {
+            return Err(anyhow!("{agent}: only http:// agents are supported"));
+        }
+        let host = url
+            .host_str()
+            .ok_or_else(|| anyhow!("{agent}: missing host"))?;
+        let agent = format!("{host}:{}", url.port().unwrap_or(8500));
+
+        let id = format!("{service}-{replica_id}");
+        let address = address
+            .map(|a| format!("\"Address\":{},", json_string(a)))
+            .unwrap_or_default();
+        let service = format!(
+            concat!(
+                "{{\"ID\":{id},\"Name\":{name},{address}\"Port\":{port},\"Tags\":[{variant}],",
+                "\"Meta\":{{\"variant\":{variant},\"version\":{version},\"replica\":{replica}}},",
+                "\"Check\":{{\"CheckID\":{check},\"Name\":\"gateway health\",\"TTL\":\"{ttl}s\",",
+                "\"DeregisterCriticalServiceAfter\":{deregister}}}}}"
+            ),
+            id = json_string(&id),
+            name = json_string(service),
+            address = address,
+            port = port,
+            variant = json_string(variant()),
+            version = json_string(env!("CARGO_PKG_VERSION")),
+            replica = json_string(replica_id),
+            check = json_string(&format!("service:{id}")),
+            ttl = ttl.as_secs(),
+            deregister = json_string(deregister_after),
+        );
+        Ok(Registration {
+            agent,
+            token,
+            id,
+            ttl,
+            service,
+        })
+    }
+
+    /// Registers and heartbeats forever; spawn on its own thread. `health`
+    /// returns whether the gateway is healthy and the report to attach.
+    pub fn run(&self, health: impl Fn() -> (bool, String)) {
+        let mut registered = false;
+        loop {
+            registered = self.beat(registered, &health);
+            std::thread::sleep(self.ttl / 3);
+        }
+    }
+
+    /// One heartbeat, registering first if needed; returns whether the
+    /// service is registered afterwards.
+    fn beat(&self, registered: bool, health: &impl Fn() -> (bool, String)) -> bool {
+        if !registered {
+            if let Err(e) = self.put("/v1/agent/service/register", &self.service) {
+                eprintln!("[{}] Consul registration failed: {e:#}", variant());
+                return false;
+            }
+            eprintln!("[{}] registered {} with Consul", variant(), self.id);
+        }
+        let (healthy, report) = health();
+        let body = format!(
+            "{{\"Status\":{},\"Output\":{}}}",
+            json_string(if healthy { "passing" } else { "warning" }),
+            json_string(&report)
+        );
+        match self.put(
+            &format!("/v1/agent/check/update/service:{}", self.id),
+            &body,
+        ) {
+            Ok(()) => true,
+            Err(e) => {
+                eprintln!("[{}] Consul heartbeat failed: {e:#}", variant());
+                // The agent may have restarted or dropped the service; a
+                // repeated registration is harmless.
+                false
+            }
+        }
+    }
+
+    fn put(&self, path: &str, body: &str) -> Result<()> {
+        let token = self
+            .token
+            .as_ref()
+            .map(|t| format!("X-Consul-Token: {}\r\n", t.reveal()))
+            .unwrap_or_default();
+        let request = format!(
+            "PUT {path} HTTP/1.0\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n{token}\r\n{body}",
+            self.agent,
+            body.len()
+        );
+        let mut stream = TcpStream::connect(self.agent.as_str())
+            .with_context(|| format!("connect Consul agent at {}", self.agent))?;
+        stream.set_read_timeout(Some(IO_TIMEOUT)).ok();
+        stream.set_write_timeout(Some(IO_TIMEOUT)).ok();
+        stream.write_all(request.as_bytes())?;
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response)?;
+        let (head, body) = crate::http::split_http_response(&response)?;
+        let status = crate::http::parse_status_code_from_head(&head)?;
+        if status != 200 {
+            return Err(anyhow!(
+                "PUT {path}: {status} {}",
+                String::from_utf8_lossy(&body).trim()
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+
+    /// Answers one request per status in `statuses`, sending each request's
+    /// line and body to the returned channel.
+    fn fake_agent(statuses: Vec<u16>) -> (String, mpsc::Receiver<(String, String)>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let (tx, rx) = mpsc::channel();
+        std::thread::spawn(move || {
+            for status in statuses {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream);
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let mut length = 0;
+                loop {
+                    let mut header = String::new();
+                    reader.read_line(&mut header).unwrap();
+                    if header.trim().is_empty() {
+                        break;
+                    }
+                    if let Some(v) = header.to_ascii_lowercase().strip_prefix("content-length:") {
+                        length = v.trim().parse().unwrap();
+                    }
+                }
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).unwrap();
+                tx.send((line.trim().to_string(), String::from_utf8(body).unwrap()))
+                    .unwrap();
+                let response = format!("HTTP/1.0 {status} X\r\nContent-Length: 0\r\n\r\n");
+                reader.get_mut().write_all(response.as_bytes()).unwrap();
+            }
+        });
+        (addr, rx)
+    }
+
+    #[test]
+    fn registers_heartbeats_and_registers_again_when_forgotten() {
+        let (agent, requests) = fake_agent(vec![200, 200, 404, 200, 200]);
+        let registration = Registration::new(
+            &agent,
+            None,
+            "bench",
+            "r1",
+            Some("10.0.0.5"),
+            8080,
+            Duration::from_secs(9),
+            "1m",
+        )
+        .unwrap();
+
+        let healthy = || (true, "{\"status\":\"ok\"}".to_string());
+        assert!(registration.beat(false, &healthy));
+        let (line, body) = requests.recv().unwrap();
+        assert_eq!(line, "PUT /v1/agent/service/register HTTP/1.0");
+        for field in [
+            "\"ID\":\"bench-r1\"",
+            "\"Address\":\"10.0.0.5\"",
+            "\"Port\":8080",
+            "\"CheckID\":\"service:bench-r1\"",
+            "\"TTL\":\"9s\"",
+        ] {
+            assert!(body.contains(field), "{field} in {body}");
+        }
+        assert!(body.contains(&format!("\"variant\":\"{}\"", variant())));
+        let (line, body) = requests.recv().unwrap();
+        assert_eq!(line, "PUT /v1/agent/check/update/service:bench-r1 HTTP/1.0");
+        assert!(body.contains("\"Status\":\"passing\""), "{body}");
+
+        // The agent forgot the service: the next beat registers again.
+        assert!(!registration.beat(true, &healthy));
+        requests.recv().unwrap();
+        let degraded = || (false, "upstream down".to_string());
+        assert!(registration.beat(false, &degraded));
+        assert!(requests.recv().unwrap().0.contains("/service/register"));
+        let (_, body) = requests.recv().unwrap();
+        assert!(body.contains("\"Status\":\"warning\""), "{body}");
+    }
+}
@@ -0,0 +1,362 @@
+//! Credentials from mounted files instead of the environment
+//! (`SECRETS_DIR`, `SECRETS_ENV_FILE`).
+//!
+//! - `SECRETS_DIR`: every file in the directory is one secret, named after
+//!   the file, e.g. a Kubernetes or Docker secret mount with `ADMIN_TOKEN`
+//!   and `HMAC_SECRET`. Dotfiles are skipped and trailing newlines dropped.
+//! - `SECRETS_ENV_FILE`: `NAME=value` lines, optionally quoted or prefixed
+//!   with `export`; `#` starts a comment. Files in `SECRETS_DIR` win.
+//!
+//! Files writable by group or others are refused; files readable by others
+//! are loaded with a warning. Both sources are re-read every
+//! `SECRETS_RELOAD_SECS` (default 30, `0` disables), so a rotated token is
+//! picked up without a restart; a reload that fails keeps the previous
+//! values, and a secret that disappears falls back to the value it started
+//! with.
+//!
+//! Credentials (`ADMIN_TOKEN`, `HEALTH_TOKEN`, `HMAC_SECRET`,
+//! `UPSTREAM_HMAC_SECRET`, the `AWS_*` keys, `OAUTH_CLIENT_SECRET`,
+//! `CONSUL_TOKEN`, `SHARED_STORE_URL`) are looked up here first, then in the
+//! environment. They are held as `Secret`, whose `Debug` never shows the
+//! value, and only names and paths are ever logged. Private keys in
+//! `TLS_CERT_DIR` get the same permission checks.
+//!
+//! Component guests can use the secrets named in `SECRETS_GUEST` through
+//! `secret-handle` and `secret-hmac-sha256` in `wit/gateway.wit`: they get
+//! a handle and a MAC, never the value.
+
+use anyhow::{anyhow, Context, Result};
+use once_cell::sync::OnceCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use crate::variant;
+
+const DEFAULT_RELOAD_SECS: u64 = 30;
+
+static STORE: OnceCell<SecretStore> = OnceCell::new();
+
+#[derive(Debug)]
+pub struct SecretStore {
+    dir: Option<PathBuf>,
+    env_file: Option<PathBuf>,
+    /// Names guests may ask for; a handle is the index in this list.
+    guest: Vec<String>,
+    values: RwLock<HashMap<String, Arc<str>>>,
+    reloads: AtomicU64,
+    reload_errors: AtomicU64,
+}
+
+/// A credential. `reveal` returns the current value, following reloads.
+#[derive(Clone)]
+pub struct Secret {
+    name: Arc<str>,
+    initial: Arc<str>,
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Secret({}, redacted)", self.name)
+    }
+}
+
+impl Secret {
+    pub fn new(name: &str, value: &str) -> Secret {
+        Secret {
+            name: name.into(),
+            initial: value.into(),
+        }
+    }
+
+    pub fn reveal(&self) -> Arc<str> {
+        configured()
+            .and_then(|store| store.get(&self.name))
+            .unwrap_or_else(|| Arc::clone(&self.initial))
+    }
+}
+
+/// Reads `SECRETS_DIR`, `SECRETS_ENV_FILE`, `SECRETS_RELOAD_SECS` and
+/// `SECRETS_GUEST`, loads the secrets and starts reloading them. Call once
+/// from `main`, before anything reads credentials.
+pub fn init_from_env() -> Result<()> {
+    let path = |name: &str| {
+        std::env::var(name)
+            .ok()
+            .filter(|v| !v.trim().is_empty())
+            .map(PathBuf::from)
+    };
+    let (dir, env_file) = (path("SECRETS_DIR"), path("SECRETS_ENV_FILE"));
+    if dir.is_none() && env_file.is_none() {
+        return Ok(());
+    }
+    let reload_secs = match std::env::var("SECRETS_RELOAD_SECS") {
+        Ok(v) if !v.trim().is_empty() => v
+            .trim()
+            .parse::<u64>()
+            .with_context(|| format!("invalid SECRETS_RELOAD_SECS={v}"))?,
+        _ => DEFAULT_RELOAD_SECS,
+    };
+    let guest = std::env::var("SECRETS_GUEST")
+        .map(|v| {
+            v.split(',')
+                .map(str::trim)
+                .filter(|n| !n.is_empty())
+                .map(str::to_string)
+                .collect()
+        })
+        .unwrap_or_default();
+    let values = load(dir.as_deref(), env_file.as_deref(), true)?;
+    eprintln!(
+        "[{}] secrets: {} loaded from {}",
+        variant(),
+        values.len(),
+        [&dir, &env_file]
+            .into_iter()
+            .flatten()
+            .map(|p| p.display().to_string())
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+    let store = STORE.get_or_init(|| SecretStore {
+        dir,
+        env_file,
+        guest,
+        values: RwLock::new(values),
+        reloads: AtomicU64::new(0),
+        reload_errors: AtomicU64::new(0),
+    });
+    if reload_secs > 0 {
+        std::thread::Builder::new()
+            .name("secrets-reload".to_string())
+            .spawn(move || loop {
+                std::thread::sleep(Duration::from_secs(reload_secs));
+                store.reload();
+            })
+            .context("failed to start the secrets reload thread")?;
+    }
+    Ok(())
+}
+
+pub fn configured() -> Option<&'static SecretStore> {
+    STORE.get()
+}
+
+/// The credential `name`, from the store or else the environment.
+pub fn lookup(name: &str) -> Option<Secret> {
+    configured()
+        .and_then(|store| store.get(name))
+        .map(|value| value.to_string())
+        .or_else(|| std::env::var(name).ok())
+        .filter(|v| !v.is_empty())
+        .map(|value| Secret::new(name, &value))
+}
+
+/// As `lookup`, for settings read once at startup.
+pub fn var(name: &str) -> Option<String> {
+    lookup(name).map(|secret| secret.reveal().to_string())
+}
+
+/// Compares credentials without returning early on the first differing byte.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+impl SecretStore {
+    fn get(&self, name: &str) -> Option<Arc<str>> {
+        self.values
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(name)
+            .cloned()
+    }
+
+    fn reload(&self) {
+        match load(self.dir.as_deref(), self.env_file.as_deref(), false) {
+            Ok(values) => {
+                let mut current = self.values.write().unwrap_or_else(|e| e.into_inner());
+                if *current != values {
+                    *current = values;
+                    self.reloads.fetch_add(1, Ordering::Relaxed);
+                    eprintln!("[{}] secrets: reloaded {}", variant(), current.len());
+                }
+            }
+            Err(e) => {
+                self.reload_errors.fetch_add(1, Ordering::Relaxed);
+                eprintln!(
+                    "[{}] secrets: reload failed, keeping previous values: {e:#}",
+                    variant()
+                );
+            }
+        }
+    }
+
+    /// The guest handle for `name`, if guests may use it and it exists.
+    pub fn guest_handle(&self, name: &str) -> Option<u32> {
+        let index = self.guest.iter().position(|n| n == name)?;
+        self.get(name)?;
+        u32::try_from(index).ok()
+    }
+
+    /// The value behind a guest handle.
+    pub fn guest_value(&self, handle: u32) -> Option<Arc<str>> {
+        self.get(self.guest.get(usize::try_from(handle).ok()?)?)
+    }
+
+    pub fn json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "names": self.values.read().unwrap_or_else(|e| e.into_inner()).len(),
+            "guest": self.guest.len(),
+            "reloads": self.reloads.load(Ordering::Relaxed),
+            "reload_errors": self.reload_errors.load(Ordering::Relaxed),
+        })
+    }
+}
+
+fn load(
+    dir: Option<&Path>,
+    env_file: Option<&Path>,
+    warn: bool,
+) -> Result<HashMap<String, Arc<str>>> {
+    let mut values = HashMap::new();
+    if let Some(path) = env_file {
+        check_permissions(path, warn)?;
+        let text = fs::read_to_string(path).with_context(|| format!("read {}", path.display()))?;
+        for (name, value) in parse_env_file(&text)
+            .with_context(|| format!("invalid SECRETS_ENV_FILE {}", path.display()))?
+        {
+            values.insert(name, value.into());
+        }
+    }
+    if let Some(dir) = dir {
+        for entry in fs::read_dir(dir).with_context(|| format!("read {}", dir.display()))? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let path = entry.path();
+            // Follows the `..data` symlinks of Kubernetes mounts.
+            if name.starts_with('.') || !fs::metadata(&path).is_ok_and(|m| m.is_file()) {
+                continue;
+            }
+            check_permissions(&path, warn)?;
+            let value =
+                fs::read_to_string(&path).with_context(|| format!("read {}", path.display()))?;
+            values.insert(name, value.trim_end_matches(['\r', '\n']).into());
+        }
+    }
+    Ok(values)
+}
+
+/// `NAME=value` pairs; errors name the line, never its content.
+pub fn parse_env_file(text: &str) -> Result<Vec<(String, String)>> {
+    let mut pairs = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        let (name, value) = line
+            .split_once('=')
+            .map(|(n, v)| (n.trim(), v.trim()))
+            .filter(|(n, _)| !n.is_empty())
+            .ok_or_else(|| anyhow!("line {} is not NAME=value", number + 1))?;
+        let value = ['"', '\'']
+            .iter()
+            .find_map(|&q| value.strip_prefix(q).and_then(|v| v.strip_suffix(q)))
+            .unwrap_or(value);
+        pairs.push((name.to_string(), value.to_string()));
+    }
+    Ok(pairs)
+}
+
+/// Refuses `path` if others could change it; warns, with `warn`, if they
+/// can read it.
+pub fn check_permissions(path: &Path, warn: bool) -> Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = fs::metadata(path)
+            .with_context(|| format!("stat {}", path.display()))?
+            .permissions()
+            .mode();
+        if mode & 0o022 != 0 {
+            return Err(anyhow!(
+                "{} is writable by group or others (mode {:o}); refusing to read secrets from it",
+                path.display(),
+                mode & 0o777
+            ));
+        }
+        if warn && mode & 0o004 != 0 {
+            eprintln!(
+                "[{}] warning: secret file {} is readable by other users (mode {:o})",
+                variant(),
+                path.display(),
+                mode & 0o777
+            );
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = (path, warn);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_env_files_and_redacts_values() {
+        let pairs = parse_env_file(
+            "# rotated weekly\nexport ADMIN_TOKEN=\"s3cret\"\n\nHMAC_SECRET = 'a=b' \nEMPTY=\n",
+        )
+        .unwrap();
+        assert_eq!(
+            pairs,
+            vec![
+                ("ADMIN_TOKEN".to_string(), "s3cret".to_string()),
+                ("HMAC_SECRET".to_string(), "a=b".to_string()),
+                ("EMPTY".to_string(), String::new()),
+            ]
+        );
+        let e = parse_env_file("OK=1\nhunter2\n").unwrap_err();
+        assert_eq!(e.to_string(), "line 2 is not NAME=value");
+
+        let secret = Secret::new("ADMIN_TOKEN", "s3cret");
+        assert_eq!(format!("{secret:?}"), "Secret(ADMIN_TOKEN, redacted)");
+        assert_eq!(&*secret.reveal(), "s3cret");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn loads_directories_and_refuses_writable_files() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = std::env::temp_dir().join(format!("gateway-secrets-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let write = |name: &str, value: &str, mode: u32| {
+            let path = dir.join(name);
+            fs::write(&path, value).unwrap();
+            fs::set_permissions(&path, fs::Permissions::from_mode(mode)).unwrap();
+        };
+        write("ADMIN_TOKEN", "from-dir\n", 0o600);
+        write(".hidden", "x", 0o600);
+        let env_file = dir.join(".env");
+        write(".env", "ADMIN_TOKEN=from-file\nOTHER=1\n", 0o400);
+
+        let values = load(Some(&dir), Some(&env_file), false).unwrap();
+        assert_eq!(values.len(), 2);
+        assert_eq!(&*values["ADMIN_TOKEN"], "from-dir");
+        assert_eq!(&*values["OTHER"], "1");
+
+        write("HMAC_SECRET", "x", 0o620);
+        let e = load(Some(&dir), None, false).unwrap_err();
+        assert!(e.to_string().contains("writable by group or others"));
+        fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -0,0 +1,159 @@
+//! The `/state` counter: one sequence per replica (`local`), shared through
+//! the store (`shared`, `redis`), or gossiped between replicas (`gossip`),
+//! chosen with `STATE_BACKEND`.
+
+use std::env;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+
+use crate::cluster::Gossip;
+use crate::secrets;
+use crate::store::SharedStore;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StateBackend {
+    /// A process-local counter; each replica counts on its own.
+    Local,
+    /// `state:counter` in the shared store, so replicas behind a balancer
+    /// hand out one sequence.
+    Shared,
+    /// A G-counter replicated to `CLUSTER_PEERS` over UDP (eventually consistent).
+    Gossip,
+}
+
+impl StateBackend {
+    /// `STATE_BACKEND=redis` is `shared` with a mandatory `SHARED_STORE_URL`.
+    /// Without `STATE_BACKEND`, setting `CLUSTER_PEERS` selects gossip.
+    pub fn from_env() -> Result<Self> {
+        match env::var("STATE_BACKEND").as_deref() {
+            Err(_) | Ok("") if env::var("CLUSTER_PEERS").is_ok_and(|p| !p.is_empty()) => {
+                Ok(StateBackend::Gossip)
+            }
+            Err(_) | Ok("") | Ok("local") => Ok(StateBackend::Local),
+            Ok("shared") => Ok(StateBackend::Shared),
+            Ok("redis") => {
+                if secrets::var("SHARED_STORE_URL").is_some() {
+                    Ok(StateBackend::Shared)
+                } else {
+                    Err(anyhow!("STATE_BACKEND=redis requires SHARED_STORE_URL"))
+                }
+            }
+            Ok("gossip") => Ok(StateBackend::Gossip),
+            Ok(other) => Err(anyhow!(
+                "invalid STATE_BACKEND={other} (expected: local|shared|redis|gossip)"
+            )),
+        }
+    }
+}
+
+/// The configured backend, this replica's id and, for `gossip`, the running
+/// cluster.
+#[derive(Debug)]
+pub struct State {
+    pub backend: StateBackend,
+    /// Reported in `X-Replica-Id` (`REPLICA_ID`, else `HOSTNAME`).
+    pub replica_id: String,
+    pub cluster: Option<Arc<Gossip>>,
+    counter: AtomicU64,
+}
+
+impl State {
+    /// Reads `STATE_BACKEND` and `REPLICA_ID`, and joins `CLUSTER_PEERS` when
+    /// gossiping.
+    pub fn from_env() -> Result<Self> {
+        let backend = StateBackend::from_env()?;
+        let replica_id = replica_id();
+        let cluster = start_cluster(backend, &replica_id)?;
+        Ok(State {
+            backend,
+            replica_id,
+            cluster,
+            counter: AtomicU64::new(0),
+        })
+    }
+
+    /// Next `/state` value, starting at 0 for every backend.
+    pub fn next_value(&self, store: &SharedStore) -> Result<u64> {
+        match self.backend {
+            StateBackend::Local => Ok(self.counter.fetch_add(1, Ordering::SeqCst)),
+            StateBackend::Shared => {
+                let value = store.incr("state:counter", None)?;
+                Ok(value.saturating_sub(1).max(0) as u64)
+            }
+            StateBackend::Gossip => self
+                .cluster
+                .as_ref()
+                .map(|gossip| gossip.incr())
+                .ok_or_else(|| anyhow!("gossip backend not started")),
+        }
+    }
+}
+
+fn replica_id() -> String {
+    env::var("REPLICA_ID")
+        .or_else(|_| env::var("HOSTNAME"))
+        .ok()
+        .filter(|id| !id.is_empty())
+        .unwrap_or_else(|| uuid::Uuid::new_v4().simple().to_string()[..8].to_string())
+}
+
+fn start_cluster(backend: StateBackend, replica_id: &str) -> Result<Option<Arc<Gossip>>> {
+    if backend != StateBackend::Gossip {
+        return Ok(None);
+    }
+    let peers: Vec<String> = env::var("CLUSTER_PEERS")
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|p| !p.is_empty())
+        .map(str::to_string)
+        .collect();
+    if peers.is_empty() {
+        return Err(anyhow!("STATE_BACKEND=gossip requires CLUSTER_PEERS"));
+    }
+    let bind = env::var("CLUSTER_BIND").unwrap_or_else(|_| "0.0.0.0:7946".to_string());
+    let interval_ms = match env::var("CLUSTER_GOSSIP_MS") {
+        Ok(v) => v
+            .parse::<u64>()
+            .with_context(|| format!("invalid CLUSTER_GOSSIP_MS={v}"))?,
+        Err(_) => 200,
+    };
+    let gossip = Gossip::start(
+        replica_id.to_string(),
+        &bind,
+        peers,
+        Duration::from_millis(interval_ms.max(1)),
+    )?;
+    Ok(Some(gossip))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state(backend: StateBackend) -> State {
+        State {
+            backend,
+            replica_id: "r1".to_string(),
+            cluster: None,
+            counter: AtomicU64::new(0),
+        }
+    }
+
+    #[test]
+    fn local_and_shared_counters_start_at_zero() {
+        let store = SharedStore::Memory(Default::default());
+        for backend in [StateBackend::Local, StateBackend::Shared] {
+            let state = state(backend);
+            let values: Vec<u64> = (0..3).map(|_| state.next_value(&store).unwrap()).collect();
+            assert_eq!(values, [0, 1, 2], "{backend:?}");
+        }
+        // Two replicas sharing the store hand out one sequence.
+        let other = state(StateBackend::Shared);
+        assert_eq!(other.next_value(&store).unwrap(), 3);
+        assert!(state(StateBackend::Gossip).next_value(&store).is_err());
+    }
+}
@@ -0,0 +1,415 @@
+//! Key/value store shared by gateway features that need cross-request state.
+//!
+//! With `SHARED_STORE_URL=redis://host:port[/db]` state lives in Redis, so
+//! several gateway replicas behind a TCP load balancer see the same values.
+//! Without it an in-process map is used (per replica).
+
+use anyhow::{anyhow, Context, Result};
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::http::IO_TIMEOUT;
+
+const MAX_MEMORY_KEYS: usize = 100_000;
+
+#[derive(Debug)]
+pub enum SharedStore {
+    Memory(Mutex<HashMap<String, MemoryEntry>>),
+    Redis(RedisStore),
+}
+
+#[derive(Debug)]
+pub struct MemoryEntry {
+    value: i64,
+    expires_at: Option<Instant>,
+}
+
+impl SharedStore {
+    pub fn from_env() -> Result<Self> {
+        match crate::secrets::var("SHARED_STORE_URL") {
+            Some(url) if !url.is_empty() => Ok(SharedStore::Redis(RedisStore::parse(&url)?)),
+            _ => Ok(SharedStore::Memory(Mutex::new(HashMap::new()))),
+        }
+    }
+
+    pub fn kind(&self) -> &'static str {
+        match self {
+            SharedStore::Memory(_) => "memory",
+            SharedStore::Redis(_) => "redis",
+        }
+    }
+
+    /// Atomically increments `key` and returns the new value. A key created by
+    /// this call expires after `ttl`; existing keys keep their expiry.
+    pub fn incr(&self, key: &str, ttl: Option<Duration>) -> Result<i64> {
+        match self {
+            SharedStore::Memory(map) => {
+                let mut map = map
+                    .lock()
+                    .map_err(|_| anyhow!("shared store lock poisoned"))?;
+                let now = Instant::now();
+                if map.len() >= MAX_MEMORY_KEYS {
+                    map.retain(|_, e| e.expires_at.is_none_or(|t| t > now));
+                }
+                let entry = map
+                    .entry(key.to_string())
+                    .and_modify(|e| {
+                        if e.expires_at.is_some_and(|t| t <= now) {
+                            e.value = 0;
+                            e.expires_at = ttl.map(|d| now + d);
+                        }
+                    })
+                    .or_insert_with(|| MemoryEntry {
+                        value: 0,
+                        expires_at: ttl.map(|d| now + d),
+                    });
+                entry.value += 1;
+                Ok(entry.value)
+            }
+            SharedStore::Redis(redis) => {
+                let value = redis.command(&["INCR", key])?.integer()?;
+                if value == 1 {
+                    if let Some(ttl) = ttl {
+                        let ms = ttl.as_millis().max(1).to_string();
+                        redis.command(&["PEXPIRE", key, &ms])?;
+                    }
+                }
+                Ok(value)
+            }
+        }
+    }
+}
+
+/// Minimal RESP2 client holding one connection, reconnecting after errors.
+#[derive(Debug)]
+pub struct RedisStore {
+    host: String,
+    port: u16,
+    db: Option<u32>,
+    conn: Mutex<Option<BufReader<TcpStream>>>,
+}
+
+#[derive(Debug)]
+pub enum Reply {
+    Simple(String),
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+    Array(Vec<Reply>),
+}
+
+impl Reply {
+    pub fn integer(self) -> Result<i64> {
+        match self {
+            Reply::Integer(n) => Ok(n),
+            other => Err(anyhow!("expected integer reply, got {}", other.describe())),
+        }
+    }
+
+    fn describe(&self) -> String {
+        match self {
+            Reply::Simple(s) => format!("status {s:?}"),
+            Reply::Integer(n) => format!("integer {n}"),
+            Reply::Bulk(Some(b)) => format!("bulk string of {} bytes", b.len()),
+            Reply::Bulk(None) => "nil".to_string(),
+            Reply::Array(items) => format!("array of {} items", items.len()),
+        }
+    }
+}
+
+impl RedisStore {
+    fn parse(raw: &str) -> Result<Self> {
+        let url =
+            url::Url::parse(raw).with_context(|| format!("invalid SHARED_STORE_URL={raw}"))?;
+        if url.scheme() != "redis" {
+            return Err(anyhow!(
+                "SHARED_STORE_URL must use redis:// (got scheme {})",
+                url.scheme()
+            ));
+        }
+        let host = url
+            .host_str()
+            .ok_or_else(|| anyhow!("SHARED_STORE_URL missing host"))?
+            .to_string();
+        let db = match url.path().trim_matches('/') {
+            "" => None,
+            db => Some(
+                db.parse::<u32>()
+                    .with_context(|| format!("invalid redis db in SHARED_STORE_URL={raw}"))?,
+            ),
+        };
+        Ok(RedisStore {
+            host,
+            port: url.port().unwrap_or(6379),
+            db,
+            conn: Mutex::new(None),
+        })
+    }
+
+    pub fn command(&self, args: &[&str]) -> Result<Reply> {
+        let mut guard = self
+            .conn
+            .lock()
+            .map_err(|_| anyhow!("redis connection lock poisoned"))?;
+        if guard.is_none() {
+            *guard = Some(self.connect()?);
+        }
+        let result = guard
+            .as_mut()
+            .map(|conn| round_trip(conn, args))
+            .unwrap_or_else(|| Err(anyhow!("redis not connected")));
+        if result.is_err() {
+            *guard = None;
+        }
+        result.with_context(|| format!("redis {} at {}:{}", args[0], self.host, self.port))
+    }
+
+    fn connect(&self) -> Result<BufReader<TcpStream>> {
+        let stream = TcpStream::connect((self.host.as_str(), self.port))
+            .with_context(|| format!("connect redis {}:{}", self.host, self.port))?;
+        stream.set_read_timeout(Some(IO_TIMEOUT)).ok();
+        stream.set_write_timeout(Some(IO_TIMEOUT)).ok();
+        stream.set_nodelay(true).ok();
+        let mut conn = BufReader::new(stream);
+        if let Some(db) = self.db {
+            round_trip(&mut conn, &["SELECT", &db.to_string()])?;
+        }
+        Ok(conn)
+    }
+}
+
+fn round_trip(conn: &mut BufReader<TcpStream>, args: &[&str]) -> Result<Reply> {
+    let mut out = format!("*{}\r\n", args.len()).into_bytes();
+    for arg in args {
+        out.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+        out.extend_from_slice(arg.as_bytes());
+        out.extend_from_slice(b"\r\n");
+    }
+    conn.get_mut().write_all(&out)?;
+    read_reply(conn)
+}
+
+fn read_reply(conn: &mut BufReader<TcpStream>) -> Result<Reply> {
+    let mut line = String::new();
+    if conn.read_line(&mut line)? == 0 {
+        return Err(anyhow!("redis closed the connection"));
+    }
+    let line = line.trim_end_matches("\r\n");
+    let (kind, rest) = line.split_at(line.len().min(1));
+    match kind {
+        "+" => Ok(Reply::Simple(rest.to_string())),
+        "-" => Err(anyhow!("redis error: {rest}")),
+        ":" => Ok(Reply::Integer(rest.parse().context("bad redis integer")?)),
+        "$" => {
+            let len: i64 = rest.parse().context("bad redis bulk length")?;
+            if len < 0 {
+                return Ok(Reply::Bulk(None));
+            }
+            let mut buf = vec![0u8; len as usize + 2];
+            conn.read_exact(&mut buf)?;
+            buf.truncate(len as usize);
+            Ok(Reply::Bulk(Some(buf)))
+        }
+        "*" => {
+            let len: i64 = rest.parse().context("bad redis array length")?;
+            let mut items = Vec::new();
+            for _ in 0..len.max(0) {
+                items.push(read_reply(conn)?);
+            }
+            Ok(Reply::Array(items))
+        }
+        _ => Err(anyhow!("unexpected redis reply {line:?}")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::sync::Arc;
+
+    type Commands = Arc<Mutex<Vec<Vec<String>>>>;
+
+    /// A Redis stand-in on a local port: each command is recorded and
+    /// answered with `reply(args)`, or the connection is dropped when that
+    /// returns `None`.
+    fn fake_redis(reply: fn(&[String]) -> Option<String>) -> (u16, Commands) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let commands = Commands::default();
+        let seen = Arc::clone(&commands);
+        std::thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let mut conn = BufReader::new(stream);
+                while let Some(args) = read_command(&mut conn) {
+                    seen.lock().unwrap().push(args.clone());
+                    match reply(&args) {
+                        Some(resp) => conn.get_mut().write_all(resp.as_bytes()).unwrap(),
+                        None => break,
+                    }
+                }
+            }
+        });
+        (port, commands)
+    }
+
+    fn read_command(conn: &mut BufReader<TcpStream>) -> Option<Vec<String>> {
+        let mut line = String::new();
+        conn.read_line(&mut line).ok().filter(|&n| n > 0)?;
+        let count: usize = line.trim_end().strip_prefix('*')?.parse().ok()?;
+        let mut args = Vec::new();
+        for _ in 0..count {
+            line.clear();
+            conn.read_line(&mut line).ok()?;
+            let len: usize = line.trim_end().strip_prefix('$')?.parse().ok()?;
+            let mut buf = vec![0u8; len + 2];
+            conn.read_exact(&mut buf).ok()?;
+            assert_eq!(&buf[len..], b"\r\n");
+            buf.truncate(len);
+            args.push(String::from_utf8(buf).ok()?);
+        }
+        Some(args)
+    }
+
+    fn redis(port: u16, db: &str) -> SharedStore {
+        SharedStore::Redis(RedisStore::parse(&format!("redis://127.0.0.1:{port}{db}")).unwrap())
+    }
+
+    fn commands(seen: &Commands) -> Vec<String> {
+        seen.lock().unwrap().iter().map(|c| c.join(" ")).collect()
+    }
+
+    #[test]
+    fn memory_counters_expire_with_their_first_ttl() {
+        let store = SharedStore::Memory(Mutex::new(HashMap::new()));
+        assert_eq!(store.kind(), "memory");
+        let ttl = Some(Duration::from_millis(50));
+        assert_eq!(store.incr("a", ttl).unwrap(), 1);
+        assert_eq!(store.incr("a", Some(Duration::from_secs(60))).unwrap(), 2);
+        assert_eq!(store.incr("b", None).unwrap(), 1);
+        std::thread::sleep(Duration::from_millis(80));
+        assert_eq!(store.incr("a", ttl).unwrap(), 1);
+        assert_eq!(store.incr("b", None).unwrap(), 2);
+    }
+
+    #[test]
+    fn incr_sets_an_expiry_on_new_redis_keys_only() {
+        let (port, seen) = fake_redis(|args| {
+            Some(match args[0].as_str() {
+                "SELECT" | "PEXPIRE" => "+OK\r\n".to_string(),
+                "INCR" if args[1] == "fresh" => ":1\r\n".to_string(),
+                "INCR" => ":7\r\n".to_string(),
+                _ => "-ERR unknown command\r\n".to_string(),
+            })
+        });
+        let store = redis(port, "/2");
+        assert_eq!(store.kind(), "redis");
+        let ttl = Some(Duration::from_millis(1500));
+        assert_eq!(store.incr("fresh", ttl).unwrap(), 1);
+        assert_eq!(store.incr("old", ttl).unwrap(), 7);
+        assert_eq!(store.incr("fresh", None).unwrap(), 1);
+        assert_eq!(
+            commands(&seen),
+            [
+                "SELECT 2",
+                "INCR fresh",
+                "PEXPIRE fresh 1500",
+                "INCR old",
+                "INCR fresh"
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_every_reply_kind() {
+        let (port, _) = fake_redis(|args| {
+            Some(match args[0].as_str() {
+                "PING" => "+PONG\r\n".to_string(),
+                "GET" if args[1] == "missing" => "$-1\r\n".to_string(),
+                // A bulk string may contain CRLF.
+                "GET" => "$7\r\nab\r\ncde\r\n".to_string(),
+                "LRANGE" => "*3\r\n:1\r\n$1\r\nx\r\n*0\r\n".to_string(),
+                "SET" => "+OK\r\n".to_string(),
+                _ => "-ERR unknown command\r\n".to_string(),
+            })
+        });
+        let SharedStore::Redis(redis) = redis(port, "") else {
+            unreachable!()
+        };
+        assert!(matches!(redis.command(&["PING"]).unwrap(), Reply::Simple(s) if s == "PONG"));
+        assert!(matches!(
+            redis.command(&["GET", "missing"]).unwrap(),
+            Reply::Bulk(None)
+        ));
+        assert!(matches!(
+            redis.command(&["GET", "k"]).unwrap(),
+            Reply::Bulk(Some(b)) if b == b"ab\r\ncde"
+        ));
+        match redis.command(&["LRANGE", "l", "0", "-1"]).unwrap() {
+            Reply::Array(items) => {
+                assert!(matches!(
+                    items.as_slice(),
+                    [Reply::Integer(1), Reply::Bulk(Some(_)), Reply::Array(empty)] if empty.is_empty()
+                ));
+            }
+            other => panic!("expected an array, got {}", other.describe()),
+        }
+        let err = redis
+            .command(&["SET", "k", "v"])
+            .unwrap()
+            .integer()
+            .unwrap_err();
+        assert_eq!(err.to_string(), "expected integer reply, got status \"OK\"");
+        // Arguments are length-prefixed, so spaces and CRLF go through intact.
+        assert!(redis.command(&["SET", "a b", "c\r\nd"]).is_ok());
+        let err = redis.command(&["FLUSHALL"]).unwrap_err();
+        assert!(
+            format!("{err:#}").contains("redis error: ERR unknown command"),
+            "{err:#}"
+        );
+    }
+
+    #[test]
+    fn reconnects_after_a_dropped_connection() {
+        let (port, seen) = fake_redis(|args| match args[0].as_str() {
+            "DROP" => None,
+            "SELECT" => Some("+OK\r\n".to_string()),
+            _ => Some(":1\r\n".to_string()),
+        });
+        let SharedStore::Redis(redis) = redis(port, "/1") else {
+            unreachable!()
+        };
+        assert!(redis.command(&["INCR", "a"]).is_ok());
+        let err = redis.command(&["DROP"]).unwrap_err();
+        assert!(
+            format!("{err:#}").contains("closed the connection"),
+            "{err:#}"
+        );
+        assert!(redis.command(&["INCR", "a"]).is_ok());
+        assert_eq!(
+            commands(&seen),
+            ["SELECT 1", "INCR a", "DROP", "SELECT 1", "INCR a"]
+        );
+    }
+
+    #[test]
+    fn parses_store_urls() {
+        let parsed = RedisStore::parse("redis://cache.internal:6380/3").unwrap();
+        assert_eq!(
+            (parsed.host.as_str(), parsed.port, parsed.db),
+            ("cache.internal", 6380, Some(3))
+        );
+        let parsed = RedisStore::parse("redis://cache").unwrap();
+        assert_eq!((parsed.port, parsed.db), (6379, None));
+        assert!(RedisStore::parse("http://cache").is_err());
+        assert!(RedisStore::parse("redis://cache/zero").is_err());
+        assert!(RedisStore::parse("not a url").is_err());
+        assert!(
+            SharedStore::Redis(RedisStore::parse("redis://127.0.0.1:1").unwrap())
+                .incr("a", None)
+                .is_err()
+        );
+    }
+}
@@ -0,0 +1,158 @@
+//! HTTP syntax checks (RFC 9110 / 9112) on what the gateway accepts and
+//! what it writes.
+//!
+//! The request line is rebuilt for the upstream from its parts, so whatever
+//! gets through here lands verbatim in another server's parser. Anything
+//! outside the grammar is rejected with a 400 rather than passed along. On
+//! the way out, every header field goes through `write_field`, so no value
+//! (upstream URL, guest output, client input echoed back) can add a line.
+
+use anyhow::{anyhow, Result};
+
+use crate::variant;
+
+/// `tchar` (RFC 9110 §5.6.2).
+fn is_tchar(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b)
+}
+
+/// A non-empty `token`: methods and field names.
+pub fn is_token(s: &str) -> bool {
+    !s.is_empty() && s.bytes().all(is_tchar)
+}
+
+/// `field-value` (RFC 9110 §5.5): visible characters, spaces, tabs and
+/// obs-text, so no CR, LF, NUL or other control.
+pub fn is_field_value(value: &str) -> bool {
+    value.bytes().all(|b| b == b'\t' || !b.is_ascii_control())
+}
+
+/// Appends `name: value\r\n`. A field whose name is not a token or whose
+/// value is not a `field-value` is dropped and logged instead: it would
+/// otherwise end the field early and start one of its own.
+pub fn write_field(out: &mut Vec<u8>, name: &str, value: &str) {
+    if !is_token(name) || !is_field_value(value) {
+        eprintln!(
+            "[{}] dropped invalid header field {name:?}: {value:?}",
+            variant()
+        );
+        return;
+    }
+    out.extend_from_slice(name.as_bytes());
+    out.extend_from_slice(b": ");
+    out.extend_from_slice(value.as_bytes());
+    out.extend_from_slice(b"\r\n");
+}
+
+/// Splits `method SP request-target SP HTTP-version`, with exactly one space
+/// between the parts, and checks each of them.
+pub fn parse_request_line(line: &str) -> Result<(&str, &str, &str)> {
+    let mut parts = line.split(' ');
+    let (Some(method), Some(target), Some(version), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return Err(anyhow!("malformed request line"));
+    };
+    if !is_token(method) {
+        return Err(anyhow!("invalid method"));
+    }
+    check_target(method, target)?;
+    if !is_version(version) {
+        return Err(anyhow!("invalid HTTP version"));
+    }
+    Ok((method, target, version))
+}
+
+/// Origin form (`/path?query`), absolute form (`http://host/path`), or `*`
+/// for `OPTIONS`; no controls, spaces, non-ASCII bytes or fragment.
+fn check_target(method: &str, target: &str) -> Result<()> {
+    if let Some(b) = target
+        .bytes()
+        .find(|b| b.is_ascii_control() || !b.is_ascii())
+    {
+        return Err(if b.is_ascii() {
+            anyhow!("control character in request target")
+        } else {
+            anyhow!("non-ASCII byte in request target")
+        });
+    }
+    if target.contains('#') {
+        return Err(anyhow!("fragment in request target"));
+    }
+    let absolute = ["http://", "https://"].iter().any(|scheme| {
+        target
+            .get(..scheme.len())
+            .is_some_and(|prefix| prefix.eq_ignore_ascii_case(scheme))
+    });
+    if target.starts_with('/') || absolute || (target == "*" && method == "OPTIONS") {
+        Ok(())
+    } else if target.is_empty() {
+        Err(anyhow!("empty request target"))
+    } else {
+        Err(anyhow!("unsupported request target form"))
+    }
+}
+
+/// `HTTP/<digit>.<digit>`; the name is case-sensitive.
+fn is_version(version: &str) -> bool {
+    matches!(
+        version.strip_prefix("HTTP/").map(str::as_bytes),
+        Some([major, b'.', minor]) if major.is_ascii_digit() && minor.is_ascii_digit()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_well_formed_request_lines() {
+        assert_eq!(
+            parse_request_line("GET /a/b?x=1%20y HTTP/1.1").unwrap(),
+            ("GET", "/a/b?x=1%20y", "HTTP/1.1")
+        );
+        assert!(parse_request_line("OPTIONS * HTTP/1.1").is_ok());
+        assert!(parse_request_line("GET http://up:8080/x HTTP/1.0").is_ok());
+        assert!(parse_request_line("M-SEARCH / HTTP/1.1").is_ok());
+    }
+
+    #[test]
+    fn rejects_smuggling_shapes() {
+        for line in [
+            "GET /a b HTTP/1.1",
+            "GET  /a HTTP/1.1",
+            "GET\t/a HTTP/1.1",
+            "GET /a\tb HTTP/1.1",
+            "GET /a\x00 HTTP/1.1",
+            "GET /a\x7f HTTP/1.1",
+            "GET /caf\u{e9} HTTP/1.1",
+            "GET /a#frag HTTP/1.1",
+            "GET a HTTP/1.1",
+            "GET * HTTP/1.1",
+            "G(T /a HTTP/1.1",
+            "GET /a HTTP/1.1 extra",
+            "GET /a http/1.1",
+            "GET /a HTTP/11",
+            "GET /a",
+            "",
+        ] {
+            assert!(parse_request_line(line).is_err(), "{line:?}");
+        }
+    }
+
+    #[test]
+    fn writes_only_valid_fields() {
+        let mut out = Vec::new();
+        write_field(&mut out, "X-Upstream-Url", "http://up:8080/\tx");
+        write_field(&mut out, "X-Split", "a\r\nSet-Cookie: evil=1");
+        write_field(&mut out, "X-Lf", "a\nb");
+        write_field(&mut out, "X-Nul", "a\0b");
+        write_field(&mut out, "Bad Name", "v");
+        write_field(&mut out, "X-Bad:", "v");
+        write_field(&mut out, "X-Utf8", "caf\u{e9}");
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "X-Upstream-Url: http://up:8080/\tx\r\nX-Utf8: caf\u{e9}\r\n"
+        );
+    }
+}
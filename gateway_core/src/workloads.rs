@@ -0,0 +1,230 @@
+//! The built-in workloads both gateways serve next to the proxy: `/compute`
+//! (an iterated SHA-256 chain, optionally streamed), `/upload` (hashes a body
+//! as it arrives) and `/echo` (describes the request it got).
+
+use std::io::{Read, Write};
+use std::time::Instant;
+
+use anyhow::{anyhow, Result};
+use sha2::{Digest, Sha256};
+
+use crate::http::{
+    build_chunked_head, build_forwarded_request, read_body_chunks, write_chunk, RequestLine,
+    Upstream,
+};
+use crate::json_string;
+
+/// Largest `Content-Length` `/upload` accepts.
+pub const MAX_UPLOAD_BYTES: usize = 1024 * 1024 * 1024;
+
+/// Iterated SHA-256 chain. Without a seed the chain starts from 32 zero bytes;
+/// with `seed` it starts from SHA-256(seed as little-endian u64), so clients can
+/// recompute and assert the expected digest.
+pub fn cpu_heavy(iters: u64, seed: Option<u64>) -> String {
+    cpu_heavy_with_progress(iters, seed, 0, |_| Ok(())).expect("no-op progress callback")
+}
+
+/// Same chain as `cpu_heavy`, calling `on_progress(done)` every `every` iterations
+/// (never when `every` is 0). A callback error aborts the computation.
+pub fn cpu_heavy_with_progress(
+    iters: u64,
+    seed: Option<u64>,
+    every: u64,
+    mut on_progress: impl FnMut(u64) -> Result<()>,
+) -> Result<String> {
+    let mut hash = match seed {
+        Some(seed) => Sha256::digest(seed.to_le_bytes()).into(),
+        None => [0u8; 32],
+    };
+
+    for i in 0..iters {
+        let mut hasher = Sha256::new();
+        hasher.update(hash);
+        hasher.update(i.to_le_bytes());
+        hash = hasher.finalize().into();
+        if every > 0 && (i + 1) % every == 0 && i + 1 < iters {
+            on_progress(i + 1)?;
+        }
+    }
+
+    Ok(hex::encode(hash))
+}
+
+/// Streams `/compute` as a chunked response: headers go out immediately, then one
+/// `progress <done>/<iters>` line per `every` iterations, then the final result
+/// (after `finish`) as the last chunk. Lets clients measure time-to-first-byte
+/// separately from total time.
+pub fn stream_compute(
+    client: &mut impl Write,
+    iters: u64,
+    seed: Option<u64>,
+    every: u64,
+    extra_headers: &[(&str, &str)],
+    finish: impl FnOnce(String) -> Result<Vec<u8>>,
+) -> Result<()> {
+    let head = build_chunked_head(
+        "HTTP/1.1 200 OK",
+        "compute",
+        Some("text/plain"),
+        extra_headers,
+    );
+    client.write_all(&head)?;
+    client.flush()?;
+
+    let result = cpu_heavy_with_progress(iters, seed, every, |done| {
+        write_chunk(client, format!("progress {done}/{iters}\n").as_bytes())?;
+        client.flush()?;
+        Ok(())
+    })?;
+
+    let mut last = finish(result)?;
+    last.push(b'\n');
+    write_chunk(client, &last)?;
+    client.write_all(b"0\r\n\r\n")?;
+    client.flush()?;
+    Ok(())
+}
+
+/// Consumes the request body for `/upload`, hashing it as it arrives, and returns
+/// a JSON summary. The body is never buffered, so it may exceed the normal body cap.
+pub fn upload_summary(
+    client: &mut impl Read,
+    remainder: Vec<u8>,
+    content_length: usize,
+) -> Result<String> {
+    if content_length > MAX_UPLOAD_BYTES {
+        return Err(anyhow!(
+            "upload too large (Content-Length {content_length})"
+        ));
+    }
+    let start = Instant::now();
+    let mut hasher = Sha256::new();
+    read_body_chunks(client, remainder, content_length, |chunk| {
+        hasher.update(chunk);
+        Ok(())
+    })?;
+    Ok(format!(
+        "{{\"bytes\":{},\"sha256\":\"{}\",\"read_ms\":{:.3}}}",
+        content_length,
+        hex::encode(hasher.finalize()),
+        start.elapsed().as_secs_f64() * 1000.0
+    ))
+}
+
+/// JSON description of the received request for `/echo`, including the head the
+/// proxy path would send upstream, so header rewriting can be inspected.
+/// The body is rendered as lossy UTF-8.
+pub fn echo_json(req: &RequestLine, body: &[u8], upstream: &Upstream) -> Result<String> {
+    let headers = req
+        .headers
+        .iter()
+        .map(|(k, v)| format!("[{},{}]", json_string(k), json_string(v)))
+        .collect::<Vec<_>>()
+        .join(",");
+    let forwarded = build_forwarded_request(req, &req.path, &[], upstream, &[])?;
+    let forwarded_head = String::from_utf8_lossy(&forwarded);
+    Ok(format!(
+        concat!(
+            "{{\"method\":{},\"path\":{},\"version\":{},\"headers\":[{}],",
+            "\"body_bytes\":{},\"body\":{},\"forwarded_head\":{}}}"
+        ),
+        json_string(&req.method),
+        json_string(&req.path),
+        json_string(&req.version),
+        headers,
+        body.len(),
+        json_string(&String::from_utf8_lossy(body)),
+        json_string(forwarded_head.trim_end()),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::{parse_request_head, parse_upstream};
+
+    #[test]
+    fn chain_is_seeded_and_reports_progress() {
+        assert_eq!(cpu_heavy(0, None), hex::encode([0u8; 32]));
+        let mut hash: [u8; 32] = Sha256::digest(7u64.to_le_bytes()).into();
+        for i in 0..3u64 {
+            hash = Sha256::new()
+                .chain_update(hash)
+                .chain_update(i.to_le_bytes())
+                .finalize()
+                .into();
+        }
+        assert_eq!(cpu_heavy(3, Some(7)), hex::encode(hash));
+        assert_ne!(cpu_heavy(3, None), cpu_heavy(3, Some(7)));
+
+        let mut seen = Vec::new();
+        let result = cpu_heavy_with_progress(10, Some(7), 3, |done| {
+            seen.push(done);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(seen, [3, 6, 9]);
+        assert_eq!(result, cpu_heavy(10, Some(7)));
+        // The last iteration is the result, not progress.
+        seen.clear();
+        cpu_heavy_with_progress(9, None, 3, |done| {
+            seen.push(done);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(seen, [3, 6]);
+        assert!(cpu_heavy_with_progress(10, None, 2, |_| Err(anyhow!("gone"))).is_err());
+    }
+
+    #[test]
+    fn streamed_compute_is_chunked_progress_then_result() {
+        crate::set_variant("test");
+        let mut out = Vec::new();
+        stream_compute(&mut out, 4, None, 2, &[("X-Seed", "none")], |result| {
+            Ok(format!("result={result}").into_bytes())
+        })
+        .unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let (head, body) = out.split_once("\r\n\r\n").unwrap();
+        assert!(head.contains("Transfer-Encoding: chunked"), "{head}");
+        assert!(head.contains("X-Seed: none"), "{head}");
+        let last = format!("result={}\n", cpu_heavy(4, None));
+        assert_eq!(
+            body,
+            format!(
+                "d\r\nprogress 2/4\n\r\n{:x}\r\n{last}\r\n0\r\n\r\n",
+                last.len()
+            )
+        );
+    }
+
+    #[test]
+    fn upload_is_hashed_without_buffering() {
+        let body = b"hello world";
+        let mut rest = &body[4..];
+        let summary = upload_summary(&mut rest, body[..4].to_vec(), body.len()).unwrap();
+        let summary: serde_json::Value = serde_json::from_str(&summary).unwrap();
+        assert_eq!(summary["bytes"], 11);
+        assert_eq!(summary["sha256"], hex::encode(Sha256::digest(body)));
+        assert!(upload_summary(&mut &b""[..], Vec::new(), MAX_UPLOAD_BYTES + 1).is_err());
+    }
+
+    #[test]
+    fn echo_describes_request_and_forwarded_head() {
+        let req =
+            parse_request_head(b"POST /echo?x=1 HTTP/1.1\r\nHost: gw\r\nX-A: \"q\"\r\n").unwrap();
+        let upstream = parse_upstream("http://up:9000/api").unwrap();
+        let echo: serde_json::Value =
+            serde_json::from_str(&echo_json(&req, b"hi\n", &upstream).unwrap()).unwrap();
+        assert_eq!(echo["method"], "POST");
+        assert_eq!(echo["path"], "/echo?x=1");
+        assert_eq!(echo["headers"][1], serde_json::json!(["X-A", "\"q\""]));
+        assert_eq!(echo["body_bytes"], 3);
+        assert_eq!(echo["body"], "hi\n");
+        let forwarded = echo["forwarded_head"].as_str().unwrap();
+        assert!(
+            forwarded.starts_with("POST /api/echo?x=1 HTTP/1.1\r\n"),
+            "{forwarded}"
+        );
+    }
+}
@@ -0,0 +1,29 @@
+//! The embedded wasmtime version for `/stats` (see `src/metrics.rs`).
+
+use std::path::Path;
+
+fn main() {
+    // The embedded runtime's version, from whichever lock file resolved it.
+    let manifest_dir = std::env::var("CARGO_MANIFEST_DIR").unwrap_or_default();
+    let wasmtime = Path::new(&manifest_dir)
+        .ancestors()
+        .map(|dir| dir.join("Cargo.lock"))
+        .find(|lock| lock.exists())
+        .and_then(|lock| {
+            println!("cargo:rerun-if-changed={}", lock.display());
+            std::fs::read_to_string(lock).ok()
+        })
+        .and_then(|text| {
+            let mut lines = text.lines();
+            while let Some(line) = lines.next() {
+                if line.trim() == "name = \"wasmtime\"" {
+                    let version = lines.next()?.trim().strip_prefix("version = ")?;
+                    return Some(version.trim_matches('"').to_string());
+                }
+            }
+            None
+        })
+        .unwrap_or_default();
+    println!("cargo:rustc-env=GATEWAY_WASMTIME_VERSION={wasmtime}");
+    println!("cargo:rerun-if-changed=build.rs");
+}
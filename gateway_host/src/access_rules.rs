@@ -0,0 +1,506 @@
+//! Declarative per-request policy (`ACCESS_RULES`), for the simple cases that
+//! do not deserve a wasm module.
+//!
+//! The file holds one rule per line, `action condition... option...`, with
+//! `#` comments:
+//!
+//! ```text
+//! deny   path=/admin/* !ip=10.0.0.0/8
+//! limit  path=/api/* rate=100/60 by=header:X-API-Key
+//! rewrite path=/v1/* to=/api/v1/*
+//! allow  method=GET|HEAD path=/public/*
+//! deny   header:User-Agent=*sqlmap* status=400
+//! ```
+//!
+//! Conditions, all of which must hold: `method=A|B`, `path=glob` (the path
+//! without its query, `*` matching anything), `header:Name=glob` and
+//! `ip=cidr|cidr` (the client address); `!` in front negates one. Rules run
+//! top to bottom. `allow` stops with the request let through and `deny`
+//! stops with `status` (default `403`). `limit` counts the request against
+//! `rate=N/SECS` per client address, or per `by=header:Name` value, in the
+//! shared store, answering `429` past it and going on otherwise. `rewrite`
+//! replaces the path with `to`, each `*` standing for what the `path` glob's
+//! `*`s matched, and goes on with the new path. A request no rule stops is let
+//! through. Rules apply before routing, so a rewrite decides the route,
+//! upstream and cache key; request signatures (`HMAC_SECRET`) still cover
+//! the target as sent. `/stats` reports how often each rule matched.
+
+use anyhow::{anyhow, Context, Result};
+use once_cell::sync::OnceCell;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::ratelimit::{self, Limit};
+use crate::store::SharedStore;
+
+static RULES: OnceCell<Rules> = OnceCell::new();
+
+#[derive(Debug)]
+pub(crate) struct Rules {
+    rules: Vec<Rule>,
+}
+
+#[derive(Debug)]
+struct Rule {
+    /// Line in the file, for logs and `/stats`.
+    line: usize,
+    text: String,
+    conditions: Vec<(bool, Condition)>,
+    action: Action,
+    matches: AtomicU64,
+}
+
+#[derive(Debug)]
+enum Condition {
+    Method(Vec<String>),
+    Path(String),
+    Header(String, String),
+    Ip(Vec<(IpAddr, u8)>),
+}
+
+#[derive(Debug)]
+enum Action {
+    Allow,
+    Deny { status: u16 },
+    Limit { limit: Limit, by: Option<String> },
+    Rewrite { to: String },
+}
+
+/// What one request is, as far as the rules are concerned.
+pub(crate) struct Request<'a> {
+    pub(crate) method: &'a str,
+    pub(crate) target: &'a str,
+    pub(crate) header: &'a dyn Fn(&str) -> Option<&'a str>,
+    pub(crate) ip: Option<IpAddr>,
+}
+
+/// How the rules ended for a request.
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct Outcome {
+    /// The target after `rewrite` rules, if any applied.
+    pub(crate) rewritten: Option<String>,
+    pub(crate) stop: Option<Stop>,
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum Stop {
+    Deny {
+        status: u16,
+        line: usize,
+    },
+    Limited {
+        line: usize,
+        limit: u64,
+        reset_secs: u64,
+    },
+}
+
+/// Reads the `ACCESS_RULES` file. Call once from `main`.
+pub(crate) fn init_from_env() -> Result<()> {
+    let path = match std::env::var("ACCESS_RULES") {
+        Ok(v) if !v.trim().is_empty() => v,
+        _ => return Ok(()),
+    };
+    let text = std::fs::read_to_string(path.trim())
+        .with_context(|| format!("read ACCESS_RULES {path}"))?;
+    let rules = parse(&text).with_context(|| format!("invalid ACCESS_RULES {path}"))?;
+    eprintln!(
+        "[wasm-host] access rules: {} from {path}",
+        rules.rules.len()
+    );
+    RULES.get_or_init(|| rules);
+    Ok(())
+}
+
+pub(crate) fn configured() -> Option<&'static Rules> {
+    RULES.get()
+}
+
+pub(crate) fn parse(text: &str) -> Result<Rules> {
+    let rules = text
+        .lines()
+        .enumerate()
+        .map(|(i, line)| (i + 1, line.split('#').next().unwrap_or("").trim()))
+        .filter(|(_, line)| !line.is_empty())
+        .map(|(n, line)| parse_rule(n, line).with_context(|| format!("line {n}: {line}")))
+        .collect::<Result<Vec<_>>>()?;
+    Ok(Rules { rules })
+}
+
+fn parse_rule(line: usize, text: &str) -> Result<Rule> {
+    let mut words = text.split_whitespace();
+    let action = words.next().unwrap_or_default();
+    let mut conditions = Vec::new();
+    let (mut status, mut rate, mut by, mut to) = (None, None, None, None);
+    for word in words {
+        let (negated, word) = match word.strip_prefix('!') {
+            Some(rest) => (true, rest),
+            None => (false, word),
+        };
+        let (name, value) = word
+            .split_once('=')
+            .ok_or_else(|| anyhow!("expected name=value, got {word:?}"))?;
+        let condition = match name {
+            "method" => Some(Condition::Method(
+                value.split('|').map(str::to_ascii_uppercase).collect(),
+            )),
+            "path" => Some(Condition::Path(value.to_string())),
+            "ip" => Some(Condition::Ip(
+                value.split('|').map(parse_cidr).collect::<Result<_>>()?,
+            )),
+            _ => name
+                .strip_prefix("header:")
+                .map(|header| Condition::Header(header.to_string(), value.to_string())),
+        };
+        match (condition, name) {
+            (Some(condition), _) => conditions.push((negated, condition)),
+            (None, _) if negated => return Err(anyhow!("only conditions take '!'")),
+            (None, "status") => status = Some(value),
+            (None, "rate") => rate = Some(value),
+            (None, "by") => by = Some(value),
+            (None, "to") => to = Some(value),
+            (None, other) => return Err(anyhow!("unknown condition or option {other:?}")),
+        }
+    }
+    let unused = |options: &[(&str, Option<&str>)]| -> Result<()> {
+        match options.iter().find(|(_, v)| v.is_some()) {
+            Some((name, _)) => Err(anyhow!("{action} takes no {name}=")),
+            None => Ok(()),
+        }
+    };
+    let action = match action {
+        "allow" => {
+            unused(&[("status", status), ("rate", rate), ("by", by), ("to", to)])?;
+            Action::Allow
+        }
+        "deny" => {
+            unused(&[("rate", rate), ("by", by), ("to", to)])?;
+            let status = match status {
+                Some(v) => v
+                    .parse::<u16>()
+                    .ok()
+                    .filter(|s| (400..600).contains(s))
+                    .ok_or_else(|| anyhow!("invalid status={v} (expected 400-599)"))?,
+                None => 403,
+            };
+            Action::Deny { status }
+        }
+        "limit" => {
+            unused(&[("status", status), ("to", to)])?;
+            let rate = rate.ok_or_else(|| anyhow!("limit needs rate=N/SECS"))?;
+            let limit = rate
+                .split_once('/')
+                .and_then(|(n, secs)| {
+                    Some(Limit {
+                        requests: n.parse().ok()?,
+                        window_secs: secs.parse().ok().filter(|&s| s > 0)?,
+                    })
+                })
+                .ok_or_else(|| anyhow!("invalid rate={rate} (expected N/SECS)"))?;
+            let by = match by {
+                None | Some("ip") => None,
+                Some(v) => Some(
+                    v.strip_prefix("header:")
+                        .filter(|h| !h.is_empty())
+                        .ok_or_else(|| anyhow!("invalid by={v} (expected ip or header:Name)"))?
+                        .to_string(),
+                ),
+            };
+            Action::Limit { limit, by }
+        }
+        "rewrite" => {
+            unused(&[("status", status), ("rate", rate), ("by", by)])?;
+            let to = to
+                .filter(|t| t.starts_with('/'))
+                .ok_or_else(|| anyhow!("rewrite needs to=/path"))?;
+            let stars = conditions
+                .iter()
+                .find_map(|(negated, c)| match c {
+                    Condition::Path(glob) if !negated => Some(glob.matches('*').count()),
+                    _ => None,
+                })
+                .unwrap_or(0);
+            if to.matches('*').count() > stars {
+                return Err(anyhow!("to= has more '*' than the path= glob"));
+            }
+            Action::Rewrite { to: to.to_string() }
+        }
+        other => {
+            return Err(anyhow!(
+                "unknown action {other:?} (expected allow|deny|limit|rewrite)"
+            ))
+        }
+    };
+    Ok(Rule {
+        line,
+        text: text.to_string(),
+        conditions,
+        action,
+        matches: AtomicU64::new(0),
+    })
+}
+
+fn parse_cidr(v: &str) -> Result<(IpAddr, u8)> {
+    let (addr, len) = match v.split_once('/') {
+        Some((addr, len)) => (addr, Some(len)),
+        None => (v, None),
+    };
+    let addr: IpAddr = addr
+        .parse()
+        .with_context(|| format!("invalid address {v:?}"))?;
+    let max = if addr.is_ipv4() { 32 } else { 128 };
+    let len = match len {
+        Some(len) => len
+            .parse::<u8>()
+            .ok()
+            .filter(|&l| l <= max)
+            .ok_or_else(|| anyhow!("invalid prefix length in {v:?}"))?,
+        None => max,
+    };
+    Ok((addr, len))
+}
+
+fn in_cidr(ip: IpAddr, (net, len): (IpAddr, u8)) -> bool {
+    let bits = |ip: IpAddr| match ip {
+        IpAddr::V4(v4) => (u128::from(u32::from(v4)) << 96, 32),
+        IpAddr::V6(v6) => (u128::from(v6), 128),
+    };
+    let ip = match ip {
+        IpAddr::V6(v6) if net.is_ipv4() => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+        ip => ip,
+    };
+    let ((a, width), (b, net_width)) = (bits(ip), bits(net));
+    if width != net_width {
+        return false;
+    }
+    let mask = u128::MAX.checked_shl(128 - u32::from(len)).unwrap_or(0);
+    a & mask == b & mask
+}
+
+/// Matches `text` against `glob`, returning what each `*` matched.
+fn glob<'t>(pattern: &str, text: &'t str) -> Option<Vec<&'t str>> {
+    let Some((literal, rest)) = pattern.split_once('*') else {
+        return (pattern == text).then(Vec::new);
+    };
+    let tail = text.strip_prefix(literal)?;
+    // Shortest match first, so a later `*` gets the remainder.
+    (0..=tail.len())
+        .filter(|&i| tail.is_char_boundary(i))
+        .find_map(|i| {
+            let mut captures = glob(rest, &tail[i..])?;
+            captures.insert(0, &tail[..i]);
+            Some(captures)
+        })
+}
+
+/// `HTTP/1.1 <status> <reason>` for a `deny` status.
+pub(crate) fn status_line(status: u16) -> String {
+    let reason = match status {
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        410 => "Gone",
+        429 => "Too Many Requests",
+        451 => "Unavailable For Legal Reasons",
+        500 => "Internal Server Error",
+        503 => "Service Unavailable",
+        s if s < 500 => "Client Error",
+        _ => "Server Error",
+    };
+    format!("HTTP/1.1 {status} {reason}")
+}
+
+impl Condition {
+    fn holds(&self, req: &Request<'_>, path: &str) -> bool {
+        match self {
+            Condition::Method(methods) => {
+                methods.iter().any(|m| m.eq_ignore_ascii_case(req.method))
+            }
+            Condition::Path(pattern) => glob(pattern, path).is_some(),
+            Condition::Header(name, pattern) => {
+                (req.header)(name).is_some_and(|v| glob(pattern, v).is_some())
+            }
+            Condition::Ip(nets) => req
+                .ip
+                .is_some_and(|ip| nets.iter().any(|net| in_cidr(ip, *net))),
+        }
+    }
+}
+
+impl Rules {
+    /// Runs the rules for one request; `limit` rules count in `store`, and a
+    /// store that fails lets the request through.
+    pub(crate) fn evaluate(&self, req: &Request<'_>, store: &SharedStore) -> Outcome {
+        let mut outcome = Outcome::default();
+        for rule in &self.rules {
+            let target = outcome.rewritten.as_deref().unwrap_or(req.target);
+            let (path, query) = match target.split_once('?') {
+                Some((path, query)) => (path, Some(query)),
+                None => (target, None),
+            };
+            if !rule
+                .conditions
+                .iter()
+                .all(|(negated, c)| c.holds(req, path) != *negated)
+            {
+                continue;
+            }
+            rule.matches.fetch_add(1, Ordering::Relaxed);
+            match &rule.action {
+                Action::Allow => return outcome,
+                Action::Deny { status } => {
+                    outcome.stop = Some(Stop::Deny {
+                        status: *status,
+                        line: rule.line,
+                    });
+                    return outcome;
+                }
+                Action::Limit { limit, by } => {
+                    let key = match by {
+                        Some(header) => (req.header)(header).unwrap_or_default().to_string(),
+                        None => req.ip.map(|ip| ip.to_string()).unwrap_or_default(),
+                    };
+                    let scope = format!("rules:{}", rule.line);
+                    match ratelimit::count(store, &scope, &key, *limit) {
+                        Ok(decision) if !decision.allowed => {
+                            outcome.stop = Some(Stop::Limited {
+                                line: rule.line,
+                                limit: decision.limit,
+                                reset_secs: decision.reset_secs,
+                            });
+                            return outcome;
+                        }
+                        Ok(_) => {}
+                        Err(e) => eprintln!(
+                            "[wasm-host] access rule line {}: limit not counted: {e:#}",
+                            rule.line
+                        ),
+                    }
+                }
+                Action::Rewrite { to } => {
+                    let captures = rule
+                        .conditions
+                        .iter()
+                        .find_map(|(negated, c)| match c {
+                            Condition::Path(pattern) if !negated => glob(pattern, path),
+                            _ => None,
+                        })
+                        .unwrap_or_default();
+                    let mut captures = captures.into_iter();
+                    let mut new = String::new();
+                    for (i, part) in to.split('*').enumerate() {
+                        if i > 0 {
+                            new.push_str(captures.next().unwrap_or_default());
+                        }
+                        new.push_str(part);
+                    }
+                    if let Some(query) = query {
+                        new.push('?');
+                        new.push_str(query);
+                    }
+                    outcome.rewritten = Some(new);
+                }
+            }
+        }
+        outcome
+    }
+
+    /// `(labels, value)` per rule, for `gateway_access_rule_matches_total`.
+    pub(crate) fn samples(&self) -> Vec<(String, String)> {
+        self.rules
+            .iter()
+            .map(|rule| {
+                (
+                    format!("{{line=\"{}\"}}", rule.line),
+                    rule.matches.load(Ordering::Relaxed).to_string(),
+                )
+            })
+            .collect()
+    }
+
+    pub(crate) fn json(&self) -> serde_json::Value {
+        self.rules
+            .iter()
+            .map(|rule| {
+                serde_json::json!({
+                    "line": rule.line,
+                    "rule": rule.text,
+                    "matches": rule.matches.load(Ordering::Relaxed),
+                })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(
+        rules: &Rules,
+        store: &SharedStore,
+        method: &str,
+        target: &str,
+        ip: &str,
+        ua: &str,
+    ) -> Outcome {
+        let header = |name: &str| (name == "User-Agent").then_some(ua);
+        rules.evaluate(
+            &Request {
+                method,
+                target,
+                header: &header,
+                ip: ip.parse().ok(),
+            },
+            store,
+        )
+    }
+
+    #[test]
+    fn evaluates_rules_in_order() {
+        assert!(parse("block path=/x").is_err());
+        assert!(parse("deny to=/y").is_err());
+        assert!(parse("rewrite path=/a to=/b/*").is_err());
+        assert!(parse("allow ip=10.0.0.0/33").is_err());
+        assert!(parse("limit rate=10").is_err());
+
+        let rules = parse(
+            "# admin only from the office\n\
+             deny path=/admin/* !ip=10.0.0.0/8|::1\n\
+             rewrite path=/v1/* to=/api/v1/*\n\
+             deny method=DELETE path=/api/* status=405\n\
+             allow header:X-Role=ops\n\
+             deny header:User-Agent=*sqlmap* status=400\n",
+        )
+        .unwrap();
+        let store = SharedStore::Memory(Default::default());
+        let curl = |method, target, ip| run(&rules, &store, method, target, ip, "curl/8");
+
+        let denied = |status, line| Some(Stop::Deny { status, line });
+        assert_eq!(curl("GET", "/admin/x", "192.0.2.1").stop, denied(403, 2));
+        assert_eq!(curl("GET", "/admin/x", "10.1.2.3").stop, None);
+        assert_eq!(curl("GET", "/admin/x", "::ffff:10.1.2.3").stop, None);
+        assert_eq!(curl("GET", "/admin/x", "::1").stop, None);
+        let rewritten = curl("DELETE", "/v1/users/7?force=1", "10.0.0.1");
+        assert_eq!(
+            rewritten.rewritten.as_deref(),
+            Some("/api/v1/users/7?force=1")
+        );
+        assert_eq!(rewritten.stop, denied(405, 4));
+        let sqlmap = run(&rules, &store, "GET", "/", "10.0.0.1", "sqlmap/1.7");
+        assert_eq!(sqlmap.stop, denied(400, 6));
+        assert_eq!(rules.json()[0]["matches"], 1);
+
+        let limits = parse("limit path=/api/* rate=2/60\n").unwrap();
+        let limited: Vec<bool> = (0..3)
+            .map(|_| {
+                run(&limits, &store, "GET", "/api/x", "192.0.2.9", "curl/8")
+                    .stop
+                    .is_some()
+            })
+            .collect();
+        assert_eq!(limited, [false, false, true]);
+    }
+}
@@ -0,0 +1,389 @@
+//! Optional per-request audit log in a SQLite file (`AUDIT_DB`).
+//!
+//! Rows are handed to a writer thread over a bounded channel and inserted in
+//! batches, one transaction per batch, so the request path never waits on
+//! disk. When the channel is full rows are dropped and counted.
+
+use anyhow::{Context, Result};
+use rusqlite::{params, Connection, OpenFlags};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, SyncSender, TrySendError};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::RequestTrace;
+
+const QUEUE_CAPACITY: usize = 10_000;
+pub(crate) const MAX_QUERY_ROWS: usize = 10_000;
+
+#[derive(Debug)]
+pub(crate) struct AuditLog {
+    db_path: String,
+    tx: SyncSender<AuditRow>,
+    dropped: AtomicU64,
+}
+
+#[derive(Debug)]
+struct AuditRow {
+    ts_ms: i64,
+    req_id: String,
+    method: String,
+    path: String,
+    status: u16,
+    latency_ms: f64,
+    wasm_ms: f64,
+    upstream: Option<String>,
+    dry_run_input_sha256: Option<String>,
+    dry_run_output_sha256: Option<String>,
+    upstream_bytes_sent: Option<i64>,
+    upstream_bytes_received: Option<i64>,
+    upstream_ttfb_ms: Option<f64>,
+    upstream_ms: Option<f64>,
+}
+
+impl AuditLog {
+    pub(crate) fn from_env() -> Result<Option<Self>> {
+        let Ok(db_path) = std::env::var("AUDIT_DB") else {
+            return Ok(None);
+        };
+        let batch_size = match std::env::var("AUDIT_BATCH") {
+            Ok(v) => v
+                .parse::<usize>()
+                .with_context(|| format!("invalid AUDIT_BATCH={v}"))?,
+            Err(_) => 100,
+        };
+        let flush_ms = match std::env::var("AUDIT_FLUSH_MS") {
+            Ok(v) => v
+                .parse::<u64>()
+                .with_context(|| format!("invalid AUDIT_FLUSH_MS={v}"))?,
+            Err(_) => 1000,
+        };
+        Self::open(db_path, batch_size, Duration::from_millis(flush_ms)).map(Some)
+    }
+
+    fn open(db_path: String, batch_size: usize, flush_every: Duration) -> Result<Self> {
+        let conn =
+            Connection::open(&db_path).with_context(|| format!("open AUDIT_DB={db_path}"))?;
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS audit (
+                 id INTEGER PRIMARY KEY,
+                 ts_ms INTEGER NOT NULL,
+                 req_id TEXT NOT NULL,
+                 method TEXT NOT NULL,
+                 path TEXT NOT NULL,
+                 status INTEGER NOT NULL,
+                 latency_ms REAL NOT NULL,
+                 wasm_ms REAL NOT NULL,
+                 upstream TEXT
+             );
+             CREATE INDEX IF NOT EXISTS audit_ts_ms ON audit (ts_ms);",
+        )
+        .context("create audit table")?;
+        // Columns added after the first release; older files get them here.
+        let columns: Vec<String> = conn
+            .prepare("SELECT name FROM pragma_table_info('audit')")?
+            .query_map([], |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+        for (column, ty) in [
+            ("dry_run_input_sha256", "TEXT"),
+            ("dry_run_output_sha256", "TEXT"),
+            ("upstream_bytes_sent", "INTEGER"),
+            ("upstream_bytes_received", "INTEGER"),
+            ("upstream_ttfb_ms", "REAL"),
+            ("upstream_ms", "REAL"),
+        ] {
+            if !columns.iter().any(|c| c == column) {
+                conn.execute_batch(&format!("ALTER TABLE audit ADD COLUMN {column} {ty}"))
+                    .with_context(|| format!("add audit column {column}"))?;
+            }
+        }
+
+        let (tx, rx) = mpsc::sync_channel(QUEUE_CAPACITY);
+        std::thread::spawn(move || writer_loop(conn, rx, batch_size.max(1), flush_every));
+        Ok(AuditLog {
+            db_path,
+            tx,
+            dropped: AtomicU64::new(0),
+        })
+    }
+
+    pub(crate) fn record(&self, trace: &RequestTrace, latency: Duration) {
+        let row = AuditRow {
+            ts_ms: now_ms(),
+            req_id: trace.req_id.clone(),
+            method: trace.method.clone(),
+            path: trace.path.clone(),
+            status: trace.status,
+            latency_ms: latency.as_secs_f64() * 1000.0,
+            wasm_ms: trace.wasm_us as f64 / 1000.0,
+            upstream: trace.upstream.clone(),
+            dry_run_input_sha256: trace.dry_run.as_ref().map(|d| d.input_sha256.clone()),
+            dry_run_output_sha256: trace.dry_run.as_ref().and_then(|d| d.output_sha256.clone()),
+            upstream_bytes_sent: trace.upstream_timing.map(|t| t.bytes_sent as i64),
+            upstream_bytes_received: trace.upstream_timing.map(|t| t.bytes_received as i64),
+            upstream_ttfb_ms: trace.upstream_timing.map(|t| t.ttfb_us as f64 / 1000.0),
+            upstream_ms: trace.upstream_timing.map(|t| t.total_us as f64 / 1000.0),
+        };
+        match self.tx.try_send(row) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                let dropped = self.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+                if dropped.is_power_of_two() {
+                    eprintln!("[wasm-host] audit queue full, {dropped} rows dropped so far");
+                }
+            }
+            Err(TrySendError::Disconnected(_)) => {
+                eprintln!("[wasm-host] audit writer is gone, row dropped");
+            }
+        }
+    }
+
+    /// Rows with `ts_ms >= since_ms`, oldest first, as a JSON array.
+    pub(crate) fn query(&self, since_ms: i64, limit: usize) -> Result<String> {
+        let conn = Connection::open_with_flags(&self.db_path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+            .with_context(|| format!("open {} read-only", self.db_path))?;
+        let mut stmt = conn.prepare(
+            "SELECT ts_ms, req_id, method, path, status, latency_ms, wasm_ms, upstream,
+                    dry_run_input_sha256, dry_run_output_sha256, upstream_bytes_sent,
+                    upstream_bytes_received, upstream_ttfb_ms, upstream_ms
+             FROM audit WHERE ts_ms >= ?1 ORDER BY ts_ms, id LIMIT ?2",
+        )?;
+        let rows = stmt.query_map(params![since_ms, limit as i64], |row| {
+            Ok(serde_json::json!({
+                "ts_ms": row.get::<_, i64>(0)?,
+                "req_id": row.get::<_, String>(1)?,
+                "method": row.get::<_, String>(2)?,
+                "path": row.get::<_, String>(3)?,
+                "status": row.get::<_, i64>(4)?,
+                "latency_ms": row.get::<_, f64>(5)?,
+                "wasm_ms": row.get::<_, f64>(6)?,
+                "upstream": row.get::<_, Option<String>>(7)?,
+                "dry_run_input_sha256": row.get::<_, Option<String>>(8)?,
+                "dry_run_output_sha256": row.get::<_, Option<String>>(9)?,
+                "upstream_bytes_sent": row.get::<_, Option<i64>>(10)?,
+                "upstream_bytes_received": row.get::<_, Option<i64>>(11)?,
+                "upstream_ttfb_ms": row.get::<_, Option<f64>>(12)?,
+                "upstream_ms": row.get::<_, Option<f64>>(13)?,
+            }))
+        })?;
+        let rows = rows.collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(serde_json::Value::Array(rows).to_string())
+    }
+}
+
+fn writer_loop(
+    mut conn: Connection,
+    rx: mpsc::Receiver<AuditRow>,
+    batch_size: usize,
+    flush_every: Duration,
+) {
+    let mut batch = Vec::with_capacity(batch_size);
+    let mut last_flush = Instant::now();
+    loop {
+        let wait = flush_every.saturating_sub(last_flush.elapsed());
+        let disconnected = match rx.recv_timeout(wait) {
+            Ok(row) => {
+                batch.push(row);
+                batch.extend(rx.try_iter().take(batch_size - batch.len()));
+                if batch.len() < batch_size && last_flush.elapsed() < flush_every {
+                    continue;
+                }
+                false
+            }
+            Err(RecvTimeoutError::Timeout) => false,
+            Err(RecvTimeoutError::Disconnected) => true,
+        };
+        if !batch.is_empty() {
+            if let Err(e) = insert_batch(&mut conn, &batch) {
+                eprintln!(
+                    "[wasm-host] audit write failed ({} rows): {e:#}",
+                    batch.len()
+                );
+            }
+            batch.clear();
+        }
+        last_flush = Instant::now();
+        if disconnected {
+            return;
+        }
+    }
+}
+
+fn insert_batch(conn: &mut Connection, batch: &[AuditRow]) -> Result<()> {
+    let tx = conn.transaction()?;
+    {
+        let mut stmt = tx.prepare_cached(
+            "INSERT INTO audit (ts_ms, req_id, method, path, status, latency_ms, wasm_ms, upstream,
+                                dry_run_input_sha256, dry_run_output_sha256, upstream_bytes_sent,
+                                upstream_bytes_received, upstream_ttfb_ms, upstream_ms)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14)",
+        )?;
+        for row in batch {
+            stmt.execute(params![
+                row.ts_ms,
+                row.req_id,
+                row.method,
+                row.path,
+                row.status,
+                row.latency_ms,
+                row.wasm_ms,
+                row.upstream,
+                row.dry_run_input_sha256,
+                row.dry_run_output_sha256,
+                row.upstream_bytes_sent,
+                row.upstream_bytes_received,
+                row.upstream_ttfb_ms,
+                row.upstream_ms,
+            ])?;
+        }
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+fn now_ms() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as i64)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transform::DryRunDigests;
+    use crate::UpstreamTiming;
+
+    fn db_path(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("audit-{name}-{}.db", std::process::id()));
+        for suffix in ["", "-wal", "-shm"] {
+            std::fs::remove_file(format!("{}{suffix}", path.display())).ok();
+        }
+        path.display().to_string()
+    }
+
+    fn trace(req_id: &str, status: u16) -> RequestTrace {
+        RequestTrace {
+            req_id: req_id.to_string(),
+            method: "POST".to_string(),
+            path: "/api/x?y=1".to_string(),
+            status,
+            ..RequestTrace::default()
+        }
+    }
+
+    /// Rows since `since_ms`, once at least `n` are written.
+    fn rows(log: &AuditLog, since_ms: i64, n: usize) -> Vec<serde_json::Value> {
+        let started = Instant::now();
+        loop {
+            let rows: Vec<serde_json::Value> =
+                serde_json::from_str(&log.query(since_ms, MAX_QUERY_ROWS).unwrap()).unwrap();
+            if rows.len() >= n || started.elapsed() > Duration::from_secs(5) {
+                return rows;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn rows_come_back_with_every_column() {
+        let log = AuditLog::open(db_path("columns"), 10, Duration::from_millis(20)).unwrap();
+        let before = now_ms();
+        let mut full = trace("r-1", 200);
+        full.wasm_us = 1_500;
+        full.upstream = Some("127.0.0.1:9000".to_string());
+        full.upstream_timing = Some(UpstreamTiming {
+            bytes_sent: 10,
+            bytes_received: 20,
+            ttfb_us: 3_000,
+            total_us: 4_500,
+        });
+        full.dry_run = Some(DryRunDigests {
+            input_sha256: "in".to_string(),
+            output_sha256: None,
+        });
+        log.record(&full, Duration::from_micros(2_500));
+        log.record(&trace("r-2", 0), Duration::ZERO);
+
+        let rows = rows(&log, before, 2);
+        assert_eq!(rows.len(), 2);
+        let row = &rows[0];
+        assert!(row["ts_ms"].as_i64().unwrap() >= before);
+        assert_eq!(row["req_id"], "r-1");
+        assert_eq!(row["method"], "POST");
+        assert_eq!(row["path"], "/api/x?y=1");
+        assert_eq!(row["status"], 200);
+        assert_eq!(row["latency_ms"], 2.5);
+        assert_eq!(row["wasm_ms"], 1.5);
+        assert_eq!(row["upstream"], "127.0.0.1:9000");
+        assert_eq!(row["dry_run_input_sha256"], "in");
+        assert_eq!(row["dry_run_output_sha256"], serde_json::Value::Null);
+        assert_eq!(row["upstream_bytes_sent"], 10);
+        assert_eq!(row["upstream_bytes_received"], 20);
+        assert_eq!(row["upstream_ttfb_ms"], 3.0);
+        assert_eq!(row["upstream_ms"], 4.5);
+
+        // No response and no upstream: status 0 and nulls.
+        let row = &rows[1];
+        assert_eq!(row["req_id"], "r-2");
+        assert_eq!(row["status"], 0);
+        for column in ["upstream", "dry_run_input_sha256", "upstream_ms"] {
+            assert_eq!(row[column], serde_json::Value::Null, "{column}");
+        }
+    }
+
+    #[test]
+    fn query_honours_since_and_limit() {
+        let log = AuditLog::open(db_path("query"), 1, Duration::from_millis(20)).unwrap();
+        for id in ["a", "b", "c"] {
+            log.record(&trace(id, 200), Duration::ZERO);
+        }
+        let ids: Vec<_> = rows(&log, 0, 3)
+            .iter()
+            .map(|r| r["req_id"].clone())
+            .collect();
+        assert_eq!(ids, ["a", "b", "c"]);
+
+        let limited: Vec<serde_json::Value> =
+            serde_json::from_str(&log.query(0, 2).unwrap()).unwrap();
+        assert_eq!(limited.len(), 2);
+        assert_eq!(limited[0]["req_id"], "a");
+        assert_eq!(log.query(now_ms() + 60_000, 10).unwrap(), "[]");
+    }
+
+    #[test]
+    fn full_batches_are_written_before_the_flush_interval() {
+        let log = AuditLog::open(db_path("batch"), 2, Duration::from_secs(3600)).unwrap();
+        log.record(&trace("a", 200), Duration::ZERO);
+        std::thread::sleep(Duration::from_millis(100));
+        assert_eq!(log.query(0, 10).unwrap(), "[]");
+        log.record(&trace("b", 200), Duration::ZERO);
+        assert_eq!(rows(&log, 0, 2).len(), 2);
+    }
+
+    #[test]
+    fn older_files_gain_the_new_columns() {
+        let path = db_path("migrate");
+        Connection::open(&path)
+            .unwrap()
+            .execute_batch(
+                "CREATE TABLE audit (
+                     id INTEGER PRIMARY KEY, ts_ms INTEGER NOT NULL, req_id TEXT NOT NULL,
+                     method TEXT NOT NULL, path TEXT NOT NULL, status INTEGER NOT NULL,
+                     latency_ms REAL NOT NULL, wasm_ms REAL NOT NULL, upstream TEXT
+                 );
+                 INSERT INTO audit (ts_ms, req_id, method, path, status, latency_ms, wasm_ms)
+                 VALUES (1, 'old', 'GET', '/', 200, 1.0, 0.0);",
+            )
+            .unwrap();
+        let log = AuditLog::open(path, 1, Duration::from_millis(20)).unwrap();
+        log.record(&trace("new", 201), Duration::ZERO);
+
+        let rows = rows(&log, 0, 2);
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0]["req_id"], "old");
+        assert_eq!(rows[0]["upstream_ms"], serde_json::Value::Null);
+        assert_eq!(rows[1]["req_id"], "new");
+        assert_eq!(rows[1]["status"], 201);
+    }
+}
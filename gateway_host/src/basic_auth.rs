@@ -0,0 +1,504 @@
+//! HTTP Basic authentication for the admin endpoints and selected proxied routes.
+//!
+//! Credentials are `user:<password hash>` pairs, taken from
+//! `BASIC_AUTH_USERS` (comma-separated) and/or `BASIC_AUTH_FILE` (one pair per
+//! line, `#` comments). Hashes are salted PBKDF2-HMAC-SHA256 in the passlib
+//! form `$pbkdf2-sha256$<rounds>$<salt>$<hash>`; a bare SHA-256 hex digest is
+//! still accepted, with a warning at startup. Repeated failures for a
+//! username from one client IP lock that pair out for
+//! `BASIC_AUTH_LOCKOUT_SECS`, so failures from elsewhere cannot lock a user
+//! out everywhere.
+
+use anyhow::{anyhow, Context, Result};
+use base64::Engine as _;
+use hmac::{Hmac, Mac};
+use once_cell::sync::Lazy;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use gateway_core::secrets::constant_time_eq;
+
+use crate::normalize;
+
+const MAX_TRACKED_USERS: usize = 10_000;
+const PBKDF2_PREFIX: &str = "$pbkdf2-sha256$";
+
+/// Whose failures are counted together: a username from one client address.
+type FailureKey = (String, Option<IpAddr>);
+
+static FAILURES: Lazy<Mutex<HashMap<FailureKey, Failures>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// A stored password.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum PasswordHash {
+    /// Unsalted SHA-256, from before PBKDF2 was supported.
+    Sha256([u8; 32]),
+    Pbkdf2 {
+        rounds: u32,
+        salt: Vec<u8>,
+        hash: [u8; 32],
+    },
+}
+
+impl PasswordHash {
+    /// `$pbkdf2-sha256$<rounds>$<salt>$<hash>`, salt and hash in passlib's
+    /// base64 (`.` for `+`, unpadded), or 64 hex characters of SHA-256.
+    fn parse(s: &str) -> Result<Self> {
+        let Some(rest) = s.strip_prefix(PBKDF2_PREFIX) else {
+            let hash: [u8; 32] = hex::decode(s)
+                .ok()
+                .and_then(|h| h.try_into().ok())
+                .ok_or_else(|| {
+                    anyhow!("expected {PBKDF2_PREFIX}<rounds>$<salt>$<hash> or 64 hex characters")
+                })?;
+            return Ok(PasswordHash::Sha256(hash));
+        };
+        let ab64 = |field: &str| {
+            base64::engine::general_purpose::STANDARD_NO_PAD.decode(field.replace('.', "+"))
+        };
+        let mut fields = rest.split('$');
+        let (Some(rounds), Some(salt), Some(hash), None) =
+            (fields.next(), fields.next(), fields.next(), fields.next())
+        else {
+            return Err(anyhow!("expected {PBKDF2_PREFIX}<rounds>$<salt>$<hash>"));
+        };
+        let rounds = rounds
+            .parse::<u32>()
+            .ok()
+            .filter(|r| *r > 0)
+            .ok_or_else(|| anyhow!("invalid PBKDF2 rounds {rounds:?}"))?;
+        let salt = ab64(salt).context("invalid PBKDF2 salt")?;
+        let hash: [u8; 32] = ab64(hash)
+            .ok()
+            .and_then(|h| h.try_into().ok())
+            .ok_or_else(|| anyhow!("PBKDF2 hash is not 32 bytes of base64"))?;
+        Ok(PasswordHash::Pbkdf2 { rounds, salt, hash })
+    }
+
+    fn verify(&self, password: &str) -> bool {
+        match self {
+            PasswordHash::Sha256(expected) => {
+                constant_time_eq(&Sha256::digest(password.as_bytes()), expected)
+            }
+            PasswordHash::Pbkdf2 { rounds, salt, hash } => {
+                constant_time_eq(&pbkdf2_sha256(password.as_bytes(), salt, *rounds), hash)
+            }
+        }
+    }
+
+    fn rounds(&self) -> u32 {
+        match self {
+            PasswordHash::Sha256(_) => 1,
+            PasswordHash::Pbkdf2 { rounds, .. } => *rounds,
+        }
+    }
+}
+
+/// PBKDF2-HMAC-SHA256 (RFC 8018) with a 32-byte output, which is one block.
+fn pbkdf2_sha256(password: &[u8], salt: &[u8], rounds: u32) -> [u8; 32] {
+    let prf = Hmac::<Sha256>::new_from_slice(password).expect("HMAC takes keys of any length");
+    let mut u: [u8; 32] = prf
+        .clone()
+        .chain_update(salt)
+        .chain_update(1u32.to_be_bytes())
+        .finalize()
+        .into_bytes()
+        .into();
+    let mut out = u;
+    for _ in 1..rounds {
+        u = prf.clone().chain_update(u).finalize().into_bytes().into();
+        out.iter_mut().zip(u).for_each(|(o, b)| *o ^= b);
+    }
+    out
+}
+
+#[derive(Debug)]
+pub(crate) struct BasicAuthConfig {
+    users: HashMap<String, PasswordHash>,
+    /// Proxied path prefixes that require credentials; empty means none (admin only).
+    pub(crate) routes: Vec<String>,
+    pub(crate) max_failures: u32,
+    pub(crate) lockout: Duration,
+}
+
+impl BasicAuthConfig {
+    pub(crate) fn from_env() -> Result<Option<Self>> {
+        let mut users = HashMap::new();
+        if let Ok(spec) = std::env::var("BASIC_AUTH_USERS") {
+            for entry in spec.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+                let (user, hash) = parse_entry(entry).context("invalid BASIC_AUTH_USERS")?;
+                users.insert(user, hash);
+            }
+        }
+        if let Ok(path) = std::env::var("BASIC_AUTH_FILE") {
+            let raw = std::fs::read_to_string(&path)
+                .with_context(|| format!("read BASIC_AUTH_FILE={path}"))?;
+            for (i, line) in raw.lines().enumerate() {
+                let line = line.trim();
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+                let (user, hash) =
+                    parse_entry(line).with_context(|| format!("{path}:{}", i + 1))?;
+                users.insert(user, hash);
+            }
+        }
+        if users.is_empty() {
+            return Ok(None);
+        }
+        let unsalted = users
+            .values()
+            .filter(|h| matches!(h, PasswordHash::Sha256(_)))
+            .count();
+        if unsalted > 0 {
+            eprintln!(
+                "[wasm-host] basic auth: {unsalted} user(s) have unsalted SHA-256 hashes; use {PBKDF2_PREFIX}<rounds>$<salt>$<hash>"
+            );
+        }
+
+        let max_failures = match std::env::var("BASIC_AUTH_MAX_FAILURES") {
+            Ok(v) => v
+                .parse::<u32>()
+                .with_context(|| format!("invalid BASIC_AUTH_MAX_FAILURES={v}"))?,
+            Err(_) => 5,
+        };
+        let lockout_secs = match std::env::var("BASIC_AUTH_LOCKOUT_SECS") {
+            Ok(v) => v
+                .parse::<u64>()
+                .with_context(|| format!("invalid BASIC_AUTH_LOCKOUT_SECS={v}"))?,
+            Err(_) => 300,
+        };
+        Ok(Some(BasicAuthConfig {
+            users,
+            routes: std::env::var("BASIC_AUTH_ROUTES")
+                .map(|v| {
+                    v.split(',')
+                        .map(str::trim)
+                        .filter(|p| !p.is_empty())
+                        .map(str::to_string)
+                        .collect()
+                })
+                .unwrap_or_default(),
+            max_failures,
+            lockout: Duration::from_secs(lockout_secs),
+        }))
+    }
+
+    pub(crate) fn applies_to(&self, path: &str) -> bool {
+        self.routes
+            .iter()
+            .any(|p| normalize::prefix_matches(p, path))
+    }
+}
+
+fn parse_entry(entry: &str) -> Result<(String, PasswordHash)> {
+    let (user, hash) = entry
+        .split_once(':')
+        .ok_or_else(|| anyhow!("expected user:hash, got {entry:?}"))?;
+    let hash = PasswordHash::parse(hash.trim())
+        .with_context(|| format!("invalid password hash for {:?}", user.trim()))?;
+    Ok((user.trim().to_string(), hash))
+}
+
+#[derive(Debug)]
+struct Failures {
+    count: u32,
+    locked_until: Option<Instant>,
+}
+
+/// Why a request was rejected; `LockedOut` carries the remaining lockout time.
+#[derive(Debug)]
+pub(crate) enum Rejection {
+    Unauthorized(&'static str),
+    LockedOut(Duration),
+}
+
+/// Checks `Authorization: Basic ...` from `client_ip` and returns the
+/// authenticated username.
+pub(crate) fn check(
+    config: &BasicAuthConfig,
+    authorization: Option<&str>,
+    client_ip: Option<IpAddr>,
+) -> std::result::Result<String, Rejection> {
+    let Some(encoded) = authorization.and_then(|v| v.strip_prefix("Basic ")) else {
+        return Err(Rejection::Unauthorized("missing basic credentials"));
+    };
+    let decoded = base64::engine::general_purpose::STANDARD
+        .decode(encoded.trim())
+        .ok()
+        .and_then(|d| String::from_utf8(d).ok())
+        .ok_or(Rejection::Unauthorized("malformed basic credentials"))?;
+    let (user, password) = decoded
+        .split_once(':')
+        .ok_or(Rejection::Unauthorized("malformed basic credentials"))?;
+
+    let key = (user.to_string(), client_ip);
+    let now = Instant::now();
+    {
+        let failures = FAILURES.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(until) = failures.get(&key).and_then(|f| f.locked_until) {
+            if until > now {
+                return Err(Rejection::LockedOut(until - now));
+            }
+        }
+    }
+
+    // Unknown users are checked against the costliest configured hash, so
+    // timing does not reveal which usernames exist. The lock is not held
+    // while hashing.
+    let ok = match config.users.get(user) {
+        Some(expected) => expected.verify(password),
+        None => {
+            if let Some(costliest) = config.users.values().max_by_key(|h| h.rounds()) {
+                std::hint::black_box(costliest.verify(password));
+            }
+            false
+        }
+    };
+    let mut failures = FAILURES.lock().unwrap_or_else(|e| e.into_inner());
+    if ok {
+        failures.remove(&key);
+        return Ok(user.to_string());
+    }
+
+    if failures.len() >= MAX_TRACKED_USERS && !failures.contains_key(&key) {
+        failures.retain(|_, f| f.locked_until.is_some_and(|t| t > now));
+    }
+    let entry = failures.entry(key).or_insert(Failures {
+        count: 0,
+        locked_until: None,
+    });
+    entry.count += 1;
+    if config.max_failures > 0 && entry.count >= config.max_failures {
+        entry.count = 0;
+        entry.locked_until = Some(now + config.lockout);
+        let from = client_ip.map_or_else(|| "unknown address".to_string(), |ip| ip.to_string());
+        eprintln!(
+            "[wasm-host] basic auth: user {user:?} locked out from {from} for {}s",
+            config.lockout.as_secs()
+        );
+    }
+    Err(Rejection::Unauthorized("invalid credentials"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gateway_core::http::parse_request_head;
+
+    /// `secret`, salted with `0123456789abcdef`, from Python's
+    /// `hashlib.pbkdf2_hmac("sha256", b"secret", salt, 1000)`.
+    const SECRET: &str =
+        "$pbkdf2-sha256$1000$MDEyMzQ1Njc4OWFiY2RlZg$tiKWHy4FAGCWE8gn6GtKhaxD2OeeAUUWXFT/p1aaNl8";
+
+    const CLIENT: Option<IpAddr> = Some(IpAddr::V4(std::net::Ipv4Addr::new(192, 0, 2, 1)));
+
+    fn config(routes: &[&str]) -> BasicAuthConfig {
+        let mut users = HashMap::new();
+        users.insert("alice".to_string(), PasswordHash::parse(SECRET).unwrap());
+        BasicAuthConfig {
+            users,
+            routes: routes.iter().map(|r| r.to_string()).collect(),
+            max_failures: 3,
+            lockout: Duration::from_secs(60),
+        }
+    }
+
+    /// `Authorization` for `user:password`.
+    fn basic(user: &str, password: &str) -> String {
+        let creds = base64::engine::general_purpose::STANDARD.encode(format!("{user}:{password}"));
+        format!("Basic {creds}")
+    }
+
+    /// `config` with `user` added (password `secret`), so each test keeps its
+    /// own failure counts in the shared map.
+    fn config_with(user: &str, lockout: Duration) -> BasicAuthConfig {
+        let mut config = config(&[]);
+        config
+            .users
+            .insert(user.to_string(), PasswordHash::parse(SECRET).unwrap());
+        config.lockout = lockout;
+        config
+    }
+
+    #[test]
+    fn checks_passwords_and_credential_syntax() {
+        let config = config(&[]);
+        assert_eq!(
+            check(&config, Some(&basic("alice", "secret")), CLIENT).unwrap(),
+            "alice"
+        );
+        assert!(matches!(
+            check(&config, Some(&basic("alice", "Secret")), CLIENT),
+            Err(Rejection::Unauthorized("invalid credentials"))
+        ));
+        for header in [None, Some("Bearer abc")] {
+            assert!(matches!(
+                check(&config, header, CLIENT),
+                Err(Rejection::Unauthorized("missing basic credentials"))
+            ));
+        }
+        for header in ["Basic !!!", "Basic YWxpY2U="] {
+            assert!(matches!(
+                check(&config, Some(header), CLIENT),
+                Err(Rejection::Unauthorized("malformed basic credentials"))
+            ));
+        }
+    }
+
+    #[test]
+    fn unknown_users_fail_like_wrong_passwords() {
+        let config = config_with("dana", Duration::from_secs(60));
+        // Checked against the dummy hash, and rejected whatever the password.
+        assert!(matches!(
+            check(&config, Some(&basic("mallory", "secret")), CLIENT),
+            Err(Rejection::Unauthorized("invalid credentials"))
+        ));
+        assert!(matches!(
+            check(&config, Some(&basic("mallory", "")), CLIENT),
+            Err(Rejection::Unauthorized("invalid credentials"))
+        ));
+        // Unknown users are counted and locked out like known ones.
+        assert!(matches!(
+            check(&config, Some(&basic("mallory", "x")), CLIENT),
+            Err(Rejection::Unauthorized("invalid credentials"))
+        ));
+        assert!(matches!(
+            check(&config, Some(&basic("mallory", "secret")), CLIENT),
+            Err(Rejection::LockedOut(_))
+        ));
+    }
+
+    #[test]
+    fn locks_out_after_max_failures_until_the_lockout_ends() {
+        let config = config_with("bob", Duration::from_millis(100));
+        for _ in 0..2 {
+            assert!(check(&config, Some(&basic("bob", "wrong")), CLIENT).is_err());
+        }
+        assert_eq!(
+            check(&config, Some(&basic("bob", "secret")), CLIENT).unwrap(),
+            "bob"
+        );
+
+        // Success reset the count, so two more failures do not lock.
+        for _ in 0..2 {
+            assert!(check(&config, Some(&basic("bob", "wrong")), CLIENT).is_err());
+        }
+        assert_eq!(
+            check(&config, Some(&basic("bob", "secret")), CLIENT).unwrap(),
+            "bob"
+        );
+
+        for _ in 0..3 {
+            assert!(matches!(
+                check(&config, Some(&basic("bob", "wrong")), CLIENT),
+                Err(Rejection::Unauthorized("invalid credentials"))
+            ));
+        }
+        match check(&config, Some(&basic("bob", "secret")), CLIENT) {
+            Err(Rejection::LockedOut(left)) => assert!(left <= Duration::from_millis(100)),
+            other => panic!("expected a lockout, got {other:?}"),
+        }
+        std::thread::sleep(Duration::from_millis(120));
+        assert_eq!(
+            check(&config, Some(&basic("bob", "secret")), CLIENT).unwrap(),
+            "bob"
+        );
+    }
+
+    #[test]
+    fn zero_max_failures_never_locks() {
+        let mut config = config_with("erin", Duration::from_secs(60));
+        config.max_failures = 0;
+        for _ in 0..10 {
+            assert!(matches!(
+                check(&config, Some(&basic("erin", "wrong")), CLIENT),
+                Err(Rejection::Unauthorized("invalid credentials"))
+            ));
+        }
+        assert_eq!(
+            check(&config, Some(&basic("erin", "secret")), CLIENT).unwrap(),
+            "erin"
+        );
+    }
+
+    #[test]
+    fn routes_scope_proxied_requests_only() {
+        assert!(!config(&[]).applies_to("/anything"));
+        let config = config(&["/internal/", "/reports"]);
+        assert!(config.applies_to("/internal/x"));
+        assert!(config.applies_to("/reports?month=1"));
+        // A prefix covers its own path, and whole segments only.
+        assert!(config.applies_to("/internal"));
+        assert!(!config.applies_to("/internals"));
+        assert!(!config.applies_to("/reportsx"));
+        assert!(!config.applies_to("/public/internal/"));
+        assert!(config.applies_to("//internal/x"));
+        assert!(config.applies_to("/public/../reports"));
+    }
+
+    #[test]
+    fn parses_user_entries() {
+        let (user, parsed) = parse_entry(&format!(" carol : {SECRET} ")).unwrap();
+        assert_eq!(user, "carol");
+        assert_eq!(parsed.rounds(), 1000);
+        assert!(parsed.verify("secret"));
+        assert!(!parsed.verify("Secret"));
+
+        // Unsalted SHA-256 hex is still read.
+        let hash = hex::encode(Sha256::digest(b"pw"));
+        let (_, parsed) = parse_entry(&format!("carol:{hash}")).unwrap();
+        assert_eq!(parsed, PasswordHash::Sha256(Sha256::digest(b"pw").into()));
+        assert!(parsed.verify("pw"));
+
+        assert!(parse_entry("carol").is_err());
+        assert!(parse_entry("carol:abcd").is_err());
+        for bad in [
+            "$pbkdf2-sha256$0$MDEy$tiKWHy4FAGCWE8gn6GtKhaxD2OeeAUUWXFT/p1aaNl8",
+            "$pbkdf2-sha256$1000$MDEy",
+            "$pbkdf2-sha256$1000$MDEy$dGlLV0h5NEZBR0NXRThnbg$x",
+            "$pbkdf2-sha256$1000$MDEy$dGlLV0h5NEZBR0NXRThnbg",
+            "$pbkdf2-sha256$1000$!!$tiKWHy4FAGCWE8gn6GtKhaxD2OeeAUUWXFT/p1aaNl8",
+        ] {
+            assert!(parse_entry(&format!("carol:{bad}")).is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn lockouts_are_per_client_address() {
+        let config = config_with("frank", Duration::from_secs(60));
+        let elsewhere = Some(IpAddr::V4(std::net::Ipv4Addr::new(198, 51, 100, 7)));
+        for _ in 0..3 {
+            assert!(check(&config, Some(&basic("frank", "wrong")), CLIENT).is_err());
+        }
+        assert!(matches!(
+            check(&config, Some(&basic("frank", "secret")), CLIENT),
+            Err(Rejection::LockedOut(_))
+        ));
+        // Failures from one address do not lock the user out of another.
+        assert_eq!(
+            check(&config, Some(&basic("frank", "secret")), elsewhere).unwrap(),
+            "frank"
+        );
+        assert!(matches!(
+            check(&config, Some(&basic("frank", "secret")), CLIENT),
+            Err(Rejection::LockedOut(_))
+        ));
+    }
+
+    #[test]
+    fn absolute_form_targets_are_still_protected() {
+        let config = config(&["/protected"]);
+        let req =
+            parse_request_head(b"GET http://h/protected/x HTTP/1.1\r\nHost: other\r\n").unwrap();
+        assert!(config.applies_to(&req.path));
+        assert!(matches!(
+            check(&config, req.header("Authorization"), CLIENT),
+            Err(Rejection::Unauthorized("missing basic credentials"))
+        ));
+    }
+}
@@ -0,0 +1,330 @@
+//! `POST /transform/batch`: each item of a newline-delimited or multipart payload
+//! is run through the wasm module and the results are returned in input order.
+//! Results can contain newlines, so a newline-delimited batch is answered with
+//! each result prefixed by its length instead.
+
+use anyhow::{anyhow, Context, Result};
+use uuid::Uuid;
+
+use crate::find_double_crlf;
+
+pub(crate) const MAX_BATCH_ITEMS: usize = 1024;
+
+#[derive(Debug)]
+pub(crate) enum BatchFormat {
+    /// One item per line; a trailing newline does not start an extra item.
+    Lines,
+    /// `multipart/*` with the given boundary; each part body is one item.
+    Multipart(String),
+}
+
+impl BatchFormat {
+    pub(crate) fn from_content_type(content_type: Option<&str>) -> Result<Self> {
+        let Some(ct) = content_type else {
+            return Ok(BatchFormat::Lines);
+        };
+        let mut params = ct.split(';');
+        let mime = params.next().unwrap_or("").trim().to_ascii_lowercase();
+        if !mime.starts_with("multipart/") {
+            return Ok(BatchFormat::Lines);
+        }
+        let boundary = params
+            .filter_map(|p| p.split_once('='))
+            .find(|(k, _)| k.trim().eq_ignore_ascii_case("boundary"))
+            .map(|(_, v)| v.trim().trim_matches('"').to_string())
+            .filter(|b| !b.is_empty())
+            .ok_or_else(|| anyhow!("multipart batch without boundary parameter"))?;
+        Ok(BatchFormat::Multipart(boundary))
+    }
+}
+
+pub(crate) fn split_items<'a>(body: &'a [u8], format: &BatchFormat) -> Result<Vec<&'a [u8]>> {
+    let items = match format {
+        BatchFormat::Lines => {
+            let body = body.strip_suffix(b"\n").unwrap_or(body);
+            if body.is_empty() {
+                Vec::new()
+            } else {
+                body.split(|b| *b == b'\n')
+                    .map(|line| line.strip_suffix(b"\r").unwrap_or(line))
+                    .collect()
+            }
+        }
+        BatchFormat::Multipart(boundary) => split_multipart(body, boundary)?,
+    };
+    if items.len() > MAX_BATCH_ITEMS {
+        return Err(anyhow!(
+            "batch has {} items (max {MAX_BATCH_ITEMS})",
+            items.len()
+        ));
+    }
+    Ok(items)
+}
+
+fn split_multipart<'a>(body: &'a [u8], boundary: &str) -> Result<Vec<&'a [u8]>> {
+    let delim = format!("--{boundary}");
+    let next_delim = format!("\r\n--{boundary}");
+    let mut pos = find(body, delim.as_bytes())
+        .ok_or_else(|| anyhow!("multipart boundary not found"))?
+        + delim.len();
+
+    let mut parts = Vec::new();
+    loop {
+        let rest = &body[pos..];
+        if rest.starts_with(b"--") {
+            return Ok(parts);
+        }
+        let rest = rest
+            .strip_prefix(b"\r\n")
+            .ok_or_else(|| anyhow!("malformed multipart delimiter line"))?;
+        let end = find(rest, next_delim.as_bytes())
+            .ok_or_else(|| anyhow!("unterminated multipart part"))?;
+        let part = &rest[..end];
+        let part_body = if let Some(b) = part.strip_prefix(b"\r\n") {
+            b
+        } else {
+            let header_end = find_double_crlf(part)
+                .ok_or_else(|| anyhow!("malformed multipart part headers"))?;
+            &part[header_end + 4..]
+        };
+        parts.push(part_body);
+        pos = body.len() - rest.len() + end + next_delim.len();
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+/// Transforms all items, spreading them over up to `parallelism` threads.
+/// Output order always matches input order; the first failing item fails the batch.
+pub(crate) fn run_batch<F>(
+    items: &[&[u8]],
+    parallelism: usize,
+    transform: F,
+) -> Result<Vec<Vec<u8>>>
+where
+    F: Fn(&[u8]) -> Result<Vec<u8>> + Sync,
+{
+    let parallelism = parallelism.clamp(1, items.len().max(1));
+    if parallelism == 1 {
+        return items
+            .iter()
+            .enumerate()
+            .map(|(i, item)| transform(item).with_context(|| format!("batch item {i}")))
+            .collect();
+    }
+
+    let chunk_size = items.len().div_ceil(parallelism);
+    let transform = &transform;
+    let chunks: Vec<Result<Vec<Vec<u8>>>> = std::thread::scope(|scope| {
+        let handles: Vec<_> = items
+            .chunks(chunk_size)
+            .enumerate()
+            .map(|(chunk_idx, chunk)| {
+                scope.spawn(move || {
+                    chunk
+                        .iter()
+                        .enumerate()
+                        .map(|(i, item)| {
+                            transform(item).with_context(|| {
+                                format!("batch item {}", chunk_idx * chunk_size + i)
+                            })
+                        })
+                        .collect()
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .map(|h| {
+                h.join()
+                    .unwrap_or_else(|_| Err(anyhow!("batch worker panicked")))
+            })
+            .collect()
+    });
+
+    let mut out = Vec::with_capacity(items.len());
+    for chunk in chunks {
+        out.extend(chunk?);
+    }
+    Ok(out)
+}
+
+/// Encodes results for the request's format: `<byte length>\n<result>\n` per
+/// result for `Lines`, parts under a fresh boundary for `Multipart`.
+/// Returns (body, Content-Type).
+pub(crate) fn encode_results(results: &[Vec<u8>], format: &BatchFormat) -> (Vec<u8>, String) {
+    match format {
+        BatchFormat::Lines => {
+            let mut out = Vec::new();
+            for r in results {
+                out.extend_from_slice(format!("{}\n", r.len()).as_bytes());
+                out.extend_from_slice(r);
+                out.push(b'\n');
+            }
+            (out, "application/octet-stream".to_string())
+        }
+        BatchFormat::Multipart(_) => {
+            let boundary = format!("batch-{}", Uuid::new_v4().simple());
+            let mut out = Vec::new();
+            for r in results {
+                out.extend_from_slice(format!("--{boundary}\r\n").as_bytes());
+                out.extend_from_slice(b"Content-Type: application/octet-stream\r\n\r\n");
+                out.extend_from_slice(r);
+                out.extend_from_slice(b"\r\n");
+            }
+            out.extend_from_slice(format!("--{boundary}--\r\n").as_bytes());
+            (out, format!("multipart/mixed; boundary={boundary}"))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(body: &[u8]) -> Vec<&[u8]> {
+        split_items(body, &BatchFormat::Lines).unwrap()
+    }
+
+    fn multipart(body: &[u8]) -> Result<Vec<&[u8]>> {
+        split_items(body, &BatchFormat::Multipart("xyz".to_string()))
+    }
+
+    #[test]
+    fn content_type_picks_the_format() {
+        assert!(matches!(
+            BatchFormat::from_content_type(None).unwrap(),
+            BatchFormat::Lines
+        ));
+        assert!(matches!(
+            BatchFormat::from_content_type(Some("application/x-ndjson")).unwrap(),
+            BatchFormat::Lines
+        ));
+        match BatchFormat::from_content_type(Some("Multipart/Mixed; charset=x; Boundary=\"b 1\""))
+            .unwrap()
+        {
+            BatchFormat::Multipart(boundary) => assert_eq!(boundary, "b 1"),
+            other => panic!("{other:?}"),
+        }
+        assert!(BatchFormat::from_content_type(Some("multipart/mixed")).is_err());
+        assert!(BatchFormat::from_content_type(Some("multipart/mixed; boundary=")).is_err());
+    }
+
+    #[test]
+    fn lines_split_on_newlines() {
+        assert_eq!(lines(b"a\r\nb\n\nc\n"), [&b"a"[..], b"b", b"", b"c"]);
+        assert_eq!(lines(b"one"), [b"one"]);
+        assert!(lines(b"").is_empty());
+        assert!(lines(b"\n").is_empty());
+        // Only one trailing newline is dropped.
+        assert_eq!(lines(b"a\n\n"), [&b"a"[..], b""]);
+    }
+
+    #[test]
+    fn multipart_parts_with_and_without_headers() {
+        let body = b"preamble\r\n--xyz\r\nContent-Type: text/plain\r\n\r\nfirst\r\nline\r\n--xyz\r\n\r\nsecond\r\n--xyz\r\n\r\n\r\n--xyz--\r\nepilogue";
+        assert_eq!(
+            multipart(body).unwrap(),
+            [&b"first\r\nline"[..], b"second", b""]
+        );
+        assert!(multipart(b"--xyz--").unwrap().is_empty());
+    }
+
+    #[test]
+    fn malformed_multipart_is_rejected() {
+        for (body, error) in [
+            (&b"no delimiter"[..], "multipart boundary not found"),
+            (b"--xyzjunk", "malformed multipart delimiter line"),
+            (b"--xyz\r\n\r\npart", "unterminated multipart part"),
+            (
+                b"--xyz\r\nX: 1\r\npart\r\n--xyz--",
+                "malformed multipart part headers",
+            ),
+        ] {
+            assert_eq!(multipart(body).unwrap_err().to_string(), error);
+        }
+    }
+
+    #[test]
+    fn item_count_is_limited() {
+        let body = "x\n".repeat(MAX_BATCH_ITEMS);
+        assert_eq!(lines(body.as_bytes()).len(), MAX_BATCH_ITEMS);
+        let body = format!("{body}x");
+        let err = split_items(body.as_bytes(), &BatchFormat::Lines).unwrap_err();
+        assert_eq!(err.to_string(), "batch has 1025 items (max 1024)");
+    }
+
+    #[test]
+    fn results_keep_input_order_at_any_parallelism() {
+        let items: Vec<Vec<u8>> = (0..25).map(|i| i.to_string().into_bytes()).collect();
+        let items: Vec<&[u8]> = items.iter().map(Vec::as_slice).collect();
+        let threads = std::sync::Mutex::new(std::collections::HashSet::new());
+        for parallelism in [0, 1, 4, 100] {
+            threads.lock().unwrap().clear();
+            let out = run_batch(&items, parallelism, |item| {
+                threads.lock().unwrap().insert(std::thread::current().id());
+                Ok([b"t:", item].concat())
+            })
+            .unwrap();
+            let expected: Vec<Vec<u8>> = items.iter().map(|i| [b"t:", *i].concat()).collect();
+            assert_eq!(out, expected);
+            let used = threads.lock().unwrap().len();
+            assert_eq!(used, parallelism.clamp(1, items.len()), "{parallelism}");
+        }
+        assert!(run_batch(&[], 8, |_| unreachable!()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn a_failing_item_fails_the_batch_with_its_index() {
+        let items: Vec<&[u8]> = vec![b"ok", b"ok", b"ok", b"bad", b"ok"];
+        for parallelism in [1, 2] {
+            let err = run_batch(&items, parallelism, |item| {
+                if item == b"bad" {
+                    Err(anyhow!("boom"))
+                } else {
+                    Ok(item.to_vec())
+                }
+            })
+            .unwrap_err();
+            assert_eq!(format!("{err:#}"), "batch item 3: boom");
+        }
+    }
+
+    /// Reads back `encode_results` for `Lines`.
+    fn length_prefixed(mut body: &[u8]) -> Vec<Vec<u8>> {
+        let mut results = Vec::new();
+        while !body.is_empty() {
+            let eol = body.iter().position(|b| *b == b'\n').unwrap();
+            let len: usize = std::str::from_utf8(&body[..eol]).unwrap().parse().unwrap();
+            let (result, rest) = body[eol + 1..].split_at(len);
+            results.push(result.to_vec());
+            body = rest.strip_prefix(b"\n").unwrap();
+        }
+        results
+    }
+
+    #[test]
+    fn results_use_the_request_framing() {
+        let results = vec![
+            b"a".to_vec(),
+            Vec::new(),
+            b"c\r\nd".to_vec(),
+            b"\n".to_vec(),
+            b"2\nxy\n".to_vec(),
+            vec![0xff, b'\r'],
+        ];
+        let (body, content_type) = encode_results(&results, &BatchFormat::Lines);
+        assert!(body.starts_with(b"1\na\n0\n\n4\nc\r\nd\n"));
+        assert_eq!(content_type, "application/octet-stream");
+        assert_eq!(length_prefixed(&body), results);
+        assert!(encode_results(&[], &BatchFormat::Lines).0.is_empty());
+
+        let (body, content_type) =
+            encode_results(&results, &BatchFormat::Multipart("in".to_string()));
+        let format = BatchFormat::from_content_type(Some(&content_type)).unwrap();
+        assert!(content_type.starts_with("multipart/mixed; boundary=batch-"));
+        assert_eq!(split_items(&body, &format).unwrap(), results);
+    }
+}
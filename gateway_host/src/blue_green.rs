@@ -0,0 +1,247 @@
+//! Two named upstream sets, `blue` and `green` (`UPSTREAM_BLUE`,
+//! `UPSTREAM_GREEN`), with live traffic on one of them, flipped by
+//! `POST /admin/switch`.
+//!
+//! Both take the `UPSTREAM_POOL` format and are balanced the same way;
+//! `UPSTREAM_ACTIVE` (default `blue`) picks where traffic starts. A switch
+//! applies to requests that arrive after it: each request holds the set it
+//! started on until it finishes, so an upgrade of the idle set never drops
+//! one, and `GET /admin/switch` shows the old set draining. With
+//! `SWITCH_CONFIRM_SECS` set, a switch is provisional: unless
+//! `POST /admin/switch/confirm` arrives in time, traffic flips back by
+//! itself, so a bad release cannot strand it.
+
+use anyhow::{anyhow, Context, Result};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::balancer::{Balancer, Pick};
+
+pub(crate) const COLORS: [&str; 2] = ["blue", "green"];
+
+#[derive(Debug)]
+pub(crate) struct BlueGreen<T> {
+    sets: [Balancer<T>; 2],
+    active: AtomicUsize,
+    in_flight: [AtomicUsize; 2],
+    confirm_within: Option<Duration>,
+    /// An unconfirmed switch: revert to the other set at this deadline.
+    pending: Mutex<Option<Instant>>,
+}
+
+/// The set a request started on; counts as in flight there until dropped.
+#[derive(Debug)]
+pub(crate) struct Lease<'a, T> {
+    owner: &'a BlueGreen<T>,
+    set: usize,
+}
+
+impl<T> Lease<'_, T> {
+    pub(crate) fn pick(&self) -> Option<Pick<'_, T>> {
+        self.owner.sets[self.set].pick()
+    }
+}
+
+impl<T> Drop for Lease<'_, T> {
+    fn drop(&mut self) {
+        self.owner.in_flight[self.set].fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl<T> BlueGreen<T> {
+    /// Reads `UPSTREAM_BLUE`, `UPSTREAM_GREEN`, `UPSTREAM_ACTIVE` and
+    /// `SWITCH_CONFIRM_SECS`; `build` turns a pool spec into a balancer.
+    pub(crate) fn from_env(build: impl Fn(&str) -> Result<Balancer<T>>) -> Result<Option<Self>> {
+        let var = |name: &str| std::env::var(name).ok().filter(|v| !v.trim().is_empty());
+        let (blue, green) = match (var("UPSTREAM_BLUE"), var("UPSTREAM_GREEN")) {
+            (None, None) => return Ok(None),
+            (Some(blue), Some(green)) => (blue, green),
+            _ => return Err(anyhow!("UPSTREAM_BLUE and UPSTREAM_GREEN go together")),
+        };
+        let active = match var("UPSTREAM_ACTIVE") {
+            Some(v) => color_index(&v)
+                .ok_or_else(|| anyhow!("invalid UPSTREAM_ACTIVE={v} (expected: blue|green)"))?,
+            None => 0,
+        };
+        let confirm_within = var("SWITCH_CONFIRM_SECS")
+            .map(|v| {
+                v.trim()
+                    .parse::<u64>()
+                    .ok()
+                    .filter(|&secs| secs > 0)
+                    .map(Duration::from_secs)
+                    .with_context(|| format!("invalid SWITCH_CONFIRM_SECS={v}"))
+            })
+            .transpose()?;
+        Ok(Some(BlueGreen::new(
+            [
+                build(&blue).context("invalid UPSTREAM_BLUE")?,
+                build(&green).context("invalid UPSTREAM_GREEN")?,
+            ],
+            active,
+            confirm_within,
+        )))
+    }
+
+    fn new(sets: [Balancer<T>; 2], active: usize, confirm_within: Option<Duration>) -> Self {
+        BlueGreen {
+            sets,
+            active: AtomicUsize::new(active),
+            in_flight: [AtomicUsize::new(0), AtomicUsize::new(0)],
+            confirm_within,
+            pending: Mutex::new(None),
+        }
+    }
+
+    /// The live set, for one request.
+    pub(crate) fn lease(&self) -> Lease<'_, T> {
+        self.revert_if_unconfirmed();
+        let set = self.active.load(Ordering::Relaxed);
+        self.in_flight[set].fetch_add(1, Ordering::Relaxed);
+        Lease { owner: self, set }
+    }
+
+    pub(crate) fn active(&self) -> &'static str {
+        self.revert_if_unconfirmed();
+        COLORS[self.active.load(Ordering::Relaxed)]
+    }
+
+    pub(crate) fn set(&self, color: &str) -> Option<&Balancer<T>> {
+        color_index(color).map(|i| &self.sets[i])
+    }
+
+    /// Sends new requests to `to`, or to the other set when `None`.
+    pub(crate) fn switch(&self, to: Option<&str>) -> Result<()> {
+        let mut pending = self.pending.lock().unwrap_or_else(|e| e.into_inner());
+        self.revert_locked(&mut pending);
+        let current = self.active.load(Ordering::Relaxed);
+        let target = match to {
+            Some(color) => color_index(color)
+                .ok_or_else(|| anyhow!("unknown set {color:?} (expected: blue|green)"))?,
+            None => 1 - current,
+        };
+        if target == current {
+            return Err(anyhow!("{} is already live", COLORS[current]));
+        }
+        self.active.store(target, Ordering::Relaxed);
+        *pending = self.confirm_within.map(|within| Instant::now() + within);
+        eprintln!(
+            "[wasm-host] blue/green: switched {} -> {}{}",
+            COLORS[current],
+            COLORS[target],
+            self.confirm_within.map_or(String::new(), |w| format!(
+                ", reverting in {w:?} unless confirmed"
+            ))
+        );
+        Ok(())
+    }
+
+    /// Makes a provisional switch final.
+    pub(crate) fn confirm(&self) -> Result<()> {
+        let mut pending = self.pending.lock().unwrap_or_else(|e| e.into_inner());
+        self.revert_locked(&mut pending);
+        pending
+            .take()
+            .map(|_| {
+                let active = COLORS[self.active.load(Ordering::Relaxed)];
+                eprintln!("[wasm-host] blue/green: {active} confirmed");
+            })
+            .ok_or_else(|| anyhow!("no switch waiting for confirmation"))
+    }
+
+    fn revert_if_unconfirmed(&self) {
+        let mut pending = self.pending.lock().unwrap_or_else(|e| e.into_inner());
+        self.revert_locked(&mut pending);
+    }
+
+    fn revert_locked(&self, pending: &mut Option<Instant>) {
+        if pending.is_some_and(|deadline| Instant::now() >= deadline) {
+            *pending = None;
+            let reverted = 1 - self.active.load(Ordering::Relaxed);
+            self.active.store(reverted, Ordering::Relaxed);
+            eprintln!(
+                "[wasm-host] blue/green: switch not confirmed, back to {}",
+                COLORS[reverted]
+            );
+        }
+    }
+
+    pub(crate) fn json(
+        &self,
+        members: impl Fn(&Balancer<T>) -> serde_json::Value,
+    ) -> serde_json::Value {
+        let active = self.active();
+        let pending = *self.pending.lock().unwrap_or_else(|e| e.into_inner());
+        let sets: serde_json::Map<String, serde_json::Value> = COLORS
+            .iter()
+            .zip(&self.sets)
+            .zip(&self.in_flight)
+            .map(|((color, set), in_flight)| {
+                (
+                    color.to_string(),
+                    serde_json::json!({
+                        "members": members(set),
+                        "in_flight": in_flight.load(Ordering::Relaxed),
+                    }),
+                )
+            })
+            .collect();
+        serde_json::json!({
+            "active": active,
+            "confirm_within_ms": pending
+                .map(|deadline| deadline.saturating_duration_since(Instant::now()).as_millis() as u64),
+            "sets": sets,
+        })
+    }
+}
+
+fn color_index(color: &str) -> Option<usize> {
+    COLORS
+        .iter()
+        .position(|c| c.eq_ignore_ascii_case(color.trim()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::balancer::OutlierSettings;
+
+    fn set(name: &str) -> Balancer<String> {
+        Balancer::new(
+            vec![(name.to_string(), 1, name.to_string())],
+            OutlierSettings::default(),
+        )
+    }
+
+    #[test]
+    fn switches_drains_and_reverts_unconfirmed() {
+        let bg = BlueGreen::new([set("b1"), set("g1")], 0, None);
+        let before = bg.lease();
+        bg.switch(None).unwrap();
+        assert!(bg.switch(Some("green")).is_err());
+        let after = bg.lease();
+        // The request that started on blue stays there.
+        assert_eq!(*before.pick().unwrap(), "b1");
+        assert_eq!(*after.pick().unwrap(), "g1");
+        assert_eq!(
+            bg.json(|_| serde_json::Value::Null)["sets"]["blue"]["in_flight"],
+            1
+        );
+        drop(before);
+        assert_eq!(
+            bg.json(|_| serde_json::Value::Null)["sets"]["blue"]["in_flight"],
+            0
+        );
+        assert!(bg.confirm().is_err());
+
+        let bg = BlueGreen::new([set("b1"), set("g1")], 0, Some(Duration::from_millis(20)));
+        bg.switch(Some("GREEN")).unwrap();
+        bg.confirm().unwrap();
+        bg.switch(Some("blue")).unwrap();
+        assert_eq!(bg.active(), "blue");
+        std::thread::sleep(Duration::from_millis(30));
+        assert_eq!(bg.active(), "green");
+        assert!(bg.confirm().is_err());
+    }
+}
@@ -0,0 +1,527 @@
+//! Upstream response cache for the proxy path (`CACHE_TTL_SECS`).
+//!
+//! Raw upstream responses to bodyless `GET`s are kept in memory under the
+//! same key as `coalesce` (upstream and forwarded request bytes), so the
+//! upstream round trip is skipped while the wasm transform still runs per
+//! request: the two costs can be compared on one gateway. Only `200`s are
+//! stored, and not with `Cache-Control: no-store`, `no-cache` or `private`,
+//! or `Set-Cookie`.
+//!
+//! An entry is fresh for the response's `s-maxage` or `max-age`, else
+//! `CACHE_TTL_SECS`, and then stale for its `stale-while-revalidate`, else
+//! `CACHE_STALE_SECS` (default 0). A stale entry is served at once and the
+//! first request to find it starts a refresh from the upstream on a thread
+//! of its own once its response has been sent; the others keep getting the
+//! stale copy meanwhile. A failed refresh leaves the entry for the next
+//! request to retry until it expires.
+//!
+//! With `CACHE_NEGATIVE_SECS`, failures are cached too, for that long and
+//! never served stale, so a struggling upstream is not hit by every retry:
+//! `5xx` responses, answered from the cache with `X-Cached-Failure: <status>`,
+//! and requests that got no response at all (connect, TLS or I/O errors),
+//! answered with a `502` and `X-Cached-Failure: connect`. Either setting
+//! enables the cache; without `CACHE_TTL_SECS` only failures are stored.
+//! `POST /admin/cache/purge` (`?failures=1` for failures only) empties it.
+//!
+//! With `CACHE_DIR`, successes are also kept on disk (see `disk_cache`) and
+//! memory holds the most recently stored or used of them.
+//!
+//! Responses carry `X-Cache: HIT|STALE|MISS` and, from the cache, `Age`. A
+//! request with `Cache-Control: no-cache` skips the lookup. At most
+//! `CACHE_MAX_ENTRIES` (default 1024) are kept; expired entries go first, then
+//! the oldest. `/stats` reports `cache` and `/metrics`
+//! `gateway_cache_requests_total{result}` and `gateway_cache_entries`.
+
+use anyhow::{Context, Result};
+use once_cell::sync::OnceCell;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::disk_cache::DiskTier;
+use crate::headers::Headers;
+use crate::UpstreamTiming;
+
+const DEFAULT_MAX_ENTRIES: usize = 1024;
+
+static CACHE: OnceCell<ResponseCache> = OnceCell::new();
+
+#[derive(Debug)]
+pub(crate) struct ResponseCache {
+    /// `None` when only failures are cached.
+    ttl: Option<Duration>,
+    stale: Duration,
+    /// How long failures are cached, if at all.
+    negative: Option<Duration>,
+    max_entries: usize,
+    entries: Mutex<HashMap<Vec<u8>, Arc<Entry>>>,
+    disk: Option<DiskTier>,
+    hits: AtomicU64,
+    failure_hits: AtomicU64,
+    stale_hits: AtomicU64,
+    misses: AtomicU64,
+    refreshes: AtomicU64,
+    refresh_failures: AtomicU64,
+}
+
+#[derive(Debug)]
+pub(crate) struct Entry {
+    /// The raw upstream response, or why there was none.
+    pub(crate) resp: Result<Arc<Vec<u8>>, String>,
+    /// A `5xx` or a failed request (`CACHE_NEGATIVE_SECS`).
+    pub(crate) failure: bool,
+    /// Of the upstream request that filled the entry.
+    pub(crate) timing: UpstreamTiming,
+    stored: Instant,
+    fresh_for: Duration,
+    stale_for: Duration,
+    refreshing: AtomicBool,
+}
+
+impl Entry {
+    pub(crate) fn age(&self) -> Duration {
+        self.stored.elapsed()
+    }
+}
+
+#[derive(Debug)]
+pub(crate) enum Lookup {
+    Fresh(Arc<Entry>),
+    /// `true` when this request should refresh the entry.
+    Stale(Arc<Entry>, bool),
+    Miss,
+}
+
+/// Error for a request answered from a cached failed request; the proxy
+/// path answers it with a 502.
+#[derive(Debug)]
+pub(crate) struct CachedFailure(pub(crate) String);
+
+impl std::fmt::Display for CachedFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "cached upstream failure: {}", self.0)
+    }
+}
+
+impl std::error::Error for CachedFailure {}
+
+/// Reads `CACHE_TTL_SECS`, `CACHE_STALE_SECS`, `CACHE_NEGATIVE_SECS` and
+/// `CACHE_MAX_ENTRIES`. Call once from `main`.
+pub(crate) fn init_from_env() -> Result<()> {
+    let secs = |name: &str| -> Result<Option<Duration>> {
+        match std::env::var(name) {
+            Ok(v) if !v.trim().is_empty() => v
+                .trim()
+                .parse::<u64>()
+                .map(|s| Some(Duration::from_secs(s)))
+                .with_context(|| format!("invalid {name}={v}")),
+            _ => Ok(None),
+        }
+    };
+    let ttl = secs("CACHE_TTL_SECS")?;
+    let negative = secs("CACHE_NEGATIVE_SECS")?.filter(|d| !d.is_zero());
+    if ttl.is_none() && negative.is_none() {
+        return Ok(());
+    }
+    let stale = secs("CACHE_STALE_SECS")?.unwrap_or_default();
+    let max_entries = match std::env::var("CACHE_MAX_ENTRIES") {
+        Ok(v) if !v.trim().is_empty() => v
+            .trim()
+            .parse::<usize>()
+            .ok()
+            .filter(|&n| n > 0)
+            .with_context(|| format!("invalid CACHE_MAX_ENTRIES={v}"))?,
+        _ => DEFAULT_MAX_ENTRIES,
+    };
+    let mut cache = ResponseCache::new(ttl, stale, negative, max_entries);
+    cache.disk = DiskTier::from_env()?;
+    let cache = CACHE.get_or_init(|| cache);
+    eprintln!(
+        "[wasm-host] response cache: ttl {:?}, stale-while-revalidate {:?}, failures {:?}, up to {} entries",
+        cache.ttl, cache.stale, cache.negative, cache.max_entries
+    );
+    Ok(())
+}
+
+pub(crate) fn configured() -> Option<&'static ResponseCache> {
+    CACHE.get()
+}
+
+impl ResponseCache {
+    fn new(
+        ttl: Option<Duration>,
+        stale: Duration,
+        negative: Option<Duration>,
+        max_entries: usize,
+    ) -> Self {
+        ResponseCache {
+            ttl,
+            stale,
+            negative,
+            max_entries,
+            entries: Mutex::new(HashMap::new()),
+            disk: None,
+            hits: AtomicU64::new(0),
+            failure_hits: AtomicU64::new(0),
+            stale_hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            refreshes: AtomicU64::new(0),
+            refresh_failures: AtomicU64::new(0),
+        }
+    }
+
+    /// The entry for `key`, if it can still be served. `request` is the
+    /// client's request head, for `Cache-Control: no-cache`.
+    pub(crate) fn lookup(&self, key: &[u8], request: &Headers) -> Lookup {
+        if directives(request).any(|(name, _)| name == "no-cache") {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            return Lookup::Miss;
+        }
+        let entry = self
+            .entries
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(key)
+            .cloned();
+        // Separate statement: promoting from disk takes the lock again.
+        let entry = entry.or_else(|| self.load_from_disk(key));
+        let Some(entry) = entry else {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            return Lookup::Miss;
+        };
+        let age = entry.age();
+        if age < entry.fresh_for {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            if entry.failure {
+                self.failure_hits.fetch_add(1, Ordering::Relaxed);
+            }
+            Lookup::Fresh(entry)
+        } else if age < entry.fresh_for + entry.stale_for {
+            self.stale_hits.fetch_add(1, Ordering::Relaxed);
+            let refresh = !entry.refreshing.swap(true, Ordering::AcqRel);
+            Lookup::Stale(entry, refresh)
+        } else {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            Lookup::Miss
+        }
+    }
+
+    /// Stores `resp` for `key` when it is a cacheable `200`; false when not.
+    pub(crate) fn store(&self, key: Vec<u8>, resp: Arc<Vec<u8>>, timing: UpstreamTiming) -> bool {
+        let Some((fresh_for, stale_for)) = self.lifetime(&resp) else {
+            return false;
+        };
+        if let Some(disk) = &self.disk {
+            disk.write_behind(&key, &resp, SystemTime::now(), fresh_for, stale_for);
+        }
+        self.insert(
+            key,
+            Entry {
+                resp: Ok(resp),
+                failure: false,
+                timing,
+                stored: Instant::now(),
+                fresh_for,
+                stale_for,
+                refreshing: AtomicBool::new(false),
+            },
+        );
+        true
+    }
+
+    /// Stores a `5xx` response, or the error of a request that got none, when
+    /// `CACHE_NEGATIVE_SECS` is set.
+    pub(crate) fn store_failure(
+        &self,
+        key: Vec<u8>,
+        resp: Result<Arc<Vec<u8>>, String>,
+        timing: UpstreamTiming,
+    ) {
+        let Some(negative) = self.negative else {
+            return;
+        };
+        if let Ok(resp) = &resp {
+            let status = resp
+                .get(9..12)
+                .and_then(|code| std::str::from_utf8(code).ok())
+                .and_then(|code| code.parse::<u16>().ok());
+            if !matches!(status, Some(500..=599)) {
+                return;
+            }
+        }
+        self.insert(
+            key,
+            Entry {
+                resp,
+                failure: true,
+                timing,
+                stored: Instant::now(),
+                fresh_for: negative,
+                stale_for: Duration::ZERO,
+                refreshing: AtomicBool::new(false),
+            },
+        );
+    }
+
+    /// Promotes the disk tier's response for `key` to memory.
+    fn load_from_disk(&self, key: &[u8]) -> Option<Arc<Entry>> {
+        let stored = self.disk.as_ref()?.load(key)?;
+        let age = SystemTime::now()
+            .duration_since(stored.stored)
+            .unwrap_or_default();
+        let entry = Entry {
+            resp: Ok(Arc::new(stored.resp)),
+            failure: false,
+            timing: UpstreamTiming::default(),
+            stored: Instant::now().checked_sub(age).unwrap_or_else(Instant::now),
+            fresh_for: stored.fresh_for,
+            stale_for: stored.stale_for,
+            refreshing: AtomicBool::new(false),
+        };
+        Some(self.insert(key.to_vec(), entry))
+    }
+
+    /// Drops every entry (on disk too), or only failures; returns how many
+    /// went from memory.
+    pub(crate) fn purge(&self, failures_only: bool) -> usize {
+        if let (Some(disk), false) = (&self.disk, failures_only) {
+            disk.purge();
+        }
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        let before = entries.len();
+        entries.retain(|_, e| failures_only && !e.failure);
+        before - entries.len()
+    }
+
+    fn insert(&self, key: Vec<u8>, entry: Entry) -> Arc<Entry> {
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        if entries.len() >= self.max_entries && !entries.contains_key(&key) {
+            entries.retain(|_, e| e.age() < e.fresh_for + e.stale_for);
+            if entries.len() >= self.max_entries {
+                if let Some(oldest) = entries
+                    .iter()
+                    .max_by_key(|(_, e)| e.age())
+                    .map(|(k, _)| k.clone())
+                {
+                    entries.remove(&oldest);
+                }
+            }
+        }
+        let entry = Arc::new(entry);
+        entries.insert(key, Arc::clone(&entry));
+        entry
+    }
+
+    /// Records the outcome of a refresh started by `Lookup::Stale`.
+    pub(crate) fn refreshed(&self, entry: &Entry, result: Result<()>) {
+        self.refreshes.fetch_add(1, Ordering::Relaxed);
+        if let Err(err) = result {
+            self.refresh_failures.fetch_add(1, Ordering::Relaxed);
+            entry.refreshing.store(false, Ordering::Release);
+            eprintln!("[wasm-host] cache refresh failed: {err:#}");
+        }
+    }
+
+    /// Freshness and stale-while-revalidate lifetimes of a raw response, or
+    /// `None` when it may not be stored.
+    fn lifetime(&self, resp: &[u8]) -> Option<(Duration, Duration)> {
+        let ttl = self.ttl?;
+        let head_end = resp.windows(4).position(|w| w == b"\r\n\r\n")?;
+        let (status_line, headers) = Headers::parse_head(&resp[..head_end]).ok()?;
+        if status_line.split_whitespace().nth(1) != Some("200")
+            || headers.get("Set-Cookie").is_some()
+        {
+            return None;
+        }
+        let mut fresh_for = None;
+        let mut stale_for = self.stale;
+        for (name, value) in directives(&headers) {
+            let secs = || {
+                value
+                    .and_then(|v| v.parse::<u64>().ok())
+                    .map(Duration::from_secs)
+            };
+            match name.as_str() {
+                "no-store" | "no-cache" | "private" => return None,
+                "s-maxage" => fresh_for = secs().or(fresh_for),
+                "max-age" if fresh_for.is_none() => fresh_for = secs(),
+                "stale-while-revalidate" => stale_for = secs().unwrap_or(stale_for),
+                _ => {}
+            }
+        }
+        let fresh_for = fresh_for.unwrap_or(ttl);
+        (fresh_for + stale_for > Duration::ZERO).then_some((fresh_for, stale_for))
+    }
+
+    pub(crate) fn json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "entries": self.len(),
+            "hits": self.hits.load(Ordering::Relaxed),
+            "failure_hits": self.failure_hits.load(Ordering::Relaxed),
+            "stale_hits": self.stale_hits.load(Ordering::Relaxed),
+            "misses": self.misses.load(Ordering::Relaxed),
+            "refreshes": self.refreshes.load(Ordering::Relaxed),
+            "refresh_failures": self.refresh_failures.load(Ordering::Relaxed),
+            "disk": self.disk.as_ref().map(DiskTier::json),
+        })
+    }
+
+    /// Prometheus samples for `gateway_cache_requests_total`.
+    pub(crate) fn samples(&self) -> Vec<(String, String)> {
+        [
+            ("hit", &self.hits),
+            ("stale", &self.stale_hits),
+            ("miss", &self.misses),
+        ]
+        .into_iter()
+        .map(|(result, n)| {
+            (
+                format!("{{result=\"{result}\"}}"),
+                n.load(Ordering::Relaxed).to_string(),
+            )
+        })
+        .collect()
+    }
+
+    /// Bytes in the disk tier, if there is one.
+    pub(crate) fn disk_bytes(&self) -> Option<u64> {
+        self.disk.as_ref().map(DiskTier::bytes)
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.entries.lock().unwrap_or_else(|e| e.into_inner()).len()
+    }
+}
+
+/// `Cache-Control` directives, names lowercased, quotes stripped from values.
+fn directives(headers: &Headers) -> impl Iterator<Item = (String, Option<&str>)> {
+    headers
+        .get_all("Cache-Control")
+        .flat_map(|v| v.split(','))
+        .map(str::trim)
+        .filter(|d| !d.is_empty())
+        .map(|d| match d.split_once('=') {
+            Some((name, value)) => (
+                name.trim().to_ascii_lowercase(),
+                Some(value.trim().trim_matches('"')),
+            ),
+            None => (d.to_ascii_lowercase(), None),
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(head: &str) -> Arc<Vec<u8>> {
+        Arc::new(format!("{head}\r\n\r\nbody").into_bytes())
+    }
+
+    #[test]
+    fn serves_fresh_then_stale_with_one_refresh() {
+        let cache = ResponseCache::new(Some(Duration::from_secs(60)), Duration::ZERO, None, 8);
+        let none = Headers::default();
+        assert!(matches!(cache.lookup(b"k", &none), Lookup::Miss));
+        cache.store(
+            b"k".to_vec(),
+            response("HTTP/1.1 200 OK"),
+            UpstreamTiming::default(),
+        );
+        assert!(matches!(cache.lookup(b"k", &none), Lookup::Fresh(_)));
+        assert!(matches!(
+            cache.lookup(b"k", &Headers::parse("Cache-Control: no-cache")),
+            Lookup::Miss
+        ));
+
+        // Already stale, with a minute to revalidate in.
+        cache.store(
+            b"k".to_vec(),
+            response("HTTP/1.1 200 OK\r\nCache-Control: max-age=0, stale-while-revalidate=60"),
+            UpstreamTiming::default(),
+        );
+        let Lookup::Stale(entry, true) = cache.lookup(b"k", &none) else {
+            panic!("first stale lookup should refresh");
+        };
+        assert!(matches!(cache.lookup(b"k", &none), Lookup::Stale(_, false)));
+        cache.refreshed(&entry, Err(anyhow::anyhow!("upstream down")));
+        assert!(matches!(cache.lookup(b"k", &none), Lookup::Stale(_, true)));
+        assert_eq!(cache.json()["stale_hits"], 3);
+        assert_eq!(cache.json()["refresh_failures"], 1);
+    }
+
+    #[test]
+    fn stores_only_cacheable_responses() {
+        let cache = ResponseCache::new(
+            Some(Duration::from_secs(60)),
+            Duration::from_secs(5),
+            None,
+            2,
+        );
+        let lifetime = |head: &str| cache.lifetime(&response(head));
+        let secs = Duration::from_secs;
+        assert_eq!(lifetime("HTTP/1.1 200 OK"), Some((secs(60), secs(5))));
+        assert_eq!(
+            lifetime("HTTP/1.1 200 OK\r\nCache-Control: public, max-age=10, s-maxage=20"),
+            Some((secs(20), secs(5)))
+        );
+        assert_eq!(
+            lifetime("HTTP/1.1 200 OK\r\nCache-Control: max-age=\"10\", stale-while-revalidate=1"),
+            Some((secs(10), secs(1)))
+        );
+        for head in [
+            "HTTP/1.1 404 Not Found",
+            "HTTP/1.1 200 OK\r\nCache-Control: no-store",
+            "HTTP/1.1 200 OK\r\nCache-Control: Private",
+            "HTTP/1.1 200 OK\r\nSet-Cookie: a=1",
+        ] {
+            assert_eq!(lifetime(head), None, "{head:?}");
+        }
+
+        for key in [b"a", b"b", b"c"] {
+            cache.store(
+                key.to_vec(),
+                response("HTTP/1.1 200 OK"),
+                UpstreamTiming::default(),
+            );
+        }
+        assert_eq!(cache.len(), 2);
+        assert!(matches!(
+            cache.lookup(b"c", &Headers::default()),
+            Lookup::Fresh(_)
+        ));
+    }
+
+    #[test]
+    fn caches_failures_when_enabled() {
+        let cache = ResponseCache::new(None, Duration::ZERO, Some(Duration::from_secs(5)), 8);
+        let none = Headers::default();
+        let timing = UpstreamTiming::default();
+        assert!(!cache.store(b"ok".to_vec(), response("HTTP/1.1 200 OK"), timing));
+        cache.store_failure(
+            b"404".to_vec(),
+            Ok(response("HTTP/1.1 404 Not Found")),
+            timing,
+        );
+        cache.store_failure(
+            b"503".to_vec(),
+            Ok(response("HTTP/1.1 503 Service Unavailable")),
+            timing,
+        );
+        cache.store_failure(
+            b"down".to_vec(),
+            Err("connection refused".to_string()),
+            timing,
+        );
+        assert_eq!(cache.len(), 2);
+        let Lookup::Fresh(entry) = cache.lookup(b"down", &none) else {
+            panic!("failed request should be cached");
+        };
+        assert_eq!(entry.resp.as_ref().unwrap_err(), "connection refused");
+        assert!(entry.failure);
+        assert_eq!(cache.json()["failure_hits"], 1);
+
+        assert_eq!(cache.purge(true), 2);
+        assert!(matches!(cache.lookup(b"503", &none), Lookup::Miss));
+    }
+}
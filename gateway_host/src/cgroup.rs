@@ -0,0 +1,189 @@
+//! Dedicated cgroup (v2) for the `wasmedge` / `wasmtime run` processes, so a
+//! busy filter is capped instead of competing with the proxy loop for CPU.
+//!
+//! - `WASM_CGROUP_CPU=0.5` writes `cpu.max` (in cores).
+//! - `WASM_CGROUP_MEMORY_MB` writes `memory.max`.
+//! - `WASM_CGROUP_PATH` (default `/sys/fs/cgroup/gateway_wasm`) is created when
+//!   either limit is set, with the controllers enabled in its parent.
+//!
+//! Each runtime process moves itself into the cgroup between fork and exec;
+//! the gateway stays where it is. Throttling counters from `cpu.stat` and
+//! `memory.events` are reported by `/metrics` and `/stats`. Linux only, and the
+//! gateway needs write access to the cgroup tree (root, or a delegated
+//! subtree).
+
+use anyhow::{anyhow, Context, Result};
+use once_cell::sync::OnceCell;
+use std::fs::{File, OpenOptions};
+use std::path::{Path, PathBuf};
+
+const DEFAULT_PATH: &str = "/sys/fs/cgroup/gateway_wasm";
+/// `cpu.max` period; the quota is `WASM_CGROUP_CPU` times this.
+const CPU_PERIOD_US: u64 = 100_000;
+/// Smallest quota the kernel accepts.
+const CPU_MIN_QUOTA_US: u64 = 1_000;
+
+static WASM_CGROUP: OnceCell<Option<WasmCgroup>> = OnceCell::new();
+
+#[derive(Debug)]
+pub(crate) struct WasmCgroup {
+    dir: PathBuf,
+    /// `cgroup.procs`, opened once so the post-fork hook only has to write.
+    procs: File,
+}
+
+/// Counters read from the cgroup's `cpu.stat`, `memory.current` and
+/// `memory.events`; fields the kernel does not report stay 0.
+#[derive(Debug, Default)]
+pub(crate) struct CgroupStats {
+    pub(crate) cpu_usage_us: u64,
+    pub(crate) cpu_periods: u64,
+    pub(crate) cpu_throttled_periods: u64,
+    pub(crate) cpu_throttled_us: u64,
+    pub(crate) memory_bytes: u64,
+    /// Times usage hit `memory.max`.
+    pub(crate) memory_max_events: u64,
+    pub(crate) oom_kills: u64,
+}
+
+/// The configured cgroup, set up on first call; `None` unless
+/// `WASM_CGROUP_CPU` or `WASM_CGROUP_MEMORY_MB` is set.
+pub(crate) fn from_env() -> Result<Option<&'static WasmCgroup>> {
+    WASM_CGROUP
+        .get_or_try_init(WasmCgroup::from_env)
+        .map(Option::as_ref)
+}
+
+/// The cgroup if one has been set up, for reporting.
+pub(crate) fn configured() -> Option<&'static WasmCgroup> {
+    WASM_CGROUP.get().and_then(Option::as_ref)
+}
+
+impl WasmCgroup {
+    fn from_env() -> Result<Option<Self>> {
+        let cpu = match std::env::var("WASM_CGROUP_CPU") {
+            Ok(v) if !v.is_empty() => {
+                let cores = v
+                    .parse::<f64>()
+                    .with_context(|| format!("invalid WASM_CGROUP_CPU={v}"))?;
+                if !(cores.is_finite() && cores > 0.0) {
+                    return Err(anyhow!("WASM_CGROUP_CPU={v} must be above 0"));
+                }
+                Some(cores)
+            }
+            _ => None,
+        };
+        let memory_mb = match std::env::var("WASM_CGROUP_MEMORY_MB") {
+            Ok(v) if !v.is_empty() => Some(
+                v.parse::<u64>()
+                    .with_context(|| format!("invalid WASM_CGROUP_MEMORY_MB={v}"))?,
+            ),
+            _ => None,
+        };
+        if cpu.is_none() && memory_mb.is_none() {
+            return Ok(None);
+        }
+        if !cfg!(target_os = "linux") {
+            return Err(anyhow!("WASM_CGROUP_* is only supported on Linux"));
+        }
+        let dir = PathBuf::from(
+            std::env::var("WASM_CGROUP_PATH")
+                .ok()
+                .filter(|p| !p.is_empty())
+                .unwrap_or_else(|| DEFAULT_PATH.to_string()),
+        );
+        let cgroup = Self::create(&dir, cpu, memory_mb)
+            .with_context(|| format!("failed to set up wasm cgroup {}", dir.display()))?;
+        eprintln!(
+            "[wasm-host] wasm cgroup: {} (cpu: {}, memory: {})",
+            dir.display(),
+            cpu.map_or("unlimited".to_string(), |c| format!("{c} cores")),
+            memory_mb.map_or("unlimited".to_string(), |mb| format!("{mb} MiB")),
+        );
+        Ok(Some(cgroup))
+    }
+
+    fn create(dir: &Path, cpu: Option<f64>, memory_mb: Option<u64>) -> Result<Self> {
+        let parent = dir
+            .parent()
+            .ok_or_else(|| anyhow!("cgroup path has no parent"))?;
+        let subtree_control = parent.join("cgroup.subtree_control");
+        let enabled = std::fs::read_to_string(&subtree_control).with_context(|| {
+            format!(
+                "failed to read {} (is cgroup v2 mounted there?)",
+                subtree_control.display()
+            )
+        })?;
+        for (controller, wanted) in [("cpu", cpu.is_some()), ("memory", memory_mb.is_some())] {
+            if wanted && !enabled.split_whitespace().any(|c| c == controller) {
+                std::fs::write(&subtree_control, format!("+{controller}")).with_context(|| {
+                    format!(
+                        "failed to enable the {controller} controller in {}",
+                        parent.display()
+                    )
+                })?;
+            }
+        }
+        if !dir.is_dir() {
+            std::fs::create_dir(dir).context("failed to create cgroup")?;
+        }
+        if let Some(cores) = cpu {
+            let quota = ((cores * CPU_PERIOD_US as f64).round() as u64).max(CPU_MIN_QUOTA_US);
+            std::fs::write(dir.join("cpu.max"), format!("{quota} {CPU_PERIOD_US}"))
+                .context("failed to write cpu.max")?;
+        }
+        if let Some(mb) = memory_mb {
+            std::fs::write(dir.join("memory.max"), (mb * 1024 * 1024).to_string())
+                .context("failed to write memory.max")?;
+        }
+        let procs = OpenOptions::new()
+            .write(true)
+            .open(dir.join("cgroup.procs"))
+            .context("failed to open cgroup.procs")?;
+        Ok(WasmCgroup {
+            dir: dir.to_path_buf(),
+            procs,
+        })
+    }
+
+    /// Descriptor of `cgroup.procs`; writing `0` to it moves the writing
+    /// process into the cgroup.
+    #[cfg(unix)]
+    pub(crate) fn procs_fd(&self) -> std::os::unix::io::RawFd {
+        use std::os::unix::io::AsRawFd;
+        self.procs.as_raw_fd()
+    }
+
+    pub(crate) fn stats(&self) -> CgroupStats {
+        let read = |file: &str| std::fs::read_to_string(self.dir.join(file)).unwrap_or_default();
+        let mut stats = CgroupStats {
+            memory_bytes: read("memory.current").trim().parse().unwrap_or(0),
+            ..CgroupStats::default()
+        };
+        for (key, value) in flat_keyed(&read("cpu.stat")) {
+            match key {
+                "usage_usec" => stats.cpu_usage_us = value,
+                "nr_periods" => stats.cpu_periods = value,
+                "nr_throttled" => stats.cpu_throttled_periods = value,
+                "throttled_usec" => stats.cpu_throttled_us = value,
+                _ => {}
+            }
+        }
+        for (key, value) in flat_keyed(&read("memory.events")) {
+            match key {
+                "max" => stats.memory_max_events = value,
+                "oom_kill" => stats.oom_kills = value,
+                _ => {}
+            }
+        }
+        stats
+    }
+}
+
+/// `key value` lines as found in `cpu.stat` and `memory.events`.
+fn flat_keyed(text: &str) -> impl Iterator<Item = (&str, u64)> {
+    text.lines().filter_map(|line| {
+        let (key, value) = line.split_once(' ')?;
+        Some((key, value.trim().parse().ok()?))
+    })
+}
@@ -0,0 +1,200 @@
+//! Request coalescing for identical concurrent `GET`s (`COALESCE_GETS=1`).
+//!
+//! A bodyless `GET` on the proxy path whose forwarded request is byte for
+//! byte the same as one already in flight to the same upstream does not send
+//! its own: it waits for the in-flight one and reuses its raw response. Each
+//! waiting request still decodes and transforms the shared bytes itself and
+//! answers with `X-Coalesced: true`. Requests that differ in any forwarded
+//! field (`Authorization`, `Cookie`, `Accept`, ...) are never merged, so a
+//! thundering herd is collapsed only where one response serves them all.
+//!
+//! An upstream failure is shared too: every waiter gets the leader's error.
+//! `/stats` reports `coalescing` and `/metrics`
+//! `gateway_coalesced_requests_total{role="leader|follower"}`, where a
+//! leader is an upstream request that had at least one follower.
+
+use anyhow::{anyhow, Result};
+use once_cell::sync::OnceCell;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+
+use crate::UpstreamTiming;
+
+static COALESCER: OnceCell<Coalescer> = OnceCell::new();
+
+/// A raw upstream response shared by every request in its flight.
+type Outcome = Result<(Arc<Vec<u8>>, UpstreamTiming), String>;
+
+#[derive(Debug, Default)]
+pub(crate) struct Coalescer {
+    /// In-flight upstream requests by upstream and forwarded request bytes.
+    flights: Mutex<HashMap<Vec<u8>, Arc<Flight>>>,
+    leaders: AtomicU64,
+    followers: AtomicU64,
+}
+
+#[derive(Debug, Default)]
+struct Flight {
+    outcome: Mutex<Option<Outcome>>,
+    done: Condvar,
+    followers: AtomicU64,
+}
+
+pub(crate) fn init_from_env() {
+    if std::env::var("COALESCE_GETS").is_ok_and(|v| v == "1") {
+        COALESCER.set(Coalescer::default()).ok();
+        eprintln!("[wasm-host] coalescing identical concurrent GETs");
+    }
+}
+
+pub(crate) fn configured() -> Option<&'static Coalescer> {
+    COALESCER.get()
+}
+
+impl Coalescer {
+    /// Runs `round_trip` for `key`, or waits for the one already running.
+    /// The flag is true when the response came from another request's trip.
+    pub(crate) fn run(
+        &self,
+        key: Vec<u8>,
+        round_trip: impl FnOnce() -> Result<(Vec<u8>, UpstreamTiming)>,
+    ) -> Result<(Arc<Vec<u8>>, UpstreamTiming, bool)> {
+        let mut flights = self.flights.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(flight) = flights.get(&key).cloned() {
+            // Counted under the map lock, before the leader can retire it.
+            flight.followers.fetch_add(1, Ordering::Relaxed);
+            drop(flights);
+            self.followers.fetch_add(1, Ordering::Relaxed);
+            let mut outcome = flight.outcome.lock().unwrap_or_else(|e| e.into_inner());
+            while outcome.is_none() {
+                outcome = flight.done.wait(outcome).unwrap_or_else(|e| e.into_inner());
+            }
+            return match outcome.as_ref() {
+                Some(Ok((resp, timing))) => Ok((Arc::clone(resp), *timing, true)),
+                Some(Err(err)) => Err(anyhow!("coalesced upstream request failed: {err}")),
+                None => unreachable!(),
+            };
+        }
+        let flight = Arc::new(Flight::default());
+        flights.insert(key.clone(), Arc::clone(&flight));
+        drop(flights);
+
+        let leading = Leading {
+            coalescer: self,
+            key,
+            flight,
+        };
+        let result = round_trip().map(|(resp, timing)| (Arc::new(resp), timing));
+        *leading
+            .flight
+            .outcome
+            .lock()
+            .unwrap_or_else(|e| e.into_inner()) = Some(
+            result
+                .as_ref()
+                .map(|(resp, timing)| (Arc::clone(resp), *timing))
+                .map_err(|e| format!("{e:#}")),
+        );
+        drop(leading);
+        result.map(|(resp, timing)| (resp, timing, false))
+    }
+
+    pub(crate) fn json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "leaders": self.leaders.load(Ordering::Relaxed),
+            "followers": self.followers.load(Ordering::Relaxed),
+        })
+    }
+
+    /// Prometheus samples for `gateway_coalesced_requests_total`.
+    pub(crate) fn samples(&self) -> [(String, String); 2] {
+        [
+            (
+                "{role=\"leader\"}".to_string(),
+                self.leaders.load(Ordering::Relaxed).to_string(),
+            ),
+            (
+                "{role=\"follower\"}".to_string(),
+                self.followers.load(Ordering::Relaxed).to_string(),
+            ),
+        ]
+    }
+}
+
+/// The leader's hold on a flight. Dropping it, also on error or panic,
+/// retires the flight so later requests start a new one, and wakes the
+/// followers (with an error if no outcome was stored).
+struct Leading<'a> {
+    coalescer: &'a Coalescer,
+    key: Vec<u8>,
+    flight: Arc<Flight>,
+}
+
+impl Drop for Leading<'_> {
+    fn drop(&mut self) {
+        self.coalescer
+            .flights
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(&self.key);
+        let mut outcome = self
+            .flight
+            .outcome
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        outcome.get_or_insert_with(|| Err("leader abandoned the request".to_string()));
+        if self.flight.followers.load(Ordering::Relaxed) > 0 {
+            self.coalescer.leaders.fetch_add(1, Ordering::Relaxed);
+        }
+        self.flight.done.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Barrier;
+    use std::time::Duration;
+
+    #[test]
+    fn followers_share_the_leaders_response() {
+        let coalescer = Coalescer::default();
+        let trips = AtomicU64::new(0);
+        let waiting = Barrier::new(4);
+        let results: Vec<_> = std::thread::scope(|scope| {
+            let handles: Vec<_> = (0..4)
+                .map(|_| {
+                    scope.spawn(|| {
+                        waiting.wait();
+                        coalescer.run(b"GET /a".to_vec(), || {
+                            trips.fetch_add(1, Ordering::Relaxed);
+                            // Long enough for the others to join the flight.
+                            std::thread::sleep(Duration::from_millis(200));
+                            Ok((
+                                b"HTTP/1.1 200 OK\r\n\r\nok".to_vec(),
+                                UpstreamTiming::default(),
+                            ))
+                        })
+                    })
+                })
+                .collect();
+            handles.into_iter().map(|h| h.join().unwrap()).collect()
+        });
+        assert_eq!(trips.load(Ordering::Relaxed), 1);
+        assert_eq!(results.iter().filter(|r| r.as_ref().unwrap().2).count(), 3);
+        assert!(results
+            .iter()
+            .all(|r| r.as_ref().unwrap().0.ends_with(b"\r\n\r\nok")));
+        assert_eq!(coalescer.json()["leaders"], 1);
+        assert_eq!(coalescer.json()["followers"], 3);
+
+        // The flight is retired: the next request goes upstream again, and a
+        // failure is returned as an error.
+        let err = coalescer
+            .run(b"GET /a".to_vec(), || Err(anyhow!("connection refused")))
+            .unwrap_err();
+        assert_eq!(err.to_string(), "connection refused");
+        assert!(coalescer.flights.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,274 @@
+//! `gateway_host coldstart`: how long each wasm backend takes from nothing to
+//! its first successful transform, and how long the next one takes.
+//!
+//! Every iteration starts the backend from scratch and times one transform
+//! of `--input` (cold), then a second one on the same backend (warm):
+//!
+//! - `wasmtime` / `wasmedge`: a process per call, as without `WASM_WORKERS`,
+//!   so cold and warm differ only by the OS page cache.
+//! - `wasmtime_embedded`: a new engine compiling the module (JIT).
+//! - `wasmtime_aot`: a new engine loading the module precompiled once before
+//!   the iterations, as from a `.cwasm` file.
+//!
+//! The JSON report (to `--out` or stdout) has per-backend distributions in
+//! milliseconds and the `/stats` fingerprint. A backend that fails its first
+//! transform, e.g. a CLI missing from `PATH`, is reported with its `error`
+//! and skipped. `GUEST_*` and `WASM_GUEST_ARGS` apply as when serving.
+
+use anyhow::{anyhow, Context, Result};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use wasmtime::Module;
+
+use crate::{metrics, run_embedded_wasmtime, sandbox, transform, EmbeddedWasmtime, Envelope};
+
+const USAGE: &str = "usage: gateway_host coldstart [--module PATH] [--iterations N] \
+[--backends wasmtime,wasmedge,wasmtime_embedded,wasmtime_aot] [--input TEXT] [--out PATH]";
+
+const BACKENDS: [&str; 4] = ["wasmtime", "wasmedge", "wasmtime_embedded", "wasmtime_aot"];
+const DEFAULT_ITERATIONS: usize = 20;
+
+#[derive(Debug)]
+struct Options {
+    module: String,
+    iterations: usize,
+    backends: Vec<String>,
+    input: Vec<u8>,
+    out: Option<PathBuf>,
+}
+
+impl Options {
+    fn parse(args: &[String]) -> Result<Self> {
+        let mut options = Options {
+            module: std::env::var("WASM_MODULE_PATH")
+                .unwrap_or_else(|_| "./gateway_logic.wasm".to_string()),
+            iterations: DEFAULT_ITERATIONS,
+            backends: BACKENDS.iter().map(|b| b.to_string()).collect(),
+            input: b"hello".to_vec(),
+            out: None,
+        };
+        let mut rest = args.iter();
+        while let Some(arg) = rest.next() {
+            let mut value = || {
+                rest.next()
+                    .ok_or_else(|| anyhow!("{arg} needs a value\n{USAGE}"))
+            };
+            match arg.as_str() {
+                "--module" => options.module = value()?.clone(),
+                "--iterations" => {
+                    let v = value()?;
+                    options.iterations = v
+                        .parse::<usize>()
+                        .ok()
+                        .filter(|&n| n > 0)
+                        .ok_or_else(|| anyhow!("invalid --iterations {v:?}"))?;
+                }
+                "--backends" => {
+                    options.backends = value()?
+                        .split(',')
+                        .map(str::trim)
+                        .filter(|b| !b.is_empty())
+                        .map(|b| {
+                            BACKENDS
+                                .contains(&b)
+                                .then(|| b.to_string())
+                                .ok_or_else(|| anyhow!("unknown backend {b:?}\n{USAGE}"))
+                        })
+                        .collect::<Result<_>>()?;
+                }
+                "--input" => options.input = value()?.clone().into_bytes(),
+                "--out" => options.out = Some(PathBuf::from(value()?)),
+                other => return Err(anyhow!("unexpected argument {other:?}\n{USAGE}")),
+            }
+        }
+        Ok(options)
+    }
+}
+
+/// Cold and warm transform times for one backend.
+#[derive(Debug, Default)]
+struct Samples {
+    cold: Vec<Duration>,
+    warm: Vec<Duration>,
+}
+
+pub(crate) fn run(args: &[String]) -> Result<()> {
+    let options = Options::parse(args)?;
+    let guest = transform::GuestConfig::from_env();
+    let sandbox = sandbox::Sandbox::from_env()?;
+
+    let mut backends = serde_json::Map::new();
+    for backend in &options.backends {
+        eprintln!(
+            "[coldstart] {backend}: {} iteration(s) of {}",
+            options.iterations, options.module
+        );
+        let value = match measure(backend, &options, &guest, &sandbox) {
+            Ok(samples) => {
+                let (cold, warm) = (summary(&samples.cold), summary(&samples.warm));
+                eprintln!(
+                    "[coldstart] {backend}: cold p50 {:.2} ms, p99 {:.2} ms; warm p50 {:.2} ms",
+                    cold["p50"].as_f64().unwrap_or_default(),
+                    cold["p99"].as_f64().unwrap_or_default(),
+                    warm["p50"].as_f64().unwrap_or_default(),
+                );
+                serde_json::json!({ "cold_ms": cold, "warm_ms": warm })
+            }
+            Err(err) => {
+                eprintln!("[coldstart] {backend}: skipped: {err:#}");
+                serde_json::json!({ "error": format!("{err:#}") })
+            }
+        };
+        backends.insert(backend.clone(), value);
+    }
+
+    let report = serde_json::json!({
+        "module": options.module,
+        "iterations": options.iterations,
+        "input_bytes": options.input.len(),
+        "fingerprint": metrics::fingerprint_json(),
+        "backends": backends,
+    });
+    let json = serde_json::to_string_pretty(&report)?;
+    match &options.out {
+        Some(out) => std::fs::write(out, json + "\n")
+            .with_context(|| format!("failed to write {}", out.display()))?,
+        None => println!("{json}"),
+    }
+    Ok(())
+}
+
+fn measure(
+    backend: &str,
+    options: &Options,
+    guest: &transform::GuestConfig,
+    sandbox: &sandbox::Sandbox,
+) -> Result<Samples> {
+    let module_path = options.module.as_str();
+    let envelope = Envelope::default();
+    // Compiled once, outside the timings, like a `.cwasm` shipped with the
+    // image.
+    let precompiled = if backend == "wasmtime_aot" {
+        let bytes = crate::embedded_engine()?
+            .precompile_module(
+                &std::fs::read(module_path)
+                    .with_context(|| format!("failed to read {module_path}"))?,
+            )
+            .with_context(|| format!("failed to precompile {module_path}"))?;
+        let path = std::env::temp_dir().join(format!("coldstart-{}.cwasm", std::process::id()));
+        std::fs::write(&path, bytes)
+            .with_context(|| format!("failed to write {}", path.display()))?;
+        Some(TempFile(path))
+    } else {
+        None
+    };
+
+    let mut samples = Samples::default();
+    for _ in 0..options.iterations {
+        let start = Instant::now();
+        let (cold, warm) = match backend {
+            "wasmtime" | "wasmedge" => {
+                let call = || {
+                    crate::wasm_transform_cli(
+                        backend,
+                        module_path,
+                        &options.input,
+                        &envelope,
+                        None,
+                        sandbox,
+                        guest,
+                    )
+                };
+                call()?;
+                let cold = start.elapsed();
+                let warm = Instant::now();
+                call()?;
+                (cold, warm.elapsed())
+            }
+            _ => {
+                let engine = crate::embedded_engine()?;
+                let module = match &precompiled {
+                    // Safety: the file was written above by
+                    // `Engine::precompile_module` with the same configuration.
+                    Some(TempFile(path)) => unsafe { Module::deserialize_file(&engine, path) },
+                    None => Module::from_file(&engine, module_path),
+                }
+                .with_context(|| format!("failed to load {module_path}"))?;
+                let runtime = EmbeddedWasmtime::new(engine, module)?;
+                let call = || {
+                    run_embedded_wasmtime(
+                        &runtime,
+                        module_path,
+                        &options.input,
+                        &envelope,
+                        guest,
+                        None,
+                        None,
+                    )
+                };
+                call()?;
+                let cold = start.elapsed();
+                let warm = Instant::now();
+                call()?;
+                (cold, warm.elapsed())
+            }
+        };
+        samples.cold.push(cold);
+        samples.warm.push(warm);
+    }
+    Ok(samples)
+}
+
+/// Removed when dropped.
+struct TempFile(PathBuf);
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        std::fs::remove_file(&self.0).ok();
+    }
+}
+
+/// Nearest-rank percentiles and the mean, in milliseconds.
+fn summary(samples: &[Duration]) -> serde_json::Value {
+    let mut ms: Vec<f64> = samples.iter().map(|d| d.as_secs_f64() * 1000.0).collect();
+    ms.sort_by(f64::total_cmp);
+    let percentile = |p: f64| {
+        let rank = ((p / 100.0) * ms.len() as f64).ceil() as usize;
+        ms.get(rank.clamp(1, ms.len().max(1)) - 1).copied()
+    };
+    let mean = (!ms.is_empty()).then(|| ms.iter().sum::<f64>() / ms.len() as f64);
+    serde_json::json!({
+        "min": ms.first(),
+        "p50": percentile(50.0),
+        "p90": percentile(90.0),
+        "p99": percentile(99.0),
+        "max": ms.last(),
+        "mean": mean,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn summarizes_with_nearest_rank() {
+        let samples: Vec<Duration> = (1..=10).rev().map(Duration::from_millis).collect();
+        let ms = summary(&samples);
+        assert_eq!(ms["min"], 1.0);
+        assert_eq!(ms["p50"], 5.0);
+        assert_eq!(ms["p90"], 9.0);
+        assert_eq!(ms["p99"], 10.0);
+        assert_eq!(ms["max"], 10.0);
+        assert_eq!(ms["mean"], 5.5);
+        assert!(summary(&[])["p50"].is_null());
+
+        let args = ["--iterations", "3", "--backends", "wasmtime_aot"].map(String::from);
+        let options = Options::parse(&args).unwrap();
+        assert_eq!(
+            (options.iterations, options.backends),
+            (3, vec!["wasmtime_aot".to_string()])
+        );
+        assert!(Options::parse(&["--backends".to_string(), "v8".to_string()]).is_err());
+    }
+}
@@ -0,0 +1,161 @@
+//! `TRANSFORM_BACKEND=component`: a WebAssembly component implementing the
+//! `gateway` world from `wit/gateway.wit`. Host bindings are generated from the
+//! WIT at build time, so the request/response shapes are typed instead of raw
+//! stdin framing.
+//!
+//! Components are linked only against the `host` interface (no WASI), so
+//! guests are built for `wasm32-unknown-unknown` and wrapped with
+//! `wasm-tools component new`.
+
+use anyhow::{anyhow, Context, Result};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use wasmtime::component::{Component, HasSelf, Linker};
+use wasmtime::{Engine, Store};
+
+use crate::secrets;
+use crate::transform::Transform;
+use crate::Envelope;
+
+wasmtime::component::bindgen!({
+    path: "../wit",
+    world: "gateway",
+});
+
+use gateway::transform::host::{self, Level};
+
+/// Per-call store data.
+struct HostState {
+    module: Arc<str>,
+    req_id: String,
+    kv: Arc<Mutex<HashMap<String, String>>>,
+}
+
+impl host::Host for HostState {
+    fn log(&mut self, level: Level, message: String) {
+        let level = match level {
+            Level::Debug => "debug",
+            Level::Info => "info",
+            Level::Warn => "warn",
+            Level::Error => "error",
+        };
+        eprintln!(
+            "[wasm-host] req_id={} {} {level}: {message}",
+            self.req_id, self.module
+        );
+    }
+
+    fn kv_get(&mut self, key: String) -> Option<String> {
+        self.kv
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(&key)
+            .cloned()
+    }
+
+    fn kv_set(&mut self, key: String, value: String) {
+        self.kv
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(key, value);
+    }
+
+    fn secret_handle(&mut self, name: String) -> Option<u32> {
+        secrets::configured()?.guest_handle(&name)
+    }
+
+    fn secret_hmac_sha256(&mut self, handle: u32, data: Vec<u8>) -> Option<Vec<u8>> {
+        let secret = secrets::configured()?.guest_value(handle)?;
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).ok()?;
+        mac.update(&data);
+        Some(mac.finalize().into_bytes().to_vec())
+    }
+}
+
+impl gateway::transform::types::Host for HostState {}
+
+/// Component compiled and pre-linked once at startup; each call gets a fresh
+/// instance sharing only the key-value map.
+pub(crate) struct ComponentTransform {
+    engine: Engine,
+    pre: GatewayPre<HostState>,
+    module: Arc<str>,
+    kv: Arc<Mutex<HashMap<String, String>>>,
+}
+
+impl std::fmt::Debug for ComponentTransform {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ComponentTransform")
+            .field("module", &self.module)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ComponentTransform {
+    pub(crate) fn load(path: &str) -> Result<Self> {
+        let engine = Engine::default();
+        let component = Component::from_file(&engine, path)
+            .with_context(|| format!("failed to compile wasm component at {path}"))?;
+        let mut linker = Linker::<HostState>::new(&engine);
+        Gateway::add_to_linker::<_, HasSelf<_>>(&mut linker, |state| state)
+            .context("failed to add gateway host imports")?;
+        let pre = linker
+            .instantiate_pre(&component)
+            .and_then(GatewayPre::new)
+            .with_context(|| format!("{path} does not implement the gateway world"))?;
+        let module = std::path::Path::new(path)
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_else(|| path.to_string());
+        Ok(ComponentTransform {
+            engine,
+            pre,
+            module: module.into(),
+            kv: Arc::new(Mutex::new(HashMap::new())),
+        })
+    }
+}
+
+impl Transform for ComponentTransform {
+    fn name(&self) -> &'static str {
+        "component"
+    }
+
+    fn transform(&self, input: &[u8], envelope: &Envelope) -> Result<Vec<u8>> {
+        let var = |name: &str| {
+            envelope
+                .vars
+                .iter()
+                .find(|(k, _)| k == name)
+                .map(|(_, v)| v.clone())
+                .unwrap_or_default()
+        };
+        let request = Request {
+            method: var("GATEWAY_METHOD"),
+            path: var("GATEWAY_PATH"),
+            metadata: envelope.vars.clone(),
+            body: input.to_vec(),
+        };
+        let mut store = Store::new(
+            &self.engine,
+            HostState {
+                module: Arc::clone(&self.module),
+                req_id: var("GATEWAY_REQ_ID"),
+                kv: Arc::clone(&self.kv),
+            },
+        );
+        let instance = self
+            .pre
+            .instantiate(&mut store)
+            .context("failed to instantiate component")?;
+        let Response { status, body } = instance
+            .call_handle(&mut store, &request)
+            .context("component handle call failed")?;
+        if !(200..300).contains(&status) {
+            return Err(anyhow!("component answered with status {status}"));
+        }
+        Ok(body)
+    }
+}
@@ -0,0 +1,302 @@
+//! Routes discovered from the gateway's own compose project
+//! (`DISCOVERY=compose`), so compose demos need no upstream configuration.
+//!
+//! At startup the Docker API (`DOCKER_HOST`, default
+//! `unix:///var/run/docker.sock`, which can be mounted read-only) lists the
+//! running containers labelled with the project: `COMPOSE_PROJECT_NAME`, else
+//! the `com.docker.compose.project` label of the gateway's own container.
+//! Each other service gets a route: `/<service>/…` goes to
+//! `http://<service>:<port>/…` with the prefix stripped. The port is the
+//! service's `gateway.port` label, else the lowest TCP port it exposes;
+//! services exposing none are skipped.
+
+use anyhow::{anyhow, Context, Result};
+use std::collections::BTreeMap;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::path::PathBuf;
+
+use crate::{chunked, headers::Headers, parse_upstream, Upstream, IO_TIMEOUT};
+
+const PROJECT_LABEL: &str = "com.docker.compose.project";
+const SERVICE_LABEL: &str = "com.docker.compose.service";
+const PORT_LABEL: &str = "gateway.port";
+
+/// `/<service>` and where it goes.
+#[derive(Debug)]
+pub(crate) struct Route {
+    pub(crate) prefix: String,
+    pub(crate) upstream: Upstream,
+}
+
+impl Route {
+    /// The request target with this route's prefix stripped, if it matches.
+    pub(crate) fn strip(&self, target: &str) -> Option<String> {
+        let rest = target.strip_prefix(self.prefix.as_str())?;
+        match rest.as_bytes().first() {
+            None => Some("/".to_string()),
+            Some(b'/') => Some(rest.to_string()),
+            Some(b'?') => Some(format!("/{rest}")),
+            Some(_) => None,
+        }
+    }
+}
+
+enum Endpoint {
+    Unix(PathBuf),
+    Tcp(String),
+}
+
+struct Docker(Endpoint);
+
+impl Docker {
+    fn from_env() -> Result<Self> {
+        let host = std::env::var("DOCKER_HOST")
+            .ok()
+            .filter(|v| !v.is_empty())
+            .unwrap_or_else(|| "unix:///var/run/docker.sock".to_string());
+        if let Some(path) = host.strip_prefix("unix://") {
+            Ok(Docker(Endpoint::Unix(PathBuf::from(path))))
+        } else if let Some(addr) = host.strip_prefix("tcp://") {
+            Ok(Docker(Endpoint::Tcp(
+                addr.trim_end_matches('/').to_string(),
+            )))
+        } else {
+            Err(anyhow!(
+                "invalid DOCKER_HOST={host} (expected unix:// or tcp://)"
+            ))
+        }
+    }
+
+    /// `GET path` against the Docker API, parsed as JSON.
+    fn get(&self, path: &str) -> Result<serde_json::Value> {
+        let request = format!("GET {path} HTTP/1.0\r\nHost: docker\r\n\r\n");
+        let mut response = Vec::new();
+        match &self.0 {
+            #[cfg(unix)]
+            Endpoint::Unix(socket) => {
+                let mut stream = std::os::unix::net::UnixStream::connect(socket)
+                    .with_context(|| format!("connect Docker API at {}", socket.display()))?;
+                stream.set_read_timeout(Some(IO_TIMEOUT)).ok();
+                stream.write_all(request.as_bytes())?;
+                stream.read_to_end(&mut response)?;
+            }
+            #[cfg(not(unix))]
+            Endpoint::Unix(_) => return Err(anyhow!("unix:// DOCKER_HOST needs a unix host")),
+            Endpoint::Tcp(addr) => {
+                let mut stream = TcpStream::connect(addr.as_str())
+                    .with_context(|| format!("connect Docker API at {addr}"))?;
+                stream.set_read_timeout(Some(IO_TIMEOUT)).ok();
+                stream.write_all(request.as_bytes())?;
+                stream.read_to_end(&mut response)?;
+            }
+        }
+        let (head, body) = crate::split_http_response(&response)?;
+        let status = crate::parse_status_code_from_head(&head)?;
+        let (_, headers) = Headers::parse_head(&head)?;
+        let (body, _) = chunked::decode_response(&headers, body, false)?;
+        if status != 200 {
+            return Err(anyhow!(
+                "Docker API {path}: {status} {}",
+                String::from_utf8_lossy(&body).trim()
+            ));
+        }
+        serde_json::from_slice(&body).with_context(|| format!("Docker API {path}: invalid JSON"))
+    }
+}
+
+/// Reads `COMPOSE_PROJECT_NAME` / `DOCKER_HOST` and lists the project's
+/// services. Call once from `main` with `DISCOVERY=compose`.
+pub(crate) fn discover() -> Result<Vec<Route>> {
+    let project = std::env::var("COMPOSE_PROJECT_NAME")
+        .ok()
+        .filter(|v| !v.is_empty());
+    // Docker sets a container's hostname to its short id.
+    let own_id = std::fs::read_to_string("/etc/hostname")
+        .ok()
+        .map(|h| h.trim().to_string())
+        .filter(|h| !h.is_empty());
+    let routes = discover_with(&Docker::from_env()?, project, own_id.as_deref())
+        .context("DISCOVERY=compose")?;
+    for route in &routes {
+        eprintln!(
+            "[wasm-host] compose route {} -> {}",
+            route.prefix, route.upstream.raw_url
+        );
+    }
+    Ok(routes)
+}
+
+fn discover_with(
+    docker: &Docker,
+    project: Option<String>,
+    own_id: Option<&str>,
+) -> Result<Vec<Route>> {
+    let own = match own_id {
+        Some(id) => docker.get(&format!("/containers/{id}/json")).ok(),
+        None => None,
+    };
+    let own_label = |name: &str| {
+        own.as_ref()
+            .and_then(|c| c["Config"]["Labels"][name].as_str())
+            .map(str::to_string)
+    };
+    let project = project
+        .or_else(|| own_label(PROJECT_LABEL))
+        .ok_or_else(|| anyhow!("not running in a compose project; set COMPOSE_PROJECT_NAME"))?;
+    let own_service = own_label(SERVICE_LABEL);
+
+    let filters = serde_json::json!({ "label": [format!("{PROJECT_LABEL}={project}")] });
+    let query: String =
+        url::form_urlencoded::byte_serialize(filters.to_string().as_bytes()).collect();
+    let containers = docker.get(&format!("/containers/json?filters={query}"))?;
+    let containers = containers
+        .as_array()
+        .ok_or_else(|| anyhow!("Docker API: expected a container list"))?;
+
+    // Ports per service, across its replicas.
+    let mut services: BTreeMap<String, Option<u16>> = BTreeMap::new();
+    for container in containers {
+        let labels = &container["Labels"];
+        let Some(service) = labels[SERVICE_LABEL].as_str() else {
+            continue;
+        };
+        if own_service.as_deref() == Some(service) {
+            continue;
+        }
+        let port = match labels[PORT_LABEL].as_str() {
+            Some(v) => Some(v.trim().parse::<u16>().with_context(|| {
+                format!("invalid {PORT_LABEL}={v} on compose service {service}")
+            })?),
+            None => container["Ports"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter(|p| p["Type"].as_str().is_none_or(|t| t == "tcp"))
+                .filter_map(|p| p["PrivatePort"].as_u64())
+                .filter_map(|p| u16::try_from(p).ok())
+                .min(),
+        };
+        let entry = services.entry(service.to_string()).or_default();
+        *entry = match (*entry, port) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+    }
+
+    let mut routes = Vec::new();
+    for (service, port) in services {
+        let Some(port) = port else {
+            eprintln!("[wasm-host] compose service {service} exposes no port; not routed");
+            continue;
+        };
+        let upstream = parse_upstream(&format!("http://{service}:{port}"))
+            .with_context(|| format!("compose service {service}"))?;
+        routes.push(Route {
+            prefix: format!("/{service}"),
+            upstream,
+        });
+    }
+    if routes.is_empty() {
+        return Err(anyhow!(
+            "compose project {project} has no other service to route to"
+        ));
+    }
+    Ok(routes)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader};
+    use std::os::unix::net::UnixListener;
+
+    /// Answers Docker API requests on a unix socket from `(path prefix, JSON)`
+    /// pairs; anything else gets a 404.
+    fn fake_docker(name: &str, answers: Vec<(&'static str, serde_json::Value)>) -> Docker {
+        let path = std::env::temp_dir().join(format!(
+            "gateway-compose-{name}-{}.sock",
+            std::process::id()
+        ));
+        std::fs::remove_file(&path).ok();
+        let listener = UnixListener::bind(&path).unwrap();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut line = String::new();
+                BufReader::new(&stream).read_line(&mut line).unwrap();
+                let target = line.split(' ').nth(1).unwrap_or_default();
+                let response = match answers.iter().find(|(p, _)| target.starts_with(p)) {
+                    Some((_, json)) => {
+                        let body = json.to_string();
+                        format!(
+                            "HTTP/1.0 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{body}",
+                            body.len()
+                        )
+                    }
+                    None => "HTTP/1.0 404 Not Found\r\nContent-Length: 2\r\n\r\n{}".to_string(),
+                };
+                stream.write_all(response.as_bytes()).ok();
+            }
+        });
+        Docker(Endpoint::Unix(path))
+    }
+
+    fn container(service: &str, ports: &[u16], labels: &[(&str, &str)]) -> serde_json::Value {
+        let mut all = serde_json::json!({
+            PROJECT_LABEL: "demo",
+            SERVICE_LABEL: service,
+        });
+        for (k, v) in labels {
+            all[*k] = (*v).into();
+        }
+        serde_json::json!({
+            "Labels": all,
+            "Ports": ports.iter().map(|p| serde_json::json!({"PrivatePort": p, "Type": "tcp"})).collect::<Vec<_>>(),
+        })
+    }
+
+    #[test]
+    fn routes_other_services_of_own_project() {
+        let docker = fake_docker(
+            "routes",
+            vec![
+                (
+                    "/containers/abc123/json",
+                    serde_json::json!({"Config": {"Labels": {PROJECT_LABEL: "demo", SERVICE_LABEL: "gateway"}}}),
+                ),
+                (
+                    "/containers/json?filters=",
+                    serde_json::json!([
+                        container("gateway", &[8080], &[]),
+                        container("api", &[9000, 8080], &[]),
+                        container("api", &[8080], &[]),
+                        container("web", &[80, 443], &[(PORT_LABEL, "3000")]),
+                        container("worker", &[], &[]),
+                    ]),
+                ),
+            ],
+        );
+        let routes = discover_with(&docker, None, Some("abc123")).unwrap();
+        let found: Vec<(&str, &str)> = routes
+            .iter()
+            .map(|r| (r.prefix.as_str(), r.upstream.raw_url.as_str()))
+            .collect();
+        assert_eq!(
+            found,
+            [("/api", "http://api:8080"), ("/web", "http://web:3000")]
+        );
+
+        let api = &routes[0];
+        assert_eq!(
+            api.strip("/api/v1/users?id=2").as_deref(),
+            Some("/v1/users?id=2")
+        );
+        assert_eq!(api.strip("/api").as_deref(), Some("/"));
+        assert_eq!(api.strip("/api?id=2").as_deref(), Some("/?id=2"));
+        assert_eq!(api.strip("/apix"), None);
+
+        let err = discover_with(&docker, None, Some("elsewhere")).unwrap_err();
+        assert!(err.to_string().contains("COMPOSE_PROJECT_NAME"), "{err:#}");
+    }
+}
@@ -0,0 +1,188 @@
+//! End-to-end request deadlines (`REQUEST_TIMEOUT_MS`).
+//!
+//! Every socket read and write has its own `IO_TIMEOUT` and the wasm runtime
+//! its own `WASM_TIMEOUT_MS`, so a request that is slow at each step can still
+//! take many times either. `REQUEST_TIMEOUT_MS` bounds the whole request
+//! instead, from the first byte read to the last byte written: the client,
+//! upstream and wasm timeouts are clamped to what is left, the request is
+//! checked between steps, and one that runs out is answered with a 504
+//! (`X-Gateway-Error-Code: deadline`) whichever step it was in.
+//!
+//! With `REQUEST_TIMEOUT_HEADER=1` a client may ask for its own budget in
+//! `X-Request-Timeout-Ms`, capped at `REQUEST_TIMEOUT_MS` when that is set;
+//! values that are not a positive number of milliseconds are ignored.
+
+use std::io;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use once_cell::sync::OnceCell;
+
+use crate::errors::GatewayError;
+
+pub(crate) const HEADER: &str = "X-Request-Timeout-Ms";
+
+static BUDGET: OnceCell<Budget> = OnceCell::new();
+
+#[derive(Debug)]
+pub(crate) struct Budget {
+    /// `REQUEST_TIMEOUT_MS`, for every request.
+    timeout: Option<Duration>,
+    /// `REQUEST_TIMEOUT_HEADER=1`.
+    header: bool,
+}
+
+/// Reads `REQUEST_TIMEOUT_MS` and `REQUEST_TIMEOUT_HEADER`. Call once from
+/// `main`.
+pub(crate) fn init_from_env() -> Result<()> {
+    let timeout = match std::env::var("REQUEST_TIMEOUT_MS") {
+        Ok(v) if !v.trim().is_empty() => match v.trim().parse::<u64>() {
+            Ok(0) => None,
+            Ok(ms) => Some(Duration::from_millis(ms)),
+            Err(e) => return Err(e).with_context(|| format!("invalid REQUEST_TIMEOUT_MS={v}")),
+        },
+        _ => None,
+    };
+    let header = match std::env::var("REQUEST_TIMEOUT_HEADER") {
+        Ok(v) if !v.trim().is_empty() => match v.trim() {
+            "1" => true,
+            "0" => false,
+            _ => return Err(anyhow::anyhow!("invalid REQUEST_TIMEOUT_HEADER={v}")),
+        },
+        _ => false,
+    };
+    if timeout.is_none() && !header {
+        return Ok(());
+    }
+    BUDGET.get_or_init(|| Budget { timeout, header });
+    eprintln!(
+        "[wasm-host] request deadline: {}{}",
+        timeout.map_or("none".to_string(), |t| format!("{t:?}")),
+        if header {
+            format!(", clients may set {HEADER}")
+        } else {
+            String::new()
+        }
+    );
+    Ok(())
+}
+
+pub(crate) fn configured() -> Option<&'static Budget> {
+    BUDGET.get()
+}
+
+impl Budget {
+    /// The deadline of a request handled since `start`; `header` is its
+    /// `X-Request-Timeout-Ms`, or `None` before the head is read.
+    pub(crate) fn deadline(&self, start: Instant, header: Option<&str>) -> Option<Instant> {
+        let asked = header
+            .filter(|_| self.header)
+            .and_then(|v| v.trim().parse::<u64>().ok())
+            .filter(|&ms| ms > 0)
+            .map(Duration::from_millis);
+        let budget = match (self.timeout, asked) {
+            (Some(timeout), Some(asked)) => Some(timeout.min(asked)),
+            (timeout, asked) => timeout.or(asked),
+        };
+        budget.map(|budget| start + budget)
+    }
+}
+
+/// Whether `deadline` has passed.
+pub(crate) fn expired(deadline: Option<Instant>) -> bool {
+    deadline.is_some_and(|d| Instant::now() >= d)
+}
+
+/// Fails with `GatewayError::Deadline` once `deadline` has passed.
+pub(crate) fn check(deadline: Option<Instant>) -> Result<()> {
+    if expired(deadline) {
+        return Err(anyhow::Error::new(GatewayError::Deadline));
+    }
+    Ok(())
+}
+
+/// `timeout`, or the time left before `deadline` if that is shorter. Never
+/// zero, which socket timeouts reject.
+pub(crate) fn clamp(timeout: Duration, deadline: Option<Instant>) -> Duration {
+    match deadline {
+        Some(d) => timeout
+            .min(d.saturating_duration_since(Instant::now()))
+            .max(Duration::from_millis(1)),
+        None => timeout,
+    }
+}
+
+/// Like `clamp`, for a timeout that may be unset.
+pub(crate) fn clamp_opt(timeout: Option<Duration>, deadline: Option<Instant>) -> Option<Duration> {
+    match (timeout, deadline) {
+        (Some(timeout), _) => Some(clamp(timeout, deadline)),
+        (None, Some(_)) => Some(clamp(Duration::MAX, deadline)),
+        (None, None) => None,
+    }
+}
+
+/// `TcpStream::connect`, giving up on each address when `deadline` passes.
+pub(crate) fn connect(host: &str, port: u16, deadline: Option<Instant>) -> io::Result<TcpStream> {
+    if deadline.is_none() {
+        return TcpStream::connect((host, port));
+    }
+    let mut last = io::Error::new(io::ErrorKind::NotFound, "no addresses");
+    for addr in (host, port).to_socket_addrs()? {
+        if expired(deadline) {
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+        match TcpStream::connect_timeout(&addr, clamp(Duration::MAX, deadline)) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last = e,
+        }
+    }
+    Err(last)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn header_shortens_the_configured_budget() {
+        let start = Instant::now();
+        let budget = Budget {
+            timeout: Some(Duration::from_millis(500)),
+            header: true,
+        };
+        let ms = |d: Option<Instant>| d.map(|d| (d - start).as_millis());
+        assert_eq!(ms(budget.deadline(start, None)), Some(500));
+        assert_eq!(ms(budget.deadline(start, Some("200"))), Some(200));
+        assert_eq!(ms(budget.deadline(start, Some("9000"))), Some(500));
+        assert_eq!(ms(budget.deadline(start, Some("0"))), Some(500));
+        assert_eq!(ms(budget.deadline(start, Some("soon"))), Some(500));
+
+        let header_only = Budget {
+            timeout: None,
+            header: true,
+        };
+        assert_eq!(header_only.deadline(start, None), None);
+        assert_eq!(ms(header_only.deadline(start, Some(" 75 "))), Some(75));
+        let ignored = Budget {
+            timeout: None,
+            header: false,
+        };
+        assert_eq!(ignored.deadline(start, Some("75")), None);
+
+        let past = Some(start);
+        assert!(expired(past));
+        assert_eq!(
+            GatewayError::classify(&check(past).unwrap_err(), true),
+            GatewayError::Deadline
+        );
+        assert_eq!(
+            clamp(Duration::from_secs(5), past),
+            Duration::from_millis(1)
+        );
+        assert_eq!(clamp_opt(None, None), None);
+        let later = Some(Instant::now() + Duration::from_secs(60));
+        assert_eq!(clamp(Duration::from_secs(5), later), Duration::from_secs(5));
+        assert!(clamp_opt(None, later).unwrap() <= Duration::from_secs(60));
+    }
+}
@@ -0,0 +1,108 @@
+//! Per-request debug overrides (`DEBUG_HEADERS=true`).
+//!
+//! With `DEBUG_HEADERS` set, a request may carry `X-Gateway-Debug` with a
+//! comma-separated list of:
+//!
+//! - `no-cache`: skip the response cache, both lookup and store, and do not
+//!   share an upstream request with coalesced ones;
+//! - `no-wasm`: serve bodies untransformed, with `X-Transform-Skipped: true`;
+//! - `trace`: log each stage of the request to the error log, whatever
+//!   `ACCESS_LOG` says.
+//!
+//! The header is trusted only from callers that pass the admin check, as for
+//! `/debug/*` (without `ADMIN_TOKEN`, `HEALTH_TOKEN` or basic auth that is
+//! every caller); from others it is ignored. It is never forwarded upstream.
+//! The overrides applied are echoed in the response's `X-Gateway-Debug`;
+//! unknown names are ignored.
+
+use once_cell::sync::OnceCell;
+use std::fmt;
+
+pub(crate) const HEADER: &str = "X-Gateway-Debug";
+
+static ENABLED: OnceCell<bool> = OnceCell::new();
+
+pub(crate) fn init_from_env() {
+    let enabled = matches!(
+        std::env::var("DEBUG_HEADERS").as_deref(),
+        Ok("true") | Ok("1")
+    );
+    ENABLED.set(enabled).ok();
+    if enabled {
+        eprintln!("[wasm-host] honoring {HEADER} from admin callers");
+    }
+}
+
+pub(crate) fn enabled() -> bool {
+    ENABLED.get().copied().unwrap_or(false)
+}
+
+/// The overrides one request asked for.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) struct Overrides {
+    pub(crate) no_cache: bool,
+    pub(crate) no_wasm: bool,
+    pub(crate) trace: bool,
+}
+
+impl Overrides {
+    pub(crate) fn parse(value: &str) -> Overrides {
+        let mut overrides = Overrides::default();
+        for name in value.split(',').map(str::trim) {
+            match name.to_ascii_lowercase().as_str() {
+                "no-cache" => overrides.no_cache = true,
+                "no-wasm" => overrides.no_wasm = true,
+                "trace" => overrides.trace = true,
+                _ => {}
+            }
+        }
+        overrides
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        *self == Overrides::default()
+    }
+
+    /// The value echoed in the response, e.g. `no-cache,trace`.
+    pub(crate) fn echo(&self) -> String {
+        [
+            (self.no_cache, "no-cache"),
+            (self.no_wasm, "no-wasm"),
+            (self.trace, "trace"),
+        ]
+        .iter()
+        .filter(|(on, _)| *on)
+        .map(|(_, name)| *name)
+        .collect::<Vec<_>>()
+        .join(",")
+    }
+
+    /// Logs one stage of a traced request.
+    pub(crate) fn log(&self, req_id: impl fmt::Display, stage: &str, detail: fmt::Arguments) {
+        if self.trace {
+            eprintln!("[wasm-host] trace req_id={req_id} {stage}: {detail}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_and_echoes_overrides() {
+        let overrides = Overrides::parse(" No-Cache , trace,bogus");
+        assert_eq!(
+            overrides,
+            Overrides {
+                no_cache: true,
+                no_wasm: false,
+                trace: true,
+            }
+        );
+        assert_eq!(overrides.echo(), "no-cache,trace");
+        assert!(Overrides::parse("").is_empty());
+        assert!(Overrides::parse("verbose").is_empty());
+        assert_eq!(Overrides::parse("no-wasm").echo(), "no-wasm");
+    }
+}
@@ -0,0 +1,448 @@
+//! Disk tier of the response cache (`CACHE_DIR`), so it can hold more than
+//! fits in memory.
+//!
+//! Every response stored in memory (failures excepted) is also written to
+//! `CACHE_DIR/<first 2 hex digits>/<rest of the SHA-256 of its key>` by a
+//! background writer, so requests never wait for the disk on the way in. A
+//! memory miss looks the key up here, and a hit is read back, checked
+//! against the full key and promoted to memory. Writes are dropped when the
+//! writer is more than `WRITE_QUEUE` behind.
+//!
+//! Files hold one header line (`wdgcache1 <stored unix ms> <fresh ms> <stale
+//! ms> <key length>`), the key and the raw response. They are indexed at
+//! startup, so the tier survives restarts. Past `CACHE_DISK_MAX_MB` (default
+//! 1024) the writer deletes expired files first, then the oldest.
+
+use anyhow::{anyhow, Context, Result};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const MAGIC: &str = "wdgcache1";
+const DEFAULT_MAX_MB: u64 = 1024;
+/// Writes waiting for the writer thread.
+const WRITE_QUEUE: usize = 256;
+
+#[derive(Debug)]
+pub(crate) struct DiskTier {
+    dir: PathBuf,
+    writes: SyncSender<Job>,
+    shared: Arc<Shared>,
+}
+
+/// State shared with the writer thread.
+#[derive(Debug)]
+struct Shared {
+    max_bytes: u64,
+    index: Mutex<Index>,
+    hits: AtomicU64,
+    writes: AtomicU64,
+    write_errors: AtomicU64,
+    dropped_writes: AtomicU64,
+    evictions: AtomicU64,
+}
+
+#[derive(Debug, Default)]
+struct Index {
+    files: HashMap<String, FileMeta>,
+    bytes: u64,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct FileMeta {
+    bytes: u64,
+    stored: SystemTime,
+    expires: SystemTime,
+}
+
+#[derive(Debug)]
+enum Job {
+    Write {
+        name: String,
+        contents: Vec<u8>,
+        meta: FileMeta,
+    },
+    Purge,
+}
+
+/// A response read back from disk.
+#[derive(Debug, PartialEq)]
+pub(crate) struct Stored {
+    pub(crate) resp: Vec<u8>,
+    pub(crate) stored: SystemTime,
+    pub(crate) fresh_for: Duration,
+    pub(crate) stale_for: Duration,
+}
+
+impl DiskTier {
+    /// Reads `CACHE_DIR` and `CACHE_DISK_MAX_MB`.
+    pub(crate) fn from_env() -> Result<Option<Self>> {
+        let dir = match std::env::var("CACHE_DIR") {
+            Ok(dir) if !dir.trim().is_empty() => PathBuf::from(dir.trim()),
+            _ => return Ok(None),
+        };
+        let max_mb = match std::env::var("CACHE_DISK_MAX_MB") {
+            Ok(v) if !v.trim().is_empty() => v
+                .trim()
+                .parse::<u64>()
+                .ok()
+                .filter(|&n| n > 0)
+                .ok_or_else(|| anyhow!("invalid CACHE_DISK_MAX_MB={v}"))?,
+            _ => DEFAULT_MAX_MB,
+        };
+        let tier = Self::open(dir, max_mb * 1024 * 1024)?;
+        let index = tier.shared.index.lock().unwrap_or_else(|e| e.into_inner());
+        eprintln!(
+            "[wasm-host] response cache disk tier: {} ({} file(s), {} of {} MiB)",
+            tier.dir.display(),
+            index.files.len(),
+            index.bytes / (1024 * 1024),
+            max_mb
+        );
+        drop(index);
+        Ok(Some(tier))
+    }
+
+    fn open(dir: PathBuf, max_bytes: u64) -> Result<Self> {
+        std::fs::create_dir_all(&dir)
+            .with_context(|| format!("failed to create CACHE_DIR {}", dir.display()))?;
+        let index = scan(&dir)?;
+        let shared = Arc::new(Shared {
+            max_bytes,
+            index: Mutex::new(index),
+            hits: AtomicU64::new(0),
+            writes: AtomicU64::new(0),
+            write_errors: AtomicU64::new(0),
+            dropped_writes: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
+        });
+        let (writes, jobs) = mpsc::sync_channel(WRITE_QUEUE);
+        let writer = Writer {
+            dir: dir.clone(),
+            shared: Arc::clone(&shared),
+        };
+        std::thread::Builder::new()
+            .name("cache-writer".to_string())
+            .spawn(move || writer.run(jobs))
+            .context("failed to start the cache writer")?;
+        Ok(DiskTier {
+            dir,
+            writes,
+            shared,
+        })
+    }
+
+    /// Queues `resp` to be written under `key`; dropped if the writer is
+    /// behind.
+    pub(crate) fn write_behind(
+        &self,
+        key: &[u8],
+        resp: &[u8],
+        stored: SystemTime,
+        fresh_for: Duration,
+        stale_for: Duration,
+    ) {
+        let stored_ms = stored
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        let mut contents = format!(
+            "{MAGIC} {stored_ms} {} {} {}\n",
+            fresh_for.as_millis(),
+            stale_for.as_millis(),
+            key.len()
+        )
+        .into_bytes();
+        contents.extend_from_slice(key);
+        contents.extend_from_slice(resp);
+        let meta = FileMeta {
+            bytes: contents.len() as u64,
+            stored,
+            expires: stored + fresh_for + stale_for,
+        };
+        let job = Job::Write {
+            name: file_name(key),
+            contents,
+            meta,
+        };
+        if let Err(TrySendError::Full(_)) = self.writes.try_send(job) {
+            self.shared.dropped_writes.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// The response stored under `key`, if there is one that has not expired.
+    pub(crate) fn load(&self, key: &[u8]) -> Option<Stored> {
+        let name = file_name(key);
+        let meta = self
+            .shared
+            .index
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .files
+            .get(&name)
+            .copied()?;
+        if meta.expires <= SystemTime::now() {
+            return None;
+        }
+        let contents = std::fs::read(self.dir.join(&name[..2]).join(&name[2..])).ok()?;
+        let (header, stored_key, resp) = parse(&contents)?;
+        if stored_key != key {
+            return None;
+        }
+        self.shared.hits.fetch_add(1, Ordering::Relaxed);
+        Some(Stored {
+            resp: resp.to_vec(),
+            ..header
+        })
+    }
+
+    /// Queues removing every file.
+    pub(crate) fn purge(&self) {
+        self.writes.send(Job::Purge).ok();
+    }
+
+    pub(crate) fn json(&self) -> serde_json::Value {
+        let index = self.shared.index.lock().unwrap_or_else(|e| e.into_inner());
+        let s = &self.shared;
+        serde_json::json!({
+            "files": index.files.len(),
+            "bytes": index.bytes,
+            "max_bytes": s.max_bytes,
+            "hits": s.hits.load(Ordering::Relaxed),
+            "writes": s.writes.load(Ordering::Relaxed),
+            "write_errors": s.write_errors.load(Ordering::Relaxed),
+            "dropped_writes": s.dropped_writes.load(Ordering::Relaxed),
+            "evictions": s.evictions.load(Ordering::Relaxed),
+        })
+    }
+
+    pub(crate) fn bytes(&self) -> u64 {
+        self.shared
+            .index
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .bytes
+    }
+}
+
+struct Writer {
+    dir: PathBuf,
+    shared: Arc<Shared>,
+}
+
+impl Writer {
+    fn run(self, jobs: Receiver<Job>) {
+        for job in jobs {
+            match job {
+                Job::Write {
+                    name,
+                    contents,
+                    meta,
+                } => match self.write(&name, &contents) {
+                    Ok(()) => {
+                        self.shared.writes.fetch_add(1, Ordering::Relaxed);
+                        let mut index = self.shared.index.lock().unwrap_or_else(|e| e.into_inner());
+                        if let Some(old) = index.files.insert(name, meta) {
+                            index.bytes -= old.bytes;
+                        }
+                        index.bytes += meta.bytes;
+                        self.evict(&mut index);
+                    }
+                    Err(e) => {
+                        self.shared.write_errors.fetch_add(1, Ordering::Relaxed);
+                        eprintln!("[wasm-host] cache write failed: {e:#}");
+                    }
+                },
+                Job::Purge => {
+                    let mut index = self.shared.index.lock().unwrap_or_else(|e| e.into_inner());
+                    for name in index.files.keys() {
+                        std::fs::remove_file(self.path(name)).ok();
+                    }
+                    *index = Index::default();
+                }
+            }
+        }
+    }
+
+    /// Written to a temporary file first, so readers never see half of one.
+    fn write(&self, name: &str, contents: &[u8]) -> Result<()> {
+        let path = self.path(name);
+        let parent = path.parent().unwrap_or(&self.dir);
+        std::fs::create_dir_all(parent)
+            .with_context(|| format!("failed to create {}", parent.display()))?;
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, contents)
+            .with_context(|| format!("failed to write {}", tmp.display()))?;
+        std::fs::rename(&tmp, &path)
+            .with_context(|| format!("failed to rename to {}", path.display()))
+    }
+
+    fn evict(&self, index: &mut Index) {
+        if index.bytes <= self.shared.max_bytes {
+            return;
+        }
+        let now = SystemTime::now();
+        let mut by_age: Vec<(String, FileMeta)> =
+            index.files.iter().map(|(n, m)| (n.clone(), *m)).collect();
+        // Expired first, then oldest first.
+        by_age.sort_by_key(|(_, meta)| (meta.expires > now, meta.stored));
+        for (name, meta) in by_age {
+            if index.bytes <= self.shared.max_bytes {
+                break;
+            }
+            std::fs::remove_file(self.path(&name)).ok();
+            index.files.remove(&name);
+            index.bytes -= meta.bytes;
+            self.shared.evictions.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn path(&self, name: &str) -> PathBuf {
+        self.dir.join(&name[..2]).join(&name[2..])
+    }
+}
+
+fn file_name(key: &[u8]) -> String {
+    hex::encode(Sha256::digest(key))
+}
+
+/// Header fields, key and response of a cache file.
+fn parse(contents: &[u8]) -> Option<(Stored, &[u8], &[u8])> {
+    let line_end = contents.iter().position(|&b| b == b'\n')?;
+    let header = parse_header(std::str::from_utf8(&contents[..line_end]).ok()?)?;
+    let (stored, key_len) = header;
+    let rest = &contents[line_end + 1..];
+    (rest.len() >= key_len).then(|| (stored, &rest[..key_len], &rest[key_len..]))
+}
+
+fn parse_header(line: &str) -> Option<(Stored, usize)> {
+    let mut fields = line.split(' ');
+    if fields.next() != Some(MAGIC) {
+        return None;
+    }
+    let mut number = || fields.next()?.parse::<u64>().ok();
+    let stored = UNIX_EPOCH + Duration::from_millis(number()?);
+    let fresh_for = Duration::from_millis(number()?);
+    let stale_for = Duration::from_millis(number()?);
+    let key_len = usize::try_from(number()?).ok()?;
+    Some((
+        Stored {
+            resp: Vec::new(),
+            stored,
+            fresh_for,
+            stale_for,
+        },
+        key_len,
+    ))
+}
+
+/// Indexes the files already in `dir`; unreadable ones and leftover
+/// temporary files are removed.
+fn scan(dir: &Path) -> Result<Index> {
+    let mut index = Index::default();
+    for shard in
+        std::fs::read_dir(dir).with_context(|| format!("failed to read {}", dir.display()))?
+    {
+        let shard = shard?;
+        let prefix = shard.file_name().to_string_lossy().into_owned();
+        if prefix.len() != 2 || !shard.file_type()?.is_dir() {
+            continue;
+        }
+        for file in std::fs::read_dir(shard.path())? {
+            let file = file?;
+            let path = file.path();
+            let name = format!("{prefix}{}", file.file_name().to_string_lossy());
+            let meta = (name.len() == 64 && path.extension().is_none())
+                .then(|| read_meta(&path))
+                .flatten();
+            match meta {
+                Some(meta) => {
+                    index.bytes += meta.bytes;
+                    index.files.insert(name, meta);
+                }
+                None => {
+                    std::fs::remove_file(&path).ok();
+                }
+            }
+        }
+    }
+    Ok(index)
+}
+
+fn read_meta(path: &Path) -> Option<FileMeta> {
+    let mut file = std::fs::File::open(path).ok()?;
+    let bytes = file.metadata().ok()?.len();
+    let mut head = [0u8; 128];
+    let n = file.read(&mut head).ok()?;
+    let line_end = head[..n].iter().position(|&b| b == b'\n')?;
+    let (stored, _) = parse_header(std::str::from_utf8(&head[..line_end]).ok()?)?;
+    Some(FileMeta {
+        bytes,
+        stored: stored.stored,
+        expires: stored.stored + stored.fresh_for + stored.stale_for,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writes_behind_reads_back_and_evicts() {
+        let dir = std::env::temp_dir().join(format!("disk-cache-test-{}", std::process::id()));
+        std::fs::remove_dir_all(&dir).ok();
+        let tier = DiskTier::open(dir.clone(), 300).unwrap();
+        let wait_for = |tier: &DiskTier, files: usize| {
+            for _ in 0..200 {
+                if tier.json()["files"] == files {
+                    return;
+                }
+                std::thread::sleep(Duration::from_millis(5));
+            }
+            panic!("expected {files} file(s): {}", tier.json());
+        };
+        let now = SystemTime::now();
+        let minute = Duration::from_secs(60);
+        tier.write_behind(b"GET /a", &[b'a'; 100], now, minute, Duration::ZERO);
+        wait_for(&tier, 1);
+        let stored = tier.load(b"GET /a").unwrap();
+        assert_eq!(stored.resp, [b'a'; 100]);
+        assert_eq!(stored.fresh_for, minute);
+        assert!(tier.load(b"GET /b").is_none());
+
+        // Indexed again after a restart.
+        drop(tier);
+        let tier = DiskTier::open(dir.clone(), 300).unwrap();
+        assert_eq!(tier.json()["files"], 1);
+        assert!(tier.load(b"GET /a").is_some());
+
+        // A third file exceeds 300 bytes: the oldest goes.
+        tier.write_behind(
+            b"GET /b",
+            &[b'b'; 100],
+            now + minute,
+            minute,
+            Duration::ZERO,
+        );
+        tier.write_behind(
+            b"GET /c",
+            &[b'c'; 100],
+            now + 2 * minute,
+            minute,
+            Duration::ZERO,
+        );
+        wait_for(&tier, 2);
+        assert!(tier.load(b"GET /a").is_none());
+        assert!(tier.load(b"GET /c").is_some());
+        assert_eq!(tier.json()["evictions"], 1);
+
+        tier.purge();
+        wait_for(&tier, 0);
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
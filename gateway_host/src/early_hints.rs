@@ -0,0 +1,121 @@
+//! `103 Early Hints` (RFC 8297) for configured routes.
+//!
+//! `EARLY_HINTS=/prefix=<link>|<link>,...` lists `Link` values per route
+//! prefix, e.g. `/app=</app.css>; rel=preload; as=style|</app.js>;
+//! rel=preload; as=script`. A request to a matching route (longest prefix
+//! wins) gets a `103` with one `Link` field per value as soon as its head is
+//! accepted, before the upstream or the transform has answered, so a browser
+//! can start fetching while the gateway works. HTTP/1.0 clients, which cannot
+//! read interim responses, get none.
+
+use anyhow::{anyhow, Result};
+use std::io::Write;
+
+use crate::route_path;
+
+#[derive(Debug)]
+pub(crate) struct EarlyHints {
+    /// Longest prefix first.
+    routes: Vec<(String, Vec<String>)>,
+}
+
+impl EarlyHints {
+    pub(crate) fn from_env() -> Result<Option<Self>> {
+        match std::env::var("EARLY_HINTS") {
+            Ok(spec) if !spec.trim().is_empty() => {
+                let hints = Self::parse(&spec)?;
+                eprintln!("[wasm-host] early hints: {} route(s)", hints.routes.len());
+                Ok(Some(hints))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn parse(spec: &str) -> Result<Self> {
+        let mut routes = spec
+            .split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(|entry| {
+                let invalid = || {
+                    anyhow!("invalid EARLY_HINTS entry {entry:?} (expected /prefix=<link>|<link>)")
+                };
+                let (prefix, links) = entry.split_once('=').ok_or_else(invalid)?;
+                let prefix = prefix.trim();
+                let links: Vec<String> = links
+                    .split('|')
+                    .map(str::trim)
+                    .filter(|link| !link.is_empty())
+                    .map(str::to_string)
+                    .collect();
+                if !prefix.starts_with('/') || links.is_empty() {
+                    return Err(invalid());
+                }
+                if let Some(link) = links
+                    .iter()
+                    .find(|link| !link.starts_with('<') || !crate::syntax::is_field_value(link))
+                {
+                    return Err(anyhow!("invalid EARLY_HINTS link {link:?}"));
+                }
+                Ok((prefix.to_string(), links))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        routes.sort_by_key(|(prefix, _)| std::cmp::Reverse(prefix.len()));
+        Ok(EarlyHints { routes })
+    }
+
+    /// The `Link` values for `path`, if its route has any.
+    pub(crate) fn links_for(&self, path: &str) -> Option<&[String]> {
+        let path = route_path(path);
+        self.routes
+            .iter()
+            .find(|(prefix, _)| path.starts_with(prefix.as_str()))
+            .map(|(_, links)| links.as_slice())
+    }
+
+    /// Writes the `103` for `path`, if any; the final response follows on the
+    /// same connection.
+    pub(crate) fn send(&self, client: &mut impl Write, path: &str) -> std::io::Result<()> {
+        let Some(links) = self.links_for(path) else {
+            return Ok(());
+        };
+        let mut out = b"HTTP/1.1 103 Early Hints\r\n".to_vec();
+        for link in links {
+            crate::syntax::write_field(&mut out, "Link", link);
+        }
+        out.extend_from_slice(b"\r\n");
+        client.write_all(&out)?;
+        client.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn longest_prefix_gets_its_links() {
+        let hints = EarlyHints::parse(
+            "/app=</app.css>; rel=preload; as=style|</app.js>; rel=preload; as=script, \
+             /app/admin=</admin.css>; rel=preload; as=style",
+        )
+        .unwrap();
+        assert_eq!(
+            hints.links_for("/app/admin/users?x=1").unwrap(),
+            ["</admin.css>; rel=preload; as=style"]
+        );
+        assert_eq!(hints.links_for("/app/home").unwrap().len(), 2);
+        assert!(hints.links_for("/proxy").is_none());
+
+        let mut out = Vec::new();
+        hints.send(&mut out, "/app/admin").unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "HTTP/1.1 103 Early Hints\r\nLink: </admin.css>; rel=preload; as=style\r\n\r\n"
+        );
+
+        assert!(EarlyHints::parse("/app").is_err());
+        assert!(EarlyHints::parse("app=</a.css>").is_err());
+        assert!(EarlyHints::parse("/app=a.css").is_err());
+    }
+}
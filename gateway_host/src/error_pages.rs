@@ -0,0 +1,284 @@
+//! Templated bodies for gateway-originated error responses.
+//!
+//! `ERROR_PAGES_DIR` holds `<status>.html` and `<status>.json` files (e.g.
+//! `401.html`, `403.json`), plus optional `default.html` / `default.json` used
+//! for any status without its own template. Templates may contain
+//! `{{status}}`, `{{reason}}`, `{{req_id}}` and `{{message}}`; values are HTML-
+//! or JSON-escaped to match the file (JSON templates quote string placeholders
+//! themselves: `"message": "{{message}}"`). When both formats exist, requests
+//! whose `Accept` mentions `application/json` get the JSON one. Statuses with no
+//! template keep their plain-text body.
+
+use anyhow::{Context, Result};
+use std::path::Path;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Format {
+    Html,
+    Json,
+}
+
+impl Format {
+    fn content_type(self) -> &'static str {
+        match self {
+            Format::Html => "text/html; charset=utf-8",
+            Format::Json => "application/json",
+        }
+    }
+
+    fn escape(self, value: &str) -> String {
+        match self {
+            Format::Html => value
+                .replace('&', "&amp;")
+                .replace('<', "&lt;")
+                .replace('>', "&gt;")
+                .replace('"', "&quot;")
+                .replace('\'', "&#39;"),
+            Format::Json => {
+                let quoted = serde_json::Value::from(value).to_string();
+                quoted[1..quoted.len() - 1].to_string()
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Page {
+    /// `None` for `default.*`.
+    status: Option<u16>,
+    format: Format,
+    template: String,
+}
+
+#[derive(Debug)]
+pub(crate) struct ErrorPages {
+    pages: Vec<Page>,
+}
+
+/// Placeholder values for one error response.
+pub(crate) struct ErrorDetails<'a> {
+    pub(crate) status: u16,
+    pub(crate) reason: &'a str,
+    pub(crate) req_id: &'a str,
+    pub(crate) message: &'a str,
+}
+
+impl ErrorPages {
+    pub(crate) fn from_env() -> Result<Option<Self>> {
+        let Some(dir) = std::env::var("ERROR_PAGES_DIR")
+            .ok()
+            .filter(|d| !d.is_empty())
+        else {
+            return Ok(None);
+        };
+        let pages = Self::load(Path::new(&dir))
+            .with_context(|| format!("failed to load ERROR_PAGES_DIR={dir}"))?;
+        eprintln!(
+            "[wasm-host] error pages: {} template(s) from {dir}",
+            pages.pages.len()
+        );
+        Ok(Some(pages))
+    }
+
+    fn load(dir: &Path) -> Result<Self> {
+        let mut pages = Vec::new();
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            let (Some(stem), Some(ext)) = (
+                path.file_stem().and_then(|s| s.to_str()),
+                path.extension().and_then(|s| s.to_str()),
+            ) else {
+                continue;
+            };
+            let format = match ext {
+                "html" => Format::Html,
+                "json" => Format::Json,
+                _ => continue,
+            };
+            let status = match stem {
+                "default" => None,
+                code => match code.parse::<u16>() {
+                    Ok(code) if (400..600).contains(&code) => Some(code),
+                    _ => continue,
+                },
+            };
+            let template = std::fs::read_to_string(&path)
+                .with_context(|| format!("failed to read {}", path.display()))?;
+            pages.push(Page {
+                status,
+                format,
+                template,
+            });
+        }
+        Ok(ErrorPages { pages })
+    }
+
+    /// Content type and body for `details`, or `None` when no template covers
+    /// the status.
+    pub(crate) fn render(
+        &self,
+        details: &ErrorDetails<'_>,
+        accept: Option<&str>,
+    ) -> Option<(&'static str, Vec<u8>)> {
+        let preferred = if accept.is_some_and(|a| a.contains("application/json")) {
+            Format::Json
+        } else {
+            Format::Html
+        };
+        let page = [Some(details.status), None]
+            .into_iter()
+            .find_map(|status| {
+                let mut candidates = self.pages.iter().filter(|p| p.status == status);
+                let first = candidates.next()?;
+                Some(
+                    std::iter::once(first)
+                        .chain(candidates)
+                        .find(|p| p.format == preferred)
+                        .unwrap_or(first),
+                )
+            })?;
+        Some((
+            page.format.content_type(),
+            fill(&page.template, page.format, details).into_bytes(),
+        ))
+    }
+}
+
+/// Replaces placeholders in one pass, so `{{...}}` inside a substituted value
+/// is left alone. Unknown placeholders are kept verbatim.
+fn fill(template: &str, format: Format, details: &ErrorDetails<'_>) -> String {
+    let mut out = String::with_capacity(template.len() + details.message.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        out.push_str(&rest[..start]);
+        let tail = &rest[start + 2..];
+        let Some(end) = tail.find("}}") else {
+            rest = &rest[start..];
+            break;
+        };
+        match tail[..end].trim() {
+            "status" => out.push_str(&details.status.to_string()),
+            "reason" => out.push_str(&format.escape(details.reason)),
+            "req_id" => out.push_str(&format.escape(details.req_id)),
+            "message" => out.push_str(&format.escape(details.message)),
+            _ => out.push_str(&rest[start..start + 2 + end + 2]),
+        }
+        rest = &tail[end + 2..];
+    }
+    out.push_str(rest);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DETAILS: ErrorDetails<'static> = ErrorDetails {
+        status: 403,
+        reason: "Forbidden",
+        req_id: "r-1",
+        message: "<b>\"no\"</b> {{status}}",
+    };
+
+    fn pages(name: &str, files: &[(&str, &str)]) -> ErrorPages {
+        let dir = std::env::temp_dir().join(format!("error-pages-{name}-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for (file, body) in files {
+            std::fs::write(dir.join(file), body).unwrap();
+        }
+        let pages = ErrorPages::load(&dir).unwrap();
+        std::fs::remove_dir_all(&dir).ok();
+        pages
+    }
+
+    fn body(rendered: Option<(&'static str, Vec<u8>)>) -> (&'static str, String) {
+        let (content_type, body) = rendered.unwrap();
+        (content_type, String::from_utf8(body).unwrap())
+    }
+
+    #[test]
+    fn fills_placeholders_escaped_for_the_format() {
+        assert_eq!(
+            fill(
+                "<h1>{{status}} {{ reason }}</h1><p>{{message}}</p><!-- {{req_id}} -->",
+                Format::Html,
+                &DETAILS
+            ),
+            "<h1>403 Forbidden</h1><p>&lt;b&gt;&quot;no&quot;&lt;/b&gt; {{status}}</p><!-- r-1 -->"
+        );
+        let json = fill(
+            r#"{"status":{{status}},"error":"{{message}}","id":"{{req_id}}"}"#,
+            Format::Json,
+            &DETAILS,
+        );
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(value["status"], 403);
+        assert_eq!(value["error"], DETAILS.message);
+        assert_eq!(value["id"], "r-1");
+    }
+
+    #[test]
+    fn keeps_unknown_and_unterminated_placeholders() {
+        assert_eq!(
+            fill("{{host}} {{status}} {{", Format::Html, &DETAILS),
+            "{{host}} 403 {{"
+        );
+        assert_eq!(
+            fill("{{status}} {{reason", Format::Html, &DETAILS),
+            "403 {{reason"
+        );
+        assert_eq!(
+            fill("no placeholders", Format::Json, &DETAILS),
+            "no placeholders"
+        );
+    }
+
+    #[test]
+    fn picks_the_status_page_then_default_by_accept() {
+        let pages = pages(
+            "pick",
+            &[
+                ("403.html", "html {{status}}"),
+                ("403.json", r#"{"s":{{status}}}"#),
+                ("default.html", "default {{status}}"),
+                ("600.html", "out of range"),
+                ("notes.txt", "ignored"),
+                ("abc.html", "ignored"),
+            ],
+        );
+        assert_eq!(pages.pages.len(), 3);
+        assert_eq!(
+            body(pages.render(&DETAILS, None)),
+            ("text/html; charset=utf-8", "html 403".to_string())
+        );
+        assert_eq!(
+            body(pages.render(&DETAILS, Some("text/html, application/json;q=0.9"))),
+            ("application/json", r#"{"s":403}"#.to_string())
+        );
+
+        // No 404 page: the default, in its only format whatever `Accept` says.
+        let missing = ErrorDetails {
+            status: 404,
+            ..DETAILS
+        };
+        assert_eq!(
+            body(pages.render(&missing, Some("application/json"))),
+            ("text/html; charset=utf-8", "default 404".to_string())
+        );
+    }
+
+    #[test]
+    fn statuses_without_a_template_keep_their_body() {
+        let pages = pages("none", &[("401.json", "{}")]);
+        assert!(pages.render(&DETAILS, None).is_none());
+        assert!(pages
+            .render(
+                &ErrorDetails {
+                    status: 401,
+                    ..DETAILS
+                },
+                None
+            )
+            .is_some());
+    }
+}
@@ -0,0 +1,52 @@
+//! `gateway_core::errors`, plus the markers only the host leaves: a wasm
+//! timeout (`transform::WasmTimeout`), a full process pool
+//! (`procs::QueueTimeout`), admission shedding (`priority::Shed`), a cached
+//! upstream failure (`cache::CachedFailure`) and a rustls handshake error.
+
+pub(crate) use gateway_core::errors::GatewayError;
+
+use crate::{cache, priority, procs, transform, upstream_tls};
+
+/// The kind of `e`, as `GatewayError::classify` with the host's markers.
+pub(crate) fn classify(e: &anyhow::Error, request_read: bool) -> GatewayError {
+    if e.downcast_ref::<transform::WasmTimeout>().is_some() {
+        GatewayError::WasmTimeout
+    } else if e.downcast_ref::<procs::QueueTimeout>().is_some() {
+        GatewayError::LimitsWasmQueue
+    } else if e.downcast_ref::<priority::Shed>().is_some() {
+        GatewayError::LimitsShed
+    } else if upstream_tls::is_handshake_failure(e) {
+        GatewayError::UpstreamTls
+    } else if e.downcast_ref::<cache::CachedFailure>().is_some() {
+        GatewayError::UpstreamCachedFailure
+    } else {
+        GatewayError::classify(e, request_read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Context;
+    use std::io;
+    use std::time::Duration;
+
+    #[test]
+    fn classifies_host_markers() {
+        let e = anyhow::Error::new(transform::WasmTimeout {
+            timeout: Duration::from_secs(1),
+        })
+        .context("wasmedge killed");
+        assert_eq!(classify(&e, true), GatewayError::WasmTimeout);
+        assert_eq!(GatewayError::WasmTimeout.workload(), "wasm-timeout");
+
+        let refused: anyhow::Result<()> =
+            Err(io::Error::from(io::ErrorKind::ConnectionRefused).into());
+        let e = refused.context(GatewayError::UpstreamConnect).unwrap_err();
+        assert_eq!(classify(&e, true), GatewayError::UpstreamConnect);
+        assert_eq!(
+            classify(&anyhow::anyhow!("bad line"), false),
+            GatewayError::ClientBadRequest
+        );
+    }
+}
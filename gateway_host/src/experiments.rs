@@ -0,0 +1,488 @@
+//! Per-route A/B experiments (`EXPERIMENTS`).
+//!
+//! An experiment splits the requests under a route prefix between named
+//! variants by percentage, e.g.
+//! `checkout@/checkout=control:50,v2:50>http://checkout-v2:8080`. A variant
+//! can carry its own target: an upstream (`>http(s)://...`) that replaces the
+//! usual choice of upstream, or a module (`>module:path.wasm`) that replaces
+//! the transform. A variant without one is served as usual, which makes it
+//! the control. Experiments are separated by `;`; the longest prefix wins.
+//!
+//! Assignment is sticky: it hashes the experiment name with the
+//! `EXPERIMENT_KEY_HEADER` value (a user or session id) or, without one, the
+//! client address, so a client keeps its variant across requests, restarts
+//! and replicas. The response and the forwarded request carry
+//! `X-Experiment: name=variant`, the guest sees it as `GATEWAY_EXPERIMENT`,
+//! and `/metrics` and `/stats` break requests, latency, transform and
+//! upstream time down by experiment and variant.
+
+use anyhow::{anyhow, Context, Result};
+use once_cell::sync::OnceCell;
+use sha2::{Digest, Sha256};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::transform::Transform;
+use crate::{PoolMember, RequestTrace};
+
+pub(crate) const HEADER: &str = "X-Experiment";
+
+static EXPERIMENTS: OnceCell<Experiments> = OnceCell::new();
+
+/// A `/metrics` counter: name, help and samples.
+type Metric = (&'static str, &'static str, Vec<(String, String)>);
+
+#[derive(Debug)]
+pub(crate) struct Experiments {
+    /// Longest prefix first.
+    experiments: Vec<Experiment>,
+    key_header: Option<String>,
+}
+
+#[derive(Debug)]
+pub(crate) struct Experiment {
+    name: String,
+    prefix: String,
+    variants: Vec<Variant>,
+}
+
+#[derive(Debug)]
+struct Variant {
+    name: String,
+    percent: u32,
+    upstream: Option<PoolMember>,
+    transform: Option<Box<dyn Transform>>,
+    counters: Counters,
+}
+
+/// One variant's share of the request counters, as in `metrics`.
+#[derive(Debug, Default)]
+struct Counters {
+    by_class: [AtomicU64; 6],
+    latency_us: AtomicU64,
+    transform_us: AtomicU64,
+    upstream_requests: AtomicU64,
+    upstream_us: AtomicU64,
+    errors: AtomicU64,
+}
+
+/// The variant one request is in.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Assignment {
+    experiment: &'static Experiment,
+    variant: &'static Variant,
+}
+
+/// What a variant's `>target` names, before it is built.
+#[derive(Debug, PartialEq)]
+enum Target<'a> {
+    Upstream(&'a str),
+    Module(&'a str),
+}
+
+/// Reads `EXPERIMENTS` and `EXPERIMENT_KEY_HEADER`; `build_upstream` and
+/// `load_module` turn variant targets into an upstream and a transform. Call
+/// once from `main`.
+pub(crate) fn init_from_env(
+    build_upstream: impl Fn(&str) -> Result<PoolMember>,
+    load_module: impl Fn(&str) -> Result<Box<dyn Transform>>,
+) -> Result<()> {
+    let spec = match std::env::var("EXPERIMENTS") {
+        Ok(v) if !v.trim().is_empty() => v,
+        _ => return Ok(()),
+    };
+    let experiments = parse(&spec, &build_upstream, &load_module)
+        .with_context(|| format!("invalid EXPERIMENTS={spec}"))?;
+    let key_header = std::env::var("EXPERIMENT_KEY_HEADER")
+        .ok()
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty());
+    for experiment in &experiments {
+        let variants: Vec<String> = experiment
+            .variants
+            .iter()
+            .map(|v| format!("{} {}%", v.name, v.percent))
+            .collect();
+        eprintln!(
+            "[wasm-host] experiment {} on {}: {}",
+            experiment.name,
+            experiment.prefix,
+            variants.join(", ")
+        );
+    }
+    EXPERIMENTS.get_or_init(|| Experiments {
+        experiments,
+        key_header,
+    });
+    Ok(())
+}
+
+pub(crate) fn configured() -> Option<&'static Experiments> {
+    EXPERIMENTS.get()
+}
+
+fn parse(
+    spec: &str,
+    build_upstream: &dyn Fn(&str) -> Result<PoolMember>,
+    load_module: &dyn Fn(&str) -> Result<Box<dyn Transform>>,
+) -> Result<Vec<Experiment>> {
+    let mut experiments = spec
+        .split(';')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            let (name, rest) = entry
+                .split_once('@')
+                .ok_or_else(|| anyhow!("{entry:?}: expected name@/prefix=variant:percent,..."))?;
+            let (prefix, variants) = rest
+                .split_once('=')
+                .ok_or_else(|| anyhow!("{entry:?}: expected name@/prefix=variant:percent,..."))?;
+            let (name, prefix) = (name.trim(), prefix.trim());
+            if name.is_empty() || !prefix.starts_with('/') {
+                return Err(anyhow!("{entry:?}: needs a name and a /prefix"));
+            }
+            let variants = variants
+                .split(',')
+                .map(str::trim)
+                .filter(|v| !v.is_empty())
+                .map(|variant| {
+                    parse_variant(variant, build_upstream, load_module)
+                        .with_context(|| format!("experiment {name}, variant {variant:?}"))
+                })
+                .collect::<Result<Vec<_>>>()?;
+            let total: u32 = variants.iter().map(|v| v.percent).sum();
+            if variants.len() < 2 || total != 100 {
+                return Err(anyhow!(
+                    "experiment {name}: needs two or more variants adding up to 100% (got {total}%)"
+                ));
+            }
+            Ok(Experiment {
+                name: name.to_string(),
+                prefix: prefix.to_string(),
+                variants,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    experiments.sort_by_key(|e| std::cmp::Reverse(e.prefix.len()));
+    for (i, experiment) in experiments.iter().enumerate() {
+        if experiments[..i]
+            .iter()
+            .any(|e| e.name == experiment.name || e.prefix == experiment.prefix)
+        {
+            return Err(anyhow!(
+                "experiment {} on {}: name or prefix used twice",
+                experiment.name,
+                experiment.prefix
+            ));
+        }
+    }
+    Ok(experiments)
+}
+
+fn parse_variant(
+    spec: &str,
+    build_upstream: &dyn Fn(&str) -> Result<PoolMember>,
+    load_module: &dyn Fn(&str) -> Result<Box<dyn Transform>>,
+) -> Result<Variant> {
+    let (split, target) = match spec.split_once('>') {
+        Some((split, target)) => (split, Some(parse_target(target.trim())?)),
+        None => (spec, None),
+    };
+    let (name, percent) = split
+        .split_once(':')
+        .ok_or_else(|| anyhow!("expected variant:percent[>target]"))?;
+    let name = name.trim();
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c))
+    {
+        return Err(anyhow!(
+            "variant names take letters, digits, '-', '_' and '.'"
+        ));
+    }
+    let percent = percent
+        .trim()
+        .parse::<u32>()
+        .ok()
+        .filter(|&p| p <= 100)
+        .ok_or_else(|| anyhow!("invalid percentage {percent:?}"))?;
+    let (upstream, transform) = match target {
+        Some(Target::Upstream(url)) => (Some(build_upstream(url)?), None),
+        Some(Target::Module(path)) => (None, Some(load_module(path)?)),
+        None => (None, None),
+    };
+    Ok(Variant {
+        name: name.to_string(),
+        percent,
+        upstream,
+        transform,
+        counters: Counters::default(),
+    })
+}
+
+fn parse_target(target: &str) -> Result<Target<'_>> {
+    if let Some(path) = target.strip_prefix("module:") {
+        return Ok(Target::Module(path.trim()));
+    }
+    if target.starts_with("http://") || target.starts_with("https://") {
+        return Ok(Target::Upstream(target));
+    }
+    Err(anyhow!(
+        "invalid target {target:?} (expected http(s)://... or module:path)"
+    ))
+}
+
+/// Where a key falls in `0..100` for one experiment; the same everywhere.
+fn bucket(experiment: &str, key: &str) -> u32 {
+    let digest = Sha256::new()
+        .chain_update(experiment.as_bytes())
+        .chain_update([0])
+        .chain_update(key.as_bytes())
+        .finalize();
+    let n = u64::from_be_bytes(digest[..8].try_into().expect("8 bytes"));
+    (n % 100) as u32
+}
+
+impl Experiments {
+    /// The variant for a request to `path`, keyed by the
+    /// `EXPERIMENT_KEY_HEADER` value when there is one, else `client`.
+    pub(crate) fn assign<'r>(
+        &'static self,
+        path: &str,
+        header: impl Fn(&str) -> Option<&'r str>,
+        client: &'r str,
+    ) -> Option<Assignment> {
+        let experiment = self
+            .experiments
+            .iter()
+            .find(|e| path.starts_with(e.prefix.as_str()))?;
+        let key = self
+            .key_header
+            .as_deref()
+            .and_then(header)
+            .map(str::trim)
+            .filter(|v| !v.is_empty())
+            .unwrap_or(client);
+        let mut point = bucket(&experiment.name, key);
+        let variant = experiment
+            .variants
+            .iter()
+            .find(|v| {
+                if point < v.percent {
+                    return true;
+                }
+                point -= v.percent;
+                false
+            })
+            .expect("percentages add up to 100");
+        Some(Assignment {
+            experiment,
+            variant,
+        })
+    }
+
+    /// The `/metrics` counters, labeled by experiment and variant.
+    pub(crate) fn samples(&self) -> Vec<Metric> {
+        let variants: Vec<(String, &Counters)> = self
+            .experiments
+            .iter()
+            .flat_map(|e| {
+                e.variants.iter().map(move |v| {
+                    (
+                        format!("experiment=\"{}\",variant=\"{}\"", e.name, v.name),
+                        &v.counters,
+                    )
+                })
+            })
+            .collect();
+        let load = |n: &AtomicU64| n.load(Ordering::Relaxed);
+        let secs = |n: &AtomicU64| (load(n) as f64 / 1_000_000.0).to_string();
+        let each = |value: &dyn Fn(&Counters) -> String| -> Vec<(String, String)> {
+            variants
+                .iter()
+                .map(|(labels, c)| (format!("{{{labels}}}"), value(c)))
+                .collect()
+        };
+        let by_class = variants
+            .iter()
+            .flat_map(|(labels, c)| {
+                crate::metrics::CLASSES
+                    .iter()
+                    .zip(&c.by_class)
+                    .map(move |(class, n)| {
+                        (
+                            format!("{{{labels},status=\"{class}\"}}"),
+                            load(n).to_string(),
+                        )
+                    })
+            })
+            .collect();
+        vec![
+            (
+                "gateway_experiment_requests_total",
+                "Requests in an experiment (EXPERIMENTS), by variant and response status class.",
+                by_class,
+            ),
+            (
+                "gateway_experiment_request_duration_seconds_total",
+                "Time spent handling requests, by experiment variant.",
+                each(&|c| secs(&c.latency_us)),
+            ),
+            (
+                "gateway_experiment_transform_duration_seconds_total",
+                "Time spent in the transform backend, by experiment variant.",
+                each(&|c| secs(&c.transform_us)),
+            ),
+            (
+                "gateway_experiment_upstream_requests_total",
+                "Requests forwarded to the upstream, by experiment variant.",
+                each(&|c| load(&c.upstream_requests).to_string()),
+            ),
+            (
+                "gateway_experiment_upstream_duration_seconds_total",
+                "Upstream connect to last response byte, by experiment variant.",
+                each(&|c| secs(&c.upstream_us)),
+            ),
+            (
+                "gateway_experiment_errors_total",
+                "Error responses of the gateway's own, by experiment variant.",
+                each(&|c| load(&c.errors).to_string()),
+            ),
+        ]
+    }
+
+    pub(crate) fn json(&self) -> serde_json::Value {
+        let load = |n: &AtomicU64| n.load(Ordering::Relaxed);
+        self.experiments
+            .iter()
+            .map(|e| {
+                let variants: serde_json::Map<String, serde_json::Value> = e
+                    .variants
+                    .iter()
+                    .map(|v| {
+                        let c = &v.counters;
+                        let requests: u64 = c.by_class.iter().map(load).sum();
+                        let by_class: serde_json::Map<String, serde_json::Value> =
+                            crate::metrics::CLASSES
+                                .iter()
+                                .zip(&c.by_class)
+                                .map(|(class, n)| (class.to_string(), load(n).into()))
+                                .collect();
+                        (
+                            v.name.clone(),
+                            serde_json::json!({
+                                "percent": v.percent,
+                                "requests": requests,
+                                "by_status": by_class,
+                                "latency_us": load(&c.latency_us),
+                                "transform_us": load(&c.transform_us),
+                                "upstream_requests": load(&c.upstream_requests),
+                                "upstream_us": load(&c.upstream_us),
+                                "errors": load(&c.errors),
+                            }),
+                        )
+                    })
+                    .collect();
+                (
+                    e.name.clone(),
+                    serde_json::json!({ "prefix": e.prefix, "variants": variants }),
+                )
+            })
+            .collect::<serde_json::Map<_, _>>()
+            .into()
+    }
+}
+
+impl Assignment {
+    /// `name=variant`, the `X-Experiment` value.
+    pub(crate) fn label(&self) -> String {
+        format!("{}={}", self.experiment.name, self.variant.name)
+    }
+
+    /// The variant's own upstream, if it has one.
+    pub(crate) fn upstream(&self) -> Option<&'static PoolMember> {
+        self.variant.upstream.as_ref()
+    }
+
+    /// The variant's own transform, if it has one.
+    pub(crate) fn transform(&self) -> Option<&'static dyn Transform> {
+        self.variant.transform.as_deref()
+    }
+
+    /// Counts a finished request against the variant.
+    pub(crate) fn record(&self, trace: &RequestTrace, latency: std::time::Duration, class: usize) {
+        let c = &self.variant.counters;
+        c.by_class[class].fetch_add(1, Ordering::Relaxed);
+        c.latency_us
+            .fetch_add(latency.as_micros() as u64, Ordering::Relaxed);
+        c.transform_us.fetch_add(trace.wasm_us, Ordering::Relaxed);
+        if let Some(timing) = trace.upstream_timing {
+            c.upstream_requests.fetch_add(1, Ordering::Relaxed);
+            c.upstream_us.fetch_add(timing.total_us, Ordering::Relaxed);
+        }
+        if trace.error.is_some() {
+            c.errors.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn no_upstream(url: &str) -> Result<PoolMember> {
+        Err(anyhow!("unexpected upstream {url}"))
+    }
+
+    fn no_module(path: &str) -> Result<Box<dyn Transform>> {
+        Err(anyhow!("unexpected module {path}"))
+    }
+
+    #[test]
+    fn splits_by_percentage_and_keeps_clients_on_their_variant() {
+        assert_eq!(
+            parse_target("module: ./b.wasm").unwrap(),
+            Target::Module("./b.wasm")
+        );
+        assert!(parse_target("ftp://x").is_err());
+        for bad in [
+            "hero@/=a:60,b:30",
+            "hero=a:50,b:50",
+            "hero@/=a:100",
+            "hero@/=a:50,b:50;hero@/x=a:50,b:50",
+            "hero@/=a b:50,c:50",
+        ] {
+            assert!(parse(bad, &no_upstream, &no_module).is_err(), "{bad}");
+        }
+
+        let experiments: &'static Experiments = Box::leak(Box::new(Experiments {
+            experiments: parse(
+                "hero@/=a:90,b:10; checkout@/checkout=control:50,v2:50",
+                &no_upstream,
+                &no_module,
+            )
+            .unwrap(),
+            key_header: Some("X-User".to_string()),
+        }));
+        let no_header = |_: &str| None;
+        assert!(experiments.assign("/other", no_header, "1.2.3.4").is_some());
+        let checkout = experiments
+            .assign("/checkout/cart", no_header, "1.2.3.4")
+            .unwrap();
+        assert!(checkout.label().starts_with("checkout="));
+
+        // Same key, same variant; the header takes precedence over the client.
+        let mut counts = [0u32; 2];
+        for user in 0..2000 {
+            let user = user.to_string();
+            let header = |_: &str| Some(user.as_str());
+            let first = experiments.assign("/", header, "1.2.3.4").unwrap().label();
+            assert_eq!(
+                experiments.assign("/", header, "5.6.7.8").unwrap().label(),
+                first
+            );
+            counts[usize::from(first == "hero=b")] += 1;
+        }
+        assert!((100..300).contains(&counts[1]), "{counts:?}");
+    }
+}
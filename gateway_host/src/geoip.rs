@@ -0,0 +1,239 @@
+//! Client location from MaxMind databases (`GEOIP_COUNTRY_DB`,
+//! `GEOIP_ASN_DB`).
+//!
+//! The client address is looked up once per request in a GeoIP2/GeoLite2
+//! Country (or City) database and an ASN database, either of them optional.
+//! The forwarded request carries `X-Client-Country` (ISO code) and
+//! `X-Client-ASN`, replacing whatever the client sent, and the guest sees
+//! `GATEWAY_CLIENT_COUNTRY` and `GATEWAY_CLIENT_ASN`. `GEOIP_DENY_COUNTRIES`
+//! answers listed countries `403`; `GEOIP_ALLOW_COUNTRIES` answers everyone
+//! else `403`, addresses with no country included. `GEOIP_ROUTES` sends a
+//! country's proxied requests to its own upstream.
+
+use anyhow::{anyhow, Context, Result};
+use maxminddb::{geoip2, MaxMindDBError, Reader};
+use once_cell::sync::OnceCell;
+use std::net::IpAddr;
+
+use crate::PoolMember;
+
+pub(crate) const COUNTRY_HEADER: &str = "X-Client-Country";
+pub(crate) const ASN_HEADER: &str = "X-Client-ASN";
+
+static GEOIP: OnceCell<GeoIp> = OnceCell::new();
+
+#[derive(Debug)]
+pub(crate) struct GeoIp {
+    country_db: Option<Reader<Vec<u8>>>,
+    asn_db: Option<Reader<Vec<u8>>>,
+    rule: Option<Rule>,
+    /// `(country, upstream)`, countries uppercase.
+    routes: Vec<(String, PoolMember)>,
+}
+
+/// Which countries get through.
+#[derive(Debug, PartialEq)]
+enum Rule {
+    Allow(Vec<String>),
+    Deny(Vec<String>),
+}
+
+/// What the databases know about one address.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct Location {
+    /// ISO 3166-1 alpha-2, uppercase.
+    pub(crate) country: Option<String>,
+    pub(crate) asn: Option<u32>,
+}
+
+/// Reads the `GEOIP_*` settings; `build_upstream` turns a `GEOIP_ROUTES`
+/// URL into an upstream. Call once from `main`.
+pub(crate) fn init_from_env(build_upstream: impl Fn(&str) -> Result<PoolMember>) -> Result<()> {
+    let var = |name: &str| std::env::var(name).ok().filter(|v| !v.trim().is_empty());
+    let open = |name: &str| -> Result<Option<Reader<Vec<u8>>>> {
+        var(name)
+            .map(|path| {
+                Reader::open_readfile(path.trim()).with_context(|| format!("open {name}={path}"))
+            })
+            .transpose()
+    };
+    let (country_db, asn_db) = (open("GEOIP_COUNTRY_DB")?, open("GEOIP_ASN_DB")?);
+    let rule = match (var("GEOIP_ALLOW_COUNTRIES"), var("GEOIP_DENY_COUNTRIES")) {
+        (Some(_), Some(_)) => {
+            return Err(anyhow!(
+                "set GEOIP_ALLOW_COUNTRIES or GEOIP_DENY_COUNTRIES, not both"
+            ))
+        }
+        (Some(allow), None) => Some(Rule::Allow(parse_countries(&allow)?)),
+        (None, Some(deny)) => Some(Rule::Deny(parse_countries(&deny)?)),
+        (None, None) => None,
+    };
+    let routes = var("GEOIP_ROUTES")
+        .map(|spec| parse_routes(&spec, &build_upstream))
+        .transpose()?
+        .unwrap_or_default();
+    if country_db.is_none() && (rule.is_some() || !routes.is_empty()) {
+        return Err(anyhow!(
+            "country rules and GEOIP_ROUTES need GEOIP_COUNTRY_DB"
+        ));
+    }
+    if asn_db.is_none() && country_db.is_none() {
+        return Ok(());
+    }
+    let geoip = GEOIP.get_or_init(|| GeoIp {
+        country_db,
+        asn_db,
+        rule,
+        routes,
+    });
+    eprintln!(
+        "[wasm-host] geoip: {}{}{}",
+        [
+            geoip.country_db.as_ref().map(|db| format!(
+                "country ({}, built {})",
+                db.metadata.database_type, db.metadata.build_epoch
+            )),
+            geoip.asn_db.as_ref().map(|db| format!(
+                "asn ({}, built {})",
+                db.metadata.database_type, db.metadata.build_epoch
+            )),
+        ]
+        .into_iter()
+        .flatten()
+        .collect::<Vec<_>>()
+        .join(", "),
+        match &geoip.rule {
+            Some(Rule::Allow(countries)) => format!(", allowing only {}", countries.join(",")),
+            Some(Rule::Deny(countries)) => format!(", denying {}", countries.join(",")),
+            None => String::new(),
+        },
+        if geoip.routes.is_empty() {
+            String::new()
+        } else {
+            format!(", {} country route(s)", geoip.routes.len())
+        }
+    );
+    Ok(())
+}
+
+pub(crate) fn configured() -> Option<&'static GeoIp> {
+    GEOIP.get()
+}
+
+fn parse_countries(spec: &str) -> Result<Vec<String>> {
+    spec.split(',')
+        .map(str::trim)
+        .filter(|c| !c.is_empty())
+        .map(|c| {
+            if c.len() == 2 && c.chars().all(|ch| ch.is_ascii_alphabetic()) {
+                Ok(c.to_ascii_uppercase())
+            } else {
+                Err(anyhow!("invalid country code {c:?} (expected e.g. DE)"))
+            }
+        })
+        .collect()
+}
+
+/// `country=url,...`, e.g. `DE=http://eu:8080,US=http://us:8080`.
+fn parse_routes(
+    spec: &str,
+    build_upstream: &dyn Fn(&str) -> Result<PoolMember>,
+) -> Result<Vec<(String, PoolMember)>> {
+    spec.split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            let (country, url) = entry.split_once('=').ok_or_else(|| {
+                anyhow!("invalid GEOIP_ROUTES entry {entry:?} (expected country=url)")
+            })?;
+            let country = parse_countries(country)?
+                .pop()
+                .ok_or_else(|| anyhow!("invalid GEOIP_ROUTES entry {entry:?}"))?;
+            let upstream = build_upstream(url.trim())
+                .with_context(|| format!("invalid GEOIP_ROUTES entry {entry:?}"))?;
+            Ok((country, upstream))
+        })
+        .collect()
+}
+
+/// A lookup that found nothing is not an error.
+fn found<T>(result: Result<T, MaxMindDBError>) -> Result<Option<T>> {
+    match result {
+        Ok(record) => Ok(Some(record)),
+        Err(MaxMindDBError::AddressNotFoundError(_)) => Ok(None),
+        Err(e) => Err(anyhow!("geoip lookup: {e}")),
+    }
+}
+
+impl GeoIp {
+    pub(crate) fn locate(&self, ip: IpAddr) -> Result<Location> {
+        let country = match &self.country_db {
+            Some(db) => found(db.lookup::<geoip2::Country>(ip))?
+                .and_then(|record| record.country?.iso_code)
+                .map(str::to_ascii_uppercase),
+            None => None,
+        };
+        let asn = match &self.asn_db {
+            Some(db) => {
+                found(db.lookup::<geoip2::Asn>(ip))?.and_then(|r| r.autonomous_system_number)
+            }
+            None => None,
+        };
+        Ok(Location { country, asn })
+    }
+
+    /// Whether the country rules let `location` through.
+    pub(crate) fn allows(&self, location: &Location) -> bool {
+        let listed = |countries: &[String]| {
+            location
+                .country
+                .as_ref()
+                .is_some_and(|c| countries.contains(c))
+        };
+        match &self.rule {
+            Some(Rule::Allow(countries)) => listed(countries),
+            Some(Rule::Deny(countries)) => !listed(countries),
+            None => true,
+        }
+    }
+
+    /// The `GEOIP_ROUTES` upstream for `location`, if its country has one.
+    pub(crate) fn upstream_for(&self, location: &Location) -> Option<&PoolMember> {
+        let country = location.country.as_ref()?;
+        self.routes
+            .iter()
+            .find(|(c, _)| c == country)
+            .map(|(_, upstream)| upstream)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn applies_country_rules_and_routes() {
+        assert_eq!(parse_countries(" de, Us ").unwrap(), ["DE", "US"]);
+        assert!(parse_countries("DEU").is_err());
+        assert!(parse_routes("DE", &|_| unreachable!()).is_err());
+
+        let at = |country: Option<&str>| Location {
+            country: country.map(str::to_string),
+            asn: Some(3320),
+        };
+        let mut geoip = GeoIp {
+            country_db: None,
+            asn_db: None,
+            rule: Some(Rule::Deny(vec!["RU".to_string()])),
+            routes: Vec::new(),
+        };
+        assert!(!geoip.allows(&at(Some("RU"))));
+        assert!(geoip.allows(&at(Some("DE"))));
+        assert!(geoip.allows(&at(None)));
+        geoip.rule = Some(Rule::Allow(vec!["DE".to_string()]));
+        assert!(geoip.allows(&at(Some("DE"))));
+        assert!(!geoip.allows(&at(Some("US"))));
+        assert!(!geoip.allows(&at(None)));
+        assert!(geoip.upstream_for(&at(Some("DE"))).is_none());
+    }
+}
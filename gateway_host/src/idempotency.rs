@@ -0,0 +1,315 @@
+//! `Idempotency-Key` support for unsafe methods on the proxy path
+//! (`IDEMPOTENCY_TTL_SECS`), so clients can retry a `POST` without it being
+//! forwarded twice.
+//!
+//! A `POST`, `PUT`, `PATCH` or `DELETE` with an `Idempotency-Key` reserves
+//! the key before it is forwarded; keys are scoped to the `Host`, method,
+//! target and `Authorization`, so one client cannot replay another's. Once
+//! answered, the response sent to the client is kept for the TTL and a retry
+//! with the same key gets it back byte for byte, plus `Idempotent-Replayed:
+//! true`, without reaching the upstream. A retry while the first request is
+//! still running gets a `409` with `Retry-After: 1`, and one whose body
+//! differs from the first a `422`. Failures and `5xx` answers release the
+//! key, so the next retry is forwarded.
+//!
+//! At most `IDEMPOTENCY_MAX_KEYS` (default 10000) are kept; expired ones go
+//! first, then the oldest, but never a key whose request is still running.
+//! `/stats` reports `idempotency` and `/metrics`
+//! `gateway_idempotency_requests_total{result}`.
+
+use anyhow::{Context, Result};
+use once_cell::sync::OnceCell;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::headers::Headers;
+
+pub(crate) const HEADER: &str = "Idempotency-Key";
+const DEFAULT_MAX_KEYS: usize = 10_000;
+
+static STORE: OnceCell<IdempotencyStore> = OnceCell::new();
+
+type Digest32 = [u8; 32];
+
+#[derive(Debug)]
+pub(crate) struct IdempotencyStore {
+    ttl: Duration,
+    max_keys: usize,
+    keys: Mutex<HashMap<Digest32, Slot>>,
+    new: AtomicU64,
+    replays: AtomicU64,
+    conflicts: AtomicU64,
+    mismatches: AtomicU64,
+}
+
+#[derive(Debug)]
+struct Slot {
+    body: Digest32,
+    since: Instant,
+    /// `None` while the first request is running.
+    response: Option<Arc<Vec<u8>>>,
+}
+
+#[derive(Debug)]
+pub(crate) enum Claim<'a> {
+    /// First time: forward, then `Reservation::complete`.
+    New(Reservation<'a>),
+    /// The stored response, `Idempotent-Replayed` already added.
+    Replay(Vec<u8>),
+    /// The first request with this key is still running.
+    InFlight,
+    /// The key was used for a different body.
+    Mismatch,
+}
+
+/// Reads `IDEMPOTENCY_TTL_SECS` and `IDEMPOTENCY_MAX_KEYS`. Call once from
+/// `main`.
+pub(crate) fn init_from_env() -> Result<()> {
+    let ttl = match std::env::var("IDEMPOTENCY_TTL_SECS") {
+        Ok(v) if !v.trim().is_empty() => v
+            .trim()
+            .parse::<u64>()
+            .with_context(|| format!("invalid IDEMPOTENCY_TTL_SECS={v}"))?,
+        _ => return Ok(()),
+    };
+    if ttl == 0 {
+        return Ok(());
+    }
+    let max_keys = match std::env::var("IDEMPOTENCY_MAX_KEYS") {
+        Ok(v) if !v.trim().is_empty() => v
+            .trim()
+            .parse::<usize>()
+            .ok()
+            .filter(|&n| n > 0)
+            .with_context(|| format!("invalid IDEMPOTENCY_MAX_KEYS={v}"))?,
+        _ => DEFAULT_MAX_KEYS,
+    };
+    let store = STORE.get_or_init(|| IdempotencyStore::new(Duration::from_secs(ttl), max_keys));
+    eprintln!(
+        "[wasm-host] idempotency keys: kept {:?}, up to {}",
+        store.ttl, store.max_keys
+    );
+    Ok(())
+}
+
+pub(crate) fn configured() -> Option<&'static IdempotencyStore> {
+    STORE.get()
+}
+
+/// Whether requests with `method` take part.
+pub(crate) fn applies_to(method: &str) -> bool {
+    matches!(method, "POST" | "PUT" | "PATCH" | "DELETE")
+}
+
+impl IdempotencyStore {
+    fn new(ttl: Duration, max_keys: usize) -> Self {
+        IdempotencyStore {
+            ttl,
+            max_keys,
+            keys: Mutex::new(HashMap::new()),
+            new: AtomicU64::new(0),
+            replays: AtomicU64::new(0),
+            conflicts: AtomicU64::new(0),
+            mismatches: AtomicU64::new(0),
+        }
+    }
+
+    /// Claims `key` for a request, or finds what an earlier one left.
+    pub(crate) fn claim(
+        &self,
+        key: &str,
+        method: &str,
+        target: &str,
+        headers: &Headers,
+        body: &[u8],
+    ) -> Claim<'_> {
+        let mut scope = Sha256::new();
+        for part in [
+            headers.get("Host").unwrap_or(""),
+            method,
+            target,
+            headers.get("Authorization").unwrap_or(""),
+            key,
+        ] {
+            scope.update(part.as_bytes());
+            scope.update(b"\n");
+        }
+        let scope: Digest32 = scope.finalize().into();
+        let body: Digest32 = Sha256::digest(body).into();
+
+        let mut keys = self.keys.lock().unwrap_or_else(|e| e.into_inner());
+        let live = |s: &Slot| s.response.is_none() || s.since.elapsed() < self.ttl;
+        if let Some(slot) = keys.get(&scope).filter(|s| live(s)) {
+            let claim = if slot.body != body {
+                self.mismatches.fetch_add(1, Ordering::Relaxed);
+                Claim::Mismatch
+            } else if let Some(response) = &slot.response {
+                self.replays.fetch_add(1, Ordering::Relaxed);
+                Claim::Replay(replayed(response))
+            } else {
+                self.conflicts.fetch_add(1, Ordering::Relaxed);
+                Claim::InFlight
+            };
+            return claim;
+        }
+        if keys.len() >= self.max_keys && !keys.contains_key(&scope) {
+            keys.retain(|_, s| live(s));
+            if keys.len() >= self.max_keys {
+                if let Some(oldest) = keys
+                    .iter()
+                    .filter(|(_, s)| s.response.is_some())
+                    .min_by_key(|(_, s)| s.since)
+                    .map(|(k, _)| *k)
+                {
+                    keys.remove(&oldest);
+                }
+            }
+        }
+        keys.insert(
+            scope,
+            Slot {
+                body,
+                since: Instant::now(),
+                response: None,
+            },
+        );
+        self.new.fetch_add(1, Ordering::Relaxed);
+        Claim::New(Reservation {
+            store: self,
+            scope,
+            done: false,
+        })
+    }
+
+    pub(crate) fn json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "keys": self.keys.lock().unwrap_or_else(|e| e.into_inner()).len(),
+            "new": self.new.load(Ordering::Relaxed),
+            "replays": self.replays.load(Ordering::Relaxed),
+            "conflicts": self.conflicts.load(Ordering::Relaxed),
+            "mismatches": self.mismatches.load(Ordering::Relaxed),
+        })
+    }
+
+    /// Prometheus samples for `gateway_idempotency_requests_total`.
+    pub(crate) fn samples(&self) -> Vec<(String, String)> {
+        [
+            ("new", &self.new),
+            ("replay", &self.replays),
+            ("conflict", &self.conflicts),
+            ("mismatch", &self.mismatches),
+        ]
+        .into_iter()
+        .map(|(result, n)| {
+            (
+                format!("{{result=\"{result}\"}}"),
+                n.load(Ordering::Relaxed).to_string(),
+            )
+        })
+        .collect()
+    }
+}
+
+/// A claimed key. Dropping it without `complete`, on an error for example,
+/// releases the key.
+#[derive(Debug)]
+pub(crate) struct Reservation<'a> {
+    store: &'a IdempotencyStore,
+    scope: Digest32,
+    done: bool,
+}
+
+impl Reservation<'_> {
+    /// Keeps `response` for retries, unless it is a `5xx`.
+    pub(crate) fn complete(mut self, response: &[u8]) {
+        if response.get(9) == Some(&b'5') {
+            return;
+        }
+        let mut keys = self.store.keys.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(slot) = keys.get_mut(&self.scope) {
+            slot.since = Instant::now();
+            slot.response = Some(Arc::new(response.to_vec()));
+            self.done = true;
+        }
+    }
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        if !self.done {
+            self.store
+                .keys
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .remove(&self.scope);
+        }
+    }
+}
+
+/// `response` with `Idempotent-Replayed: true` after its status line.
+fn replayed(response: &[u8]) -> Vec<u8> {
+    let line_end = response
+        .windows(2)
+        .position(|w| w == b"\r\n")
+        .unwrap_or(response.len());
+    let mut out = Vec::with_capacity(response.len() + 32);
+    out.extend_from_slice(&response[..line_end]);
+    out.extend_from_slice(b"\r\nIdempotent-Replayed: true");
+    out.extend_from_slice(&response[line_end..]);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replays_completed_keys_and_releases_failed_ones() {
+        let store = IdempotencyStore::new(Duration::from_secs(60), 2);
+        let headers = Headers::parse("Host: a\r\nAuthorization: Bearer x");
+        let claim = |key: &str, body: &[u8]| store.claim(key, "POST", "/orders", &headers, body);
+
+        let Claim::New(first) = claim("k1", b"{}") else {
+            panic!("expected a new claim");
+        };
+        assert!(matches!(claim("k1", b"{}"), Claim::InFlight));
+        first.complete(b"HTTP/1.1 201 Created\r\nContent-Length: 2\r\n\r\nok");
+        match claim("k1", b"{}") {
+            Claim::Replay(resp) => assert_eq!(
+                resp,
+                b"HTTP/1.1 201 Created\r\nIdempotent-Replayed: true\r\nContent-Length: 2\r\n\r\nok"
+            ),
+            other => panic!("expected a replay, got {other:?}"),
+        }
+        assert!(matches!(claim("k1", b"{\"n\":2}"), Claim::Mismatch));
+
+        // Another caller's key of the same name is its own.
+        let other = Headers::parse("Host: a\r\nAuthorization: Bearer y");
+        assert!(matches!(
+            store.claim("k1", "POST", "/orders", &other, b"{}"),
+            Claim::New(_)
+        ));
+
+        // A 5xx, or no answer at all, releases the key.
+        let Claim::New(failed) = claim("k2", b"") else {
+            panic!("expected a new claim");
+        };
+        failed.complete(b"HTTP/1.1 503 Service Unavailable\r\n\r\n");
+        assert!(matches!(claim("k2", b""), Claim::New(_)));
+
+        // Bounded: the oldest answered key makes room.
+        let Claim::New(third) = claim("k3", b"") else {
+            panic!("expected a new claim");
+        };
+        third.complete(b"HTTP/1.1 200 OK\r\n\r\n");
+        assert_eq!(store.json()["keys"], 2);
+        assert!(matches!(claim("k4", b""), Claim::New(_)));
+        assert!(matches!(claim("k1", b"{}"), Claim::New(_)));
+        assert_eq!(store.json()["replays"], 1);
+        assert_eq!(store.json()["mismatches"], 1);
+        assert_eq!(store.json()["conflicts"], 1);
+    }
+}
@@ -0,0 +1,418 @@
+//! Upstream pool from a Kubernetes Service (`DISCOVERY=kubernetes`).
+//!
+//! With the pod's service account, the gateway lists the EndpointSlices of
+//! `K8S_SERVICE` (`name` or `namespace/name`, default namespace: the pod's)
+//! at startup and balances over their ready endpoints as with
+//! `UPSTREAM_POOL`. A background thread then watches the slices and swaps
+//! members in as pods come and go. `K8S_PORT` picks the Service port by name
+//! or number (default: the first one). While no endpoint is ready, requests
+//! go to `UPSTREAM_URL`.
+//!
+//! The service account needs `list` and `watch` on
+//! `endpointslices.discovery.k8s.io` in the Service's namespace.
+
+use anyhow::{anyhow, Context, Result};
+use std::collections::BTreeMap;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{IpAddr, TcpStream};
+use std::path::Path;
+use std::time::Duration;
+
+use crate::balancer::Balancer;
+use crate::upstream_tls::{UpstreamStream, UpstreamTls};
+use crate::{parse_upstream, PoolMember, IO_TIMEOUT};
+
+const SERVICE_ACCOUNT: &str = "/var/run/secrets/kubernetes.io/serviceaccount";
+/// The API server's certificate always covers this name.
+const API_SERVER_NAME: &str = "kubernetes.default.svc";
+/// Server-side watch timeout; the watch is then resumed.
+const WATCH_SECS: u64 = 300;
+const RETRY: Duration = Duration::from_secs(1);
+const MAX_RETRY: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum PortSelector {
+    Name(String),
+    Number(u16),
+}
+
+/// Ready endpoints per EndpointSlice name.
+type Slices = BTreeMap<String, Vec<String>>;
+
+/// Keeps a pool in step with the Service's EndpointSlices.
+#[derive(Debug)]
+pub(crate) struct Watch {
+    api: String,
+    tls: UpstreamTls,
+    namespace: String,
+    service: String,
+    state: State,
+}
+
+/// What the watch has seen so far.
+#[derive(Debug)]
+struct State {
+    /// `namespace/service`, for logs.
+    name: String,
+    port: Option<PortSelector>,
+    slices: Slices,
+    resource_version: String,
+}
+
+impl Watch {
+    /// Reads `K8S_SERVICE` / `K8S_PORT` and the in-cluster credentials, and
+    /// fills `pool` from a first listing.
+    pub(crate) fn start(pool: &Balancer<PoolMember>) -> Result<Self> {
+        let var = |name: &str| std::env::var(name).ok().filter(|v| !v.trim().is_empty());
+        let (host, port) = var("KUBERNETES_SERVICE_HOST")
+            .zip(var("KUBERNETES_SERVICE_PORT"))
+            .ok_or_else(|| {
+                anyhow!(
+                    "DISCOVERY=kubernetes needs to run in a pod (KUBERNETES_SERVICE_HOST unset)"
+                )
+            })?;
+        let api = match host.parse::<IpAddr>() {
+            Ok(IpAddr::V6(ip)) => format!("[{ip}]:{port}"),
+            _ => format!("{host}:{port}"),
+        };
+        let service =
+            var("K8S_SERVICE").ok_or_else(|| anyhow!("DISCOVERY=kubernetes needs K8S_SERVICE"))?;
+        let (namespace, service) = match service.split_once('/') {
+            Some((namespace, service)) => (namespace.to_string(), service.to_string()),
+            None => {
+                let path = Path::new(SERVICE_ACCOUNT).join("namespace");
+                let namespace = std::fs::read_to_string(&path)
+                    .with_context(|| format!("read {}", path.display()))?;
+                (namespace.trim().to_string(), service)
+            }
+        };
+        let port = var("K8S_PORT").map(|v| match v.trim().parse::<u16>() {
+            Ok(n) => PortSelector::Number(n),
+            Err(_) => PortSelector::Name(v.trim().to_string()),
+        });
+        let ca = Path::new(SERVICE_ACCOUNT).join("ca.crt");
+        let tls = UpstreamTls::with_ca(API_SERVER_NAME, &ca.to_string_lossy())?;
+        let mut watch = Watch {
+            api,
+            tls,
+            state: State {
+                name: format!("{namespace}/{service}"),
+                port,
+                slices: Slices::new(),
+                resource_version: String::new(),
+            },
+            namespace,
+            service,
+        };
+        let name = watch.state.name.clone();
+        watch
+            .list()
+            .with_context(|| format!("list EndpointSlices of {name}"))?;
+        watch.state.apply(pool);
+        Ok(watch)
+    }
+
+    /// Watches until the process exits, relisting after any error.
+    pub(crate) fn run(mut self, pool: &Balancer<PoolMember>) {
+        let mut failures = 0u32;
+        loop {
+            match self.watch_once(pool) {
+                Ok(()) => failures = 0,
+                Err(e) => {
+                    eprintln!(
+                        "[wasm-host] watch of {} failed, relisting: {e:#}",
+                        self.state.name
+                    );
+                    failures = failures.saturating_add(1);
+                    std::thread::sleep(RETRY.saturating_mul(failures).min(MAX_RETRY));
+                    match self.list() {
+                        Ok(()) => self.state.apply(pool),
+                        Err(e) => {
+                            eprintln!("[wasm-host] list of {} failed: {e:#}", self.state.name)
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn path(&self) -> String {
+        format!(
+            "/apis/discovery.k8s.io/v1/namespaces/{}/endpointslices?labelSelector=kubernetes.io%2Fservice-name%3D{}",
+            self.namespace, self.service
+        )
+    }
+
+    fn list(&mut self) -> Result<()> {
+        let mut body = Vec::new();
+        self.get(&self.path(), IO_TIMEOUT)?.read_to_end(&mut body)?;
+        let list: serde_json::Value =
+            serde_json::from_slice(&body).context("invalid EndpointSliceList")?;
+        self.state.load(&list);
+        Ok(())
+    }
+
+    /// One watch request, applying events until the server ends it.
+    fn watch_once(&mut self, pool: &Balancer<PoolMember>) -> Result<()> {
+        let path = format!(
+            "{}&watch=true&allowWatchBookmarks=true&timeoutSeconds={WATCH_SECS}&resourceVersion={}",
+            self.path(),
+            self.state.resource_version
+        );
+        let mut stream = self.get(&path, Duration::from_secs(WATCH_SECS) + IO_TIMEOUT)?;
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if stream.read_line(&mut line)? == 0 {
+                return Ok(());
+            }
+            if line.trim().is_empty() {
+                continue;
+            }
+            let event: serde_json::Value =
+                serde_json::from_str(&line).context("invalid watch event")?;
+            if self.state.handle(&event)? {
+                self.state.apply(pool);
+            }
+        }
+    }
+
+    /// `GET path` on the API server; the reader is positioned at the body.
+    fn get(&self, path: &str, read_timeout: Duration) -> Result<BufReader<UpstreamStream>> {
+        let tcp = TcpStream::connect(self.api.as_str())
+            .with_context(|| format!("connect Kubernetes API at {}", self.api))?;
+        tcp.set_read_timeout(Some(read_timeout)).ok();
+        tcp.set_write_timeout(Some(IO_TIMEOUT)).ok();
+        let mut stream = self.tls.connect(tcp)?;
+        let token_path = Path::new(SERVICE_ACCOUNT).join("token");
+        // Projected tokens rotate, so it is read for every request.
+        let token = std::fs::read_to_string(&token_path)
+            .with_context(|| format!("read {}", token_path.display()))?;
+        // HTTP/1.0: the body, watch streams included, runs to EOF unchunked.
+        write!(
+            stream,
+            "GET {path} HTTP/1.0\r\nHost: {API_SERVER_NAME}\r\nAuthorization: Bearer {}\r\nAccept: application/json\r\n\r\n",
+            token.trim()
+        )?;
+        stream.flush()?;
+        let mut reader = BufReader::new(stream);
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        let status = line
+            .split_whitespace()
+            .nth(1)
+            .and_then(|s| s.parse::<u16>().ok())
+            .ok_or_else(|| anyhow!("invalid Kubernetes API response {:?}", line.trim()))?;
+        loop {
+            line.clear();
+            if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+                break;
+            }
+        }
+        if status != 200 {
+            let mut message = String::new();
+            reader.take(4096).read_to_string(&mut message).ok();
+            return Err(anyhow!("Kubernetes API {status}: {}", message.trim()));
+        }
+        Ok(reader)
+    }
+}
+
+impl State {
+    /// Replaces everything with an EndpointSliceList.
+    fn load(&mut self, list: &serde_json::Value) {
+        self.slices = list["items"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|slice| {
+                let name = slice["metadata"]["name"].as_str()?;
+                Some((name.to_string(), endpoints(slice, self.port.as_ref())))
+            })
+            .collect();
+        self.resource_version = list["metadata"]["resourceVersion"]
+            .as_str()
+            .unwrap_or_default()
+            .to_string();
+    }
+
+    /// Applies one watch event; `true` if the endpoints may have changed.
+    fn handle(&mut self, event: &serde_json::Value) -> Result<bool> {
+        let object = &event["object"];
+        if let Some(version) = object["metadata"]["resourceVersion"].as_str() {
+            self.resource_version = version.to_string();
+        }
+        let name = object["metadata"]["name"].as_str().unwrap_or_default();
+        match event["type"].as_str() {
+            Some("ADDED" | "MODIFIED") => {
+                self.slices
+                    .insert(name.to_string(), endpoints(object, self.port.as_ref()));
+                Ok(true)
+            }
+            Some("DELETED") => Ok(self.slices.remove(name).is_some()),
+            Some("BOOKMARK") => Ok(false),
+            // Typically 410 Gone: the resource version is too old.
+            _ => Err(anyhow!(
+                "watch error: {}",
+                object["message"].as_str().unwrap_or("unexpected event")
+            )),
+        }
+    }
+
+    /// Swaps the pool's members for the current endpoints if they differ.
+    fn apply(&self, pool: &Balancer<PoolMember>) {
+        let mut urls: Vec<&String> = self.slices.values().flatten().collect();
+        urls.sort();
+        urls.dedup();
+        let current: Vec<String> = pool.members().iter().map(|m| m.name.clone()).collect();
+        if urls
+            .iter()
+            .map(|u| u.as_str())
+            .eq(current.iter().map(String::as_str))
+        {
+            return;
+        }
+        let members = urls
+            .into_iter()
+            .filter_map(|url| match parse_upstream(url) {
+                Ok(upstream) => Some((url.clone(), 1, (upstream, None))),
+                Err(e) => {
+                    eprintln!("[wasm-host] skipping endpoint {url}: {e:#}");
+                    None
+                }
+            })
+            .collect::<Vec<_>>();
+        eprintln!(
+            "[wasm-host] {}: {} ready endpoint(s){}",
+            self.name,
+            members.len(),
+            if members.is_empty() {
+                ", forwarding to UPSTREAM_URL"
+            } else {
+                ""
+            }
+        );
+        pool.replace_members(members);
+    }
+}
+
+/// `http://ip:port` of each ready endpoint in an EndpointSlice.
+fn endpoints(slice: &serde_json::Value, port: Option<&PortSelector>) -> Vec<String> {
+    let ports = slice["ports"]
+        .as_array()
+        .map(Vec::as_slice)
+        .unwrap_or_default();
+    let found = match port {
+        None => ports.first(),
+        Some(PortSelector::Name(name)) => ports.iter().find(|p| p["name"].as_str() == Some(name)),
+        Some(PortSelector::Number(n)) => ports
+            .iter()
+            .find(|p| p["port"].as_u64() == Some(u64::from(*n))),
+    };
+    let Some(port) = found.and_then(|p| p["port"].as_u64()) else {
+        return Vec::new();
+    };
+    slice["endpoints"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        // An unset condition means ready.
+        .filter(|e| e["conditions"]["ready"].as_bool() != Some(false))
+        .flat_map(|e| e["addresses"].as_array().into_iter().flatten())
+        .filter_map(|a| a.as_str()?.parse::<IpAddr>().ok())
+        .map(|ip| match ip {
+            IpAddr::V4(ip) => format!("http://{ip}:{port}"),
+            IpAddr::V6(ip) => format!("http://[{ip}]:{port}"),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::balancer::OutlierSettings;
+
+    fn slice(name: &str, ready: &[(&str, Option<bool>)]) -> serde_json::Value {
+        serde_json::json!({
+            "metadata": {"name": name, "resourceVersion": "42"},
+            "ports": [{"name": "metrics", "port": 9090}, {"name": "http", "port": 8080}],
+            "endpoints": ready.iter().map(|(ip, ready)| serde_json::json!({
+                "addresses": [ip],
+                "conditions": ready.map_or(serde_json::json!({}), |r| serde_json::json!({"ready": r})),
+            })).collect::<Vec<_>>(),
+        })
+    }
+
+    #[test]
+    fn ready_endpoints_on_the_chosen_port() {
+        let s = slice(
+            "web-a",
+            &[
+                ("10.0.0.1", Some(true)),
+                ("10.0.0.2", Some(false)),
+                ("fd00::3", None),
+            ],
+        );
+        assert_eq!(
+            endpoints(&s, Some(&PortSelector::Name("http".to_string()))),
+            ["http://10.0.0.1:8080", "http://[fd00::3]:8080"]
+        );
+        assert_eq!(
+            endpoints(&s, None),
+            ["http://10.0.0.1:9090", "http://[fd00::3]:9090"]
+        );
+        assert!(endpoints(&s, Some(&PortSelector::Number(1))).is_empty());
+    }
+
+    #[test]
+    fn watch_events_update_the_pool() {
+        let pool = Balancer::new(Vec::new(), OutlierSettings::default());
+        let mut watch = State {
+            name: "default/web".to_string(),
+            port: Some(PortSelector::Name("http".to_string())),
+            slices: Slices::new(),
+            resource_version: "1".to_string(),
+        };
+        let event = |kind: &str, object: serde_json::Value| serde_json::json!({"type": kind, "object": object});
+        let names = |pool: &Balancer<PoolMember>| -> Vec<String> {
+            pool.members().iter().map(|m| m.name.clone()).collect()
+        };
+
+        for e in [
+            event("ADDED", slice("web-a", &[("10.0.0.1", Some(true))])),
+            event("ADDED", slice("web-b", &[("10.0.0.2", None)])),
+        ] {
+            assert!(watch.handle(&e).unwrap());
+        }
+        watch.apply(&pool);
+        assert_eq!(
+            names(&pool),
+            ["http://10.0.0.1:8080", "http://10.0.0.2:8080"]
+        );
+        assert_eq!(watch.resource_version, "42");
+
+        watch
+            .handle(&event(
+                "MODIFIED",
+                slice("web-a", &[("10.0.0.1", Some(false))]),
+            ))
+            .unwrap();
+        assert!(!watch
+            .handle(&event("BOOKMARK", serde_json::json!({})))
+            .unwrap());
+        watch.apply(&pool);
+        assert_eq!(names(&pool), ["http://10.0.0.2:8080"]);
+
+        assert!(watch
+            .handle(&event("DELETED", slice("web-b", &[])))
+            .unwrap());
+        watch.apply(&pool);
+        assert!(pool.pick().is_none());
+
+        let gone = event(
+            "ERROR",
+            serde_json::json!({"code": 410, "message": "too old resource version"}),
+        );
+        assert!(watch.handle(&gone).is_err());
+    }
+}
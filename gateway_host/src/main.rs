@@ -1,174 +1,1525 @@
+mod access_rules;
+mod audit;
+mod basic_auth;
+mod batch;
+mod blue_green;
+mod cache;
+mod cgroup;
+mod coalesce;
+mod coldstart;
+mod component;
+mod compose;
+mod deadline;
+mod debug_headers;
+mod disk_cache;
+mod early_hints;
+mod error_pages;
+mod errors;
+mod experiments;
+mod geoip;
+mod idempotency;
+mod kubernetes;
+mod metrics;
+mod module_verify;
+mod normalize;
+mod oauth;
+mod priority;
+mod procs;
+mod range;
+mod ratelimit;
+mod redirect;
+mod reload;
+mod sandbox;
+mod schema;
+mod signature;
+mod slo;
+mod tls_listener;
+mod transform;
+mod upstream_signing;
+mod upstream_tls;
+mod user_agent;
+mod versions;
+mod waf;
+mod well_known;
+mod workers;
+
 use anyhow::{anyhow, Context, Result};
 use once_cell::sync::Lazy;
 use sha2::{Digest, Sha256};
 use std::collections::HashMap;
 use std::env;
 use std::io::{Read, Write};
-use std::net::{Shutdown, TcpListener, TcpStream};
+use std::net::{IpAddr, Shutdown, TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
-use std::sync::{Arc, RwLock};
-use std::sync::atomic::{AtomicU64, Ordering};
-use std::time::{Duration, Instant};
-use url::Url;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use uuid::Uuid;
-use wasmtime::{Engine, Linker, Module, Store};
+use wasmtime::{Engine, Instance, InstancePre, Linker, Module, Store, Trap};
 use wasmtime_wasi::p1::{self, WasiP1Ctx};
 use wasmtime_wasi::p2::pipe::{MemoryInputPipe, MemoryOutputPipe};
 use wasmtime_wasi::{I32Exit, WasiCtxBuilder};
 
-const MAX_HEADER_BYTES: usize = 64 * 1024;
-const MAX_REQ_BODY_BYTES: usize = 2 * 1024 * 1024;
-const MAX_RESP_BYTES: usize = 10 * 1024 * 1024;
-const IO_TIMEOUT: Duration = Duration::from_secs(5);
-const GATEWAY_VARIANT: &str = "wasm-host";
+use gateway_core::http::{
+    self, bearer_token_matches, build_forwarded_request, build_response, find_double_crlf,
+    forwarded_target, parse_request_head, parse_status_code_from_head, parse_upstream, query_param,
+    read_all_response, read_http_body, read_http_head, route_path, split_http_response,
+    RequestLine, Upstream, IO_TIMEOUT,
+};
+use gateway_core::{
+    accept, affinity, allocator, balancer, chunked, cookies, dns, fingerprint, headers, health,
+    json_string, logging, profiling, registry, secrets, state, store, syntax, workloads,
+};
+use headers::Headers;
+use tls_listener::ClientStream;
 
-static COUNTER: Lazy<AtomicU64> = Lazy::new(|| AtomicU64::new(0));
+const GATEWAY_VARIANT: &str = "wasm-host";
+/// How often the embedded engine's epoch advances, so how far past its
+/// deadline an embedded call may run before it is interrupted.
+const EPOCH_TICK: Duration = Duration::from_millis(10);
+
+/// `FRAMING_AUDIT=1`: every response written by `respond` is re-checked
+/// against its own framing, and mismatches are logged and counted.
+pub(crate) static FRAMING_AUDIT: Lazy<bool> =
+    Lazy::new(|| env::var("FRAMING_AUDIT").is_ok_and(|v| v == "1"));
 static WASMTIME_EMBEDDED_CACHE: Lazy<RwLock<HashMap<String, Arc<EmbeddedWasmtime>>>> =
     Lazy::new(|| RwLock::new(HashMap::new()));
 
-#[derive(Debug)]
 struct EmbeddedWasmtime {
     engine: Engine,
     module: Module,
+    /// The module linked against WASI once, so a call only creates a store
+    /// and instantiates.
+    pre: InstancePre<WasiP1Ctx>,
+    /// The module exports `gateway_alloc` and no `_start`, and is called
+    /// through linear memory instead of with stdio.
+    reactor: bool,
+    /// The reactor exports `gateway_env`, so a warm instance can be handed
+    /// each request's `GATEWAY_*` variables.
+    env_export: bool,
+    /// The module imports WASI `environ_get` and may read its environment.
+    reads_env: bool,
+    /// Idle reactor instances kept between calls (`WASM_EXECUTION=embedded`).
+    warm: Mutex<Vec<WarmInstance>>,
 }
 
-fn cpu_heavy(iters: u64) -> String {
-    let mut hash = [0u8; 32];
+/// A reactor instance and the store it lives in, reused across calls.
+struct WarmInstance {
+    store: Store<WasiP1Ctx>,
+    instance: Instance,
+    stderr: MemoryOutputPipe,
+    /// Bytes of `stderr` already logged.
+    logged: usize,
+    calls: u64,
+}
 
-    for i in 0..iters {
-        let mut hasher = Sha256::new();
-        hasher.update(hash);
-        hasher.update(i.to_le_bytes());
-        hash = hasher.finalize().into();
+impl EmbeddedWasmtime {
+    fn new(engine: Engine, module: Module) -> Result<Self> {
+        let exports_func =
+            |name: &str| matches!(module.get_export(name), Some(wasmtime::ExternType::Func(_)));
+        let reactor = exports_func(REACTOR_ALLOC_EXPORT) && !exports_func("_start");
+        let env_export = exports_func(REACTOR_ENV_EXPORT);
+        let reads_env = module
+            .imports()
+            .any(|import| import.name() == "environ_get");
+        let mut linker: Linker<WasiP1Ctx> = Linker::new(&engine);
+        p1::add_to_linker_sync(&mut linker, |ctx| ctx)
+            .context("failed to add WASI preview1 imports for embedded runtime")?;
+        let pre = linker
+            .instantiate_pre(&module)
+            .context("failed to link embedded module against WASI preview1")?;
+        Ok(EmbeddedWasmtime {
+            engine,
+            module,
+            pre,
+            reactor,
+            env_export,
+            reads_env,
+            warm: Mutex::new(Vec::new()),
+        })
     }
-
-    hex::encode(hash)
 }
 
-// simple query parser for /compute?iters=123
-fn query_param(path: &str, key: &str) -> Option<String> {
-    let q = path.splitn(2, '?').nth(1)?;
-    for pair in q.split('&') {
-        let mut it = pair.splitn(2, '=');
-        let k = it.next().unwrap_or("");
-        let v = it.next().unwrap_or("");
-        if k == key {
-            return Some(v.to_string());
-        }
+impl std::fmt::Debug for EmbeddedWasmtime {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EmbeddedWasmtime")
+            .field("module", &self.module)
+            .field("reactor", &self.reactor)
+            .field("env_export", &self.env_export)
+            .field(
+                "warm",
+                &self.warm.lock().unwrap_or_else(|e| e.into_inner()).len(),
+            )
+            .finish_non_exhaustive()
     }
-    None
 }
 
+/// Reactor ABI: `gateway_alloc(len: i32) -> i32` reserves space for the input,
+/// `transform(ptr: i32, len: i32) -> i64` (or any export with that signature
+/// named in `WASM_ROUTE_EXPORTS`) returns `(out_ptr << 32) | out_len`. A warm
+/// instance gets the request's variables before each call through the
+/// optional `gateway_env(ptr: i32, len: i32)`, as `KEY=VALUE` entries each
+/// ending in a NUL byte.
+const REACTOR_ALLOC_EXPORT: &str = "gateway_alloc";
+const REACTOR_TRANSFORM_EXPORT: &str = "transform";
+const REACTOR_ENV_EXPORT: &str = "gateway_env";
+
 fn main() -> Result<()> {
+    gateway_core::set_variant(GATEWAY_VARIANT);
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("coldstart") {
+        return coldstart::run(&args[1..]);
+    }
+    let reload_settings = reload::init_from_env()?;
+    logging::init_from_env()?;
     env_logger::init();
+    secrets::init_from_env()?;
+    procs::init_from_env()?;
+    priority::init_from_env()?;
+    slo::init_from_env()?;
+    coalesce::init_from_env();
+    debug_headers::init_from_env();
+    cache::init_from_env()?;
+    idempotency::init_from_env()?;
+    well_known::init_from_env()?;
+    normalize::init_from_env()?;
+    access_rules::init_from_env()?;
+    deadline::init_from_env()?;
+    health::mark_started();
+    accept::raise_nofile_limit();
+    affinity::pin_process_from_env()?;
 
     let listen = env::var("LISTEN").unwrap_or_else(|_| "0.0.0.0:8080".to_string());
-    let upstream_url =
-        env::var("UPSTREAM_URL").unwrap_or_else(|_| "http://127.0.0.1:18080".to_string());
-    let wasm_module_path =
-        env::var("WASM_MODULE_PATH").unwrap_or_else(|_| "./gateway_logic.wasm".to_string());
-    let wasm_runtime = env::var("WASM_RUNTIME").unwrap_or_else(|_| "wasmedge".to_string());
-    if wasm_runtime != "wasmedge"
-        && wasm_runtime != "wasmtime"
-        && wasm_runtime != "wasmtime_embedded"
-    {
+    let listen_internal = env::var("LISTEN_INTERNAL").ok().filter(|v| !v.is_empty());
+    let listen_native = env::var("LISTEN_NATIVE").ok().filter(|v| !v.is_empty());
+    let listen_tls = env::var("LISTEN_TLS").ok().filter(|v| !v.is_empty());
+    let listen_redirect = env::var("LISTEN_REDIRECT").ok().filter(|v| !v.is_empty());
+    // Where redirected clients go: `LISTEN_TLS`'s port unless overridden.
+    let redirect_https_port = match env::var("REDIRECT_HTTPS_PORT") {
+        Ok(v) => v
+            .parse::<u16>()
+            .with_context(|| format!("invalid REDIRECT_HTTPS_PORT={v}"))?,
+        Err(_) => listen_tls
+            .as_deref()
+            .and_then(|addr| addr.rsplit_once(':'))
+            .and_then(|(_, port)| port.parse().ok())
+            .unwrap_or(443),
+    };
+    let discovery = env::var("DISCOVERY").ok().filter(|v| !v.is_empty());
+    let discovered_pool = match discovery.as_deref() {
+        Some("kubernetes") => Some(Arc::new(balancer::Balancer::new(
+            Vec::new(),
+            balancer::OutlierSettings::from_env()?,
+        ))),
+        _ => None,
+    };
+    let live = LiveConfig::build(&|name| env::var(name).ok(), None, discovered_pool.clone())?;
+    let wasm_module_path = live.wasm_module_path.clone();
+    let wasm_runtime = transform::runtime_from_env()?;
+    let verifier = module_verify::ModuleVerifier::from_env()?;
+    let route_exports = parse_route_exports()?;
+    let loader = {
+        let wasm_runtime = wasm_runtime.clone();
+        let route_exports = route_exports.clone();
+        move |module_path: &str| -> Result<Box<dyn transform::Transform>> {
+            let transform = transform::from_env(&wasm_runtime, module_path)?;
+            if !route_exports.is_empty() {
+                check_route_exports(transform.as_ref(), module_path, &route_exports)?;
+            }
+            Ok(transform)
+        }
+    };
+    geoip::init_from_env(parse_pool_member)?;
+    user_agent::init_from_env(parse_pool_member)?;
+    let wasm_enabled = Arc::new(AtomicBool::new(live.wasm_enabled));
+    let loads_module = transform::loads_module(&transform::backend_from_env(&wasm_runtime));
+    waf::init_from_env(loads_module)?;
+    experiments::init_from_env(parse_pool_member, |module_path| {
+        if !loads_module {
+            return Err(anyhow!(
+                "module variants need a module-backed TRANSFORM_BACKEND"
+            ));
+        }
+        if let Some(verifier) = verifier.as_ref() {
+            verifier.verify(module_path)?;
+        }
+        Ok(Box::new(transform::Switchable {
+            inner: loader(module_path)?,
+            enabled: Arc::clone(&wasm_enabled),
+        }))
+    })?;
+    // Module-backed transforms go through the version history so
+    // `/admin/wasm/*` can swap them at runtime.
+    let (transform, versions): (Box<dyn transform::Transform>, _) = if loads_module {
+        let versions = Arc::new(versions::ModuleVersions::load_initial(
+            &wasm_module_path,
+            Box::new(loader),
+            verifier,
+        )?);
+        (Box::new(Arc::clone(&versions)), Some(versions))
+    } else {
+        if let Some(verifier) = verifier.as_ref() {
+            verifier.verify(&wasm_module_path)?;
+        }
+        (loader(&wasm_module_path)?, None)
+    };
+    let transform: Box<dyn transform::Transform> =
+        if env::var("WASM_DRY_RUN").is_ok_and(|v| v == "1") {
+            eprintln!("[wasm-host] dry-run: transform output is discarded");
+            Box::new(transform::DryRun { inner: transform })
+        } else {
+            transform
+        };
+    if listen_native.is_some() && transform.name() == "native" {
         return Err(anyhow!(
-            "invalid WASM_RUNTIME={wasm_runtime} (expected: wasmedge|wasmtime|wasmtime_embedded)"
+            "LISTEN_NATIVE compares against the native backend; TRANSFORM_BACKEND is already native"
         ));
     }
-    if wasm_runtime == "wasmtime_embedded" {
-        get_or_compile_embedded_wasmtime(&wasm_module_path).with_context(|| {
-            format!("failed to initialize embedded Wasmtime with module {wasm_module_path}")
-        })?;
-    }
+    let transform = Box::new(transform::Switchable {
+        inner: transform,
+        enabled: Arc::clone(&wasm_enabled),
+    });
+
+    let health_token = secrets::lookup("HEALTH_TOKEN");
+    let admin_token = secrets::lookup("ADMIN_TOKEN");
+    let upstream_signing = upstream_signing::Signer::from_env()?;
+    let signature = match secrets::lookup("HMAC_SECRET") {
+        Some(secret) => Some(signature::SignatureConfig {
+            secret,
+            max_skew_secs: env::var("HMAC_MAX_SKEW_SECS")
+                .ok()
+                .map(|v| {
+                    v.parse::<u64>()
+                        .with_context(|| format!("invalid HMAC_MAX_SKEW_SECS={v}"))
+                })
+                .transpose()?
+                .unwrap_or(300),
+            routes: env::var("HMAC_ROUTES")
+                .map(|v| {
+                    v.split(',')
+                        .map(str::trim)
+                        .filter(|p| !p.is_empty())
+                        .map(str::to_string)
+                        .collect()
+                })
+                .unwrap_or_default(),
+        }),
+        _ => None,
+    };
+    let oauth = oauth::OAuthConfig::from_env()?;
+    let error_pages = error_pages::ErrorPages::from_env()?;
+    let early_hints = early_hints::EarlyHints::from_env()?;
+    let basic_auth = basic_auth::BasicAuthConfig::from_env()?;
+    let store = store::SharedStore::from_env()?;
+    let cookies = cookies::CookieConfig::from_env()?;
+    let audit = audit::AuditLog::from_env()?;
+    let state = state::State::from_env()?;
+    let registration = registry::Registration::from_env(&listen, &state.replica_id)?;
+    let schema_routes = match env::var("SCHEMA_ROUTES") {
+        Ok(spec) => schema::load_routes(&spec)?,
+        Err(_) => Vec::new(),
+    };
 
-    let upstream = parse_upstream(&upstream_url)?;
+    let blue_green = blue_green::BlueGreen::from_env(|spec| {
+        parse_upstream_pool(&dns::expand_pool(&balancer::parse_pool(spec)?)?)
+    })?;
+    if blue_green.is_some() && live.pool.is_some() {
+        return Err(anyhow!(
+            "UPSTREAM_BLUE / UPSTREAM_GREEN cannot be set with UPSTREAM_POOL or DISCOVERY=kubernetes"
+        ));
+    }
+    let kubernetes = discovered_pool
+        .as_deref()
+        .map(kubernetes::Watch::start)
+        .transpose()?;
+    let routes = match discovery.as_deref() {
+        Some("compose") => compose::discover()?,
+        Some("kubernetes") | None => Vec::new(),
+        Some(v) => {
+            return Err(anyhow!(
+                "invalid DISCOVERY={v} (expected compose or kubernetes)"
+            ))
+        }
+    };
+    let tls_termination = tls_listener::TlsTermination::from_env()?;
+
+    let config = Config {
+        live: RwLock::new(Arc::new(live)),
+        routes,
+        blue_green,
+        wasm_runtime,
+        transform,
+        native_transform: listen_native
+            .is_some()
+            .then(|| Box::new(transform::NativePrefix::from_env()) as _),
+        internal_listener: listen_internal.is_some(),
+        wasm_enabled,
+        versions,
+        health_token,
+        admin_token,
+        static_dir: PathBuf::from(
+            env::var("STATIC_DIR").unwrap_or_else(|_| "./static".to_string()),
+        ),
+        schema_routes,
+        route_exports,
+        signature,
+        upstream_signing,
+        oauth,
+        error_pages,
+        early_hints,
+        basic_auth,
+        store,
+        cookies,
+        state,
+        audit,
+    };
     let listener = TcpListener::bind(&listen).with_context(|| format!("bind LISTEN={listen}"))?;
+    let internal_listener = listen_internal
+        .as_deref()
+        .map(|addr| TcpListener::bind(addr).with_context(|| format!("bind LISTEN_INTERNAL={addr}")))
+        .transpose()?;
+    let native_listener = listen_native
+        .as_deref()
+        .map(|addr| TcpListener::bind(addr).with_context(|| format!("bind LISTEN_NATIVE={addr}")))
+        .transpose()?;
+    let tls_listener = listen_tls
+        .as_deref()
+        .map(|addr| TcpListener::bind(addr).with_context(|| format!("bind LISTEN_TLS={addr}")))
+        .transpose()?;
+    let redirect_listener = listen_redirect
+        .as_deref()
+        .map(|addr| TcpListener::bind(addr).with_context(|| format!("bind LISTEN_REDIRECT={addr}")))
+        .transpose()?;
 
     eprintln!("[wasm-host] listening on http://{listen}");
-    eprintln!("[wasm-host] forwarding to {upstream_url}");
-    eprintln!("[wasm-host] wasm module: {wasm_module_path}");
-    eprintln!("[wasm-host] wasm runtime: {wasm_runtime}");
-
-    for incoming in listener.incoming() {
-        match incoming {
-            Ok(mut client) => {
-                if let Err(e) =
-                    handle_client(&mut client, &upstream, &wasm_module_path, &wasm_runtime)
-                {
-                    eprintln!("[wasm-host] client error: {e:#}");
+    if let Some(addr) = listen_internal.as_deref() {
+        eprintln!("[wasm-host] operational endpoints on http://{addr}");
+    }
+    if let (Some(addr), Some(native)) = (listen_native.as_deref(), config.native_transform.as_ref())
+    {
+        eprintln!(
+            "[wasm-host] side by side: {} pipeline on http://{addr}",
+            native.name()
+        );
+        metrics::side_by_side([config.transform.name(), native.name()]);
+    }
+    if let (Some(addr), Some(tls)) = (listen_tls.as_deref(), tls_termination.as_ref()) {
+        eprintln!(
+            "[wasm-host] listening on https://{addr} (certificates: {})",
+            tls.names.join(", ")
+        );
+    }
+    if let Some(addr) = listen_redirect.as_deref() {
+        eprintln!("[wasm-host] redirecting http://{addr} to https (port {redirect_https_port})");
+    }
+    let live = config.live();
+    let members: Vec<String> = live.pool.as_ref().map_or_else(Vec::new, |pool| {
+        pool.members()
+            .iter()
+            .map(|m| format!("{} (weight {})", m.name, m.weight))
+            .collect()
+    });
+    if let Some(blue_green) = config.blue_green.as_ref() {
+        for color in blue_green::COLORS {
+            let members: Vec<String> = blue_green
+                .set(color)
+                .map(|set| set.members().iter().map(|m| m.name.clone()).collect())
+                .unwrap_or_default();
+            eprintln!("[wasm-host] {color} set: {}", members.join(", "));
+        }
+        eprintln!("[wasm-host] live traffic on {}", blue_green.active());
+    } else if members.is_empty() {
+        eprintln!("[wasm-host] forwarding to {}", live.upstream.raw_url);
+    } else {
+        eprintln!("[wasm-host] balancing over {}", members.join(", "));
+    }
+    for vhost in &live.vhosts {
+        eprintln!(
+            "[wasm-host] forwarding Host {} to {}",
+            vhost.host, vhost.upstream.raw_url
+        );
+    }
+    if let Some(tls) = live.upstream_tls.as_ref() {
+        eprintln!(
+            "[wasm-host] upstream TLS: {}",
+            if tls.client_auth() {
+                "client certificate"
+            } else {
+                "server authentication only"
+            }
+        );
+    }
+    if config.basic_auth.is_none() && config.admin_token.is_none() && config.health_token.is_none()
+    {
+        eprintln!(
+            "[wasm-host] /admin/* and /debug/* are disabled; set ADMIN_TOKEN or BASIC_AUTH_USERS"
+        );
+    }
+    eprintln!("[wasm-host] wasm module: {}", live.wasm_module_path);
+    eprintln!("[wasm-host] wasm runtime: {}", config.wasm_runtime);
+    eprintln!("[wasm-host] transform backend: {}", config.transform.name());
+    eprintln!(
+        "[wasm-host] replica {} state backend: {:?} (store: {})",
+        config.state.replica_id,
+        config.state.backend,
+        config.store.kind()
+    );
+
+    std::thread::scope(|scope| {
+        if let Some(internal) = internal_listener.as_ref() {
+            let config = &config;
+            scope.spawn(move || serve(internal, config, Listener::Internal, None));
+        }
+        if let (Some(listener), Some(tls)) = (tls_listener.as_ref(), tls_termination.as_ref()) {
+            let config = &config;
+            scope.spawn(move || serve(listener, config, Listener::Workload, Some(tls)));
+        }
+        if let Some(listener) = native_listener.as_ref() {
+            let config = &config;
+            scope.spawn(move || serve(listener, config, Listener::Native, None));
+        }
+        if let Some(listener) = redirect_listener.as_ref() {
+            scope.spawn(move || redirect::serve(listener, redirect_https_port));
+        }
+        if let (Some(watch), Some(pool)) = (kubernetes, discovered_pool.as_deref()) {
+            scope.spawn(move || watch.run(pool));
+        }
+        if let Some(settings) = reload_settings {
+            let config = &config;
+            scope.spawn(move || settings.run(|var| reload_config(config, var)));
+        }
+        if let Some(registration) = registration.as_ref() {
+            let config = &config;
+            scope.spawn(move || registration.run(|| health_report(config)));
+        }
+        serve(&listener, &config, Listener::Workload, None);
+    });
+
+    Ok(())
+}
+
+/// What a listener serves.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Listener {
+    /// `LISTEN` and `LISTEN_TLS`: every route, through `transform`.
+    Workload,
+    /// `LISTEN_NATIVE`: every route, through `native_transform`.
+    Native,
+    /// `LISTEN_INTERNAL`: operational routes only, left out of `/metrics`.
+    Internal,
+}
+
+/// Accept loop for one listener. Internal connections get a thread each, so
+/// a long `/debug/pprof/profile` does not hold up health checks and scrapes;
+/// so do workload ones under `MAX_INFLIGHT` (see `priority`). `tls`
+/// terminates TLS (`LISTEN_TLS`).
+fn serve(
+    listener: &TcpListener,
+    config: &Config,
+    kind: Listener,
+    tls: Option<&tls_listener::TlsTermination>,
+) {
+    let mut backoff = accept::AcceptBackoff::default();
+    std::thread::scope(|scope| {
+        for incoming in listener.incoming() {
+            match incoming {
+                Ok(client) if kind == Listener::Internal || priority::configured().is_some() => {
+                    backoff.accepted();
+                    scope.spawn(move || serve_connection(client, config, kind, tls));
                 }
+                Ok(client) => {
+                    backoff.accepted();
+                    serve_connection(client, config, kind, tls);
+                }
+                Err(e) => backoff.failed(&e),
+            }
+        }
+    });
+}
+
+fn serve_connection(
+    tcp: TcpStream,
+    config: &Config,
+    kind: Listener,
+    tls: Option<&tls_listener::TlsTermination>,
+) {
+    let start = Instant::now();
+    let internal = kind == Listener::Internal;
+    let mut client = match tls {
+        Some(tls) => match tls.accept(tcp) {
+            Ok(client) => client,
+            Err(e) => {
+                eprintln!("[wasm-host] client error: {e:#}");
+                return;
             }
-            Err(e) => eprintln!("[wasm-host] accept error: {e}"),
+        },
+        None => ClientStream::Plain(tcp),
+    };
+    let mut trace = RequestTrace {
+        native: kind == Listener::Native,
+        ..RequestTrace::default()
+    };
+    if let Err(e) = handle_client(&mut client, config, &mut trace, internal) {
+        eprintln!("[wasm-host] client error: {e:#}");
+    }
+    let latency = start.elapsed();
+    trace.debug.log(
+        &trace.req_id,
+        "done",
+        format_args!("status {}, {} us", trace.status, latency.as_micros()),
+    );
+    if !internal {
+        metrics::METRICS.record(&trace, latency);
+        if let Some(slo) = slo::configured() {
+            slo.record(trace.status, latency);
+        }
+    }
+    if let Some(audit) = config.audit.as_ref() {
+        audit.record(&trace, latency);
+    }
+}
+
+#[derive(Debug)]
+struct Config {
+    /// What a `CONFIG_FILE` reload can change; see `Config::live`.
+    live: RwLock<Arc<LiveConfig>>,
+    /// `/<service>` prefixes from `DISCOVERY=compose`.
+    routes: Vec<compose::Route>,
+    /// `UPSTREAM_BLUE` / `UPSTREAM_GREEN`, flipped by `/admin/switch`; used
+    /// where `pool` would be.
+    blue_green: Option<blue_green::BlueGreen<PoolMember>>,
+    wasm_runtime: String,
+    /// Backend every body goes through (`TRANSFORM_BACKEND`, default `WASM_RUNTIME`).
+    transform: Box<dyn transform::Transform>,
+    /// The `native` backend used instead on `LISTEN_NATIVE`.
+    native_transform: Option<Box<dyn transform::Transform>>,
+    /// `LISTEN_INTERNAL` is set: operational routes are served there only.
+    internal_listener: bool,
+    /// Off switch for `transform` (`WASM_ENABLED`, `/admin/wasm/enabled`).
+    wasm_enabled: Arc<AtomicBool>,
+    /// Loaded module versions behind `transform`, for wasm backends.
+    versions: Option<Arc<versions::ModuleVersions>>,
+    /// When set, `/health/full` requires `Authorization: Bearer <token>`.
+    health_token: Option<secrets::Secret>,
+    /// Bearer token for `/admin/*` and `/debug/*`; `HEALTH_TOKEN` when unset.
+    admin_token: Option<secrets::Secret>,
+    /// Templates served by `/render/{name}` (`STATIC_DIR`).
+    static_dir: PathBuf,
+    /// Request-body JSON Schemas for proxied routes, longest prefix first.
+    schema_routes: Vec<schema::SchemaRoute>,
+    /// Reactor export called per route prefix (`WASM_ROUTE_EXPORTS`), longest
+    /// prefix first; unmatched routes call `transform`.
+    route_exports: Vec<(String, String)>,
+    /// Inbound HMAC signature verification, enabled by `HMAC_SECRET`.
+    signature: Option<signature::SignatureConfig>,
+    /// Signs forwarded requests (`UPSTREAM_SIGNING`).
+    upstream_signing: Option<upstream_signing::Signer>,
+    /// Bearer token introspection, enabled by `OAUTH_INTROSPECTION_URL`.
+    oauth: Option<oauth::OAuthConfig>,
+    /// `ERROR_PAGES_DIR` templates for gateway-originated errors.
+    error_pages: Option<error_pages::ErrorPages>,
+    /// `103 Early Hints` per route (`EARLY_HINTS`).
+    early_hints: Option<early_hints::EarlyHints>,
+    /// HTTP Basic credentials for admin endpoints and `BASIC_AUTH_ROUTES`.
+    basic_auth: Option<basic_auth::BasicAuthConfig>,
+    /// Counters shared across replicas (`SHARED_STORE_URL`), in-process otherwise.
+    store: store::SharedStore,
+    /// Cookie drop / Set-Cookie rewrite rules (`COOKIE_*`).
+    cookies: Option<cookies::CookieConfig>,
+    /// The `/state` counter (`STATE_BACKEND`) and this replica's id.
+    state: state::State,
+    /// SQLite request audit log, enabled by `AUDIT_DB`.
+    audit: Option<audit::AuditLog>,
+}
+
+/// Settings `CONFIG_FILE` reloads swap as one (see `reload::RELOADABLE`).
+#[derive(Debug)]
+struct LiveConfig {
+    upstream: Upstream,
+    /// Set for an `https://` upstream.
+    upstream_tls: Option<upstream_tls::UpstreamTls>,
+    /// Per-`Host` upstreams (`VHOST_UPSTREAMS`); others go to `routes`,
+    /// `pool`, else to `upstream`.
+    vhosts: Vec<VirtualHost>,
+    /// `UPSTREAM_POOL` or `DISCOVERY=kubernetes`, balanced with outlier
+    /// ejection.
+    pool: Option<Arc<balancer::Balancer<PoolMember>>>,
+    /// The `UPSTREAM_POOL` that built `pool`; a reload that leaves it as is
+    /// keeps the pool and its outlier state.
+    pool_spec: Option<String>,
+    wasm_module_path: String,
+    /// `WASM_ENABLED`; `/admin/wasm/enabled` flips `Config::wasm_enabled`.
+    wasm_enabled: bool,
+    /// `WASM_AUDIT_HEADERS=1`: add `X-Wasm-Module`, `X-Wasm-Runtime` and
+    /// `X-Wasm-Duration-Ms` to transformed responses.
+    audit_headers: bool,
+    /// Default number of threads used by `/transform/batch` (`?parallel=N` overrides).
+    batch_parallelism: usize,
+    /// Per-route / per-key request limits, enabled by `RATE_LIMIT_ROUTES`.
+    rate_limit: Option<ratelimit::RateLimitConfig>,
+}
+
+impl LiveConfig {
+    /// Reads the reloadable settings through `var`. `previous` lends its pool
+    /// when `UPSTREAM_POOL` is unchanged; `discovered` is the
+    /// `DISCOVERY=kubernetes` pool.
+    fn build(
+        var: &dyn Fn(&str) -> Option<String>,
+        previous: Option<&LiveConfig>,
+        discovered: Option<Arc<balancer::Balancer<PoolMember>>>,
+    ) -> Result<LiveConfig> {
+        let pool_spec = var("UPSTREAM_POOL").filter(|v| !v.trim().is_empty());
+        if pool_spec.is_some() && discovered.is_some() {
+            return Err(anyhow!(
+                "UPSTREAM_POOL and DISCOVERY=kubernetes cannot both be set"
+            ));
+        }
+        if pool_spec.is_none() && dns::configured() && var("UPSTREAM_BLUE").is_none() {
+            return Err(anyhow!("DNS_SERVER is set but UPSTREAM_POOL is not"));
+        }
+        let pool = match (discovered, previous) {
+            (Some(pool), _) => Some(pool),
+            (None, Some(previous)) if previous.pool_spec == pool_spec => previous.pool.clone(),
+            // `dns+` / `srv+` members are resolved here, once per change.
+            (None, _) => pool_spec
+                .as_deref()
+                .map(|spec| parse_upstream_pool(&dns::expand_pool(&balancer::parse_pool(spec)?)?))
+                .transpose()?
+                .map(Arc::new),
+        };
+        // With a pool, `UPSTREAM_URL` (still used by `/echo` and the
+        // `/health/full` probe) defaults to its first member.
+        let upstream_url = var("UPSTREAM_URL").unwrap_or_else(|| {
+            pool.as_ref()
+                .filter(|_| pool_spec.is_some())
+                .and_then(|pool| pool.members().first().map(|m| m.target.0.raw_url.clone()))
+                .unwrap_or_else(|| "http://127.0.0.1:18080".to_string())
+        });
+        let upstream = parse_upstream(&upstream_url)?;
+        let upstream_tls = upstream_tls::UpstreamTls::from_env(&upstream)?;
+        let vhosts = match var("VHOST_UPSTREAMS") {
+            Some(spec) => parse_vhosts(&spec)?,
+            None => Vec::new(),
+        };
+        if upstream_tls.is_none()
+            && vhosts.iter().all(|v| v.upstream_tls.is_none())
+            && pool
+                .as_ref()
+                .is_none_or(|pool| pool.members().iter().all(|m| m.target.1.is_none()))
+            && upstream_tls::configured()
+        {
+            return Err(anyhow!("UPSTREAM_TLS_* is set but no upstream is https"));
         }
+        let wasm_enabled = match var("WASM_ENABLED").as_deref() {
+            None | Some("") | Some("true") | Some("1") => true,
+            Some("false") | Some("0") => false,
+            Some(other) => {
+                return Err(anyhow!(
+                    "invalid WASM_ENABLED={other} (expected: true|false)"
+                ))
+            }
+        };
+        let batch_parallelism = var("BATCH_PARALLELISM")
+            .map(|v| {
+                v.parse::<usize>()
+                    .with_context(|| format!("invalid BATCH_PARALLELISM={v}"))
+            })
+            .transpose()?
+            .unwrap_or(1);
+        Ok(LiveConfig {
+            upstream,
+            upstream_tls,
+            vhosts,
+            pool,
+            pool_spec,
+            wasm_module_path: var("WASM_MODULE_PATH")
+                .unwrap_or_else(|| "./gateway_logic.wasm".to_string()),
+            wasm_enabled,
+            audit_headers: var("WASM_AUDIT_HEADERS").is_some_and(|v| v == "1"),
+            batch_parallelism,
+            rate_limit: ratelimit::RateLimitConfig::from_vars(var)?,
+        })
     }
+}
 
+/// Applies a `CONFIG_FILE` reload: builds the new settings, loads a changed
+/// module, then swaps the settings in. On an error nothing changes.
+fn reload_config(config: &Config, var: &dyn Fn(&str) -> Option<String>) -> Result<()> {
+    let current = config.live();
+    // A pool without `UPSTREAM_POOL` is the Kubernetes one; it stays.
+    let discovered = current.pool.clone().filter(|_| current.pool_spec.is_none());
+    let next = LiveConfig::build(var, Some(&current), discovered)?;
+    if next.pool.is_some() && config.blue_green.is_some() {
+        return Err(anyhow!("UPSTREAM_POOL cannot be set with UPSTREAM_BLUE"));
+    }
+    if next.wasm_module_path != current.wasm_module_path {
+        if let Some(versions) = config.versions.as_ref() {
+            versions
+                .load(&next.wasm_module_path)
+                .with_context(|| format!("load WASM_MODULE_PATH={}", next.wasm_module_path))?;
+        }
+    }
+    if next.wasm_enabled != current.wasm_enabled {
+        config
+            .wasm_enabled
+            .store(next.wasm_enabled, Ordering::Relaxed);
+    }
+    *config.live.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(next);
     Ok(())
 }
 
-#[derive(Clone, Debug)]
-struct Upstream {
-    host: String,
-    port: u16,
-    base_path: String,
-    raw_url: String,
+/// Per-request metadata handed to the guest as `GATEWAY_*` WASI environment
+/// variables, alongside the body on stdin.
+#[derive(Debug, Default)]
+struct Envelope {
+    vars: Vec<(String, String)>,
+    /// Time spent in the transform backend for this request, in microseconds.
+    wasm_us: AtomicU64,
+    /// Set when a backend's failure policy served the untransformed input.
+    bypassed: AtomicBool,
+    /// Reactor export to call instead of `transform` (`WASM_ROUTE_EXPORTS`).
+    export: Option<String>,
+    /// Headers the backend added to the response, e.g. `Content-Encoding`
+    /// from a guest response envelope.
+    response_headers: Mutex<Vec<(String, String)>>,
+    /// Digests of the last dry-run transform (`WASM_DRY_RUN`).
+    dry_run: Mutex<Option<transform::DryRunDigests>>,
+    /// `X-Gateway-Debug` overrides (`DEBUG_HEADERS`).
+    debug: debug_headers::Overrides,
+    /// When the request runs out of time (`REQUEST_TIMEOUT_MS`); wasm
+    /// timeouts are clamped to it.
+    deadline: Option<Instant>,
 }
 
-fn parse_upstream(s: &str) -> Result<Upstream> {
-    let url = Url::parse(s).with_context(|| format!("invalid UPSTREAM_URL={s}"))?;
-    if url.scheme() != "http" {
-        return Err(anyhow!(
-            "only http upstream supported (got scheme {})",
-            url.scheme()
+/// What happened to one request, filled in while it is handled and read by the
+/// audit log afterwards. `status` stays 0 when no response was written.
+#[derive(Debug, Default)]
+struct RequestTrace {
+    req_id: String,
+    method: String,
+    path: String,
+    /// Request `Accept` header, for picking an error page format.
+    accept: Option<String>,
+    status: u16,
+    wasm_us: u64,
+    upstream: Option<String>,
+    upstream_timing: Option<UpstreamTiming>,
+    dry_run: Option<transform::DryRunDigests>,
+    /// Served on `LISTEN_NATIVE`.
+    native: bool,
+    /// `X-Gateway-Debug` overrides (`DEBUG_HEADERS`).
+    debug: debug_headers::Overrides,
+    /// Why the gateway answered with an error of its own, if it did.
+    error: Option<errors::GatewayError>,
+    /// The `EXPERIMENTS` variant the request was in.
+    experiment: Option<experiments::Assignment>,
+    /// See `deadline`.
+    deadline: Option<Instant>,
+}
+
+/// Upstream side of a proxied request. Times run from the connect attempt, so
+/// `ttfb_us` covers connect, send and the upstream's think time, and
+/// `total_us - ttfb_us` is the response transfer.
+#[derive(Clone, Copy, Debug, Default)]
+struct UpstreamTiming {
+    bytes_sent: u64,
+    bytes_received: u64,
+    ttfb_us: u64,
+    total_us: u64,
+}
+
+impl Envelope {
+    fn set(&mut self, key: &str, value: impl Into<String>) {
+        let name = format!("GATEWAY_{key}");
+        let value = value.into();
+        match self.vars.iter_mut().find(|(k, _)| *k == name) {
+            Some(slot) => slot.1 = value,
+            None => self.vars.push((name, value)),
+        }
+    }
+
+    fn get(&self, key: &str) -> Option<&str> {
+        let name = format!("GATEWAY_{key}");
+        self.vars
+            .iter()
+            .find(|(k, _)| *k == name)
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+/// Echoes what a guest wrote to stderr into the host log, one line at a time,
+/// tagged with the request id so host and guest lines can be joined.
+fn log_guest_stderr(envelope: &Envelope, output: &[u8]) {
+    let req_id = envelope.get("REQ_ID").unwrap_or("-");
+    for line in String::from_utf8_lossy(output)
+        .lines()
+        .filter(|l| !l.trim().is_empty())
+    {
+        eprintln!("[wasm-host] req_id={req_id} guest: {line}");
+    }
+}
+
+/// Headers for a response whose body went through the transform: what the
+/// backend added, plus the provenance headers when `WASM_AUDIT_HEADERS=1`.
+fn response_headers(config: &Config, envelope: &Envelope) -> Vec<(String, String)> {
+    let mut headers = envelope
+        .response_headers
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .clone();
+    if config.live().audit_headers {
+        if let Some(versions) = config.versions.as_ref() {
+            let active = versions.active();
+            let name = Path::new(&active.source)
+                .file_name()
+                .map_or(active.source.clone(), |n| n.to_string_lossy().into_owned());
+            headers.push((
+                "X-Wasm-Module".to_string(),
+                format!("{name}@{}", &active.sha256[..12]),
+            ));
+        }
+        headers.push((
+            "X-Wasm-Runtime".to_string(),
+            config.transform.name().to_string(),
+        ));
+        let wasm_us = envelope.wasm_us.load(Ordering::Relaxed);
+        headers.push((
+            "X-Wasm-Duration-Ms".to_string(),
+            format!("{:.3}", wasm_us as f64 / 1000.0),
         ));
     }
-    let host = url
-        .host_str()
-        .ok_or_else(|| anyhow!("UPSTREAM_URL missing host"))?
-        .to_string();
-    let port = url
-        .port_or_known_default()
-        .ok_or_else(|| anyhow!("UPSTREAM_URL missing port"))?;
-    let base_path = url.path().trim_end_matches('/').to_string();
-    Ok(Upstream {
-        host,
-        port,
-        base_path,
-        raw_url: s.to_string(),
-    })
+    headers
+}
+
+fn header_refs(headers: &[(String, String)]) -> Vec<(&str, &str)> {
+    headers
+        .iter()
+        .map(|(k, v)| (k.as_str(), v.as_str()))
+        .collect()
+}
+
+/// A `VHOST_UPSTREAMS` entry: requests whose `Host` is `host` go to `upstream`.
+#[derive(Debug)]
+struct VirtualHost {
+    /// Lowercase, without a port.
+    host: String,
+    upstream: Upstream,
+    upstream_tls: Option<upstream_tls::UpstreamTls>,
+}
+
+/// `host=url,...`, e.g. `api.local=http://api:8080,web.local=https://web`.
+fn parse_vhosts(spec: &str) -> Result<Vec<VirtualHost>> {
+    spec.split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            let (host, url) = entry.split_once('=').ok_or_else(|| {
+                anyhow!("invalid VHOST_UPSTREAMS entry {entry:?} (expected host=url)")
+            })?;
+            let upstream = parse_upstream(url.trim())
+                .with_context(|| format!("invalid VHOST_UPSTREAMS entry {entry:?}"))?;
+            let upstream_tls = upstream_tls::UpstreamTls::from_env(&upstream)?;
+            Ok(VirtualHost {
+                host: host.trim().to_ascii_lowercase(),
+                upstream,
+                upstream_tls,
+            })
+        })
+        .collect()
+}
+
+/// An `UPSTREAM_POOL` member.
+type PoolMember = (Upstream, Option<upstream_tls::UpstreamTls>);
+
+/// A single upstream outside the pool, with its TLS settings.
+fn parse_pool_member(url: &str) -> Result<PoolMember> {
+    let upstream = parse_upstream(url)?;
+    let upstream_tls = upstream_tls::UpstreamTls::from_env(&upstream)?;
+    Ok((upstream, upstream_tls))
+}
+
+/// `(url, weight)` members, with the outlier settings from `POOL_*`.
+fn parse_upstream_pool(members: &[(String, u32)]) -> Result<balancer::Balancer<PoolMember>> {
+    let members = members
+        .iter()
+        .map(|(url, weight)| {
+            let upstream = parse_upstream(url)
+                .with_context(|| format!("invalid UPSTREAM_POOL member {url:?}"))?;
+            let upstream_tls = upstream_tls::UpstreamTls::from_env(&upstream)?;
+            Ok((upstream.raw_url.clone(), *weight, (upstream, upstream_tls)))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok(balancer::Balancer::new(
+        members,
+        balancer::OutlierSettings::from_env()?,
+    ))
+}
+
+impl Config {
+    /// The reloadable settings in effect. A request keeps the snapshot it
+    /// took for its whole life, so a reload never applies halfway through.
+    fn live(&self) -> Arc<LiveConfig> {
+        Arc::clone(&self.live.read().unwrap_or_else(|e| e.into_inner()))
+    }
+
+    /// The compose route for `target` and the target it forwards, unless
+    /// the request's `Host` has a `VHOST_UPSTREAMS` entry.
+    fn route_for(
+        &self,
+        live: &LiveConfig,
+        host: Option<&str>,
+        target: &str,
+    ) -> Option<(&compose::Route, String)> {
+        if self.routes.is_empty() || live.has_vhost(host) {
+            return None;
+        }
+        self.routes
+            .iter()
+            .find_map(|route| Some((route, route.strip(target)?)))
+    }
+}
+
+impl LiveConfig {
+    /// The `UPSTREAM_POOL` member for this request, unless its `Host` has a
+    /// `VHOST_UPSTREAMS` entry.
+    fn pool_for(&self, host: Option<&str>) -> Option<balancer::Pick<'_, PoolMember>> {
+        let pool = self.pool.as_ref()?;
+        (!self.has_vhost(host)).then(|| pool.pick()).flatten()
+    }
+
+    fn has_vhost(&self, host: Option<&str>) -> bool {
+        let name = host.map(host_name);
+        self.vhosts
+            .iter()
+            .any(|v| name.is_some_and(|n| n.eq_ignore_ascii_case(&v.host)))
+    }
+
+    /// The `VHOST_UPSTREAMS` entry for the request's `Host`, else `UPSTREAM_URL`.
+    fn upstream_for(&self, host: Option<&str>) -> (&Upstream, Option<&upstream_tls::UpstreamTls>) {
+        let name = host.map(host_name);
+        self.vhosts
+            .iter()
+            .find(|v| name.is_some_and(|n| n.eq_ignore_ascii_case(&v.host)))
+            .map_or((&self.upstream, self.upstream_tls.as_ref()), |v| {
+                (&v.upstream, v.upstream_tls.as_ref())
+            })
+    }
+}
+
+/// A `Host` header without its port; `[::1]` keeps its brackets.
+fn host_name(host: &str) -> &str {
+    match host.rsplit_once(':') {
+        Some((name, port)) if !port.contains(']') => name,
+        _ => host,
+    }
 }
 
 fn handle_client(
-    client: &mut TcpStream,
-    upstream: &Upstream,
-    wasm_module_path: &str,
-    wasm_runtime: &str,
+    client: &mut ClientStream,
+    config: &Config,
+    trace: &mut RequestTrace,
+    internal: bool,
 ) -> Result<()> {
-    client.set_read_timeout(Some(IO_TIMEOUT)).ok();
-    client.set_write_timeout(Some(IO_TIMEOUT)).ok();
+    let mut envelope = Envelope::default();
+    let result = handle_request(client, config, trace, &mut envelope, internal);
+    trace.wasm_us = envelope.wasm_us.load(Ordering::Relaxed);
+    trace.debug = envelope.debug;
+    trace.dry_run = envelope
+        .dry_run
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .take();
+    if let Err(e) = &result {
+        // Answer instead of dropping the connection, unless part of a
+        // response already went out; see `errors` for the status each kind
+        // of failure gets.
+        if trace.status == 0 {
+            // Whatever failed once the deadline passed failed because of it.
+            let kind = if deadline::expired(trace.deadline) {
+                errors::GatewayError::Deadline
+            } else {
+                errors::classify(e, !trace.method.is_empty())
+            };
+            trace.error = Some(kind);
+            let mut headers = kind.headers().to_vec();
+            if let Some(shed) = e.downcast_ref::<priority::Shed>() {
+                headers.push(("X-Priority", shed.tier.label()));
+            }
+            headers.push(("X-Gateway-Error-Code", kind.code()));
+            // The chain names upstream addresses and paths; it only goes to
+            // the log, in `serve_connection`.
+            let message = format!("{kind}\n");
+            let resp = error_response(
+                config,
+                trace,
+                kind.status_line(),
+                &message,
+                kind.workload(),
+                &headers,
+            );
+            respond_and_close(client, &resp, trace).ok();
+        }
+    }
+    result
+}
+
+fn handle_request(
+    client: &mut ClientStream,
+    config: &Config,
+    trace: &mut RequestTrace,
+    envelope: &mut Envelope,
+    internal: bool,
+) -> Result<()> {
+    let transform = match config.native_transform.as_ref() {
+        Some(native) if trace.native => native.as_ref(),
+        _ => config.transform.as_ref(),
+    };
 
     let req_id = Uuid::new_v4();
     let start = Instant::now();
+    let budget = deadline::configured();
+    trace.deadline = budget.and_then(|b| b.deadline(start, None));
+    client
+        .set_read_timeout(Some(deadline::clamp(IO_TIMEOUT, trace.deadline)))
+        .ok();
+    client.set_write_timeout(Some(IO_TIMEOUT)).ok();
+    trace.req_id = req_id.to_string();
+    let mut allocs = allocator::RequestAllocs::start();
+
+    let (head_bytes, remainder) = read_http_head(client)?;
+    let mut req = parse_request_head(&head_bytes)?;
+    if let Some(budget) = budget {
+        trace.deadline = budget.deadline(start, req.header(deadline::HEADER));
+        envelope.deadline = trace.deadline;
+        client
+            .set_read_timeout(Some(deadline::clamp(IO_TIMEOUT, trace.deadline)))
+            .ok();
+        client
+            .set_write_timeout(Some(deadline::clamp(IO_TIMEOUT, trace.deadline)))
+            .ok();
+    }
+    if let Some(normalizer) = normalize::configured() {
+        normalizer.apply(&mut req.path, &mut req.headers);
+    }
+    allocs.route(&req.path);
+    trace.method = req.method.clone();
+    trace.path = req.path.clone();
+    trace.accept = req.headers.get_joined("Accept");
+    let client_ip = client.peer_addr().ok().map(|a| a.ip());
+    let live = config.live();
+    let (upstream, upstream_tls) = live.upstream_for(req.header("Host"));
+
+    // With `LISTEN_INTERNAL`, operational routes live only on that listener
+    // and it serves nothing else; plain `/health` stays on both. `/debug/*`
+    // is never served on `LISTEN`.
+    let operational = is_operational_route(&req.path);
+    let misrouted = if internal {
+        !operational && route_path(&req.path) != "/health"
+    } else {
+        operational && (config.internal_listener || route_path(&req.path).starts_with("/debug/"))
+    };
+    if misrouted {
+        let resp = error_response(
+            config,
+            trace,
+            "HTTP/1.1 404 Not Found",
+            "not found",
+            "not-found",
+            &[],
+        );
+        respond_and_close(client, &resp, trace).ok();
+        return Ok(());
+    }
+
+    if let Some(resp) = well_known::configured()
+        .map(|w| w.response(&req.method, &req.path))
+        .transpose()?
+        .flatten()
+    {
+        return respond_and_close(client, &resp, trace);
+    }
+
+    if let Some(rules) = access_rules::configured() {
+        let header = |name: &str| req.header(name);
+        let outcome = rules.evaluate(
+            &access_rules::Request {
+                method: &req.method,
+                target: &req.path,
+                header: &header,
+                ip: client_ip,
+            },
+            &config.store,
+        );
+        if let Some(stop) = outcome.stop {
+            let (status, message, extra, line) = match stop {
+                access_rules::Stop::Deny { status, line } => {
+                    (status, "denied by access rule", None, line)
+                }
+                access_rules::Stop::Limited {
+                    line,
+                    limit,
+                    reset_secs,
+                } => (
+                    429,
+                    "rate limit exceeded",
+                    Some((limit.to_string(), reset_secs.max(1).to_string())),
+                    line,
+                ),
+            };
+            let headers: Vec<(&str, &str)> = extra
+                .iter()
+                .flat_map(|(limit, retry)| {
+                    [
+                        ("X-RateLimit-Limit", limit.as_str()),
+                        ("X-RateLimit-Remaining", "0"),
+                        ("Retry-After", retry.as_str()),
+                    ]
+                })
+                .collect();
+            let status_line = access_rules::status_line(status);
+            let resp = error_response(config, trace, &status_line, message, "rules", &headers);
+            respond_and_close(client, &resp, trace)?;
+            eprintln!(
+                "[wasm-host] req_id={} {} {} -> {status} access rule line {line}",
+                trace.req_id, req.method, req.path
+            );
+            return Ok(());
+        }
+        if let Some(target) = outcome.rewritten {
+            req.path = target;
+        }
+    }
+
+    if let Some(value) = req
+        .header(debug_headers::HEADER)
+        .filter(|_| debug_headers::enabled())
+    {
+        let overrides = debug_headers::Overrides::parse(value);
+        if !overrides.is_empty() && admin_authorized(&req, config, client_ip).is_ok() {
+            envelope.debug = overrides;
+            envelope
+                .response_headers
+                .get_mut()
+                .unwrap_or_else(|e| e.into_inner())
+                .push((debug_headers::HEADER.to_string(), overrides.echo()));
+            overrides.log(
+                &trace.req_id,
+                "request",
+                format_args!("{} {} {}", req.method, req.path, req.version),
+            );
+        }
+    }
+
+    envelope.set("REQ_ID", trace.req_id.as_str());
+    envelope.set("METHOD", req.method.as_str());
+    envelope.set("PATH", req.path.as_str());
+    envelope.set("ENVELOPE_TOKEN", Uuid::new_v4().simple().to_string());
+    envelope.export = config
+        .route_exports
+        .iter()
+        .find(|(prefix, _)| route_path(&req.path).starts_with(prefix.as_str()))
+        .map(|(_, export)| export.clone());
+    trace.experiment = experiments::configured().and_then(|experiments| {
+        let remote = client.peer_addr().map(|a| a.ip().to_string());
+        experiments.assign(
+            route_path(&req.path),
+            |name| req.header(name),
+            remote.as_deref().unwrap_or_default(),
+        )
+    });
+    if let Some(assignment) = trace.experiment {
+        let label = assignment.label();
+        envelope.set("EXPERIMENT", label.as_str());
+        envelope
+            .response_headers
+            .get_mut()
+            .unwrap_or_else(|e| e.into_inner())
+            .push((experiments::HEADER.to_string(), label));
+    }
+    let location = geoip::configured().and_then(|geoip| {
+        let ip = client.peer_addr().ok()?.ip();
+        geoip
+            .locate(ip)
+            .map_err(|e| eprintln!("[wasm-host] req_id={} {e:#}", trace.req_id))
+            .ok()
+    });
+    if let Some(location) = location.as_ref() {
+        if let Some(country) = location.country.as_deref() {
+            envelope.set("CLIENT_COUNTRY", country);
+        }
+        if let Some(asn) = location.asn {
+            envelope.set("CLIENT_ASN", asn.to_string());
+        }
+    }
+    let geo_denied = geoip::configured()
+        .zip(location.as_ref())
+        .is_some_and(|(geoip, location)| !geoip.allows(location));
+    if geo_denied && !is_operational_route(&req.path) {
+        let resp = error_response(
+            config,
+            trace,
+            "HTTP/1.1 403 Forbidden",
+            "not available in your country",
+            "geoip",
+            &[],
+        );
+        respond_and_close(client, &resp, trace)?;
+        eprintln!(
+            "[wasm-host] req_id={} {} {} -> 403 geoip: country {}",
+            trace.req_id,
+            req.method,
+            req.path,
+            location
+                .as_ref()
+                .and_then(|l| l.country.as_deref())
+                .unwrap_or("unknown")
+        );
+        return Ok(());
+    }
+    let device = user_agent::configured()
+        .map(|devices| (devices, user_agent::classify(req.header("User-Agent"))));
+    if let Some((devices, class)) = device {
+        envelope.set("DEVICE_CLASS", class.label());
+        if !devices.allows(req.header("User-Agent"), class) && !is_operational_route(&req.path) {
+            let resp = error_response(
+                config,
+                trace,
+                "HTTP/1.1 403 Forbidden",
+                "automated clients are not allowed",
+                "bot",
+                &[],
+            );
+            respond_and_close(client, &resp, trace)?;
+            eprintln!(
+                "[wasm-host] req_id={} {} {} -> 403 bot rules: {:?}",
+                trace.req_id,
+                req.method,
+                req.path,
+                req.header("User-Agent").unwrap_or_default()
+            );
+            return Ok(());
+        }
+    }
+    // A variant's own module stands in for the transform; `LISTEN_NATIVE`
+    // keeps the native one it is compared against.
+    let transform = match trace.experiment.and_then(|a| a.transform()) {
+        Some(variant) if !trace.native => variant,
+        _ => transform,
+    };
+
+    let mut request_cookies: Vec<(&str, &str)> = req
+        .headers
+        .get_all("cookie")
+        .flat_map(cookies::parse_cookie_header)
+        .collect();
+    let had_cookies = !request_cookies.is_empty();
+    if let Some(cookie_cfg) = config.cookies.as_ref() {
+        request_cookies = cookie_cfg.filter_request(request_cookies);
+    }
+    if !request_cookies.is_empty() {
+        let map: serde_json::Map<String, serde_json::Value> = request_cookies
+            .iter()
+            .map(|(k, v)| (k.to_string(), serde_json::Value::from(*v)))
+            .collect();
+        envelope.set("COOKIES", serde_json::Value::Object(map).to_string());
+    }
+
+    if req.content_length > remainder.len()
+        && req
+            .header("Expect")
+            .is_some_and(|v| v.eq_ignore_ascii_case("100-continue"))
+    {
+        client.write_all(b"HTTP/1.1 100 Continue\r\n\r\n")?;
+        client.flush()?;
+    }
+    if let Some(hints) = config.early_hints.as_ref() {
+        if !internal && req.version.eq_ignore_ascii_case("HTTP/1.1") {
+            hints.send(client, &req.path)?;
+        }
+    }
+
+    if req.method == "POST" && route_path(&req.path) == "/upload" {
+        let summary = workloads::upload_summary(client, remainder, req.content_length)?;
+        let body = run_transform(transform, summary.as_bytes(), envelope)
+            .context("wasm transform failed for /upload workload")?;
+        let headers = response_headers(config, envelope);
+        let resp = build_response(
+            "HTTP/1.1 200 OK",
+            &body,
+            "upload",
+            Some("text/plain"),
+            &header_refs(&headers),
+        );
+        return respond_and_close(client, &resp, trace);
+    }
+
+    let body_bytes = read_http_body(client, remainder, req.content_length)
+        .context(errors::GatewayError::ClientBadRequest)?;
+    deadline::check(trace.deadline)?;
+
+    if let Some(waf) = waf::configured().filter(|_| !is_operational_route(&req.path)) {
+        let content_type = req.header("Content-Type").unwrap_or_default();
+        let violation = waf.check(
+            &req.path,
+            content_type,
+            &body_bytes,
+            config.transform.as_ref(),
+            &trace.req_id,
+        )?;
+        if let Some(violation) = violation {
+            let resp = error_response(
+                config,
+                trace,
+                "HTTP/1.1 403 Forbidden",
+                &format!("blocked by waf rule {}", violation.rule),
+                "waf",
+                &[("X-WAF-Rule", violation.rule)],
+            );
+            respond_and_close(client, &resp, trace)?;
+            eprintln!(
+                "[wasm-host] req_id={} {} {} -> 403 waf rule {} in {}",
+                trace.req_id, req.method, req.path, violation.rule, violation.location
+            );
+            return Ok(());
+        }
+    }
 
-    let (head_bytes, body_bytes) = read_http_request(client)?;
-    let req = parse_request_head(&head_bytes)?;
+    if (req.method == "GET" || req.method == "POST") && route_path(&req.path) == "/echo" {
+        let body = workloads::echo_json(&req, &body_bytes, upstream)?;
+        let resp = build_response(
+            "HTTP/1.1 200 OK",
+            body.as_bytes(),
+            "echo",
+            Some("application/json"),
+            &[],
+        );
+        return respond_and_close(client, &resp, trace);
+    }
 
     if req.method == "GET" && req.path == "/health" {
         let resp = build_response("HTTP/1.1 200 OK", b"OK", "health", Some("text/plain"), &[]);
-        client.write_all(&resp).ok();
-        client.flush().ok();
-        client.shutdown(Shutdown::Both).ok();
+        respond_and_close(client, &resp, trace).ok();
+        return Ok(());
+    }
+
+    if req.method == "GET" && route_path(&req.path) == "/health/full" {
+        let resp = if let Err(rejection) = operator_authorized(
+            &req,
+            client_ip,
+            config.basic_auth.as_ref(),
+            config
+                .health_token
+                .as_ref()
+                .map(secrets::Secret::reveal)
+                .as_deref(),
+            true,
+        ) {
+            auth_rejection_response(config, trace, rejection, "health")
+        } else {
+            let (healthy, body) = health_report(config);
+            let status = if healthy {
+                "HTTP/1.1 200 OK"
+            } else {
+                "HTTP/1.1 503 Service Unavailable"
+            };
+            build_response(
+                status,
+                body.as_bytes(),
+                "health",
+                Some("application/json"),
+                &[],
+            )
+        };
+        respond_and_close(client, &resp, trace).ok();
+        return Ok(());
+    }
+
+    // Held until the response is written; operational routes skip the queue.
+    let _slot = match priority::configured() {
+        Some(admission) if !internal && !operational && route_path(&req.path) != "/health" => {
+            Some(admission.admit(admission.tier(&req.path))?)
+        }
+        _ => None,
+    };
+
+    if req.method == "GET" && route_path(&req.path) == "/metrics" {
+        let body = metrics::METRICS.prometheus(
+            health::uptime(),
+            config.wasm_enabled.load(Ordering::Relaxed),
+        );
+        let resp = build_response(
+            "HTTP/1.1 200 OK",
+            body.as_bytes(),
+            "metrics",
+            Some("text/plain; version=0.0.4"),
+            &[],
+        );
+        respond_and_close(client, &resp, trace).ok();
+        return Ok(());
+    }
+
+    if req.method == "GET" && route_path(&req.path) == "/stats" {
+        let body = metrics::METRICS
+            .json(
+                health::uptime(),
+                config.transform.name(),
+                config.wasm_enabled.load(Ordering::Relaxed),
+            )
+            .to_string();
+        let resp = build_response(
+            "HTTP/1.1 200 OK",
+            body.as_bytes(),
+            "metrics",
+            Some("application/json"),
+            &[],
+        );
+        respond_and_close(client, &resp, trace).ok();
         return Ok(());
     }
 
     if req.method == "GET" && (req.path == "/" || req.path.starts_with("/?")) {
-        let body = wasm_transform(wasm_runtime, wasm_module_path, b"hello")
+        let body = run_transform(transform, b"hello", envelope)
             .context("wasm transform failed for / workload")?;
-        let resp = build_response("HTTP/1.1 200 OK", &body, "hello", Some("text/plain"), &[]);
-        client.write_all(&resp)?;
-        client.flush().ok();
-        client.shutdown(std::net::Shutdown::Both).ok();
-        return Ok(());
+        let headers = response_headers(config, envelope);
+        let resp = build_response(
+            "HTTP/1.1 200 OK",
+            &body,
+            "hello",
+            Some("text/plain"),
+            &header_refs(&headers),
+        );
+        return respond_and_close(client, &resp, trace);
+    }
+
+    // `in=wasm` classifies inside the guest's `classify` mode instead.
+    if req.method == "GET" && route_path(&req.path) == "/classify" {
+        let user_agent = req.header("User-Agent").unwrap_or_default();
+        let (body, workload) = if query_param(&req.path, "in").is_some_and(|v| v == "wasm") {
+            envelope.set("MODE", "classify");
+            envelope.set("USER_AGENT", user_agent);
+            let body = run_transform(transform, b"", envelope)
+                .context("wasm transform failed for /classify?in=wasm workload")?;
+            (body, "classify_wasm")
+        } else {
+            let class = user_agent::classify(Some(user_agent)).label();
+            envelope
+                .response_headers
+                .get_mut()
+                .unwrap_or_else(|e| e.into_inner())
+                .push((user_agent::HEADER.to_string(), class.to_string()));
+            (class.as_bytes().to_vec(), "classify")
+        };
+        let headers = response_headers(config, envelope);
+        let resp = build_response(
+            "HTTP/1.1 200 OK",
+            &body,
+            workload,
+            Some("text/plain"),
+            &header_refs(&headers),
+        );
+        return respond_and_close(client, &resp, trace);
     }
 
     if req.method == "GET" && req.path.starts_with("/compute") {
@@ -176,428 +1527,1896 @@ fn handle_client(
             .and_then(|v| v.parse::<u64>().ok())
             .unwrap_or(50_000);
 
-        let result = cpu_heavy(iters);
-        let body = wasm_transform(wasm_runtime, wasm_module_path, result.as_bytes())
+        let seed = query_param(&req.path, "seed").and_then(|v| v.parse::<u64>().ok());
+        let seed_str = seed.map(|s| s.to_string());
+        let mut seed_headers: Vec<(&str, &str)> = seed_str
+            .as_deref()
+            .map(|s| vec![("X-Workload-Seed", s)])
+            .unwrap_or_default();
+
+        // `in=wasm` runs the same chain inside the guest (its `cpu` mode)
+        // instead of natively, with an empty body.
+        if query_param(&req.path, "in").is_some_and(|v| v == "wasm") {
+            envelope.set("MODE", "cpu");
+            envelope.set("ITERS", iters.to_string());
+            if let Some(seed) = seed_str.as_deref() {
+                envelope.set("SEED", seed);
+            }
+            let body = run_transform(transform, b"", envelope)
+                .context("wasm transform failed for /compute?in=wasm workload")?;
+            let headers = response_headers(config, envelope);
+            seed_headers.extend(header_refs(&headers));
+            let resp = build_response(
+                "HTTP/1.1 200 OK",
+                &body,
+                "compute_wasm",
+                Some("text/plain"),
+                &seed_headers,
+            );
+            return respond_and_close(client, &resp, trace);
+        }
+
+        if query_param(&req.path, "stream").is_some_and(|v| v == "1" || v == "true") {
+            let every = query_param(&req.path, "progress_every")
+                .and_then(|v| v.parse::<u64>().ok())
+                .unwrap_or((iters / 10).max(1));
+            workloads::stream_compute(client, iters, seed, every, &seed_headers, |result| {
+                run_transform(transform, result.as_bytes(), envelope)
+                    .context("wasm transform failed for /compute workload")
+            })?;
+            trace.status = 200;
+            client.shutdown(Shutdown::Both).ok();
+            return Ok(());
+        }
+
+        let result = workloads::cpu_heavy(iters, seed);
+        let body = run_transform(transform, result.as_bytes(), envelope)
             .context("wasm transform failed for /compute workload")?;
-        let resp = build_response("HTTP/1.1 200 OK", &body, "compute", Some("text/plain"), &[]);
-        client.write_all(&resp)?;
-        client.flush().ok();
-        client.shutdown(std::net::Shutdown::Both).ok();
-        return Ok(());
+        let headers = response_headers(config, envelope);
+        seed_headers.extend(header_refs(&headers));
+        let resp = build_response(
+            "HTTP/1.1 200 OK",
+            &body,
+            "compute",
+            Some("text/plain"),
+            &seed_headers,
+        );
+        return respond_and_close(client, &resp, trace);
+    }
+
+    if req.method == "GET" && route_path(&req.path).starts_with("/render/") {
+        let name = &route_path(&req.path)["/render/".len()..];
+        let resp = match read_template(&config.static_dir, name)? {
+            Some(template) => {
+                envelope.set("MODE", "render");
+                envelope.set("QUERY", req.path.split_once('?').map_or("", |(_, q)| q));
+                let body = run_transform(transform, &template, envelope)
+                    .context("wasm transform failed for /render workload")?;
+                let mut headers = response_headers(config, envelope);
+                let selection = range::Selection::for_request(
+                    &req.method,
+                    &req.headers,
+                    &Headers::default(),
+                    body.len(),
+                );
+                let (status_line, body, content_range) = selection.apply(&body);
+                headers.push(("Accept-Ranges".to_string(), "bytes".to_string()));
+                if let Some(content_range) = content_range {
+                    headers.push(("Content-Range".to_string(), content_range));
+                }
+                build_response(
+                    status_line.unwrap_or("HTTP/1.1 200 OK"),
+                    body,
+                    "render",
+                    Some("text/html; charset=utf-8"),
+                    &header_refs(&headers),
+                )
+            }
+            None => build_response(
+                "HTTP/1.1 404 Not Found",
+                b"template not found\n",
+                "render",
+                Some("text/plain"),
+                &[],
+            ),
+        };
+        return respond_and_close(client, &resp, trace);
+    }
+
+    if req.method == "POST" && route_path(&req.path) == "/transform/batch" {
+        let format = batch::BatchFormat::from_content_type(req.header("Content-Type"))?;
+        let items = batch::split_items(&body_bytes, &format)?;
+        let parallelism = query_param(&req.path, "parallel")
+            .and_then(|v| v.parse::<usize>().ok())
+            .unwrap_or(config.live().batch_parallelism);
+        let results = batch::run_batch(&items, parallelism, |item| {
+            run_transform(transform, item, envelope)
+        })
+        .context("wasm transform failed for /transform/batch workload")?;
+        let (body, content_type) = batch::encode_results(&results, &format);
+        let item_count = results.len().to_string();
+        let resp = build_response(
+            "HTTP/1.1 200 OK",
+            &body,
+            "transform_batch",
+            Some(&content_type),
+            &[("X-Batch-Items", &item_count)],
+        );
+        return respond_and_close(client, &resp, trace);
+    }
+
+    if req.method == "POST" && route_path(&req.path) == "/transform" {
+        if let Some(content_type) = req.header("Content-Type") {
+            envelope.set("CONTENT_TYPE", content_type);
+        }
+        let body = run_transform(transform, &body_bytes, envelope)
+            .context("wasm transform failed for /transform workload")?;
+        let content_type = req
+            .header("Content-Type")
+            .unwrap_or("application/octet-stream")
+            .to_string();
+        let headers = response_headers(config, envelope);
+        let mut extra_headers = header_refs(&headers);
+        if envelope.bypassed.load(Ordering::Relaxed) {
+            extra_headers.push((transform.bypass_header(), "true"));
+        }
+        let resp = build_response(
+            "HTTP/1.1 200 OK",
+            &body,
+            "transform",
+            Some(&content_type),
+            &extra_headers,
+        );
+        return respond_and_close(client, &resp, trace);
+    }
+
+    if req.method == "GET" && route_path(&req.path) == "/admin/audit" {
+        let resp = if let Err(rejection) = admin_authorized(&req, config, client_ip) {
+            auth_rejection_response(config, trace, rejection, "admin")
+        } else if let Some(audit) = config.audit.as_ref() {
+            let since = query_param(&req.path, "since")
+                .and_then(|v| v.parse::<i64>().ok())
+                .unwrap_or(0);
+            let limit = query_param(&req.path, "limit")
+                .and_then(|v| v.parse::<usize>().ok())
+                .unwrap_or(1000)
+                .min(audit::MAX_QUERY_ROWS);
+            let body = audit.query(since, limit).context("audit query failed")?;
+            build_response(
+                "HTTP/1.1 200 OK",
+                body.as_bytes(),
+                "admin",
+                Some("application/json"),
+                &[],
+            )
+        } else {
+            build_response(
+                "HTTP/1.1 404 Not Found",
+                b"audit log disabled (set AUDIT_DB)",
+                "admin",
+                Some("text/plain"),
+                &[],
+            )
+        };
+        return respond_and_close(client, &resp, trace);
+    }
+
+    if req.method == "GET" && route_path(&req.path) == profiling::ROUTE {
+        let resp = if let Err(rejection) = admin_authorized(&req, config, client_ip) {
+            auth_rejection_response(config, trace, rejection, "admin")
+        } else {
+            profiling::response(&req)
+        };
+        return respond_and_close(client, &resp, trace);
+    }
+
+    if req.method == "GET" && route_path(&req.path) == allocator::ROUTE {
+        let resp = if let Err(rejection) = admin_authorized(&req, config, client_ip) {
+            auth_rejection_response(config, trace, rejection, "admin")
+        } else {
+            build_response(
+                "HTTP/1.1 200 OK",
+                allocator::report_json().as_bytes(),
+                "allocator",
+                Some("application/json"),
+                &[],
+            )
+        };
+        return respond_and_close(client, &resp, trace);
+    }
+
+    if route_path(&req.path).starts_with("/admin/") {
+        let Some(caller) = admin_caller(client, &req, config, trace)? else {
+            return Ok(());
+        };
+        let path = route_path(&req.path);
+        let resp = if path.starts_with("/admin/wasm/") {
+            wasm_admin_response(&req, &body_bytes, config, &caller)
+        } else if path.starts_with("/admin/switch") {
+            switch_response(&req, config, &caller)
+        } else if path == "/admin/cache/purge" {
+            cache_purge_response(&req, &caller)
+        } else {
+            build_response(
+                "HTTP/1.1 404 Not Found",
+                b"not found",
+                "admin",
+                Some("text/plain"),
+                &[],
+            )
+        };
+        return respond_and_close(client, &resp, trace);
     }
 
     if req.method == "GET" && req.path.starts_with("/state") {
-        let value = COUNTER.fetch_add(1, Ordering::SeqCst);
+        let value = config
+            .state
+            .next_value(&config.store)
+            .context("/state counter")?;
         let body_str = value.to_string();
-        let body = wasm_transform(wasm_runtime, wasm_module_path, body_str.as_bytes())
+        let body = run_transform(transform, body_str.as_bytes(), envelope)
             .context("wasm transform failed for /state workload")?;
-        let resp = build_response("HTTP/1.1 200 OK", &body, "state", Some("text/plain"), &[]);
-        client.write_all(&resp)?;
-        client.flush().ok();
-        client.shutdown(std::net::Shutdown::Both).ok();
-        return Ok(());
+        let headers = response_headers(config, envelope);
+        let mut extra_headers = header_refs(&headers);
+        extra_headers.push(("X-Replica-Id", config.state.replica_id.as_str()));
+        let resp = build_response(
+            "HTTP/1.1 200 OK",
+            &body,
+            "state",
+            Some("text/plain"),
+            &extra_headers,
+        );
+        return respond_and_close(client, &resp, trace);
     }
 
-    // Forward to upstream
-    let mut upstream_stream = TcpStream::connect((&*upstream.host, upstream.port))
-        .with_context(|| format!("connect upstream {}:{}", upstream.host, upstream.port))?;
-    upstream_stream.set_read_timeout(Some(IO_TIMEOUT)).ok();
-    upstream_stream.set_write_timeout(Some(IO_TIMEOUT)).ok();
+    let mut rate_headers = Vec::new();
+    if let Some(limits) = live.rate_limit.as_ref() {
+        let key = limits.key(req.header(&limits.key_header), client_ip);
+        // A store outage must not take the data path down: fail open.
+        match limits.check(&config.store, route_path(&req.path), &key) {
+            Ok(Some(decision)) => {
+                rate_headers.push(("X-RateLimit-Limit", decision.limit.to_string()));
+                rate_headers.push(("X-RateLimit-Remaining", decision.remaining.to_string()));
+                if !decision.allowed {
+                    rate_headers.push(("Retry-After", decision.reset_secs.max(1).to_string()));
+                    let headers: Vec<(&str, &str)> =
+                        rate_headers.iter().map(|(k, v)| (*k, v.as_str())).collect();
+                    let resp = error_response(
+                        config,
+                        trace,
+                        "HTTP/1.1 429 Too Many Requests",
+                        "rate limit exceeded",
+                        "proxy",
+                        &headers,
+                    );
+                    respond_and_close(client, &resp, trace)?;
+                    eprintln!(
+                        "[wasm-host] req_id={} {} {} -> 429 rate limited",
+                        req_id, req.method, req.path
+                    );
+                    return Ok(());
+                }
+            }
+            Ok(None) => {}
+            Err(e) => eprintln!("[wasm-host] req_id={req_id} rate limit check failed: {e:#}"),
+        }
+    }
 
-    let forwarded = build_forwarded_request(&req, &head_bytes, &body_bytes, upstream)?;
-    upstream_stream.write_all(&forwarded)?;
-    upstream_stream.flush()?;
+    if let Some(sig) = config.signature.as_ref() {
+        if sig.applies_to(route_path(&req.path)) {
+            if let Err(reason) = signature::verify(sig, &req, &body_bytes) {
+                let resp = error_response(
+                    config,
+                    trace,
+                    "HTTP/1.1 401 Unauthorized",
+                    reason,
+                    "proxy",
+                    &[],
+                );
+                respond_and_close(client, &resp, trace)?;
+                eprintln!(
+                    "[wasm-host] req_id={} {} {} -> 401 signature: {}",
+                    req_id, req.method, req.path, reason
+                );
+                return Ok(());
+            }
+        }
+    }
 
-    let resp_bytes = read_all_response(&mut upstream_stream)?;
+    let mut forward_headers = Vec::new();
+    if let Some(basic) = config.basic_auth.as_ref() {
+        if basic.applies_to(route_path(&req.path)) {
+            match basic_auth::check(basic, req.header("Authorization"), client_ip) {
+                Ok(user) => {
+                    envelope.set("AUTH_SUBJECT", user.as_str());
+                    forward_headers.push(("X-Auth-Subject", user));
+                }
+                Err(rejection) => {
+                    let reason = match &rejection {
+                        basic_auth::Rejection::Unauthorized(r) => r,
+                        basic_auth::Rejection::LockedOut(_) => "locked out",
+                    };
+                    eprintln!(
+                        "[wasm-host] req_id={} {} {} -> basic auth: {}",
+                        req_id, req.method, req.path, reason
+                    );
+                    let resp = auth_rejection_response(config, trace, rejection, "proxy");
+                    return respond_and_close(client, &resp, trace);
+                }
+            }
+        }
+    }
+    if let Some(oauth_cfg) = config.oauth.as_ref() {
+        if oauth_cfg.applies_to(route_path(&req.path)) {
+            let outcome = oauth::authorize(oauth_cfg, req.header("Authorization"))
+                .context("token introspection failed")?;
+            match outcome {
+                Ok(principal) => {
+                    envelope.set("AUTH_SUBJECT", principal.subject.as_str());
+                    envelope.set("AUTH_SCOPE", principal.scope.as_str());
+                    forward_headers.push(("X-Auth-Subject", principal.subject));
+                    forward_headers.push(("X-Auth-Scope", principal.scope));
+                }
+                Err(rejection) => {
+                    let (status, code, challenge, reason) = match rejection {
+                        oauth::Rejection::Unauthorized(r) => {
+                            ("HTTP/1.1 401 Unauthorized", 401, "Bearer", r)
+                        }
+                        oauth::Rejection::Forbidden(r) => (
+                            "HTTP/1.1 403 Forbidden",
+                            403,
+                            "Bearer error=\"insufficient_scope\"",
+                            r,
+                        ),
+                    };
+                    let resp = error_response(
+                        config,
+                        trace,
+                        status,
+                        reason,
+                        "proxy",
+                        &[("WWW-Authenticate", challenge)],
+                    );
+                    respond_and_close(client, &resp, trace)?;
+                    eprintln!(
+                        "[wasm-host] req_id={} {} {} -> {} oauth: {}",
+                        req_id, req.method, req.path, code, reason
+                    );
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    let mut validation_us = None;
+    if matches!(req.method.as_str(), "POST" | "PUT" | "PATCH") {
+        if let Some(route) = schema::route_for(&config.schema_routes, route_path(&req.path)) {
+            let validation_start = Instant::now();
+            let violations = schema::validate_body(&route.schema, &body_bytes);
+            let us = validation_start.elapsed().as_micros().to_string();
+            if !violations.is_empty() {
+                let body = schema::violations_json(&violations);
+                let resp = build_response(
+                    "HTTP/1.1 422 Unprocessable Entity",
+                    body.as_bytes(),
+                    "proxy",
+                    Some("application/json"),
+                    &[("X-Schema-Validation-Us", &us), ("X-Gateway-Error", "true")],
+                );
+                respond_and_close(client, &resp, trace)?;
+                eprintln!(
+                    "[wasm-host] req_id={} {} {} -> 422 schema ({} violations), validation {} us",
+                    req_id,
+                    req.method,
+                    req.path,
+                    violations.len(),
+                    us
+                );
+                return Ok(());
+            }
+            validation_us = Some(us);
+        }
+    }
+
+    // A retried unsafe request with a known `Idempotency-Key` is answered
+    // here; see `idempotency`.
+    let mut reservation = None;
+    if let (Some(store), Some(key)) = (
+        idempotency::configured().filter(|_| idempotency::applies_to(&req.method)),
+        req.header(idempotency::HEADER),
+    ) {
+        let answer = match store.claim(
+            key.trim(),
+            &req.method,
+            &req.path,
+            &req.headers,
+            &body_bytes,
+        ) {
+            idempotency::Claim::New(claimed) => {
+                reservation = Some(claimed);
+                None
+            }
+            idempotency::Claim::Replay(resp) => Some(resp),
+            idempotency::Claim::InFlight => Some(error_response(
+                config,
+                trace,
+                "HTTP/1.1 409 Conflict",
+                "a request with this Idempotency-Key is still in progress\n",
+                "proxy",
+                &[("Retry-After", "1")],
+            )),
+            idempotency::Claim::Mismatch => Some(error_response(
+                config,
+                trace,
+                "HTTP/1.1 422 Unprocessable Entity",
+                "Idempotency-Key was already used with a different request body\n",
+                "proxy",
+                &[],
+            )),
+        };
+        if let Some(resp) = answer {
+            respond_and_close(client, &resp, trace)?;
+            logging::access(format_args!(
+                "[wasm-host] req_id={} {} {} -> {} idempotency key {:?}",
+                req_id, req.method, req.path, trace.status, key
+            ));
+            return Ok(());
+        }
+    }
+
+    // Forward to upstream: an experiment variant's own, else the client
+    // country's, else the device class's, else the `Host`'s own, else a compose route, else a pool or
+    // live blue/green member, else `UPSTREAM_URL`. The pick decides the cache
+    // key, so it is taken before the lookup; an unfinished one counts against
+    // its member.
+    let pinned_upstream = trace.experiment.and_then(|a| a.upstream()).or_else(|| {
+        geoip::configured()
+            .zip(location.as_ref())
+            .and_then(|(geoip, location)| geoip.upstream_for(location))
+            .or_else(|| device.and_then(|(devices, class)| devices.upstream_for(class)))
+    });
+    let route = pinned_upstream
+        .is_none()
+        .then(|| config.route_for(&live, req.header("Host"), &req.path))
+        .flatten();
+    let lease =
+        (pinned_upstream.is_none() && route.is_none() && !live.has_vhost(req.header("Host")))
+            .then_some(config.blue_green.as_ref())
+            .flatten()
+            .map(blue_green::BlueGreen::lease);
+    let pick = (pinned_upstream.is_none() && route.is_none())
+        .then(|| {
+            live.pool_for(req.header("Host"))
+                .or_else(|| lease.as_ref()?.pick())
+        })
+        .flatten();
+    let (upstream, upstream_tls, target) = match (&route, pick.as_deref().or(pinned_upstream)) {
+        (Some((route, target)), _) => (&route.upstream, None, target.as_str()),
+        (None, Some((upstream, tls))) => (upstream, tls.as_ref(), req.path.as_str()),
+        (None, None) => (upstream, upstream_tls, req.path.as_str()),
+    };
+    trace.upstream = Some(upstream.raw_url.clone());
+    if config.cookies.is_some() && had_cookies {
+        // An empty value removes the client's Cookie header entirely.
+        let joined: Vec<String> = request_cookies
+            .iter()
+            .map(|(k, v)| format!("{k}={v}"))
+            .collect();
+        forward_headers.push(("Cookie", joined.join("; ")));
+    }
+    if debug_headers::enabled() {
+        forward_headers.push((debug_headers::HEADER, String::new()));
+    }
+    if let Some(assignment) = trace.experiment {
+        forward_headers.push((experiments::HEADER, assignment.label()));
+    }
+    if let Some((_, class)) = device {
+        forward_headers.push((user_agent::HEADER, class.label().to_string()));
+    }
+    if geoip::configured().is_some() {
+        // Empty values drop whatever the client sent under these names.
+        let location = location.clone().unwrap_or_default();
+        forward_headers.push((geoip::COUNTRY_HEADER, location.country.unwrap_or_default()));
+        forward_headers.push((
+            geoip::ASN_HEADER,
+            location.asn.map(|asn| asn.to_string()).unwrap_or_default(),
+        ));
+    }
+    let forward_header_refs: Vec<(&str, &str)> = forward_headers
+        .iter()
+        .map(|(k, v)| (*k, v.as_str()))
+        .chain(range::FORWARD_OVERRIDES)
+        .collect();
+    let unsigned =
+        build_forwarded_request(&req, target, &body_bytes, upstream, &forward_header_refs)?;
+    let signed = match config.upstream_signing.as_ref() {
+        Some(signer) => {
+            let fields = signer.headers(
+                &req.method,
+                &forwarded_target(upstream, target),
+                &upstream.host,
+                &body_bytes,
+                SystemTime::now(),
+            )?;
+            let refs: Vec<(&str, &str)> = forward_header_refs
+                .iter()
+                .copied()
+                .chain(fields.iter().map(|(k, v)| (*k, v.as_str())))
+                .collect();
+            Some(build_forwarded_request(
+                &req,
+                target,
+                &body_bytes,
+                upstream,
+                &refs,
+            )?)
+        }
+        None => None,
+    };
+    let forwarded = signed.as_ref().unwrap_or(&unsigned);
+    let debug = envelope.debug;
+    let due = trace.deadline;
+    let round_trip = || -> Result<(Vec<u8>, UpstreamTiming)> {
+        let (resp_bytes, timing) = upstream_exchange(upstream, upstream_tls, forwarded, due)?;
+        debug.log(
+            req_id,
+            "upstream",
+            format_args!(
+                "{} {target}: {} B out, {} B in, ttfb {} us, total {} us",
+                upstream.raw_url,
+                timing.bytes_sent,
+                timing.bytes_received,
+                timing.ttfb_us,
+                timing.total_us
+            ),
+        );
+        Ok((resp_bytes, timing))
+    };
+    // Bodyless GETs can be answered from the cache, or share one upstream
+    // request with identical ones; see `cache` and `coalesce`.
+    let key = (req.method == "GET" && body_bytes.is_empty()).then(|| {
+        let mut key = upstream.raw_url.clone().into_bytes();
+        key.push(b'\n');
+        key.extend_from_slice(&unsigned);
+        key
+    });
+    let fetch = || match (
+        coalesce::configured().filter(|_| !debug.no_cache),
+        key.as_ref(),
+    ) {
+        (Some(coalescer), Some(key)) => coalescer.run(key.clone(), round_trip),
+        _ => round_trip().map(|(resp_bytes, timing)| (Arc::new(resp_bytes), timing, false)),
+    };
+    let cache = cache::configured()
+        .filter(|_| !debug.no_cache)
+        .zip(key.as_ref());
+    let lookup = match cache {
+        Some((cache, key)) => cache.lookup(key, &req.headers),
+        None => cache::Lookup::Miss,
+    };
+    if cache.is_some() {
+        debug.log(
+            req_id,
+            "cache",
+            format_args!(
+                "{}",
+                match &lookup {
+                    cache::Lookup::Fresh(_) => "hit",
+                    cache::Lookup::Stale(..) => "stale",
+                    cache::Lookup::Miss => "miss",
+                }
+            ),
+        );
+    }
+    // A hit never reaches the picked member, so it says nothing about it.
+    if let (Some(pick), cache::Lookup::Fresh(_) | cache::Lookup::Stale(..)) = (&pick, &lookup) {
+        pick.release();
+    }
+    let (resp_bytes, timing, coalesced) = match &lookup {
+        cache::Lookup::Fresh(entry) | cache::Lookup::Stale(entry, _) => match &entry.resp {
+            Ok(resp_bytes) => (Arc::clone(resp_bytes), entry.timing, false),
+            Err(err) => return Err(anyhow::Error::new(cache::CachedFailure(err.clone()))),
+        },
+        cache::Lookup::Miss => match fetch() {
+            Ok((resp_bytes, timing, coalesced)) => {
+                if let Some((cache, key)) = cache.filter(|_| !coalesced) {
+                    if !cache.store(key.clone(), Arc::clone(&resp_bytes), timing) {
+                        cache.store_failure(key.clone(), Ok(Arc::clone(&resp_bytes)), timing);
+                    }
+                }
+                (resp_bytes, timing, coalesced)
+            }
+            Err(e) => {
+                if let Some((cache, key)) = cache {
+                    cache.store_failure(
+                        key.clone(),
+                        Err(format!("{e:#}")),
+                        UpstreamTiming::default(),
+                    );
+                }
+                return Err(e);
+            }
+        },
+    };
+    // The upstream request is counted once, by the request that made it.
+    if !coalesced && matches!(lookup, cache::Lookup::Miss) {
+        trace.upstream_timing = Some(timing);
+    }
     let (resp_head, resp_body) = split_http_response(&resp_bytes)?;
     let upstream_status = parse_status_code_from_head(&resp_head)?;
     let upstream_status_str = upstream_status.to_string();
-    let transformed_body = wasm_transform(wasm_runtime, wasm_module_path, &resp_body)
+    let (status_line, mut resp_headers) = Headers::parse_head(&resp_head)?;
+    let bodyless = req.method == "HEAD" || matches!(upstream_status, 100..=199 | 204 | 304);
+    let (resp_body, trailers) = chunked::decode_response(&resp_headers, resp_body, bodyless)?;
+    if let Some(pick) = &pick {
+        pick.finish(upstream_status);
+    }
+    // Trailers need chunked framing, which HTTP/1.0 clients cannot read.
+    let trailers = if req.version.eq_ignore_ascii_case("HTTP/1.1") {
+        trailers
+    } else {
+        Headers::default()
+    };
+    if let Some(cookie_cfg) = config.cookies.as_ref() {
+        cookie_cfg.rewrite_response_headers(&mut resp_headers);
+    }
+    if let Some(content_type) = resp_headers.get("Content-Type") {
+        envelope.set("CONTENT_TYPE", content_type);
+    }
+    let transformed_body = run_transform(transform, &resp_body, envelope)
         .context("wasm transform failed for proxy workload")?;
-    let proxy_headers = vec![
+    let bytes_sent = timing.bytes_sent.to_string();
+    let bytes_received = timing.bytes_received.to_string();
+    let ttfb_ms = format!("{:.3}", timing.ttfb_us as f64 / 1000.0);
+    let total_ms = format!("{:.3}", timing.total_us as f64 / 1000.0);
+    let mut proxy_headers = vec![
         ("X-Upstream-Url", upstream.raw_url.as_str()),
         ("X-Upstream-Status", upstream_status_str.as_str()),
+        ("X-Upstream-Bytes-Sent", bytes_sent.as_str()),
+        ("X-Upstream-Bytes-Received", bytes_received.as_str()),
+        ("X-Upstream-Ttfb-Ms", ttfb_ms.as_str()),
+        ("X-Upstream-Total-Ms", total_ms.as_str()),
         ("x-wasm-processed", "1"),
     ];
-    let new_resp = rebuild_response_with_extra_headers(
-        &resp_head,
-        &transformed_body,
+    if let Some(us) = validation_us.as_deref() {
+        proxy_headers.push(("X-Schema-Validation-Us", us));
+    }
+    proxy_headers.extend(rate_headers.iter().map(|(k, v)| (*k, v.as_str())));
+    if envelope.bypassed.load(Ordering::Relaxed) {
+        proxy_headers.push((transform.bypass_header(), "true"));
+    }
+    if coalesced {
+        proxy_headers.push(("X-Coalesced", "true"));
+    }
+    let age;
+    if cache.is_some() {
+        let (result, entry) = match &lookup {
+            cache::Lookup::Fresh(entry) => ("HIT", Some(entry)),
+            cache::Lookup::Stale(entry, _) => ("STALE", Some(entry)),
+            cache::Lookup::Miss => ("MISS", None),
+        };
+        proxy_headers.push(("X-Cache", result));
+        if let Some(entry) = entry {
+            age = entry.age().as_secs().to_string();
+            proxy_headers.push(("Age", &age));
+            if entry.failure {
+                proxy_headers.push(("X-Cached-Failure", &upstream_status_str));
+            }
+        }
+    }
+    let guest_headers = response_headers(config, envelope);
+    proxy_headers.extend(header_refs(&guest_headers));
+    // Ranges are served from the transformed body; see `range`.
+    let selection = if upstream_status == 200 {
+        range::Selection::for_request(
+            &req.method,
+            &req.headers,
+            &resp_headers,
+            transformed_body.len(),
+        )
+    } else {
+        range::Selection::Full
+    };
+    let (range_status, transformed_body, content_range) = selection.apply(&transformed_body);
+    if upstream_status == 200 {
+        proxy_headers.push(("Accept-Ranges", "bytes"));
+    }
+    if let Some(content_range) = content_range.as_deref() {
+        proxy_headers.push(("Content-Range", content_range));
+    }
+    let new_resp = rebuild_response(
+        range_status.unwrap_or(&status_line),
+        resp_headers,
+        transformed_body,
+        &trailers,
         "proxy",
         &proxy_headers,
-    )?;
-
-    client.write_all(&new_resp)?;
-    client.flush().ok();
-    client.shutdown(Shutdown::Both).ok();
+    );
+
+    deadline::check(trace.deadline)?;
+    respond_and_close(client, &new_resp, trace)?;
+    if let Some(reservation) = reservation {
+        reservation.complete(&new_resp);
+    }
 
     let elapsed = start.elapsed().as_millis();
-    eprintln!(
-        "[wasm-host] req_id={} {} {} -> {} bytes, {} ms",
+    logging::access(format_args!(
+        "[wasm-host] req_id={} {} {} -> {} bytes, {} ms (upstream: {} B out, {} B in, ttfb {} ms, total {} ms)",
         req_id,
         req.method,
         req.path,
         new_resp.len(),
-        elapsed
-    );
+        elapsed,
+        timing.bytes_sent,
+        timing.bytes_received,
+        ttfb_ms,
+        total_ms
+    ));
+
+    // The refresh runs on its own thread so the accept loop can go on;
+    // `Lookup::Stale(_, true)` went to this request alone, and the entry
+    // stays claimed until `refreshed` records the outcome.
+    if let (cache::Lookup::Stale(entry, true), Some((cache, key))) = (&lookup, cache) {
+        let (entry, key) = (Arc::clone(entry), key.clone());
+        let (upstream, upstream_tls) = (upstream.clone(), upstream_tls.cloned());
+        let forwarded = forwarded.clone();
+        std::thread::spawn(move || {
+            let refreshed = upstream_exchange(&upstream, upstream_tls.as_ref(), &forwarded, None)
+                .and_then(|(resp_bytes, timing)| {
+                    cache
+                        .store(key, Arc::new(resp_bytes), timing)
+                        .then_some(())
+                        .ok_or_else(|| anyhow!("upstream response not cacheable"))
+                });
+            cache.refreshed(&entry, refreshed);
+        });
+    }
 
     Ok(())
 }
 
-#[derive(Debug)]
-struct RequestLine {
-    method: String,
-    path: String,
-    version: String,
-    content_length: usize,
+/// Sends `forwarded` to `upstream` and reads the whole response, giving up
+/// when `due` passes.
+fn upstream_exchange(
+    upstream: &Upstream,
+    upstream_tls: Option<&upstream_tls::UpstreamTls>,
+    forwarded: &[u8],
+    due: Option<Instant>,
+) -> Result<(Vec<u8>, UpstreamTiming)> {
+    let upstream_start = Instant::now();
+    deadline::check(due)?;
+    let io_timeout = Some(deadline::clamp(IO_TIMEOUT, due));
+    let mut upstream_stream = match upstream_tls.and_then(|tls| tls.take_warm()) {
+        Some(warm) => {
+            warm.tcp().set_read_timeout(io_timeout).ok();
+            warm.tcp().set_write_timeout(io_timeout).ok();
+            warm
+        }
+        None => {
+            let tcp = deadline::connect(&upstream.host, upstream.port, due)
+                .context(errors::GatewayError::UpstreamConnect)
+                .with_context(|| format!("connect upstream {}:{}", upstream.host, upstream.port))?;
+            tcp.set_read_timeout(io_timeout).ok();
+            tcp.set_write_timeout(io_timeout).ok();
+            match upstream_tls {
+                Some(tls) => tls.connect(tcp)?,
+                None => upstream_tls::UpstreamStream::Plain(tcp),
+            }
+        }
+    };
+    let mut ttfb = Duration::ZERO;
+    let resp_bytes = (|| -> Result<Vec<u8>> {
+        upstream_stream.write_all(forwarded)?;
+        upstream_stream.flush()?;
+
+        // Blocks until the first response byte is readable without consuming it.
+        upstream_stream
+            .wait_readable()
+            .context("read upstream response")?;
+        ttfb = upstream_start.elapsed();
+        read_all_response(&mut upstream_stream)
+    })()
+    .map_err(errors::GatewayError::upstream)?;
+    let timing = UpstreamTiming {
+        bytes_sent: forwarded.len() as u64,
+        bytes_received: resp_bytes.len() as u64,
+        ttfb_us: ttfb.as_micros() as u64,
+        total_us: upstream_start.elapsed().as_micros() as u64,
+    };
+    Ok((resp_bytes, timing))
 }
 
-/// Reads request head until CRLFCRLF, then reads body if Content-Length is present.
-/// Does NOT support chunked transfer encoding.
-fn read_http_request(stream: &mut TcpStream) -> Result<(Vec<u8>, Vec<u8>)> {
-    let mut buf = Vec::<u8>::new();
-    let mut tmp = [0u8; 4096];
-
-    // Read until headers end
-    loop {
-        let n = stream.read(&mut tmp).context("read from client")?;
-        if n == 0 {
-            return Err(anyhow!("client closed before request complete"));
-        }
-        buf.extend_from_slice(&tmp[..n]);
+/// Routes moved off the public port by `LISTEN_INTERNAL`.
+fn is_operational_route(path: &str) -> bool {
+    let path = route_path(path);
+    matches!(path, "/health/full" | "/metrics" | "/stats")
+        || path.starts_with("/admin/")
+        || path.starts_with("/debug/")
+}
 
-        if buf.len() > MAX_HEADER_BYTES {
-            return Err(anyhow!("request headers too large"));
-        }
-        if find_double_crlf(&buf).is_some() {
-            break;
+/// Operational endpoints accept Basic credentials (when `BASIC_AUTH_*` is
+/// configured) or the `token` bearer token. With neither configured, routes
+/// that may stay `open` (`/health/full`) let everyone in and the rest are
+/// refused. Returns who was let in: `basic:<user>`, `token` or `anonymous`.
+fn operator_authorized(
+    req: &RequestLine,
+    client_ip: Option<IpAddr>,
+    basic: Option<&basic_auth::BasicAuthConfig>,
+    token: Option<&str>,
+    open: bool,
+) -> std::result::Result<String, basic_auth::Rejection> {
+    let authorization = req.header("Authorization");
+    match basic {
+        Some(basic) if authorization.is_some_and(|v| v.starts_with("Basic ")) => {
+            basic_auth::check(basic, authorization, client_ip).map(|user| format!("basic:{user}"))
         }
+        Some(_) if token.is_none() => Err(basic_auth::Rejection::Unauthorized(
+            "missing basic credentials",
+        )),
+        None if token.is_none() && open => Ok("anonymous".to_string()),
+        None if token.is_none() => Err(basic_auth::Rejection::Unauthorized(
+            "no admin credentials configured",
+        )),
+        _ if bearer_token_matches(req, token) => Ok("token".to_string()),
+        _ => Err(basic_auth::Rejection::Unauthorized("unauthorized")),
     }
+}
 
-    let header_end = find_double_crlf(&buf).ok_or_else(|| anyhow!("malformed headers"))?;
-    let head = buf[..header_end].to_vec();
-    let mut remainder = buf[header_end + 4..].to_vec();
+/// `/admin/*` and `/debug/*`: as `/health/full`, but with `ADMIN_TOKEN`
+/// replacing `HEALTH_TOKEN` when set, so a monitoring token cannot change
+/// anything, and closed to everyone while no credentials are configured.
+fn admin_authorized(
+    req: &RequestLine,
+    config: &Config,
+    client_ip: Option<IpAddr>,
+) -> std::result::Result<String, basic_auth::Rejection> {
+    let token = config
+        .admin_token
+        .as_ref()
+        .or(config.health_token.as_ref())
+        .map(secrets::Secret::reveal);
+    operator_authorized(
+        req,
+        client_ip,
+        config.basic_auth.as_ref(),
+        token.as_deref(),
+        false,
+    )
+}
 
-    let req = parse_request_head(&head)?;
-    let mut body = Vec::<u8>::new();
+/// Who made an admin call, for the audit line of a change.
+struct AdminCaller {
+    actor: String,
+    remote: String,
+    req_id: String,
+}
 
-    if req.content_length > 0 {
-        if req.content_length > MAX_REQ_BODY_BYTES {
-            return Err(anyhow!(
-                "request body too large (Content-Length {})",
-                req.content_length
-            ));
+/// The caller of an admin route, or `None` once an unauthorized one has been
+/// answered.
+fn admin_caller(
+    client: &mut ClientStream,
+    req: &RequestLine,
+    config: &Config,
+    trace: &mut RequestTrace,
+) -> Result<Option<AdminCaller>> {
+    let client_ip = client.peer_addr().ok().map(|a| a.ip());
+    match admin_authorized(req, config, client_ip) {
+        Ok(actor) => Ok(Some(AdminCaller {
+            actor,
+            remote: client_ip.map(|ip| ip.to_string()).unwrap_or_default(),
+            req_id: trace.req_id.clone(),
+        })),
+        Err(rejection) => {
+            let resp = auth_rejection_response(config, trace, rejection, "admin");
+            respond_and_close(client, &resp, trace)?;
+            Ok(None)
         }
+    }
+}
 
-        body.extend_from_slice(&remainder);
-        remainder.clear();
+/// Logs an admin change as one JSON line: who, from where, what, and the
+/// value before and after (`null` after a rejected change, with `error`).
+fn audit_admin_change(
+    req: &RequestLine,
+    caller: &AdminCaller,
+    previous: serde_json::Value,
+    outcome: std::result::Result<serde_json::Value, String>,
+) {
+    let ts_ms = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0);
+    let (new, error) = match outcome {
+        Ok(new) => (new, None),
+        Err(error) => (serde_json::Value::Null, Some(error)),
+    };
+    let line = serde_json::json!({
+        "ts_ms": ts_ms,
+        "req_id": caller.req_id,
+        "actor": caller.actor,
+        "remote": caller.remote,
+        "method": req.method,
+        "path": req.path,
+        "previous": previous,
+        "new": new,
+        "error": error,
+    });
+    eprintln!("[wasm-host] admin-audit {line}");
+}
 
-        while body.len() < req.content_length {
-            let n = stream.read(&mut tmp).context("read request body")?;
-            if n == 0 {
-                return Err(anyhow!(
-                    "client closed during body read (got {}, expected {})",
-                    body.len(),
-                    req.content_length
-                ));
-            }
-            body.extend_from_slice(&tmp[..n]);
-            if body.len() > req.content_length {
-                body.truncate(req.content_length);
-                break;
-            }
+/// 401 with the challenge matching the configured schemes, or 429 while locked out.
+fn auth_rejection_response(
+    config: &Config,
+    trace: &RequestTrace,
+    rejection: basic_auth::Rejection,
+    variant: &str,
+) -> Vec<u8> {
+    match rejection {
+        basic_auth::Rejection::Unauthorized(reason) => {
+            let challenge = if config.basic_auth.is_some() {
+                "Basic realm=\"gateway\""
+            } else {
+                "Bearer"
+            };
+            error_response(
+                config,
+                trace,
+                "HTTP/1.1 401 Unauthorized",
+                reason,
+                variant,
+                &[("WWW-Authenticate", challenge)],
+            )
+        }
+        basic_auth::Rejection::LockedOut(remaining) => {
+            let retry_after = remaining.as_secs().max(1).to_string();
+            error_response(
+                config,
+                trace,
+                "HTTP/1.1 429 Too Many Requests",
+                "too many failed login attempts",
+                variant,
+                &[("Retry-After", retry_after.as_str())],
+            )
         }
     }
-
-    Ok((head, body))
 }
 
-fn parse_request_head(head: &[u8]) -> Result<RequestLine> {
-    let s = std::str::from_utf8(head).context("headers not valid UTF-8")?;
-    let mut lines = s.split("\r\n");
-
-    let request_line = lines.next().ok_or_else(|| anyhow!("empty request"))?;
-    let mut parts = request_line.split_whitespace();
-    let method = parts
-        .next()
-        .ok_or_else(|| anyhow!("missing method"))?
-        .to_string();
-    let path = parts
-        .next()
-        .ok_or_else(|| anyhow!("missing path"))?
-        .to_string();
-    let version = parts
-        .next()
-        .ok_or_else(|| anyhow!("missing version"))?
-        .to_string();
-
-    let mut content_length = 0usize;
-    for line in lines {
-        let lower = line.to_ascii_lowercase();
-        if let Some(rest) = lower.strip_prefix("content-length:") {
-            content_length = rest
-                .trim()
-                .parse::<usize>()
-                .context("invalid Content-Length")?;
+/// Response for an error the gateway itself produced: the `ERROR_PAGES_DIR`
+/// template for the status when there is one, `message` as plain text
+/// otherwise. Always carries `X-Gateway-Error: true`, which upstream responses
+/// never do.
+fn error_response(
+    config: &Config,
+    trace: &RequestTrace,
+    status_line: &str,
+    message: &str,
+    workload: &str,
+    extra_headers: &[(&str, &str)],
+) -> Vec<u8> {
+    let mut headers = extra_headers.to_vec();
+    headers.push(("X-Gateway-Error", "true"));
+    let mut parts = status_line.splitn(3, ' ').skip(1);
+    let status = parts.next().and_then(|c| c.parse().ok()).unwrap_or(500);
+    let reason = parts.next().unwrap_or("");
+    let rendered = config.error_pages.as_ref().and_then(|pages| {
+        pages.render(
+            &error_pages::ErrorDetails {
+                status,
+                reason,
+                req_id: &trace.req_id,
+                message: message.trim_end(),
+            },
+            trace.accept.as_deref(),
+        )
+    });
+    match rendered {
+        Some((content_type, body)) => {
+            build_response(status_line, &body, workload, Some(content_type), &headers)
         }
+        None => build_response(
+            status_line,
+            message.as_bytes(),
+            workload,
+            Some("text/plain"),
+            &headers,
+        ),
     }
-
-    Ok(RequestLine {
-        method,
-        path,
-        version,
-        content_length,
-    })
 }
 
-/// Rewrites request line to respect upstream base_path.
-/// Rewrites Host.
-/// Forces Connection: close.
-fn build_forwarded_request(
+/// `GET|POST /admin/wasm/enabled` (body: `true` or `false`),
+/// `GET /admin/wasm/versions`, `POST /admin/wasm/load` (body: module path on
+/// the gateway host) and `POST /admin/wasm/rollback[?sha256=<prefix>]`.
+/// Every `POST` is audited with `audit_admin_change`.
+fn wasm_admin_response(
     req: &RequestLine,
-    original_head: &[u8],
     body: &[u8],
-    upstream: &Upstream,
-) -> Result<Vec<u8>> {
-    let original = std::str::from_utf8(original_head).context("original headers not UTF-8")?;
-
-    let forwarded_path = if upstream.base_path.is_empty() || upstream.base_path == "/" {
-        req.path.clone()
-    } else {
-        let bp = upstream.base_path.trim_end_matches('/');
-        let rp = req.path.trim_start_matches('/');
-        format!("{bp}/{rp}")
+    config: &Config,
+    caller: &AdminCaller,
+) -> Vec<u8> {
+    let json = |status: &str, value: serde_json::Value| {
+        build_response(
+            status,
+            value.to_string().as_bytes(),
+            "admin",
+            Some("application/json"),
+            &[],
+        )
     };
-
-    let mut out = Vec::<u8>::new();
-    out.extend_from_slice(
-        format!("{} {} {}\r\n", req.method, forwarded_path, req.version).as_bytes(),
-    );
-
-    for line in original.split("\r\n").skip(1) {
-        if line.is_empty() {
-            continue;
+    let text = |status: &str, message: String| {
+        build_response(status, message.as_bytes(), "admin", Some("text/plain"), &[])
+    };
+    if route_path(&req.path) == "/admin/wasm/enabled" {
+        if req.method == "POST" {
+            let previous = config.wasm_enabled.load(Ordering::Relaxed);
+            let enabled = match String::from_utf8_lossy(body).trim() {
+                "true" | "1" => true,
+                "false" | "0" => false,
+                _ => {
+                    let message = "expected true or false as the request body";
+                    audit_admin_change(req, caller, previous.into(), Err(message.to_string()));
+                    return text("HTTP/1.1 400 Bad Request", message.to_string());
+                }
+            };
+            config.wasm_enabled.store(enabled, Ordering::Relaxed);
+            audit_admin_change(req, caller, previous.into(), Ok(enabled.into()));
+            eprintln!(
+                "[wasm-host] transform {}",
+                if enabled { "enabled" } else { "disabled" }
+            );
         }
-        let lower = line.to_ascii_lowercase();
-        if lower.starts_with("host:") || lower.starts_with("connection:") {
-            continue;
+        let enabled = config.wasm_enabled.load(Ordering::Relaxed);
+        return json("HTTP/1.1 200 OK", serde_json::json!({ "enabled": enabled }));
+    }
+    let Some(versions) = config.versions.as_ref() else {
+        return text(
+            "HTTP/1.1 404 Not Found",
+            format!(
+                "the {} backend does not load a wasm module",
+                config.transform.name()
+            ),
+        );
+    };
+    let previous = || versions.active().to_json(true);
+    match (req.method.as_str(), route_path(&req.path)) {
+        ("GET", "/admin/wasm/versions") => json("HTTP/1.1 200 OK", versions.to_json()),
+        ("POST", "/admin/wasm/load") => {
+            let module_path = String::from_utf8_lossy(body).trim().to_string();
+            if module_path.is_empty() {
+                let message = "expected the module path as the request body";
+                audit_admin_change(req, caller, previous(), Err(message.to_string()));
+                return text("HTTP/1.1 400 Bad Request", message.to_string());
+            }
+            let before = previous();
+            match versions.load(&module_path) {
+                Ok(info) => {
+                    audit_admin_change(req, caller, before, Ok(info.to_json(true)));
+                    json("HTTP/1.1 200 OK", info.to_json(true))
+                }
+                Err(e) => {
+                    audit_admin_change(req, caller, before, Err(format!("{e:#}")));
+                    text("HTTP/1.1 422 Unprocessable Entity", format!("{e:#}"))
+                }
+            }
+        }
+        ("POST", "/admin/wasm/rollback") => {
+            let before = previous();
+            match versions.rollback(query_param(&req.path, "sha256").as_deref()) {
+                Ok(info) => {
+                    audit_admin_change(req, caller, before, Ok(info.to_json(true)));
+                    json("HTTP/1.1 200 OK", info.to_json(true))
+                }
+                Err(e) => {
+                    audit_admin_change(req, caller, before, Err(format!("{e:#}")));
+                    text("HTTP/1.1 409 Conflict", format!("{e:#}"))
+                }
+            }
         }
-        out.extend_from_slice(line.as_bytes());
-        out.extend_from_slice(b"\r\n");
+        _ => text("HTTP/1.1 404 Not Found", "not found".to_string()),
     }
-
-    out.extend_from_slice(format!("Host: {}\r\n", upstream.host).as_bytes());
-    out.extend_from_slice(b"Connection: close\r\n");
-    out.extend_from_slice(b"\r\n");
-    out.extend_from_slice(body);
-
-    Ok(out)
 }
 
-/// Minimal response read: read until EOF (Connection: close).
-fn read_all_response(stream: &mut TcpStream) -> Result<Vec<u8>> {
-    let mut resp = Vec::<u8>::new();
-    let mut tmp = [0u8; 8192];
-
-    loop {
-        let n = stream.read(&mut tmp).context("read upstream response")?;
-        if n == 0 {
-            break;
+/// `/admin/switch`: the blue/green sets and their in-flight requests
+/// (`GET`), a switch (`POST`, `?to=blue|green`, else the other set) and its
+/// confirmation (`POST /admin/switch/confirm`).
+fn switch_response(req: &RequestLine, config: &Config, caller: &AdminCaller) -> Vec<u8> {
+    let json = |status: &str, value: serde_json::Value| {
+        build_response(
+            status,
+            value.to_string().as_bytes(),
+            "admin",
+            Some("application/json"),
+            &[],
+        )
+    };
+    let text = |status: &str, message: &str| {
+        build_response(status, message.as_bytes(), "admin", Some("text/plain"), &[])
+    };
+    let Some(blue_green) = config.blue_green.as_ref() else {
+        return text(
+            "HTTP/1.1 404 Not Found",
+            "blue/green disabled (set UPSTREAM_BLUE and UPSTREAM_GREEN)",
+        );
+    };
+    let status = || {
+        blue_green.json(|set| {
+            set.members()
+                .iter()
+                .map(|m| m.name.clone())
+                .collect::<Vec<_>>()
+                .into()
+        })
+    };
+    let previous = serde_json::json!({ "active": blue_green.active() });
+    let outcome = match (req.method.as_str(), route_path(&req.path)) {
+        ("GET", "/admin/switch") => return json("HTTP/1.1 200 OK", status()),
+        ("POST", "/admin/switch") => blue_green.switch(query_param(&req.path, "to").as_deref()),
+        ("POST", "/admin/switch/confirm") => blue_green.confirm(),
+        (_, "/admin/switch" | "/admin/switch/confirm") => {
+            return text("HTTP/1.1 405 Method Not Allowed", "use GET or POST")
+        }
+        _ => return text("HTTP/1.1 404 Not Found", "not found"),
+    };
+    match outcome {
+        Ok(()) => {
+            let active = serde_json::json!({ "active": blue_green.active() });
+            audit_admin_change(req, caller, previous, Ok(active));
+            json("HTTP/1.1 200 OK", status())
         }
-        resp.extend_from_slice(&tmp[..n]);
-        if resp.len() > MAX_RESP_BYTES {
-            return Err(anyhow!("upstream response too large"));
+        Err(e) => {
+            audit_admin_change(req, caller, previous, Err(format!("{e:#}")));
+            text("HTTP/1.1 409 Conflict", &format!("{e:#}"))
         }
     }
-
-    Ok(resp)
 }
 
-fn split_http_response(resp: &[u8]) -> Result<(Vec<u8>, Vec<u8>)> {
-    let header_end = find_double_crlf(resp).ok_or_else(|| anyhow!("invalid upstream response"))?;
-    let head = resp[..header_end].to_vec();
-    let body = resp[header_end + 4..].to_vec();
-    Ok((head, body))
+/// `POST /admin/cache/purge[?failures=1]`: empties the response cache, or
+/// drops only its cached failures.
+fn cache_purge_response(req: &RequestLine, caller: &AdminCaller) -> Vec<u8> {
+    let text = |status: &str, message: &str| {
+        build_response(status, message.as_bytes(), "admin", Some("text/plain"), &[])
+    };
+    if req.method != "POST" {
+        return text("HTTP/1.1 405 Method Not Allowed", "use POST");
+    }
+    let Some(cache) = cache::configured() else {
+        return text(
+            "HTTP/1.1 404 Not Found",
+            "response cache disabled (set CACHE_TTL_SECS or CACHE_NEGATIVE_SECS)",
+        );
+    };
+    let failures_only = query_param(&req.path, "failures").is_some_and(|v| v == "1");
+    let previous = serde_json::json!({ "entries": cache.len() });
+    let purged = cache.purge(failures_only);
+    let outcome = serde_json::json!({ "purged": purged, "failures_only": failures_only });
+    audit_admin_change(req, caller, previous, Ok(outcome.clone()));
+    build_response(
+        "HTTP/1.1 200 OK",
+        outcome.to_string().as_bytes(),
+        "admin",
+        Some("application/json"),
+        &[],
+    )
 }
 
-fn parse_status_code_from_head(head: &[u8]) -> Result<u16> {
-    let head_str = std::str::from_utf8(head).context("resp head not utf8")?;
-    let status_line = head_str
-        .split("\r\n")
-        .next()
-        .ok_or_else(|| anyhow!("missing status line"))?;
-    let status = status_line
-        .split_whitespace()
-        .nth(1)
-        .ok_or_else(|| anyhow!("missing status code"))?
-        .parse::<u16>()
-        .context("invalid status code")?;
-    Ok(status)
+/// Probes the upstream with a bare TCP connect and checks that the wasm module is
+/// loadable by the configured runtime. Returns (healthy, JSON body).
+fn health_report(config: &Config) -> (bool, String) {
+    let live = config.live();
+    let probe = health::probe_upstream(&live.upstream);
+
+    let module = std::fs::read(&live.wasm_module_path)
+        .with_context(|| format!("read wasm module {}", live.wasm_module_path))
+        .and_then(|bytes| {
+            if config.transform.name() == "wasmtime_embedded" {
+                get_or_compile_embedded_wasmtime(&live.wasm_module_path)?;
+            }
+            Ok(bytes)
+        });
+    let (module_loaded, module_sha256, module_size, module_error) = match module {
+        Ok(bytes) => (
+            true,
+            json_string(&hex::encode(Sha256::digest(&bytes))),
+            bytes.len().to_string(),
+            "null".to_string(),
+        ),
+        Err(e) => (
+            false,
+            "null".to_string(),
+            "null".to_string(),
+            json_string(&format!("{e:#}")),
+        ),
+    };
+    let cached_modules = WASMTIME_EMBEDDED_CACHE
+        .read()
+        .map(|cache| cache.len())
+        .unwrap_or(0);
+    let backend = config.transform.name();
+    let execution = match backend {
+        "wasmedge" | "wasmtime" => "process-per-request",
+        "http" => "remote",
+        _ => "in-process",
+    };
+    // Only the wasm backends need the module.
+    let needs_module = matches!(backend, "wasmtime_embedded" | "wasmedge" | "wasmtime");
+
+    let healthy = probe.reachable && (module_loaded || !needs_module);
+    let body = format!(
+        concat!(
+            "{{\"status\":{},\"variant\":{},\"uptime_secs\":{:.3},",
+            "\"upstream\":{},",
+            "\"wasm\":{{\"module_path\":{},\"runtime\":{},\"loaded\":{},\"sha256\":{},",
+            "\"size_bytes\":{},\"error\":{}}},",
+            "\"transform\":{{\"backend\":{}}},",
+            "\"pool\":{{\"execution\":{},\"cached_modules\":{}}}}}"
+        ),
+        json_string(if healthy { "ok" } else { "degraded" }),
+        json_string(GATEWAY_VARIANT),
+        health::uptime().as_secs_f64(),
+        probe.json,
+        json_string(&live.wasm_module_path),
+        json_string(&config.wasm_runtime),
+        module_loaded,
+        module_sha256,
+        module_size,
+        module_error,
+        json_string(backend),
+        json_string(execution),
+        cached_modules,
+    );
+    (healthy, body)
 }
 
-fn build_response(
+/// `http::rebuild_response`, also dropping the fields only this gateway
+/// adds, so an upstream cannot pass off its own.
+fn rebuild_response(
     status_line: &str,
+    mut headers: Headers,
     body: &[u8],
+    trailers: &Headers,
     workload: &str,
-    content_type: Option<&str>,
     extra_headers: &[(&str, &str)],
 ) -> Vec<u8> {
-    let mut out = Vec::<u8>::new();
-    out.extend_from_slice(status_line.as_bytes());
-    out.extend_from_slice(b"\r\n");
-
-    if let Some(content_type) = content_type {
-        out.extend_from_slice(format!("Content-Type: {content_type}\r\n").as_bytes());
+    for name in [
+        "X-Wasm-Processed",
+        "X-Schema-Validation-Us",
+        "X-Coalesced",
+        "X-Cache",
+        "X-Cached-Failure",
+    ] {
+        headers.remove(name);
     }
+    http::rebuild_response(
+        status_line,
+        headers,
+        body,
+        trailers,
+        workload,
+        extra_headers,
+    )
+}
 
-    out.extend_from_slice(format!("X-Gateway-Variant: {GATEWAY_VARIANT}\r\n").as_bytes());
-    out.extend_from_slice(format!("X-Gateway-Workload: {workload}\r\n").as_bytes());
-
-    for (name, value) in extra_headers {
-        out.extend_from_slice(format!("{name}: {value}\r\n").as_bytes());
+/// Reads `name` from `dir`; `None` when it does not exist or is not a plain
+/// file name (no separators, no leading dot).
+fn read_template(dir: &Path, name: &str) -> Result<Option<Vec<u8>>> {
+    if name.is_empty() || name.starts_with('.') || name.contains(['/', '\\']) {
+        return Ok(None);
+    }
+    let path = dir.join(name);
+    match std::fs::read(&path) {
+        Ok(template) => Ok(Some(template)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("read template {}", path.display())),
     }
-
-    out.extend_from_slice(format!("Content-Length: {}\r\n", body.len()).as_bytes());
-    out.extend_from_slice(b"Connection: close\r\n\r\n");
-    out.extend_from_slice(body);
-    out
 }
 
-fn rebuild_response_with_extra_headers(
-    head: &[u8],
-    body: &[u8],
-    workload: &str,
-    extra_headers: &[(&str, &str)],
+/// Runs `input` through the configured backend, adding the time spent to the
+/// request's `wasm_us`.
+fn run_transform(
+    transform: &dyn transform::Transform,
+    input: &[u8],
+    envelope: &Envelope,
 ) -> Result<Vec<u8>> {
-    let head_str = std::str::from_utf8(head).context("resp head not utf8")?;
-
-    let mut lines = head_str.split("\r\n");
-    let status = lines.next().ok_or_else(|| anyhow!("missing status line"))?;
-
-    let mut out = Vec::<u8>::new();
-    out.extend_from_slice(status.as_bytes());
-    out.extend_from_slice(b"\r\n");
-
-    for line in lines {
-        if line.is_empty() {
-            continue;
-        }
-        let lower = line.to_ascii_lowercase();
-        if lower.starts_with("content-length:")
-            || lower.starts_with("connection:")
-            || lower.starts_with("x-gateway-variant:")
-            || lower.starts_with("x-gateway-workload:")
-            || lower.starts_with("x-upstream-url:")
-            || lower.starts_with("x-upstream-status:")
-            || lower.starts_with("x-wasm-processed:")
-        {
-            continue;
+    let req_id = envelope.get("REQ_ID").unwrap_or("-");
+    if envelope.debug.no_wasm {
+        let mut headers = envelope
+            .response_headers
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        if !headers.iter().any(|(k, _)| k == "X-Transform-Skipped") {
+            headers.push(("X-Transform-Skipped".to_string(), "true".to_string()));
         }
-        out.extend_from_slice(line.as_bytes());
-        out.extend_from_slice(b"\r\n");
+        drop(headers);
+        envelope.debug.log(
+            req_id,
+            "transform",
+            format_args!("skipped, {} B passed through", input.len()),
+        );
+        return Ok(input.to_vec());
     }
+    let started = Instant::now();
+    let result = transform.transform(input, envelope);
+    let us = started.elapsed().as_micros() as u64;
+    envelope.wasm_us.fetch_add(us, Ordering::Relaxed);
+    envelope.debug.log(
+        req_id,
+        "transform",
+        format_args!(
+            "{} {} B -> {}, {us} us",
+            transform.name(),
+            input.len(),
+            match &result {
+                Ok(output) => format!("{} B", output.len()),
+                Err(e) => format!("error: {e:#}"),
+            }
+        ),
+    );
+    let output = result.map_err(|e| match errors::classify(&e, true) {
+        errors::GatewayError::Internal => e.context(errors::GatewayError::Transform),
+        _ => e,
+    })?;
+    deadline::check(envelope.deadline)?;
+    Ok(output)
+}
 
-    out.extend_from_slice(format!("X-Gateway-Variant: {GATEWAY_VARIANT}\r\n").as_bytes());
-    out.extend_from_slice(format!("X-Gateway-Workload: {workload}\r\n").as_bytes());
-    for (name, value) in extra_headers {
-        out.extend_from_slice(format!("{name}: {value}\r\n").as_bytes());
+/// Writes a complete response and records its status code in `trace`. The
+/// write gives up at the request deadline, whatever the response is.
+fn respond(
+    client: &mut ClientStream,
+    resp: &[u8],
+    trace: &mut RequestTrace,
+) -> std::io::Result<()> {
+    trace.status = resp
+        .get(9..12)
+        .and_then(|code| std::str::from_utf8(code).ok())
+        .and_then(|code| code.parse().ok())
+        .unwrap_or(0);
+    if *FRAMING_AUDIT {
+        if let Err(reason) = chunked::audit_response(resp, trace.method == "HEAD") {
+            metrics::METRICS.framing_mismatch();
+            eprintln!(
+                "[wasm-host] framing mismatch req_id={} {} {} status {}: {reason}",
+                trace.req_id, trace.method, trace.path, trace.status
+            );
+        }
     }
-    out.extend_from_slice(format!("Content-Length: {}\r\n", body.len()).as_bytes());
-    out.extend_from_slice(b"Connection: close\r\n\r\n");
-    out.extend_from_slice(body);
-    Ok(out)
+    client
+        .set_write_timeout(Some(deadline::clamp(IO_TIMEOUT, trace.deadline)))
+        .ok();
+    client.write_all(resp)
 }
 
-fn wasm_transform(runtime: &str, module_path: &str, input: &[u8]) -> Result<Vec<u8>> {
-    match runtime {
-        "wasmedge" | "wasmtime" => wasm_transform_cli(runtime, module_path, input),
-        "wasmtime_embedded" => wasm_transform_wasmtime_embedded(module_path, input),
-        _ => Err(anyhow!("unsupported wasm runtime: {runtime}")),
-    }
+/// `respond`, then flushes and closes the connection.
+fn respond_and_close(
+    client: &mut ClientStream,
+    resp: &[u8],
+    trace: &mut RequestTrace,
+) -> Result<()> {
+    respond(client, resp, trace)?;
+    client.flush().ok();
+    client.shutdown(Shutdown::Both).ok();
+    Ok(())
 }
 
-fn wasm_transform_cli(runtime: &str, module_path: &str, input: &[u8]) -> Result<Vec<u8>> {
+/// `wasmedge` / `wasmtime run` for `module_path` with the guest's static
+/// configuration and `vars` in its environment, inside `sandbox`.
+fn runtime_command(
+    runtime: &str,
+    module_path: &str,
+    vars: &[(String, String)],
+    sandbox: &sandbox::Sandbox,
+    guest: &transform::GuestConfig,
+) -> Result<Command> {
     let mut cmd = match runtime {
-        "wasmedge" => {
-            let mut cmd = Command::new("wasmedge");
-            cmd.arg(module_path);
-            cmd
-        }
+        "wasmedge" => Command::new("wasmedge"),
         "wasmtime" => {
             let mut cmd = Command::new("wasmtime");
-            cmd.arg("run").arg(module_path);
+            cmd.arg("run");
             cmd
         }
         _ => return Err(anyhow!("unsupported CLI wasm runtime: {runtime}")),
     };
+    for (k, v) in guest.env.iter().chain(vars) {
+        cmd.arg("--env").arg(format!("{k}={v}"));
+    }
+    cmd.args(sandbox.preopen_args(runtime));
+    cmd.arg(module_path);
+    cmd.args(&guest.args);
+    sandbox.apply(&mut cmd);
+    Ok(cmd)
+}
 
-    let mut child = cmd
+fn wasm_transform_cli(
+    runtime: &str,
+    module_path: &str,
+    input: &[u8],
+    envelope: &Envelope,
+    timeout: Option<Duration>,
+    sandbox: &sandbox::Sandbox,
+    guest: &transform::GuestConfig,
+) -> Result<Vec<u8>> {
+    // Held until the child is reaped below.
+    let _permit = procs::acquire()?;
+    let timeout = deadline::clamp_opt(timeout, envelope.deadline);
+    let mut child = runtime_command(runtime, module_path, &envelope.vars, sandbox, guest)?
         .stdin(Stdio::piped())
         .stdout(Stdio::piped())
         .stderr(Stdio::piped())
         .spawn()
+        .context(errors::GatewayError::WasmSpawn)
         .with_context(|| format!("failed to spawn {runtime} for module {module_path}"))?;
+    let mut stdin = child
+        .stdin
+        .take()
+        .ok_or_else(|| anyhow!("failed to open stdin for {runtime}"))?;
+    let mut stdout = child
+        .stdout
+        .take()
+        .ok_or_else(|| anyhow!("failed to open stdout for {runtime}"))?;
+    let mut stderr = child
+        .stderr
+        .take()
+        .ok_or_else(|| anyhow!("failed to open stderr for {runtime}"))?;
+
+    // The watchdog only kills while holding the lock and after `try_wait` saw
+    // the child still running, so it never signals a reaped (reused) pid.
+    let child = Arc::new(Mutex::new(child));
+    procs::register(&child);
+    let timed_out = Arc::new(AtomicBool::new(false));
+    let (done_tx, done_rx) = mpsc::channel::<()>();
+    if let Some(timeout) = timeout {
+        let child = Arc::clone(&child);
+        let timed_out = Arc::clone(&timed_out);
+        std::thread::spawn(move || {
+            if done_rx.recv_timeout(timeout) != Err(RecvTimeoutError::Timeout) {
+                return;
+            }
+            let mut child = child.lock().unwrap_or_else(|e| e.into_inner());
+            if matches!(child.try_wait(), Ok(None)) {
+                timed_out.store(true, Ordering::Relaxed);
+                child.kill().ok();
+            }
+        });
+    }
 
-    {
-        let stdin = child
-            .stdin
-            .as_mut()
-            .ok_or_else(|| anyhow!("failed to open stdin for {runtime}"))?;
-        stdin
-            .write_all(input)
-            .with_context(|| format!("failed writing input to {runtime}"))?;
-    }
-
-    let output = child
-        .wait_with_output()
-        .with_context(|| format!("failed waiting for {runtime} process"))?;
-    if !output.status.success() {
-        let stderr = String::from_utf8_lossy(&output.stderr);
-        return Err(anyhow!(
-            "{runtime} exited with status {}: {}",
-            output.status,
-            stderr.trim()
-        ));
+    let stderr_reader = std::thread::spawn(move || {
+        let mut buf = Vec::new();
+        stderr.read_to_end(&mut buf).ok();
+        buf
+    });
+    // A module that never reads stdin would block this write; the watchdog
+    // covers it too.
+    let write_result = stdin.write_all(input);
+    drop(stdin);
+    let mut output = Vec::new();
+    let read_result = stdout.read_to_end(&mut output);
+    let stderr_output = stderr_reader.join().unwrap_or_default();
+    log_guest_stderr(envelope, &stderr_output);
+    // Both pipes are closed, so the child has exited or is about to.
+    let status = loop {
+        let mut guard = child.lock().unwrap_or_else(|e| e.into_inner());
+        match guard
+            .try_wait()
+            .with_context(|| format!("failed waiting for {runtime} process"))?
+        {
+            Some(status) => break status,
+            None => {
+                drop(guard);
+                std::thread::sleep(Duration::from_millis(1));
+            }
+        }
+    };
+    drop(done_tx);
+
+    if timed_out.load(Ordering::Relaxed) {
+        return Err(anyhow::Error::new(transform::WasmTimeout {
+            timeout: timeout.unwrap_or_default(),
+        })
+        .context(format!("{runtime} killed for module {module_path}")));
+    }
+    write_result.with_context(|| format!("failed writing input to {runtime}"))?;
+    read_result.with_context(|| format!("failed reading output from {runtime}"))?;
+    if !status.success() {
+        let stderr = String::from_utf8_lossy(&stderr_output);
+        return Err(
+            anyhow::Error::new(errors::GatewayError::WasmTrap).context(format!(
+                "{runtime} exited with status {}: {}",
+                status,
+                stderr.trim()
+            )),
+        );
     }
 
-    Ok(output.stdout)
+    Ok(output)
 }
 
-fn wasm_transform_wasmtime_embedded(module_path: &str, input: &[u8]) -> Result<Vec<u8>> {
+fn wasm_transform_wasmtime_embedded(
+    module_path: &str,
+    input: &[u8],
+    envelope: &Envelope,
+    guest: &transform::GuestConfig,
+    reuse: Option<u64>,
+    timeout: Option<Duration>,
+) -> Result<Vec<u8>> {
     let runtime = get_or_compile_embedded_wasmtime(module_path)?;
+    run_embedded_wasmtime(
+        &runtime,
+        module_path,
+        input,
+        envelope,
+        guest,
+        reuse,
+        timeout,
+    )
+}
+
+/// Runs `input` through an already compiled module, on a fresh instance
+/// unless `reuse` lets a reactor instance serve that many calls. The call is
+/// interrupted after `timeout` or at the request deadline.
+fn run_embedded_wasmtime(
+    runtime: &EmbeddedWasmtime,
+    module_path: &str,
+    input: &[u8],
+    envelope: &Envelope,
+    guest: &transform::GuestConfig,
+    reuse: Option<u64>,
+    timeout: Option<Duration>,
+) -> Result<Vec<u8>> {
+    if runtime.reactor {
+        // A warm instance's WASI environment predates the request; a guest
+        // that can read it but takes no `gateway_env` runs fresh instead.
+        let warm_env = runtime.env_export || !runtime.reads_env || envelope.vars.is_empty();
+        let result = match reuse {
+            Some(max_calls) if warm_env => wasm_transform_warm(
+                runtime,
+                module_path,
+                input,
+                envelope,
+                guest,
+                max_calls,
+                timeout,
+            ),
+            _ => wasm_transform_reactor(runtime, module_path, input, envelope, guest, timeout),
+        };
+        return result.map_err(|e| embedded_interrupt(e, timeout));
+    }
 
     let stdin_pipe = MemoryInputPipe::new(input.to_vec());
     let stdout_pipe = MemoryOutputPipe::new(usize::MAX);
+    let stderr_pipe = MemoryOutputPipe::new(usize::MAX);
 
-    let mut wasi_builder = WasiCtxBuilder::new();
+    let mut wasi_builder = embedded_wasi(module_path, guest, Some(envelope), &stderr_pipe);
     wasi_builder.stdin(stdin_pipe);
     wasi_builder.stdout(stdout_pipe.clone());
-    wasi_builder.arg(module_path);
     let mut store = Store::new(&runtime.engine, wasi_builder.build_p1());
+    set_embedded_deadline(&mut store, timeout, envelope.deadline);
 
-    let mut linker: Linker<WasiP1Ctx> = Linker::new(&runtime.engine);
-    p1::add_to_linker_sync(&mut linker, |ctx| ctx)
-        .context("failed to add WASI preview1 imports for embedded runtime")?;
-
-    let instance = linker
-        .instantiate(&mut store, &runtime.module)
-        .with_context(|| format!("failed to instantiate embedded module {module_path}"))?;
+    let instance = runtime
+        .pre
+        .instantiate(&mut store)
+        .with_context(|| format!("failed to instantiate embedded module {module_path}"))
+        .map_err(|e| embedded_interrupt(e, timeout))?;
     let start = instance
         .get_typed_func::<(), ()>(&mut store, "_start")
         .context("embedded module is missing _start")?;
 
-    if let Err(err) = start.call(&mut store, ()) {
+    let result = start.call(&mut store, ());
+    log_guest_stderr(envelope, &stderr_pipe.contents());
+    if let Err(err) = result {
+        if err.downcast_ref::<Trap>() == Some(&Trap::Interrupt) {
+            return Err(embedded_interrupt(err, timeout));
+        }
         if let Some(exit) = err.downcast_ref::<I32Exit>() {
             if exit.0 != 0 {
-                return Err(anyhow!("wasmtime_embedded exited with status {}", exit.0));
+                return Err(anyhow::Error::new(errors::GatewayError::WasmTrap)
+                    .context(format!("wasmtime_embedded exited with status {}", exit.0)));
             }
         } else {
-            return Err(err).context("embedded module _start call failed");
+            return Err(err)
+                .context(errors::GatewayError::WasmTrap)
+                .context("embedded module _start call failed");
         }
     }
 
     Ok(stdout_pipe.contents().to_vec())
 }
 
+/// Parses `WASM_ROUTE_EXPORTS=/prefix=export,...`, longest prefix first.
+fn parse_route_exports() -> Result<Vec<(String, String)>> {
+    let Ok(spec) = env::var("WASM_ROUTE_EXPORTS") else {
+        return Ok(Vec::new());
+    };
+    let mut routes = spec
+        .split(',')
+        .map(str::trim)
+        .filter(|e| !e.is_empty())
+        .map(|entry| {
+            let (prefix, export) = entry.split_once('=').ok_or_else(|| {
+                anyhow!("invalid WASM_ROUTE_EXPORTS entry {entry:?} (expected /prefix=export)")
+            })?;
+            Ok((prefix.trim().to_string(), export.trim().to_string()))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    routes.sort_by_key(|(prefix, _)| std::cmp::Reverse(prefix.len()));
+    Ok(routes)
+}
+
+/// Route exports only work with an embedded reactor module; every named export
+/// must exist with the reactor signature, so typos fail at startup.
+fn check_route_exports(
+    transform: &dyn transform::Transform,
+    module_path: &str,
+    routes: &[(String, String)],
+) -> Result<()> {
+    if transform.name() != "wasmtime_embedded" {
+        return Err(anyhow!(
+            "WASM_ROUTE_EXPORTS requires the wasmtime_embedded backend (got {})",
+            transform.name()
+        ));
+    }
+    let compiled = get_or_compile_embedded_wasmtime(module_path)?;
+    if !compiled.reactor {
+        return Err(anyhow!(
+            "WASM_ROUTE_EXPORTS requires a reactor module, {module_path} has _start"
+        ));
+    }
+    for (prefix, export) in routes {
+        let ok = match compiled.module.get_export(export) {
+            Some(wasmtime::ExternType::Func(ty)) => {
+                let params: Vec<_> = ty.params().collect();
+                let results: Vec<_> = ty.results().collect();
+                params.len() == 2
+                    && params.iter().all(|p| matches!(p, wasmtime::ValType::I32))
+                    && matches!(results.as_slice(), [wasmtime::ValType::I64])
+            }
+            _ => false,
+        };
+        if !ok {
+            return Err(anyhow!(
+                "WASM_ROUTE_EXPORTS {prefix}={export}: {module_path} has no {export}(i32, i32) -> i64"
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Calls the reactor `transform` export (or the route's export) on a fresh
+/// instance. WASI is still
+/// linked so the guest can read the `GATEWAY_*` envelope; stdin and stdout
+/// are closed and stderr goes to the host log.
+fn wasm_transform_reactor(
+    runtime: &EmbeddedWasmtime,
+    module_path: &str,
+    input: &[u8],
+    envelope: &Envelope,
+    guest: &transform::GuestConfig,
+    timeout: Option<Duration>,
+) -> Result<Vec<u8>> {
+    let stderr_pipe = MemoryOutputPipe::new(usize::MAX);
+    let mut store = Store::new(
+        &runtime.engine,
+        embedded_wasi(module_path, guest, Some(envelope), &stderr_pipe).build_p1(),
+    );
+    set_embedded_deadline(&mut store, timeout, envelope.deadline);
+    let result = instantiate_reactor(&mut store, runtime, module_path)
+        .and_then(|instance| call_reactor(&mut store, instance, input, envelope));
+    log_guest_stderr(envelope, &stderr_pipe.contents());
+    result
+}
+
+/// Calls the reactor on an idle instance from `runtime.warm`, or a new one,
+/// and keeps it for the next call until it has served `max_calls` or failed.
+/// Its WASI environment is fixed when it is created, with the `GUEST_*`
+/// variables only; the per-request `GATEWAY_*` ones go through `gateway_env`.
+fn wasm_transform_warm(
+    runtime: &EmbeddedWasmtime,
+    module_path: &str,
+    input: &[u8],
+    envelope: &Envelope,
+    guest: &transform::GuestConfig,
+    max_calls: u64,
+    timeout: Option<Duration>,
+) -> Result<Vec<u8>> {
+    let idle = runtime.warm.lock().unwrap_or_else(|e| e.into_inner()).pop();
+    let mut warm = match idle {
+        Some(warm) => warm,
+        None => {
+            let stderr = MemoryOutputPipe::new(usize::MAX);
+            let mut store = Store::new(
+                &runtime.engine,
+                embedded_wasi(module_path, guest, None, &stderr).build_p1(),
+            );
+            set_embedded_deadline(&mut store, timeout, envelope.deadline);
+            let instance = instantiate_reactor(&mut store, runtime, module_path)?;
+            WarmInstance {
+                store,
+                instance,
+                stderr,
+                logged: 0,
+                calls: 0,
+            }
+        }
+    };
+    set_embedded_deadline(&mut warm.store, timeout, envelope.deadline);
+    let result = if runtime.env_export {
+        reactor_env(&mut warm.store, warm.instance, envelope)
+    } else {
+        Ok(())
+    }
+    .and_then(|()| call_reactor(&mut warm.store, warm.instance, input, envelope));
+    let stderr = warm.stderr.contents();
+    log_guest_stderr(envelope, &stderr[warm.logged..]);
+    warm.logged = stderr.len();
+    warm.calls += 1;
+    if result.is_ok() && warm.calls < max_calls {
+        runtime
+            .warm
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(warm);
+    }
+    result
+}
+
+/// WASI for an embedded instance: argv, the guest environment plus the
+/// request's when there is one, and stderr captured in `stderr`.
+fn embedded_wasi(
+    module_path: &str,
+    guest: &transform::GuestConfig,
+    envelope: Option<&Envelope>,
+    stderr: &MemoryOutputPipe,
+) -> WasiCtxBuilder {
+    let mut wasi_builder = WasiCtxBuilder::new();
+    wasi_builder.stderr(stderr.clone());
+    wasi_builder.arg(module_path);
+    for arg in &guest.args {
+        wasi_builder.arg(arg);
+    }
+    let request_vars = envelope.map(|e| e.vars.as_slice()).unwrap_or_default();
+    for (k, v) in guest.env.iter().chain(request_vars) {
+        wasi_builder.env(k, v);
+    }
+    wasi_builder
+}
+
+/// Interrupts calls into `store` once `timeout` (`WASM_TIMEOUT_MS`) or the
+/// request `deadline` passes, whichever comes first. Stores without either
+/// still get a deadline, since the engine traps at the default of zero.
+fn set_embedded_deadline(
+    store: &mut Store<WasiP1Ctx>,
+    timeout: Option<Duration>,
+    deadline: Option<Instant>,
+) {
+    let ticks = match deadline::clamp_opt(timeout, deadline) {
+        Some(left) => left
+            .as_nanos()
+            .div_ceil(EPOCH_TICK.as_nanos())
+            .clamp(1, u128::from(u64::MAX / 2)) as u64,
+        // `set_epoch_deadline` adds to the current epoch without saturating.
+        None => u64::MAX / 2,
+    };
+    store.set_epoch_deadline(ticks);
+}
+
+/// `err` as a `WasmTimeout` when the call was interrupted at its epoch
+/// deadline.
+fn embedded_interrupt(err: anyhow::Error, timeout: Option<Duration>) -> anyhow::Error {
+    if err.downcast_ref::<Trap>() != Some(&Trap::Interrupt) {
+        return err;
+    }
+    anyhow::Error::new(transform::WasmTimeout {
+        timeout: timeout.unwrap_or_default(),
+    })
+    .context(format!("embedded module interrupted: {err:#}"))
+}
+
+/// An engine whose calls can be interrupted, with a thread advancing its
+/// epoch every `EPOCH_TICK` for as long as the engine is alive.
+pub(crate) fn embedded_engine() -> Result<Engine> {
+    let engine = Engine::new(wasmtime::Config::new().epoch_interruption(true))
+        .context("failed to create the embedded Wasmtime engine")?;
+    let weak = engine.weak();
+    std::thread::Builder::new()
+        .name("wasm-epoch".to_string())
+        .spawn(move || {
+            while let Some(engine) = weak.upgrade() {
+                engine.increment_epoch();
+                drop(engine);
+                std::thread::sleep(EPOCH_TICK);
+            }
+        })
+        .context("failed to start the wasm epoch thread")?;
+    Ok(engine)
+}
+
+fn instantiate_reactor(
+    store: &mut Store<WasiP1Ctx>,
+    runtime: &EmbeddedWasmtime,
+    module_path: &str,
+) -> Result<Instance> {
+    let instance = runtime
+        .pre
+        .instantiate(&mut *store)
+        .with_context(|| format!("failed to instantiate embedded module {module_path}"))?;
+    if let Ok(init) = instance.get_typed_func::<(), ()>(&mut *store, "_initialize") {
+        init.call(&mut *store, ())
+            .context("reactor module _initialize call failed")?;
+    }
+    Ok(instance)
+}
+
+fn call_reactor(
+    store: &mut Store<WasiP1Ctx>,
+    instance: Instance,
+    input: &[u8],
+    envelope: &Envelope,
+) -> Result<Vec<u8>> {
+    let export = envelope
+        .export
+        .as_deref()
+        .unwrap_or(REACTOR_TRANSFORM_EXPORT);
+    let transform = instance
+        .get_typed_func::<(u32, u32), u64>(&mut *store, export)
+        .with_context(|| format!("reactor module has no {export}(i32, i32) -> i64"))?;
+
+    let (memory, ptr, len) = reactor_write(store, instance, input)?;
+    let packed = transform
+        .call(&mut *store, (ptr, len))
+        .with_context(|| format!("reactor module {export} call failed"))?;
+
+    let out_ptr = (packed >> 32) as usize;
+    let out_len = (packed & 0xffff_ffff) as usize;
+    memory
+        .data(&*store)
+        .get(out_ptr..out_ptr + out_len)
+        .map(<[u8]>::to_vec)
+        .ok_or_else(|| anyhow!("reactor transform returned an out-of-bounds result"))
+}
+
+/// Hands `envelope.vars` to a warm instance through `gateway_env`.
+fn reactor_env(
+    store: &mut Store<WasiP1Ctx>,
+    instance: Instance,
+    envelope: &Envelope,
+) -> Result<()> {
+    let set_env = instance
+        .get_typed_func::<(u32, u32), ()>(&mut *store, REACTOR_ENV_EXPORT)
+        .with_context(|| format!("reactor module has no {REACTOR_ENV_EXPORT}(i32, i32)"))?;
+    let mut block = Vec::new();
+    for (k, v) in &envelope.vars {
+        block.extend_from_slice(k.as_bytes());
+        block.push(b'=');
+        block.extend_from_slice(v.as_bytes());
+        block.push(0);
+    }
+    let (_, ptr, len) = reactor_write(store, instance, &block)?;
+    set_env
+        .call(&mut *store, (ptr, len))
+        .with_context(|| format!("reactor module {REACTOR_ENV_EXPORT} call failed"))
+}
+
+/// Copies `bytes` into a buffer from `gateway_alloc`, returning the memory
+/// and the buffer.
+fn reactor_write(
+    store: &mut Store<WasiP1Ctx>,
+    instance: Instance,
+    bytes: &[u8],
+) -> Result<(wasmtime::Memory, u32, u32)> {
+    let memory = instance
+        .get_memory(&mut *store, "memory")
+        .ok_or_else(|| anyhow!("reactor module does not export memory"))?;
+    let alloc = instance
+        .get_typed_func::<u32, u32>(&mut *store, REACTOR_ALLOC_EXPORT)
+        .with_context(|| format!("reactor module has no {REACTOR_ALLOC_EXPORT}(i32) -> i32"))?;
+    let len = u32::try_from(bytes.len()).context("input too large for a wasm32 module")?;
+    let ptr = alloc
+        .call(&mut *store, len)
+        .context("reactor module gateway_alloc call failed")?;
+    memory
+        .write(&mut *store, ptr as usize, bytes)
+        .context("gateway_alloc returned an out-of-bounds buffer")?;
+    Ok((memory, ptr, len))
+}
+
 fn get_or_compile_embedded_wasmtime(module_path: &str) -> Result<Arc<EmbeddedWasmtime>> {
     {
         let cache = WASMTIME_EMBEDDED_CACHE
@@ -608,10 +3427,10 @@ fn get_or_compile_embedded_wasmtime(module_path: &str) -> Result<Arc<EmbeddedWas
         }
     }
 
-    let engine = Engine::default();
+    let engine = embedded_engine()?;
     let module = Module::from_file(&engine, module_path)
         .with_context(|| format!("failed to compile wasm module at {module_path}"))?;
-    let compiled = Arc::new(EmbeddedWasmtime { engine, module });
+    let compiled = Arc::new(EmbeddedWasmtime::new(engine, module)?);
 
     let mut cache = WASMTIME_EMBEDDED_CACHE
         .write()
@@ -623,6 +3442,522 @@ fn get_or_compile_embedded_wasmtime(module_path: &str) -> Result<Arc<EmbeddedWas
     Ok(compiled)
 }
 
-fn find_double_crlf(buf: &[u8]) -> Option<usize> {
-    buf.windows(4).position(|w| w == b"\r\n\r\n")
+/// Drops a compiled module from the cache once nothing will run it again.
+fn forget_embedded_wasmtime(module_path: &str) {
+    if let Ok(mut cache) = WASMTIME_EMBEDDED_CACHE.write() {
+        cache.remove(module_path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn target(upstream_url: &str, path: &str) -> String {
+        forwarded_target(&parse_upstream(upstream_url).unwrap(), path)
+    }
+
+    #[test]
+    fn parses_vhost_upstreams() {
+        let vhosts =
+            parse_vhosts("API.local=http://127.0.0.1:9000/api, web.local=https://web").unwrap();
+        assert_eq!(vhosts[0].host, "api.local");
+        assert_eq!(vhosts[0].upstream.base_path, "/api");
+        assert!(!vhosts[0].upstream.tls && vhosts[1].upstream.tls);
+        assert_eq!(vhosts[1].upstream.port, 443);
+        assert!(parse_vhosts("api.local").is_err());
+        assert!(parse_vhosts("api.local=ftp://x").is_err());
+
+        assert_eq!(host_name("api.local:8443"), "api.local");
+        assert_eq!(host_name("api.local"), "api.local");
+        assert_eq!(host_name("[::1]:8443"), "[::1]");
+        assert_eq!(host_name("[::1]"), "[::1]");
+    }
+
+    #[test]
+    fn root_upstream_forwards_target_unchanged() {
+        assert_eq!(target("http://up:8080", "/"), "/");
+        assert_eq!(target("http://up:8080/", "/?x=1"), "/?x=1");
+        assert_eq!(target("http://up:8080", "/a/b/"), "/a/b/");
+        assert_eq!(target("http://up:8080", "//a"), "//a");
+    }
+
+    #[test]
+    fn base_path_joins_with_single_slash() {
+        assert_eq!(target("http://up/api", "/"), "/api/");
+        assert_eq!(target("http://up/api/", "/"), "/api/");
+        assert_eq!(target("http://up/api//", "/users"), "/api/users");
+        assert_eq!(target("http://up/api", "//users"), "/api/users");
+        assert_eq!(target("http://up/api", "/users/"), "/api/users/");
+    }
+
+    #[test]
+    fn query_strings_are_merged() {
+        assert_eq!(target("http://up/api", "/?x=1"), "/api/?x=1");
+        assert_eq!(
+            target("http://up/api", "/users?x=1&y=2"),
+            "/api/users?x=1&y=2"
+        );
+        assert_eq!(target("http://up/api?key=k", "/users"), "/api/users?key=k");
+        assert_eq!(
+            target("http://up/api?key=k", "/users?x=1"),
+            "/api/users?key=k&x=1"
+        );
+        assert_eq!(target("http://up/?key=k", "/?x=1"), "/?key=k&x=1");
+        assert_eq!(target("http://up/api", "/users?"), "/api/users");
+        assert_eq!(target("http://up/api?", "/users"), "/api/users");
+    }
+
+    #[test]
+    fn encoded_characters_pass_through() {
+        assert_eq!(
+            target("http://up/api", "/a%2Fb?q=%26%3D"),
+            "/api/a%2Fb?q=%26%3D"
+        );
+        assert_eq!(target("http://up/my%20api", "/x%20y"), "/my%20api/x%20y");
+        assert_eq!(target("http://up/my api", "/x"), "/my%20api/x");
+    }
+
+    #[test]
+    fn rebuild_response_keeps_every_set_cookie() {
+        gateway_core::set_variant(GATEWAY_VARIANT);
+        let (status, headers) = Headers::parse_head(
+            b"HTTP/1.1 200 OK\r\nSet-Cookie: a=1\r\nX-Upstream-Url: stale\r\n folded\r\nSet-Cookie: b=2\r\nContent-Length: 3",
+        )
+        .unwrap();
+        let resp = rebuild_response(
+            &status,
+            headers,
+            b"abcd",
+            &Headers::default(),
+            "proxy",
+            &[("X-Upstream-Url", "http://up"), ("Set-Cookie", "gw=1")],
+        );
+        let resp = String::from_utf8(resp).unwrap();
+        assert_eq!(
+            resp,
+            concat!(
+                "HTTP/1.1 200 OK\r\n",
+                "Set-Cookie: a=1\r\n",
+                "Set-Cookie: b=2\r\n",
+                "X-Gateway-Variant: wasm-host\r\n",
+                "X-Gateway-Workload: proxy\r\n",
+                "X-Upstream-Url: http://up\r\n",
+                "Set-Cookie: gw=1\r\n",
+                "Content-Length: 4\r\n",
+                "Connection: close\r\n\r\n",
+                "abcd"
+            )
+        );
+    }
+
+    #[test]
+    fn rebuild_response_forwards_trailers_chunked() {
+        gateway_core::set_variant(GATEWAY_VARIANT);
+        let (status, headers) = Headers::parse_head(
+            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\nTrailer: grpc-status",
+        )
+        .unwrap();
+        let trailers = Headers::parse("grpc-status: 0\r\ngrpc-message: ok");
+        let resp = rebuild_response(&status, headers, b"abcd", &trailers, "proxy", &[]);
+        assert_eq!(
+            String::from_utf8(resp).unwrap(),
+            concat!(
+                "HTTP/1.1 200 OK\r\n",
+                "X-Gateway-Variant: wasm-host\r\n",
+                "X-Gateway-Workload: proxy\r\n",
+                "Trailer: grpc-status, grpc-message\r\n",
+                "Transfer-Encoding: chunked\r\n",
+                "Connection: close\r\n\r\n",
+                "4\r\nabcd\r\n0\r\n",
+                "grpc-status: 0\r\ngrpc-message: ok\r\n\r\n"
+            )
+        );
+    }
+
+    #[test]
+    fn fragments_and_asterisk_form() {
+        assert_eq!(target("http://up/api", "/users#top"), "/api/users");
+        assert_eq!(target("http://up/api", "/users?x=1#top"), "/api/users?x=1");
+        assert_eq!(target("http://up/api", "*"), "*");
+    }
+
+    #[test]
+    fn warm_reactor_instances_are_reused_up_to_max_calls() {
+        // Prefixes its input with the number of calls this instance has served.
+        let wat = r#"(module
+            (memory (export "memory") 1)
+            (global $heap (mut i32) (i32.const 1024))
+            (global $calls (mut i32) (i32.const 0))
+            (func (export "gateway_alloc") (param $len i32) (result i32)
+                (global.get $heap)
+                (global.set $heap (i32.add (global.get $heap) (local.get $len))))
+            (func (export "transform") (param $ptr i32) (param $len i32) (result i64)
+                (global.set $calls (i32.add (global.get $calls) (i32.const 1)))
+                (i32.store8 (i32.const 16) (i32.add (global.get $calls) (i32.const 48)))
+                (memory.copy (i32.const 17) (local.get $ptr) (local.get $len))
+                (i64.or (i64.shl (i64.const 16) (i64.const 32))
+                        (i64.extend_i32_u (i32.add (local.get $len) (i32.const 1))))))"#;
+        let engine = embedded_engine().unwrap();
+        let module = Module::new(&engine, wat).unwrap();
+        let runtime = EmbeddedWasmtime::new(engine, module).unwrap();
+        assert!(runtime.reactor);
+        let guest = transform::GuestConfig::default();
+        let call = |reuse| {
+            let envelope = Envelope::default();
+            let out =
+                run_embedded_wasmtime(&runtime, "t.wat", b"x", &envelope, &guest, reuse, None)
+                    .unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        let warm: Vec<String> = (0..4).map(|_| call(Some(2))).collect();
+        assert_eq!(warm, ["1x", "2x", "1x", "2x"]);
+        assert_eq!(call(None), "1x");
+    }
+
+    #[test]
+    fn warm_reactor_instances_get_the_request_envelope() {
+        // Returns the block last passed to `gateway_env`.
+        let wat = r#"(module
+            (memory (export "memory") 1)
+            (global $heap (mut i32) (i32.const 1024))
+            (global $env (mut i32) (i32.const 0))
+            (global $env_len (mut i32) (i32.const 0))
+            (func (export "gateway_alloc") (param $len i32) (result i32)
+                (global.get $heap)
+                (global.set $heap (i32.add (global.get $heap) (local.get $len))))
+            (func (export "gateway_env") (param $ptr i32) (param $len i32)
+                (global.set $env (local.get $ptr))
+                (global.set $env_len (local.get $len)))
+            (func (export "transform") (param i32 i32) (result i64)
+                (i64.or (i64.shl (i64.extend_i32_u (global.get $env)) (i64.const 32))
+                        (i64.extend_i32_u (global.get $env_len)))))"#;
+        let engine = embedded_engine().unwrap();
+        let module = Module::new(&engine, wat).unwrap();
+        let runtime = EmbeddedWasmtime::new(engine, module).unwrap();
+        assert!(runtime.env_export);
+        let guest = transform::GuestConfig::default();
+        let call = |path: &str| {
+            let mut envelope = Envelope::default();
+            envelope.set("PATH", path);
+            envelope.set("METHOD", "GET");
+            let out =
+                run_embedded_wasmtime(&runtime, "t.wat", b"", &envelope, &guest, Some(10), None)
+                    .unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        assert_eq!(call("/a"), "GATEWAY_PATH=/a\0GATEWAY_METHOD=GET\0");
+        assert_eq!(call("/b"), "GATEWAY_PATH=/b\0GATEWAY_METHOD=GET\0");
+        assert_eq!(runtime.warm.lock().unwrap().len(), 1);
+
+        // Without `gateway_env`, a guest that can read its WASI environment
+        // gets a fresh instance whenever the request has variables.
+        let wat = r#"(module
+            (import "wasi_snapshot_preview1" "environ_get"
+                (func (param i32 i32) (result i32)))
+            (memory (export "memory") 1)
+            (global $heap (mut i32) (i32.const 1024))
+            (global $calls (mut i32) (i32.const 0))
+            (func (export "gateway_alloc") (param $len i32) (result i32)
+                (global.get $heap)
+                (global.set $heap (i32.add (global.get $heap) (local.get $len))))
+            (func (export "transform") (param i32 i32) (result i64)
+                (global.set $calls (i32.add (global.get $calls) (i32.const 1)))
+                (i32.store8 (i32.const 16) (i32.add (global.get $calls) (i32.const 48)))
+                (i64.or (i64.shl (i64.const 16) (i64.const 32)) (i64.const 1))))"#;
+        let engine = embedded_engine().unwrap();
+        let module = Module::new(&engine, wat).unwrap();
+        let runtime = EmbeddedWasmtime::new(engine, module).unwrap();
+        assert!(runtime.reads_env && !runtime.env_export);
+        let call = |envelope: &Envelope| {
+            let out =
+                run_embedded_wasmtime(&runtime, "t.wat", b"", envelope, &guest, Some(10), None)
+                    .unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        let mut envelope = Envelope::default();
+        assert_eq!(call(&envelope), "1");
+        assert_eq!(call(&envelope), "2");
+        envelope.set("PATH", "/a");
+        assert_eq!(call(&envelope), "1");
+        assert_eq!(call(&envelope), "1");
+    }
+
+    #[test]
+    fn embedded_calls_are_interrupted_at_their_deadline() {
+        let wat = r#"(module
+            (memory (export "memory") 1)
+            (func (export "gateway_alloc") (param i32) (result i32) (i32.const 1024))
+            (func (export "transform") (param i32 i32) (result i64)
+                (loop $spin (br $spin))
+                (i64.const 0)))"#;
+        let engine = embedded_engine().unwrap();
+        let module = Module::new(&engine, wat).unwrap();
+        let runtime = EmbeddedWasmtime::new(engine, module).unwrap();
+        let guest = transform::GuestConfig::default();
+        let timeout = Some(Duration::from_millis(50));
+        for reuse in [None, Some(10)] {
+            let start = Instant::now();
+            let envelope = Envelope::default();
+            let err =
+                run_embedded_wasmtime(&runtime, "t.wat", b"x", &envelope, &guest, reuse, timeout)
+                    .unwrap_err();
+            assert_eq!(
+                errors::classify(&err, true),
+                errors::GatewayError::WasmTimeout
+            );
+            assert!(start.elapsed() < Duration::from_secs(5));
+        }
+        // An interrupted warm instance is not kept.
+        assert!(runtime.warm.lock().unwrap().is_empty());
+
+        let envelope = Envelope {
+            deadline: Some(Instant::now() + Duration::from_millis(50)),
+            ..Envelope::default()
+        };
+        let err = run_embedded_wasmtime(&runtime, "t.wat", b"x", &envelope, &guest, None, None)
+            .unwrap_err();
+        assert_eq!(
+            errors::classify(&err, true),
+            errors::GatewayError::WasmTimeout
+        );
+    }
+
+    fn envelope_with_token(token: &str) -> Envelope {
+        let mut envelope = Envelope::default();
+        envelope.set("ENVELOPE_TOKEN", token);
+        envelope
+    }
+
+    fn added_headers(envelope: &Envelope) -> Vec<(String, String)> {
+        envelope.response_headers.lock().unwrap().clone()
+    }
+
+    #[test]
+    fn noop_passes_envelope_prefixed_bodies_through() {
+        let envelope = envelope_with_token("t0k3n");
+        for body in [
+            &b"GATEWAY-ENVELOPE/1\nSet-Cookie: session=evil\n\nbody"[..],
+            b"GATEWAY-ENVELOPE/1 t0k3n\nX-Cache: HIT\n\nbody",
+        ] {
+            let output = run_transform(&transform::Noop, body, &envelope).unwrap();
+            assert_eq!(output, body);
+        }
+        assert!(added_headers(&envelope).is_empty());
+    }
+
+    #[test]
+    fn response_envelopes_need_the_request_token() {
+        let envelope = envelope_with_token("t0k3n");
+        let output = transform::strip_response_envelope(
+            b"GATEWAY-ENVELOPE/1 t0k3n\nContent-Encoding: gzip\nContent-Length: 1\n\nzz".to_vec(),
+            &envelope,
+        );
+        assert_eq!(output, b"zz");
+        assert_eq!(
+            added_headers(&envelope),
+            [("Content-Encoding".to_string(), "gzip".to_string())]
+        );
+
+        for (token, body) in [
+            ("t0k3n", &b"GATEWAY-ENVELOPE/1\nX-Cache: HIT\n\nbody"[..]),
+            ("t0k3n", b"GATEWAY-ENVELOPE/1 guess\nX-Cache: HIT\n\nbody"),
+            ("t0k3n", b"GATEWAY-ENVELOPE/1 t0k3n2\nX-Cache: HIT\n\nbody"),
+            ("", b"GATEWAY-ENVELOPE/1 \nX-Cache: HIT\n\nbody"),
+        ] {
+            let envelope = envelope_with_token(token);
+            assert_eq!(
+                transform::strip_response_envelope(body.to_vec(), &envelope),
+                body
+            );
+            assert!(added_headers(&envelope).is_empty());
+        }
+    }
+    #[test]
+    fn admin_routes_fail_closed_without_credentials() {
+        let request = |headers: &str| {
+            parse_request_head(format!("POST /admin/wasm/load HTTP/1.1\r\n{headers}").as_bytes())
+                .unwrap()
+        };
+        let anonymous = request("");
+        let bearer = request("Authorization: Bearer s3cret\r\n");
+
+        // Nothing configured: `/health/full` stays open, admin routes do not.
+        assert_eq!(
+            operator_authorized(&anonymous, None, None, None, true).unwrap(),
+            "anonymous"
+        );
+        for req in [&anonymous, &bearer] {
+            assert!(matches!(
+                operator_authorized(req, None, None, None, false),
+                Err(basic_auth::Rejection::Unauthorized(
+                    "no admin credentials configured"
+                ))
+            ));
+        }
+
+        for open in [true, false] {
+            assert_eq!(
+                operator_authorized(&bearer, None, None, Some("s3cret"), open).unwrap(),
+                "token"
+            );
+            assert!(operator_authorized(&anonymous, None, None, Some("s3cret"), open).is_err());
+            assert!(operator_authorized(&bearer, None, None, Some("other"), open).is_err());
+        }
+    }
+}
+
+#[cfg(test)]
+mod rebuild_props {
+    use super::*;
+    use proptest::prelude::*;
+
+    /// Field names, including the framing ones in any case.
+    fn name() -> impl Strategy<Value = String> {
+        prop_oneof![
+            "[A-Za-z][A-Za-z0-9-]{0,11}",
+            prop::sample::select(vec![
+                "Content-Length",
+                "content-length",
+                "CONNECTION",
+                "Connection",
+                "Transfer-Encoding",
+                "Host",
+                "Expect",
+                "Set-Cookie",
+            ])
+            .prop_map(str::to_string),
+        ]
+    }
+
+    /// Valid field values: no leading or trailing whitespace, which parsing
+    /// trims.
+    fn value() -> impl Strategy<Value = String> {
+        "([!-~]([ -~]{0,20}[!-~])?)?"
+    }
+
+    fn fields() -> impl Strategy<Value = Vec<(String, String)>> {
+        prop::collection::vec((name(), value()), 0..12)
+    }
+
+    fn headers(fields: &[(String, String)]) -> Headers {
+        let mut headers = Headers::default();
+        for (name, value) in fields {
+            headers.append(name.clone(), value.clone());
+        }
+        headers
+    }
+
+    /// Field lines of a written head, which must all be `name: value`.
+    fn field_lines(head: &str) -> Vec<(&str, &str)> {
+        head.split("\r\n")
+            .skip(1)
+            .map(|line| line.split_once(": ").expect("field line without `: `"))
+            .collect()
+    }
+
+    fn count(lines: &[(&str, &str)], name: &str) -> usize {
+        lines
+            .iter()
+            .filter(|(n, _)| n.eq_ignore_ascii_case(name))
+            .count()
+    }
+
+    proptest! {
+        #[test]
+        fn forwarding_preserves_header_semantics(
+            fields in fields(),
+            body in prop::collection::vec(any::<u8>(), 0..64),
+        ) {
+            let req = RequestLine {
+                method: "POST".to_string(),
+                path: "/proxy".to_string(),
+                target: "/proxy".to_string(),
+                version: "HTTP/1.1".to_string(),
+                content_length: body.len(),
+                headers: headers(&fields),
+            };
+            let upstream = parse_upstream("http://up:8080/api").unwrap();
+            let out = build_forwarded_request(&req, "/proxy", &body, &upstream, &[]).unwrap();
+            let head_end = find_double_crlf(&out).unwrap();
+            prop_assert_eq!(&out[head_end + 4..], &body[..]);
+
+            // Parsed back, every field the gateway does not own keeps its
+            // values in order; Host and Connection are the gateway's alone.
+            let forwarded = Headers::parse_head(&out[..head_end]).unwrap().1;
+            for (name, _) in &fields {
+                if ["host", "connection", "expect"].contains(&name.to_ascii_lowercase().as_str()) {
+                    continue;
+                }
+                let sent: Vec<&str> = req.headers.get_all(name).collect();
+                let received: Vec<&str> = forwarded.get_all(name).collect();
+                prop_assert_eq!(sent, received);
+            }
+            prop_assert_eq!(forwarded.get_all("host").collect::<Vec<_>>(), ["up"]);
+            prop_assert_eq!(forwarded.get_all("connection").collect::<Vec<_>>(), ["close"]);
+            prop_assert!(forwarded.get("expect").is_none());
+        }
+
+        #[test]
+        fn rebuild_frames_exactly_once(
+            fields in fields(),
+            body in prop::collection::vec(any::<u8>(), 0..64),
+            trailers in prop::collection::vec(("[a-z][a-z-]{0,8}", value()), 0..3),
+        ) {
+            let trailers = headers(&trailers);
+            let resp = rebuild_response(
+                "HTTP/1.1 200 OK",
+                headers(&fields),
+                &body,
+                &trailers,
+                "proxy",
+                &[],
+            );
+            let head_end = find_double_crlf(&resp).unwrap();
+            let head = std::str::from_utf8(&resp[..head_end]).unwrap();
+            let lines = field_lines(head);
+            prop_assert_eq!(count(&lines, "connection"), 1);
+            prop_assert_eq!(
+                count(&lines, "content-length") + count(&lines, "transfer-encoding"),
+                1
+            );
+            prop_assert_eq!(chunked::audit_response(&resp, false), Ok(()));
+        }
+
+        #[test]
+        fn written_heads_never_gain_lines(
+            fields in prop::collection::vec((any::<String>(), any::<String>()), 0..8),
+            extra in prop::collection::vec(("[\\PC\r\n]{0,12}", "[\\PC\r\n\0]{0,24}"), 0..4),
+        ) {
+            let extra: Vec<(&str, &str)> =
+                extra.iter().map(|(n, v)| (n.as_str(), v.as_str())).collect();
+            let rebuilt = rebuild_response(
+                "HTTP/1.1 200 OK",
+                headers(&fields),
+                b"",
+                &Headers::default(),
+                "proxy",
+                &extra,
+            );
+            let built = build_response("HTTP/1.1 200 OK", b"", "proxy", None, &extra);
+            for resp in [rebuilt, built] {
+                let head_end = find_double_crlf(&resp).unwrap();
+                let head = &resp[..head_end];
+                // CR and LF only ever appear together, as line ends.
+                let paired = head.iter().enumerate().all(|(i, &b)| match b {
+                    b'\r' => head.get(i + 1) == Some(&b'\n'),
+                    b'\n' => i > 0 && head[i - 1] == b'\r',
+                    _ => true,
+                });
+                prop_assert!(paired);
+                let lines = field_lines(std::str::from_utf8(head).unwrap());
+                let valid = lines
+                    .iter()
+                    .all(|(name, value)| syntax::is_token(name) && syntax::is_field_value(value));
+                prop_assert!(valid);
+                prop_assert_eq!(count(&lines, "connection"), 1);
+                prop_assert_eq!(count(&lines, "content-length"), 1);
+            }
+        }
+    }
 }
@@ -0,0 +1,675 @@
+//! Process-wide request counters behind `/metrics` (Prometheus text format)
+//! and `/stats` (JSON). Everything is a relaxed atomic bumped once per request
+//! after the response is written; requests on `LISTEN_INTERNAL` are not
+//! counted. With `LISTEN_NATIVE`, requests are also broken down by pipeline.
+
+use once_cell::sync::{Lazy, OnceCell};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use crate::errors::GatewayError;
+use crate::{
+    accept, access_rules, cache, cgroup, coalesce, experiments, fingerprint, idempotency, priority,
+    secrets, slo, upstream_tls, waf, workers, RequestTrace, FRAMING_AUDIT,
+};
+
+pub(crate) static METRICS: Metrics = Metrics::new();
+
+/// Transform backend names of `LISTEN`'s and `LISTEN_NATIVE`'s pipelines,
+/// once side-by-side mode is on.
+static PIPELINES: OnceCell<[&'static str; 2]> = OnceCell::new();
+
+/// Status classes: index 0 counts requests that got no response.
+pub(crate) const CLASSES: [&str; 6] = ["none", "1xx", "2xx", "3xx", "4xx", "5xx"];
+
+pub(crate) struct Metrics {
+    by_class: [AtomicU64; 6],
+    latency_us: AtomicU64,
+    transform_us: AtomicU64,
+    upstream_requests: AtomicU64,
+    upstream_ttfb_us: AtomicU64,
+    upstream_us: AtomicU64,
+    upstream_bytes_sent: AtomicU64,
+    upstream_bytes_received: AtomicU64,
+    /// Indexed like `PIPELINES`.
+    pipelines: [PipelineCounters; 2],
+    /// Responses that failed `FRAMING_AUDIT`.
+    framing_mismatches: AtomicU64,
+    /// Error responses of the gateway's own, indexed like `GatewayError::ALL`.
+    errors: [AtomicU64; GatewayError::ALL.len()],
+}
+
+/// One pipeline's share of the request counters.
+struct PipelineCounters {
+    by_class: [AtomicU64; 6],
+    latency_us: AtomicU64,
+    transform_us: AtomicU64,
+}
+
+impl PipelineCounters {
+    const fn new() -> Self {
+        PipelineCounters {
+            by_class: [const { AtomicU64::new(0) }; 6],
+            latency_us: AtomicU64::new(0),
+            transform_us: AtomicU64::new(0),
+        }
+    }
+}
+
+/// Values read at one point in time.
+struct Snapshot {
+    by_class: [u64; 6],
+    latency_us: u64,
+    transform_us: u64,
+    upstream_requests: u64,
+    upstream_ttfb_us: u64,
+    upstream_us: u64,
+    upstream_bytes_sent: u64,
+    upstream_bytes_received: u64,
+}
+
+impl Metrics {
+    const fn new() -> Self {
+        Metrics {
+            by_class: [
+                AtomicU64::new(0),
+                AtomicU64::new(0),
+                AtomicU64::new(0),
+                AtomicU64::new(0),
+                AtomicU64::new(0),
+                AtomicU64::new(0),
+            ],
+            latency_us: AtomicU64::new(0),
+            transform_us: AtomicU64::new(0),
+            upstream_requests: AtomicU64::new(0),
+            upstream_ttfb_us: AtomicU64::new(0),
+            upstream_us: AtomicU64::new(0),
+            upstream_bytes_sent: AtomicU64::new(0),
+            upstream_bytes_received: AtomicU64::new(0),
+            pipelines: [PipelineCounters::new(), PipelineCounters::new()],
+            framing_mismatches: AtomicU64::new(0),
+            errors: [const { AtomicU64::new(0) }; GatewayError::ALL.len()],
+        }
+    }
+
+    pub(crate) fn framing_mismatch(&self) {
+        self.framing_mismatches.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record(&self, trace: &RequestTrace, latency: Duration) {
+        let class = match trace.status {
+            100..=599 => (trace.status / 100) as usize,
+            _ => 0,
+        };
+        self.by_class[class].fetch_add(1, Ordering::Relaxed);
+        self.latency_us
+            .fetch_add(latency.as_micros() as u64, Ordering::Relaxed);
+        self.transform_us
+            .fetch_add(trace.wasm_us, Ordering::Relaxed);
+        let pipeline = &self.pipelines[usize::from(trace.native)];
+        pipeline.by_class[class].fetch_add(1, Ordering::Relaxed);
+        pipeline
+            .latency_us
+            .fetch_add(latency.as_micros() as u64, Ordering::Relaxed);
+        pipeline
+            .transform_us
+            .fetch_add(trace.wasm_us, Ordering::Relaxed);
+        if let Some(assignment) = trace.experiment {
+            assignment.record(trace, latency, class);
+        }
+        if let Some(kind) = trace.error {
+            if let Some(i) = GatewayError::ALL.iter().position(|k| *k == kind) {
+                self.errors[i].fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        if let Some(timing) = trace.upstream_timing {
+            self.upstream_requests.fetch_add(1, Ordering::Relaxed);
+            self.upstream_ttfb_us
+                .fetch_add(timing.ttfb_us, Ordering::Relaxed);
+            self.upstream_us
+                .fetch_add(timing.total_us, Ordering::Relaxed);
+            self.upstream_bytes_sent
+                .fetch_add(timing.bytes_sent, Ordering::Relaxed);
+            self.upstream_bytes_received
+                .fetch_add(timing.bytes_received, Ordering::Relaxed);
+        }
+    }
+
+    fn error_samples(&self) -> Vec<(String, String)> {
+        GatewayError::ALL
+            .iter()
+            .zip(&self.errors)
+            .map(|(kind, n)| {
+                (
+                    format!("{{code=\"{}\"}}", kind.code()),
+                    n.load(Ordering::Relaxed).to_string(),
+                )
+            })
+            .collect()
+    }
+
+    fn snapshot(&self) -> Snapshot {
+        let load = |v: &AtomicU64| v.load(Ordering::Relaxed);
+        Snapshot {
+            by_class: std::array::from_fn(|i| load(&self.by_class[i])),
+            latency_us: load(&self.latency_us),
+            transform_us: load(&self.transform_us),
+            upstream_requests: load(&self.upstream_requests),
+            upstream_ttfb_us: load(&self.upstream_ttfb_us),
+            upstream_us: load(&self.upstream_us),
+            upstream_bytes_sent: load(&self.upstream_bytes_sent),
+            upstream_bytes_received: load(&self.upstream_bytes_received),
+        }
+    }
+
+    pub(crate) fn prometheus(&self, uptime: Duration, transform_enabled: bool) -> String {
+        let s = self.snapshot();
+        let secs = |us: u64| us as f64 / 1_000_000.0;
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, samples: &[(String, String)]| {
+            out.push_str(&format!("# HELP {name} {help}\n# TYPE {name} {kind}\n"));
+            for (labels, value) in samples {
+                out.push_str(&format!("{name}{labels} {value}\n"));
+            }
+        };
+        let by_class: Vec<(String, String)> = CLASSES
+            .iter()
+            .zip(s.by_class)
+            .map(|(class, n)| (format!("{{status=\"{class}\"}}"), n.to_string()))
+            .collect();
+        metric(
+            "gateway_requests_total",
+            "counter",
+            "Requests handled, by response status class.",
+            &by_class,
+        );
+        for (name, help, value) in [
+            (
+                "gateway_request_duration_seconds_total",
+                "Time spent handling requests.",
+                secs(s.latency_us),
+            ),
+            (
+                "gateway_transform_duration_seconds_total",
+                "Time spent in the transform backend.",
+                secs(s.transform_us),
+            ),
+            (
+                "gateway_upstream_ttfb_seconds_total",
+                "Upstream connect to first response byte.",
+                secs(s.upstream_ttfb_us),
+            ),
+            (
+                "gateway_upstream_duration_seconds_total",
+                "Upstream connect to last response byte.",
+                secs(s.upstream_us),
+            ),
+        ] {
+            metric(name, "counter", help, &[(String::new(), value.to_string())]);
+        }
+        for (name, help, value) in [
+            (
+                "gateway_upstream_requests_total",
+                "Requests forwarded to the upstream.",
+                s.upstream_requests,
+            ),
+            (
+                "gateway_upstream_sent_bytes_total",
+                "Bytes sent to the upstream.",
+                s.upstream_bytes_sent,
+            ),
+            (
+                "gateway_upstream_received_bytes_total",
+                "Bytes received from the upstream.",
+                s.upstream_bytes_received,
+            ),
+        ] {
+            metric(name, "counter", help, &[(String::new(), value.to_string())]);
+        }
+        let accept_errors: Vec<(String, String)> = accept::error_counts()
+            .iter()
+            .map(|(kind, n)| (format!("{{kind=\"{}\"}}", kind.label()), n.to_string()))
+            .collect();
+        metric(
+            "gateway_accept_errors_total",
+            "counter",
+            "Failed accept() calls on the gateway's listeners, by class.",
+            &accept_errors,
+        );
+        if let Some(names) = PIPELINES.get() {
+            let pipelines: Vec<_> = names.iter().zip(&self.pipelines).collect();
+            let by_class: Vec<(String, String)> = pipelines
+                .iter()
+                .flat_map(|(name, counters)| {
+                    CLASSES
+                        .iter()
+                        .zip(&counters.by_class)
+                        .map(move |(class, n)| {
+                            (
+                                format!("{{pipeline=\"{name}\",status=\"{class}\"}}"),
+                                n.load(Ordering::Relaxed).to_string(),
+                            )
+                        })
+                })
+                .collect();
+            metric(
+                "gateway_pipeline_requests_total",
+                "counter",
+                "Requests handled side by side, by pipeline and response status class.",
+                &by_class,
+            );
+            let by_pipeline =
+                |value: fn(&PipelineCounters) -> &AtomicU64| -> Vec<(String, String)> {
+                    pipelines
+                        .iter()
+                        .map(|(name, counters)| {
+                            (
+                                format!("{{pipeline=\"{name}\"}}"),
+                                secs(value(counters).load(Ordering::Relaxed)).to_string(),
+                            )
+                        })
+                        .collect()
+                };
+            metric(
+                "gateway_pipeline_request_duration_seconds_total",
+                "counter",
+                "Time spent handling requests, by pipeline.",
+                &by_pipeline(|c| &c.latency_us),
+            );
+            metric(
+                "gateway_pipeline_transform_duration_seconds_total",
+                "counter",
+                "Time spent in the transform backend, by pipeline.",
+                &by_pipeline(|c| &c.transform_us),
+            );
+        }
+        if let Some(admission) = priority::configured() {
+            let tiers = priority::Tier::ALL.map(|tier| (tier, admission.stats(tier)));
+            let by_tier = |value: fn(&priority::TierStats) -> String| -> Vec<(String, String)> {
+                tiers
+                    .iter()
+                    .map(|(tier, stats)| (format!("{{tier=\"{}\"}}", tier.label()), value(stats)))
+                    .collect()
+            };
+            for (name, kind, help, samples) in [
+                (
+                    "gateway_admitted_requests_total",
+                    "counter",
+                    "Workload requests admitted under MAX_INFLIGHT, by priority tier.",
+                    by_tier(|s| s.admitted.to_string()),
+                ),
+                (
+                    "gateway_queued_requests_total",
+                    "counter",
+                    "Workload requests that waited for a slot before being admitted.",
+                    by_tier(|s| s.queued.to_string()),
+                ),
+                (
+                    "gateway_queue_timeouts_total",
+                    "counter",
+                    "Workload requests answered 503 after INFLIGHT_QUEUE_TIMEOUT_MS.",
+                    by_tier(|s| s.timed_out.to_string()),
+                ),
+                (
+                    "gateway_queue_wait_seconds_total",
+                    "counter",
+                    "Time admitted requests spent queued.",
+                    by_tier(|s| (s.wait_us as f64 / 1_000_000.0).to_string()),
+                ),
+                (
+                    "gateway_queue_depth",
+                    "gauge",
+                    "Workload requests waiting for a slot.",
+                    by_tier(|s| s.waiting.to_string()),
+                ),
+            ] {
+                metric(name, kind, help, &samples);
+            }
+            metric(
+                "gateway_inflight_requests",
+                "gauge",
+                "Workload requests holding a slot.",
+                &[(String::new(), admission.running().to_string())],
+            );
+            metric(
+                "gateway_inflight_limit",
+                "gauge",
+                "MAX_INFLIGHT.",
+                &[(String::new(), admission.max().to_string())],
+            );
+        }
+        if let Some(tls) = upstream_tls::stats() {
+            metric(
+                "gateway_upstream_tls_handshakes_total",
+                "counter",
+                "Completed TLS handshakes with upstreams, full or resumed.",
+                &[
+                    (
+                        "{kind=\"full\"}".to_string(),
+                        tls.full_handshakes.to_string(),
+                    ),
+                    (
+                        "{kind=\"resumed\"}".to_string(),
+                        tls.resumed_handshakes.to_string(),
+                    ),
+                ],
+            );
+            metric(
+                "gateway_upstream_tls_handshake_seconds_total",
+                "counter",
+                "Time spent in TLS handshakes with upstreams, warm pool included.",
+                &[(String::new(), secs(tls.handshake_us).to_string())],
+            );
+            metric(
+                "gateway_upstream_tls_warm_total",
+                "counter",
+                "Requests that found a handshaked connection in UPSTREAM_TLS_WARM, or not.",
+                &[
+                    ("{result=\"hit\"}".to_string(), tls.warm_hits.to_string()),
+                    ("{result=\"miss\"}".to_string(), tls.warm_misses.to_string()),
+                ],
+            );
+        }
+        metric(
+            "gateway_errors_total",
+            "counter",
+            "Error responses of the gateway's own, by X-Gateway-Error-Code.",
+            &self.error_samples(),
+        );
+        if *FRAMING_AUDIT {
+            metric(
+                "gateway_framing_mismatches_total",
+                "counter",
+                "Responses whose body did not match their own framing (FRAMING_AUDIT).",
+                &[(
+                    String::new(),
+                    self.framing_mismatches.load(Ordering::Relaxed).to_string(),
+                )],
+            );
+        }
+        if let Some(rules) = access_rules::configured() {
+            metric(
+                "gateway_access_rule_matches_total",
+                "counter",
+                "Requests matched by each ACCESS_RULES rule, by line.",
+                &rules.samples(),
+            );
+        }
+        if let Some(samples) = workers::samples() {
+            metric(
+                "gateway_wasm_workers_replaced_total",
+                "counter",
+                "WASM_WORKERS processes replaced, by reason.",
+                &samples,
+            );
+        }
+        if let Some(waf) = waf::configured() {
+            for (name, help, samples) in waf.samples() {
+                metric(name, "counter", help, &samples);
+            }
+        }
+        if let Some(experiments) = experiments::configured() {
+            for (name, help, samples) in experiments.samples() {
+                metric(name, "counter", help, &samples);
+            }
+        }
+        if let Some(store) = idempotency::configured() {
+            metric(
+                "gateway_idempotency_requests_total",
+                "counter",
+                "Requests with an Idempotency-Key (IDEMPOTENCY_TTL_SECS), by result.",
+                &store.samples(),
+            );
+        }
+        if let Some(cache) = cache::configured() {
+            metric(
+                "gateway_cache_requests_total",
+                "counter",
+                "Proxy GETs looked up in the response cache (CACHE_TTL_SECS), by result.",
+                &cache.samples(),
+            );
+            metric(
+                "gateway_cache_entries",
+                "gauge",
+                "Responses held in the response cache.",
+                &[(String::new(), cache.len().to_string())],
+            );
+            if let Some(bytes) = cache.disk_bytes() {
+                metric(
+                    "gateway_cache_disk_bytes",
+                    "gauge",
+                    "Bytes in the response cache's disk tier (CACHE_DIR).",
+                    &[(String::new(), bytes.to_string())],
+                );
+            }
+        }
+        if let Some(coalescer) = coalesce::configured() {
+            metric(
+                "gateway_coalesced_requests_total",
+                "counter",
+                "GETs that shared one upstream request (COALESCE_GETS): leaders made it, followers reused it.",
+                &coalescer.samples(),
+            );
+        }
+        if let Some(counts) = slo::configured().map(slo::Slo::total) {
+            metric(
+                "gateway_slo_requests_total",
+                "counter",
+                "Requests that met SLO_LATENCY_MS without a 5xx, or not.",
+                &[
+                    ("{result=\"good\"}".to_string(), counts.good.to_string()),
+                    ("{result=\"bad\"}".to_string(), counts.bad.to_string()),
+                ],
+            );
+        }
+        if let Some(stats) = cgroup::configured().map(cgroup::WasmCgroup::stats) {
+            for (name, help, value) in [
+                (
+                    "gateway_wasm_cgroup_cpu_seconds_total",
+                    "CPU time used by wasm runtime processes.",
+                    secs(stats.cpu_usage_us),
+                ),
+                (
+                    "gateway_wasm_cgroup_cpu_throttled_seconds_total",
+                    "Time wasm runtime processes were throttled by WASM_CGROUP_CPU.",
+                    secs(stats.cpu_throttled_us),
+                ),
+            ] {
+                metric(name, "counter", help, &[(String::new(), value.to_string())]);
+            }
+            for (name, help, value) in [
+                (
+                    "gateway_wasm_cgroup_cpu_periods_total",
+                    "Enforcement periods with wasm runtime processes runnable.",
+                    stats.cpu_periods,
+                ),
+                (
+                    "gateway_wasm_cgroup_cpu_throttled_periods_total",
+                    "Enforcement periods in which the CPU quota ran out.",
+                    stats.cpu_throttled_periods,
+                ),
+                (
+                    "gateway_wasm_cgroup_memory_max_events_total",
+                    "Times wasm runtime memory reached WASM_CGROUP_MEMORY_MB.",
+                    stats.memory_max_events,
+                ),
+                (
+                    "gateway_wasm_cgroup_oom_kills_total",
+                    "Wasm runtime processes killed by the cgroup OOM killer.",
+                    stats.oom_kills,
+                ),
+            ] {
+                metric(name, "counter", help, &[(String::new(), value.to_string())]);
+            }
+            metric(
+                "gateway_wasm_cgroup_memory_bytes",
+                "gauge",
+                "Memory currently charged to wasm runtime processes.",
+                &[(String::new(), stats.memory_bytes.to_string())],
+            );
+        }
+        metric(
+            "gateway_uptime_seconds",
+            "gauge",
+            "Seconds since the gateway started.",
+            &[(String::new(), uptime.as_secs_f64().to_string())],
+        );
+        metric(
+            "gateway_transform_enabled",
+            "gauge",
+            "1 while the transform is switched on.",
+            &[(String::new(), u8::from(transform_enabled).to_string())],
+        );
+        out
+    }
+
+    pub(crate) fn json(
+        &self,
+        uptime: Duration,
+        backend: &str,
+        transform_enabled: bool,
+    ) -> serde_json::Value {
+        let s = self.snapshot();
+        let ms = |us: u64| us as f64 / 1000.0;
+        let accept_errors: serde_json::Map<String, serde_json::Value> = accept::error_counts()
+            .iter()
+            .map(|(kind, n)| (kind.label().to_string(), (*n).into()))
+            .collect();
+        let by_class: serde_json::Map<String, serde_json::Value> = CLASSES
+            .iter()
+            .zip(s.by_class)
+            .map(|(class, n)| (class.to_string(), n.into()))
+            .collect();
+        serde_json::json!({
+            "uptime_s": uptime.as_secs_f64(),
+            "requests": {
+                "total": s.by_class.iter().sum::<u64>(),
+                "by_status": by_class,
+                "total_ms": ms(s.latency_us),
+            },
+            "transform": {
+                "backend": backend,
+                "enabled": transform_enabled,
+                "total_ms": ms(s.transform_us),
+            },
+            "upstream": {
+                "requests": s.upstream_requests,
+                "ttfb_ms": ms(s.upstream_ttfb_us),
+                "total_ms": ms(s.upstream_us),
+                "bytes_sent": s.upstream_bytes_sent,
+                "bytes_received": s.upstream_bytes_received,
+                "tls": upstream_tls::stats().map(|tls| serde_json::json!({
+                    "full_handshakes": tls.full_handshakes,
+                    "resumed_handshakes": tls.resumed_handshakes,
+                    "handshake_ms": ms(tls.handshake_us),
+                    "warm_hits": tls.warm_hits,
+                    "warm_misses": tls.warm_misses,
+                })),
+            },
+            "accept_errors": accept_errors,
+            "errors": GatewayError::ALL
+                .iter()
+                .zip(&self.errors)
+                .map(|(kind, n)| (kind.code().to_string(), n.load(Ordering::Relaxed).into()))
+                .collect::<serde_json::Map<String, serde_json::Value>>(),
+            "framing_mismatches": FRAMING_AUDIT
+                .then(|| self.framing_mismatches.load(Ordering::Relaxed)),
+            "coalescing": coalesce::configured().map(coalesce::Coalescer::json),
+            "cache": cache::configured().map(cache::ResponseCache::json),
+            "idempotency": idempotency::configured().map(idempotency::IdempotencyStore::json),
+            "access_rules": access_rules::configured().map(access_rules::Rules::json),
+            "experiments": experiments::configured().map(experiments::Experiments::json),
+            "waf": waf::configured().map(waf::Waf::json),
+            "wasm_workers_replaced": workers::json(),
+            "secrets": secrets::configured().map(secrets::SecretStore::json),
+            "pipelines": PIPELINES.get().map(|names| {
+                let pipelines: serde_json::Map<String, serde_json::Value> = names
+                    .iter()
+                    .zip(&self.pipelines)
+                    .map(|(name, counters)| {
+                        let by_class: serde_json::Map<String, serde_json::Value> = CLASSES
+                            .iter()
+                            .zip(&counters.by_class)
+                            .map(|(class, n)| (class.to_string(), n.load(Ordering::Relaxed).into()))
+                            .collect();
+                        let value = serde_json::json!({
+                            "total": by_class.values().filter_map(serde_json::Value::as_u64).sum::<u64>(),
+                            "by_status": by_class,
+                            "total_ms": ms(counters.latency_us.load(Ordering::Relaxed)),
+                            "transform_ms": ms(counters.transform_us.load(Ordering::Relaxed)),
+                        });
+                        (name.to_string(), value)
+                    })
+                    .collect();
+                pipelines
+            }),
+            "fingerprint": fingerprint_json(),
+            "slo": slo::configured().map(slo::Slo::json),
+            "admission": priority::configured().map(|admission| {
+                let tiers: serde_json::Map<String, serde_json::Value> = priority::Tier::ALL
+                    .iter()
+                    .map(|tier| {
+                        let stats = admission.stats(*tier);
+                        let value = serde_json::json!({
+                            "admitted": stats.admitted,
+                            "queued": stats.queued,
+                            "timed_out": stats.timed_out,
+                            "wait_ms": ms(stats.wait_us),
+                            "waiting": stats.waiting,
+                        });
+                        (tier.label().to_string(), value)
+                    })
+                    .collect();
+                serde_json::json!({
+                    "max_inflight": admission.max(),
+                    "inflight": admission.running(),
+                    "tiers": tiers,
+                })
+            }),
+            "wasm_cgroup": cgroup::configured().map(|cgroup| {
+                let stats = cgroup.stats();
+                serde_json::json!({
+                    "cpu_ms": ms(stats.cpu_usage_us),
+                    "cpu_periods": stats.cpu_periods,
+                    "cpu_throttled_periods": stats.cpu_throttled_periods,
+                    "cpu_throttled_ms": ms(stats.cpu_throttled_us),
+                    "memory_bytes": stats.memory_bytes,
+                    "memory_max_events": stats.memory_max_events,
+                    "oom_kills": stats.oom_kills,
+                })
+            }),
+        })
+    }
+}
+
+/// Breaks requests down by pipeline from now on: `names` are the transform
+/// backends of `LISTEN` and `LISTEN_NATIVE`.
+pub(crate) fn side_by_side(names: [&'static str; 2]) {
+    PIPELINES.set(names).ok();
+}
+
+/// The build and machine fingerprint plus the wasm runtimes this host can
+/// use: the embedded wasmtime it was built with and whichever `wasmedge` /
+/// `wasmtime` CLIs are on `PATH`. Collected on first use.
+pub(crate) fn fingerprint_json() -> serde_json::Value {
+    static FINGERPRINT: Lazy<serde_json::Value> = Lazy::new(|| {
+        let cli_version = |program: &str| {
+            std::process::Command::new(program)
+                .arg("--version")
+                .output()
+                .ok()
+                .filter(|out| out.status.success())
+                .and_then(|out| String::from_utf8(out.stdout).ok())
+                .and_then(|v| v.lines().next().map(|line| line.trim().to_string()))
+        };
+        let mut value: serde_json::Value =
+            serde_json::from_str(&fingerprint::get().to_json()).unwrap_or_default();
+        value["wasm_runtimes"] = serde_json::json!({
+            "wasmtime_embedded": env!("GATEWAY_WASMTIME_VERSION"),
+            "wasmedge": cli_version("wasmedge"),
+            "wasmtime": cli_version("wasmtime"),
+        });
+        value
+    });
+    FINGERPRINT.clone()
+}
@@ -0,0 +1,166 @@
+//! Signature check on the wasm module before it is loaded, enabled by
+//! `WASM_VERIFY_KEY` (a PEM `PUBLIC KEY`, e.g. `cosign.pub`).
+//!
+//! The signature is the ECDSA P-256 / SHA-256 signature over the module
+//! bytes that `cosign sign-blob --key cosign.key module.wasm
+//! --output-signature module.wasm.sig` writes: base64 (or raw) DER, read from
+//! `WASM_MODULE_SIG` or `<module>.sig`. A missing or invalid signature refuses
+//! to start. OCI-attached signatures are not fetched; export them next to the
+//! module with `cosign download signature` first.
+
+use anyhow::{anyhow, Context, Result};
+use base64::Engine as _;
+use p256::ecdsa::signature::Verifier;
+use p256::ecdsa::{Signature, VerifyingKey};
+use p256::pkcs8::DecodePublicKey;
+
+#[derive(Debug)]
+pub(crate) struct ModuleVerifier {
+    key: VerifyingKey,
+    key_path: String,
+    /// `WASM_MODULE_SIG`; `<module>.sig` when unset.
+    signature_path: Option<String>,
+}
+
+impl ModuleVerifier {
+    pub(crate) fn from_env() -> Result<Option<Self>> {
+        let key_path = match std::env::var("WASM_VERIFY_KEY") {
+            Ok(path) if !path.is_empty() => path,
+            _ => return Ok(None),
+        };
+        let pem = std::fs::read_to_string(&key_path)
+            .with_context(|| format!("read WASM_VERIFY_KEY={key_path}"))?;
+        let key = VerifyingKey::from_public_key_pem(&pem)
+            .map_err(|e| anyhow!("WASM_VERIFY_KEY={key_path} is not a P-256 public key: {e}"))?;
+        Ok(Some(ModuleVerifier {
+            key,
+            key_path,
+            signature_path: std::env::var("WASM_MODULE_SIG")
+                .ok()
+                .filter(|p| !p.is_empty()),
+        }))
+    }
+
+    /// Fails unless `module_path` carries a valid signature from the key.
+    pub(crate) fn verify(&self, module_path: &str) -> Result<()> {
+        let module = std::fs::read(module_path)
+            .with_context(|| format!("read wasm module {module_path}"))?;
+        self.verify_bytes(module_path, &module)
+    }
+
+    /// Like `verify` for contents already read from `module_path`.
+    pub(crate) fn verify_bytes(&self, module_path: &str, module: &[u8]) -> Result<()> {
+        let sig_path = self
+            .signature_path
+            .clone()
+            .unwrap_or_else(|| format!("{module_path}.sig"));
+        let raw = std::fs::read(&sig_path).with_context(|| {
+            format!("wasm module {module_path} is unsigned (no signature at {sig_path})")
+        })?;
+        let der = base64::engine::general_purpose::STANDARD
+            .decode(raw.trim_ascii())
+            .unwrap_or(raw);
+        let signature = Signature::from_der(&der)
+            .map_err(|e| anyhow!("invalid signature in {sig_path}: {e}"))?;
+        self.key.verify(module, &signature).map_err(|_| {
+            anyhow!(
+                "signature {sig_path} does not match {module_path} for key {}",
+                self.key_path
+            )
+        })?;
+        eprintln!(
+            "[wasm-host] wasm module {module_path} verified against {}",
+            self.key_path
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use p256::ecdsa::signature::Signer;
+    use p256::ecdsa::SigningKey;
+    use std::path::PathBuf;
+
+    const MODULE: &[u8] = b"\0asm\x01\0\0\0 module bytes";
+
+    fn signing_key(seed: u8) -> SigningKey {
+        SigningKey::from_slice(&[seed; 32]).unwrap()
+    }
+
+    /// DER signature of `data` by `key`.
+    fn sign(key: &SigningKey, data: &[u8]) -> Vec<u8> {
+        let signature: Signature = key.sign(data);
+        signature.to_der().as_bytes().to_vec()
+    }
+
+    fn verifier(signature_path: Option<String>) -> ModuleVerifier {
+        ModuleVerifier {
+            key: *signing_key(1).verifying_key(),
+            key_path: "cosign.pub".to_string(),
+            signature_path,
+        }
+    }
+
+    /// A scratch directory holding `module.wasm`.
+    fn module_dir(name: &str) -> (PathBuf, String) {
+        let dir = std::env::temp_dir().join(format!("module-verify-{name}-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let module = dir.join("module.wasm");
+        std::fs::write(&module, MODULE).unwrap();
+        (dir, module.display().to_string())
+    }
+
+    #[test]
+    fn accepts_base64_and_raw_der_signatures() {
+        let (dir, module) = module_dir("ok");
+        let der = sign(&signing_key(1), MODULE);
+        let encoded = base64::engine::general_purpose::STANDARD.encode(&der);
+        std::fs::write(format!("{module}.sig"), format!("{encoded}\n")).unwrap();
+        verifier(None).verify(&module).unwrap();
+
+        let raw = dir.join("raw.sig");
+        std::fs::write(&raw, &der).unwrap();
+        verifier(Some(raw.display().to_string()))
+            .verify(&module)
+            .unwrap();
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn rejects_modified_modules_and_other_keys() {
+        let (dir, module) = module_dir("mismatch");
+        std::fs::write(format!("{module}.sig"), sign(&signing_key(1), MODULE)).unwrap();
+
+        let mut tampered = MODULE.to_vec();
+        tampered[9] ^= 1;
+        let err = verifier(None).verify_bytes(&module, &tampered).unwrap_err();
+        assert!(err.to_string().contains("does not match"), "{err:#}");
+        std::fs::write(&module, &tampered).unwrap();
+        assert!(verifier(None).verify(&module).is_err());
+
+        std::fs::write(&module, MODULE).unwrap();
+        std::fs::write(format!("{module}.sig"), sign(&signing_key(2), MODULE)).unwrap();
+        let err = verifier(None).verify(&module).unwrap_err();
+        assert!(err.to_string().contains("does not match"), "{err:#}");
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn rejects_missing_and_malformed_signatures() {
+        let (dir, module) = module_dir("malformed");
+        let err = verifier(None).verify(&module).unwrap_err();
+        assert!(err.to_string().contains("is unsigned"), "{err:#}");
+
+        std::fs::write(format!("{module}.sig"), "bm90IGEgc2lnbmF0dXJl").unwrap();
+        let err = verifier(None).verify(&module).unwrap_err();
+        assert!(err.to_string().contains("invalid signature"), "{err:#}");
+
+        let err = verifier(None)
+            .verify(&format!("{module}.missing"))
+            .unwrap_err();
+        assert!(err.to_string().contains("read wasm module"), "{err:#}");
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
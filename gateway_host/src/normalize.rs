@@ -0,0 +1,209 @@
+//! Request normalization (`NORMALIZE`), applied right after the request head
+//! is parsed, so routing, rate limits, the cache key and the forwarded
+//! request all see one spelling of each URL.
+//!
+//! `NORMALIZE=1` lowercases `Host`, decodes percent-encoded unreserved
+//! characters (letters, digits, `-`, `.`, `_`, `~`) and uppercases the hex
+//! digits of the encodings that stay, in both path and query, then collapses
+//! runs of `/` and resolves `.` and `..` segments in the path, so an encoded
+//! `..` cannot slip past a route prefix. `NORMALIZE_SORT_QUERY=1` also sorts
+//! query parameters by name, keeping repeated names in their order.
+//! Absolute-form targets have already been reduced to origin form by
+//! `parse_request_head`; any other form is left alone.
+//!
+//! Route prefixes that guard a request (`BASIC_AUTH_ROUTES`, `HMAC_ROUTES`,
+//! `OAUTH_ROUTES`) are matched with `prefix_matches`, which normalizes the
+//! path itself so they hold whether `NORMALIZE` is on or not.
+
+use anyhow::{Context, Result};
+use once_cell::sync::OnceCell;
+
+use crate::headers::Headers;
+
+static NORMALIZE: OnceCell<Normalizer> = OnceCell::new();
+
+#[derive(Debug)]
+pub(crate) struct Normalizer {
+    pub(crate) sort_query: bool,
+}
+
+/// Reads `NORMALIZE` and `NORMALIZE_SORT_QUERY`. Call once from `main`.
+pub(crate) fn init_from_env() -> Result<()> {
+    let flag = |name: &str| -> Result<bool> {
+        match std::env::var(name) {
+            Ok(v) if !v.trim().is_empty() => match v.trim() {
+                "1" => Ok(true),
+                "0" => Ok(false),
+                _ => Err(anyhow::anyhow!("expected 0 or 1")),
+            }
+            .with_context(|| format!("invalid {name}={v}")),
+            _ => Ok(false),
+        }
+    };
+    let sort_query = flag("NORMALIZE_SORT_QUERY")?;
+    if !flag("NORMALIZE")? {
+        if sort_query {
+            return Err(anyhow::anyhow!("NORMALIZE_SORT_QUERY needs NORMALIZE=1"));
+        }
+        return Ok(());
+    }
+    NORMALIZE.get_or_init(|| Normalizer { sort_query });
+    eprintln!(
+        "[wasm-host] normalizing request targets{}",
+        if sort_query {
+            ", query parameters sorted"
+        } else {
+            ""
+        }
+    );
+    Ok(())
+}
+
+pub(crate) fn configured() -> Option<&'static Normalizer> {
+    NORMALIZE.get()
+}
+
+impl Normalizer {
+    /// Normalizes `target` and the `Host` fields in place.
+    pub(crate) fn apply(&self, target: &mut String, headers: &mut Headers) {
+        headers.rewrite_all("Host", |host| Some(host.to_ascii_lowercase()));
+        if target.starts_with('/') {
+            *target = self.target(target);
+        }
+    }
+
+    fn target(&self, target: &str) -> String {
+        let (path, query) = match target.split_once('?') {
+            Some((path, query)) => (path, Some(query)),
+            None => (target, None),
+        };
+        let mut collapsed = String::with_capacity(target.len());
+        for c in percent(path).chars() {
+            if !(c == '/' && collapsed.ends_with('/')) {
+                collapsed.push(c);
+            }
+        }
+        let mut out = remove_dot_segments(&collapsed);
+        if let Some(query) = query {
+            let query = percent(query);
+            let mut params: Vec<&str> = query.split('&').collect();
+            if self.sort_query {
+                params.sort_by_key(|p| p.split_once('=').map_or(*p, |(name, _)| name));
+            }
+            out.push('?');
+            out.push_str(&params.join("&"));
+        }
+        out
+    }
+}
+
+/// Whether the route prefix `prefix` covers `target`: its normalized path
+/// is the prefix or continues past it at a `/`, so `/internal` and
+/// `/internal/` both cover `/internal` and `/internal/x` but not
+/// `/internalx`. The query is ignored.
+pub(crate) fn prefix_matches(prefix: &str, target: &str) -> bool {
+    let normalizer = Normalizer { sort_query: false };
+    let path = normalizer.target(gateway_core::http::route_path(target));
+    let prefix = normalizer.target(prefix);
+    path.strip_prefix(prefix.trim_end_matches('/'))
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+}
+
+/// Resolves `.` and `..` in a path starting with `/`; one that ends in
+/// either keeps a trailing `/`.
+fn remove_dot_segments(path: &str) -> String {
+    let segments: Vec<&str> = path.split('/').skip(1).collect();
+    let mut out: Vec<&str> = Vec::with_capacity(segments.len());
+    for (i, segment) in segments.iter().enumerate() {
+        match *segment {
+            "." => {}
+            ".." => {
+                out.pop();
+            }
+            segment => out.push(segment),
+        }
+        if i + 1 == segments.len() && matches!(*segment, "." | "..") {
+            out.push("");
+        }
+    }
+    format!("/{}", out.join("/"))
+}
+
+/// Decodes `%XX` for unreserved characters and uppercases the rest.
+fn percent(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = String::with_capacity(s.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .filter(|h| h.iter().all(u8::is_ascii_hexdigit));
+        match (bytes[i], hex) {
+            (b'%', Some(hex)) => {
+                let hex = std::str::from_utf8(hex).expect("ascii hex digits");
+                let byte = u8::from_str_radix(hex, 16).expect("hex digits");
+                if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+                    out.push(byte as char);
+                } else {
+                    out.push('%');
+                    out.push_str(&hex.to_ascii_uppercase());
+                }
+                i += 3;
+            }
+            _ => {
+                // Targets are ASCII after parsing; keep any other byte as is.
+                let len = s[i..].chars().next().map_or(1, char::len_utf8);
+                out.push_str(&s[i..i + len]);
+                i += len;
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalizes_host_path_and_query() {
+        let normalizer = Normalizer { sort_query: false };
+        let mut headers = Headers::parse("Host: API.Example.test:8080\r\nAccept: */*");
+        let mut target = "//api///%7Euser/%61b%2fc/?q=%4a%20x&a=1".to_string();
+        normalizer.apply(&mut target, &mut headers);
+        assert_eq!(target, "/api/~user/ab%2Fc/?q=J%20x&a=1");
+        assert_eq!(headers.get("host"), Some("api.example.test:8080"));
+
+        let sorted = Normalizer { sort_query: true };
+        assert_eq!(sorted.target("/p?b=2&a=1&b=1&flag"), "/p?a=1&b=2&b=1&flag");
+        assert_eq!(sorted.target("/p%"), "/p%");
+        assert_eq!(sorted.target("/public/%2e%2E/admin"), "/admin");
+        assert_eq!(sorted.target("/a/./b/../c/"), "/a/c/");
+        assert_eq!(sorted.target("/a/.."), "/");
+        let mut absolute = "http://Example.test//x".to_string();
+        sorted.apply(&mut absolute, &mut Headers::default());
+        assert_eq!(absolute, "http://Example.test//x");
+    }
+
+    #[test]
+    fn prefixes_match_whole_normalized_segments() {
+        for prefix in ["/internal", "/internal/"] {
+            for target in [
+                "/internal",
+                "/internal/",
+                "/internal/x?y=1",
+                "//internal//x",
+            ] {
+                assert!(prefix_matches(prefix, target), "{prefix} {target}");
+            }
+            for target in ["/internalx", "/public/internal/", "/", "/x/../internalx"] {
+                assert!(!prefix_matches(prefix, target), "{prefix} {target}");
+            }
+        }
+        // Encodings and dot segments cannot step around a prefix.
+        assert!(prefix_matches("/internal", "/public/../internal/x"));
+        assert!(prefix_matches("/internal", "/%69nternal/x"));
+        assert!(prefix_matches("/internal", "/./internal"));
+        assert!(prefix_matches("/", "/anything"));
+    }
+}
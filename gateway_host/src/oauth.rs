@@ -0,0 +1,407 @@
+//! Opaque bearer token validation via RFC 7662 token introspection.
+//!
+//! Results (including inactive tokens) are cached per token hash for
+//! `OAUTH_CACHE_TTL_SECS`, never beyond the token's own `exp`.
+
+use anyhow::{anyhow, Context, Result};
+use base64::Engine as _;
+use once_cell::sync::Lazy;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::io::Write;
+use std::net::TcpStream;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::{
+    normalize, parse_status_code_from_head, parse_upstream, read_all_response, split_http_response,
+    Upstream, IO_TIMEOUT,
+};
+
+const MAX_CACHE_ENTRIES: usize = 10_000;
+
+static CACHE: Lazy<Mutex<HashMap<[u8; 32], CachedIntrospection>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+#[derive(Debug)]
+pub(crate) struct OAuthConfig {
+    pub(crate) endpoint: Upstream,
+    pub(crate) client_id: Option<String>,
+    pub(crate) client_secret: Option<crate::secrets::Secret>,
+    pub(crate) cache_ttl: Duration,
+    /// Scope that must be present in the introspected `scope` list, if any.
+    pub(crate) required_scope: Option<String>,
+    /// Path prefixes that require a token; empty means every proxied request.
+    pub(crate) routes: Vec<String>,
+}
+
+impl OAuthConfig {
+    pub(crate) fn from_env() -> Result<Option<Self>> {
+        let Ok(url) = std::env::var("OAUTH_INTROSPECTION_URL") else {
+            return Ok(None);
+        };
+        let endpoint = parse_upstream(&url)
+            .with_context(|| format!("invalid OAUTH_INTROSPECTION_URL={url}"))?;
+        if endpoint.tls {
+            return Err(anyhow!(
+                "OAUTH_INTROSPECTION_URL={url}: only http is supported"
+            ));
+        }
+        let cache_ttl_secs = match std::env::var("OAUTH_CACHE_TTL_SECS") {
+            Ok(v) => v
+                .parse::<u64>()
+                .with_context(|| format!("invalid OAUTH_CACHE_TTL_SECS={v}"))?,
+            Err(_) => 60,
+        };
+        Ok(Some(OAuthConfig {
+            endpoint,
+            client_id: std::env::var("OAUTH_CLIENT_ID").ok(),
+            client_secret: crate::secrets::lookup("OAUTH_CLIENT_SECRET"),
+            cache_ttl: Duration::from_secs(cache_ttl_secs),
+            required_scope: std::env::var("OAUTH_REQUIRED_SCOPE")
+                .ok()
+                .filter(|s| !s.is_empty()),
+            routes: std::env::var("OAUTH_ROUTES")
+                .map(|v| {
+                    v.split(',')
+                        .map(str::trim)
+                        .filter(|p| !p.is_empty())
+                        .map(str::to_string)
+                        .collect()
+                })
+                .unwrap_or_default(),
+        }))
+    }
+
+    pub(crate) fn applies_to(&self, path: &str) -> bool {
+        self.routes.is_empty()
+            || self
+                .routes
+                .iter()
+                .any(|p| normalize::prefix_matches(p, path))
+    }
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct Principal {
+    pub(crate) subject: String,
+    pub(crate) scope: String,
+}
+
+#[derive(Clone, Debug)]
+struct CachedIntrospection {
+    principal: Option<Principal>,
+    expires_at: Instant,
+}
+
+/// Why a request was rejected; `Unauthorized` maps to 401, `Forbidden` to 403.
+#[derive(Debug)]
+pub(crate) enum Rejection {
+    Unauthorized(&'static str),
+    Forbidden(&'static str),
+}
+
+/// Validates the request's bearer token. Introspection endpoint failures are
+/// returned as `Err` (the caller treats them as a gateway error, not a 401).
+pub(crate) fn authorize(
+    config: &OAuthConfig,
+    authorization: Option<&str>,
+) -> Result<std::result::Result<Principal, Rejection>> {
+    let Some(token) = authorization
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .map(str::trim)
+        .filter(|t| !t.is_empty())
+    else {
+        return Ok(Err(Rejection::Unauthorized("missing bearer token")));
+    };
+
+    let key: [u8; 32] = Sha256::digest(token.as_bytes()).into();
+    let cached = {
+        let cache = CACHE
+            .lock()
+            .map_err(|_| anyhow!("oauth cache lock poisoned"))?;
+        cache
+            .get(&key)
+            .filter(|c| c.expires_at > Instant::now())
+            .cloned()
+    };
+    let principal = match cached {
+        Some(c) => c.principal,
+        None => {
+            let (principal, token_ttl) = introspect(config, token)?;
+            let ttl = token_ttl.map_or(config.cache_ttl, |t| t.min(config.cache_ttl));
+            store(
+                key,
+                CachedIntrospection {
+                    principal: principal.clone(),
+                    expires_at: Instant::now() + ttl,
+                },
+            )?;
+            principal
+        }
+    };
+
+    let Some(principal) = principal else {
+        return Ok(Err(Rejection::Unauthorized("token is not active")));
+    };
+    if let Some(required) = config.required_scope.as_deref() {
+        if !principal.scope.split_whitespace().any(|s| s == required) {
+            return Ok(Err(Rejection::Forbidden("token lacks required scope")));
+        }
+    }
+    Ok(Ok(principal))
+}
+
+fn store(key: [u8; 32], entry: CachedIntrospection) -> Result<()> {
+    let mut cache = CACHE
+        .lock()
+        .map_err(|_| anyhow!("oauth cache lock poisoned"))?;
+    if cache.len() >= MAX_CACHE_ENTRIES {
+        let now = Instant::now();
+        cache.retain(|_, c| c.expires_at > now);
+        if cache.len() >= MAX_CACHE_ENTRIES {
+            cache.clear();
+        }
+    }
+    cache.insert(key, entry);
+    Ok(())
+}
+
+/// POSTs the token to the introspection endpoint. Returns the principal for an
+/// active token plus the remaining token lifetime from `exp`, if present.
+fn introspect(config: &OAuthConfig, token: &str) -> Result<(Option<Principal>, Option<Duration>)> {
+    let endpoint = &config.endpoint;
+    let form = format!(
+        "token={}&token_type_hint=access_token",
+        url::form_urlencoded::byte_serialize(token.as_bytes()).collect::<String>()
+    );
+    let path = if endpoint.base_path.is_empty() {
+        "/"
+    } else {
+        endpoint.base_path.as_str()
+    };
+
+    // HTTP/1.0 keeps the response un-chunked and closes the connection.
+    let mut req = format!(
+        "POST {path} HTTP/1.0\r\nHost: {}\r\nContent-Type: application/x-www-form-urlencoded\r\nAccept: application/json\r\nContent-Length: {}\r\n",
+        endpoint.host,
+        form.len()
+    );
+    if let Some(id) = config.client_id.as_deref() {
+        let secret = config
+            .client_secret
+            .as_ref()
+            .map(crate::secrets::Secret::reveal);
+        let secret = secret.as_deref().unwrap_or("");
+        let creds = base64::engine::general_purpose::STANDARD.encode(format!("{id}:{secret}"));
+        req.push_str(&format!("Authorization: Basic {creds}\r\n"));
+    }
+    req.push_str("\r\n");
+    req.push_str(&form);
+
+    let mut stream = TcpStream::connect((&*endpoint.host, endpoint.port))
+        .with_context(|| format!("connect introspection endpoint {}", endpoint.raw_url))?;
+    stream.set_read_timeout(Some(IO_TIMEOUT)).ok();
+    stream.set_write_timeout(Some(IO_TIMEOUT)).ok();
+    stream.write_all(req.as_bytes())?;
+    stream.flush()?;
+
+    let resp = read_all_response(&mut stream)?;
+    let (head, body) = split_http_response(&resp)?;
+    let status = parse_status_code_from_head(&head)?;
+    if status != 200 {
+        return Err(anyhow!("introspection endpoint returned status {status}"));
+    }
+    let json: Value = serde_json::from_slice(&body).context("introspection response not JSON")?;
+
+    if !json.get("active").and_then(Value::as_bool).unwrap_or(false) {
+        return Ok((None, None));
+    }
+    let remaining = json.get("exp").and_then(Value::as_u64).map(|exp| {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        Duration::from_secs(exp.saturating_sub(now))
+    });
+    let subject = json
+        .get("sub")
+        .or_else(|| json.get("username"))
+        .or_else(|| json.get("client_id"))
+        .and_then(Value::as_str)
+        .unwrap_or("")
+        .to_string();
+    let scope = json
+        .get("scope")
+        .and_then(Value::as_str)
+        .unwrap_or("")
+        .to_string();
+    Ok((Some(Principal { subject, scope }), remaining))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::net::TcpListener;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    /// An introspection endpoint answering every call with `status` and
+    /// `body`; returns its config and the number of calls made to it.
+    fn endpoint(status: u16, body: String) -> (OAuthConfig, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&calls);
+        std::thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                let mut req = Vec::new();
+                let mut buf = [0u8; 1024];
+                while let Ok(n @ 1..) = stream.read(&mut buf) {
+                    req.extend_from_slice(&buf[..n]);
+                    let text = String::from_utf8_lossy(&req);
+                    if let Some((head, form)) = text.split_once("\r\n\r\n") {
+                        let len = head
+                            .lines()
+                            .find_map(|l| l.strip_prefix("Content-Length: "))
+                            .and_then(|v| v.parse::<usize>().ok())
+                            .unwrap_or(0);
+                        if form.len() >= len {
+                            break;
+                        }
+                    }
+                }
+                let text = String::from_utf8_lossy(&req);
+                assert!(text.starts_with("POST /introspect HTTP/1.0\r\n"));
+                assert!(text.contains("Authorization: Basic Z3c6czNjcmV0\r\n"));
+                assert!(text.contains("token_type_hint=access_token"));
+                counter.fetch_add(1, Ordering::SeqCst);
+                let resp = format!(
+                    "HTTP/1.0 {status} X\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{body}",
+                    body.len()
+                );
+                stream.write_all(resp.as_bytes()).ok();
+            }
+        });
+        let config = OAuthConfig {
+            endpoint: parse_upstream(&format!("http://127.0.0.1:{port}/introspect")).unwrap(),
+            client_id: Some("gw".to_string()),
+            client_secret: Some(crate::secrets::Secret::new("OAUTH_CLIENT_SECRET", "s3cret")),
+            cache_ttl: Duration::from_secs(60),
+            required_scope: None,
+            routes: Vec::new(),
+        };
+        (config, calls)
+    }
+
+    fn active(scope: &str) -> String {
+        format!(r#"{{"active":true,"sub":"alice","scope":"{scope}"}}"#)
+    }
+
+    /// Tokens are unique per test, since the cache is shared.
+    fn bearer(token: &str) -> String {
+        format!("Bearer {token}")
+    }
+
+    #[test]
+    fn active_tokens_are_introspected_once_and_cached() {
+        let (config, calls) = endpoint(200, active("read write"));
+        let auth = bearer("cached-token");
+        for _ in 0..3 {
+            let principal = authorize(&config, Some(&auth)).unwrap().unwrap();
+            assert_eq!(principal.subject, "alice");
+            assert_eq!(principal.scope, "read write");
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        for header in [None, Some("Basic YTpi"), Some("Bearer  ")] {
+            assert!(matches!(
+                authorize(&config, header).unwrap(),
+                Err(Rejection::Unauthorized("missing bearer token"))
+            ));
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn cached_results_expire_with_the_ttl_or_the_token() {
+        let (mut config, calls) = endpoint(200, active("read"));
+        config.cache_ttl = Duration::from_millis(50);
+        let auth = bearer("short-ttl-token");
+        authorize(&config, Some(&auth)).unwrap().unwrap();
+        authorize(&config, Some(&auth)).unwrap().unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        std::thread::sleep(Duration::from_millis(80));
+        authorize(&config, Some(&auth)).unwrap().unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        // An `exp` already past is never cached, whatever the TTL.
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let body = format!(r#"{{"active":true,"sub":"bob","exp":{}}}"#, now - 10);
+        let (config, calls) = endpoint(200, body);
+        let auth = bearer("expired-token");
+        authorize(&config, Some(&auth)).unwrap().unwrap();
+        authorize(&config, Some(&auth)).unwrap().unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn inactive_tokens_are_rejected_and_endpoint_failures_are_errors() {
+        let (config, calls) = endpoint(200, r#"{"active":false}"#.to_string());
+        let auth = bearer("inactive-token");
+        for _ in 0..2 {
+            assert!(matches!(
+                authorize(&config, Some(&auth)).unwrap(),
+                Err(Rejection::Unauthorized("token is not active"))
+            ));
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        let (config, _) = endpoint(200, r#"{"sub":"alice"}"#.to_string());
+        assert!(matches!(
+            authorize(&config, Some(&bearer("no-active-field"))).unwrap(),
+            Err(Rejection::Unauthorized("token is not active"))
+        ));
+
+        let (config, _) = endpoint(500, active("read"));
+        let err = authorize(&config, Some(&bearer("endpoint-500"))).unwrap_err();
+        assert!(err.to_string().contains("status 500"), "{err:#}");
+        let (config, _) = endpoint(200, "<html>".to_string());
+        assert!(authorize(&config, Some(&bearer("endpoint-html"))).is_err());
+    }
+
+    #[test]
+    fn required_scope_must_be_a_whole_entry() {
+        let (mut config, _) = endpoint(200, active("read writer"));
+        config.required_scope = Some("write".to_string());
+        assert!(matches!(
+            authorize(&config, Some(&bearer("scope-writer"))).unwrap(),
+            Err(Rejection::Forbidden("token lacks required scope"))
+        ));
+
+        let (mut config, _) = endpoint(200, active("read write"));
+        config.required_scope = Some("write".to_string());
+        assert!(authorize(&config, Some(&bearer("scope-write")))
+            .unwrap()
+            .is_ok());
+    }
+
+    #[test]
+    fn routes_limit_which_paths_need_a_token() {
+        let (mut config, _) = endpoint(200, active(""));
+        assert!(config.applies_to("/anything"));
+        config.routes = vec!["/api/".to_string(), "/admin".to_string()];
+        assert!(config.applies_to("/api/users"));
+        assert!(config.applies_to("/admin/switch"));
+        assert!(config.applies_to("/api"));
+        assert!(config.applies_to("/public/../api/users"));
+        assert!(!config.applies_to("/apix"));
+        assert!(!config.applies_to("/administrator"));
+        assert!(!config.applies_to("/public/api/"));
+    }
+}
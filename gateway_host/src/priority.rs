@@ -0,0 +1,295 @@
+//! Two-tier request admission for the public listeners (`MAX_INFLIGHT`).
+//!
+//! Without `MAX_INFLIGHT` each public listener serves one connection at a
+//! time. With it, connections get a thread each and at most N workload
+//! requests run at once. Operational routes (`/health`, `/health/full`,
+//! `/metrics`, `/stats`, `/admin/*`, `/debug/*`) neither count nor wait, so
+//! they stay responsive while workloads saturate the gateway.
+//!
+//! Workload routes under `LOW_PRIORITY_ROUTES` (default `/compute`) are low
+//! priority: a freed slot goes to a queued normal request first. A request
+//! still queued after `INFLIGHT_QUEUE_TIMEOUT_MS` (default 5000) gets a 503.
+
+use anyhow::{Context, Result};
+use once_cell::sync::OnceCell;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::route_path;
+
+const DEFAULT_QUEUE_TIMEOUT: Duration = Duration::from_millis(5000);
+
+static ADMISSION: OnceCell<Admission> = OnceCell::new();
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Tier {
+    Normal,
+    Low,
+}
+
+impl Tier {
+    pub(crate) const ALL: [Tier; 2] = [Tier::Normal, Tier::Low];
+
+    pub(crate) fn label(self) -> &'static str {
+        match self {
+            Tier::Normal => "normal",
+            Tier::Low => "low",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct Admission {
+    max: usize,
+    queue_timeout: Duration,
+    low_routes: Vec<String>,
+    state: Mutex<State>,
+    freed: Condvar,
+    counters: [TierCounters; 2],
+}
+
+#[derive(Debug, Default)]
+struct State {
+    running: usize,
+    /// Requests queued per tier, indexed by `Tier as usize`.
+    waiting: [usize; 2],
+}
+
+#[derive(Debug, Default)]
+struct TierCounters {
+    admitted: AtomicU64,
+    /// Admitted after waiting for a slot.
+    queued: AtomicU64,
+    timed_out: AtomicU64,
+    wait_us: AtomicU64,
+}
+
+/// Counters for one tier, read at one point in time.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) struct TierStats {
+    pub(crate) admitted: u64,
+    pub(crate) queued: u64,
+    pub(crate) timed_out: u64,
+    pub(crate) wait_us: u64,
+    /// Requests waiting right now.
+    pub(crate) waiting: usize,
+}
+
+/// Error marker for a request shed after `INFLIGHT_QUEUE_TIMEOUT_MS`; the
+/// request path answers it with a 503.
+#[derive(Debug)]
+pub(crate) struct Shed {
+    pub(crate) tier: Tier,
+    pub(crate) waited: Duration,
+}
+
+impl std::fmt::Display for Shed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "gateway busy: {}-priority request queued for {:?}",
+            self.tier.label(),
+            self.waited
+        )
+    }
+}
+
+impl std::error::Error for Shed {}
+
+/// A workload slot, given back on drop.
+pub(crate) struct Slot<'a>(&'a Admission);
+
+impl Drop for Slot<'_> {
+    fn drop(&mut self) {
+        let mut state = self.0.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.running -= 1;
+        // Every waiter re-checks its tier, so normal requests get the slot.
+        self.0.freed.notify_all();
+    }
+}
+
+impl Admission {
+    fn new(max: usize, queue_timeout: Duration, low_routes: Vec<String>) -> Self {
+        Admission {
+            max,
+            queue_timeout,
+            low_routes,
+            state: Mutex::default(),
+            freed: Condvar::new(),
+            counters: Default::default(),
+        }
+    }
+
+    pub(crate) fn tier(&self, path: &str) -> Tier {
+        let path = route_path(path);
+        let low = self.low_routes.iter().any(|prefix| {
+            path.strip_prefix(prefix.as_str())
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+        });
+        if low {
+            Tier::Low
+        } else {
+            Tier::Normal
+        }
+    }
+
+    /// Waits for a slot; low-priority requests also wait while a normal one
+    /// is queued.
+    pub(crate) fn admit(&self, tier: Tier) -> Result<Slot<'_>> {
+        let started = Instant::now();
+        let counters = &self.counters[tier as usize];
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let blocked = |state: &State| {
+            state.running >= self.max
+                || (tier == Tier::Low && state.waiting[Tier::Normal as usize] > 0)
+        };
+        if blocked(&state) {
+            state.waiting[tier as usize] += 1;
+            while blocked(&state) {
+                let left = self.queue_timeout.saturating_sub(started.elapsed());
+                if left.is_zero() {
+                    state.waiting[tier as usize] -= 1;
+                    // A queued normal request may have been holding back low ones.
+                    self.freed.notify_all();
+                    counters.timed_out.fetch_add(1, Ordering::Relaxed);
+                    return Err(Shed {
+                        tier,
+                        waited: started.elapsed(),
+                    }
+                    .into());
+                }
+                state = self
+                    .freed
+                    .wait_timeout(state, left)
+                    .unwrap_or_else(|e| e.into_inner())
+                    .0;
+            }
+            state.waiting[tier as usize] -= 1;
+            counters.queued.fetch_add(1, Ordering::Relaxed);
+            counters
+                .wait_us
+                .fetch_add(started.elapsed().as_micros() as u64, Ordering::Relaxed);
+        }
+        state.running += 1;
+        counters.admitted.fetch_add(1, Ordering::Relaxed);
+        Ok(Slot(self))
+    }
+
+    pub(crate) fn max(&self) -> usize {
+        self.max
+    }
+
+    /// Workload requests running right now.
+    pub(crate) fn running(&self) -> usize {
+        self.state.lock().unwrap_or_else(|e| e.into_inner()).running
+    }
+
+    pub(crate) fn stats(&self, tier: Tier) -> TierStats {
+        let counters = &self.counters[tier as usize];
+        let load = |v: &AtomicU64| v.load(Ordering::Relaxed);
+        TierStats {
+            admitted: load(&counters.admitted),
+            queued: load(&counters.queued),
+            timed_out: load(&counters.timed_out),
+            wait_us: load(&counters.wait_us),
+            waiting: self.state.lock().unwrap_or_else(|e| e.into_inner()).waiting[tier as usize],
+        }
+    }
+}
+
+/// Reads `MAX_INFLIGHT`, `LOW_PRIORITY_ROUTES` and
+/// `INFLIGHT_QUEUE_TIMEOUT_MS`. Call once from `main`.
+pub(crate) fn init_from_env() -> Result<()> {
+    let max = match std::env::var("MAX_INFLIGHT") {
+        Ok(v) if !v.trim().is_empty() => v
+            .trim()
+            .parse::<usize>()
+            .with_context(|| format!("invalid MAX_INFLIGHT={v}"))?,
+        _ => 0,
+    };
+    if max == 0 {
+        return Ok(());
+    }
+    let queue_timeout = match std::env::var("INFLIGHT_QUEUE_TIMEOUT_MS") {
+        Ok(v) if !v.trim().is_empty() => Duration::from_millis(
+            v.trim()
+                .parse()
+                .with_context(|| format!("invalid INFLIGHT_QUEUE_TIMEOUT_MS={v}"))?,
+        ),
+        _ => DEFAULT_QUEUE_TIMEOUT,
+    };
+    let low_routes: Vec<String> = std::env::var("LOW_PRIORITY_ROUTES")
+        .unwrap_or_else(|_| "/compute".to_string())
+        .split(',')
+        .map(|p| p.trim().trim_end_matches('/').to_string())
+        .filter(|p| !p.is_empty())
+        .collect();
+    let admission = ADMISSION.get_or_init(|| Admission::new(max, queue_timeout, low_routes));
+    eprintln!(
+        "[wasm-host] admission: {} workload request(s) at once, low priority: {:?}, queued up to {:?}",
+        admission.max, admission.low_routes, admission.queue_timeout
+    );
+    Ok(())
+}
+
+/// The admission gate, when `MAX_INFLIGHT` is set.
+pub(crate) fn configured() -> Option<&'static Admission> {
+    ADMISSION.get()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn classifies_low_priority_routes() {
+        let admission = Admission::new(1, DEFAULT_QUEUE_TIMEOUT, vec!["/compute".to_string()]);
+        assert_eq!(admission.tier("/compute?n=5"), Tier::Low);
+        assert_eq!(admission.tier("/compute/heavy"), Tier::Low);
+        assert_eq!(admission.tier("/computer"), Tier::Normal);
+        assert_eq!(admission.tier("/api/x"), Tier::Normal);
+    }
+
+    #[test]
+    fn normal_requests_go_first_and_waits_time_out() {
+        let admission = Arc::new(Admission::new(1, Duration::from_millis(300), Vec::new()));
+        let first = admission.admit(Tier::Normal).unwrap();
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        let low = {
+            let (admission, tx) = (Arc::clone(&admission), tx.clone());
+            std::thread::spawn(move || {
+                let slot = admission.admit(Tier::Low);
+                tx.send("low").unwrap();
+                drop(slot);
+            })
+        };
+        std::thread::sleep(Duration::from_millis(20));
+        let normal = {
+            let admission = Arc::clone(&admission);
+            std::thread::spawn(move || {
+                let slot = admission.admit(Tier::Normal);
+                tx.send("normal").unwrap();
+                std::thread::sleep(Duration::from_millis(20));
+                drop(slot);
+            })
+        };
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(admission.stats(Tier::Low).waiting, 1);
+        drop(first);
+        assert_eq!(rx.recv().unwrap(), "normal");
+        assert_eq!(rx.recv().unwrap(), "low");
+        low.join().unwrap();
+        normal.join().unwrap();
+        assert_eq!(admission.stats(Tier::Normal).queued, 1);
+        assert_eq!(admission.stats(Tier::Low).queued, 1);
+
+        let _held = admission.admit(Tier::Normal).unwrap();
+        let err = admission.admit(Tier::Low).err().unwrap();
+        assert_eq!(err.downcast_ref::<Shed>().unwrap().tier, Tier::Low);
+        assert_eq!(admission.stats(Tier::Low).timed_out, 1);
+        assert_eq!(admission.running(), 1);
+    }
+}
@@ -0,0 +1,261 @@
+//! Limits and supervision for wasm runtime subprocesses.
+//!
+//! `WASM_MAX_PROCS` (default: 4 per CPU) caps the one-shot `wasmedge` /
+//! `wasmtime run` processes running at once, so a burst of requests cannot
+//! fork the host to death. A request over the cap waits up to
+//! `WASM_QUEUE_TIMEOUT_MS` (default 5000) for a slot, as does a request
+//! waiting for a busy `WASM_WORKERS` pool, and is then answered with a 503.
+//!
+//! Every child, pool workers included, is registered here. On SIGTERM or
+//! SIGINT the ones still running are killed and reaped before the gateway
+//! exits, instead of being left to init.
+
+use anyhow::{Context, Result};
+use once_cell::sync::{Lazy, OnceCell};
+use std::process::Child;
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::time::{Duration, Instant};
+
+const DEFAULT_QUEUE_TIMEOUT: Duration = Duration::from_millis(5000);
+const PROCS_PER_CPU: usize = 4;
+
+static LIMIT: OnceCell<ProcessLimit> = OnceCell::new();
+static CHILDREN: Lazy<Mutex<Vec<Weak<Mutex<Child>>>>> = Lazy::new(Mutex::default);
+
+#[derive(Debug)]
+pub(crate) struct ProcessLimit {
+    max: usize,
+    queue_timeout: Duration,
+    running: Mutex<usize>,
+    freed: Condvar,
+}
+
+/// Error marker for a request that waited `WASM_QUEUE_TIMEOUT_MS` without
+/// getting a wasm process; the request path answers it with a 503.
+#[derive(Debug)]
+pub(crate) struct QueueTimeout {
+    pub(crate) waited: Duration,
+}
+
+impl std::fmt::Display for QueueTimeout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "no wasm process free after {:?}", self.waited)
+    }
+}
+
+impl std::error::Error for QueueTimeout {}
+
+/// A running-process slot, given back on drop.
+pub(crate) struct Permit(&'static ProcessLimit);
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let mut running = self.0.running.lock().unwrap_or_else(|e| e.into_inner());
+        *running -= 1;
+        self.0.freed.notify_one();
+    }
+}
+
+impl ProcessLimit {
+    fn new(max: usize, queue_timeout: Duration) -> Self {
+        ProcessLimit {
+            max,
+            queue_timeout,
+            running: Mutex::new(0),
+            freed: Condvar::new(),
+        }
+    }
+
+    fn acquire(&'static self) -> Result<Permit> {
+        let started = Instant::now();
+        let mut running = self.running.lock().unwrap_or_else(|e| e.into_inner());
+        while *running >= self.max {
+            let left = self.queue_timeout.saturating_sub(started.elapsed());
+            if left.is_zero() {
+                return Err(QueueTimeout {
+                    waited: started.elapsed(),
+                }
+                .into());
+            }
+            running = self
+                .freed
+                .wait_timeout(running, left)
+                .unwrap_or_else(|e| e.into_inner())
+                .0;
+        }
+        *running += 1;
+        Ok(Permit(self))
+    }
+}
+
+/// Reads `WASM_MAX_PROCS` / `WASM_QUEUE_TIMEOUT_MS` and installs the
+/// shutdown reaper. Call once from `main`, before any module runs.
+pub(crate) fn init_from_env() -> Result<()> {
+    let number = |var: &str| -> Result<Option<u64>> {
+        match std::env::var(var) {
+            Ok(v) if !v.trim().is_empty() => v
+                .trim()
+                .parse::<u64>()
+                .map(Some)
+                .with_context(|| format!("invalid {var}={v}")),
+            _ => Ok(None),
+        }
+    };
+    let max = match number("WASM_MAX_PROCS")? {
+        Some(0) => usize::MAX,
+        Some(n) => n as usize,
+        None => std::thread::available_parallelism().map_or(1, |n| n.get()) * PROCS_PER_CPU,
+    };
+    let queue_timeout =
+        number("WASM_QUEUE_TIMEOUT_MS")?.map_or(DEFAULT_QUEUE_TIMEOUT, Duration::from_millis);
+    let limit = LIMIT.get_or_init(|| ProcessLimit::new(max, queue_timeout));
+    if limit.max != usize::MAX {
+        eprintln!(
+            "[wasm-host] wasm processes: at most {} at once, queued up to {:?}",
+            limit.max, limit.queue_timeout
+        );
+    }
+    install_reaper()
+}
+
+/// Waits for a slot below `WASM_MAX_PROCS`. Unlimited before `init_from_env`.
+pub(crate) fn acquire() -> Result<Option<Permit>> {
+    LIMIT.get().map(ProcessLimit::acquire).transpose()
+}
+
+/// How long a request may wait for a wasm process.
+pub(crate) fn queue_timeout() -> Duration {
+    LIMIT
+        .get()
+        .map_or(DEFAULT_QUEUE_TIMEOUT, |l| l.queue_timeout)
+}
+
+/// Tracks `child` for the shutdown reaper; dropped children fall out on
+/// their own.
+pub(crate) fn register(child: &Arc<Mutex<Child>>) {
+    let mut children = CHILDREN.lock().unwrap_or_else(|e| e.into_inner());
+    children.retain(|c| c.strong_count() > 0);
+    children.push(Arc::downgrade(child));
+}
+
+/// Kills and reaps every registered child still running; returns how many.
+/// Like the timeout watchdogs, it only kills while holding the child's lock
+/// after `try_wait` saw it running, so it never signals a reused pid.
+fn reap_all() -> usize {
+    let children = std::mem::take(&mut *CHILDREN.lock().unwrap_or_else(|e| e.into_inner()));
+    let mut killed = 0;
+    for child in children.iter().filter_map(Weak::upgrade) {
+        let mut child = child.lock().unwrap_or_else(|e| e.into_inner());
+        if matches!(child.try_wait(), Ok(None)) {
+            child.kill().ok();
+            child.wait().ok();
+            killed += 1;
+        }
+    }
+    killed
+}
+
+#[cfg(unix)]
+fn install_reaper() -> Result<()> {
+    use std::io::Read;
+    use std::os::unix::io::FromRawFd;
+    use std::sync::atomic::{AtomicI32, Ordering};
+
+    static WAKE_FD: AtomicI32 = AtomicI32::new(-1);
+
+    // Only async-signal-safe calls here: the signal number goes down a pipe
+    // and the reaper thread does the rest.
+    extern "C" fn on_signal(signal: libc::c_int) {
+        let byte = signal as u8;
+        unsafe {
+            libc::write(
+                WAKE_FD.load(Ordering::Relaxed),
+                (&byte as *const u8).cast(),
+                1,
+            );
+        }
+    }
+
+    if WAKE_FD.load(Ordering::Relaxed) >= 0 {
+        return Ok(());
+    }
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+        return Err(std::io::Error::last_os_error()).context("create shutdown pipe");
+    }
+    // Keep both ends out of the runtime processes.
+    for fd in fds {
+        unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+    }
+    WAKE_FD.store(fds[1], Ordering::Relaxed);
+    let mut wake = unsafe { std::fs::File::from_raw_fd(fds[0]) };
+    std::thread::Builder::new()
+        .name("wasm-reaper".to_string())
+        .spawn(move || {
+            let mut signal = [0u8; 1];
+            if wake.read_exact(&mut signal).is_err() {
+                return;
+            }
+            let killed = reap_all();
+            eprintln!(
+                "[wasm-host] signal {}: killed {killed} wasm process(es), exiting",
+                signal[0]
+            );
+            std::process::exit(128 + i32::from(signal[0]));
+        })
+        .context("spawn wasm reaper thread")?;
+    for signal in [libc::SIGTERM, libc::SIGINT] {
+        // `sigaction` handlers reset to the default in exec'd children.
+        let handler: extern "C" fn(libc::c_int) = on_signal;
+        unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction = handler as libc::sighandler_t;
+            action.sa_flags = libc::SA_RESTART;
+            libc::sigemptyset(&mut action.sa_mask);
+            if libc::sigaction(signal, &action, std::ptr::null_mut()) != 0 {
+                return Err(std::io::Error::last_os_error())
+                    .with_context(|| format!("install handler for signal {signal}"));
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn install_reaper() -> Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn queues_then_times_out() {
+        let limit: &'static ProcessLimit =
+            Box::leak(Box::new(ProcessLimit::new(1, Duration::from_millis(50))));
+        let first = limit.acquire().unwrap();
+        let err = limit.acquire().err().unwrap();
+        assert!(err.downcast_ref::<QueueTimeout>().unwrap().waited >= Duration::from_millis(50));
+
+        let waiter = std::thread::spawn(move || limit.acquire().map(|_| ()));
+        std::thread::sleep(Duration::from_millis(10));
+        drop(first);
+        assert!(waiter.join().unwrap().is_ok());
+        assert_eq!(*limit.running.lock().unwrap(), 0);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn reaps_running_children() {
+        let child = Arc::new(Mutex::new(
+            std::process::Command::new("sleep")
+                .arg("30")
+                .spawn()
+                .unwrap(),
+        ));
+        register(&child);
+        assert!(reap_all() >= 1);
+        assert!(child.lock().unwrap().try_wait().unwrap().is_some());
+    }
+}
@@ -0,0 +1,202 @@
+//! Single byte-range requests (RFC 9110 §14) on bodies the gateway holds in
+//! full: `/render` output and transformed `/proxy` responses.
+//!
+//! The range is always taken from the body the client would otherwise get,
+//! so `Range` and `If-Range` are not forwarded upstream: a partial upstream
+//! body would be transformed as if it were the whole one. A `GET` for a `200`
+//! with one satisfiable `bytes=` range gets a `206` with `Content-Range`, one
+//! that starts past the end a `416` with `Content-Range: bytes */<length>`.
+//! Multiple ranges, other units and malformed values are ignored and the
+//! full `200` is sent, as the RFC allows. An `If-Range` that does not match
+//! the response's strong `ETag` or its `Last-Modified` also gets the full
+//! body.
+
+use std::ops::Range;
+
+use crate::headers::Headers;
+
+/// Request fields not forwarded upstream (empty values only drop).
+pub(crate) const FORWARD_OVERRIDES: [(&str, &str); 2] = [("Range", ""), ("If-Range", "")];
+
+/// The part of a complete `200` body to send.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Selection {
+    Full,
+    Partial(Range<usize>),
+    Unsatisfiable,
+}
+
+impl Selection {
+    /// The selection for `request` against a `len`-byte body whose response
+    /// carries `validators` (`ETag`, `Last-Modified`).
+    pub(crate) fn for_request(
+        method: &str,
+        request: &Headers,
+        validators: &Headers,
+        len: usize,
+    ) -> Selection {
+        if method != "GET" {
+            return Selection::Full;
+        }
+        let Some(range) = request.get("Range") else {
+            return Selection::Full;
+        };
+        if let Some(if_range) = request.get("If-Range") {
+            if !if_range_matches(if_range.trim(), validators) {
+                return Selection::Full;
+            }
+        }
+        parse(range, len).unwrap_or(Selection::Full)
+    }
+
+    /// Status line, body slice and `Content-Range` for this selection of
+    /// `body`; `None` as the status keeps the original `200`.
+    pub(crate) fn apply<'a>(
+        &self,
+        body: &'a [u8],
+    ) -> (Option<&'static str>, &'a [u8], Option<String>) {
+        match self {
+            Selection::Full => (None, body, None),
+            Selection::Partial(range) => (
+                Some("HTTP/1.1 206 Partial Content"),
+                &body[range.clone()],
+                Some(format!(
+                    "bytes {}-{}/{}",
+                    range.start,
+                    range.end - 1,
+                    body.len()
+                )),
+            ),
+            Selection::Unsatisfiable => (
+                Some("HTTP/1.1 416 Range Not Satisfiable"),
+                &[],
+                Some(format!("bytes */{}", body.len())),
+            ),
+        }
+    }
+}
+
+/// `None` for anything that is not a single valid `bytes=` range.
+fn parse(value: &str, len: usize) -> Option<Selection> {
+    let (unit, spec) = value.trim().split_once('=')?;
+    if !unit.trim().eq_ignore_ascii_case("bytes") || spec.contains(',') {
+        return None;
+    }
+    let (first, last) = spec.trim().split_once('-')?;
+    let number = |s: &str| {
+        (!s.is_empty() && s.bytes().all(|b| b.is_ascii_digit()))
+            .then(|| s.parse::<u64>().ok())
+            .flatten()
+    };
+    let len64 = len as u64;
+    let (start, end) = match (first.trim(), last.trim()) {
+        ("", suffix) => {
+            let suffix = number(suffix)?;
+            if suffix == 0 || len == 0 {
+                return Some(Selection::Unsatisfiable);
+            }
+            (len64.saturating_sub(suffix), len64)
+        }
+        (first, "") => (number(first)?, len64),
+        (first, last) => {
+            let (first, last) = (number(first)?, number(last)?);
+            if last < first {
+                return None;
+            }
+            (first, last.saturating_add(1).min(len64))
+        }
+    };
+    if start >= len64 {
+        return Some(Selection::Unsatisfiable);
+    }
+    Some(Selection::Partial(start as usize..end as usize))
+}
+
+/// Only strong validators count (RFC 9110 §13.1.5).
+fn if_range_matches(if_range: &str, validators: &Headers) -> bool {
+    if if_range.starts_with('"') {
+        validators.get("ETag").map(str::trim) == Some(if_range)
+    } else if if_range.starts_with("W/") {
+        false
+    } else {
+        validators.get("Last-Modified").map(str::trim) == Some(if_range)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn selects_single_ranges() {
+        let select = |request: &str| {
+            Selection::for_request("GET", &Headers::parse(request), &Headers::default(), 10)
+        };
+        assert_eq!(select("Range: bytes=0-3"), Selection::Partial(0..4));
+        assert_eq!(select("Range: bytes=7-"), Selection::Partial(7..10));
+        assert_eq!(select("Range: bytes=-4"), Selection::Partial(6..10));
+        assert_eq!(select("Range: bytes=-40"), Selection::Partial(0..10));
+        assert_eq!(select("Range: bytes=5-99"), Selection::Partial(5..10));
+        assert_eq!(select("Range: bytes=10-"), Selection::Unsatisfiable);
+        assert_eq!(select("Range: bytes=-0"), Selection::Unsatisfiable);
+        for ignored in [
+            "",
+            "Range: bytes=0-1, 4-5",
+            "Range: items=0-1",
+            "Range: bytes=3-1",
+            "Range: bytes=+1-2",
+            "Range: bytes=-",
+        ] {
+            assert_eq!(select(ignored), Selection::Full, "{ignored:?}");
+        }
+        assert_eq!(
+            Selection::for_request(
+                "HEAD",
+                &Headers::parse("Range: bytes=0-3"),
+                &Headers::default(),
+                10
+            ),
+            Selection::Full
+        );
+
+        let validators =
+            Headers::parse("ETag: \"v1\"\r\nLast-Modified: Tue, 01 Sep 2026 10:00:00 GMT");
+        let conditional = |request: &str| {
+            Selection::for_request("GET", &Headers::parse(request), &validators, 10)
+        };
+        assert_eq!(
+            conditional("Range: bytes=0-3\r\nIf-Range: \"v1\""),
+            Selection::Partial(0..4)
+        );
+        assert_eq!(
+            conditional("Range: bytes=0-3\r\nIf-Range: Tue, 01 Sep 2026 10:00:00 GMT"),
+            Selection::Partial(0..4)
+        );
+        assert_eq!(
+            conditional("Range: bytes=0-3\r\nIf-Range: \"v2\""),
+            Selection::Full
+        );
+        assert_eq!(
+            conditional("Range: bytes=0-3\r\nIf-Range: W/\"v1\""),
+            Selection::Full
+        );
+
+        let body = b"0123456789";
+        assert_eq!(
+            Selection::Partial(2..5).apply(body),
+            (
+                Some("HTTP/1.1 206 Partial Content"),
+                &b"234"[..],
+                Some("bytes 2-4/10".to_string())
+            )
+        );
+        assert_eq!(
+            Selection::Unsatisfiable.apply(body),
+            (
+                Some("HTTP/1.1 416 Range Not Satisfiable"),
+                &b""[..],
+                Some("bytes */10".to_string())
+            )
+        );
+    }
+}
@@ -0,0 +1,182 @@
+//! Fixed-window rate limits for proxied requests, configured per route and
+//! optionally overridden per API key, counted in the shared store.
+//!
+//! `RATE_LIMIT_ROUTES=/api=100/60,/=1000/60` allows 100 requests per 60 s per
+//! key under `/api` (longest prefix wins). `RATE_LIMIT_KEYS=gold=5000/60`
+//! replaces the route limit for that key. The key is the value of
+//! `RATE_LIMIT_KEY_HEADER` (default `X-API-Key`) when it names one of
+//! `RATE_LIMIT_KEYS`, and the client IP otherwise, so a client cannot escape
+//! its limit by sending a new made-up key with each request.
+
+use anyhow::{anyhow, Context, Result};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::store::SharedStore;
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Limit {
+    pub(crate) requests: u64,
+    pub(crate) window_secs: u64,
+}
+
+#[derive(Debug)]
+pub(crate) struct RateLimitConfig {
+    /// Longest prefix first.
+    routes: Vec<(String, Limit)>,
+    keys: HashMap<String, Limit>,
+    pub(crate) key_header: String,
+}
+
+/// Outcome of a counted request, used for the `X-RateLimit-*` headers.
+#[derive(Debug)]
+pub(crate) struct Decision {
+    pub(crate) allowed: bool,
+    pub(crate) limit: u64,
+    pub(crate) remaining: u64,
+    pub(crate) reset_secs: u64,
+}
+
+impl RateLimitConfig {
+    pub(crate) fn from_vars(var: &dyn Fn(&str) -> Option<String>) -> Result<Option<Self>> {
+        let Some(spec) = var("RATE_LIMIT_ROUTES") else {
+            return Ok(None);
+        };
+        let mut routes = parse_pairs(&spec).context("invalid RATE_LIMIT_ROUTES")?;
+        routes.sort_by_key(|(prefix, _)| std::cmp::Reverse(prefix.len()));
+        let keys = match var("RATE_LIMIT_KEYS") {
+            Some(spec) => parse_pairs(&spec)
+                .context("invalid RATE_LIMIT_KEYS")?
+                .into_iter()
+                .collect(),
+            None => HashMap::new(),
+        };
+        if routes.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(RateLimitConfig {
+            routes,
+            keys,
+            key_header: var("RATE_LIMIT_KEY_HEADER").unwrap_or_else(|| "X-API-Key".to_string()),
+        }))
+    }
+
+    /// The key a request is counted under: `presented` (the
+    /// `key_header` value) if it is a configured key, else the client IP.
+    pub(crate) fn key(&self, presented: Option<&str>, peer: Option<IpAddr>) -> String {
+        match presented {
+            Some(key) if self.keys.contains_key(key) => key.to_string(),
+            _ => peer.map(|ip| ip.to_string()).unwrap_or_default(),
+        }
+    }
+
+    /// Counts one request for `key` on `path`. Returns `None` when no route
+    /// limit covers the path.
+    pub(crate) fn check(
+        &self,
+        store: &SharedStore,
+        path: &str,
+        key: &str,
+    ) -> Result<Option<Decision>> {
+        let Some((prefix, route_limit)) = self.routes.iter().find(|(p, _)| path.starts_with(p))
+        else {
+            return Ok(None);
+        };
+        let limit = self.keys.get(key).copied().unwrap_or(*route_limit);
+        if limit.window_secs == 0 {
+            return Ok(None);
+        }
+        count(store, prefix, key, limit).map(Some)
+    }
+}
+
+/// Counts one request for `key` in `scope`'s current window of `limit`.
+pub(crate) fn count(store: &SharedStore, scope: &str, key: &str, limit: Limit) -> Result<Decision> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let window = now / limit.window_secs;
+    let reset_secs = (window + 1) * limit.window_secs - now;
+    // Raw API keys are never written to the store.
+    let key_hash = hex::encode(&Sha256::digest(key.as_bytes())[..8]);
+    let store_key = format!("ratelimit:{scope}:{key_hash}:{window}");
+    let count = store.incr(&store_key, Some(Duration::from_secs(limit.window_secs)))?;
+    let count = count.max(0) as u64;
+    Ok(Decision {
+        allowed: count <= limit.requests,
+        limit: limit.requests,
+        remaining: limit.requests.saturating_sub(count),
+        reset_secs,
+    })
+}
+
+/// Parses `name=N/SECS,...`.
+fn parse_pairs(spec: &str) -> Result<Vec<(String, Limit)>> {
+    spec.split(',')
+        .map(str::trim)
+        .filter(|e| !e.is_empty())
+        .map(|entry| {
+            let (name, limit) = entry
+                .rsplit_once('=')
+                .ok_or_else(|| anyhow!("expected name=N/SECS, got {entry:?}"))?;
+            let (requests, window) = limit
+                .split_once('/')
+                .ok_or_else(|| anyhow!("expected N/SECS in {entry:?}"))?;
+            Ok((
+                name.trim().to_string(),
+                Limit {
+                    requests: requests
+                        .trim()
+                        .parse()
+                        .with_context(|| format!("bad request count in {entry:?}"))?,
+                    window_secs: window
+                        .trim()
+                        .parse()
+                        .with_context(|| format!("bad window in {entry:?}"))?,
+                },
+            ))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> RateLimitConfig {
+        let vars = |name: &str| match name {
+            "RATE_LIMIT_ROUTES" => Some("/api=2/60,/=100/60".to_string()),
+            "RATE_LIMIT_KEYS" => Some("gold=5/60".to_string()),
+            _ => None,
+        };
+        RateLimitConfig::from_vars(&vars).unwrap().unwrap()
+    }
+
+    #[test]
+    fn only_configured_keys_replace_the_client_ip() {
+        let config = config();
+        let peer: Option<IpAddr> = "192.0.2.7".parse().ok();
+        assert_eq!(config.key_header, "X-API-Key");
+        assert_eq!(config.key(Some("gold"), peer), "gold");
+        assert_eq!(config.key(Some("made-up-1"), peer), "192.0.2.7");
+        assert_eq!(config.key(Some(""), peer), "192.0.2.7");
+        assert_eq!(config.key(None, peer), "192.0.2.7");
+
+        // Rotating unknown keys still counts against the one address.
+        let store = SharedStore::Memory(Default::default());
+        let mut allowed = Vec::new();
+        for i in 0..3 {
+            let key = config.key(Some(&format!("made-up-{i}")), peer);
+            let decision = config.check(&store, "/api/x", &key).unwrap().unwrap();
+            allowed.push(decision.allowed);
+        }
+        assert_eq!(allowed, [true, true, false]);
+
+        let gold = config.check(&store, "/api/x", "gold").unwrap().unwrap();
+        assert_eq!((gold.limit, gold.remaining), (5, 4));
+        assert!(config.check(&store, "/other", "gold").unwrap().is_some());
+    }
+}
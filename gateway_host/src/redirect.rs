@@ -0,0 +1,119 @@
+//! Plain-HTTP listener that sends everything to HTTPS (`LISTEN_REDIRECT`).
+//!
+//! Every request gets a `301` to `https://<Host><target>`, with the port of
+//! `LISTEN_TLS` (or `REDIRECT_HTTPS_PORT`, for when a load balancer maps it)
+//! unless it is 443. `GET /health` is answered here instead, so container
+//! healthchecks against this port keep working, and so are the
+//! `well_known` paths, so ACME HTTP-01 challenges can be met.
+
+use anyhow::{anyhow, Context, Result};
+use std::io::Write;
+use std::net::{Shutdown, TcpListener, TcpStream};
+
+use crate::{
+    accept, build_response, host_name, parse_request_head, read_http_head, route_path, well_known,
+    IO_TIMEOUT,
+};
+
+/// Accept loop for `LISTEN_REDIRECT`; `https_port` is where clients are sent.
+pub(crate) fn serve(listener: &TcpListener, https_port: u16) {
+    let mut backoff = accept::AcceptBackoff::default();
+    for incoming in listener.incoming() {
+        match incoming {
+            Ok(mut client) => {
+                backoff.accepted();
+                if let Err(e) = handle(&mut client, https_port) {
+                    eprintln!("[wasm-host] redirect error: {e:#}");
+                }
+            }
+            Err(e) => backoff.failed(&e),
+        }
+    }
+}
+
+fn handle(client: &mut TcpStream, https_port: u16) -> Result<()> {
+    client.set_read_timeout(Some(IO_TIMEOUT)).ok();
+    client.set_write_timeout(Some(IO_TIMEOUT)).ok();
+    let (head, _) = read_http_head(client)?;
+    let req = parse_request_head(&head)?;
+    let well_known = well_known::configured()
+        .map(|w| w.response(&req.method, &req.path))
+        .transpose()?
+        .flatten();
+    let resp = if req.method == "GET" && route_path(&req.path) == "/health" {
+        build_response("HTTP/1.1 200 OK", b"OK", "health", Some("text/plain"), &[])
+    } else if let Some(resp) = well_known {
+        resp
+    } else {
+        match location(req.header("Host"), &req.path, https_port) {
+            Ok(location) => build_response(
+                "HTTP/1.1 301 Moved Permanently",
+                b"",
+                "redirect",
+                None,
+                &[("Location", &location)],
+            ),
+            Err(e) => build_response(
+                "HTTP/1.1 400 Bad Request",
+                format!("{e}\n").as_bytes(),
+                "redirect",
+                Some("text/plain"),
+                &[],
+            ),
+        }
+    };
+    client.write_all(&resp).context("write redirect")?;
+    client.flush().ok();
+    client.shutdown(Shutdown::Both).ok();
+    Ok(())
+}
+
+/// The HTTPS URL for `target` (origin or absolute form) on `host`.
+fn location(host: Option<&str>, target: &str, https_port: u16) -> Result<String> {
+    let host = host
+        .map(host_name)
+        .filter(|h| !h.is_empty())
+        .ok_or_else(|| anyhow!("missing Host header"))?;
+    if host
+        .chars()
+        .any(|c| c.is_whitespace() || matches!(c, '/' | '\\' | '@' | '?' | '#'))
+    {
+        return Err(anyhow!("invalid Host header"));
+    }
+    let target = match target.strip_prefix("http://") {
+        // Absolute form: keep what follows the authority.
+        Some(rest) => rest.find('/').map_or("/", |i| &rest[i..]),
+        None => target,
+    };
+    Ok(match https_port {
+        443 => format!("https://{host}{target}"),
+        port => format!("https://{host}:{port}{target}"),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builds_https_location() {
+        assert_eq!(
+            location(Some("api.test:8080"), "/a/b?x=1&y=2", 443).unwrap(),
+            "https://api.test/a/b?x=1&y=2"
+        );
+        assert_eq!(
+            location(Some("api.test"), "/", 8443).unwrap(),
+            "https://api.test:8443/"
+        );
+        assert_eq!(
+            location(Some("[::1]:8080"), "/x", 8443).unwrap(),
+            "https://[::1]:8443/x"
+        );
+        assert_eq!(
+            location(Some("api.test"), "http://api.test:8080/p?q", 443).unwrap(),
+            "https://api.test/p?q"
+        );
+        assert!(location(None, "/", 443).is_err());
+        assert!(location(Some("evil.test/x"), "/", 443).is_err());
+    }
+}
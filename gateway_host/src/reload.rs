@@ -0,0 +1,288 @@
+//! Settings file re-read on `SIGHUP` (`CONFIG_FILE`).
+//!
+//! `CONFIG_FILE` holds `NAME=value` lines, in the `SECRETS_ENV_FILE` format,
+//! that override the environment. At startup every entry applies. On
+//! `SIGHUP`, or when the file changes with `CONFIG_WATCH_SECS` set, the file
+//! is read again and the settings in `RELOADABLE` are applied together: the
+//! route table (`UPSTREAM_URL`, `UPSTREAM_POOL`, `VHOST_UPSTREAMS`), the
+//! limits (`RATE_LIMIT_*`, `BATCH_PARALLELISM`) and the wasm settings
+//! (`WASM_MODULE_PATH`, `WASM_ENABLED`, `WASM_AUDIT_HEADERS`). A request
+//! keeps the settings it started with, so it never sees half a reload. An
+//! invalid file, or one whose settings fail to load (a module that does not
+//! compile or verify, an unresolvable pool member), is rejected and the
+//! running settings stay. Each applied change is logged as `NAME: old -> new`;
+//! other settings that changed are logged as needing a restart.
+
+use anyhow::{Context, Result};
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+use std::sync::mpsc;
+use std::time::{Duration, SystemTime};
+
+use crate::secrets;
+
+/// Settings a reload applies; everything else is read once at startup.
+pub(crate) const RELOADABLE: &[&str] = &[
+    "UPSTREAM_URL",
+    "UPSTREAM_POOL",
+    "VHOST_UPSTREAMS",
+    "RATE_LIMIT_ROUTES",
+    "RATE_LIMIT_KEYS",
+    "RATE_LIMIT_KEY_HEADER",
+    "BATCH_PARALLELISM",
+    "WASM_MODULE_PATH",
+    "WASM_ENABLED",
+    "WASM_AUDIT_HEADERS",
+];
+
+/// The settings a reload starts from: environment plus `CONFIG_FILE`.
+#[derive(Debug)]
+pub(crate) struct Settings {
+    path: PathBuf,
+    env: HashMap<String, String>,
+    file: BTreeMap<String, String>,
+    modified: Option<SystemTime>,
+    watch: Option<Duration>,
+}
+
+/// Reads `CONFIG_FILE` and `CONFIG_WATCH_SECS`, and puts the file's entries
+/// into the environment for the startup code to read. Call first thing in
+/// `main`, before any thread starts.
+pub(crate) fn init_from_env() -> Result<Option<Settings>> {
+    let Some(path) = std::env::var("CONFIG_FILE")
+        .ok()
+        .filter(|v| !v.trim().is_empty())
+        .map(PathBuf::from)
+    else {
+        return Ok(None);
+    };
+    let watch = match std::env::var("CONFIG_WATCH_SECS") {
+        Ok(v) if !v.trim().is_empty() => {
+            let secs = v
+                .trim()
+                .parse::<u64>()
+                .with_context(|| format!("invalid CONFIG_WATCH_SECS={v}"))?;
+            (secs > 0).then(|| Duration::from_secs(secs))
+        }
+        _ => None,
+    };
+    let env: HashMap<String, String> = std::env::vars().collect();
+    let (file, modified) = read(&path)?;
+    for (name, value) in &file {
+        std::env::set_var(name, value);
+    }
+    eprintln!(
+        "[wasm-host] config: {} setting(s) from {}, reloaded on SIGHUP{}",
+        file.len(),
+        path.display(),
+        watch.map_or(String::new(), |w| format!(" and every {w:?} if changed"))
+    );
+    Ok(Some(Settings {
+        path,
+        env,
+        file,
+        modified,
+        watch,
+    }))
+}
+
+fn read(path: &std::path::Path) -> Result<(BTreeMap<String, String>, Option<SystemTime>)> {
+    let modified = std::fs::metadata(path).and_then(|m| m.modified()).ok();
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("read CONFIG_FILE {}", path.display()))?;
+    let entries = secrets::parse_env_file(&text)
+        .with_context(|| format!("invalid CONFIG_FILE {}", path.display()))?;
+    Ok((entries.into_iter().collect(), modified))
+}
+
+impl Settings {
+    fn get<'a>(&'a self, file: &'a BTreeMap<String, String>, name: &str) -> Option<&'a str> {
+        file.get(name)
+            .or_else(|| self.env.get(name))
+            .map(String::as_str)
+    }
+
+    /// Waits for `SIGHUP` (or a changed file) and hands the new settings to
+    /// `apply`, which either applies all of them or returns an error and
+    /// changes nothing. Runs until the process exits.
+    pub(crate) fn run(mut self, apply: impl Fn(&dyn Fn(&str) -> Option<String>) -> Result<()>) {
+        let hangups = match on_hangup() {
+            Ok(hangups) => hangups,
+            Err(e) => {
+                eprintln!("[wasm-host] config: no SIGHUP reloads: {e:#}");
+                return;
+            }
+        };
+        loop {
+            let signaled = match self.watch {
+                Some(every) => match hangups.recv_timeout(every) {
+                    Ok(()) => true,
+                    Err(mpsc::RecvTimeoutError::Timeout) => false,
+                    Err(mpsc::RecvTimeoutError::Disconnected) => return,
+                },
+                None => match hangups.recv() {
+                    Ok(()) => true,
+                    Err(_) => return,
+                },
+            };
+            if !signaled
+                && std::fs::metadata(&self.path)
+                    .and_then(|m| m.modified())
+                    .ok()
+                    == self.modified
+            {
+                continue;
+            }
+            if let Err(e) = self.reload(&apply) {
+                eprintln!("[wasm-host] config: rejected, keeping the running settings: {e:#}");
+            }
+        }
+    }
+
+    fn reload(
+        &mut self,
+        apply: &impl Fn(&dyn Fn(&str) -> Option<String>) -> Result<()>,
+    ) -> Result<()> {
+        let (file, modified) = read(&self.path)?;
+        self.modified = modified;
+        let mut changed = Vec::new();
+        let mut restart = Vec::new();
+        let names: std::collections::BTreeSet<&String> =
+            self.file.keys().chain(file.keys()).collect();
+        for name in names {
+            let (old, new) = (self.get(&self.file, name), self.get(&file, name));
+            if old == new {
+                continue;
+            }
+            if RELOADABLE.contains(&name.as_str()) {
+                changed.push(format!(
+                    "{name}: {} -> {}",
+                    old.unwrap_or("(unset)"),
+                    new.unwrap_or("(unset)")
+                ));
+            } else {
+                restart.push(name.as_str());
+            }
+        }
+        if !restart.is_empty() {
+            eprintln!(
+                "[wasm-host] config: changed but applied only on restart: {}",
+                restart.join(", ")
+            );
+        }
+        if changed.is_empty() {
+            eprintln!("[wasm-host] config: reloaded, nothing to apply");
+        } else {
+            apply(&|name: &str| self.get(&file, name).map(str::to_string))?;
+            for change in &changed {
+                eprintln!("[wasm-host] config: {change}");
+            }
+        }
+        self.file = file;
+        Ok(())
+    }
+}
+
+/// A channel that gets a message for every `SIGHUP`.
+#[cfg(unix)]
+fn on_hangup() -> Result<mpsc::Receiver<()>> {
+    use std::io::Read;
+    use std::os::unix::io::FromRawFd;
+    use std::sync::atomic::{AtomicI32, Ordering};
+
+    static WAKE_FD: AtomicI32 = AtomicI32::new(-1);
+
+    // As in `procs`: only a pipe write in the handler.
+    extern "C" fn on_signal(_: libc::c_int) {
+        let byte = 1u8;
+        unsafe {
+            libc::write(
+                WAKE_FD.load(Ordering::Relaxed),
+                (&byte as *const u8).cast(),
+                1,
+            );
+        }
+    }
+
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+        return Err(std::io::Error::last_os_error()).context("create reload pipe");
+    }
+    for fd in fds {
+        unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+    }
+    WAKE_FD.store(fds[1], Ordering::Relaxed);
+    let mut wake = unsafe { std::fs::File::from_raw_fd(fds[0]) };
+    let (tx, rx) = mpsc::channel();
+    std::thread::Builder::new()
+        .name("config-reload".to_string())
+        .spawn(move || {
+            let mut byte = [0u8; 1];
+            while wake.read_exact(&mut byte).is_ok() && tx.send(()).is_ok() {}
+        })
+        .context("spawn config reload thread")?;
+    let handler: extern "C" fn(libc::c_int) = on_signal;
+    unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = handler as libc::sighandler_t;
+        action.sa_flags = libc::SA_RESTART;
+        libc::sigemptyset(&mut action.sa_mask);
+        if libc::sigaction(libc::SIGHUP, &action, std::ptr::null_mut()) != 0 {
+            return Err(std::io::Error::last_os_error()).context("install SIGHUP handler");
+        }
+    }
+    Ok(rx)
+}
+
+#[cfg(not(unix))]
+fn on_hangup() -> Result<mpsc::Receiver<()>> {
+    Err(anyhow::anyhow!("signals are unix-only"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    #[test]
+    fn applies_changes_and_keeps_settings_on_rejection() {
+        let path = std::env::temp_dir().join(format!("gateway-config-{}.env", std::process::id()));
+        std::fs::write(&path, "UPSTREAM_URL=http://a:1\nLISTEN=0.0.0.0:1\n").unwrap();
+        let (file, modified) = read(&path).unwrap();
+        let mut settings = Settings {
+            path: path.clone(),
+            env: HashMap::from([("BATCH_PARALLELISM".to_string(), "2".to_string())]),
+            file,
+            modified,
+            watch: None,
+        };
+        let seen = Mutex::new(Vec::new());
+        let apply = |var: &dyn Fn(&str) -> Option<String>| {
+            let upstream = var("UPSTREAM_URL").unwrap_or_default();
+            if upstream.contains("bad") {
+                return Err(anyhow::anyhow!("invalid UPSTREAM_URL={upstream}"));
+            }
+            seen.lock()
+                .unwrap()
+                .push((upstream, var("BATCH_PARALLELISM")));
+            Ok(())
+        };
+
+        std::fs::write(&path, "UPSTREAM_URL=http://bad\n").unwrap();
+        assert!(settings.reload(&apply).is_err());
+        assert_eq!(settings.file["UPSTREAM_URL"], "http://a:1");
+
+        // Restart-only settings alone do not call `apply`.
+        std::fs::write(&path, "UPSTREAM_URL=http://a:1\n").unwrap();
+        settings.reload(&apply).unwrap();
+        assert!(seen.lock().unwrap().is_empty());
+
+        std::fs::write(&path, "UPSTREAM_URL=http://b:2\n").unwrap();
+        settings.reload(&apply).unwrap();
+        assert_eq!(
+            *seen.lock().unwrap(),
+            vec![("http://b:2".to_string(), Some("2".to_string()))]
+        );
+        std::fs::remove_file(&path).ok();
+    }
+}
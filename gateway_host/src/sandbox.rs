@@ -0,0 +1,327 @@
+//! Restrictions applied to the `wasmedge` / `wasmtime run` child processes.
+//!
+//! Everything is off by default, which keeps the historical behaviour:
+//! inherited environment and working directory, no preopened directories and
+//! no resource limits.
+//!
+//! - `WASM_SANDBOX_CLEAR_ENV=1` starts the runtime with only `PATH` set (the
+//!   `GATEWAY_*` envelope is passed with `--env`, not inherited).
+//! - `WASM_SANDBOX_WORKDIR` sets the child's working directory.
+//! - `WASM_PREOPEN_DIRS=host[:guest],...` adds `--dir` preopens; nothing is
+//!   preopened otherwise.
+//! - `WASM_RLIMIT_CPU_SECS`, `WASM_RLIMIT_AS_MB`, `WASM_RLIMIT_NOFILE` set
+//!   rlimits in the child (Unix).
+//! - `WASM_SANDBOX_NO_NEW_PRIVS=1` sets `PR_SET_NO_NEW_PRIVS` (Linux).
+//! - `WASM_SANDBOX_SECCOMP=1` installs a seccomp filter failing a denylist of
+//!   host-administration syscalls with `EPERM` (Linux x86_64 / aarch64). It
+//!   implies no-new-privs.
+//! - `WASM_CGROUP_CPU` / `WASM_CGROUP_MEMORY_MB` move the child into a cgroup
+//!   with those limits (Linux, cgroup v2; see `cgroup`).
+//! - `WASM_CPU_AFFINITY=4-7` pins the child to those cores instead of the
+//!   gateway's own (`CPU_AFFINITY`) mask (Linux).
+
+use anyhow::{anyhow, Context, Result};
+use std::process::Command;
+
+use crate::affinity;
+use crate::cgroup::{self, WasmCgroup};
+
+#[derive(Debug, Default)]
+pub(crate) struct Sandbox {
+    clear_env: bool,
+    workdir: Option<String>,
+    /// `(host, guest)` pairs.
+    preopens: Vec<(String, String)>,
+    cpu_secs: Option<u64>,
+    address_space_bytes: Option<u64>,
+    nofile: Option<u64>,
+    no_new_privs: bool,
+    seccomp: bool,
+    cgroup: Option<&'static WasmCgroup>,
+    cpus: Option<Vec<usize>>,
+}
+
+impl Sandbox {
+    pub(crate) fn from_env() -> Result<Self> {
+        let flag = |var: &str| std::env::var(var).is_ok_and(|v| v == "1");
+        let number = |var: &str| -> Result<Option<u64>> {
+            match std::env::var(var) {
+                Ok(v) if v.is_empty() => Ok(None),
+                Ok(v) => Ok(Some(
+                    v.parse::<u64>()
+                        .with_context(|| format!("invalid {var}={v}"))?,
+                )),
+                Err(_) => Ok(None),
+            }
+        };
+        let preopens = std::env::var("WASM_PREOPEN_DIRS")
+            .map(|v| {
+                v.split(',')
+                    .map(str::trim)
+                    .filter(|e| !e.is_empty())
+                    .map(|entry| match entry.split_once(':') {
+                        Some((host, guest)) => (host.to_string(), guest.to_string()),
+                        None => (entry.to_string(), entry.to_string()),
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        let sandbox = Sandbox {
+            clear_env: flag("WASM_SANDBOX_CLEAR_ENV"),
+            workdir: std::env::var("WASM_SANDBOX_WORKDIR")
+                .ok()
+                .filter(|d| !d.is_empty()),
+            preopens,
+            cpu_secs: number("WASM_RLIMIT_CPU_SECS")?,
+            address_space_bytes: number("WASM_RLIMIT_AS_MB")?.map(|mb| mb * 1024 * 1024),
+            nofile: number("WASM_RLIMIT_NOFILE")?,
+            no_new_privs: flag("WASM_SANDBOX_NO_NEW_PRIVS"),
+            seccomp: flag("WASM_SANDBOX_SECCOMP"),
+            cgroup: cgroup::from_env()?,
+            cpus: affinity::cpus_from_env("WASM_CPU_AFFINITY")?,
+        };
+
+        let wants_rlimits = sandbox.cpu_secs.is_some()
+            || sandbox.address_space_bytes.is_some()
+            || sandbox.nofile.is_some();
+        if wants_rlimits && !cfg!(unix) {
+            return Err(anyhow!("WASM_RLIMIT_* is only supported on Unix"));
+        }
+        if sandbox.no_new_privs && !cfg!(target_os = "linux") {
+            return Err(anyhow!(
+                "WASM_SANDBOX_NO_NEW_PRIVS is only supported on Linux"
+            ));
+        }
+        if sandbox.seccomp
+            && !cfg!(all(
+                target_os = "linux",
+                any(target_arch = "x86_64", target_arch = "aarch64")
+            ))
+        {
+            return Err(anyhow!(
+                "WASM_SANDBOX_SECCOMP is only supported on Linux x86_64 and aarch64"
+            ));
+        }
+        #[cfg(target_os = "linux")]
+        if let Some(cpus) = sandbox.cpus.as_deref() {
+            affinity::cpu_set(cpus).context("invalid WASM_CPU_AFFINITY")?;
+        }
+        if let Some(dir) = sandbox.workdir.as_deref() {
+            if !std::path::Path::new(dir).is_dir() {
+                return Err(anyhow!("WASM_SANDBOX_WORKDIR={dir} is not a directory"));
+            }
+        }
+        if sandbox.is_enabled() {
+            eprintln!("[wasm-host] wasm subprocess sandbox: {sandbox:?}");
+        }
+        Ok(sandbox)
+    }
+
+    fn is_enabled(&self) -> bool {
+        self.clear_env
+            || self.workdir.is_some()
+            || !self.preopens.is_empty()
+            || self.cpu_secs.is_some()
+            || self.address_space_bytes.is_some()
+            || self.nofile.is_some()
+            || self.no_new_privs
+            || self.seccomp
+            || self.cgroup.is_some()
+            || self.cpus.is_some()
+    }
+
+    /// `--dir` arguments for `runtime`, placed before the module path.
+    pub(crate) fn preopen_args(&self, runtime: &str) -> Vec<String> {
+        self.preopens
+            .iter()
+            .flat_map(|(host, guest)| {
+                let spec = match runtime {
+                    "wasmedge" => format!("{guest}:{host}"),
+                    _ => format!("{host}::{guest}"),
+                };
+                ["--dir".to_string(), spec]
+            })
+            .collect()
+    }
+
+    /// Applies the environment, working directory and (on Unix) the
+    /// post-fork restrictions to `cmd`.
+    pub(crate) fn apply(&self, cmd: &mut Command) {
+        if self.clear_env {
+            let path = std::env::var_os("PATH");
+            cmd.env_clear();
+            if let Some(path) = path {
+                cmd.env("PATH", path);
+            }
+        }
+        if let Some(dir) = self.workdir.as_deref() {
+            cmd.current_dir(dir);
+        }
+        #[cfg(unix)]
+        self.apply_pre_exec(cmd);
+    }
+
+    #[cfg(unix)]
+    fn apply_pre_exec(&self, cmd: &mut Command) {
+        use std::os::unix::process::CommandExt;
+
+        let limits: Vec<(_, u64)> = [
+            (libc::RLIMIT_CPU, self.cpu_secs),
+            (libc::RLIMIT_AS, self.address_space_bytes),
+            (libc::RLIMIT_NOFILE, self.nofile),
+        ]
+        .into_iter()
+        .filter_map(|(resource, value)| value.map(|v| (resource, v)))
+        .collect();
+        let no_new_privs = self.no_new_privs || self.seccomp;
+        #[cfg(all(
+            target_os = "linux",
+            any(target_arch = "x86_64", target_arch = "aarch64")
+        ))]
+        let filter = self.seccomp.then(seccomp::denylist_filter);
+        let cgroup_procs = self.cgroup.map(WasmCgroup::procs_fd);
+        #[cfg(target_os = "linux")]
+        let cpu_set = self
+            .cpus
+            .as_deref()
+            .and_then(|cpus| affinity::cpu_set(cpus).ok());
+        if limits.is_empty() && !no_new_privs && cgroup_procs.is_none() && self.cpus.is_none() {
+            return;
+        }
+
+        // Runs between fork and exec: only async-signal-safe calls, and
+        // everything it needs is allocated beforehand.
+        let hook = move || -> std::io::Result<()> {
+            if let Some(fd) = cgroup_procs {
+                if unsafe { libc::write(fd, b"0".as_ptr().cast(), 1) } != 1 {
+                    return Err(std::io::Error::last_os_error());
+                }
+            }
+            for (resource, value) in &limits {
+                let limit = libc::rlimit {
+                    rlim_cur: *value as libc::rlim_t,
+                    rlim_max: *value as libc::rlim_t,
+                };
+                if unsafe { libc::setrlimit(*resource, &limit) } != 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+            }
+            #[cfg(target_os = "linux")]
+            {
+                if let Some(set) = cpu_set.as_ref() {
+                    let size = std::mem::size_of::<libc::cpu_set_t>();
+                    if unsafe { libc::sched_setaffinity(0, size, set) } != 0 {
+                        return Err(std::io::Error::last_os_error());
+                    }
+                }
+                if no_new_privs
+                    && unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0
+                {
+                    return Err(std::io::Error::last_os_error());
+                }
+                #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+                if let Some(filter) = filter.as_ref() {
+                    seccomp::install(filter)?;
+                }
+            }
+            Ok(())
+        };
+        unsafe {
+            cmd.pre_exec(hook);
+        }
+    }
+}
+
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+mod seccomp {
+    const RET_ALLOW: u32 = 0x7fff_0000;
+    const RET_ERRNO: u32 = 0x0005_0000;
+    const RET_KILL_PROCESS: u32 = 0x8000_0000;
+    const LD_W_ABS: u16 = 0x20;
+    const JEQ_K: u16 = 0x15;
+    const RET_K: u16 = 0x06;
+    /// Offsets into `struct seccomp_data`.
+    const NR_OFFSET: u32 = 0;
+    const ARCH_OFFSET: u32 = 4;
+
+    #[cfg(target_arch = "x86_64")]
+    const AUDIT_ARCH: u32 = 0xC000_003E;
+    #[cfg(target_arch = "aarch64")]
+    const AUDIT_ARCH: u32 = 0xC000_00B7;
+
+    /// Syscalls a wasm runtime never needs but an escaped guest could abuse.
+    const DENIED: &[libc::c_long] = &[
+        libc::SYS_ptrace,
+        libc::SYS_process_vm_readv,
+        libc::SYS_process_vm_writev,
+        libc::SYS_mount,
+        libc::SYS_umount2,
+        libc::SYS_pivot_root,
+        libc::SYS_chroot,
+        libc::SYS_unshare,
+        libc::SYS_setns,
+        libc::SYS_reboot,
+        libc::SYS_kexec_load,
+        libc::SYS_init_module,
+        libc::SYS_finit_module,
+        libc::SYS_delete_module,
+        libc::SYS_bpf,
+        libc::SYS_perf_event_open,
+        libc::SYS_keyctl,
+        libc::SYS_add_key,
+        libc::SYS_request_key,
+        libc::SYS_userfaultfd,
+        libc::SYS_swapon,
+        libc::SYS_swapoff,
+        libc::SYS_acct,
+    ];
+
+    fn stmt(code: u16, k: u32) -> libc::sock_filter {
+        libc::sock_filter {
+            code,
+            jt: 0,
+            jf: 0,
+            k,
+        }
+    }
+
+    fn jump(code: u16, k: u32, jt: u8, jf: u8) -> libc::sock_filter {
+        libc::sock_filter { code, jt, jf, k }
+    }
+
+    pub(super) fn denylist_filter() -> Vec<libc::sock_filter> {
+        let mut filter = vec![
+            stmt(LD_W_ABS, ARCH_OFFSET),
+            jump(JEQ_K, AUDIT_ARCH, 1, 0),
+            stmt(RET_K, RET_KILL_PROCESS),
+            stmt(LD_W_ABS, NR_OFFSET),
+        ];
+        for nr in DENIED {
+            filter.push(jump(JEQ_K, *nr as u32, 0, 1));
+            filter.push(stmt(RET_K, RET_ERRNO | libc::EPERM as u32));
+        }
+        filter.push(stmt(RET_K, RET_ALLOW));
+        filter
+    }
+
+    pub(super) fn install(filter: &[libc::sock_filter]) -> std::io::Result<()> {
+        let prog = libc::sock_fprog {
+            len: filter.len() as libc::c_ushort,
+            filter: filter.as_ptr() as *mut libc::sock_filter,
+        };
+        let rc = unsafe {
+            libc::prctl(
+                libc::PR_SET_SECCOMP,
+                libc::SECCOMP_MODE_FILTER,
+                &prog as *const libc::sock_fprog,
+            )
+        };
+        if rc != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,446 @@
+//! Per-route JSON Schema validation of request bodies.
+//!
+//! Supports the commonly used subset of JSON Schema: `type` (single or list),
+//! `enum`, `const`, `required`, `properties`, `additionalProperties` (boolean or
+//! schema), `items`, `minItems`/`maxItems`, `minLength`/`maxLength`,
+//! `minimum`/`maximum`. Other keywords (e.g. `pattern`, `$ref`) are ignored.
+
+use anyhow::{anyhow, Context, Result};
+use serde_json::Value;
+
+#[derive(Debug)]
+pub(crate) struct SchemaRoute {
+    pub(crate) prefix: String,
+    pub(crate) schema: Value,
+}
+
+#[derive(Debug)]
+pub(crate) struct Violation {
+    pub(crate) path: String,
+    pub(crate) message: String,
+}
+
+/// Parses `SCHEMA_ROUTES` (`/prefix=schema.json,/other=other.json`) and loads
+/// every schema file. Routes are returned longest prefix first.
+pub(crate) fn load_routes(spec: &str) -> Result<Vec<SchemaRoute>> {
+    let mut routes = Vec::new();
+    for entry in spec.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        let (prefix, path) = entry.split_once('=').ok_or_else(|| {
+            anyhow!("invalid SCHEMA_ROUTES entry {entry} (expected /prefix=file)")
+        })?;
+        let raw = std::fs::read(path).with_context(|| format!("read schema {path}"))?;
+        let schema: Value =
+            serde_json::from_slice(&raw).with_context(|| format!("parse schema {path}"))?;
+        routes.push(SchemaRoute {
+            prefix: prefix.to_string(),
+            schema,
+        });
+    }
+    routes.sort_by_key(|r| std::cmp::Reverse(r.prefix.len()));
+    Ok(routes)
+}
+
+pub(crate) fn route_for<'a>(routes: &'a [SchemaRoute], path: &str) -> Option<&'a SchemaRoute> {
+    routes.iter().find(|r| path.starts_with(&r.prefix))
+}
+
+/// Validates a raw body. A body that is not JSON yields a single violation at `/`.
+pub(crate) fn validate_body(schema: &Value, body: &[u8]) -> Vec<Violation> {
+    match serde_json::from_slice::<Value>(body) {
+        Ok(value) => {
+            let mut out = Vec::new();
+            validate(schema, &value, "", &mut out);
+            out
+        }
+        Err(e) => vec![Violation {
+            path: "/".to_string(),
+            message: format!("body is not valid JSON: {e}"),
+        }],
+    }
+}
+
+fn validate(schema: &Value, value: &Value, path: &str, out: &mut Vec<Violation>) {
+    let Some(schema) = schema.as_object() else {
+        if schema == &Value::Bool(false) {
+            push(out, path, "no value is allowed here".to_string());
+        }
+        return;
+    };
+
+    if let Some(ty) = schema.get("type") {
+        let allowed: Vec<&str> = match ty {
+            Value::String(s) => vec![s.as_str()],
+            Value::Array(list) => list.iter().filter_map(Value::as_str).collect(),
+            _ => Vec::new(),
+        };
+        if !allowed.is_empty() && !allowed.iter().any(|t| type_matches(t, value)) {
+            push(
+                out,
+                path,
+                format!(
+                    "expected type {}, got {}",
+                    allowed.join("|"),
+                    type_name(value)
+                ),
+            );
+            return;
+        }
+    }
+
+    if let Some(Value::Array(options)) = schema.get("enum") {
+        if !options.contains(value) {
+            push(
+                out,
+                path,
+                "value is not one of the allowed enum values".to_string(),
+            );
+        }
+    }
+    if let Some(expected) = schema.get("const") {
+        if expected != value {
+            push(out, path, format!("value must equal {expected}"));
+        }
+    }
+
+    match value {
+        Value::Object(map) => {
+            if let Some(Value::Array(required)) = schema.get("required") {
+                for key in required.iter().filter_map(Value::as_str) {
+                    if !map.contains_key(key) {
+                        push(out, path, format!("missing required property {key:?}"));
+                    }
+                }
+            }
+            let props = schema.get("properties").and_then(Value::as_object);
+            for (key, child) in map {
+                let child_path = format!("{path}/{key}");
+                match props.and_then(|p| p.get(key)) {
+                    Some(child_schema) => validate(child_schema, child, &child_path, out),
+                    None => match schema.get("additionalProperties") {
+                        Some(Value::Bool(false)) => push(
+                            out,
+                            &child_path,
+                            "additional property not allowed".to_string(),
+                        ),
+                        Some(extra @ Value::Object(_)) => validate(extra, child, &child_path, out),
+                        _ => {}
+                    },
+                }
+            }
+        }
+        Value::Array(items) => {
+            check_bound(schema, "minItems", items.len(), |n, b| n >= b, path, out);
+            check_bound(schema, "maxItems", items.len(), |n, b| n <= b, path, out);
+            if let Some(item_schema) = schema.get("items") {
+                for (i, item) in items.iter().enumerate() {
+                    validate(item_schema, item, &format!("{path}/{i}"), out);
+                }
+            }
+        }
+        Value::String(s) => {
+            let len = s.chars().count();
+            check_bound(schema, "minLength", len, |n, b| n >= b, path, out);
+            check_bound(schema, "maxLength", len, |n, b| n <= b, path, out);
+        }
+        Value::Number(n) => {
+            let n = n.as_f64().unwrap_or(f64::NAN);
+            if let Some(min) = schema.get("minimum").and_then(Value::as_f64) {
+                if n < min {
+                    push(out, path, format!("value {n} is below minimum {min}"));
+                }
+            }
+            if let Some(max) = schema.get("maximum").and_then(Value::as_f64) {
+                if n > max {
+                    push(out, path, format!("value {n} is above maximum {max}"));
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+fn check_bound(
+    schema: &serde_json::Map<String, Value>,
+    keyword: &str,
+    actual: usize,
+    ok: impl Fn(usize, usize) -> bool,
+    path: &str,
+    out: &mut Vec<Violation>,
+) {
+    if let Some(bound) = schema.get(keyword).and_then(Value::as_u64) {
+        if !ok(actual, bound as usize) {
+            push(
+                out,
+                path,
+                format!("{keyword} {bound} violated (got {actual})"),
+            );
+        }
+    }
+}
+
+fn type_matches(ty: &str, value: &Value) -> bool {
+    match ty {
+        "integer" => value.as_i64().is_some() || value.as_u64().is_some(),
+        "number" => value.is_number(),
+        other => type_name(value) == other,
+    }
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+fn push(out: &mut Vec<Violation>, path: &str, message: String) {
+    let path = if path.is_empty() { "/" } else { path };
+    out.push(Violation {
+        path: path.to_string(),
+        message,
+    });
+}
+
+/// 422 response body listing every violation.
+pub(crate) fn violations_json(violations: &[Violation]) -> String {
+    let details: Vec<Value> = violations
+        .iter()
+        .map(|v| serde_json::json!({ "path": v.path, "message": v.message }))
+        .collect();
+    serde_json::json!({ "error": "request body failed schema validation", "details": details })
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn order_schema() -> Value {
+        json!({
+            "type": "object",
+            "required": ["id", "status", "customer"],
+            "additionalProperties": false,
+            "properties": {
+                "id": { "type": "integer", "minimum": 1 },
+                "status": { "enum": ["open", "paid"] },
+                "note": { "type": ["string", "null"], "maxLength": 8 },
+                "customer": {
+                    "type": "object",
+                    "required": ["email"],
+                    "properties": { "email": { "type": "string", "minLength": 3 } },
+                    "additionalProperties": { "type": "string" }
+                },
+                "lines": {
+                    "type": "array",
+                    "minItems": 1,
+                    "maxItems": 3,
+                    "items": { "type": "object", "required": ["sku"] }
+                }
+            }
+        })
+    }
+
+    /// `(path, message)` of every violation of `body`.
+    fn violations(schema: &Value, body: &str) -> Vec<(String, String)> {
+        validate_body(schema, body.as_bytes())
+            .into_iter()
+            .map(|v| (v.path, v.message))
+            .collect()
+    }
+
+    fn paths(schema: &Value, body: &str) -> Vec<String> {
+        violations(schema, body)
+            .into_iter()
+            .map(|(p, _)| p)
+            .collect()
+    }
+
+    #[test]
+    fn accepts_a_conforming_body() {
+        let body = r#"{"id":7,"status":"paid","note":null,
+            "customer":{"email":"a@b","tier":"gold"},"lines":[{"sku":"x"}]}"#;
+        assert_eq!(violations(&order_schema(), body), []);
+    }
+
+    #[test]
+    fn checks_types() {
+        let schema = order_schema();
+        assert_eq!(
+            violations(&schema, "[]"),
+            [(
+                "/".to_string(),
+                "expected type object, got array".to_string()
+            )]
+        );
+        let body = r#"{"id":1.5,"status":"open","note":3,"customer":{"email":"a@b"}}"#;
+        assert_eq!(
+            violations(&schema, body),
+            [
+                (
+                    "/id".to_string(),
+                    "expected type integer, got number".to_string()
+                ),
+                (
+                    "/note".to_string(),
+                    "expected type string|null, got number".to_string()
+                ),
+            ]
+        );
+        let body = r#"{"id":-1,"status":"open","customer":{"email":"a@b"}}"#;
+        assert_eq!(
+            violations(&schema, body),
+            [("/id".to_string(), "value -1 is below minimum 1".to_string())]
+        );
+    }
+
+    #[test]
+    fn checks_required_and_additional_properties() {
+        let schema = order_schema();
+        let missing = violations(&schema, r#"{"id":1}"#);
+        assert_eq!(
+            missing,
+            [
+                (
+                    "/".to_string(),
+                    r#"missing required property "status""#.to_string()
+                ),
+                (
+                    "/".to_string(),
+                    r#"missing required property "customer""#.to_string()
+                ),
+            ]
+        );
+        let body = r#"{"id":1,"status":"open","customer":{"email":"a@b"},"coupon":"x"}"#;
+        assert_eq!(
+            violations(&schema, body),
+            [(
+                "/coupon".to_string(),
+                "additional property not allowed".to_string()
+            )]
+        );
+    }
+
+    #[test]
+    fn checks_enums_and_const() {
+        let schema = order_schema();
+        let body = r#"{"id":1,"status":"refunded","customer":{"email":"a@b"}}"#;
+        assert_eq!(
+            violations(&schema, body),
+            [(
+                "/status".to_string(),
+                "value is not one of the allowed enum values".to_string()
+            )]
+        );
+        let schema = json!({ "const": { "v": 2 } });
+        assert_eq!(violations(&schema, r#"{"v":2}"#), []);
+        assert_eq!(paths(&schema, r#"{"v":3}"#), ["/"]);
+        assert_eq!(paths(&json!(false), "1"), ["/"]);
+        assert_eq!(paths(&json!(true), "1"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn checks_nested_objects_and_arrays() {
+        let schema = order_schema();
+        let body = r#"{"id":1,"status":"open",
+            "customer":{"email":"ab","tier":5},
+            "lines":[{"sku":"a"},{"qty":2},{"sku":"c"},{"sku":"d"}]}"#;
+        assert_eq!(
+            violations(&schema, body),
+            [
+                (
+                    "/customer/email".to_string(),
+                    "minLength 3 violated (got 2)".to_string()
+                ),
+                (
+                    "/customer/tier".to_string(),
+                    "expected type string, got number".to_string()
+                ),
+                (
+                    "/lines".to_string(),
+                    "maxItems 3 violated (got 4)".to_string()
+                ),
+                (
+                    "/lines/1".to_string(),
+                    r#"missing required property "sku""#.to_string()
+                ),
+            ]
+        );
+        let body = r#"{"id":1,"status":"open","customer":{"email":"a@b"},"lines":[]}"#;
+        assert_eq!(paths(&schema, body), ["/lines"]);
+    }
+
+    #[test]
+    fn malformed_and_oversized_bodies_are_violations() {
+        let schema = order_schema();
+        for body in ["", "{", r#"{"id":1,}"#, "\u{feff}{}", "{} {}"] {
+            let found = violations(&schema, body);
+            assert_eq!(found.len(), 1, "{body:?}");
+            assert_eq!(found[0].0, "/");
+            assert!(found[0].1.starts_with("body is not valid JSON"), "{body:?}");
+        }
+        assert_eq!(paths(&schema, "\u{0}\u{1}binary"), ["/"]);
+
+        // Nesting past the parser's recursion limit is rejected, not
+        // recursed into.
+        let deep = format!("{}{}", "[".repeat(10_000), "]".repeat(10_000));
+        assert_eq!(paths(&json!({ "items": {} }), &deep), ["/"]);
+
+        let note = "x".repeat(1 << 20);
+        let body =
+            format!(r#"{{"id":1,"status":"open","customer":{{"email":"a@b"}},"note":"{note}"}}"#);
+        assert_eq!(
+            violations(&schema, &body),
+            [(
+                "/note".to_string(),
+                format!("maxLength 8 violated (got {})", 1 << 20)
+            )]
+        );
+    }
+
+    #[test]
+    fn routes_are_matched_longest_prefix_first() {
+        let dir = std::env::temp_dir().join(format!("schema-routes-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let write = |name: &str, schema: Value| {
+            let path = dir.join(name);
+            std::fs::write(&path, schema.to_string()).unwrap();
+            path.display().to_string()
+        };
+        let api = write("api.json", json!({ "type": "object" }));
+        let orders = write("orders.json", order_schema());
+        let routes = load_routes(&format!(" /api={api}, /api/orders={orders} ,")).unwrap();
+        assert_eq!(
+            routes.iter().map(|r| r.prefix.as_str()).collect::<Vec<_>>(),
+            ["/api/orders", "/api"]
+        );
+        assert_eq!(
+            route_for(&routes, "/api/orders/7").unwrap().prefix,
+            "/api/orders"
+        );
+        assert_eq!(route_for(&routes, "/api/users").unwrap().prefix, "/api");
+        assert!(route_for(&routes, "/health").is_none());
+
+        assert!(load_routes("/api").is_err());
+        assert!(load_routes(&format!("/x={}", dir.join("missing.json").display())).is_err());
+        let bad = dir.join("bad.json");
+        std::fs::write(&bad, "{").unwrap();
+        assert!(load_routes(&format!("/x={}", bad.display())).is_err());
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn violations_are_reported_as_json() {
+        let found = validate_body(&order_schema(), b"[]");
+        let json: Value = serde_json::from_str(&violations_json(&found)).unwrap();
+        assert_eq!(json["error"], "request body failed schema validation");
+        assert_eq!(
+            json["details"],
+            json!([{ "path": "/", "message": "expected type object, got array" }])
+        );
+    }
+}
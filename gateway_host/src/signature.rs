@@ -0,0 +1,257 @@
+//! Inbound HMAC-SHA256 request signatures (webhook style).
+//!
+//! The client sends `X-Signature-Timestamp: <unix seconds>` and
+//! `X-Signature: sha256=<hex>` where the MAC covers
+//! `"{timestamp}\n{METHOD}\n{path}\n"` followed by the raw body, where
+//! `path` is the target the client sent: `NORMALIZE` and access-rule
+//! rewrites only change what the gateway routes on.
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::{normalize, RequestLine};
+
+type HmacSha256 = Hmac<Sha256>;
+
+#[derive(Debug)]
+pub(crate) struct SignatureConfig {
+    pub(crate) secret: crate::secrets::Secret,
+    pub(crate) max_skew_secs: u64,
+    /// Path prefixes that require a signature; empty means every proxied request.
+    pub(crate) routes: Vec<String>,
+}
+
+impl SignatureConfig {
+    pub(crate) fn applies_to(&self, path: &str) -> bool {
+        self.routes.is_empty()
+            || self
+                .routes
+                .iter()
+                .any(|p| normalize::prefix_matches(p, path))
+    }
+}
+
+/// Returns `Err(reason)` when the request must be rejected with 401.
+pub(crate) fn verify(
+    config: &SignatureConfig,
+    req: &RequestLine,
+    body: &[u8],
+) -> Result<(), &'static str> {
+    let timestamp = req
+        .header("X-Signature-Timestamp")
+        .ok_or("missing X-Signature-Timestamp")?;
+    let ts = timestamp
+        .parse::<u64>()
+        .map_err(|_| "invalid X-Signature-Timestamp")?;
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    if now.abs_diff(ts) > config.max_skew_secs {
+        return Err("signature timestamp outside allowed clock skew");
+    }
+
+    let presented = req.header("X-Signature").ok_or("missing X-Signature")?;
+    let presented = presented.strip_prefix("sha256=").unwrap_or(presented);
+    let presented = hex::decode(presented).map_err(|_| "malformed X-Signature")?;
+
+    let mut mac = HmacSha256::new_from_slice(config.secret.reveal().as_bytes())
+        .map_err(|_| "invalid HMAC secret")?;
+    mac.update(format!("{timestamp}\n{}\n{}\n", req.method, req.target).as_bytes());
+    mac.update(body);
+    // verify_slice compares in constant time
+    mac.verify_slice(&presented)
+        .map_err(|_| "signature mismatch")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::normalize::Normalizer;
+    use gateway_core::http::parse_request_head;
+
+    const SECRET: &str = "whsec";
+
+    fn config() -> SignatureConfig {
+        SignatureConfig {
+            secret: crate::secrets::Secret::new("HMAC_SECRET", SECRET),
+            max_skew_secs: 300,
+            routes: Vec::new(),
+        }
+    }
+
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    /// The hex MAC of a request signed at `ts`.
+    fn sign(ts: u64, method: &str, path: &str, body: &[u8]) -> String {
+        let mut mac = HmacSha256::new_from_slice(SECRET.as_bytes()).unwrap();
+        mac.update(format!("{ts}\n{method}\n{path}\n").as_bytes());
+        mac.update(body);
+        hex::encode(mac.finalize().into_bytes())
+    }
+
+    fn request(method: &str, target: &str, headers: &str) -> RequestLine {
+        parse_request_head(format!("{method} {target} HTTP/1.1\r\n{headers}").as_bytes()).unwrap()
+    }
+
+    /// A request for `target`, signed over `signed_path`.
+    fn signed(target: &str, signed_path: &str, body: &[u8]) -> RequestLine {
+        let ts = now();
+        let sig = sign(ts, "POST", signed_path, body);
+        request(
+            "POST",
+            target,
+            &format!("X-Signature-Timestamp: {ts}\r\nX-Signature: sha256={sig}\r\n"),
+        )
+    }
+
+    #[test]
+    fn accepts_a_valid_mac_and_rejects_tampering() {
+        let req = signed("/hooks/github", "/hooks/github", b"{}");
+        assert_eq!(verify(&config(), &req, b"{}"), Ok(()));
+        assert_eq!(verify(&config(), &req, b"{ }"), Err("signature mismatch"));
+
+        // The `sha256=` prefix is optional.
+        let ts = now();
+        let sig = sign(ts, "POST", "/hooks/github", b"{}");
+        let bare = request(
+            "POST",
+            "/hooks/github",
+            &format!("X-Signature-Timestamp: {ts}\r\nX-Signature: {sig}\r\n"),
+        );
+        assert_eq!(verify(&config(), &bare, b"{}"), Ok(()));
+
+        let headers = format!("X-Signature-Timestamp: {ts}\r\nX-Signature: sha256={sig}\r\n");
+        let put = request("PUT", "/hooks/github", &headers);
+        assert_eq!(verify(&config(), &put, b"{}"), Err("signature mismatch"));
+        let other_path = request("POST", "/hooks/gitlab", &headers);
+        assert_eq!(
+            verify(&config(), &other_path, b"{}"),
+            Err("signature mismatch")
+        );
+        let query = request("POST", "/hooks/github?replay=1", &headers);
+        assert_eq!(verify(&config(), &query, b"{}"), Err("signature mismatch"));
+
+        let mut other_secret = config();
+        other_secret.secret = crate::secrets::Secret::new("HMAC_SECRET", "other");
+        assert_eq!(
+            verify(&other_secret, &req, b"{}"),
+            Err("signature mismatch")
+        );
+    }
+
+    #[test]
+    fn rejects_timestamps_outside_the_skew_either_way() {
+        let at = |ts: u64| {
+            let sig = sign(ts, "POST", "/hooks", b"");
+            let req = request(
+                "POST",
+                "/hooks",
+                &format!("X-Signature-Timestamp: {ts}\r\nX-Signature: sha256={sig}\r\n"),
+            );
+            verify(&config(), &req, b"")
+        };
+        let skew = Err("signature timestamp outside allowed clock skew");
+        assert_eq!(at(now() - 250), Ok(()));
+        assert_eq!(at(now() + 250), Ok(()));
+        assert_eq!(at(now() - 400), skew);
+        assert_eq!(at(now() + 400), skew);
+        assert_eq!(at(0), skew);
+    }
+
+    #[test]
+    fn rejects_missing_and_malformed_headers() {
+        let ts = now();
+        let sig = sign(ts, "GET", "/", b"");
+        let check = |headers: &str| verify(&config(), &request("GET", "/", headers), b"");
+        assert_eq!(
+            check(&format!("X-Signature: sha256={sig}\r\n")),
+            Err("missing X-Signature-Timestamp")
+        );
+        assert_eq!(
+            check(&format!(
+                "X-Signature-Timestamp: yesterday\r\nX-Signature: sha256={sig}\r\n"
+            )),
+            Err("invalid X-Signature-Timestamp")
+        );
+        assert_eq!(
+            check(&format!("X-Signature-Timestamp: {ts}\r\n")),
+            Err("missing X-Signature")
+        );
+        assert_eq!(
+            check(&format!(
+                "X-Signature-Timestamp: {ts}\r\nX-Signature: sha256=not-hex\r\n"
+            )),
+            Err("malformed X-Signature")
+        );
+        assert_eq!(
+            check(&format!(
+                "X-Signature-Timestamp: {ts}\r\nX-Signature: sha256={}\r\n",
+                &sig[..32]
+            )),
+            Err("signature mismatch")
+        );
+    }
+
+    #[test]
+    fn routes_limit_which_paths_need_a_signature() {
+        assert!(config().applies_to("/anything"));
+        let scoped = SignatureConfig {
+            routes: vec!["/hooks/".to_string(), "/api/v1/events".to_string()],
+            ..config()
+        };
+        assert!(scoped.applies_to("/hooks/github"));
+        assert!(scoped.applies_to("/api/v1/events?id=1"));
+        assert!(scoped.applies_to("/hooks"));
+        assert!(scoped.applies_to("//hooks//github"));
+        assert!(scoped.applies_to("/api/v1/x/../events"));
+        assert!(!scoped.applies_to("/hooksx"));
+        assert!(!scoped.applies_to("/api/v1/eventsx"));
+        assert!(!scoped.applies_to("/api/v1/users"));
+        assert!(!scoped.applies_to("/"));
+    }
+
+    #[test]
+    fn normalization_does_not_change_the_signed_target() {
+        let target = "/hooks//github/./%7Eci?b=2&a=1";
+        let mut req = signed(target, target, b"{}");
+        Normalizer { sort_query: true }.apply(&mut req.path, &mut req.headers);
+        assert_eq!(req.path, "/hooks/github/~ci?a=1&b=2");
+        assert_eq!(verify(&config(), &req, b"{}"), Ok(()));
+
+        let normalized_only = signed(target, "/hooks/github/~ci?a=1&b=2", b"{}");
+        assert_eq!(
+            verify(&config(), &normalized_only, b"{}"),
+            Err("signature mismatch")
+        );
+    }
+
+    #[test]
+    fn access_rule_rewrites_do_not_change_the_signed_target() {
+        let rules = crate::access_rules::parse("rewrite path=/v1/* to=/api/v1/*").unwrap();
+        let mut req = signed("/v1/hooks?id=7", "/v1/hooks?id=7", b"payload");
+        let header = |_: &str| None;
+        let outcome = rules.evaluate(
+            &crate::access_rules::Request {
+                method: &req.method,
+                target: &req.path,
+                header: &header,
+                ip: None,
+            },
+            &crate::store::SharedStore::Memory(Default::default()),
+        );
+        req.path = outcome.rewritten.unwrap();
+        assert_eq!(req.path, "/api/v1/hooks?id=7");
+        assert_eq!(verify(&config(), &req, b"payload"), Ok(()));
+        assert_eq!(
+            verify(&config(), &req, b"tampered"),
+            Err("signature mismatch")
+        );
+    }
+}
@@ -0,0 +1,224 @@
+//! Latency SLO tracking (`SLO_LATENCY_MS`), so an experiment can be read as
+//! "did the variant keep its objective" rather than as raw percentiles.
+//!
+//! A request is good when it got a non-5xx response within `SLO_LATENCY_MS`.
+//! The objective `SLO_TARGET` (percent, default 99) leaves an error budget of
+//! the other (100 - target)% of requests. `/stats` reports, under `slo`:
+//!
+//! - `window`: the last `SLO_WINDOW_SECS` (default 300, kept as one-second
+//!   buckets), with the burn rate: the bad share over the budgeted share, so
+//!   1 spends the budget exactly as fast as the objective allows.
+//! - `total`: since start, with the fraction of the error budget left
+//!   (negative once the objective is missed).
+//!
+//! `/metrics` counts `gateway_slo_requests_total{result="good|bad"}`.
+//! Requests on `LISTEN_INTERNAL` are not counted.
+
+use anyhow::{anyhow, Result};
+use once_cell::sync::OnceCell;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+const DEFAULT_TARGET_PERCENT: f64 = 99.0;
+const DEFAULT_WINDOW_SECS: u64 = 300;
+
+static SLO: OnceCell<Slo> = OnceCell::new();
+
+#[derive(Debug)]
+pub(crate) struct Slo {
+    threshold: Duration,
+    /// Fraction of requests that must be good.
+    target: f64,
+    started: Instant,
+    /// One bucket per second of the window, indexed by second modulo its
+    /// length.
+    window: Mutex<Vec<Bucket>>,
+    good: AtomicU64,
+    bad: AtomicU64,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+struct Bucket {
+    second: u64,
+    good: u64,
+    bad: u64,
+}
+
+/// Good and bad requests over some period.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) struct Counts {
+    pub(crate) good: u64,
+    pub(crate) bad: u64,
+}
+
+impl Slo {
+    fn new(threshold: Duration, target: f64, window_secs: u64) -> Self {
+        Slo {
+            threshold,
+            target,
+            started: Instant::now(),
+            window: Mutex::new(vec![Bucket::default(); window_secs as usize]),
+            good: AtomicU64::new(0),
+            bad: AtomicU64::new(0),
+        }
+    }
+
+    /// `status` 0 means the request got no response.
+    pub(crate) fn record(&self, status: u16, latency: Duration) {
+        self.record_at(self.started.elapsed().as_secs(), status, latency);
+    }
+
+    fn record_at(&self, second: u64, status: u16, latency: Duration) {
+        let good = (100..500).contains(&status) && latency <= self.threshold;
+        let counter = if good { &self.good } else { &self.bad };
+        counter.fetch_add(1, Ordering::Relaxed);
+
+        let mut window = self.window.lock().unwrap_or_else(|e| e.into_inner());
+        let len = window.len() as u64;
+        let bucket = &mut window[(second % len) as usize];
+        if bucket.second != second {
+            *bucket = Bucket {
+                second,
+                ..Bucket::default()
+            };
+        }
+        if good {
+            bucket.good += 1;
+        } else {
+            bucket.bad += 1;
+        }
+    }
+
+    pub(crate) fn total(&self) -> Counts {
+        Counts {
+            good: self.good.load(Ordering::Relaxed),
+            bad: self.bad.load(Ordering::Relaxed),
+        }
+    }
+
+    pub(crate) fn window(&self) -> Counts {
+        self.window_at(self.started.elapsed().as_secs())
+    }
+
+    fn window_at(&self, now: u64) -> Counts {
+        let window = self.window.lock().unwrap_or_else(|e| e.into_inner());
+        let oldest = (now + 1).saturating_sub(window.len() as u64);
+        window
+            .iter()
+            .filter(|b| b.second >= oldest && b.second <= now)
+            .fold(Counts::default(), |acc, b| Counts {
+                good: acc.good + b.good,
+                bad: acc.bad + b.bad,
+            })
+    }
+
+    /// Bad share of `counts` over the budgeted share; `None` without requests.
+    pub(crate) fn burn_rate(&self, counts: Counts) -> Option<f64> {
+        let total = counts.good + counts.bad;
+        (total > 0).then(|| counts.bad as f64 / total as f64 / (1.0 - self.target))
+    }
+
+    pub(crate) fn json(&self) -> serde_json::Value {
+        let (window, total) = (self.window(), self.total());
+        let compliance = |c: Counts| {
+            let requests = c.good + c.bad;
+            (requests > 0).then(|| c.good as f64 * 100.0 / requests as f64)
+        };
+        serde_json::json!({
+            "latency_ms": self.threshold.as_secs_f64() * 1000.0,
+            "target_percent": self.target * 100.0,
+            "window_s": self.window.lock().unwrap_or_else(|e| e.into_inner()).len(),
+            "window": {
+                "requests": window.good + window.bad,
+                "good": window.good,
+                "compliance_percent": compliance(window),
+                "burn_rate": self.burn_rate(window),
+            },
+            "total": {
+                "requests": total.good + total.bad,
+                "good": total.good,
+                "compliance_percent": compliance(total),
+                "error_budget_remaining": self.burn_rate(total).map(|burn| 1.0 - burn),
+            },
+        })
+    }
+}
+
+/// Reads `SLO_LATENCY_MS`, `SLO_TARGET` and `SLO_WINDOW_SECS`. Call once from
+/// `main`.
+pub(crate) fn init_from_env() -> Result<()> {
+    let var = |name: &str| {
+        std::env::var(name)
+            .ok()
+            .map(|v| v.trim().to_string())
+            .filter(|v| !v.is_empty())
+    };
+    let Some(latency) = var("SLO_LATENCY_MS") else {
+        return Ok(());
+    };
+    let threshold = latency
+        .parse::<f64>()
+        .ok()
+        .filter(|ms| ms.is_finite() && *ms > 0.0)
+        .map(|ms| Duration::from_secs_f64(ms / 1000.0))
+        .ok_or_else(|| anyhow!("invalid SLO_LATENCY_MS={latency}"))?;
+    let target = match var("SLO_TARGET") {
+        Some(v) => v
+            .trim_end_matches('%')
+            .parse::<f64>()
+            .ok()
+            .filter(|p| *p > 0.0 && *p < 100.0)
+            .ok_or_else(|| anyhow!("invalid SLO_TARGET={v} (percent, below 100)"))?,
+        None => DEFAULT_TARGET_PERCENT,
+    };
+    let window_secs = match var("SLO_WINDOW_SECS") {
+        Some(v) => v
+            .parse::<u64>()
+            .ok()
+            .filter(|&secs| secs > 0)
+            .ok_or_else(|| anyhow!("invalid SLO_WINDOW_SECS={v}"))?,
+        None => DEFAULT_WINDOW_SECS,
+    };
+    let slo = SLO.get_or_init(|| Slo::new(threshold, target / 100.0, window_secs));
+    eprintln!(
+        "[wasm-host] SLO: {}% of requests under {:?}, burn rate over {window_secs}s",
+        target, slo.threshold
+    );
+    Ok(())
+}
+
+/// The SLO, when `SLO_LATENCY_MS` is set.
+pub(crate) fn configured() -> Option<&'static Slo> {
+    SLO.get()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_good_requests_and_burns_budget() {
+        let slo = Slo::new(Duration::from_millis(50), 0.99, 10);
+        let fast = Duration::from_millis(10);
+        for _ in 0..97 {
+            slo.record_at(0, 200, fast);
+        }
+        slo.record_at(0, 404, fast);
+        slo.record_at(0, 200, Duration::from_millis(80));
+        slo.record_at(0, 503, fast);
+        slo.record_at(5, 0, fast);
+
+        assert_eq!(slo.total(), Counts { good: 98, bad: 3 });
+        let window = slo.window_at(5);
+        assert_eq!(window, Counts { good: 98, bad: 3 });
+        let burn = slo.burn_rate(window).unwrap();
+        assert!((burn - 3.0 / 101.0 / 0.01).abs() < 1e-9, "{burn}");
+
+        // Second 0 has left the 10 s window by second 10; its slot is reused.
+        assert_eq!(slo.window_at(10), Counts { good: 0, bad: 1 });
+        slo.record_at(10, 200, fast);
+        assert_eq!(slo.window_at(10), Counts { good: 1, bad: 1 });
+        assert_eq!(slo.burn_rate(Counts::default()), None);
+    }
+}
@@ -0,0 +1,256 @@
+//! TLS termination on `LISTEN_TLS`, with the certificate picked by SNI.
+//!
+//! `TLS_CERT_DIR` holds `<hostname>.pem` (certificate chain) / `<hostname>.key`
+//! pairs, plus an optional `default.pem` / `default.key` for clients that send
+//! no SNI or a name without a pair. `_.example.com.pem` covers
+//! `*.example.com` (one label deep). A name with no pair and no default fails
+//! the handshake with `unrecognized_name`.
+
+use anyhow::{anyhow, Context, Result};
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use rustls::{ServerConfig, ServerConnection, StreamOwned};
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+#[derive(Debug)]
+pub(crate) struct TlsTermination {
+    config: Arc<ServerConfig>,
+    /// Names with a certificate (`default` included), for the startup log.
+    pub(crate) names: Vec<String>,
+}
+
+impl TlsTermination {
+    /// `None` unless `LISTEN_TLS` is set, which then needs `TLS_CERT_DIR`.
+    pub(crate) fn from_env() -> Result<Option<Self>> {
+        if std::env::var("LISTEN_TLS").map_or(true, |v| v.is_empty()) {
+            return Ok(None);
+        }
+        let dir =
+            std::env::var("TLS_CERT_DIR").map_err(|_| anyhow!("LISTEN_TLS needs TLS_CERT_DIR"))?;
+        Self::from_dir(Path::new(&dir)).map(Some)
+    }
+
+    fn from_dir(dir: &Path) -> Result<Self> {
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let resolver = SniResolver::load(dir, &provider)?;
+        let mut names: Vec<String> = resolver.by_name.keys().cloned().collect();
+        names.sort();
+        if resolver.default.is_some() {
+            names.push("default".to_string());
+        }
+        let mut config = ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .context("TLS protocol versions")?
+            .with_no_client_auth()
+            .with_cert_resolver(Arc::new(resolver));
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+        Ok(TlsTermination {
+            config: Arc::new(config),
+            names,
+        })
+    }
+
+    /// Wraps an accepted connection; the handshake runs on the first read.
+    pub(crate) fn accept(&self, tcp: TcpStream) -> Result<ClientStream> {
+        let conn = ServerConnection::new(Arc::clone(&self.config)).context("start TLS session")?;
+        Ok(ClientStream::Tls(Box::new(StreamOwned::new(conn, tcp))))
+    }
+}
+
+/// Certificates by lowercase server name; wildcards keep their `*.`.
+struct SniResolver {
+    by_name: HashMap<String, Arc<CertifiedKey>>,
+    default: Option<Arc<CertifiedKey>>,
+}
+
+impl std::fmt::Debug for SniResolver {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SniResolver")
+            .field("names", &self.by_name.keys().collect::<Vec<_>>())
+            .field("default", &self.default.is_some())
+            .finish()
+    }
+}
+
+impl SniResolver {
+    fn load(dir: &Path, provider: &CryptoProvider) -> Result<Self> {
+        let mut by_name = HashMap::new();
+        let mut default = None;
+        let entries = std::fs::read_dir(dir)
+            .with_context(|| format!("read TLS_CERT_DIR={}", dir.display()))?;
+        for entry in entries {
+            let path = entry?.path();
+            let Some(name) = path
+                .file_name()
+                .and_then(|n| n.to_str())
+                .and_then(|n| n.strip_suffix(".pem"))
+            else {
+                continue;
+            };
+            let key_path = path.with_file_name(format!("{name}.key"));
+            let key = load_key(&path, &key_path, provider)?;
+            match name.to_ascii_lowercase() {
+                n if n == "default" => default = Some(key),
+                n => {
+                    let n = match n.strip_prefix("_.") {
+                        Some(parent) => format!("*.{parent}"),
+                        None => n,
+                    };
+                    by_name.insert(n, key);
+                }
+            }
+        }
+        if by_name.is_empty() && default.is_none() {
+            return Err(anyhow!(
+                "no <hostname>.pem / <hostname>.key pairs in TLS_CERT_DIR={}",
+                dir.display()
+            ));
+        }
+        Ok(SniResolver { by_name, default })
+    }
+
+    fn lookup(&self, server_name: Option<&str>) -> Option<Arc<CertifiedKey>> {
+        let found = server_name.and_then(|name| {
+            let name = name.trim_end_matches('.').to_ascii_lowercase();
+            self.by_name.get(&name).or_else(|| {
+                let (_, parent) = name.split_once('.')?;
+                self.by_name.get(&format!("*.{parent}"))
+            })
+        });
+        found.or(self.default.as_ref()).cloned()
+    }
+}
+
+impl ResolvesServerCert for SniResolver {
+    fn resolve(&self, hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        self.lookup(hello.server_name())
+    }
+}
+
+fn load_key(cert: &Path, key: &Path, provider: &CryptoProvider) -> Result<Arc<CertifiedKey>> {
+    let chain = CertificateDer::pem_file_iter(cert)
+        .and_then(|certs| certs.collect::<std::result::Result<Vec<_>, _>>())
+        .with_context(|| format!("read {}", cert.display()))?;
+    if chain.is_empty() {
+        return Err(anyhow!("no certificates in {}", cert.display()));
+    }
+    crate::secrets::check_permissions(key, true)?;
+    let der =
+        PrivateKeyDer::from_pem_file(key).with_context(|| format!("read {}", key.display()))?;
+    let signing_key = provider
+        .key_provider
+        .load_private_key(der)
+        .with_context(|| format!("unsupported private key {}", key.display()))?;
+    let certified = CertifiedKey::new(chain, signing_key);
+    certified
+        .keys_match()
+        .with_context(|| format!("{} does not match {}", key.display(), cert.display()))?;
+    Ok(Arc::new(certified))
+}
+
+/// An accepted client connection, plain or TLS. Mirrors the `TcpStream`
+/// methods the request path uses.
+pub(crate) enum ClientStream {
+    Plain(TcpStream),
+    Tls(Box<StreamOwned<ServerConnection, TcpStream>>),
+}
+
+impl ClientStream {
+    fn tcp(&self) -> &TcpStream {
+        match self {
+            ClientStream::Plain(tcp) => tcp,
+            ClientStream::Tls(tls) => &tls.sock,
+        }
+    }
+
+    pub(crate) fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.tcp().set_read_timeout(timeout)
+    }
+
+    pub(crate) fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.tcp().set_write_timeout(timeout)
+    }
+
+    pub(crate) fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.tcp().peer_addr()
+    }
+
+    /// Sends `close_notify` first on TLS, so clients see a clean end.
+    pub(crate) fn shutdown(&mut self, how: Shutdown) -> io::Result<()> {
+        if let ClientStream::Tls(tls) = self {
+            tls.conn.send_close_notify();
+            tls.flush().ok();
+        }
+        self.tcp().shutdown(how)
+    }
+}
+
+impl Read for ClientStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            ClientStream::Plain(tcp) => tcp.read(buf),
+            ClientStream::Tls(tls) => tls.read(buf),
+        }
+    }
+}
+
+impl Write for ClientStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            ClientStream::Plain(tcp) => tcp.write(buf),
+            ClientStream::Tls(tls) => tls.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            ClientStream::Plain(tcp) => tcp.flush(),
+            ClientStream::Tls(tls) => tls.flush(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_pair(dir: &Path, file: &str, san: &str) {
+        let key = rcgen::KeyPair::generate().unwrap();
+        let cert = rcgen::CertificateParams::new(vec![san.to_string()])
+            .unwrap()
+            .self_signed(&key)
+            .unwrap();
+        std::fs::write(dir.join(format!("{file}.pem")), cert.pem()).unwrap();
+        std::fs::write(dir.join(format!("{file}.key")), key.serialize_pem()).unwrap();
+    }
+
+    #[test]
+    fn picks_certificate_by_server_name() {
+        let dir = std::env::temp_dir().join(format!("wasm-host-tls-certs-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        write_pair(&dir, "api.example.com", "api.example.com");
+        write_pair(&dir, "_.apps.example.com", "*.apps.example.com");
+        let provider = rustls::crypto::ring::default_provider();
+
+        let resolver = SniResolver::load(&dir, &provider).unwrap();
+        let api = resolver.lookup(Some("API.example.com.")).unwrap();
+        let wildcard = resolver.lookup(Some("web.apps.example.com")).unwrap();
+        assert!(!Arc::ptr_eq(&api, &wildcard));
+        assert!(resolver.lookup(Some("a.b.apps.example.com")).is_none());
+        assert!(resolver.lookup(None).is_none());
+
+        write_pair(&dir, "default", "fallback");
+        let resolver = SniResolver::load(&dir, &provider).unwrap();
+        assert!(resolver.lookup(Some("other.test")).is_some());
+        assert!(resolver.lookup(None).is_some());
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -0,0 +1,765 @@
+//! Body transform backends. The gateway runs every workload result and proxied
+//! response body through one `Transform`, chosen by `TRANSFORM_BACKEND`
+//! (defaulting to `WASM_RUNTIME`), so the benchmark can swap the execution model
+//! without touching the request path.
+
+use anyhow::{anyhow, Context, Result};
+use std::io::Write;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use sha2::{Digest, Sha256};
+
+use crate::sandbox::Sandbox;
+use crate::workers::{WorkerPool, WorkerSettings};
+use crate::{
+    get_or_compile_embedded_wasmtime, parse_status_code_from_head, parse_upstream,
+    read_all_response, split_http_response, wasm_transform_cli, wasm_transform_wasmtime_embedded,
+    Envelope, Upstream, IO_TIMEOUT,
+};
+
+pub(crate) trait Transform: std::fmt::Debug + Send + Sync {
+    /// Backend name as accepted by `TRANSFORM_BACKEND`.
+    fn name(&self) -> &'static str;
+
+    fn transform(&self, input: &[u8], envelope: &Envelope) -> Result<Vec<u8>>;
+
+    /// Response header set to `true` when a failure policy bypassed this backend.
+    fn bypass_header(&self) -> &'static str {
+        "X-Transform-Bypassed"
+    }
+}
+
+impl<T: Transform + ?Sized> Transform for std::sync::Arc<T> {
+    fn name(&self) -> &'static str {
+        (**self).name()
+    }
+
+    fn transform(&self, input: &[u8], envelope: &Envelope) -> Result<Vec<u8>> {
+        (**self).transform(input, envelope)
+    }
+
+    fn bypass_header(&self) -> &'static str {
+        (**self).bypass_header()
+    }
+}
+
+/// Starts the first line of a wasm guest's response envelope (the guest's
+/// `envelope` module): `GATEWAY-ENVELOPE/1 <token>`, where the token is the
+/// request's `GATEWAY_ENVELOPE_TOKEN`, then `Name: value` lines up to an empty
+/// line, then the body. Upstream and client bodies never see the token, so a
+/// body the guest passed through is never taken for an envelope.
+const RESPONSE_ENVELOPE_MAGIC: &str = "GATEWAY-ENVELOPE/1";
+
+/// Moves the headers of a response envelope into `envelope` and returns the
+/// body. Framing headers are dropped since the gateway sets them itself. Only
+/// the wasm backends call this, on what their guest wrote.
+pub(crate) fn strip_response_envelope(output: Vec<u8>, envelope: &Envelope) -> Vec<u8> {
+    let Some(token) = envelope.get("ENVELOPE_TOKEN").filter(|t| !t.is_empty()) else {
+        return output;
+    };
+    let magic = format!("{RESPONSE_ENVELOPE_MAGIC} {token}\n");
+    let Some(rest) = output.strip_prefix(magic.as_bytes()) else {
+        return output;
+    };
+    let Some(split) = rest.windows(2).position(|w| w == b"\n\n") else {
+        return output;
+    };
+    let mut headers = envelope
+        .response_headers
+        .lock()
+        .unwrap_or_else(|e| e.into_inner());
+    for line in String::from_utf8_lossy(&rest[..split]).lines() {
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        let name = name.trim();
+        if ["content-length", "connection", "transfer-encoding"]
+            .iter()
+            .any(|h| name.eq_ignore_ascii_case(h))
+        {
+            continue;
+        }
+        headers.retain(|(k, _)| !k.eq_ignore_ascii_case(name));
+        headers.push((name.to_string(), value.trim().to_string()));
+    }
+    rest[split + 2..].to_vec()
+}
+
+/// `WASM_FAILURE_POLICY` for the wasm backends.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum FailurePolicy {
+    /// Fail the request (the default).
+    Error,
+    /// Serve the untransformed input.
+    Bypass,
+    /// Run once more on a fresh instance, then fail.
+    Retry,
+}
+
+impl FailurePolicy {
+    fn from_env() -> Result<Self> {
+        match std::env::var("WASM_FAILURE_POLICY").as_deref() {
+            Err(_) | Ok("") | Ok("error") => Ok(FailurePolicy::Error),
+            Ok("bypass") => Ok(FailurePolicy::Bypass),
+            Ok("retry") => Ok(FailurePolicy::Retry),
+            Ok(other) => Err(anyhow!(
+                "invalid WASM_FAILURE_POLICY={other} (expected: error|bypass|retry)"
+            )),
+        }
+    }
+}
+
+/// The configured backend name. Without `TRANSFORM_BACKEND`, setting
+/// `TRANSFORM_URL` selects `http`; otherwise the already validated
+/// `WASM_RUNTIME` is used.
+pub(crate) fn backend_from_env(wasm_runtime: &str) -> String {
+    std::env::var("TRANSFORM_BACKEND")
+        .ok()
+        .filter(|b| !b.is_empty())
+        .unwrap_or_else(|| {
+            if std::env::var("TRANSFORM_URL").is_ok_and(|u| !u.is_empty()) {
+                "http".to_string()
+            } else {
+                wasm_runtime.to_string()
+            }
+        })
+}
+
+/// `WASM_RUNTIME`, checked against `WASM_EXECUTION`: `embedded` means
+/// `wasmtime_embedded`, `subprocess` one of the CLI runtimes (default
+/// `wasmedge`).
+pub(crate) fn runtime_from_env() -> Result<String> {
+    let var = |name: &str| std::env::var(name).ok().filter(|v| !v.is_empty());
+    let runtime = match (var("WASM_EXECUTION").as_deref(), var("WASM_RUNTIME")) {
+        (None, runtime) => runtime.unwrap_or_else(|| "wasmedge".to_string()),
+        (Some("embedded"), None) => "wasmtime_embedded".to_string(),
+        (Some("subprocess"), None) => "wasmedge".to_string(),
+        (Some(execution @ ("embedded" | "subprocess")), Some(runtime)) => {
+            let embedded = runtime == "wasmtime_embedded";
+            if embedded != (execution == "embedded") {
+                return Err(anyhow!(
+                    "WASM_EXECUTION={execution} conflicts with WASM_RUNTIME={runtime}"
+                ));
+            }
+            runtime
+        }
+        (Some(other), _) => {
+            return Err(anyhow!(
+                "invalid WASM_EXECUTION={other} (expected: embedded|subprocess)"
+            ))
+        }
+    };
+    if !matches!(
+        runtime.as_str(),
+        "wasmedge" | "wasmtime" | "wasmtime_embedded"
+    ) {
+        return Err(anyhow!(
+            "invalid WASM_RUNTIME={runtime} (expected: wasmedge|wasmtime|wasmtime_embedded)"
+        ));
+    }
+    Ok(runtime)
+}
+
+/// With `WASM_EXECUTION=embedded`, how many calls a warm reactor instance
+/// serves (`WASM_INSTANCE_MAX_CALLS`, default 1000) before a new one replaces
+/// it, bounding what a guest that never frees can grow to.
+fn instance_reuse_from_env() -> Result<Option<u64>> {
+    if std::env::var("WASM_EXECUTION").as_deref() != Ok("embedded") {
+        return Ok(None);
+    }
+    match std::env::var("WASM_INSTANCE_MAX_CALLS") {
+        Ok(v) if !v.is_empty() => v
+            .parse::<u64>()
+            .ok()
+            .filter(|&n| n > 0)
+            .map(Some)
+            .ok_or_else(|| anyhow!("invalid WASM_INSTANCE_MAX_CALLS={v} (expected >= 1)")),
+        _ => Ok(Some(1000)),
+    }
+}
+
+/// Whether `backend` runs `WASM_MODULE_PATH` (and so can be reloaded).
+pub(crate) fn loads_module(backend: &str) -> bool {
+    matches!(
+        backend,
+        "wasmtime_embedded" | "wasmedge" | "wasmtime" | "component"
+    )
+}
+
+/// Builds the configured backend (see `backend_from_env`).
+pub(crate) fn from_env(wasm_runtime: &str, module_path: &str) -> Result<Box<dyn Transform>> {
+    let backend = backend_from_env(wasm_runtime);
+    let wasm_policy = FailurePolicy::from_env()?;
+    let transform: Box<dyn Transform> = match backend.as_str() {
+        "wasmtime_embedded" => {
+            let compiled = get_or_compile_embedded_wasmtime(module_path).with_context(|| {
+                format!("failed to initialize embedded Wasmtime with module {module_path}")
+            })?;
+            eprintln!(
+                "[wasm-host] wasm module ABI: {}",
+                if compiled.reactor {
+                    "reactor (exported functions via linear memory)"
+                } else {
+                    "command (_start with stdio)"
+                }
+            );
+            let reuse = instance_reuse_from_env()?;
+            if let (Some(max_calls), true) = (reuse, compiled.reactor) {
+                eprintln!("[wasm-host] reusing warm reactor instances for {max_calls} calls each");
+            }
+            with_policy(
+                WasmEmbedded {
+                    module_path: module_path.to_string(),
+                    guest: GuestConfig::from_env(),
+                    reuse,
+                    timeout: wasm_timeout()?,
+                },
+                wasm_policy,
+            )
+        }
+        "wasmedge" => subprocess_backend("wasmedge", module_path, wasm_policy)?,
+        "wasmtime" => subprocess_backend("wasmtime", module_path, wasm_policy)?,
+        "native" => Box::new(NativePrefix::from_env()),
+        "noop" => Box::new(Noop),
+        "gzip" => Box::new(NativeGzip),
+        "rhai" => Box::new(RhaiScript::from_env()?),
+        "component" => Box::new(crate::component::ComponentTransform::load(module_path)?),
+        "http" => {
+            let url = std::env::var("TRANSFORM_URL")
+                .map_err(|_| anyhow!("TRANSFORM_BACKEND=http requires TRANSFORM_URL"))?;
+            let timeout_ms = match std::env::var("TRANSFORM_TIMEOUT_MS") {
+                Ok(v) => v
+                    .parse::<u64>()
+                    .with_context(|| format!("invalid TRANSFORM_TIMEOUT_MS={v}"))?,
+                Err(_) => IO_TIMEOUT.as_millis() as u64,
+            };
+            let bypass_on_failure = match std::env::var("TRANSFORM_FAILURE_POLICY").as_deref() {
+                Err(_) | Ok("") | Ok("error") => false,
+                Ok("bypass") => true,
+                Ok(other) => {
+                    return Err(anyhow!(
+                        "invalid TRANSFORM_FAILURE_POLICY={other} (expected: error|bypass)"
+                    ))
+                }
+            };
+            let endpoint =
+                parse_upstream(&url).with_context(|| format!("invalid TRANSFORM_URL={url}"))?;
+            if endpoint.tls {
+                return Err(anyhow!("TRANSFORM_URL={url}: only http is supported"));
+            }
+            Box::new(HttpService {
+                endpoint,
+                timeout: Duration::from_millis(timeout_ms.max(1)),
+                bypass_on_failure,
+            })
+        }
+        other => {
+            return Err(anyhow!(
+                "invalid TRANSFORM_BACKEND={other} \
+                 (expected: wasmtime_embedded|wasmedge|wasmtime|native|noop|gzip|rhai|component|http)"
+            ))
+        }
+    };
+    Ok(transform)
+}
+
+/// A `wasmedge` / `wasmtime` backend: persistent workers with `WASM_WORKERS`,
+/// otherwise one process per call.
+fn subprocess_backend(
+    runtime: &'static str,
+    module_path: &str,
+    policy: FailurePolicy,
+) -> Result<Box<dyn Transform>> {
+    let module_path = module_path.to_string();
+    let timeout = wasm_timeout()?;
+    let sandbox = Sandbox::from_env()?;
+    let guest = GuestConfig::from_env();
+    Ok(match WorkerSettings::from_env()? {
+        Some(settings) => with_policy(
+            WorkerPool::new(runtime, module_path, timeout, sandbox, guest, settings),
+            policy,
+        ),
+        None => with_policy(
+            WasmSubprocess {
+                runtime,
+                module_path,
+                timeout,
+                sandbox,
+                guest,
+            },
+            policy,
+        ),
+    })
+}
+
+/// `WASM_TIMEOUT_MS` for every wasm backend; unset or `0` waits forever.
+fn wasm_timeout() -> Result<Option<Duration>> {
+    match std::env::var("WASM_TIMEOUT_MS") {
+        Ok(v) => {
+            let ms = v
+                .parse::<u64>()
+                .with_context(|| format!("invalid WASM_TIMEOUT_MS={v}"))?;
+            Ok((ms > 0).then(|| Duration::from_millis(ms)))
+        }
+        Err(_) => Ok(None),
+    }
+}
+
+/// Error marker for a module killed (or, embedded, interrupted) after
+/// `WASM_TIMEOUT_MS`; the request path answers it with a 504.
+#[derive(Debug)]
+pub(crate) struct WasmTimeout {
+    pub(crate) timeout: Duration,
+}
+
+impl std::fmt::Display for WasmTimeout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "wasm module timed out after {:?}", self.timeout)
+    }
+}
+
+impl std::error::Error for WasmTimeout {}
+
+/// Static configuration handed to wasm guests: every host variable starting
+/// with `GUEST_` is passed into the module's environment unchanged, and
+/// `WASM_GUEST_ARGS` (whitespace-separated) is appended to its argv.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct GuestConfig {
+    pub(crate) env: Vec<(String, String)>,
+    pub(crate) args: Vec<String>,
+}
+
+impl GuestConfig {
+    pub(crate) fn from_env() -> Self {
+        let mut env: Vec<(String, String)> = std::env::vars()
+            .filter(|(k, _)| k.starts_with("GUEST_"))
+            .collect();
+        env.sort();
+        let args = std::env::var("WASM_GUEST_ARGS")
+            .map(|v| v.split_whitespace().map(str::to_string).collect())
+            .unwrap_or_default();
+        GuestConfig { env, args }
+    }
+}
+
+fn with_policy(inner: impl Transform + 'static, policy: FailurePolicy) -> Box<dyn Transform> {
+    match policy {
+        FailurePolicy::Error => Box::new(inner),
+        policy => Box::new(WasmWithPolicy {
+            inner: Box::new(inner),
+            policy,
+        }),
+    }
+}
+
+/// Applies a non-default `WASM_FAILURE_POLICY` around a wasm backend. Both
+/// backends instantiate the module per call, so a retry always gets a fresh
+/// instance; with `WASM_WORKERS` the failed worker is discarded, so a retry
+/// runs on another process, and with `WASM_EXECUTION=embedded` the failed
+/// warm instance is dropped, so a retry runs on another one.
+#[derive(Debug)]
+pub(crate) struct WasmWithPolicy {
+    inner: Box<dyn Transform>,
+    policy: FailurePolicy,
+}
+
+impl Transform for WasmWithPolicy {
+    fn name(&self) -> &'static str {
+        self.inner.name()
+    }
+
+    fn transform(&self, input: &[u8], envelope: &Envelope) -> Result<Vec<u8>> {
+        let err = match self.inner.transform(input, envelope) {
+            Ok(body) => return Ok(body),
+            Err(e) => e,
+        };
+        match self.policy {
+            FailurePolicy::Error => Err(err),
+            FailurePolicy::Bypass => {
+                eprintln!("[wasm-host] wasm transform failed, bypassing: {err:#}");
+                envelope.bypassed.store(true, Ordering::Relaxed);
+                Ok(input.to_vec())
+            }
+            FailurePolicy::Retry => {
+                eprintln!("[wasm-host] wasm transform failed, retrying once: {err:#}");
+                self.inner.transform(input, envelope)
+            }
+        }
+    }
+
+    fn bypass_header(&self) -> &'static str {
+        "X-Wasm-Bypassed"
+    }
+}
+
+/// SHA-256 of what went into and came out of a dry-run transform; no output
+/// digest when the backend failed.
+#[derive(Clone, Debug)]
+pub(crate) struct DryRunDigests {
+    pub(crate) input_sha256: String,
+    pub(crate) output_sha256: Option<String>,
+}
+
+/// `WASM_DRY_RUN=1`: runs the backend but serves the original body, recording
+/// both digests in the envelope (and so the audit log). Failures and headers
+/// the backend adds never reach the client.
+#[derive(Debug)]
+pub(crate) struct DryRun {
+    pub(crate) inner: Box<dyn Transform>,
+}
+
+impl Transform for DryRun {
+    fn name(&self) -> &'static str {
+        self.inner.name()
+    }
+
+    fn transform(&self, input: &[u8], envelope: &Envelope) -> Result<Vec<u8>> {
+        let saved_headers = envelope
+            .response_headers
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone();
+        let output = self.inner.transform(input, envelope);
+        *envelope
+            .response_headers
+            .lock()
+            .unwrap_or_else(|e| e.into_inner()) = saved_headers;
+
+        let digests = DryRunDigests {
+            input_sha256: hex::encode(Sha256::digest(input)),
+            output_sha256: output
+                .as_ref()
+                .ok()
+                .map(|output| hex::encode(Sha256::digest(output))),
+        };
+        match (&output, &digests.output_sha256) {
+            (Err(e), _) => eprintln!(
+                "[wasm-host] dry-run transform failed input_sha256={}: {e:#}",
+                digests.input_sha256
+            ),
+            (Ok(_), Some(output_sha256)) => eprintln!(
+                "[wasm-host] dry-run input_sha256={} output_sha256={output_sha256} diverged={}",
+                digests.input_sha256,
+                *output_sha256 != digests.input_sha256
+            ),
+            (Ok(_), None) => {}
+        }
+        *envelope.dry_run.lock().unwrap_or_else(|e| e.into_inner()) = Some(digests);
+        Ok(input.to_vec())
+    }
+}
+
+/// Runtime on/off switch around the configured backend (`WASM_ENABLED`,
+/// `/admin/wasm/enabled`). While off, bodies pass through untouched and the
+/// response carries `X-Transform-Disabled: true`.
+#[derive(Debug)]
+pub(crate) struct Switchable {
+    pub(crate) inner: Box<dyn Transform>,
+    pub(crate) enabled: std::sync::Arc<std::sync::atomic::AtomicBool>,
+}
+
+impl Transform for Switchable {
+    fn name(&self) -> &'static str {
+        self.inner.name()
+    }
+
+    fn transform(&self, input: &[u8], envelope: &Envelope) -> Result<Vec<u8>> {
+        if self.enabled.load(Ordering::Relaxed) {
+            return self.inner.transform(input, envelope);
+        }
+        let mut headers = envelope
+            .response_headers
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        if !headers.iter().any(|(k, _)| k == "X-Transform-Disabled") {
+            headers.push(("X-Transform-Disabled".to_string(), "true".to_string()));
+        }
+        Ok(input.to_vec())
+    }
+
+    fn bypass_header(&self) -> &'static str {
+        self.inner.bypass_header()
+    }
+}
+
+/// In-process Wasmtime with the module compiled once at startup.
+#[derive(Debug)]
+pub(crate) struct WasmEmbedded {
+    module_path: String,
+    guest: GuestConfig,
+    /// Calls a warm reactor instance serves before it is replaced, with
+    /// `WASM_EXECUTION=embedded`.
+    reuse: Option<u64>,
+    /// `WASM_TIMEOUT_MS`, after which a call is interrupted.
+    timeout: Option<Duration>,
+}
+
+impl Transform for WasmEmbedded {
+    fn name(&self) -> &'static str {
+        "wasmtime_embedded"
+    }
+
+    fn transform(&self, input: &[u8], envelope: &Envelope) -> Result<Vec<u8>> {
+        wasm_transform_wasmtime_embedded(
+            &self.module_path,
+            input,
+            envelope,
+            &self.guest,
+            self.reuse,
+            self.timeout,
+        )
+        .map(|output| strip_response_envelope(output, envelope))
+    }
+}
+
+/// One `wasmedge` / `wasmtime run` process per call.
+#[derive(Debug)]
+pub(crate) struct WasmSubprocess {
+    runtime: &'static str,
+    module_path: String,
+    /// The child is killed after this long (`WASM_TIMEOUT_MS`).
+    timeout: Option<Duration>,
+    sandbox: Sandbox,
+    guest: GuestConfig,
+}
+
+impl Transform for WasmSubprocess {
+    fn name(&self) -> &'static str {
+        self.runtime
+    }
+
+    fn transform(&self, input: &[u8], envelope: &Envelope) -> Result<Vec<u8>> {
+        wasm_transform_cli(
+            self.runtime,
+            &self.module_path,
+            input,
+            envelope,
+            self.timeout,
+            &self.sandbox,
+            &self.guest,
+        )
+        .map(|output| strip_response_envelope(output, envelope))
+    }
+}
+
+/// Native Rust equivalent of the guest: prepends `TRANSFORM_PREFIX`.
+#[derive(Debug)]
+pub(crate) struct NativePrefix {
+    prefix: Vec<u8>,
+}
+
+impl NativePrefix {
+    pub(crate) fn from_env() -> Self {
+        NativePrefix {
+            prefix: std::env::var("TRANSFORM_PREFIX")
+                .unwrap_or_else(|_| "wasm:".to_string())
+                .into_bytes(),
+        }
+    }
+}
+
+impl Transform for NativePrefix {
+    fn name(&self) -> &'static str {
+        "native"
+    }
+
+    fn transform(&self, input: &[u8], _envelope: &Envelope) -> Result<Vec<u8>> {
+        let mut out = Vec::with_capacity(self.prefix.len() + input.len());
+        out.extend_from_slice(&self.prefix);
+        out.extend_from_slice(input);
+        Ok(out)
+    }
+}
+
+/// In-host counterpart of the guest's `gzip` mode, for comparing compression
+/// inside and outside the sandbox.
+#[derive(Debug)]
+pub(crate) struct NativeGzip;
+
+impl Transform for NativeGzip {
+    fn name(&self) -> &'static str {
+        "gzip"
+    }
+
+    fn transform(&self, input: &[u8], envelope: &Envelope) -> Result<Vec<u8>> {
+        let mut encoder = flate2::write::GzEncoder::new(
+            Vec::with_capacity(input.len() / 2),
+            flate2::Compression::default(),
+        );
+        encoder.write_all(input)?;
+        let body = encoder.finish().context("gzip body")?;
+        envelope
+            .response_headers
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(("Content-Encoding".to_string(), "gzip".to_string()));
+        Ok(body)
+    }
+}
+
+/// Returns the input unchanged (pure gateway overhead baseline).
+#[derive(Debug)]
+pub(crate) struct Noop;
+
+impl Transform for Noop {
+    fn name(&self) -> &'static str {
+        "noop"
+    }
+
+    fn transform(&self, input: &[u8], _envelope: &Envelope) -> Result<Vec<u8>> {
+        Ok(input.to_vec())
+    }
+}
+
+/// Embedded Rhai script (`TRANSFORM_SCRIPT`) defining `fn transform(body, env)`,
+/// compiled once at startup: the "scripted gateway" data point.
+#[derive(Debug)]
+pub(crate) struct RhaiScript {
+    engine: rhai::Engine,
+    ast: rhai::AST,
+}
+
+impl RhaiScript {
+    fn from_env() -> Result<Self> {
+        let path = std::env::var("TRANSFORM_SCRIPT")
+            .unwrap_or_else(|_| "./configs/transform.rhai".to_string());
+        let max_operations = match std::env::var("RHAI_MAX_OPERATIONS") {
+            Ok(v) => v
+                .parse::<u64>()
+                .with_context(|| format!("invalid RHAI_MAX_OPERATIONS={v}"))?,
+            Err(_) => 1_000_000,
+        };
+        let mut engine = rhai::Engine::new();
+        // Bounds runaway scripts; 0 disables the limit.
+        engine.set_max_operations(max_operations);
+        let ast = engine
+            .compile_file(path.clone().into())
+            .map_err(|e| anyhow!("compile TRANSFORM_SCRIPT={path}: {e}"))?;
+        if !ast
+            .iter_functions()
+            .any(|f| f.name == "transform" && f.params.len() == 2)
+        {
+            return Err(anyhow!(
+                "TRANSFORM_SCRIPT={path} does not define fn transform(body, env)"
+            ));
+        }
+        Ok(RhaiScript { engine, ast })
+    }
+}
+
+impl Transform for RhaiScript {
+    fn name(&self) -> &'static str {
+        "rhai"
+    }
+
+    fn transform(&self, input: &[u8], envelope: &Envelope) -> Result<Vec<u8>> {
+        let env: rhai::Map = envelope
+            .vars
+            .iter()
+            .map(|(k, v)| (k.as_str().into(), v.clone().into()))
+            .collect();
+        let body = String::from_utf8_lossy(input).into_owned();
+        let out: rhai::Dynamic = self
+            .engine
+            .call_fn(&mut rhai::Scope::new(), &self.ast, "transform", (body, env))
+            .map_err(|e| anyhow!("rhai transform failed: {e}"))?;
+        if out.is_string() {
+            return Ok(out.into_string().unwrap_or_default().into_bytes());
+        }
+        if out.is_blob() {
+            return Ok(out.cast::<rhai::Blob>());
+        }
+        Err(anyhow!(
+            "rhai transform returned {} (expected string or blob)",
+            out.type_name()
+        ))
+    }
+}
+
+/// POSTs the body to an external transform service and uses its response body.
+/// Envelope variables travel as headers (`GATEWAY_AUTH_SUBJECT` becomes
+/// `X-Gateway-Auth-Subject`).
+#[derive(Debug)]
+pub(crate) struct HttpService {
+    endpoint: Upstream,
+    /// Bounds connect, write and read separately.
+    timeout: Duration,
+    /// `TRANSFORM_FAILURE_POLICY=bypass`: serve the untransformed body instead
+    /// of failing the request.
+    bypass_on_failure: bool,
+}
+
+impl Transform for HttpService {
+    fn name(&self) -> &'static str {
+        "http"
+    }
+
+    fn transform(&self, input: &[u8], envelope: &Envelope) -> Result<Vec<u8>> {
+        match self.call(input, envelope) {
+            Ok(body) => Ok(body),
+            Err(e) if self.bypass_on_failure => {
+                eprintln!("[wasm-host] transform service failed, bypassing: {e:#}");
+                envelope.bypassed.store(true, Ordering::Relaxed);
+                Ok(input.to_vec())
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl HttpService {
+    fn call(&self, input: &[u8], envelope: &Envelope) -> Result<Vec<u8>> {
+        let endpoint = &self.endpoint;
+        let path = if endpoint.base_path.is_empty() {
+            "/"
+        } else {
+            endpoint.base_path.as_str()
+        };
+        // HTTP/1.0 keeps the response un-chunked and closes the connection.
+        let mut head = format!(
+            "POST {path} HTTP/1.0\r\nHost: {}\r\nContent-Type: application/octet-stream\r\nContent-Length: {}\r\n",
+            endpoint.host,
+            input.len()
+        );
+        for (name, value) in &envelope.vars {
+            let name = name.strip_prefix("GATEWAY_").unwrap_or(name);
+            let header: Vec<String> = name
+                .split('_')
+                .map(|part| {
+                    let lower = part.to_ascii_lowercase();
+                    let mut chars = lower.chars();
+                    chars
+                        .next()
+                        .map(|c| c.to_ascii_uppercase().to_string() + chars.as_str())
+                        .unwrap_or_default()
+                })
+                .collect();
+            let value = value.replace(['\r', '\n'], " ");
+            head.push_str(&format!("X-Gateway-{}: {value}\r\n", header.join("-")));
+        }
+        head.push_str("\r\n");
+
+        let addr = (endpoint.host.as_str(), endpoint.port)
+            .to_socket_addrs()
+            .with_context(|| format!("resolve transform service {}", endpoint.raw_url))?
+            .next()
+            .ok_or_else(|| anyhow!("transform service {} has no address", endpoint.raw_url))?;
+        let mut stream = TcpStream::connect_timeout(&addr, self.timeout)
+            .with_context(|| format!("connect transform service {}", endpoint.raw_url))?;
+        stream.set_read_timeout(Some(self.timeout)).ok();
+        stream.set_write_timeout(Some(self.timeout)).ok();
+        stream.write_all(head.as_bytes())?;
+        stream.write_all(input)?;
+        stream.flush()?;
+
+        let resp = read_all_response(&mut stream)?;
+        let (head, body) = split_http_response(&resp)?;
+        let status = parse_status_code_from_head(&head)?;
+        if !(200..300).contains(&status) {
+            return Err(anyhow!("transform service returned status {status}"));
+        }
+        Ok(body)
+    }
+}
@@ -0,0 +1,706 @@
+//! TLS toward the upstream (`UPSTREAM_URL=https://…`), optionally presenting a
+//! client certificate for meshes that require mutual TLS.
+//!
+//! - `UPSTREAM_TLS_CA`: PEM bundle the upstream's certificate must chain to
+//!   (default: the Mozilla roots built into the binary).
+//! - `UPSTREAM_TLS_CERT` / `UPSTREAM_TLS_KEY`: PEM client certificate chain and
+//!   private key, sent when the upstream asks for one.
+//!
+//! - `UPSTREAM_TLS_SESSION_CACHE`: sessions kept for resumption (default 256,
+//!   `0` turns resumption off).
+//! - `UPSTREAM_TLS_WARM`: handshaked connections a background thread keeps
+//!   ready per `https://` upstream, so requests skip the handshake (default 0).
+//!   One idle longer than `UPSTREAM_TLS_WARM_IDLE_SECS` (default 15) or closed
+//!   by the upstream is dropped and replaced.
+//!
+//! Handshakes (full vs resumed, time spent) and warm pool hits are counted
+//! for `/metrics`, so TLS overhead can be told apart from transform cost.
+//!
+//! Failed handshakes (untrusted or mismatched server certificate, client
+//! certificate refused) are answered with a 502 carrying
+//! `X-Upstream-Error: tls-handshake`; see `is_handshake_failure`.
+
+use anyhow::{anyhow, Context, Result};
+use rustls::client::Resumption;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::{ClientConfig, ClientConnection, HandshakeKind, RootCertStore, StreamOwned};
+use std::io::{self, BufRead, Read, Write};
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::time::{Duration, Instant};
+
+use crate::{Upstream, IO_TIMEOUT};
+
+const DEFAULT_SESSION_CACHE: usize = 256;
+const DEFAULT_WARM_IDLE: Duration = Duration::from_secs(15);
+const WARM_RETRY: Duration = Duration::from_millis(200);
+const WARM_MAX_RETRY: Duration = Duration::from_secs(5);
+
+type TlsStream = StreamOwned<ClientConnection, TcpStream>;
+
+static IN_USE: AtomicBool = AtomicBool::new(false);
+/// Completed handshakes, indexed full / resumed.
+static HANDSHAKES: [AtomicU64; 2] = [AtomicU64::new(0), AtomicU64::new(0)];
+static HANDSHAKE_US: AtomicU64 = AtomicU64::new(0);
+/// Warm pool lookups, indexed hit / miss.
+static WARM: [AtomicU64; 2] = [AtomicU64::new(0), AtomicU64::new(0)];
+
+/// Handshake and warm pool counters, read at one point in time.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) struct TlsStats {
+    pub(crate) full_handshakes: u64,
+    pub(crate) resumed_handshakes: u64,
+    pub(crate) handshake_us: u64,
+    pub(crate) warm_hits: u64,
+    pub(crate) warm_misses: u64,
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct UpstreamTls {
+    config: Arc<ClientConfig>,
+    server_name: ServerName<'static>,
+    warm: Option<Arc<WarmPool>>,
+}
+
+impl UpstreamTls {
+    /// `None` for an `http://` upstream.
+    pub(crate) fn from_env(upstream: &Upstream) -> Result<Option<Self>> {
+        if !upstream.tls {
+            return Ok(None);
+        }
+        let (ca, cert, key) = (
+            var("UPSTREAM_TLS_CA"),
+            var("UPSTREAM_TLS_CERT"),
+            var("UPSTREAM_TLS_KEY"),
+        );
+        let client_cert = match (cert.as_deref(), key.as_deref()) {
+            (Some(cert), Some(key)) => Some((cert, key)),
+            (Some(_), None) => return Err(anyhow!("UPSTREAM_TLS_CERT needs UPSTREAM_TLS_KEY")),
+            (None, Some(_)) => return Err(anyhow!("UPSTREAM_TLS_KEY needs UPSTREAM_TLS_CERT")),
+            (None, None) => None,
+        };
+        let session_cache =
+            number("UPSTREAM_TLS_SESSION_CACHE")?.map_or(DEFAULT_SESSION_CACHE, |n| n as usize);
+        let mut tls = Self::new(&upstream.host, ca.as_deref(), client_cert, session_cache)?;
+        if let Some(size) = number("UPSTREAM_TLS_WARM")?.filter(|n| *n > 0) {
+            let max_idle = number("UPSTREAM_TLS_WARM_IDLE_SECS")?
+                .map_or(DEFAULT_WARM_IDLE, Duration::from_secs);
+            tls.start_warm(&upstream.host, upstream.port, size as usize, max_idle)?;
+            eprintln!(
+                "[wasm-host] upstream TLS: keeping {size} warm connection(s) to {}:{}, idle up to {max_idle:?}",
+                upstream.host, upstream.port
+            );
+        }
+        Ok(Some(tls))
+    }
+
+    /// TLS to `host`, trusting only the certificates in `ca`; for the
+    /// gateway's own API clients rather than upstreams.
+    pub(crate) fn with_ca(host: &str, ca: &str) -> Result<Self> {
+        Self::new(host, Some(ca), None, DEFAULT_SESSION_CACHE)
+    }
+
+    fn new(
+        host: &str,
+        ca: Option<&str>,
+        client_cert: Option<(&str, &str)>,
+        session_cache: usize,
+    ) -> Result<Self> {
+        let mut roots = RootCertStore::empty();
+        match ca {
+            Some(path) => {
+                for cert in CertificateDer::pem_file_iter(path)
+                    .with_context(|| format!("read UPSTREAM_TLS_CA={path}"))?
+                {
+                    roots
+                        .add(cert.with_context(|| format!("parse UPSTREAM_TLS_CA={path}"))?)
+                        .with_context(|| format!("invalid CA certificate in {path}"))?;
+                }
+                if roots.is_empty() {
+                    return Err(anyhow!("no certificates in UPSTREAM_TLS_CA={path}"));
+                }
+            }
+            None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+        }
+
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let builder = ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .context("TLS protocol versions")?
+            .with_root_certificates(roots);
+        let mut config = match client_cert {
+            Some((cert_path, key_path)) => {
+                let chain = CertificateDer::pem_file_iter(cert_path)
+                    .and_then(|certs| certs.collect::<std::result::Result<Vec<_>, _>>())
+                    .with_context(|| format!("read UPSTREAM_TLS_CERT={cert_path}"))?;
+                if chain.is_empty() {
+                    return Err(anyhow!("no certificates in UPSTREAM_TLS_CERT={cert_path}"));
+                }
+                let key = PrivateKeyDer::from_pem_file(key_path)
+                    .with_context(|| format!("read UPSTREAM_TLS_KEY={key_path}"))?;
+                builder
+                    .with_client_auth_cert(chain, key)
+                    .context("UPSTREAM_TLS_CERT and UPSTREAM_TLS_KEY do not match")?
+            }
+            None => builder.with_no_client_auth(),
+        };
+        config.resumption = match session_cache {
+            0 => Resumption::disabled(),
+            n => Resumption::in_memory_sessions(n),
+        };
+        let server_name = ServerName::try_from(host.to_string())
+            .with_context(|| format!("invalid TLS server name {host}"))?;
+        IN_USE.store(true, Ordering::Relaxed);
+        Ok(UpstreamTls {
+            config: Arc::new(config),
+            server_name,
+            warm: None,
+        })
+    }
+
+    /// Starts the thread keeping `size` handshaked connections to
+    /// `host:port` ready. It exits once this `UpstreamTls` is dropped.
+    fn start_warm(&mut self, host: &str, port: u16, size: usize, max_idle: Duration) -> Result<()> {
+        let pool = Arc::new(WarmPool::new(self, host, port, size, max_idle));
+        let weak = Arc::downgrade(&pool);
+        std::thread::Builder::new()
+            .name("upstream-tls-warm".to_string())
+            .spawn(move || refill(weak))
+            .context("spawn upstream TLS warm pool thread")?;
+        self.warm = Some(pool);
+        Ok(())
+    }
+
+    /// Whether a client certificate is configured.
+    pub(crate) fn client_auth(&self) -> bool {
+        self.config.client_auth_cert_resolver.has_certs()
+    }
+
+    /// Runs the handshake on `tcp` before anything is sent.
+    pub(crate) fn connect(&self, tcp: TcpStream) -> Result<UpstreamStream> {
+        handshake(&self.config, &self.server_name, tcp).map(UpstreamStream::Tls)
+    }
+
+    /// A connection from the warm pool, if one is configured and ready.
+    pub(crate) fn take_warm(&self) -> Option<UpstreamStream> {
+        let pool = self.warm.as_ref()?;
+        let mut idle = pool.idle.lock().unwrap_or_else(|e| e.into_inner());
+        let mut found = None;
+        // Newest first: the oldest are the likeliest to have been closed.
+        while let Some((at, mut tls)) = idle.pop() {
+            if at.elapsed() < pool.max_idle && still_open(&mut tls) {
+                found = Some(tls);
+                break;
+            }
+        }
+        drop(idle);
+        pool.taken.notify_one();
+        WARM[usize::from(found.is_none())].fetch_add(1, Ordering::Relaxed);
+        found.map(UpstreamStream::Tls)
+    }
+}
+
+/// Handshaked connections to one upstream, refilled by `refill`.
+struct WarmPool {
+    size: usize,
+    max_idle: Duration,
+    host: String,
+    port: u16,
+    config: Arc<ClientConfig>,
+    server_name: ServerName<'static>,
+    idle: Mutex<Vec<(Instant, Box<TlsStream>)>>,
+    taken: Condvar,
+}
+
+impl std::fmt::Debug for WarmPool {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WarmPool")
+            .field("size", &self.size)
+            .field("max_idle", &self.max_idle)
+            .field("host", &self.host)
+            .field("port", &self.port)
+            .finish_non_exhaustive()
+    }
+}
+
+impl WarmPool {
+    fn new(tls: &UpstreamTls, host: &str, port: u16, size: usize, max_idle: Duration) -> Self {
+        WarmPool {
+            size,
+            max_idle,
+            host: host.to_string(),
+            port,
+            config: Arc::clone(&tls.config),
+            server_name: tls.server_name.clone(),
+            idle: Mutex::default(),
+            taken: Condvar::new(),
+        }
+    }
+
+    /// Waits until the pool is short of `size`, dropping connections idle
+    /// past `max_idle` meanwhile. `false` if it is still full on return, so
+    /// the caller can check whether the pool is still wanted.
+    fn wait_for_room(&self) -> bool {
+        let check_every =
+            (self.max_idle / 2).clamp(Duration::from_millis(100), Duration::from_secs(1));
+        let mut idle = self.idle.lock().unwrap_or_else(|e| e.into_inner());
+        idle.retain(|(at, _)| at.elapsed() < self.max_idle);
+        if idle.len() < self.size {
+            return true;
+        }
+        idle = self
+            .taken
+            .wait_timeout(idle, check_every)
+            .unwrap_or_else(|e| e.into_inner())
+            .0;
+        idle.retain(|(at, _)| at.elapsed() < self.max_idle);
+        idle.len() < self.size
+    }
+
+    fn open(&self) -> Result<Box<TlsStream>> {
+        let tcp = TcpStream::connect((&*self.host, self.port))
+            .with_context(|| format!("connect upstream {}:{}", self.host, self.port))?;
+        tcp.set_read_timeout(Some(IO_TIMEOUT)).ok();
+        tcp.set_write_timeout(Some(IO_TIMEOUT)).ok();
+        handshake(&self.config, &self.server_name, tcp)
+    }
+}
+
+/// Body of the warm pool thread; returns once the pool has been dropped.
+fn refill(pool: Weak<WarmPool>) {
+    let mut failures = 0u32;
+    while let Some(pool) = pool.upgrade() {
+        if !pool.wait_for_room() {
+            continue;
+        }
+        match pool.open() {
+            Ok(tls) => {
+                failures = 0;
+                pool.idle
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .push((Instant::now(), tls));
+            }
+            Err(e) => {
+                if failures == 0 {
+                    eprintln!(
+                        "[wasm-host] upstream TLS warm connection to {}:{} failed: {e:#}",
+                        pool.host, pool.port
+                    );
+                }
+                failures = failures.saturating_add(1);
+                drop(pool);
+                std::thread::sleep(WARM_RETRY.saturating_mul(failures).min(WARM_MAX_RETRY));
+            }
+        }
+    }
+}
+
+fn handshake(
+    config: &Arc<ClientConfig>,
+    server_name: &ServerName<'static>,
+    mut tcp: TcpStream,
+) -> Result<Box<TlsStream>> {
+    let started = Instant::now();
+    let mut conn = ClientConnection::new(Arc::clone(config), server_name.clone())
+        .context("start upstream TLS session")?;
+    while conn.is_handshaking() {
+        conn.complete_io(&mut tcp)
+            .context("upstream TLS handshake failed")?;
+    }
+    let resumed = conn.handshake_kind() == Some(HandshakeKind::Resumed);
+    HANDSHAKES[usize::from(resumed)].fetch_add(1, Ordering::Relaxed);
+    HANDSHAKE_US.fetch_add(started.elapsed().as_micros() as u64, Ordering::Relaxed);
+    Ok(Box::new(StreamOwned::new(conn, tcp)))
+}
+
+/// Whether an idle connection can still carry a request: reads whatever the
+/// upstream sent meanwhile (TLS 1.3 session tickets) without blocking, and
+/// fails on EOF, `close_notify` or an error.
+fn still_open(tls: &mut TlsStream) -> bool {
+    if tls.sock.set_nonblocking(true).is_err() {
+        return false;
+    }
+    let open = loop {
+        match tls.conn.read_tls(&mut tls.sock) {
+            Ok(0) => break false,
+            Ok(_) => match tls.conn.process_new_packets() {
+                Ok(state) if !state.peer_has_closed() => continue,
+                _ => break false,
+            },
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => break true,
+            Err(_) => break false,
+        }
+    };
+    open && tls.sock.set_nonblocking(false).is_ok()
+}
+
+fn var(name: &str) -> Option<String> {
+    std::env::var(name).ok().filter(|v| !v.is_empty())
+}
+
+fn number(name: &str) -> Result<Option<u64>> {
+    var(name)
+        .map(|v| {
+            v.trim()
+                .parse::<u64>()
+                .with_context(|| format!("invalid {name}={v}"))
+        })
+        .transpose()
+}
+
+/// Whether any `UPSTREAM_TLS_*` setting is present, so one with no `https://`
+/// upstream to apply to can be rejected rather than silently ignored.
+pub(crate) fn configured() -> bool {
+    [
+        "UPSTREAM_TLS_CA",
+        "UPSTREAM_TLS_CERT",
+        "UPSTREAM_TLS_KEY",
+        "UPSTREAM_TLS_SESSION_CACHE",
+        "UPSTREAM_TLS_WARM",
+        "UPSTREAM_TLS_WARM_IDLE_SECS",
+    ]
+    .iter()
+    .any(|name| var(name).is_some())
+}
+
+/// Handshake and warm pool counters; `None` with no `https://` upstream.
+pub(crate) fn stats() -> Option<TlsStats> {
+    if !IN_USE.load(Ordering::Relaxed) {
+        return None;
+    }
+    let load = |v: &AtomicU64| v.load(Ordering::Relaxed);
+    Some(TlsStats {
+        full_handshakes: load(&HANDSHAKES[0]),
+        resumed_handshakes: load(&HANDSHAKES[1]),
+        handshake_us: load(&HANDSHAKE_US),
+        warm_hits: load(&WARM[0]),
+        warm_misses: load(&WARM[1]),
+    })
+}
+
+/// A connection to the upstream, plain or TLS.
+pub(crate) enum UpstreamStream {
+    Plain(TcpStream),
+    Tls(Box<StreamOwned<ClientConnection, TcpStream>>),
+}
+
+impl UpstreamStream {
+    pub(crate) fn tcp(&self) -> &TcpStream {
+        match self {
+            UpstreamStream::Plain(tcp) => tcp,
+            UpstreamStream::Tls(tls) => tls.get_ref(),
+        }
+    }
+
+    /// Blocks until response bytes are readable, without consuming them.
+    pub(crate) fn wait_readable(&mut self) -> io::Result<()> {
+        match self {
+            UpstreamStream::Plain(tcp) => tcp.peek(&mut [0u8; 1]).map(|_| ()),
+            UpstreamStream::Tls(tls) => tls.fill_buf().map(|_| ()),
+        }
+    }
+}
+
+impl Read for UpstreamStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            UpstreamStream::Plain(tcp) => tcp.read(buf),
+            // Plenty of servers close without `close_notify`; the response is
+            // read to EOF either way, as on a plain connection.
+            UpstreamStream::Tls(tls) => match tls.read(buf) {
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(0),
+                other => other,
+            },
+        }
+    }
+}
+
+impl Write for UpstreamStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            UpstreamStream::Plain(tcp) => tcp.write(buf),
+            UpstreamStream::Tls(tls) => tls.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            UpstreamStream::Plain(tcp) => tcp.flush(),
+            UpstreamStream::Tls(tls) => tls.flush(),
+        }
+    }
+}
+
+/// Whether `e` came from TLS with the upstream. Under TLS 1.3 a refused
+/// client certificate only shows up as an alert on the first read, so this
+/// looks at the whole chain, not just the handshake.
+pub(crate) fn is_handshake_failure(e: &anyhow::Error) -> bool {
+    e.chain().any(|cause| {
+        cause.is::<rustls::Error>()
+            || cause
+                .downcast_ref::<io::Error>()
+                .and_then(|io| io.get_ref())
+                .is_some_and(|inner| inner.is::<rustls::Error>())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustls::server::WebPkiClientVerifier;
+    use rustls::{ServerConfig, ServerConnection};
+    use std::net::TcpListener;
+
+    struct Pki {
+        dir: std::path::PathBuf,
+        ca: rcgen::CertifiedKey,
+    }
+
+    impl Pki {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!(
+                "wasm-host-upstream-tls-{name}-{}",
+                std::process::id()
+            ));
+            std::fs::create_dir_all(&dir).unwrap();
+            let mut params = rcgen::CertificateParams::new(Vec::new()).unwrap();
+            params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+            let key = rcgen::KeyPair::generate().unwrap();
+            let cert = params.self_signed(&key).unwrap();
+            std::fs::write(dir.join("ca.pem"), cert.pem()).unwrap();
+            Pki {
+                dir,
+                ca: rcgen::CertifiedKey {
+                    cert,
+                    key_pair: key,
+                },
+            }
+        }
+
+        /// A leaf signed by this CA, written as `<name>.pem` / `<name>.key`.
+        fn issue(&self, name: &str, san: &str) -> (String, String) {
+            let params = rcgen::CertificateParams::new(vec![san.to_string()]).unwrap();
+            let key = rcgen::KeyPair::generate().unwrap();
+            let cert = params
+                .signed_by(&key, &self.ca.cert, &self.ca.key_pair)
+                .unwrap();
+            let (cert_path, key_path) = (
+                self.dir.join(format!("{name}.pem")),
+                self.dir.join(format!("{name}.key")),
+            );
+            std::fs::write(&cert_path, cert.pem()).unwrap();
+            std::fs::write(&key_path, key.serialize_pem()).unwrap();
+            (path(&cert_path), path(&key_path))
+        }
+
+        fn ca_path(&self) -> String {
+            path(&self.dir.join("ca.pem"))
+        }
+    }
+
+    impl Drop for Pki {
+        fn drop(&mut self) {
+            std::fs::remove_dir_all(&self.dir).ok();
+        }
+    }
+
+    fn path(p: &std::path::Path) -> String {
+        p.to_str().unwrap().to_string()
+    }
+
+    /// One-connection HTTPS server that requires a client certificate from
+    /// `pki`'s CA and answers `ok`.
+    fn serve_once(pki: &Pki) -> (u16, std::thread::JoinHandle<bool>) {
+        let (port, server) = serve(pki, 1);
+        (port, std::thread::spawn(move || server.join().unwrap()[0]))
+    }
+
+    /// Like `serve_once`, for `count` connections one after another; yields
+    /// whether each one was answered.
+    fn serve(pki: &Pki, count: usize) -> (u16, std::thread::JoinHandle<Vec<bool>>) {
+        let (cert, key) = pki.issue("server", "localhost");
+        let mut roots = RootCertStore::empty();
+        roots
+            .add(CertificateDer::from_pem_file(pki.ca_path()).unwrap())
+            .unwrap();
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let verifier = WebPkiClientVerifier::builder_with_provider(roots.into(), provider.clone())
+            .build()
+            .unwrap();
+        let config = ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_client_cert_verifier(verifier)
+            .with_single_cert(
+                CertificateDer::pem_file_iter(&cert)
+                    .unwrap()
+                    .map(|c| c.unwrap())
+                    .collect(),
+                PrivateKeyDer::from_pem_file(&key).unwrap(),
+            )
+            .unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let config = Arc::new(config);
+        let server = std::thread::spawn(move || {
+            (0..count)
+                .map(|_| {
+                    let (tcp, _) = listener.accept().unwrap();
+                    let conn = ServerConnection::new(Arc::clone(&config)).unwrap();
+                    let mut tls = StreamOwned::new(conn, tcp);
+                    let mut buf = [0u8; 64];
+                    let ok = tls.read(&mut buf).is_ok_and(|n| n > 0);
+                    if ok {
+                        tls.write_all(b"ok").ok();
+                        tls.conn.send_close_notify();
+                        tls.flush().ok();
+                    }
+                    ok
+                })
+                .collect()
+        });
+        (port, server)
+    }
+
+    fn exchange(tls: &UpstreamTls, port: u16) -> Result<Vec<u8>> {
+        ping(tls.connect(TcpStream::connect(("127.0.0.1", port))?)?)
+    }
+
+    fn ping(mut stream: UpstreamStream) -> Result<Vec<u8>> {
+        stream.write_all(b"ping")?;
+        stream.flush()?;
+        let mut out = Vec::new();
+        stream.read_to_end(&mut out)?;
+        Ok(out)
+    }
+
+    #[test]
+    fn presents_client_certificate() {
+        let pki = Pki::new("mtls");
+        let (cert, key) = pki.issue("client", "gateway");
+        let (port, server) = serve_once(&pki);
+        let tls = UpstreamTls::new(
+            "localhost",
+            Some(&pki.ca_path()),
+            Some((cert.as_str(), key.as_str())),
+            DEFAULT_SESSION_CACHE,
+        )
+        .unwrap();
+        assert!(tls.client_auth());
+        assert_eq!(exchange(&tls, port).unwrap(), b"ok");
+        assert!(server.join().unwrap());
+    }
+
+    #[test]
+    fn refused_handshakes_are_classified() {
+        let pki = Pki::new("refused");
+        // No client certificate: the server aborts the session.
+        let (port, server) = serve_once(&pki);
+        let tls = UpstreamTls::new(
+            "localhost",
+            Some(&pki.ca_path()),
+            None,
+            DEFAULT_SESSION_CACHE,
+        )
+        .unwrap();
+        let err = exchange(&tls, port).unwrap_err();
+        assert!(is_handshake_failure(&err), "{err:#}");
+        assert!(!server.join().unwrap());
+
+        // Server certificate from a CA we do not trust.
+        let other = Pki::new("untrusted");
+        let (port, server) = serve_once(&other);
+        let err = exchange(&tls, port).unwrap_err();
+        assert!(is_handshake_failure(&err), "{err:#}");
+        assert!(!server.join().unwrap());
+
+        assert!(!is_handshake_failure(&anyhow!("connection refused")));
+    }
+
+    fn handshake_kind(stream: &UpstreamStream) -> Option<HandshakeKind> {
+        match stream {
+            UpstreamStream::Tls(tls) => tls.conn.handshake_kind(),
+            UpstreamStream::Plain(_) => None,
+        }
+    }
+
+    fn mtls_client(pki: &Pki, session_cache: usize) -> UpstreamTls {
+        let (cert, key) = pki.issue("client", "gateway");
+        UpstreamTls::new(
+            "localhost",
+            Some(&pki.ca_path()),
+            Some((cert.as_str(), key.as_str())),
+            session_cache,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn resumes_sessions_unless_disabled() {
+        let pki = Pki::new("resume");
+        for (session_cache, second) in [
+            (DEFAULT_SESSION_CACHE, HandshakeKind::Resumed),
+            (0, HandshakeKind::Full),
+        ] {
+            let tls = mtls_client(&pki, session_cache);
+            let (port, server) = serve(&pki, 2);
+            let mut kinds = Vec::new();
+            for _ in 0..2 {
+                let stream = tls
+                    .connect(TcpStream::connect(("127.0.0.1", port)).unwrap())
+                    .unwrap();
+                kinds.push(handshake_kind(&stream));
+                // Reading the reply also takes in the TLS 1.3 session ticket.
+                assert_eq!(ping(stream).unwrap(), b"ok");
+            }
+            assert_eq!(kinds, [Some(HandshakeKind::Full), Some(second)]);
+            assert_eq!(server.join().unwrap(), [true, true]);
+        }
+        let stats = stats().unwrap();
+        assert!(stats.resumed_handshakes >= 1 && stats.full_handshakes >= 3);
+    }
+
+    #[test]
+    fn warm_pool_hands_out_handshaked_connections() {
+        let pki = Pki::new("warm");
+        let mut tls = mtls_client(&pki, DEFAULT_SESSION_CACHE);
+        assert!(tls.take_warm().is_none(), "no pool configured");
+        let (port, server) = serve_once(&pki);
+        tls.start_warm("127.0.0.1", port, 1, DEFAULT_WARM_IDLE)
+            .unwrap();
+        let pool = Arc::clone(tls.warm.as_ref().unwrap());
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while pool.idle.lock().unwrap().is_empty() {
+            assert!(Instant::now() < deadline, "pool never filled");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        let stream = tls.take_warm().expect("warm connection");
+        assert_eq!(ping(stream).unwrap(), b"ok");
+        assert!(server.join().unwrap());
+
+        // Expired or closed connections are dropped, not handed out.
+        let mut tls = mtls_client(&pki, DEFAULT_SESSION_CACHE);
+        let pool = Arc::new(WarmPool::new(&tls, "127.0.0.1", port, 2, DEFAULT_WARM_IDLE));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let idle_conn = || {
+            let tcp = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+            let (peer, _) = listener.accept().unwrap();
+            let conn =
+                ClientConnection::new(Arc::clone(&tls.config), tls.server_name.clone()).unwrap();
+            (Box::new(StreamOwned::new(conn, tcp)), peer)
+        };
+        let (expired, _open_peer) = idle_conn();
+        let (closed, closed_peer) = idle_conn();
+        drop(closed_peer);
+        pool.idle.lock().unwrap().extend([
+            (Instant::now() - DEFAULT_WARM_IDLE * 2, expired),
+            (Instant::now(), closed),
+        ]);
+        tls.warm = Some(Arc::clone(&pool));
+        assert!(tls.take_warm().is_none());
+        assert!(pool.idle.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,249 @@
+//! `User-Agent` classification into `bot`, `mobile` or `desktop`.
+//!
+//! With `DEVICE_CLASS=1`, `DEVICE_ROUTES` or `BOT_RULES` set, each request is
+//! classified once: the forwarded request carries `X-Device-Class`, replacing
+//! whatever the client sent, and the guest sees `GATEWAY_DEVICE_CLASS`.
+//! `DEVICE_ROUTES` (`class=url,...`) sends a class's proxied requests to its
+//! own upstream. `BOT_RULES` (`allow=pattern,deny=pattern,...`) is checked in
+//! order against the lowercased `User-Agent`, first match wins: a pattern is
+//! a substring, and `*` matches any request classified `bot`. A `deny` answers
+//! `403`; no match lets the request through.
+//!
+//! The guest's `classify` mode carries the same rules, so `GET /classify`
+//! and `GET /classify?in=wasm` compare them natively and in wasm.
+
+use anyhow::{anyhow, Context, Result};
+use once_cell::sync::OnceCell;
+
+use crate::PoolMember;
+
+pub(crate) const HEADER: &str = "X-Device-Class";
+
+/// Substrings of a lowercased `User-Agent` that mark automated clients.
+const BOT_TOKENS: &[&str] = &[
+    "bot",
+    "crawl",
+    "spider",
+    "slurp",
+    "curl/",
+    "wget/",
+    "python-requests",
+    "python-urllib",
+    "go-http-client",
+    "java/",
+    "libwww",
+    "httpclient",
+    "headless",
+    "scrapy",
+    "facebookexternalhit",
+];
+
+/// Substrings of a lowercased `User-Agent` that mark phones and tablets.
+const MOBILE_TOKENS: &[&str] = &[
+    "mobi",
+    "android",
+    "iphone",
+    "ipad",
+    "ipod",
+    "windows phone",
+    "blackberry",
+    "opera mini",
+    "silk/",
+];
+
+static DEVICES: OnceCell<Devices> = OnceCell::new();
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum DeviceClass {
+    Bot,
+    Mobile,
+    Desktop,
+}
+
+impl DeviceClass {
+    pub(crate) const ALL: [DeviceClass; 3] =
+        [DeviceClass::Bot, DeviceClass::Mobile, DeviceClass::Desktop];
+
+    pub(crate) fn label(self) -> &'static str {
+        match self {
+            DeviceClass::Bot => "bot",
+            DeviceClass::Mobile => "mobile",
+            DeviceClass::Desktop => "desktop",
+        }
+    }
+}
+
+/// Classifies a `User-Agent`; a missing or empty one is a bot.
+pub(crate) fn classify(user_agent: Option<&str>) -> DeviceClass {
+    let ua = user_agent.unwrap_or("").trim().to_ascii_lowercase();
+    if ua.is_empty() || BOT_TOKENS.iter().any(|t| ua.contains(t)) {
+        DeviceClass::Bot
+    } else if MOBILE_TOKENS.iter().any(|t| ua.contains(t)) {
+        DeviceClass::Mobile
+    } else {
+        DeviceClass::Desktop
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct Devices {
+    routes: Vec<(DeviceClass, PoolMember)>,
+    rules: Vec<BotRule>,
+}
+
+#[derive(Debug, PartialEq)]
+struct BotRule {
+    allow: bool,
+    /// Lowercase; `*` for any bot.
+    pattern: String,
+}
+
+/// Reads `DEVICE_CLASS`, `DEVICE_ROUTES` and `BOT_RULES`; `build_upstream`
+/// turns a route URL into an upstream. Call once from `main`.
+pub(crate) fn init_from_env(build_upstream: impl Fn(&str) -> Result<PoolMember>) -> Result<()> {
+    let var = |name: &str| std::env::var(name).ok().filter(|v| !v.trim().is_empty());
+    let routes = var("DEVICE_ROUTES")
+        .map(|spec| parse_routes(&spec, &build_upstream))
+        .transpose()?
+        .unwrap_or_default();
+    let rules = var("BOT_RULES")
+        .map(|spec| parse_rules(&spec).with_context(|| format!("invalid BOT_RULES={spec}")))
+        .transpose()?
+        .unwrap_or_default();
+    if var("DEVICE_CLASS").is_none_or(|v| v.trim() != "1") && routes.is_empty() && rules.is_empty()
+    {
+        return Ok(());
+    }
+    let devices = DEVICES.get_or_init(|| Devices { routes, rules });
+    let routes: Vec<String> = devices
+        .routes
+        .iter()
+        .map(|(class, (upstream, _))| format!("{} -> {}", class.label(), upstream.raw_url))
+        .collect();
+    eprintln!(
+        "[wasm-host] device classes: {} route(s){}, {} bot rule(s)",
+        routes.len(),
+        if routes.is_empty() {
+            String::new()
+        } else {
+            format!(" ({})", routes.join(", "))
+        },
+        devices.rules.len()
+    );
+    Ok(())
+}
+
+pub(crate) fn configured() -> Option<&'static Devices> {
+    DEVICES.get()
+}
+
+/// `class=url,...`, e.g. `mobile=http://m:8080,bot=http://prerender:3000`.
+fn parse_routes(
+    spec: &str,
+    build_upstream: &dyn Fn(&str) -> Result<PoolMember>,
+) -> Result<Vec<(DeviceClass, PoolMember)>> {
+    spec.split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            let (class, url) = entry.split_once('=').ok_or_else(|| {
+                anyhow!("invalid DEVICE_ROUTES entry {entry:?} (expected class=url)")
+            })?;
+            let class = DeviceClass::ALL
+                .into_iter()
+                .find(|c| c.label().eq_ignore_ascii_case(class.trim()))
+                .ok_or_else(|| {
+                    anyhow!("invalid DEVICE_ROUTES entry {entry:?} (expected bot|mobile|desktop)")
+                })?;
+            let upstream = build_upstream(url.trim())
+                .with_context(|| format!("invalid DEVICE_ROUTES entry {entry:?}"))?;
+            Ok((class, upstream))
+        })
+        .collect()
+}
+
+fn parse_rules(spec: &str) -> Result<Vec<BotRule>> {
+    spec.split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            let (action, pattern) = entry
+                .split_once('=')
+                .ok_or_else(|| anyhow!("{entry:?}: expected allow=pattern or deny=pattern"))?;
+            let allow = match action.trim() {
+                "allow" => true,
+                "deny" => false,
+                other => return Err(anyhow!("{entry:?}: unknown action {other:?}")),
+            };
+            let pattern = pattern.trim().to_ascii_lowercase();
+            if pattern.is_empty() {
+                return Err(anyhow!("{entry:?}: empty pattern"));
+            }
+            Ok(BotRule { allow, pattern })
+        })
+        .collect()
+}
+
+impl Devices {
+    /// Whether `BOT_RULES` let a request with this `User-Agent` through.
+    pub(crate) fn allows(&self, user_agent: Option<&str>, class: DeviceClass) -> bool {
+        let ua = user_agent.unwrap_or("").to_ascii_lowercase();
+        self.rules
+            .iter()
+            .find(|rule| match rule.pattern.as_str() {
+                "*" => class == DeviceClass::Bot,
+                pattern => ua.contains(pattern),
+            })
+            .is_none_or(|rule| rule.allow)
+    }
+
+    /// The `DEVICE_ROUTES` upstream for `class`, if it has one.
+    pub(crate) fn upstream_for(&self, class: DeviceClass) -> Option<&PoolMember> {
+        self.routes
+            .iter()
+            .find(|(c, _)| *c == class)
+            .map(|(_, upstream)| upstream)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classifies_agents_and_applies_bot_rules_in_order() {
+        for (ua, class) in [
+            (None, DeviceClass::Bot),
+            (Some("curl/8.5.0"), DeviceClass::Bot),
+            (
+                Some("Mozilla/5.0 (compatible; Googlebot/2.1; +http://www.google.com/bot.html)"),
+                DeviceClass::Bot,
+            ),
+            (
+                Some("Mozilla/5.0 (iPhone; CPU iPhone OS 17_0 like Mac OS X) Mobile/15E148"),
+                DeviceClass::Mobile,
+            ),
+            (
+                Some("Mozilla/5.0 (Linux; Android 14; Pixel 8) Chrome/120.0 Mobile Safari/537.36"),
+                DeviceClass::Mobile,
+            ),
+            (
+                Some("Mozilla/5.0 (Windows NT 10.0; Win64; x64) Chrome/120.0 Safari/537.36"),
+                DeviceClass::Desktop,
+            ),
+        ] {
+            assert_eq!(classify(ua), class, "{ua:?}");
+        }
+
+        assert!(parse_rules("block=x").is_err());
+        let devices = Devices {
+            routes: Vec::new(),
+            rules: parse_rules("allow=Googlebot, deny=*").unwrap(),
+        };
+        let check = |ua: &str| devices.allows(Some(ua), classify(Some(ua)));
+        assert!(check("Mozilla/5.0 (compatible; Googlebot/2.1)"));
+        assert!(!check("Mozilla/5.0 (compatible; AhrefsBot/7.0)"));
+        assert!(check("Mozilla/5.0 (Windows NT 10.0; Win64; x64)"));
+        assert!(!devices.allows(None, DeviceClass::Bot));
+    }
+}
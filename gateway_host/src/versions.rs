@@ -0,0 +1,232 @@
+//! Loaded wasm module versions, for swapping the module at runtime and rolling
+//! back when a new one misbehaves.
+//!
+//! Every load copies the module to `WASM_VERSIONS_DIR/<sha256>.wasm` and builds
+//! a backend from that snapshot, so an older version stays runnable after its
+//! source file is overwritten. The last `WASM_KEEP_VERSIONS` backends are kept
+//! built, which makes a rollback a pointer swap.
+
+use anyhow::{anyhow, Context, Result};
+use sha2::{Digest, Sha256};
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::module_verify::ModuleVerifier;
+use crate::transform::Transform;
+use crate::Envelope;
+
+type Loader = Box<dyn Fn(&str) -> Result<Box<dyn Transform>> + Send + Sync>;
+
+#[derive(Clone, Debug)]
+pub(crate) struct VersionInfo {
+    pub(crate) sha256: String,
+    /// Path the module was loaded from.
+    pub(crate) source: String,
+    pub(crate) loaded_at_ms: u64,
+}
+
+impl VersionInfo {
+    pub(crate) fn to_json(&self, active: bool) -> serde_json::Value {
+        serde_json::json!({
+            "sha256": self.sha256,
+            "source": self.source,
+            "loaded_at_ms": self.loaded_at_ms,
+            "active": active,
+        })
+    }
+}
+
+struct Loaded {
+    info: VersionInfo,
+    transform: Arc<dyn Transform>,
+}
+
+/// Newest first; `active` indexes into `loaded`.
+struct History {
+    loaded: VecDeque<Loaded>,
+    active: usize,
+}
+
+pub(crate) struct ModuleVersions {
+    current: RwLock<Arc<dyn Transform>>,
+    history: Mutex<History>,
+    keep: usize,
+    dir: PathBuf,
+    loader: Loader,
+    verifier: Option<ModuleVerifier>,
+}
+
+impl std::fmt::Debug for ModuleVersions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ModuleVersions")
+            .field("keep", &self.keep)
+            .field("dir", &self.dir)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ModuleVersions {
+    /// Loads `module_path` as the first version with `loader`, which builds
+    /// the backend for a module path.
+    pub(crate) fn load_initial(
+        module_path: &str,
+        loader: Loader,
+        verifier: Option<ModuleVerifier>,
+    ) -> Result<Self> {
+        let keep = match std::env::var("WASM_KEEP_VERSIONS") {
+            Ok(v) => v
+                .parse::<usize>()
+                .ok()
+                .filter(|n| *n > 0)
+                .ok_or_else(|| anyhow!("invalid WASM_KEEP_VERSIONS={v} (expected >= 1)"))?,
+            Err(_) => 5,
+        };
+        let dir = std::env::var("WASM_VERSIONS_DIR")
+            .map(PathBuf::from)
+            .unwrap_or_else(|_| std::env::temp_dir().join("gateway_wasm_versions"));
+        std::fs::create_dir_all(&dir)
+            .with_context(|| format!("create WASM_VERSIONS_DIR={}", dir.display()))?;
+        let mut versions = ModuleVersions {
+            current: RwLock::new(Arc::new(crate::transform::Noop)),
+            history: Mutex::new(History {
+                loaded: VecDeque::new(),
+                active: 0,
+            }),
+            keep,
+            dir,
+            loader,
+            verifier,
+        };
+        let first = versions.build(module_path)?;
+        *versions
+            .current
+            .get_mut()
+            .unwrap_or_else(|e| e.into_inner()) = Arc::clone(&first.transform);
+        versions
+            .history
+            .get_mut()
+            .unwrap_or_else(|e| e.into_inner())
+            .loaded
+            .push_front(first);
+        Ok(versions)
+    }
+
+    /// Verifies and snapshots `module_path` and builds a backend from it.
+    fn build(&self, module_path: &str) -> Result<Loaded> {
+        let bytes = std::fs::read(module_path)
+            .with_context(|| format!("read wasm module {module_path}"))?;
+        if let Some(verifier) = self.verifier.as_ref() {
+            verifier.verify_bytes(module_path, &bytes)?;
+        }
+        let sha256 = hex::encode(Sha256::digest(&bytes));
+        let snapshot = self.dir.join(format!("{sha256}.wasm"));
+        if !snapshot.exists() {
+            std::fs::write(&snapshot, &bytes)
+                .with_context(|| format!("write module snapshot {}", snapshot.display()))?;
+        }
+        let snapshot = snapshot.to_string_lossy().into_owned();
+        let transform = (self.loader)(&snapshot)?;
+        let loaded_at_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or(0);
+        eprintln!("[wasm-host] loaded wasm module {module_path} (sha256 {sha256})");
+        Ok(Loaded {
+            info: VersionInfo {
+                sha256,
+                source: module_path.to_string(),
+                loaded_at_ms,
+            },
+            transform: transform.into(),
+        })
+    }
+
+    /// Loads a new version and makes it active.
+    pub(crate) fn load(&self, module_path: &str) -> Result<VersionInfo> {
+        let loaded = self.build(module_path)?;
+        let info = loaded.info.clone();
+        let mut history = self.history.lock().unwrap_or_else(|e| e.into_inner());
+        history.loaded.push_front(loaded);
+        history.active = 0;
+        while history.loaded.len() > self.keep {
+            if let Some(evicted) = history.loaded.pop_back() {
+                self.remove_snapshot(&history, &evicted.info.sha256);
+            }
+        }
+        self.activate(&history);
+        Ok(info)
+    }
+
+    /// Activates the version with `sha256`, or the one loaded before the
+    /// active version when `None`.
+    pub(crate) fn rollback(&self, sha256: Option<&str>) -> Result<VersionInfo> {
+        let mut history = self.history.lock().unwrap_or_else(|e| e.into_inner());
+        let target = match sha256 {
+            Some(sha256) => history
+                .loaded
+                .iter()
+                .position(|l| l.info.sha256.starts_with(sha256))
+                .ok_or_else(|| anyhow!("no loaded version with sha256 {sha256}"))?,
+            None if history.active + 1 < history.loaded.len() => history.active + 1,
+            None => return Err(anyhow!("no older version to roll back to")),
+        };
+        history.active = target;
+        self.activate(&history);
+        let info = history.loaded[target].info.clone();
+        eprintln!(
+            "[wasm-host] rolled back to wasm module {} (sha256 {})",
+            info.source, info.sha256
+        );
+        Ok(info)
+    }
+
+    /// `{"keep": N, "versions": [...]}`, newest first.
+    pub(crate) fn to_json(&self) -> serde_json::Value {
+        let history = self.history.lock().unwrap_or_else(|e| e.into_inner());
+        let versions: Vec<_> = history
+            .loaded
+            .iter()
+            .enumerate()
+            .map(|(i, l)| l.info.to_json(i == history.active))
+            .collect();
+        serde_json::json!({ "keep": self.keep, "versions": versions })
+    }
+
+    pub(crate) fn active(&self) -> VersionInfo {
+        let history = self.history.lock().unwrap_or_else(|e| e.into_inner());
+        history.loaded[history.active].info.clone()
+    }
+
+    fn activate(&self, history: &History) {
+        let transform = Arc::clone(&history.loaded[history.active].transform);
+        *self.current.write().unwrap_or_else(|e| e.into_inner()) = transform;
+    }
+
+    fn remove_snapshot(&self, history: &History, sha256: &str) {
+        if history.loaded.iter().all(|l| l.info.sha256 != sha256) {
+            let snapshot = self.dir.join(format!("{sha256}.wasm"));
+            crate::forget_embedded_wasmtime(&snapshot.to_string_lossy());
+            std::fs::remove_file(snapshot).ok();
+        }
+    }
+
+    fn current(&self) -> Arc<dyn Transform> {
+        Arc::clone(&self.current.read().unwrap_or_else(|e| e.into_inner()))
+    }
+}
+
+impl Transform for ModuleVersions {
+    fn name(&self) -> &'static str {
+        self.current().name()
+    }
+
+    fn transform(&self, input: &[u8], envelope: &Envelope) -> Result<Vec<u8>> {
+        self.current().transform(input, envelope)
+    }
+
+    fn bypass_header(&self) -> &'static str {
+        self.current().bypass_header()
+    }
+}
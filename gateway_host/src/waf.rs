@@ -0,0 +1,531 @@
+//! Built-in checks for common attack patterns (`WAF`), answering `403` with
+//! the id of the rule that matched.
+//!
+//! The path, the query and `application/x-www-form-urlencoded`, JSON, XML and
+//! text bodies are checked, percent-decoded, in this order:
+//!
+//! | rule | matches |
+//! |---|---|
+//! | `null-byte` | a NUL in the path, a parameter or a text body |
+//! | `too-many-params` | more than `WAF_MAX_PARAMS` (default 100) query or form parameters |
+//! | `sqli-union` | `union select` |
+//! | `sqli-tautology` | `or 1=1`, `' or 'a'='a` and the like |
+//! | `sqli-comment` | a quote closed by `--`, `#` or `/*` |
+//! | `sqli-stacked` | `; drop table`, `; delete from` and other stacked statements |
+//! | `sqli-function` | `sleep(`, `benchmark(`, `information_schema`, ... |
+//! | `xss-script` | `<script` |
+//! | `xss-handler` | an `on...=` attribute inside a tag |
+//! | `xss-uri` | `javascript:`, `vbscript:`, `data:text/html` |
+//!
+//! Case, runs of whitespace and `/**/` comments do not matter. `WAF=native`
+//! runs the checks in the gateway; `WAF=wasm` hands the same body and target
+//! to the guest's `waf` mode (`gateway_wasm/src/waf.rs` carries the same
+//! rules), so the two can be compared under load. Operational routes are not
+//! checked.
+
+use anyhow::{anyhow, Result};
+use once_cell::sync::OnceCell;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+
+use crate::{run_transform, transform, Envelope};
+
+/// Rule ids, in the order they are checked.
+pub(crate) const RULES: [&str; 10] = [
+    "null-byte",
+    "too-many-params",
+    "sqli-union",
+    "sqli-tautology",
+    "sqli-comment",
+    "sqli-stacked",
+    "sqli-function",
+    "xss-script",
+    "xss-handler",
+    "xss-uri",
+];
+
+static WAF: OnceCell<Waf> = OnceCell::new();
+
+type Metric = (&'static str, &'static str, Vec<(String, String)>);
+
+#[derive(Debug)]
+pub(crate) struct Waf {
+    engine: Engine,
+    max_params: usize,
+    checks: AtomicU64,
+    check_us: AtomicU64,
+    /// Per `RULES` entry.
+    blocked: [AtomicU64; RULES.len()],
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Engine {
+    Native,
+    Wasm,
+}
+
+impl Engine {
+    fn label(self) -> &'static str {
+        match self {
+            Engine::Native => "native",
+            Engine::Wasm => "wasm",
+        }
+    }
+}
+
+/// A request a rule rejected.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct Violation {
+    pub(crate) rule: &'static str,
+    /// `path`, `query` or `body`.
+    pub(crate) location: &'static str,
+}
+
+/// Reads `WAF` and `WAF_MAX_PARAMS`; `loads_module` says whether the
+/// transform backend runs the guest, which `WAF=wasm` needs. Call once from
+/// `main`.
+pub(crate) fn init_from_env(loads_module: bool) -> Result<()> {
+    let engine = match std::env::var("WAF").as_deref() {
+        Err(_) | Ok("") | Ok("0") => return Ok(()),
+        Ok("native") | Ok("1") => Engine::Native,
+        Ok("wasm") => Engine::Wasm,
+        Ok(other) => return Err(anyhow!("invalid WAF={other} (expected: native|wasm)")),
+    };
+    if engine == Engine::Wasm && !loads_module {
+        return Err(anyhow!("WAF=wasm needs a module-backed TRANSFORM_BACKEND"));
+    }
+    let max_params = match std::env::var("WAF_MAX_PARAMS") {
+        Ok(v) if !v.is_empty() => v
+            .parse::<usize>()
+            .ok()
+            .filter(|&n| n > 0)
+            .ok_or_else(|| anyhow!("invalid WAF_MAX_PARAMS={v} (expected >= 1)"))?,
+        _ => 100,
+    };
+    WAF.get_or_init(|| Waf {
+        engine,
+        max_params,
+        checks: AtomicU64::new(0),
+        check_us: AtomicU64::new(0),
+        blocked: Default::default(),
+    });
+    eprintln!(
+        "[wasm-host] waf: {} rules, {}, at most {max_params} parameters",
+        RULES.len(),
+        engine.label()
+    );
+    Ok(())
+}
+
+pub(crate) fn configured() -> Option<&'static Waf> {
+    WAF.get()
+}
+
+impl Waf {
+    /// Checks one request, natively or in the guest. With the transform
+    /// switched off (`/admin/wasm/enabled`) the guest is skipped and the
+    /// checks run natively.
+    pub(crate) fn check(
+        &self,
+        target: &str,
+        content_type: &str,
+        body: &[u8],
+        transform: &dyn transform::Transform,
+        req_id: &str,
+    ) -> Result<Option<Violation>> {
+        let started = Instant::now();
+        let verdict = match self.engine {
+            Engine::Native => inspect(target, content_type, body, self.max_params),
+            Engine::Wasm => {
+                let mut envelope = Envelope::default();
+                envelope.set("REQ_ID", req_id);
+                envelope.set("MODE", "waf");
+                envelope.set("PATH", target);
+                envelope.set("CONTENT_TYPE", content_type);
+                envelope.set("WAF_MAX_PARAMS", self.max_params.to_string());
+                let output = run_transform(transform, body, &envelope)?;
+                let disabled = envelope
+                    .response_headers
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .iter()
+                    .any(|(k, _)| k == "X-Transform-Disabled");
+                if disabled {
+                    inspect(target, content_type, body, self.max_params)
+                } else {
+                    parse_verdict(&output)?
+                }
+            }
+        };
+        self.checks.fetch_add(1, Ordering::Relaxed);
+        self.check_us
+            .fetch_add(started.elapsed().as_micros() as u64, Ordering::Relaxed);
+        if let Some(violation) = verdict {
+            if let Some(i) = RULES.iter().position(|r| *r == violation.rule) {
+                self.blocked[i].fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        Ok(verdict)
+    }
+
+    /// `(name, help, samples)` for `/metrics`.
+    pub(crate) fn samples(&self) -> Vec<Metric> {
+        let engine = format!("{{engine=\"{}\"}}", self.engine.label());
+        vec![
+            (
+                "gateway_waf_checks_total",
+                "Requests checked by the WAF rules (WAF).",
+                vec![(
+                    engine.clone(),
+                    self.checks.load(Ordering::Relaxed).to_string(),
+                )],
+            ),
+            (
+                "gateway_waf_check_seconds_total",
+                "Time spent in WAF checks, native or in the guest.",
+                vec![(
+                    engine,
+                    (self.check_us.load(Ordering::Relaxed) as f64 / 1_000_000.0).to_string(),
+                )],
+            ),
+            (
+                "gateway_waf_blocked_total",
+                "Requests answered 403 by a WAF rule, by rule.",
+                RULES
+                    .iter()
+                    .zip(&self.blocked)
+                    .map(|(rule, n)| {
+                        (
+                            format!("{{rule=\"{rule}\"}}"),
+                            n.load(Ordering::Relaxed).to_string(),
+                        )
+                    })
+                    .collect(),
+            ),
+        ]
+    }
+
+    pub(crate) fn json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "engine": self.engine.label(),
+            "max_params": self.max_params,
+            "checks": self.checks.load(Ordering::Relaxed),
+            "check_us": self.check_us.load(Ordering::Relaxed),
+            "blocked": RULES
+                .iter()
+                .zip(&self.blocked)
+                .map(|(rule, n)| (rule.to_string(), n.load(Ordering::Relaxed).into()))
+                .collect::<serde_json::Map<String, serde_json::Value>>(),
+        })
+    }
+}
+
+/// The guest answers `pass` or `<rule> <location>`.
+fn parse_verdict(output: &[u8]) -> Result<Option<Violation>> {
+    let output = String::from_utf8_lossy(output);
+    let output = output.trim();
+    if output == "pass" {
+        return Ok(None);
+    }
+    let (rule, location) = output
+        .split_once(' ')
+        .ok_or_else(|| anyhow!("unexpected waf verdict from guest: {output:?}"))?;
+    let rule = RULES
+        .iter()
+        .find(|r| **r == rule)
+        .ok_or_else(|| anyhow!("unknown waf rule from guest: {rule:?}"))?;
+    let location = ["path", "query", "body"]
+        .into_iter()
+        .find(|l| *l == location)
+        .ok_or_else(|| anyhow!("unknown waf location from guest: {location:?}"))?;
+    Ok(Some(Violation { rule, location }))
+}
+
+/// Runs every rule over `target` and `body`; the first match wins.
+pub(crate) fn inspect(
+    target: &str,
+    content_type: &str,
+    body: &[u8],
+    max_params: usize,
+) -> Option<Violation> {
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let at = |rule, location| Some(Violation { rule, location });
+    if decode(path).contains('\0') {
+        return at("null-byte", "path");
+    }
+    if let Some(rule) = check_params(query, max_params) {
+        return at(rule, "query");
+    }
+    let media_type = content_type
+        .split(';')
+        .next()
+        .unwrap_or("")
+        .trim()
+        .to_ascii_lowercase();
+    let rule = if media_type == "application/x-www-form-urlencoded" {
+        check_params(&String::from_utf8_lossy(body), max_params)
+    } else if is_text(&media_type) {
+        let text = String::from_utf8_lossy(body);
+        if text.contains('\0') {
+            Some("null-byte")
+        } else {
+            signature(&text)
+        }
+    } else {
+        None
+    };
+    rule.and_then(|rule| at(rule, "body"))
+}
+
+fn is_text(media_type: &str) -> bool {
+    media_type.is_empty()
+        || media_type.starts_with("text/")
+        || media_type == "application/json"
+        || media_type == "application/xml"
+        || media_type.ends_with("+json")
+        || media_type.ends_with("+xml")
+}
+
+/// `name=value&...`: too many parameters, or a NUL or signature in one.
+fn check_params(params: &str, max_params: usize) -> Option<&'static str> {
+    let pairs: Vec<&str> = params.split('&').filter(|p| !p.is_empty()).collect();
+    if pairs.len() > max_params {
+        return Some("too-many-params");
+    }
+    pairs.iter().find_map(|pair| {
+        let decoded = decode(pair);
+        if decoded.contains('\0') {
+            Some("null-byte")
+        } else {
+            signature(&decoded)
+        }
+    })
+}
+
+/// Percent-decodes `s`, with `+` as a space.
+fn decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .filter(|h| h.iter().all(u8::is_ascii_hexdigit))
+            .and_then(|h| u8::from_str_radix(std::str::from_utf8(h).ok()?, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => {
+                out.push(byte);
+                i += 3;
+            }
+            (b'+', _) => {
+                out.push(b' ');
+                i += 1;
+            }
+            (byte, _) => {
+                out.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// Lowercases `text` and turns each run of whitespace and closed `/* */`
+/// comments into one space.
+fn squash(text: &str) -> String {
+    let lower = text.to_lowercase();
+    let mut out = String::with_capacity(lower.len());
+    let mut rest = lower.as_str();
+    while let Some(c) = rest.chars().next() {
+        let skip = if c.is_whitespace() {
+            c.len_utf8()
+        } else if let Some(end) = rest.strip_prefix("/*").and_then(|r| r.find("*/")) {
+            end + 4
+        } else {
+            out.push(c);
+            rest = &rest[c.len_utf8()..];
+            continue;
+        };
+        if !out.ends_with(' ') {
+            out.push(' ');
+        }
+        rest = &rest[skip..];
+    }
+    out
+}
+
+/// The first SQL injection or XSS rule `text` matches.
+fn signature(text: &str) -> Option<&'static str> {
+    let t = squash(text);
+    let any = |needles: &[&str]| needles.iter().any(|n| t.contains(n));
+    if any(&[
+        "union select",
+        "union all select",
+        "union distinct select",
+        "union(select",
+    ]) {
+        Some("sqli-union")
+    } else if tautology(&t) {
+        Some("sqli-tautology")
+    } else if any(&["'--", "' --", "'#", "' #", "'/*", "' /*", "\"--", "\" --"]) {
+        Some("sqli-comment")
+    } else if stacked(&t) {
+        Some("sqli-stacked")
+    } else if any(&[
+        "sleep(",
+        "benchmark(",
+        "waitfor delay",
+        "load_file(",
+        "into outfile",
+        "information_schema",
+        "xp_cmdshell",
+    ]) {
+        Some("sqli-function")
+    } else if t.contains("<script") {
+        Some("xss-script")
+    } else if handler(&t) {
+        Some("xss-handler")
+    } else if any(&["javascript:", "vbscript:", "data:text/html"]) {
+        Some("xss-uri")
+    } else {
+        None
+    }
+}
+
+/// `or` / `and` followed by a comparison of a value with itself.
+fn tautology(t: &str) -> bool {
+    let unquoted: String = t
+        .chars()
+        .filter(|c| !matches!(c, '\'' | '"' | '(' | ')' | '`'))
+        .collect();
+    let unquoted = unquoted.replace(" =", "=").replace("= ", "=");
+    let words: Vec<&str> = unquoted.split(' ').collect();
+    words.windows(2).any(|w| {
+        matches!(w[0], "or" | "and" | "||" | "&&")
+            && w[1]
+                .split_once('=')
+                .is_some_and(|(left, right)| left == right)
+    })
+}
+
+/// A `;` followed by a destructive statement.
+fn stacked(t: &str) -> bool {
+    t.split(';').skip(1).any(|statement| {
+        let s = statement.trim_start();
+        [
+            "drop table",
+            "drop database",
+            "delete from",
+            "insert into",
+            "truncate table",
+            "alter table",
+            "exec ",
+            "shutdown",
+        ]
+        .iter()
+        .any(|k| s.starts_with(k))
+            || (s.starts_with("update ") && s.contains(" set "))
+    })
+}
+
+/// An `on...=` attribute inside an open tag.
+fn handler(t: &str) -> bool {
+    t.match_indices("on").any(|(i, _)| {
+        let before = t[..i].chars().next_back();
+        let in_tag = t[..i]
+            .rfind('<')
+            .is_some_and(|open| !t[open..i].contains('>'));
+        let name: String = t[i + 2..]
+            .chars()
+            .take_while(char::is_ascii_alphabetic)
+            .collect();
+        matches!(before, Some(' ' | '/' | '"' | '\''))
+            && in_tag
+            && name.len() >= 2
+            && t[i + 2 + name.len()..].trim_start().starts_with('=')
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flags_attacks_and_passes_ordinary_requests() {
+        let check = |target: &str, content_type: &str, body: &str| {
+            inspect(target, content_type, body.as_bytes(), 5).map(|v| (v.rule, v.location))
+        };
+        for (target, content_type, body, expected) in [
+            ("/files/a%00.txt", "", "", ("null-byte", "path")),
+            (
+                "/p?a=1&b=2&c=3&d=4&e=5&f=6",
+                "",
+                "",
+                ("too-many-params", "query"),
+            ),
+            (
+                "/p?id=1%20UNION/**/SELECT%20pass",
+                "",
+                "",
+                ("sqli-union", "query"),
+            ),
+            ("/p?id=1'+OR+'1'='1", "", "", ("sqli-tautology", "query")),
+            ("/p?id=1 or 1 = 1", "", "", ("sqli-tautology", "query")),
+            (
+                "/login",
+                "application/x-www-form-urlencoded",
+                "user=admin'--&pw=x",
+                ("sqli-comment", "body"),
+            ),
+            (
+                "/p?q=1;%20DROP%20TABLE%20users",
+                "",
+                "",
+                ("sqli-stacked", "query"),
+            ),
+            (
+                "/p",
+                "application/json",
+                r#"{"q":"1 AND SLEEP(5)"}"#,
+                ("sqli-function", "body"),
+            ),
+            ("/p?q=%3CScRiPt%3Ealert(1)", "", "", ("xss-script", "query")),
+            (
+                "/p",
+                "text/html",
+                "<img src=x onerror=alert(1)>",
+                ("xss-handler", "body"),
+            ),
+            ("/p?next=javascript:alert(1)", "", "", ("xss-uri", "query")),
+            ("/p", "text/plain", "a\0b", ("null-byte", "body")),
+        ] {
+            assert_eq!(
+                check(target, content_type, body),
+                Some(expected),
+                "{target} {body}"
+            );
+        }
+        for (target, content_type, body) in [
+            ("/search?q=cats+or+dogs&page=2", "", ""),
+            (
+                "/p",
+                "application/json",
+                r#"{"note":"it's online=yes; update the docs"}"#,
+            ),
+            ("/p", "text/html", "<p>we are online=yes</p>"),
+            ("/upload", "application/octet-stream", "<script>\0"),
+        ] {
+            assert_eq!(check(target, content_type, body), None, "{target} {body}");
+        }
+
+        assert_eq!(parse_verdict(b"pass\n").unwrap(), None);
+        assert_eq!(
+            parse_verdict(b"xss-uri query").unwrap(),
+            Some(Violation {
+                rule: "xss-uri",
+                location: "query"
+            })
+        );
+        assert!(parse_verdict(b"wasm:hello").is_err());
+    }
+}
@@ -0,0 +1,263 @@
+//! `/robots.txt` and `/.well-known/*` answered by the gateway itself, ahead
+//! of the bot, country, auth and rate-limit checks, so they never reach the
+//! upstream or the wasm pipeline.
+//!
+//! `ROBOTS_TXT` is a file served as `/robots.txt`, or `allow` / `disallow`
+//! for a robots.txt that lets every crawler in or keeps every one out.
+//! `WELL_KNOWN_DIR` is served under `/.well-known/`, read on each request so
+//! files written later (ACME `acme-challenge/<token>` files from a webroot
+//! client) show up at once. `SECURITY_CONTACT` (e.g.
+//! `mailto:security@example.com`) answers `/.well-known/security.txt` when
+//! the directory has none, with `Expires` a year after startup. Once either
+//! is set, the gateway owns `/.well-known/`: unknown names get `404`. The
+//! `LISTEN_REDIRECT` listener answers these paths too, so HTTP-01 challenges
+//! work on port 80.
+
+use anyhow::{anyhow, Context, Result};
+use once_cell::sync::OnceCell;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::{build_response, route_path, upstream_signing};
+
+const PREFIX: &str = "/.well-known/";
+
+static WELL_KNOWN: OnceCell<WellKnown> = OnceCell::new();
+
+#[derive(Debug)]
+pub(crate) struct WellKnown {
+    robots: Option<Robots>,
+    dir: Option<PathBuf>,
+    /// Generated `security.txt`, if `SECURITY_CONTACT` is set.
+    security_txt: Option<String>,
+}
+
+#[derive(Debug)]
+enum Robots {
+    Text(String),
+    File(PathBuf),
+}
+
+/// Reads `ROBOTS_TXT`, `WELL_KNOWN_DIR` and `SECURITY_CONTACT`. Call once
+/// from `main`.
+pub(crate) fn init_from_env() -> Result<()> {
+    let var = |name: &str| {
+        std::env::var(name)
+            .ok()
+            .map(|v| v.trim().to_string())
+            .filter(|v| !v.is_empty())
+    };
+    let robots = var("ROBOTS_TXT").map(|v| match v.as_str() {
+        "allow" => Robots::Text("User-agent: *\nDisallow:\n".to_string()),
+        "disallow" => Robots::Text("User-agent: *\nDisallow: /\n".to_string()),
+        _ => Robots::File(PathBuf::from(v)),
+    });
+    if let Some(Robots::File(path)) = &robots {
+        std::fs::metadata(path).with_context(|| format!("ROBOTS_TXT={}", path.display()))?;
+    }
+    let dir = var("WELL_KNOWN_DIR").map(PathBuf::from);
+    if let Some(dir) = &dir {
+        if !dir.is_dir() {
+            return Err(anyhow!(
+                "WELL_KNOWN_DIR={} is not a directory",
+                dir.display()
+            ));
+        }
+    }
+    let expires = SystemTime::now() + Duration::from_secs(365 * 86_400);
+    let security_txt = var("SECURITY_CONTACT").map(|contact| security_txt(&contact, expires));
+    if robots.is_none() && dir.is_none() && security_txt.is_none() {
+        return Ok(());
+    }
+    let well_known = WELL_KNOWN.get_or_init(|| WellKnown {
+        robots,
+        dir,
+        security_txt,
+    });
+    let served: Vec<String> = [
+        match &well_known.robots {
+            Some(Robots::File(path)) => Some(format!("/robots.txt from {}", path.display())),
+            Some(Robots::Text(_)) => Some("a built-in /robots.txt".to_string()),
+            None => None,
+        },
+        well_known
+            .dir
+            .as_ref()
+            .map(|dir| format!("{PREFIX} from {}", dir.display())),
+        well_known
+            .security_txt
+            .as_ref()
+            .map(|_| "a generated security.txt".to_string()),
+    ]
+    .into_iter()
+    .flatten()
+    .collect();
+    eprintln!("[wasm-host] serving {}", served.join(", "));
+    Ok(())
+}
+
+pub(crate) fn configured() -> Option<&'static WellKnown> {
+    WELL_KNOWN.get()
+}
+
+fn security_txt(contact: &str, expires: SystemTime) -> String {
+    let unix = expires
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    // `YYYYMMDDTHHMMSSZ` to RFC 3339.
+    let d = upstream_signing::amz_date(unix);
+    format!(
+        "Contact: {contact}\nExpires: {}-{}-{}T{}:{}:{}Z\n",
+        &d[0..4],
+        &d[4..6],
+        &d[6..8],
+        &d[9..11],
+        &d[11..13],
+        &d[13..15]
+    )
+}
+
+/// The file under `dir` for `name`, unless a segment is empty, hidden or
+/// not a plain name.
+fn file_in(dir: &Path, name: &str) -> Option<PathBuf> {
+    let mut path = dir.to_path_buf();
+    for segment in name.split('/') {
+        if segment.is_empty()
+            || segment.starts_with('.')
+            || !segment
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c))
+        {
+            return None;
+        }
+        path.push(segment);
+    }
+    Some(path)
+}
+
+fn content_type(name: &str) -> &'static str {
+    if name.ends_with(".json") || name == "openid-configuration" {
+        "application/json"
+    } else {
+        "text/plain; charset=utf-8"
+    }
+}
+
+impl WellKnown {
+    /// The response for `path`, if the gateway answers it itself.
+    pub(crate) fn response(&self, method: &str, path: &str) -> Result<Option<Vec<u8>>> {
+        let path = route_path(path);
+        let body = if path == "/robots.txt" {
+            match &self.robots {
+                Some(Robots::Text(text)) => Some((text.clone().into_bytes(), "text/plain")),
+                Some(Robots::File(file)) => Some((
+                    std::fs::read(file)
+                        .with_context(|| format!("read ROBOTS_TXT {}", file.display()))?,
+                    "text/plain",
+                )),
+                None => return Ok(None),
+            }
+        } else if let Some(name) = path.strip_prefix(PREFIX) {
+            if self.dir.is_none() && self.security_txt.is_none() {
+                return Ok(None);
+            }
+            let file = self.dir.as_deref().and_then(|dir| file_in(dir, name));
+            let read = match file.as_deref().map(std::fs::read) {
+                Some(Ok(bytes)) => Some(bytes),
+                Some(Err(e))
+                    if matches!(
+                        e.kind(),
+                        std::io::ErrorKind::NotFound | std::io::ErrorKind::IsADirectory
+                    ) =>
+                {
+                    None
+                }
+                Some(Err(e)) => return Err(e).context("read WELL_KNOWN_DIR file"),
+                None => None,
+            };
+            read.or_else(|| {
+                (name == "security.txt")
+                    .then(|| self.security_txt.clone())
+                    .flatten()
+                    .map(String::into_bytes)
+            })
+            .map(|bytes| (bytes, content_type(name)))
+        } else {
+            return Ok(None);
+        };
+        let mut resp = match (method, body) {
+            ("GET" | "HEAD", Some((body, content_type))) => build_response(
+                "HTTP/1.1 200 OK",
+                &body,
+                "well_known",
+                Some(content_type),
+                &[("Cache-Control", "max-age=300")],
+            ),
+            ("GET" | "HEAD", None) => build_response(
+                "HTTP/1.1 404 Not Found",
+                b"not found\n",
+                "well_known",
+                Some("text/plain"),
+                &[],
+            ),
+            _ => build_response(
+                "HTTP/1.1 405 Method Not Allowed",
+                b"method not allowed\n",
+                "well_known",
+                Some("text/plain"),
+                &[("Allow", "GET, HEAD")],
+            ),
+        };
+        if method == "HEAD" {
+            if let Some(end) = resp.windows(4).position(|w| w == b"\r\n\r\n") {
+                resp.truncate(end + 4);
+            }
+        }
+        Ok(Some(resp))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serves_robots_challenges_and_security_txt() {
+        let dir = std::env::temp_dir().join(format!("gateway-well-known-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("acme-challenge")).unwrap();
+        std::fs::write(dir.join("acme-challenge/tok-1"), "tok-1.thumb").unwrap();
+        let well_known = WellKnown {
+            robots: Some(Robots::Text("User-agent: *\nDisallow: /\n".to_string())),
+            dir: Some(dir.clone()),
+            security_txt: Some(security_txt(
+                "mailto:sec@example.com",
+                UNIX_EPOCH + Duration::from_secs(1_440_938_160),
+            )),
+        };
+        let get = |path: &str| {
+            String::from_utf8(well_known.response("GET", path).unwrap().unwrap()).unwrap()
+        };
+
+        assert!(get("/robots.txt").ends_with("\r\n\r\nUser-agent: *\nDisallow: /\n"));
+        assert!(get("/.well-known/acme-challenge/tok-1").ends_with("tok-1.thumb"));
+        assert!(get("/.well-known/security.txt")
+            .ends_with("Contact: mailto:sec@example.com\nExpires: 2015-08-30T12:36:00Z\n"));
+        for missing in [
+            "/.well-known/acme-challenge/nope",
+            "/.well-known/../secret",
+            "/.well-known/acme-challenge",
+        ] {
+            assert!(get(missing).starts_with("HTTP/1.1 404"), "{missing}");
+        }
+        let head = String::from_utf8(well_known.response("HEAD", "/robots.txt").unwrap().unwrap())
+            .unwrap();
+        assert!(head.ends_with("\r\n\r\n"));
+        assert!(
+            String::from_utf8(well_known.response("POST", "/robots.txt").unwrap().unwrap())
+                .unwrap()
+                .starts_with("HTTP/1.1 405")
+        );
+        assert!(well_known.response("GET", "/proxy").unwrap().is_none());
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
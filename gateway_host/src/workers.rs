@@ -0,0 +1,566 @@
+//! Persistent runtime workers for the subprocess backends (`WASM_WORKERS=N`).
+//!
+//! Instead of one `wasmedge` / `wasmtime run` process per request, up to N
+//! long-lived processes run the module with `--worker` and are fed framed
+//! requests on stdin, answering each with one frame on stdout. All lengths are
+//! big-endian u32:
+//!
+//! - request: variable count, then `len` + `KEY=value` per envelope variable,
+//!   then `len` + body
+//! - response: `len` + output
+//!
+//! Workers start on first use and are replaced after
+//! `WASM_WORKER_MAX_REQUESTS` requests, after any failed exchange, and when
+//! killed for `WASM_TIMEOUT_MS`; the replacement is started right away, so
+//! the pool stays at its size. A worker found dead when it is checked out
+//! (crashed or killed while idle) is replaced before the request uses it. A
+//! request finding every worker busy waits up to `WASM_QUEUE_TIMEOUT_MS`
+//! before it gets a 503. Guest stderr is logged line by line, tagged with the
+//! request the worker is serving.
+
+use anyhow::{anyhow, Context, Result};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::process::{Child, ChildStdin, ChildStdout, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::errors::GatewayError;
+use crate::procs::{self, QueueTimeout};
+use crate::sandbox::Sandbox;
+use crate::transform::{self, GuestConfig, Transform, WasmTimeout};
+use crate::{deadline, runtime_command, Envelope};
+
+/// Requests a worker serves before it is replaced, unless
+/// `WASM_WORKER_MAX_REQUESTS` says otherwise.
+const DEFAULT_MAX_REQUESTS: u64 = 1000;
+
+/// Why workers were replaced, for `/metrics` and `/stats`.
+const REPLACED: [&str; 3] = ["exited", "failed", "recycled"];
+
+static POOLS: AtomicBool = AtomicBool::new(false);
+static REPLACEMENTS: [AtomicU64; REPLACED.len()] =
+    [AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0)];
+
+fn replaced(reason: usize) {
+    REPLACEMENTS[reason].fetch_add(1, Ordering::Relaxed);
+}
+
+/// `(labels, value)` per replacement reason, once a worker pool exists.
+pub(crate) fn samples() -> Option<Vec<(String, String)>> {
+    POOLS.load(Ordering::Relaxed).then(|| {
+        REPLACED
+            .iter()
+            .zip(&REPLACEMENTS)
+            .map(|(reason, n)| {
+                (
+                    format!("{{reason=\"{reason}\"}}"),
+                    n.load(Ordering::Relaxed).to_string(),
+                )
+            })
+            .collect()
+    })
+}
+
+#[derive(Debug)]
+pub(crate) struct WorkerPool {
+    runtime: &'static str,
+    module_path: String,
+    timeout: Option<Duration>,
+    sandbox: Sandbox,
+    guest: GuestConfig,
+    settings: WorkerSettings,
+    state: Mutex<PoolState>,
+    available: Condvar,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct WorkerSettings {
+    size: usize,
+    /// `None` keeps workers until they fail.
+    max_requests: Option<u64>,
+}
+
+impl WorkerSettings {
+    /// `Some` when `WASM_WORKERS` is set above 0.
+    pub(crate) fn from_env() -> Result<Option<Self>> {
+        let size = match std::env::var("WASM_WORKERS") {
+            Ok(v) if !v.is_empty() => v
+                .parse::<usize>()
+                .with_context(|| format!("invalid WASM_WORKERS={v}"))?,
+            _ => 0,
+        };
+        if size == 0 {
+            return Ok(None);
+        }
+        let max_requests = match std::env::var("WASM_WORKER_MAX_REQUESTS") {
+            Ok(v) if !v.is_empty() => v
+                .parse::<u64>()
+                .with_context(|| format!("invalid WASM_WORKER_MAX_REQUESTS={v}"))?,
+            _ => DEFAULT_MAX_REQUESTS,
+        };
+        Ok(Some(WorkerSettings {
+            size,
+            max_requests: (max_requests > 0).then_some(max_requests),
+        }))
+    }
+}
+
+#[derive(Debug, Default)]
+struct PoolState {
+    idle: Vec<Worker>,
+    /// Idle plus checked-out workers.
+    live: usize,
+}
+
+#[derive(Debug)]
+struct Worker {
+    child: Arc<Mutex<Child>>,
+    stdin: ChildStdin,
+    stdout: BufReader<ChildStdout>,
+    served: u64,
+    /// Request id the stderr logger tags guest lines with.
+    req_id: Arc<Mutex<String>>,
+}
+
+/// Replacements by reason for `/stats`, once a worker pool exists.
+pub(crate) fn json() -> Option<serde_json::Value> {
+    POOLS.load(Ordering::Relaxed).then(|| {
+        REPLACED
+            .iter()
+            .zip(&REPLACEMENTS)
+            .map(|(reason, n)| (reason.to_string(), n.load(Ordering::Relaxed).into()))
+            .collect::<serde_json::Map<String, serde_json::Value>>()
+            .into()
+    })
+}
+
+impl WorkerPool {
+    pub(crate) fn new(
+        runtime: &'static str,
+        module_path: String,
+        timeout: Option<Duration>,
+        sandbox: Sandbox,
+        guest: GuestConfig,
+        settings: WorkerSettings,
+    ) -> Self {
+        eprintln!(
+            "[wasm-host] wasm workers: up to {} {runtime} process(es), recycled after {}",
+            settings.size,
+            settings
+                .max_requests
+                .map_or("failures only".to_string(), |m| format!("{m} requests"))
+        );
+        POOLS.store(true, Ordering::Relaxed);
+        WorkerPool {
+            runtime,
+            module_path,
+            timeout,
+            sandbox,
+            guest,
+            settings,
+            state: Mutex::new(PoolState::default()),
+            available: Condvar::new(),
+        }
+    }
+
+    /// An idle worker, a newly started one while the pool is below its size,
+    /// or the next one returned within `WASM_QUEUE_TIMEOUT_MS`.
+    fn checkout(&self) -> Result<Worker> {
+        let started = Instant::now();
+        let queue_timeout = procs::queue_timeout();
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        loop {
+            if let Some(worker) = state.idle.pop() {
+                let Some(status) = worker.exited() else {
+                    return Ok(worker);
+                };
+                eprintln!(
+                    "[wasm-host] {} worker exited while idle ({status}), replacing it",
+                    self.runtime
+                );
+                replaced(0);
+                state.live -= 1;
+                continue;
+            }
+            if state.live < self.settings.size {
+                state.live += 1;
+                drop(state);
+                return self.spawn().inspect_err(|_| self.checkin(None));
+            }
+            let left = queue_timeout.saturating_sub(started.elapsed());
+            if left.is_zero() {
+                return Err(QueueTimeout {
+                    waited: started.elapsed(),
+                }
+                .into());
+            }
+            state = self
+                .available
+                .wait_timeout(state, left)
+                .unwrap_or_else(|e| e.into_inner())
+                .0;
+        }
+    }
+
+    /// Returns `worker` to the pool; `None` frees its slot.
+    fn checkin(&self, worker: Option<Worker>) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        match worker {
+            Some(worker) => state.idle.push(worker),
+            None => state.live -= 1,
+        }
+        self.available.notify_one();
+    }
+
+    fn spawn(&self) -> Result<Worker> {
+        let runtime = self.runtime;
+        let mut cmd = runtime_command(runtime, &self.module_path, &[], &self.sandbox, &self.guest)?;
+        let mut child = cmd
+            .arg("--worker")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .context(GatewayError::WasmSpawn)
+            .with_context(|| {
+                format!(
+                    "failed to spawn {runtime} worker for module {}",
+                    self.module_path
+                )
+            })?;
+        let (Some(stdin), Some(stdout), Some(stderr)) =
+            (child.stdin.take(), child.stdout.take(), child.stderr.take())
+        else {
+            child.kill().ok();
+            child.wait().ok();
+            return Err(anyhow!("failed to open pipes for {runtime} worker"));
+        };
+
+        let req_id = Arc::new(Mutex::new("-".to_string()));
+        {
+            let req_id = Arc::clone(&req_id);
+            std::thread::spawn(move || {
+                for line in BufReader::new(stderr).lines() {
+                    let Ok(line) = line else { break };
+                    if line.trim().is_empty() {
+                        continue;
+                    }
+                    let req_id = req_id.lock().unwrap_or_else(|e| e.into_inner()).clone();
+                    eprintln!("[wasm-host] req_id={req_id} guest: {line}");
+                }
+            });
+        }
+
+        let child = Arc::new(Mutex::new(child));
+        procs::register(&child);
+        Ok(Worker {
+            child,
+            stdin,
+            stdout: BufReader::new(stdout),
+            served: 0,
+            req_id,
+        })
+    }
+}
+
+impl Transform for WorkerPool {
+    fn name(&self) -> &'static str {
+        self.runtime
+    }
+
+    fn transform(&self, input: &[u8], envelope: &Envelope) -> Result<Vec<u8>> {
+        let mut worker = self.checkout()?;
+        *worker.req_id.lock().unwrap_or_else(|e| e.into_inner()) =
+            envelope.get("REQ_ID").unwrap_or("-").to_string();
+        let result = worker.call(
+            self.runtime,
+            input,
+            envelope,
+            deadline::clamp_opt(self.timeout, envelope.deadline),
+        );
+        worker.served += 1;
+        let recycle = self
+            .settings
+            .max_requests
+            .is_some_and(|max| worker.served >= max);
+        if result.is_ok() && !recycle {
+            self.checkin(Some(worker));
+        } else {
+            replaced(if result.is_ok() { 2 } else { 1 });
+            drop(worker);
+            // Only forks the runtime; the module starts while the next
+            // request is on its way.
+            match self.spawn() {
+                Ok(fresh) => self.checkin(Some(fresh)),
+                Err(e) => {
+                    eprintln!(
+                        "[wasm-host] replacing {} worker failed: {e:#}",
+                        self.runtime
+                    );
+                    self.checkin(None);
+                }
+            }
+        }
+        result
+            .map(|output| transform::strip_response_envelope(output, envelope))
+            .with_context(|| format!("{} worker for module {}", self.runtime, self.module_path))
+    }
+}
+
+impl Worker {
+    /// The exit status, once the process is gone.
+    fn exited(&self) -> Option<ExitStatus> {
+        let mut child = self.child.lock().unwrap_or_else(|e| e.into_inner());
+        child.try_wait().ok().flatten()
+    }
+
+    /// One request/response exchange, killing the process after `timeout`.
+    fn call(
+        &mut self,
+        runtime: &str,
+        input: &[u8],
+        envelope: &Envelope,
+        timeout: Option<Duration>,
+    ) -> Result<Vec<u8>> {
+        // Same watchdog as the one-shot path: it only kills while holding the
+        // lock and after `try_wait` saw the child still running.
+        let timed_out = Arc::new(AtomicBool::new(false));
+        let (done_tx, done_rx) = mpsc::channel::<()>();
+        if let Some(timeout) = timeout {
+            let child = Arc::clone(&self.child);
+            let timed_out = Arc::clone(&timed_out);
+            std::thread::spawn(move || {
+                if done_rx.recv_timeout(timeout) != Err(RecvTimeoutError::Timeout) {
+                    return;
+                }
+                let mut child = child.lock().unwrap_or_else(|e| e.into_inner());
+                if matches!(child.try_wait(), Ok(None)) {
+                    timed_out.store(true, Ordering::Relaxed);
+                    child.kill().ok();
+                }
+            });
+        }
+        let result = self.exchange(input, envelope);
+        drop(done_tx);
+
+        if timed_out.load(Ordering::Relaxed) {
+            return Err(anyhow::Error::new(WasmTimeout {
+                timeout: timeout.unwrap_or_default(),
+            })
+            .context(format!("{runtime} worker killed")));
+        }
+        result.map_err(|e| {
+            let mut child = self.child.lock().unwrap_or_else(|e| e.into_inner());
+            match child.try_wait() {
+                Ok(Some(status)) => anyhow::Error::new(GatewayError::WasmTrap)
+                    .context(format!("{runtime} worker exited with status {status}")),
+                _ => anyhow::Error::new(e).context(format!("{runtime} worker exchange failed")),
+            }
+        })
+    }
+
+    fn exchange(&mut self, input: &[u8], envelope: &Envelope) -> std::io::Result<Vec<u8>> {
+        let mut frame = Vec::with_capacity(input.len() + 256);
+        frame.extend_from_slice(&(envelope.vars.len() as u32).to_be_bytes());
+        for (key, value) in &envelope.vars {
+            put_chunk(&mut frame, format!("{key}={value}").as_bytes());
+        }
+        put_chunk(&mut frame, input);
+        self.stdin.write_all(&frame)?;
+        self.stdin.flush()?;
+
+        let mut len = [0u8; 4];
+        self.stdout.read_exact(&mut len)?;
+        let mut output = vec![0u8; u32::from_be_bytes(len) as usize];
+        self.stdout.read_exact(&mut output)?;
+        Ok(output)
+    }
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        let mut child = self.child.lock().unwrap_or_else(|e| e.into_inner());
+        child.kill().ok();
+        child.wait().ok();
+    }
+}
+
+fn put_chunk(frame: &mut Vec<u8>, bytes: &[u8]) {
+    frame.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+    frame.extend_from_slice(bytes);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::errors;
+    use std::sync::OnceLock;
+
+    /// Stands in for `wasmtime run <module> --worker`: answers each body with
+    /// `<pid>:<body>`, exits on `crash` and hangs on `hang`.
+    const FAKE_RUNTIME: &str = r#"#!/bin/sh
+num() { od -An -tu1 | { read a b c d && echo $(( (a << 24) | (b << 16) | (c << 8) | d )); }; }
+byte() { printf "\\$(printf %03o "$1")"; }
+while :; do
+    vars=$(head -c 4 | num)
+    [ -n "$vars" ] || exit 0
+    while [ "$vars" -gt 0 ]; do
+        head -c "$(head -c 4 | num)" >/dev/null
+        vars=$((vars - 1))
+    done
+    body=$(head -c "$(head -c 4 | num)")
+    case $body in
+        crash) exit 3 ;;
+        hang) exec sleep 60 ;;
+    esac
+    out="$$:$body"
+    n=${#out}
+    byte $((n >> 24 & 255)); byte $((n >> 16 & 255)); byte $((n >> 8 & 255)); byte $((n & 255))
+    printf %s "$out"
+done
+"#;
+
+    /// Puts `FAKE_RUNTIME` first on `PATH` as `wasmtime`, once per process.
+    fn fake_runtime() {
+        static DIR: OnceLock<std::path::PathBuf> = OnceLock::new();
+        DIR.get_or_init(|| {
+            use std::os::unix::fs::PermissionsExt;
+
+            let dir = std::env::temp_dir().join(format!("fake-runtime-{}", std::process::id()));
+            std::fs::create_dir_all(&dir).unwrap();
+            let bin = dir.join("wasmtime");
+            std::fs::write(&bin, FAKE_RUNTIME).unwrap();
+            std::fs::set_permissions(&bin, std::fs::Permissions::from_mode(0o755)).unwrap();
+            let path = std::env::var_os("PATH").unwrap_or_default();
+            let mut paths = vec![dir.clone()];
+            paths.extend(std::env::split_paths(&path));
+            std::env::set_var("PATH", std::env::join_paths(paths).unwrap());
+            dir
+        });
+    }
+
+    fn pool(size: usize, max_requests: Option<u64>, timeout: Option<Duration>) -> WorkerPool {
+        fake_runtime();
+        WorkerPool::new(
+            "wasmtime",
+            "guest.wasm".to_string(),
+            timeout,
+            Sandbox::default(),
+            GuestConfig::default(),
+            WorkerSettings { size, max_requests },
+        )
+    }
+
+    /// The pid of the worker that answered `body`.
+    fn call(pool: &WorkerPool, body: &str) -> String {
+        let out = pool
+            .transform(body.as_bytes(), &Envelope::default())
+            .unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let (pid, echoed) = out.split_once(':').unwrap();
+        assert_eq!(echoed, body);
+        pid.to_string()
+    }
+
+    fn idle_pid(pool: &WorkerPool) -> u32 {
+        pool.state.lock().unwrap().idle[0]
+            .child
+            .lock()
+            .unwrap()
+            .id()
+    }
+
+    #[test]
+    fn workers_are_reused_and_get_the_envelope() {
+        let pool = pool(1, None, None);
+        let mut envelope = Envelope::default();
+        envelope.set("PATH", "/api");
+        envelope.set("REQ_ID", "r-1");
+        let out = pool.transform(b"a", &envelope).unwrap();
+        let pid = call(&pool, "b");
+        assert_eq!(out, format!("{pid}:a").into_bytes());
+        assert_eq!(call(&pool, ""), pid);
+    }
+
+    #[test]
+    fn crashed_worker_is_replaced_right_away() {
+        let pool = pool(1, None, None);
+        let first = call(&pool, "a");
+        let err = pool.transform(b"crash", &Envelope::default()).unwrap_err();
+        // Depending on whether the exit is reaped before the read fails, it
+        // reports as a trap or as a broken exchange; either way not a timeout.
+        assert_ne!(errors::classify(&err, true), GatewayError::WasmTimeout);
+        let msg = format!("{err:#}");
+        assert!(
+            msg.contains("worker exited with status") || msg.contains("worker exchange failed"),
+            "{msg}"
+        );
+
+        // The replacement is already idle, and the pool stays at its size.
+        assert_ne!(idle_pid(&pool).to_string(), first);
+        assert_eq!(pool.state.lock().unwrap().live, 1);
+        assert_ne!(call(&pool, "b"), first);
+    }
+
+    #[test]
+    fn worker_killed_while_idle_is_replaced_on_checkout() {
+        let pool = pool(1, None, None);
+        let first = call(&pool, "a");
+        let pid = idle_pid(&pool);
+        assert_eq!(pid.to_string(), first);
+        // SAFETY: `pid` is our own child, not yet reaped.
+        assert_eq!(unsafe { libc::kill(pid as libc::pid_t, libc::SIGKILL) }, 0);
+        let started = Instant::now();
+        while pool.state.lock().unwrap().idle[0].exited().is_none() {
+            assert!(started.elapsed() < Duration::from_secs(5));
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        let exited = REPLACEMENTS[0].load(Ordering::Relaxed);
+        assert_ne!(call(&pool, "b"), first);
+        assert!(REPLACEMENTS[0].load(Ordering::Relaxed) > exited);
+        assert_eq!(pool.state.lock().unwrap().live, 1);
+    }
+
+    #[test]
+    fn workers_are_recycled_after_max_requests() {
+        let pool = pool(1, Some(2), None);
+        let first = call(&pool, "a");
+        assert_eq!(call(&pool, "b"), first);
+        let second = call(&pool, "c");
+        assert_ne!(second, first);
+        assert_eq!(call(&pool, "d"), second);
+        assert_ne!(call(&pool, "e"), second);
+    }
+
+    #[test]
+    fn hung_worker_is_killed_at_the_timeout_and_replaced() {
+        let pool = pool(1, None, Some(Duration::from_millis(200)));
+        let first = call(&pool, "a");
+        let started = Instant::now();
+        let err = pool.transform(b"hang", &Envelope::default()).unwrap_err();
+        assert_eq!(errors::classify(&err, true), GatewayError::WasmTimeout);
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_ne!(call(&pool, "b"), first);
+    }
+
+    #[test]
+    fn busy_pool_grows_to_its_size_then_waits() {
+        let pool = pool(2, None, None);
+        let a = pool.checkout().unwrap();
+        let b = pool.checkout().unwrap();
+        assert_eq!(pool.state.lock().unwrap().live, 2);
+
+        // A waiting request gets the next worker checked back in.
+        std::thread::scope(|s| {
+            let waiter = s.spawn(|| pool.checkout().map(|w| w.child.lock().unwrap().id()));
+            std::thread::sleep(Duration::from_millis(50));
+            let returned = a.child.lock().unwrap().id();
+            pool.checkin(Some(a));
+            assert_eq!(waiter.join().unwrap().unwrap(), returned);
+        });
+        drop(b);
+    }
+}
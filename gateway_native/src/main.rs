@@ -1,116 +1,292 @@
 use anyhow::{anyhow, Context, Result};
-use once_cell::sync::Lazy;
-use sha2::{Digest, Sha256};
 use std::env;
-use std::io::{Read, Write};
+use std::io::Write;
 use std::net::{Shutdown, TcpListener, TcpStream};
-use std::sync::atomic::{AtomicU64, Ordering};
-use std::time::{Duration, Instant};
-use url::Url;
+use std::time::Instant;
 use uuid::Uuid;
 
-const MAX_HEADER_BYTES: usize = 64 * 1024;
-const MAX_BODY_BYTES: usize = 2 * 1024 * 1024;
-const IO_TIMEOUT: Duration = Duration::from_secs(5);
-const GATEWAY_VARIANT: &str = "native";
-
-static COUNTER: Lazy<AtomicU64> = Lazy::new(|| AtomicU64::new(0));
-
-fn cpu_heavy(iters: u64) -> String {
-    let mut hash = [0u8; 32];
+use gateway_core::errors::GatewayError;
+use gateway_core::http::{
+    self, bearer_token_matches, build_forwarded_request, build_response, parse_request_head,
+    parse_status_code_from_head, query_param, read_all_response, read_http_body, read_http_head,
+    rebuild_response, route_path, split_http_response, Upstream, IO_TIMEOUT,
+};
+use gateway_core::{
+    accept, affinity, allocator, balancer, chunked, cookies, dns, fingerprint, headers, health,
+    json_string, logging, profiling, registry, secrets, state, store, workloads,
+};
+use headers::Headers;
 
-    for i in 0..iters {
-        let mut hasher = Sha256::new();
-        hasher.update(hash);
-        hasher.update(i.to_le_bytes());
-        hash = hasher.finalize().into();
-    }
-
-    hex::encode(hash)
-}
-
-// simple query parser for /compute?iters=123
-fn query_param(path: &str, key: &str) -> Option<String> {
-    let q = path.splitn(2, '?').nth(1)?;
-    for pair in q.split('&') {
-        let mut it = pair.splitn(2, '=');
-        let k = it.next().unwrap_or("");
-        let v = it.next().unwrap_or("");
-        if k == key {
-            return Some(v.to_string());
-        }
-    }
-    None
-}
+const GATEWAY_VARIANT: &str = "native";
 
 fn main() -> Result<()> {
+    gateway_core::set_variant(GATEWAY_VARIANT);
+    logging::init_from_env()?;
     env_logger::init();
+    secrets::init_from_env()?;
+    health::mark_started();
+    accept::raise_nofile_limit();
+    affinity::pin_process_from_env()?;
 
     let listen = env::var("LISTEN").unwrap_or_else(|_| "0.0.0.0:8080".to_string());
-    let upstream_url =
-        env::var("UPSTREAM_URL").unwrap_or_else(|_| "http://127.0.0.1:18080".to_string());
-
-    let upstream = parse_upstream(&upstream_url)?;
+    let listen_internal = env::var("LISTEN_INTERNAL").ok().filter(|v| !v.is_empty());
+    // `dns+` / `srv+` members are resolved here, once.
+    let upstream_pool = match env::var("UPSTREAM_POOL")
+        .ok()
+        .filter(|v| !v.trim().is_empty())
+    {
+        Some(spec) => Some(dns::expand_pool(&balancer::parse_pool(&spec)?)?),
+        None if dns::configured() => {
+            return Err(anyhow!("DNS_SERVER is set but UPSTREAM_POOL is not"));
+        }
+        None => None,
+    };
+    // With a pool, `UPSTREAM_URL` (still used by the `/health/full` probe)
+    // defaults to its first member.
+    let upstream_url = env::var("UPSTREAM_URL").unwrap_or_else(|_| {
+        upstream_pool
+            .as_ref()
+            .and_then(|members| members.first())
+            .map_or_else(
+                || "http://127.0.0.1:18080".to_string(),
+                |(url, _)| url.clone(),
+            )
+    });
+
+    let health_token = secrets::lookup("HEALTH_TOKEN");
+    let store = store::SharedStore::from_env()?;
+    let state = state::State::from_env()?;
+    let registration = registry::Registration::from_env(&listen, &state.replica_id)?;
+
+    let config = Config {
+        upstream: parse_upstream(&upstream_url)?,
+        pool: upstream_pool
+            .as_deref()
+            .map(parse_upstream_pool)
+            .transpose()?,
+        health_token,
+        cookies: cookies::CookieConfig::from_env()?,
+        store,
+        state,
+        internal_listener: listen_internal.is_some(),
+    };
     let listener = TcpListener::bind(&listen).with_context(|| format!("bind LISTEN={listen}"))?;
+    let internal_listener = listen_internal
+        .as_deref()
+        .map(|addr| TcpListener::bind(addr).with_context(|| format!("bind LISTEN_INTERNAL={addr}")))
+        .transpose()?;
 
     eprintln!("[native] listening on http://{listen}");
-    eprintln!("[native] forwarding to {upstream_url}");
+    if let Some(addr) = listen_internal.as_deref() {
+        eprintln!("[native] operational endpoints on http://{addr}");
+    }
+    match config.pool.as_ref() {
+        Some(pool) => {
+            let members: Vec<String> = pool
+                .members()
+                .iter()
+                .map(|m| format!("{} (weight {})", m.name, m.weight))
+                .collect();
+            eprintln!("[native] balancing over {}", members.join(", "));
+        }
+        None => eprintln!("[native] forwarding to {upstream_url}"),
+    }
+    eprintln!(
+        "[native] replica {} state backend: {:?} (store: {})",
+        config.state.replica_id,
+        config.state.backend,
+        config.store.kind()
+    );
+
+    std::thread::scope(|scope| {
+        if let Some(internal) = internal_listener.as_ref() {
+            let config = &config;
+            scope.spawn(move || serve(internal, config, true));
+        }
+        if let Some(registration) = registration.as_ref() {
+            let config = &config;
+            scope.spawn(move || registration.run(|| health_report(config)));
+        }
+        serve(&listener, &config, false);
+    });
+
+    Ok(())
+}
 
-    for incoming in listener.incoming() {
-        match incoming {
-            Ok(mut client) => {
-                if let Err(e) = handle_client(&mut client, &upstream) {
-                    eprintln!("[native] client error: {e:#}");
+/// Accept loop for one listener. `internal` marks the `LISTEN_INTERNAL`
+/// socket, which only serves operational routes. Its connections get a thread
+/// each, so a long `/debug/pprof/profile` does not hold up health checks.
+fn serve(listener: &TcpListener, config: &Config, internal: bool) {
+    let mut backoff = accept::AcceptBackoff::default();
+    std::thread::scope(|scope| {
+        for incoming in listener.incoming() {
+            match incoming {
+                Ok(client) if internal => {
+                    backoff.accepted();
+                    scope.spawn(move || serve_connection(client, config, internal));
+                }
+                Ok(client) => {
+                    backoff.accepted();
+                    serve_connection(client, config, internal);
                 }
+                Err(e) => backoff.failed(&e),
             }
-            Err(e) => eprintln!("[native] accept error: {e}"),
         }
+    });
+}
+
+fn serve_connection(mut client: TcpStream, config: &Config, internal: bool) {
+    if let Err(e) = handle_client(&mut client, config, internal) {
+        eprintln!("[native] client error: {e:#}");
     }
+}
 
-    Ok(())
+#[derive(Debug)]
+struct Config {
+    upstream: Upstream,
+    /// `UPSTREAM_POOL`, balanced with outlier ejection; replaces `upstream`
+    /// on the proxy path.
+    pool: Option<balancer::Balancer<Upstream>>,
+    /// When set, `/health/full` requires `Authorization: Bearer <token>`.
+    health_token: Option<secrets::Secret>,
+    /// `COOKIE_*` rewrites of proxied requests and responses.
+    cookies: Option<cookies::CookieConfig>,
+    /// Counters shared across replicas (`SHARED_STORE_URL`), in-process otherwise.
+    store: store::SharedStore,
+    /// The `/state` counter (`STATE_BACKEND`) and this replica's id.
+    state: state::State,
+    /// `LISTEN_INTERNAL` is set: operational routes are served there only.
+    internal_listener: bool,
 }
 
-#[derive(Clone, Debug)]
-struct Upstream {
-    host: String,
-    port: u16,
-    base_path: String,
-    raw_url: String,
+/// `(url, weight)` members, with the outlier settings from `POOL_*`.
+fn parse_upstream_pool(members: &[(String, u32)]) -> Result<balancer::Balancer<Upstream>> {
+    let members = members
+        .iter()
+        .map(|(url, weight)| {
+            let upstream = parse_upstream(url)
+                .with_context(|| format!("invalid UPSTREAM_POOL member {url:?}"))?;
+            Ok((upstream.raw_url.clone(), *weight, upstream))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok(balancer::Balancer::new(
+        members,
+        balancer::OutlierSettings::from_env()?,
+    ))
 }
 
+/// An `http://` upstream; `https://` needs `gateway_host`.
 fn parse_upstream(s: &str) -> Result<Upstream> {
-    let url = Url::parse(s).with_context(|| format!("invalid UPSTREAM_URL={s}"))?;
-    if url.scheme() != "http" {
-        return Err(anyhow!(
-            "only http upstream supported (got scheme {})",
-            url.scheme()
-        ));
+    let upstream = http::parse_upstream(s)?;
+    if upstream.tls {
+        return Err(anyhow!("only http upstream supported (got scheme https)"));
+    }
+    Ok(upstream)
+}
+
+fn handle_client(client: &mut TcpStream, config: &Config, internal: bool) -> Result<()> {
+    let result = handle_request(client, config, internal);
+    if let Err(e) = &result {
+        // Answer failures tagged with a kind; see `errors`. Untagged ones are
+        // mostly the client going away mid-response, so the connection is
+        // just dropped. The client gets the kind only; the chain is logged
+        // by `serve_connection`.
+        let kind = GatewayError::classify(e, true);
+        if kind != GatewayError::Internal {
+            let mut headers = kind.headers().to_vec();
+            headers.push(("X-Gateway-Error-Code", kind.code()));
+            let resp = build_response(
+                kind.status_line(),
+                format!("{kind}\n").as_bytes(),
+                kind.workload(),
+                Some("text/plain"),
+                &headers,
+            );
+            client.write_all(&resp).ok();
+            client.flush().ok();
+            client.shutdown(Shutdown::Both).ok();
+        }
     }
-    let host = url
-        .host_str()
-        .ok_or_else(|| anyhow!("UPSTREAM_URL missing host"))?
-        .to_string();
-    let port = url
-        .port_or_known_default()
-        .ok_or_else(|| anyhow!("UPSTREAM_URL missing port"))?;
-    let base_path = url.path().trim_end_matches('/').to_string();
-    Ok(Upstream {
-        host,
-        port,
-        base_path,
-        raw_url: s.to_string(),
-    })
+    result
 }
 
-fn handle_client(client: &mut TcpStream, upstream: &Upstream) -> Result<()> {
+fn handle_request(client: &mut TcpStream, config: &Config, internal: bool) -> Result<()> {
+    let upstream = &config.upstream;
     client.set_read_timeout(Some(IO_TIMEOUT)).ok();
     client.set_write_timeout(Some(IO_TIMEOUT)).ok();
 
     let req_id = Uuid::new_v4();
     let start = Instant::now();
+    let mut allocs = allocator::RequestAllocs::start();
+
+    let (head_bytes, remainder) = read_http_head(client).context(GatewayError::ClientBadRequest)?;
+    let req = parse_request_head(&head_bytes).context(GatewayError::ClientBadRequest)?;
+    allocs.route(&req.path);
+
+    // With `LISTEN_INTERNAL`, operational routes live only on that listener
+    // and it serves nothing else; plain `/health` stays on both. `/debug/*`
+    // is never served on `LISTEN`.
+    let debug = route_path(&req.path).starts_with("/debug/");
+    let operational = debug || matches!(route_path(&req.path), "/health/full" | "/stats");
+    let misrouted = if internal {
+        !operational && req.path != "/health"
+    } else {
+        operational && (config.internal_listener || debug)
+    };
+    if misrouted {
+        let resp = build_response(
+            "HTTP/1.1 404 Not Found",
+            b"not found",
+            "not-found",
+            Some("text/plain"),
+            &[],
+        );
+        client.write_all(&resp).ok();
+        client.flush().ok();
+        client.shutdown(Shutdown::Both).ok();
+        return Ok(());
+    }
+
+    if req.content_length > remainder.len()
+        && req
+            .header("Expect")
+            .is_some_and(|v| v.eq_ignore_ascii_case("100-continue"))
+    {
+        client.write_all(b"HTTP/1.1 100 Continue\r\n\r\n")?;
+        client.flush()?;
+    }
+
+    if req.method == "POST" && route_path(&req.path) == "/upload" {
+        let summary = workloads::upload_summary(client, remainder, req.content_length)?;
+        let resp = build_response(
+            "HTTP/1.1 200 OK",
+            summary.as_bytes(),
+            "upload",
+            Some("application/json"),
+            &[],
+        );
+        client.write_all(&resp)?;
+        client.flush().ok();
+        client.shutdown(Shutdown::Both).ok();
+        return Ok(());
+    }
 
-    let (head_bytes, body_bytes) = read_http_request(client)?;
-    let req = parse_request_head(&head_bytes)?;
+    let body_bytes = read_http_body(client, remainder, req.content_length)?;
+
+    if (req.method == "GET" || req.method == "POST") && route_path(&req.path) == "/echo" {
+        let body = workloads::echo_json(&req, &body_bytes, upstream)?;
+        let resp = build_response(
+            "HTTP/1.1 200 OK",
+            body.as_bytes(),
+            "echo",
+            Some("application/json"),
+            &[],
+        );
+        client.write_all(&resp)?;
+        client.flush().ok();
+        client.shutdown(Shutdown::Both).ok();
+        return Ok(());
+    }
 
     if req.method == "GET" && req.path == "/health" {
         let resp = build_response("HTTP/1.1 200 OK", b"OK", "health", Some("text/plain"), &[]);
@@ -120,6 +296,99 @@ fn handle_client(client: &mut TcpStream, upstream: &Upstream) -> Result<()> {
         return Ok(());
     }
 
+    let health_token = config.health_token.as_ref().map(secrets::Secret::reveal);
+    if req.method == "GET" && route_path(&req.path) == "/health/full" {
+        let resp = if !bearer_token_matches(&req, health_token.as_deref()) {
+            build_response(
+                "HTTP/1.1 401 Unauthorized",
+                b"unauthorized",
+                "health",
+                Some("text/plain"),
+                &[("WWW-Authenticate", "Bearer")],
+            )
+        } else {
+            let (healthy, body) = health_report(config);
+            let status = if healthy {
+                "HTTP/1.1 200 OK"
+            } else {
+                "HTTP/1.1 503 Service Unavailable"
+            };
+            build_response(
+                status,
+                body.as_bytes(),
+                "health",
+                Some("application/json"),
+                &[],
+            )
+        };
+        client.write_all(&resp).ok();
+        client.flush().ok();
+        client.shutdown(Shutdown::Both).ok();
+        return Ok(());
+    }
+
+    if req.method == "GET" && route_path(&req.path) == "/stats" {
+        let body = format!(
+            "{{\"variant\":{},\"uptime_s\":{:.3},\"fingerprint\":{}}}",
+            json_string(GATEWAY_VARIANT),
+            health::uptime().as_secs_f64(),
+            fingerprint::get().to_json()
+        );
+        let resp = build_response(
+            "HTTP/1.1 200 OK",
+            body.as_bytes(),
+            "stats",
+            Some("application/json"),
+            &[],
+        );
+        client.write_all(&resp).ok();
+        client.flush().ok();
+        client.shutdown(Shutdown::Both).ok();
+        return Ok(());
+    }
+
+    if req.method == "GET" && route_path(&req.path) == profiling::ROUTE {
+        let resp = if !bearer_token_matches(&req, health_token.as_deref()) {
+            build_response(
+                "HTTP/1.1 401 Unauthorized",
+                b"unauthorized",
+                "profile",
+                Some("text/plain"),
+                &[("WWW-Authenticate", "Bearer")],
+            )
+        } else {
+            profiling::response(&req)
+        };
+        client.write_all(&resp).ok();
+        client.flush().ok();
+        client.shutdown(Shutdown::Both).ok();
+        return Ok(());
+    }
+
+    if req.method == "GET" && route_path(&req.path) == allocator::ROUTE {
+        let resp = if !bearer_token_matches(&req, health_token.as_deref()) {
+            build_response(
+                "HTTP/1.1 401 Unauthorized",
+                b"unauthorized",
+                "allocator",
+                Some("text/plain"),
+                &[("WWW-Authenticate", "Bearer")],
+            )
+        } else {
+            build_response(
+                "HTTP/1.1 200 OK",
+                allocator::report_json().as_bytes(),
+                "allocator",
+                Some("application/json"),
+                &[],
+            )
+        };
+        client.write_all(&resp).ok();
+        client.flush().ok();
+        client.shutdown(Shutdown::Both).ok();
+        return Ok(());
+    }
+
     if req.method == "GET" && (req.path == "/" || req.path.starts_with("/?")) {
         let resp = build_response(
             "HTTP/1.1 200 OK",
@@ -139,13 +408,31 @@ fn handle_client(client: &mut TcpStream, upstream: &Upstream) -> Result<()> {
             .and_then(|v| v.parse::<u64>().ok())
             .unwrap_or(50_000);
 
-        let result = cpu_heavy(iters);
+        let seed = query_param(&req.path, "seed").and_then(|v| v.parse::<u64>().ok());
+        let seed_str = seed.map(|s| s.to_string());
+        let seed_headers: Vec<(&str, &str)> = seed_str
+            .as_deref()
+            .map(|s| vec![("X-Workload-Seed", s)])
+            .unwrap_or_default();
+
+        if query_param(&req.path, "stream").is_some_and(|v| v == "1" || v == "true") {
+            let every = query_param(&req.path, "progress_every")
+                .and_then(|v| v.parse::<u64>().ok())
+                .unwrap_or((iters / 10).max(1));
+            workloads::stream_compute(client, iters, seed, every, &seed_headers, |result| {
+                Ok(result.into_bytes())
+            })?;
+            client.shutdown(Shutdown::Both).ok();
+            return Ok(());
+        }
+
+        let result = workloads::cpu_heavy(iters, seed);
         let resp = build_response(
             "HTTP/1.1 200 OK",
             result.as_bytes(),
             "compute",
             Some("text/plain"),
-            &[],
+            &seed_headers,
         );
         client.write_all(&resp)?;
         client.flush().ok();
@@ -153,15 +440,37 @@ fn handle_client(client: &mut TcpStream, upstream: &Upstream) -> Result<()> {
         return Ok(());
     }
 
+    // Baseline for the wasm-host /transform workload: the body is returned as-is.
+    if req.method == "POST" && route_path(&req.path) == "/transform" {
+        let content_type = req
+            .header("Content-Type")
+            .unwrap_or("application/octet-stream")
+            .to_string();
+        let resp = build_response(
+            "HTTP/1.1 200 OK",
+            &body_bytes,
+            "transform",
+            Some(&content_type),
+            &[],
+        );
+        client.write_all(&resp)?;
+        client.flush().ok();
+        client.shutdown(Shutdown::Both).ok();
+        return Ok(());
+    }
+
     if req.method == "GET" && req.path.starts_with("/state") {
-        let value = COUNTER.fetch_add(1, Ordering::SeqCst);
+        let value = config
+            .state
+            .next_value(&config.store)
+            .context("/state counter")?;
         let body_str = value.to_string();
         let resp = build_response(
             "HTTP/1.1 200 OK",
             body_str.as_bytes(),
             "state",
             Some("text/plain"),
-            &[],
+            &[("X-Replica-Id", config.state.replica_id.as_str())],
         );
         client.write_all(&resp)?;
         client.flush().ok();
@@ -169,298 +478,203 @@ fn handle_client(client: &mut TcpStream, upstream: &Upstream) -> Result<()> {
         return Ok(());
     }
 
+    // An unfinished pick counts against its pool member.
+    let pick = config.pool.as_ref().and_then(balancer::Balancer::pick);
+    let upstream = pick.as_deref().unwrap_or(upstream);
     let mut upstream_stream = TcpStream::connect((&*upstream.host, upstream.port))
+        .context(GatewayError::UpstreamConnect)
         .with_context(|| format!("connect upstream {}:{}", upstream.host, upstream.port))?;
     upstream_stream.set_read_timeout(Some(IO_TIMEOUT)).ok();
     upstream_stream.set_write_timeout(Some(IO_TIMEOUT)).ok();
 
-    let forwarded = build_forwarded_request(&req, &head_bytes, &body_bytes, upstream)?;
-    upstream_stream.write_all(&forwarded)?;
-    upstream_stream.flush()?;
-
-    let resp_bytes = read_all_response(&mut upstream_stream)?;
-    let (resp_head, resp_body) = split_http_response(&resp_bytes)?;
-    let upstream_status = parse_status_code_from_head(&resp_head)?;
+    // `COOKIE_DROP` applies to the client's cookies too; an empty value
+    // removes the `Cookie` header entirely.
+    let request_cookies: Vec<(&str, &str)> = req
+        .headers
+        .get_all("cookie")
+        .flat_map(cookies::parse_cookie_header)
+        .collect();
+    let cookie_header = config
+        .cookies
+        .as_ref()
+        .filter(|_| !request_cookies.is_empty())
+        .map(|cookie_cfg| {
+            let kept: Vec<String> = cookie_cfg
+                .filter_request(request_cookies)
+                .iter()
+                .map(|(k, v)| format!("{k}={v}"))
+                .collect();
+            kept.join("; ")
+        });
+    let forward_headers: Vec<(&str, &str)> = cookie_header
+        .iter()
+        .map(|cookie| ("Cookie", cookie.as_str()))
+        .collect();
+    let forwarded =
+        build_forwarded_request(&req, &req.path, &body_bytes, upstream, &forward_headers)?;
+    let resp_bytes = (|| -> Result<Vec<u8>> {
+        upstream_stream.write_all(&forwarded)?;
+        upstream_stream.flush()?;
+        read_all_response(&mut upstream_stream)
+    })()
+    .map_err(GatewayError::upstream)?;
+    let (resp_head, resp_body) =
+        split_http_response(&resp_bytes).context(GatewayError::Upstream)?;
+    let upstream_status =
+        parse_status_code_from_head(&resp_head).context(GatewayError::Upstream)?;
     let upstream_status_str = upstream_status.to_string();
+    let (status_line, mut resp_headers) =
+        Headers::parse_head(&resp_head).context(GatewayError::Upstream)?;
+    let bodyless = req.method == "HEAD" || matches!(upstream_status, 100..=199 | 204 | 304);
+    let (resp_body, trailers) = chunked::decode_response(&resp_headers, resp_body, bodyless)
+        .context(GatewayError::UpstreamFraming)?;
+    if let Some(pick) = &pick {
+        pick.finish(upstream_status);
+    }
+    // Trailers need chunked framing, which HTTP/1.0 clients cannot read.
+    let trailers = if req.version.eq_ignore_ascii_case("HTTP/1.1") {
+        trailers
+    } else {
+        Headers::default()
+    };
+    if let Some(cookie_cfg) = config.cookies.as_ref() {
+        cookie_cfg.rewrite_response_headers(&mut resp_headers);
+    }
     let proxy_headers = vec![
         ("X-Upstream-Url", upstream.raw_url.as_str()),
         ("X-Upstream-Status", upstream_status_str.as_str()),
     ];
-    let rewritten =
-        rebuild_response_with_extra_headers(&resp_head, &resp_body, "proxy", &proxy_headers)?;
+    let rewritten = rebuild_response(
+        &status_line,
+        resp_headers,
+        &resp_body,
+        &trailers,
+        "proxy",
+        &proxy_headers,
+    );
 
     client.write_all(&rewritten)?;
     client.flush().ok();
     client.shutdown(Shutdown::Both).ok();
 
     let elapsed = start.elapsed().as_millis();
-    eprintln!(
+    logging::access(format_args!(
         "[native] req_id={} {} {} -> {} bytes, {} ms",
         req_id,
         req.method,
         req.path,
         rewritten.len(),
         elapsed
-    );
+    ));
 
     Ok(())
 }
 
-#[derive(Debug)]
-struct RequestLine {
-    method: String,
-    path: String,
-    version: String,
-    content_length: usize,
+/// Probes the upstream with a bare TCP connect and returns (healthy, JSON body).
+fn health_report(config: &Config) -> (bool, String) {
+    let probe = health::probe_upstream(&config.upstream);
+    let body = format!(
+        concat!(
+            "{{\"status\":{},\"variant\":{},\"uptime_secs\":{:.3},",
+            "\"upstream\":{},\"accept_errors\":{}}}"
+        ),
+        json_string(if probe.reachable { "ok" } else { "degraded" }),
+        json_string(GATEWAY_VARIANT),
+        health::uptime().as_secs_f64(),
+        probe.json,
+        accept::error_counts().iter().map(|(_, n)| n).sum::<u64>(),
+    );
+    (probe.reachable, body)
 }
 
-fn read_http_request(stream: &mut TcpStream) -> Result<(Vec<u8>, Vec<u8>)> {
-    let mut buf = Vec::<u8>::new();
-    let mut tmp = [0u8; 4096];
-
-    loop {
-        let n = stream.read(&mut tmp).context("read from client")?;
-        if n == 0 {
-            return Err(anyhow!("client closed before request complete"));
-        }
-        buf.extend_from_slice(&tmp[..n]);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gateway_core::http::forwarded_target;
 
-        if buf.len() > MAX_HEADER_BYTES {
-            return Err(anyhow!("request headers too large"));
-        }
-        if find_double_crlf(&buf).is_some() {
-            break;
-        }
+    fn target(upstream_url: &str, path: &str) -> String {
+        forwarded_target(&parse_upstream(upstream_url).unwrap(), path)
     }
 
-    let header_end = find_double_crlf(&buf).ok_or_else(|| anyhow!("malformed headers"))?;
-    let head = buf[..header_end].to_vec();
-    let mut remainder = buf[header_end + 4..].to_vec();
-
-    let req = parse_request_head(&head)?;
-    let mut body = Vec::<u8>::new();
-
-    if req.content_length > 0 {
-        if req.content_length > MAX_BODY_BYTES {
-            return Err(anyhow!(
-                "request body too large (Content-Length {})",
-                req.content_length
-            ));
-        }
-
-        body.extend_from_slice(&remainder);
-        remainder.clear();
-
-        while body.len() < req.content_length {
-            let n = stream.read(&mut tmp).context("read request body")?;
-            if n == 0 {
-                return Err(anyhow!(
-                    "client closed during body read (got {}, expected {})",
-                    body.len(),
-                    req.content_length
-                ));
-            }
-            body.extend_from_slice(&tmp[..n]);
-            if body.len() > req.content_length {
-                body.truncate(req.content_length);
-                break;
-            }
-        }
+    #[test]
+    fn root_upstream_forwards_target_unchanged() {
+        assert_eq!(target("http://up:8080", "/"), "/");
+        assert_eq!(target("http://up:8080/", "/?x=1"), "/?x=1");
+        assert_eq!(target("http://up:8080", "/a/b/"), "/a/b/");
+        assert_eq!(target("http://up:8080", "//a"), "//a");
     }
 
-    Ok((head, body))
-}
-
-fn parse_request_head(head: &[u8]) -> Result<RequestLine> {
-    let s = std::str::from_utf8(head).context("headers not valid UTF-8")?;
-    let mut lines = s.split("\r\n");
-
-    let request_line = lines.next().ok_or_else(|| anyhow!("empty request"))?;
-    let mut parts = request_line.split_whitespace();
-    let method = parts
-        .next()
-        .ok_or_else(|| anyhow!("missing method"))?
-        .to_string();
-    let path = parts
-        .next()
-        .ok_or_else(|| anyhow!("missing path"))?
-        .to_string();
-    let version = parts
-        .next()
-        .ok_or_else(|| anyhow!("missing version"))?
-        .to_string();
-
-    let mut content_length = 0usize;
-    for line in lines {
-        let lower = line.to_ascii_lowercase();
-        if let Some(rest) = lower.strip_prefix("content-length:") {
-            content_length = rest
-                .trim()
-                .parse::<usize>()
-                .context("invalid Content-Length")?;
-        }
-    }
-
-    Ok(RequestLine {
-        method,
-        path,
-        version,
-        content_length,
-    })
-}
-
-fn build_forwarded_request(
-    req: &RequestLine,
-    original_head: &[u8],
-    body: &[u8],
-    upstream: &Upstream,
-) -> Result<Vec<u8>> {
-    let original = std::str::from_utf8(original_head).context("original headers not UTF-8")?;
-
-    let forwarded_path = if upstream.base_path.is_empty() || upstream.base_path == "/" {
-        req.path.clone()
-    } else {
-        let bp = upstream.base_path.trim_end_matches('/');
-        let rp = req.path.trim_start_matches('/');
-        format!("{bp}/{rp}")
-    };
-
-    let mut out = Vec::<u8>::new();
-    out.extend_from_slice(
-        format!("{} {} {}\r\n", req.method, forwarded_path, req.version).as_bytes(),
-    );
-
-    for line in original.split("\r\n").skip(1) {
-        if line.is_empty() {
-            continue;
-        }
-        let lower = line.to_ascii_lowercase();
-        if lower.starts_with("host:") || lower.starts_with("connection:") {
-            continue;
-        }
-        out.extend_from_slice(line.as_bytes());
-        out.extend_from_slice(b"\r\n");
+    #[test]
+    fn base_path_joins_with_single_slash() {
+        assert_eq!(target("http://up/api", "/"), "/api/");
+        assert_eq!(target("http://up/api/", "/"), "/api/");
+        assert_eq!(target("http://up/api//", "/users"), "/api/users");
+        assert_eq!(target("http://up/api", "//users"), "/api/users");
+        assert_eq!(target("http://up/api", "/users/"), "/api/users/");
     }
 
-    out.extend_from_slice(format!("Host: {}\r\n", upstream.host).as_bytes());
-    out.extend_from_slice(b"Connection: close\r\n");
-    out.extend_from_slice(b"\r\n");
-    out.extend_from_slice(body);
-
-    Ok(out)
-}
-
-fn read_all_response(stream: &mut TcpStream) -> Result<Vec<u8>> {
-    let mut resp = Vec::<u8>::new();
-    let mut tmp = [0u8; 8192];
-
-    loop {
-        let n = stream.read(&mut tmp).context("read upstream response")?;
-        if n == 0 {
-            break;
-        }
-        resp.extend_from_slice(&tmp[..n]);
-        if resp.len() > 10 * 1024 * 1024 {
-            return Err(anyhow!("upstream response too large"));
-        }
-    }
-
-    Ok(resp)
-}
-
-fn split_http_response(resp: &[u8]) -> Result<(Vec<u8>, Vec<u8>)> {
-    let header_end = find_double_crlf(resp).ok_or_else(|| anyhow!("invalid upstream response"))?;
-    let head = resp[..header_end].to_vec();
-    let body = resp[header_end + 4..].to_vec();
-    Ok((head, body))
-}
-
-fn parse_status_code_from_head(head: &[u8]) -> Result<u16> {
-    let head_str = std::str::from_utf8(head).context("resp head not utf8")?;
-    let status_line = head_str
-        .split("\r\n")
-        .next()
-        .ok_or_else(|| anyhow!("missing status line"))?;
-    let status = status_line
-        .split_whitespace()
-        .nth(1)
-        .ok_or_else(|| anyhow!("missing status code"))?
-        .parse::<u16>()
-        .context("invalid status code")?;
-    Ok(status)
-}
-
-fn build_response(
-    status_line: &str,
-    body: &[u8],
-    workload: &str,
-    content_type: Option<&str>,
-    extra_headers: &[(&str, &str)],
-) -> Vec<u8> {
-    let mut out = Vec::<u8>::new();
-    out.extend_from_slice(status_line.as_bytes());
-    out.extend_from_slice(b"\r\n");
-
-    if let Some(content_type) = content_type {
-        out.extend_from_slice(format!("Content-Type: {content_type}\r\n").as_bytes());
+    #[test]
+    fn query_strings_are_merged() {
+        assert_eq!(target("http://up/api", "/?x=1"), "/api/?x=1");
+        assert_eq!(
+            target("http://up/api", "/users?x=1&y=2"),
+            "/api/users?x=1&y=2"
+        );
+        assert_eq!(target("http://up/api?key=k", "/users"), "/api/users?key=k");
+        assert_eq!(
+            target("http://up/api?key=k", "/users?x=1"),
+            "/api/users?key=k&x=1"
+        );
+        assert_eq!(target("http://up/?key=k", "/?x=1"), "/?key=k&x=1");
+        assert_eq!(target("http://up/api", "/users?"), "/api/users");
+        assert_eq!(target("http://up/api?", "/users"), "/api/users");
     }
 
-    out.extend_from_slice(format!("X-Gateway-Variant: {GATEWAY_VARIANT}\r\n").as_bytes());
-    out.extend_from_slice(format!("X-Gateway-Workload: {workload}\r\n").as_bytes());
-
-    for (name, value) in extra_headers {
-        out.extend_from_slice(format!("{name}: {value}\r\n").as_bytes());
+    #[test]
+    fn encoded_characters_pass_through() {
+        assert_eq!(
+            target("http://up/api", "/a%2Fb?q=%26%3D"),
+            "/api/a%2Fb?q=%26%3D"
+        );
+        assert_eq!(target("http://up/my%20api", "/x%20y"), "/my%20api/x%20y");
+        assert_eq!(target("http://up/my api", "/x"), "/my%20api/x");
     }
 
-    out.extend_from_slice(format!("Content-Length: {}\r\n", body.len()).as_bytes());
-    out.extend_from_slice(b"Connection: close\r\n\r\n");
-    out.extend_from_slice(body);
-    out
-}
-
-fn rebuild_response_with_extra_headers(
-    head: &[u8],
-    body: &[u8],
-    workload: &str,
-    extra_headers: &[(&str, &str)],
-) -> Result<Vec<u8>> {
-    let head_str = std::str::from_utf8(head).context("resp head not utf8")?;
-    let mut lines = head_str.split("\r\n");
-    let status = lines.next().ok_or_else(|| anyhow!("missing status line"))?;
-
-    let mut out = Vec::<u8>::new();
-    out.extend_from_slice(status.as_bytes());
-    out.extend_from_slice(b"\r\n");
-
-    for line in lines {
-        if line.is_empty() {
-            continue;
-        }
-        let lower = line.to_ascii_lowercase();
-        if lower.starts_with("content-length:")
-            || lower.starts_with("connection:")
-            || lower.starts_with("x-gateway-variant:")
-            || lower.starts_with("x-gateway-workload:")
-            || lower.starts_with("x-upstream-url:")
-            || lower.starts_with("x-upstream-status:")
-        {
-            continue;
-        }
-        out.extend_from_slice(line.as_bytes());
-        out.extend_from_slice(b"\r\n");
+    #[test]
+    fn rebuild_response_keeps_every_set_cookie() {
+        gateway_core::set_variant(GATEWAY_VARIANT);
+        let (status, headers) = Headers::parse_head(
+            b"HTTP/1.1 200 OK\r\nSet-Cookie: a=1\r\nX-Upstream-Url: stale\r\n folded\r\nSet-Cookie: b=2\r\nContent-Length: 3",
+        )
+        .unwrap();
+        let resp = rebuild_response(
+            &status,
+            headers,
+            b"abcd",
+            &Headers::default(),
+            "proxy",
+            &[("X-Upstream-Url", "http://up")],
+        );
+        assert_eq!(
+            String::from_utf8(resp).unwrap(),
+            concat!(
+                "HTTP/1.1 200 OK\r\n",
+                "Set-Cookie: a=1\r\n",
+                "Set-Cookie: b=2\r\n",
+                "X-Gateway-Variant: native\r\n",
+                "X-Gateway-Workload: proxy\r\n",
+                "X-Upstream-Url: http://up\r\n",
+                "Content-Length: 4\r\n",
+                "Connection: close\r\n\r\n",
+                "abcd"
+            )
+        );
     }
 
-    out.extend_from_slice(format!("X-Gateway-Variant: {GATEWAY_VARIANT}\r\n").as_bytes());
-    out.extend_from_slice(format!("X-Gateway-Workload: {workload}\r\n").as_bytes());
-    for (name, value) in extra_headers {
-        out.extend_from_slice(format!("{name}: {value}\r\n").as_bytes());
+    #[test]
+    fn fragments_and_asterisk_form() {
+        assert_eq!(target("http://up/api", "/users#top"), "/api/users");
+        assert_eq!(target("http://up/api", "/users?x=1#top"), "/api/users?x=1");
+        assert_eq!(target("http://up/api", "*"), "*");
     }
-    out.extend_from_slice(format!("Content-Length: {}\r\n", body.len()).as_bytes());
-    out.extend_from_slice(b"Connection: close\r\n\r\n");
-    out.extend_from_slice(body);
-    Ok(out)
-}
-
-fn find_double_crlf(buf: &[u8]) -> Option<usize> {
-    buf.windows(4).position(|w| w == b"\r\n\r\n")
 }
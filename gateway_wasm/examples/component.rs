@@ -0,0 +1,37 @@
+//! Component build of the guest for `TRANSFORM_BACKEND=component`, using
+//! bindings generated from `wit/gateway.wit`. Same transform as the command
+//! build (prepend `wasm:`), plus a per-path hit counter in the host key-value
+//! store to exercise the host imports.
+
+// The generated canonical-ABI glue takes one argument per flattened field.
+#![allow(clippy::too_many_arguments)]
+
+wit_bindgen::generate!({
+    path: "../wit",
+    world: "gateway",
+});
+
+use gateway::transform::host::{self, Level};
+
+struct Component;
+
+impl Guest for Component {
+    fn handle(req: Request) -> Response {
+        let key = format!("hits:{}", req.path);
+        let hits = host::kv_get(&key)
+            .and_then(|v| v.parse::<u64>().ok())
+            .unwrap_or(0)
+            + 1;
+        host::kv_set(&key, &hits.to_string());
+        host::log(
+            Level::Debug,
+            &format!("{} {} hit {hits}", req.method, req.path),
+        );
+
+        let mut body = b"wasm:".to_vec();
+        body.extend_from_slice(&req.body);
+        Response { status: 200, body }
+    }
+}
+
+export!(Component);
@@ -0,0 +1,63 @@
+//! Reactor variant of the guest. Instead of reading stdin in `_start`, it
+//! exports functions that `gateway_host` calls directly through linear memory
+//! (`WASM_RUNTIME=wasmtime_embedded` only):
+//!
+//! 1. the host calls `gateway_alloc(len)` and writes the input there,
+//! 2. `transform(ptr, len)` returns `(out_ptr << 32) | out_len`.
+//!
+//! `sanitize` has the same signature and can be selected per route with
+//! `WASM_ROUTE_EXPORTS=/api=sanitize`. Every call runs on a fresh instance, so
+//! nothing is ever freed; with `WASM_EXECUTION=embedded` an instance serves up
+//! to `WASM_INSTANCE_MAX_CALLS` calls, and what it leaks is dropped with it.
+//! This guest reads no `GATEWAY_*` variables, so it does not export the
+//! optional `gateway_env(ptr, len)` that warm instances receive them through.
+
+#[cfg(target_arch = "wasm32")]
+mod exports {
+    const PREFIX: &[u8] = b"wasm:";
+
+    #[no_mangle]
+    pub extern "C" fn gateway_alloc(len: u32) -> *mut u8 {
+        let mut buf = Vec::<u8>::with_capacity(len as usize);
+        let ptr = buf.as_mut_ptr();
+        std::mem::forget(buf);
+        ptr
+    }
+
+    /// # Safety
+    ///
+    /// `ptr` must point to `len` bytes obtained from `gateway_alloc`.
+    #[no_mangle]
+    pub unsafe extern "C" fn transform(ptr: *const u8, len: u32) -> u64 {
+        let input = std::slice::from_raw_parts(ptr, len as usize);
+
+        // deterministic transform, same as the command build
+        let mut output = PREFIX.to_vec();
+        output.extend_from_slice(input);
+        into_result(output)
+    }
+
+    /// Replaces control bytes other than tab and newline with `?`.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must point to `len` bytes obtained from `gateway_alloc`.
+    #[no_mangle]
+    pub unsafe extern "C" fn sanitize(ptr: *const u8, len: u32) -> u64 {
+        let input = std::slice::from_raw_parts(ptr, len as usize);
+        let output = input
+            .iter()
+            .map(|&b| match b {
+                b'\t' | b'\n' | b'\r' => b,
+                0..=0x1f | 0x7f => b'?',
+                _ => b,
+            })
+            .collect();
+        into_result(output)
+    }
+
+    fn into_result(output: Vec<u8>) -> u64 {
+        let output = Box::leak(output.into_boxed_slice());
+        ((output.as_ptr() as u64) << 32) | output.len() as u64
+    }
+}
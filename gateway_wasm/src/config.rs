@@ -0,0 +1,148 @@
+//! Guest configuration supplied by the host, so behaviour can change without
+//! recompiling the module. Environment variables are read first, then argv
+//! overrides them:
+//!
+//! | env | argv | default |
+//! |---|---|---|
+//! | `GUEST_PREFIX` | `--prefix=STR` | `wasm:` |
+//! | `GUEST_UPPERCASE=1` | `--uppercase` | off |
+//! | `GUEST_REDACT_FIELDS=a,b` | `--redact=a,b` | `password,token,secret,authorization` |
+//! | `GUEST_MODE` | `--mode=` | `auto` |
+//!
+//! `GATEWAY_MODE` in the request envelope overrides the mode per request.
+
+/// What the guest does with the body.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mode {
+    /// Pick the transform from `GATEWAY_CONTENT_TYPE`.
+    Auto,
+    /// Gzip the body and report `Content-Encoding: gzip` in the envelope.
+    Gzip,
+    /// Ignore the body and run the host's `/compute` SHA-256 chain for
+    /// `GATEWAY_ITERS` iterations (seeded by `GATEWAY_SEED`).
+    Cpu,
+    /// Fill the `{{name}}` tags of the template body from `GATEWAY_QUERY`.
+    Render,
+    /// Ignore the body and classify `GATEWAY_USER_AGENT` as `bot`, `mobile`
+    /// or `desktop`, also reported as `X-Device-Class` in the envelope.
+    Classify,
+    /// Check `GATEWAY_PATH` and the body against the host's WAF rules and
+    /// answer `pass` or `<rule> <location>`.
+    Waf,
+}
+
+impl Mode {
+    pub fn parse(v: &str) -> Mode {
+        match v {
+            "" | "auto" => Mode::Auto,
+            "gzip" => Mode::Gzip,
+            "cpu" => Mode::Cpu,
+            "render" => Mode::Render,
+            "classify" => Mode::Classify,
+            "waf" => Mode::Waf,
+            other => {
+                eprintln!("[wasm-guest] unknown mode {other:?}, using auto");
+                Mode::Auto
+            }
+        }
+    }
+}
+
+pub struct Config {
+    /// Prepended to plain-text bodies.
+    pub prefix: String,
+    /// Uppercases plain-text output (prefix included).
+    pub uppercase: bool,
+    /// JSON keys or dotted key paths whose values are redacted (see `json`).
+    pub redact: Vec<String>,
+    pub mode: Mode,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            prefix: "wasm:".to_string(),
+            uppercase: false,
+            redact: ["password", "token", "secret", "authorization"]
+                .map(String::from)
+                .to_vec(),
+            mode: Mode::Auto,
+        }
+    }
+}
+
+impl Config {
+    pub fn from_env_and_args() -> Self {
+        let mut config = Config::default();
+        if let Ok(prefix) = std::env::var("GUEST_PREFIX") {
+            config.prefix = prefix;
+        }
+        if let Ok(v) = std::env::var("GUEST_UPPERCASE") {
+            config.uppercase = v == "1";
+        }
+        if let Ok(v) = std::env::var("GUEST_REDACT_FIELDS") {
+            config.redact = split_list(&v);
+        }
+        if let Ok(v) = std::env::var("GUEST_MODE") {
+            config.mode = Mode::parse(&v);
+        }
+        // argv[0] is the module name.
+        for arg in std::env::args().skip(1) {
+            if let Some(prefix) = arg.strip_prefix("--prefix=") {
+                config.prefix = prefix.to_string();
+            } else if arg == "--uppercase" {
+                config.uppercase = true;
+            } else if let Some(fields) = arg.strip_prefix("--redact=") {
+                config.redact = split_list(fields);
+            } else if let Some(mode) = arg.strip_prefix("--mode=") {
+                config.mode = Mode::parse(mode);
+            }
+        }
+        config
+    }
+}
+
+fn split_list(v: &str) -> Vec<String> {
+    v.split(',')
+        .map(str::trim)
+        .filter(|f| !f.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_modes() {
+        let modes = [
+            ("", Mode::Auto),
+            ("auto", Mode::Auto),
+            ("gzip", Mode::Gzip),
+            ("cpu", Mode::Cpu),
+            ("render", Mode::Render),
+            ("classify", Mode::Classify),
+            ("waf", Mode::Waf),
+            ("GZIP", Mode::Auto),
+            ("bogus", Mode::Auto),
+        ];
+        for (name, mode) in modes {
+            assert_eq!(Mode::parse(name), mode, "{name:?}");
+        }
+    }
+
+    #[test]
+    fn defaults_and_lists() {
+        let config = Config::default();
+        assert_eq!(config.prefix, "wasm:");
+        assert!(!config.uppercase);
+        assert_eq!(
+            config.redact,
+            ["password", "token", "secret", "authorization"]
+        );
+        assert_eq!(config.mode, Mode::Auto);
+        assert_eq!(split_list(" a, ,b.c ,"), ["a", "b.c"]);
+        assert!(split_list("").is_empty());
+    }
+}
@@ -0,0 +1,19 @@
+//! Response envelope: lets the guest hand headers back to the host alongside
+//! the body. The output starts with `MAGIC`, a space and the request's
+//! `GATEWAY_ENVELOPE_TOKEN`, then carries `Name: value` lines up to an empty
+//! line, then the body; the host strips it before responding. The token is
+//! what tells the host the guest wrote the envelope, rather than a body it
+//! passed through. Output without it is the body as-is.
+
+pub const MAGIC: &str = "GATEWAY-ENVELOPE/1";
+
+pub fn wrap(headers: &[(&str, &str)], body: &[u8]) -> Vec<u8> {
+    let token = std::env::var("GATEWAY_ENVELOPE_TOKEN").unwrap_or_default();
+    let mut out = format!("{MAGIC} {token}\n").into_bytes();
+    for (name, value) in headers {
+        out.extend_from_slice(format!("{name}: {value}\n").as_bytes());
+    }
+    out.push(b'\n');
+    out.extend_from_slice(body);
+    out
+}
@@ -0,0 +1,262 @@
+//! Gzip (RFC 1952) written out here so the guest stays dependency-free: one
+//! fixed-Huffman deflate block, with LZ77 matches found through 3-byte hash
+//! chains over the 32 KiB window. Ratios trail zlib's dynamic Huffman coding
+//! but the per-byte work is of the same kind.
+
+const WINDOW: usize = 32 * 1024;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+/// Candidates examined per position before settling for the best so far.
+const MAX_CHAIN: usize = 32;
+const HASH_BITS: u32 = 15;
+const NONE: u32 = u32::MAX;
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DIST_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DIST_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+
+pub fn compress(input: &[u8]) -> Vec<u8> {
+    let mut bits = BitWriter::default();
+    // magic, deflate, no flags, mtime 0, no extra flags, unknown OS
+    bits.out
+        .extend_from_slice(&[0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 0xff]);
+    bits.write(1, 1); // BFINAL
+    bits.write(1, 2); // BTYPE: fixed Huffman
+    deflate(input, &mut bits);
+    bits.literal(256); // end of block
+    let mut out = bits.finish();
+    out.extend_from_slice(&crc32(input).to_le_bytes());
+    out.extend_from_slice(&(input.len() as u32).to_le_bytes());
+    out
+}
+
+fn deflate(input: &[u8], bits: &mut BitWriter) {
+    let mut chains = Chains {
+        head: vec![NONE; 1 << HASH_BITS],
+        prev: vec![NONE; WINDOW],
+    };
+    let mut pos = 0;
+    while pos < input.len() {
+        let (len, dist) = chains.longest_match(input, pos);
+        if len >= MIN_MATCH {
+            bits.length(len);
+            bits.distance(dist);
+            for p in pos..pos + len {
+                chains.insert(input, p);
+            }
+            pos += len;
+        } else {
+            bits.literal(input[pos] as u32);
+            chains.insert(input, pos);
+            pos += 1;
+        }
+    }
+}
+
+/// Positions of earlier occurrences of each 3-byte prefix, newest first.
+struct Chains {
+    head: Vec<u32>,
+    /// Indexed by position modulo the window.
+    prev: Vec<u32>,
+}
+
+impl Chains {
+    fn hash(input: &[u8], pos: usize) -> usize {
+        let key = u32::from_le_bytes([input[pos], input[pos + 1], input[pos + 2], 0]);
+        (key.wrapping_mul(2_654_435_761) >> (32 - HASH_BITS)) as usize
+    }
+
+    fn insert(&mut self, input: &[u8], pos: usize) {
+        if pos + MIN_MATCH > input.len() {
+            return;
+        }
+        let h = Self::hash(input, pos);
+        self.prev[pos % WINDOW] = self.head[h];
+        self.head[h] = pos as u32;
+    }
+
+    fn longest_match(&self, input: &[u8], pos: usize) -> (usize, usize) {
+        if pos + MIN_MATCH > input.len() {
+            return (0, 0);
+        }
+        let max = MAX_MATCH.min(input.len() - pos);
+        let mut best = (0, 0);
+        let mut candidate = self.head[Self::hash(input, pos)];
+        for _ in 0..MAX_CHAIN {
+            if candidate == NONE || pos - candidate as usize > WINDOW {
+                break;
+            }
+            let start = candidate as usize;
+            let len = input[start..]
+                .iter()
+                .zip(&input[pos..pos + max])
+                .take_while(|(a, b)| a == b)
+                .count();
+            if len > best.0 {
+                best = (len, pos - start);
+                if len == max {
+                    break;
+                }
+            }
+            candidate = self.prev[start % WINDOW];
+        }
+        best
+    }
+}
+
+/// Deflate bit order: values LSB first, Huffman codes MSB first.
+#[derive(Default)]
+struct BitWriter {
+    out: Vec<u8>,
+    acc: u32,
+    count: u32,
+}
+
+impl BitWriter {
+    fn write(&mut self, value: u32, len: u32) {
+        self.acc |= value << self.count;
+        self.count += len;
+        while self.count >= 8 {
+            self.out.push(self.acc as u8);
+            self.acc >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    fn code(&mut self, code: u32, len: u32) {
+        self.write(code.reverse_bits() >> (32 - len), len);
+    }
+
+    /// Literal/length symbol with the fixed code table (RFC 1951 3.2.6).
+    fn literal(&mut self, symbol: u32) {
+        match symbol {
+            0..=143 => self.code(0x30 + symbol, 8),
+            144..=255 => self.code(0x190 + symbol - 144, 9),
+            256..=279 => self.code(symbol - 256, 7),
+            _ => self.code(0xc0 + symbol - 280, 8),
+        }
+    }
+
+    fn length(&mut self, len: usize) {
+        let i = LENGTH_BASE
+            .iter()
+            .rposition(|&base| base as usize <= len)
+            .unwrap_or(0);
+        self.literal(257 + i as u32);
+        self.write(
+            (len - LENGTH_BASE[i] as usize) as u32,
+            LENGTH_EXTRA[i] as u32,
+        );
+    }
+
+    fn distance(&mut self, dist: usize) {
+        let i = DIST_BASE
+            .iter()
+            .rposition(|&base| base as usize <= dist)
+            .unwrap_or(0);
+        self.code(i as u32, 5);
+        self.write((dist - DIST_BASE[i] as usize) as u32, DIST_EXTRA[i] as u32);
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.count > 0 {
+            self.out.push(self.acc as u8);
+        }
+        self.out
+    }
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut table = [0u32; 256];
+    for (n, entry) in table.iter_mut().enumerate() {
+        let mut c = n as u32;
+        for _ in 0..8 {
+            c = if c & 1 != 0 {
+                0xedb8_8320 ^ (c >> 1)
+            } else {
+                c >> 1
+            };
+        }
+        *entry = c;
+    }
+    !data.iter().fold(!0u32, |crc, &b| {
+        table[((crc ^ b as u32) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    fn gunzip(data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        flate2::read::GzDecoder::new(data)
+            .read_to_end(&mut out)
+            .unwrap();
+        out
+    }
+
+    /// Deterministic bytes that barely compress.
+    fn noise(len: usize) -> Vec<u8> {
+        let mut x: u32 = 0x1234_5678;
+        (0..len)
+            .map(|_| {
+                x ^= x << 13;
+                x ^= x >> 17;
+                x ^= x << 5;
+                x as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn round_trips() {
+        let text = "the quick brown fox jumps over the lazy dog\n".repeat(2_000);
+        let mut far = noise(40_000);
+        far.extend_from_within(..1_000);
+        let inputs: Vec<Vec<u8>> = vec![
+            Vec::new(),
+            b"a".to_vec(),
+            b"ab".to_vec(),
+            b"abcabcabc".to_vec(),
+            vec![0; 1_000],
+            (0..=255).collect(),
+            text.clone().into_bytes(),
+            noise(100_000),
+            // A repeat more than a window back cannot be matched.
+            far,
+        ];
+        for input in &inputs {
+            assert_eq!(&gunzip(&compress(input)), input, "{} bytes", input.len());
+        }
+        assert!(compress(text.as_bytes()).len() < text.len() / 20);
+    }
+
+    #[test]
+    fn writes_the_gzip_framing() {
+        let out = compress(b"hello");
+        assert_eq!(&out[..4], &[0x1f, 0x8b, 8, 0]);
+        let trailer = &out[out.len() - 8..];
+        assert_eq!(trailer[..4], crc32(b"hello").to_le_bytes());
+        assert_eq!(trailer[4..], 5u32.to_le_bytes());
+    }
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(crc32(b""), 0);
+    }
+}
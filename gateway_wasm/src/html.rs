@@ -0,0 +1,27 @@
+//! Banner injection for `text/html` bodies.
+
+const BANNER: &[u8] = b"<div data-gateway=\"wasm\">served via wasm gateway</div>";
+
+/// Inserts `BANNER` right after the opening `<body ...>` tag, or at the very
+/// start when there is none.
+pub fn inject_banner(input: &[u8]) -> Vec<u8> {
+    let at = find_ascii_ci(input, b"<body")
+        .and_then(|start| {
+            input[start..]
+                .iter()
+                .position(|&b| b == b'>')
+                .map(|p| start + p + 1)
+        })
+        .unwrap_or(0);
+    let mut out = Vec::with_capacity(input.len() + BANNER.len());
+    out.extend_from_slice(&input[..at]);
+    out.extend_from_slice(BANNER);
+    out.extend_from_slice(&input[at..]);
+    out
+}
+
+fn find_ascii_ci(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|w| w.eq_ignore_ascii_case(needle))
+}
@@ -0,0 +1,296 @@
+//! JSON redaction without a JSON dependency: a validating recursive-descent
+//! pass that copies the body byte for byte (whitespace and key order
+//! included) and swaps the value of every redacted path for `"***"`.
+//!
+//! A path is a dot-separated list of keys from the document root, with `*`
+//! matching any single key: `user.password`, `*.token`. Arrays are
+//! transparent, so `users.password` also covers `{"users": [{"password": ..}]}`.
+//! A bare key (`password`) matches at any depth. Keys are compared as written,
+//! escape sequences are not decoded.
+
+const MASK: &[u8] = b"\"***\"";
+/// Deeper documents are passed through unredacted rather than risking the
+/// guest's stack.
+const MAX_DEPTH: usize = 128;
+
+/// Returns `None` when the body is not valid JSON; callers then pass it
+/// through unchanged.
+pub fn redact(input: &[u8], paths: &[&str]) -> Option<Vec<u8>> {
+    let mut redactor = Redactor {
+        input,
+        pos: 0,
+        out: Vec::with_capacity(input.len()),
+        patterns: paths.iter().map(|p| p.split('.').collect()).collect(),
+        path: Vec::new(),
+    };
+    redactor.whitespace();
+    redactor.value(0)?;
+    redactor.whitespace();
+    (redactor.pos == input.len()).then_some(redactor.out)
+}
+
+struct Redactor<'a> {
+    input: &'a [u8],
+    pos: usize,
+    out: Vec<u8>,
+    patterns: Vec<Vec<&'a str>>,
+    /// Keys of the objects enclosing the current value.
+    path: Vec<&'a [u8]>,
+}
+
+impl<'a> Redactor<'a> {
+    fn peek(&self) -> Option<u8> {
+        self.input.get(self.pos).copied()
+    }
+
+    fn copy(&mut self, n: usize) {
+        self.out
+            .extend_from_slice(&self.input[self.pos..self.pos + n]);
+        self.pos += n;
+    }
+
+    fn expect(&mut self, byte: u8) -> Option<()> {
+        (self.peek()? == byte).then(|| self.copy(1))
+    }
+
+    fn whitespace(&mut self) {
+        while matches!(self.peek(), Some(b' ' | b'\t' | b'\n' | b'\r')) {
+            self.copy(1);
+        }
+    }
+
+    fn value(&mut self, depth: usize) -> Option<()> {
+        if depth > MAX_DEPTH {
+            return None;
+        }
+        match self.peek()? {
+            b'{' => self.object(depth),
+            b'[' => self.array(depth),
+            b'"' => self.string().map(|_| ()),
+            b't' => self.literal(b"true"),
+            b'f' => self.literal(b"false"),
+            b'n' => self.literal(b"null"),
+            b'-' | b'0'..=b'9' => self.number(),
+            _ => None,
+        }
+    }
+
+    fn object(&mut self, depth: usize) -> Option<()> {
+        self.expect(b'{')?;
+        self.whitespace();
+        if self.peek()? == b'}' {
+            self.copy(1);
+            return Some(());
+        }
+        loop {
+            let key = self.string()?;
+            self.whitespace();
+            self.expect(b':')?;
+            self.whitespace();
+            self.path.push(key);
+            let redacted = self.is_redacted();
+            let start = self.out.len();
+            self.value(depth + 1)?;
+            if redacted {
+                self.out.truncate(start);
+                self.out.extend_from_slice(MASK);
+            }
+            self.path.pop();
+            self.whitespace();
+            match self.peek()? {
+                b',' => {
+                    self.copy(1);
+                    self.whitespace();
+                }
+                b'}' => {
+                    self.copy(1);
+                    return Some(());
+                }
+                _ => return None,
+            }
+        }
+    }
+
+    fn array(&mut self, depth: usize) -> Option<()> {
+        self.expect(b'[')?;
+        self.whitespace();
+        if self.peek()? == b']' {
+            self.copy(1);
+            return Some(());
+        }
+        loop {
+            self.value(depth + 1)?;
+            self.whitespace();
+            match self.peek()? {
+                b',' => {
+                    self.copy(1);
+                    self.whitespace();
+                }
+                b']' => {
+                    self.copy(1);
+                    return Some(());
+                }
+                _ => return None,
+            }
+        }
+    }
+
+    /// Copies a string and returns its raw contents (between the quotes).
+    fn string(&mut self) -> Option<&'a [u8]> {
+        let input = self.input;
+        let start = self.pos;
+        self.expect(b'"')?;
+        loop {
+            match self.peek()? {
+                b'"' => {
+                    self.copy(1);
+                    return Some(&input[start + 1..self.pos - 1]);
+                }
+                b'\\' if self.pos + 1 < input.len() => self.copy(2),
+                c if c < 0x20 => return None,
+                _ => self.copy(1),
+            }
+        }
+    }
+
+    fn literal(&mut self, word: &[u8]) -> Option<()> {
+        self.input[self.pos..]
+            .starts_with(word)
+            .then(|| self.copy(word.len()))
+    }
+
+    fn number(&mut self) -> Option<()> {
+        let len = self.input[self.pos..]
+            .iter()
+            .position(|b| !matches!(b, b'-' | b'+' | b'.' | b'e' | b'E' | b'0'..=b'9'))
+            .unwrap_or(self.input.len() - self.pos);
+        let digits = &self.input[self.pos..self.pos + len];
+        if !digits.iter().any(u8::is_ascii_digit) {
+            return None;
+        }
+        self.copy(len);
+        Some(())
+    }
+
+    fn is_redacted(&self) -> bool {
+        self.patterns
+            .iter()
+            .any(|pattern| match pattern.as_slice() {
+                [key] => self.path.last().is_some_and(|k| key.as_bytes() == *k),
+                _ => {
+                    pattern.len() == self.path.len()
+                        && pattern
+                            .iter()
+                            .zip(&self.path)
+                            .all(|(p, k)| *p == "*" || p.as_bytes() == *k)
+                }
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn redacted(input: &str, paths: &[&str]) -> Option<String> {
+        redact(input.as_bytes(), paths).map(|out| String::from_utf8(out).unwrap())
+    }
+
+    #[test]
+    fn redacts_dotted_paths_from_the_root() {
+        assert_eq!(
+            redacted(
+                r#"{"user": {"password": "x", "name": "a"}, "token": 1}"#,
+                &["user.password", "token"]
+            )
+            .unwrap(),
+            r#"{"user": {"password": "***", "name": "a"}, "token": "***"}"#
+        );
+        // A dotted path only matches at its own depth.
+        assert_eq!(
+            redacted(
+                r#"{"password":"x","user":{"name":"a"}}"#,
+                &["user.password"]
+            )
+            .unwrap(),
+            r#"{"password":"x","user":{"name":"a"}}"#
+        );
+    }
+
+    #[test]
+    fn bare_keys_match_at_any_depth_and_arrays_are_transparent() {
+        assert_eq!(
+            redacted(r#"{"a":{"b":[{"password":{"x":[1]}}]}}"#, &["password"]).unwrap(),
+            r#"{"a":{"b":[{"password":"***"}]}}"#
+        );
+        assert_eq!(
+            redacted(
+                r#"{"users":[{"password":"p"},{"id":1,"password":"q"}]}"#,
+                &["users.password"]
+            )
+            .unwrap(),
+            r#"{"users":[{"password":"***"},{"id":1,"password":"***"}]}"#
+        );
+        assert_eq!(
+            redacted(r#"[{"token":"t"},"token"]"#, &["token"]).unwrap(),
+            r#"[{"token":"***"},"token"]"#
+        );
+    }
+
+    #[test]
+    fn wildcards_match_one_key() {
+        assert_eq!(
+            redacted(
+                r#"{"a":{"token":1},"b":{"c":{"token":2}},"token":3}"#,
+                &["*.token"]
+            )
+            .unwrap(),
+            r#"{"a":{"token":"***"},"b":{"c":{"token":2}},"token":3}"#
+        );
+    }
+
+    #[test]
+    fn copies_everything_else_byte_for_byte() {
+        let input = " { \"z\" : [ 1.5e-3 , true , null ] ,\n\t\"password\" :\r\n\"a\\\"b\" } ";
+        assert_eq!(
+            redacted(input, &["password"]).unwrap(),
+            " { \"z\" : [ 1.5e-3 , true , null ] ,\n\t\"password\" :\r\n\"***\" } "
+        );
+        let unicode = r#"{"name":"Zoë é","n":-0}"#;
+        assert_eq!(redacted(unicode, &["password"]).unwrap(), unicode);
+        // Keys are compared as written, escapes and all.
+        let escaped = r#"{"pass\u0077ord":"x"}"#;
+        assert_eq!(redacted(escaped, &["password"]).unwrap(), escaped);
+        assert_eq!(redacted(r#""plain""#, &["password"]).unwrap(), r#""plain""#);
+    }
+
+    #[test]
+    fn rejects_invalid_json() {
+        for input in [
+            "",
+            "{",
+            r#"{"a":}"#,
+            r#"{"a":1,}"#,
+            r#"{"a" 1}"#,
+            "[1 2]",
+            "[1,]",
+            r#""abc"#,
+            r#"{"a":1} x"#,
+            "tru",
+            "-",
+            "{'a':1}",
+            "\"a\u{1}\"",
+        ] {
+            assert_eq!(redacted(input, &["a"]), None, "{input:?}");
+        }
+    }
+
+    #[test]
+    fn stops_at_the_depth_limit() {
+        let nested = |depth: usize| format!("{}{}", "[".repeat(depth), "]".repeat(depth));
+        let ok = nested(MAX_DEPTH + 1);
+        assert_eq!(redacted(&ok, &["a"]).unwrap(), ok);
+        assert_eq!(redacted(&nested(MAX_DEPTH + 2), &["a"]), None);
+        assert_eq!(redacted(&nested(100_000), &["a"]), None);
+    }
+}
@@ -1,12 +1,116 @@
+mod config;
+mod envelope;
+mod gzip;
+mod html;
+mod json;
+mod render;
+mod sha256;
+mod user_agent;
+mod waf;
+mod worker;
+
 use std::io::{self, Read, Write};
 
+use config::{Config, Mode};
+
 fn main() {
+    let config = Config::from_env_and_args();
+    if std::env::args().skip(1).any(|arg| arg == "--worker") {
+        worker::run(&config);
+        return;
+    }
+
     let mut input = Vec::new();
     io::stdin().read_to_end(&mut input).unwrap();
+    let output = handle(&config, input);
+    io::stdout().write_all(&output).unwrap();
+}
+
+// one request: the envelope is in the environment, the body is `input`
+fn handle(config: &Config, input: Vec<u8>) -> Vec<u8> {
+    // The host passes the body's media type in the envelope.
+    let content_type = std::env::var("GATEWAY_CONTENT_TYPE").unwrap_or_default();
+    // A per-request mode from the host wins over the configured one.
+    let mode = std::env::var("GATEWAY_MODE")
+        .map(|m| Mode::parse(&m))
+        .unwrap_or(config.mode);
+    match mode {
+        Mode::Auto => transform(config, &content_type, input),
+        Mode::Gzip => envelope::wrap(&[("Content-Encoding", "gzip")], &gzip::compress(&input)),
+        Mode::Cpu => cpu(),
+        Mode::Render => {
+            let query = std::env::var("GATEWAY_QUERY").unwrap_or_default();
+            render::render(&input, &query)
+        }
+        Mode::Classify => {
+            let user_agent = std::env::var("GATEWAY_USER_AGENT").unwrap_or_default();
+            let class = user_agent::classify(&user_agent);
+            envelope::wrap(&[("X-Device-Class", class)], class.as_bytes())
+        }
+        Mode::Waf => waf(&content_type, &input),
+    }
+}
+
+fn transform(config: &Config, content_type: &str, input: Vec<u8>) -> Vec<u8> {
+    let media_type = content_type
+        .split(';')
+        .next()
+        .unwrap_or("")
+        .trim()
+        .to_ascii_lowercase();
+    match media_type.as_str() {
+        "application/json" => redact(config, input),
+        t if t.ends_with("+json") => redact(config, input),
+        "text/html" => html::inject_banner(&input),
+        t if is_binary(t) => input,
+        _ => prefix(config, input),
+    }
+}
 
-    // deterministic transform
-    let mut output = b"wasm:".to_vec();
+fn redact(config: &Config, input: Vec<u8>) -> Vec<u8> {
+    let paths: Vec<&str> = config.redact.iter().map(String::as_str).collect();
+    json::redact(&input, &paths).unwrap_or(input)
+}
+
+// deterministic transform for plain text and untyped bodies
+fn prefix(config: &Config, input: Vec<u8>) -> Vec<u8> {
+    let mut output = config.prefix.clone().into_bytes();
     output.extend_from_slice(&input);
+    if config.uppercase {
+        output.make_ascii_uppercase();
+    }
+    output
+}
 
-    io::stdout().write_all(&output).unwrap();
+// the host's WAF=wasm check, with the request target and limit from the envelope
+fn waf(content_type: &str, input: &[u8]) -> Vec<u8> {
+    let target = std::env::var("GATEWAY_PATH").unwrap_or_default();
+    let max_params = std::env::var("GATEWAY_WAF_MAX_PARAMS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(100);
+    match waf::inspect(&target, content_type, input, max_params) {
+        Some(v) => format!("{} {}", v.rule, v.location).into_bytes(),
+        None => b"pass".to_vec(),
+    }
+}
+
+// the host's /compute workload, with its parameters from the envelope
+fn cpu() -> Vec<u8> {
+    let number = |name: &str| std::env::var(name).ok().and_then(|v| v.parse::<u64>().ok());
+    let iters = number("GATEWAY_ITERS").unwrap_or(50_000);
+    sha256::cpu_heavy(iters, number("GATEWAY_SEED")).into_bytes()
+}
+
+fn is_binary(media_type: &str) -> bool {
+    matches!(
+        media_type,
+        "application/octet-stream"
+            | "application/pdf"
+            | "application/zip"
+            | "application/gzip"
+            | "application/wasm"
+    ) || ["image/", "audio/", "video/", "font/"]
+        .iter()
+        .any(|p| media_type.starts_with(p))
 }
@@ -0,0 +1,131 @@
+//! `{{name}}` template rendering for the `render` mode. Values come from the
+//! URL query string the host passes as `GATEWAY_QUERY` and are HTML-escaped;
+//! unknown names render as nothing.
+
+pub fn render(template: &[u8], query: &str) -> Vec<u8> {
+    let vars = parse_query(query);
+    let mut out = Vec::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(open) = find(rest, b"{{") {
+        out.extend_from_slice(&rest[..open]);
+        let after = &rest[open + 2..];
+        let Some(close) = find(after, b"}}") else {
+            // unterminated tag: keep it literally
+            rest = &rest[open..];
+            break;
+        };
+        let name = String::from_utf8_lossy(&after[..close]);
+        if let Some((_, value)) = vars.iter().find(|(k, _)| *k == name.trim()) {
+            escape_into(&mut out, value);
+        }
+        rest = &after[close + 2..];
+    }
+    out.extend_from_slice(rest);
+    out
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+fn escape_into(out: &mut Vec<u8>, value: &str) {
+    for b in value.bytes() {
+        match b {
+            b'&' => out.extend_from_slice(b"&amp;"),
+            b'<' => out.extend_from_slice(b"&lt;"),
+            b'>' => out.extend_from_slice(b"&gt;"),
+            b'"' => out.extend_from_slice(b"&quot;"),
+            b'\'' => out.extend_from_slice(b"&#39;"),
+            _ => out.push(b),
+        }
+    }
+}
+
+fn parse_query(query: &str) -> Vec<(String, String)> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (k, v) = pair.split_once('=').unwrap_or((pair, ""));
+            (decode(k), decode(v))
+        })
+        .collect()
+}
+
+/// `application/x-www-form-urlencoded` decoding: `+` and `%XX`.
+fn decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => out.push(b' '),
+            b'%' if i + 2 < bytes.len()
+                && bytes[i + 1].is_ascii_hexdigit()
+                && bytes[i + 2].is_ascii_hexdigit() =>
+            {
+                out.push(hex(bytes[i + 1]) << 4 | hex(bytes[i + 2]));
+                i += 2;
+            }
+            b => out.push(b),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+fn hex(digit: u8) -> u8 {
+    match digit {
+        b'0'..=b'9' => digit - b'0',
+        b'a'..=b'f' => digit - b'a' + 10,
+        _ => digit - b'A' + 10,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rendered(template: &str, query: &str) -> String {
+        String::from_utf8(render(template.as_bytes(), query)).unwrap()
+    }
+
+    #[test]
+    fn substitutes_and_escapes_query_values() {
+        assert_eq!(
+            rendered("Hi {{name}}, you are {{ age }}.", "name=Ada+L%2E&age=36"),
+            "Hi Ada L., you are 36."
+        );
+        assert_eq!(
+            rendered("<p>{{q}}</p>", "q=%3Cscript%3E%26%22'"),
+            "<p>&lt;script&gt;&amp;&quot;&#39;</p>"
+        );
+        assert_eq!(rendered("{{a}}{{a}}", "a=1&a=2"), "11");
+    }
+
+    #[test]
+    fn unknown_and_malformed_tags() {
+        assert_eq!(rendered("[{{missing}}]", "name=x"), "[]");
+        assert_eq!(rendered("[{{flag}}]", "flag"), "[]");
+        assert_eq!(rendered("a {{name", "name=x"), "a {{name");
+        assert_eq!(rendered("{{name}} {{", "name=x"), "x {{");
+        assert_eq!(rendered("no tags } {", "name=x"), "no tags } {");
+        assert_eq!(rendered("", "name=x"), "");
+    }
+
+    #[test]
+    fn decodes_form_encoding() {
+        assert_eq!(decode("a+b%20c"), "a b c");
+        assert_eq!(decode("%e2%82%ac"), "€");
+        assert_eq!(decode("100%"), "100%");
+        assert_eq!(decode("%zz%4"), "%zz%4");
+        assert_eq!(
+            parse_query("&k=v&&empty=&bare"),
+            [
+                ("k".to_string(), "v".to_string()),
+                ("empty".to_string(), String::new()),
+                ("bare".to_string(), String::new()),
+            ]
+        );
+    }
+}
@@ -0,0 +1,46 @@
+//! `User-Agent` classification for the `classify` mode: the same rules as the
+//! host's `user_agent` module, so the two can be compared.
+
+/// Substrings of a lowercased `User-Agent` that mark automated clients.
+const BOT_TOKENS: &[&str] = &[
+    "bot",
+    "crawl",
+    "spider",
+    "slurp",
+    "curl/",
+    "wget/",
+    "python-requests",
+    "python-urllib",
+    "go-http-client",
+    "java/",
+    "libwww",
+    "httpclient",
+    "headless",
+    "scrapy",
+    "facebookexternalhit",
+];
+
+/// Substrings of a lowercased `User-Agent` that mark phones and tablets.
+const MOBILE_TOKENS: &[&str] = &[
+    "mobi",
+    "android",
+    "iphone",
+    "ipad",
+    "ipod",
+    "windows phone",
+    "blackberry",
+    "opera mini",
+    "silk/",
+];
+
+/// `bot`, `mobile` or `desktop`; an empty `User-Agent` is a bot.
+pub fn classify(user_agent: &str) -> &'static str {
+    let ua = user_agent.trim().to_ascii_lowercase();
+    if ua.is_empty() || BOT_TOKENS.iter().any(|t| ua.contains(t)) {
+        "bot"
+    } else if MOBILE_TOKENS.iter().any(|t| ua.contains(t)) {
+        "mobile"
+    } else {
+        "desktop"
+    }
+}
@@ -0,0 +1,336 @@
+//! Attack-pattern checks for the `waf` mode: the same rules as the host's
+//! `waf` module, so `WAF=native` and `WAF=wasm` can be compared.
+
+/// A request a rule rejected.
+pub struct Violation {
+    pub rule: &'static str,
+    /// `path`, `query` or `body`.
+    pub location: &'static str,
+}
+
+/// Runs every rule over `target` and `body`; the first match wins.
+pub fn inspect(
+    target: &str,
+    content_type: &str,
+    body: &[u8],
+    max_params: usize,
+) -> Option<Violation> {
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let at = |rule, location| Some(Violation { rule, location });
+    if decode(path).contains('\0') {
+        return at("null-byte", "path");
+    }
+    if let Some(rule) = check_params(query, max_params) {
+        return at(rule, "query");
+    }
+    let media_type = content_type
+        .split(';')
+        .next()
+        .unwrap_or("")
+        .trim()
+        .to_ascii_lowercase();
+    let rule = if media_type == "application/x-www-form-urlencoded" {
+        check_params(&String::from_utf8_lossy(body), max_params)
+    } else if is_text(&media_type) {
+        let text = String::from_utf8_lossy(body);
+        if text.contains('\0') {
+            Some("null-byte")
+        } else {
+            signature(&text)
+        }
+    } else {
+        None
+    };
+    rule.and_then(|rule| at(rule, "body"))
+}
+
+fn is_text(media_type: &str) -> bool {
+    media_type.is_empty()
+        || media_type.starts_with("text/")
+        || media_type == "application/json"
+        || media_type == "application/xml"
+        || media_type.ends_with("+json")
+        || media_type.ends_with("+xml")
+}
+
+/// `name=value&...`: too many parameters, or a NUL or signature in one.
+fn check_params(params: &str, max_params: usize) -> Option<&'static str> {
+    let pairs: Vec<&str> = params.split('&').filter(|p| !p.is_empty()).collect();
+    if pairs.len() > max_params {
+        return Some("too-many-params");
+    }
+    pairs.iter().find_map(|pair| {
+        let decoded = decode(pair);
+        if decoded.contains('\0') {
+            Some("null-byte")
+        } else {
+            signature(&decoded)
+        }
+    })
+}
+
+/// Percent-decodes `s`, with `+` as a space.
+fn decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .filter(|h| h.iter().all(u8::is_ascii_hexdigit))
+            .and_then(|h| u8::from_str_radix(std::str::from_utf8(h).ok()?, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => {
+                out.push(byte);
+                i += 3;
+            }
+            (b'+', _) => {
+                out.push(b' ');
+                i += 1;
+            }
+            (byte, _) => {
+                out.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// Lowercases `text` and turns each run of whitespace and closed `/* */`
+/// comments into one space.
+fn squash(text: &str) -> String {
+    let lower = text.to_lowercase();
+    let mut out = String::with_capacity(lower.len());
+    let mut rest = lower.as_str();
+    while let Some(c) = rest.chars().next() {
+        let skip = if c.is_whitespace() {
+            c.len_utf8()
+        } else if let Some(end) = rest.strip_prefix("/*").and_then(|r| r.find("*/")) {
+            end + 4
+        } else {
+            out.push(c);
+            rest = &rest[c.len_utf8()..];
+            continue;
+        };
+        if !out.ends_with(' ') {
+            out.push(' ');
+        }
+        rest = &rest[skip..];
+    }
+    out
+}
+
+/// The first SQL injection or XSS rule `text` matches.
+fn signature(text: &str) -> Option<&'static str> {
+    let t = squash(text);
+    let any = |needles: &[&str]| needles.iter().any(|n| t.contains(n));
+    if any(&[
+        "union select",
+        "union all select",
+        "union distinct select",
+        "union(select",
+    ]) {
+        Some("sqli-union")
+    } else if tautology(&t) {
+        Some("sqli-tautology")
+    } else if any(&["'--", "' --", "'#", "' #", "'/*", "' /*", "\"--", "\" --"]) {
+        Some("sqli-comment")
+    } else if stacked(&t) {
+        Some("sqli-stacked")
+    } else if any(&[
+        "sleep(",
+        "benchmark(",
+        "waitfor delay",
+        "load_file(",
+        "into outfile",
+        "information_schema",
+        "xp_cmdshell",
+    ]) {
+        Some("sqli-function")
+    } else if t.contains("<script") {
+        Some("xss-script")
+    } else if handler(&t) {
+        Some("xss-handler")
+    } else if any(&["javascript:", "vbscript:", "data:text/html"]) {
+        Some("xss-uri")
+    } else {
+        None
+    }
+}
+
+/// `or` / `and` followed by a comparison of a value with itself.
+fn tautology(t: &str) -> bool {
+    let unquoted: String = t
+        .chars()
+        .filter(|c| !matches!(c, '\'' | '"' | '(' | ')' | '`'))
+        .collect();
+    let unquoted = unquoted.replace(" =", "=").replace("= ", "=");
+    let words: Vec<&str> = unquoted.split(' ').collect();
+    words.windows(2).any(|w| {
+        matches!(w[0], "or" | "and" | "||" | "&&")
+            && w[1]
+                .split_once('=')
+                .is_some_and(|(left, right)| left == right)
+    })
+}
+
+/// A `;` followed by a destructive statement.
+fn stacked(t: &str) -> bool {
+    t.split(';').skip(1).any(|statement| {
+        let s = statement.trim_start();
+        [
+            "drop table",
+            "drop database",
+            "delete from",
+            "insert into",
+            "truncate table",
+            "alter table",
+            "exec ",
+            "shutdown",
+        ]
+        .iter()
+        .any(|k| s.starts_with(k))
+            || (s.starts_with("update ") && s.contains(" set "))
+    })
+}
+
+/// An `on...=` attribute inside an open tag.
+fn handler(t: &str) -> bool {
+    t.match_indices("on").any(|(i, _)| {
+        let before = t[..i].chars().next_back();
+        let in_tag = t[..i]
+            .rfind('<')
+            .is_some_and(|open| !t[open..i].contains('>'));
+        let name: String = t[i + 2..]
+            .chars()
+            .take_while(char::is_ascii_alphabetic)
+            .collect();
+        matches!(before, Some(' ' | '/' | '"' | '\''))
+            && in_tag
+            && name.len() >= 2
+            && t[i + 2 + name.len()..].trim_start().starts_with('=')
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `rule location` as the `waf` mode answers it, or `pass`.
+    fn check(target: &str, content_type: &str, body: &str) -> String {
+        match inspect(target, content_type, body.as_bytes(), 5) {
+            Some(v) => format!("{} {}", v.rule, v.location),
+            None => "pass".to_string(),
+        }
+    }
+
+    #[test]
+    fn flags_sql_injection() {
+        let cases = [
+            (
+                "/items?id=1%20UNION%20SELECT%20password",
+                "sqli-union query",
+            ),
+            ("/items?id=1+union/**/all+select+1", "sqli-union query"),
+            ("/items?id=1%27%20OR%20%271%27=%271", "sqli-tautology query"),
+            ("/items?id=2+and+x+=+x", "sqli-tautology query"),
+            ("/login?user=admin'--", "sqli-comment query"),
+            ("/items?id=1;%20DROP%20TABLE%20users", "sqli-stacked query"),
+            ("/items?id=1;update+users+set+admin=1", "sqli-stacked query"),
+            ("/items?id=sleep(5)", "sqli-function query"),
+            ("/items?t=information_schema.tables", "sqli-function query"),
+        ];
+        for (target, expected) in cases {
+            assert_eq!(check(target, "", ""), expected, "{target}");
+        }
+    }
+
+    #[test]
+    fn flags_cross_site_scripting() {
+        assert_eq!(
+            check("/?q=%3CScRiPt%3Ealert(1)", "", ""),
+            "xss-script query"
+        );
+        assert_eq!(
+            check("/", "text/html", "<img src=x onerror=alert(1)>"),
+            "xss-handler body"
+        );
+        assert_eq!(
+            check("/", "text/html", "<a href=\"#\" onclick = \"go()\">"),
+            "xss-handler body"
+        );
+        assert_eq!(check("/?u=javascript:alert(1)", "", ""), "xss-uri query");
+        assert_eq!(
+            check(
+                "/",
+                "application/vnd.api+json",
+                r#"{"u":"data:text/html,x"}"#
+            ),
+            "xss-uri body"
+        );
+    }
+
+    #[test]
+    fn checks_paths_params_and_bodies_by_media_type() {
+        assert_eq!(check("/a%00b", "", ""), "null-byte path");
+        assert_eq!(check("/?a=%00", "", ""), "null-byte query");
+        assert_eq!(check("/?a&b&c&d&e&f", "", ""), "too-many-params query");
+        assert_eq!(check("/?a&b&c&d&e", "", ""), "pass");
+        assert_eq!(
+            check(
+                "/",
+                "application/x-www-form-urlencoded; charset=utf-8",
+                "a=%3Cscript%3E"
+            ),
+            "xss-script body"
+        );
+        assert_eq!(
+            check("/", "application/x-www-form-urlencoded", "a&b&c&d&e&f"),
+            "too-many-params body"
+        );
+        assert_eq!(
+            check("/", "application/json", "{\"a\":\"\0\"}"),
+            "null-byte body"
+        );
+        assert_eq!(
+            check("/", "text/plain", "x; drop table t"),
+            "sqli-stacked body"
+        );
+        // Bodies that are not text are not inspected.
+        assert_eq!(check("/", "application/octet-stream", "<script>"), "pass");
+        assert_eq!(check("/", "image/png", "' or 1=1"), "pass");
+        // The query is checked before the body.
+        assert_eq!(check("/?q=sleep(1)", "", "<script>"), "sqli-function query");
+    }
+
+    #[test]
+    fn passes_ordinary_requests() {
+        for target in [
+            "/search?q=union+station&sort=asc",
+            "/search?q=select+a+plan",
+            "/docs/on-call?lang=en",
+            "/?q=bacon+and+eggs",
+            "/?a=1+or+2",
+        ] {
+            assert_eq!(check(target, "", ""), "pass", "{target}");
+        }
+        assert_eq!(
+            check("/", "text/html", "<p>Sign on = yes</p><button>ok</button>"),
+            "pass"
+        );
+        assert_eq!(
+            check("/", "application/json", r#"{"note":"it's ok"}"#),
+            "pass"
+        );
+    }
+
+    #[test]
+    fn squashes_case_space_and_comments() {
+        assert_eq!(squash("UNION/**/SELECT"), "union select");
+        assert_eq!(squash("a \t\n b"), "a b");
+        assert_eq!(squash("a/* x */ /**/b"), "a b");
+        assert_eq!(squash("open /* comment"), "open /* comment");
+        assert_eq!(decode("a+b%2B%zz"), "a b+%zz");
+    }
+}
@@ -0,0 +1,80 @@
+//! Persistent worker loop, entered with `--worker`: the host keeps the
+//! process running and sends one frame per request on stdin instead of
+//! starting the module each time. All lengths are big-endian u32:
+//!
+//! - request: variable count, then `len` + `KEY=value` per envelope
+//!   variable, then `len` + body
+//! - response: `len` + output
+//!
+//! Envelope variables are set in the environment for the duration of one
+//! request, so the rest of the guest reads them as in one-shot mode. EOF
+//! between frames ends the loop.
+
+use std::io::{self, Read, Write};
+
+use crate::config::Config;
+
+pub fn run(config: &Config) {
+    let mut stdin = io::stdin().lock();
+    let mut stdout = io::stdout().lock();
+    let mut request_vars = Vec::new();
+    loop {
+        match serve_one(config, &mut stdin, &mut stdout, &mut request_vars) {
+            Ok(true) => {}
+            Ok(false) => return,
+            Err(e) => {
+                eprintln!("[wasm-guest] worker frame error: {e}");
+                std::process::exit(1);
+            }
+        }
+    }
+}
+
+// false on a clean EOF before the next frame
+fn serve_one(
+    config: &Config,
+    input: &mut impl Read,
+    output: &mut impl Write,
+    request_vars: &mut Vec<String>,
+) -> io::Result<bool> {
+    let Some(count) = read_u32(input, true)? else {
+        return Ok(false);
+    };
+    for key in request_vars.drain(..) {
+        std::env::remove_var(key);
+    }
+    for _ in 0..count {
+        let var = String::from_utf8(read_chunk(input)?)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "variable not UTF-8"))?;
+        let (key, value) = var.split_once('=').unwrap_or((&var, ""));
+        std::env::set_var(key, value);
+        request_vars.push(key.to_string());
+    }
+    let body = read_chunk(input)?;
+
+    let result = crate::handle(config, body);
+    output.write_all(&(result.len() as u32).to_be_bytes())?;
+    output.write_all(&result)?;
+    output.flush()?;
+    Ok(true)
+}
+
+fn read_u32(input: &mut impl Read, eof_ok: bool) -> io::Result<Option<u32>> {
+    let mut buf = [0u8; 4];
+    let mut filled = 0;
+    while filled < buf.len() {
+        match input.read(&mut buf[filled..])? {
+            0 if filled == 0 && eof_ok => return Ok(None),
+            0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+            n => filled += n,
+        }
+    }
+    Ok(Some(u32::from_be_bytes(buf)))
+}
+
+fn read_chunk(input: &mut impl Read) -> io::Result<Vec<u8>> {
+    let len = read_u32(input, false)?.unwrap_or_default() as usize;
+    let mut buf = vec![0u8; len];
+    input.read_exact(&mut buf)?;
+    Ok(buf)
+}
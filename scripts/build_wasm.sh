@@ -17,3 +17,20 @@ cargo build -p gateway_wasm --release --target "$TARGET"
 
 cp "target/$TARGET/release/gateway_wasm.wasm" "$ROOT/gateway_logic.wasm"
 echo "[build_wasm] wrote $ROOT/gateway_logic.wasm"
+
+echo "[build_wasm] building reactor example for $TARGET"
+cargo build -p gateway_wasm --example reactor --release --target "$TARGET"
+
+cp "target/$TARGET/release/examples/reactor.wasm" "$ROOT/gateway_reactor.wasm"
+echo "[build_wasm] wrote $ROOT/gateway_reactor.wasm"
+
+# The component guest needs wasm32-unknown-unknown and wasm-tools.
+if command -v wasm-tools >/dev/null 2>&1 && rustup target add wasm32-unknown-unknown >/dev/null 2>&1; then
+  echo "[build_wasm] building component example"
+  cargo build -p gateway_wasm --example component --release --target wasm32-unknown-unknown
+  wasm-tools component new target/wasm32-unknown-unknown/release/examples/component.wasm \
+    -o "$ROOT/gateway_component.wasm"
+  echo "[build_wasm] wrote $ROOT/gateway_component.wasm"
+else
+  echo "[build_wasm] skipping component example (needs wasm-tools and wasm32-unknown-unknown)"
+fi
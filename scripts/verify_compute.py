@@ -0,0 +1,51 @@
+#!/usr/bin/env python3
+# verify_compute.py -- correctness check for the seeded /compute workload
+#
+# Usage:
+#   python3 scripts/verify_compute.py
+#       --url http://127.0.0.1:18081   (gateway base URL)
+#       --iters 20000                  (SHA-256 chain length)
+#       --seeds 1,2,3                  (comma-separated u64 seeds)
+#
+# For every seed, requests /compute?iters=N&seed=S and compares the body with
+# the digest computed locally. Wasm variants prefix the body (e.g. "wasm:"),
+# so only the trailing 64 hex characters are compared.
+#
+# Exit status: 0 if all seeds match, 1 otherwise.
+import argparse
+import hashlib
+import sys
+import urllib.request
+
+
+def expected_digest(iters: int, seed: int) -> str:
+    h = hashlib.sha256(seed.to_bytes(8, "little")).digest()
+    for i in range(iters):
+        h = hashlib.sha256(h + i.to_bytes(8, "little")).digest()
+    return h.hex()
+
+
+def main() -> int:
+    ap = argparse.ArgumentParser()
+    ap.add_argument("--url", default="http://127.0.0.1:18081")
+    ap.add_argument("--iters", type=int, default=20000)
+    ap.add_argument("--seeds", default="1,2,3")
+    args = ap.parse_args()
+
+    failures = 0
+    for seed in (int(s) for s in args.seeds.split(",") if s):
+        url = f"{args.url.rstrip('/')}/compute?iters={args.iters}&seed={seed}"
+        with urllib.request.urlopen(url, timeout=30) as resp:
+            body = resp.read().decode("utf-8", errors="replace").strip()
+        got = body[-64:]
+        want = expected_digest(args.iters, seed)
+        status = "ok" if got == want else "MISMATCH"
+        if got != want:
+            failures += 1
+        print(f"seed={seed} iters={args.iters} {status} got={got} want={want}")
+
+    return 1 if failures else 0
+
+
+if __name__ == "__main__":
+    sys.exit(main())